@@ -0,0 +1,54 @@
+//! Measure how fast the emulator runs frames, with and without the "debugger" feature.
+//!
+//!   cargo run -p cpu-6502 --profile fast --example benchmark
+//!   cargo run -p cpu-6502 --profile fast --no-default-features --example benchmark
+
+use cpu_6502::asm::{AsmLexer, BytesLabels};
+use cpu_6502::emulator::Emulator;
+use cpu_6502::mappers::SimpleProgram;
+use std::time::Instant;
+
+const FRAMES: u64 = 600;
+
+/// Count through RAM forever, with rendering on so the PPU does all of its work.
+const PROGRAM: &str = "
+    lda #$1e
+    sta $2001
+  loop:
+    inx
+    inc $0200,x
+    lda $0200,x
+    adc $10
+    sta $10
+    jmp loop
+";
+
+fn main() {
+    let mut lexer = AsmLexer::new(PROGRAM);
+    if let Err(parse_error) = lexer.parse() {
+        parse_error.panic_nicely();
+    }
+    let BytesLabels { bytes, .. } = lexer.into_bytes().unwrap();
+    let mut emulator = Emulator::new(Box::new(SimpleProgram::load(&bytes)));
+
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        emulator.run_frame();
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "debugger feature: {}",
+        if cfg!(feature = "debugger") {
+            "on"
+        } else {
+            "off"
+        }
+    );
+    println!(
+        "{} frames in {:.2?}, {:.1} frames per second",
+        FRAMES,
+        elapsed,
+        FRAMES as f64 / elapsed.as_secs_f64()
+    );
+}
@@ -0,0 +1,41 @@
+//! Run a ROM without a frontend, and print the hashes of the picture and RAM that it
+//! ends up with. The input script is optional, see `InputScript::from_text`.
+//!
+//!   cargo run -p cpu-6502 --release --example headless -- game.nes 600 input.txt
+
+use cpu_6502::headless::{HeadlessRunner, InputScript};
+use std::{env, fs, process::exit};
+
+fn run(args: &[String]) -> Result<(), String> {
+    let (rom_path, frames) = match args {
+        [rom_path, frames, ..] => (rom_path, frames),
+        _ => return Err("Usage: headless <rom.nes> <frames> [input.txt]".into()),
+    };
+    let frames: u64 = frames
+        .parse()
+        .map_err(|_| format!("Expected a number of frames, not \"{}\"", frames))?;
+    let script = match args.get(2) {
+        Some(path) => InputScript::from_text(
+            &fs::read_to_string(path)
+                .map_err(|err| format!("Couldn't read {}: {}", path, err))?,
+        )?,
+        None => InputScript::default(),
+    };
+    let rom = fs::read(rom_path)
+        .map_err(|err| format!("Couldn't read {}: {}", rom_path, err))?;
+
+    let result = HeadlessRunner::from_ines_bytes(&rom, script)?.run(frames);
+    println!("frames:      {}", result.frames);
+    println!("framebuffer: {:016x}", result.framebuffer_hash);
+    println!("ram:         {:016x}", result.ram_hash);
+    println!("hash:        {:016x}", result.hash());
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(message) = run(&args) {
+        eprintln!("{}", message);
+        exit(1);
+    }
+}
@@ -0,0 +1,58 @@
+//! Run two ROMs side by side on the same input, and print the first frame where they
+//! differ, e.g. a game assembled with and without `--optimize`. The input script is
+//! optional, see `InputScript::from_text`.
+//!
+//!   cargo run -p cpu-6502 --release --example lockstep -- a.nes b.nes 600 input.txt
+
+use cpu_6502::emulator::Emulator;
+use cpu_6502::headless::InputScript;
+use cpu_6502::lockstep::Lockstep;
+use std::{env, fs, process::exit};
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|err| format!("Couldn't read {}: {}", path, err))
+}
+
+fn run(args: &[String]) -> Result<bool, String> {
+    let (a_path, b_path, frames) = match args {
+        [a_path, b_path, frames, ..] => (a_path, b_path, frames),
+        _ => return Err("Usage: lockstep <a.nes> <b.nes> <frames> [input.txt]".into()),
+    };
+    let frames: u64 = frames
+        .parse()
+        .map_err(|_| format!("Expected a number of frames, not \"{}\"", frames))?;
+    let script = match args.get(3) {
+        Some(path) => InputScript::from_text(
+            &fs::read_to_string(path)
+                .map_err(|err| format!("Couldn't read {}: {}", path, err))?,
+        )?,
+        None => InputScript::default(),
+    };
+
+    let mut lockstep = Lockstep::new(
+        Emulator::from_ines_bytes(&read(a_path)?)?,
+        Emulator::from_ines_bytes(&read(b_path)?)?,
+    );
+    match lockstep.run(&script, frames)? {
+        Some(divergence) => {
+            println!("{}", divergence.report());
+            Ok(false)
+        }
+        None => {
+            println!("The ROMs matched for {} frames.", frames);
+            Ok(true)
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(message) => {
+            eprintln!("{}", message);
+            exit(2);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! The names and comments given to addresses while reverse engineering a ROM. They're
+//! kept per ROM in the storage, so they're still there in the next session, and
+//! they're shown wherever the instructions are disassembled.
+//!
+//!   # Labels and comments for the addresses in a ROM.
+//!   label $8000 = reset
+//!   comment $8003 = Wait for the PPU to warm up
+
+use std::collections::BTreeMap;
+
+use crate::asm::AddressToLabel;
+use crate::disassembler::{parse_address, DisassembledInstruction};
+use crate::storage::StorageBackend;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Annotations {
+    /// The labels given in the debugger, which take the place of the assembler's.
+    pub labels: BTreeMap<u16, String>,
+    pub comments: BTreeMap<u16, String>,
+}
+
+/// A label has to be something the assembler could parse back, e.g. "read_input".
+fn is_valid_label(name: &str) -> bool {
+    let mut characters = name.chars();
+    match characters.next() {
+        Some(first) if first.is_alphabetic() || first == '_' => {
+            characters.all(|c| c.is_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+impl Annotations {
+    /// Name an address, or remove its name with an empty one.
+    pub fn rename_label(&mut self, address: u16, name: &str) -> Result<(), String> {
+        let name = name.trim();
+        if name.is_empty() {
+            self.labels.remove(&address);
+            return Ok(());
+        }
+        if !is_valid_label(name) {
+            return Err(format!(
+                "\"{}\" isn't a valid label, use letters, numbers, and underscores",
+                name
+            ));
+        }
+        if let Some((other, _)) = self
+            .labels
+            .iter()
+            .find(|(other, label)| **other != address && *label == name)
+        {
+            return Err(format!("${:04x} is already labeled {}", other, name));
+        }
+        self.labels.insert(address, name.to_string());
+        Ok(())
+    }
+
+    /// Comment on an address, or remove its comment with an empty one.
+    pub fn set_comment(&mut self, address: u16, comment: &str) {
+        // The comments are stored a line each.
+        let comment = comment.trim().replace('\n', " ");
+        if comment.is_empty() {
+            self.comments.remove(&address);
+        } else {
+            self.comments.insert(address, comment);
+        }
+    }
+
+    pub fn comment(&self, address: u16) -> Option<&str> {
+        self.comments.get(&address).map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.comments.is_empty()
+    }
+
+    /// The labels from the assembler, or imported from a label file, with the ones
+    /// from the debugger on top.
+    pub fn labels_over(&self, labels: &AddressToLabel) -> AddressToLabel {
+        let mut labels = labels.clone();
+        for (address, label) in &self.labels {
+            labels.insert(*address, label.clone());
+        }
+        labels
+    }
+
+    /// Fill in the labels and the comment of a disassembled instruction.
+    pub fn annotate(&self, instruction: &mut DisassembledInstruction) {
+        if let Some(label) = self.labels.get(&instruction.address) {
+            instruction.label = Some(label.clone());
+        }
+        if let Some(label) = instruction
+            .target
+            .and_then(|target| self.labels.get(&target))
+        {
+            instruction.target_label = Some(label.clone());
+        }
+        if let Some(comment) = self.comment(instruction.address) {
+            instruction.comment = Some(comment.to_string());
+        }
+    }
+
+    pub fn to_config_string(&self) -> String {
+        let mut text =
+            String::from("# Labels and comments for the addresses in a ROM.\n");
+        for (address, label) in &self.labels {
+            text.push_str(&format!("label ${:04x} = {}\n", address, label));
+        }
+        for (address, comment) in &self.comments {
+            text.push_str(&format!("comment ${:04x} = {}\n", address, comment));
+        }
+        text
+    }
+
+    pub fn from_config_str(text: &str) -> Result<Annotations, String> {
+        let mut annotations = Annotations::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error =
+                || format!("Expected \"label $8000 = name\" but found \"{}\"", line);
+            let (key, value) = line.split_once('=').ok_or_else(error)?;
+            let (kind, address) = key.trim().split_once(' ').ok_or_else(error)?;
+            let address = parse_address(address.trim())?;
+            match kind {
+                "label" => annotations.rename_label(address, value)?,
+                "comment" => annotations.set_comment(address, value),
+                _ => return Err(format!("Unknown annotation \"{}\"", kind)),
+            }
+        }
+        Ok(annotations)
+    }
+
+    /// Load the annotations for a ROM, which starts out without any.
+    pub fn load(storage: &dyn StorageBackend, key: &str) -> Result<Annotations, String> {
+        match storage.read_string(key)? {
+            Some(text) => Annotations::from_config_str(&text)
+                .map_err(|err| format!("{}: {}", key, err)),
+            None => Ok(Annotations::default()),
+        }
+    }
+
+    pub fn save(
+        &self,
+        storage: &mut dyn StorageBackend,
+        key: &str,
+    ) -> Result<(), String> {
+        if self.is_empty() {
+            return storage.remove(key);
+        }
+        storage.write(key, self.to_config_string().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::disassembler::{disassemble_with_labels, listing};
+    use crate::mappers::SimpleProgram;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_annotations() {
+        let mut annotations = Annotations::default();
+        annotations.rename_label(0x8000, "wait_vblank").unwrap();
+        annotations.rename_label(0x8002, "reset").unwrap();
+        annotations.set_comment(0x8002, "  Jump back\nup ");
+        assert!(annotations.rename_label(0x8004, "reset").is_err());
+        assert!(annotations.rename_label(0x8004, "2fast").is_err());
+        assert_eq!(annotations.comment(0x8002), Some("Jump back up"));
+
+        // $8000: bit $2002, $8003: jmp $8000
+        let program = [0x2c, 0x02, 0x20, 0x4c, 0x00, 0x80];
+        let bus = Bus::new(Box::new(SimpleProgram::load(&program)));
+        let mut assembled = AddressToLabel::new();
+        assembled.insert(0x8000, "loop".to_string());
+        let labels = annotations.labels_over(&assembled);
+        assert_eq!(labels[&0x8000], "wait_vblank");
+
+        annotations.rename_label(0x8002, "").unwrap();
+        annotations.set_comment(0x8003, "Spin");
+        let mut instructions = disassemble_with_labels(&bus, 0x8000, 2, Some(&assembled));
+        for instruction in instructions.iter_mut() {
+            annotations.annotate(instruction);
+        }
+        assert_eq!(
+            listing(&instructions),
+            "wait_vblank:\n  $8000 bit $2002\n  $8003 jmp wait_vblank $8000 ; Spin\n"
+        );
+
+        let mut storage = MemoryStorage::default();
+        assert_eq!(
+            Annotations::load(&storage, "game.labels"),
+            Ok(Annotations::default())
+        );
+        annotations.save(&mut storage, "game.labels").unwrap();
+        assert_eq!(Annotations::load(&storage, "game.labels"), Ok(annotations));
+        Annotations::default()
+            .save(&mut storage, "game.labels")
+            .unwrap();
+        assert_eq!(storage.read("game.labels"), Ok(None));
+
+        assert!(Annotations::from_config_str("label 8000 = reset").is_err());
+        assert!(Annotations::from_config_str("note $8000 = reset").is_err());
+        assert!(Annotations::from_config_str("label $8000").is_err());
+    }
+}
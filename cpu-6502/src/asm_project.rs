@@ -0,0 +1,376 @@
+//! An `asm.toml` describes how a game is assembled, so a project with several files
+//! builds the same way every time, with `asm asm.toml` or `cargo task build-asm`.
+//!
+//!   entry = "main.asm"
+//!   include_paths = ["src"]
+//!   target = "nes"
+//!   chr = ["game.chr"]
+//!   out = "game.nes"
+//!   optimize = false
+//!   mapper = 0
+//!   mirroring = "horizontal"
+//!   battery = false
+//!   prg_banks = 2
+//!
+//!   [defines]
+//!   DEBUG = 1
+//!   PPU_STATUS = "$2002"
+//!
+//! Only the entry is required. The paths are relative to the manifest. The target is
+//! either "nes" for an iNES file, or "bin" for the assembled bytes from $8000, or from
+//! the lowest `.org` when it's below that, like a program for another 6502 machine. The
+//! mapper, mirroring, and battery go into the iNES header, which defaults to NROM. The
+//! program fills 2 banks of PRG ROM from $8000, or with `prg_banks = 1` 1 bank from
+//! $c000.
+
+use std::path::{Path, PathBuf};
+
+use crate::asm::{AsmLexer, Segment, Sources, U8OrU16, ORIGIN};
+use crate::rom::{ines_from_program, InesHeader};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AsmTarget {
+    Bin,
+    Nes,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmProject {
+    pub entry: PathBuf,
+    /// The directories that `.include` looks in, after the including file's own.
+    pub include_paths: Vec<PathBuf>,
+    /// Constants defined before the entry is parsed.
+    pub defines: Vec<(String, U8OrU16)>,
+    pub target: AsmTarget,
+    /// The CHR files, in bank order. Without any, an iNES file uses CHR RAM.
+    pub chr: Vec<PathBuf>,
+    pub out: PathBuf,
+    /// The header of an iNES file.
+    pub header: InesHeader,
+    /// Run the peephole optimizer.
+    pub optimize: bool,
+}
+
+/// What the build made, and the warnings and optimizations to show.
+pub struct AsmBuild {
+    pub bytes: Vec<u8>,
+    pub messages: Vec<String>,
+}
+
+/// A define is a number, or a string in the assembler's syntax, like "$2002".
+fn parse_define(name: &str, value: &toml::Value) -> Result<U8OrU16, String> {
+    let error = || {
+        format!(
+            "The define {} should be a number, or hex like \"$2002\"",
+            name
+        )
+    };
+    if let Some(number) = value.as_integer() {
+        return match number {
+            0..=0xff => Ok(U8OrU16::U8(number as u8)),
+            0x100..=0xffff => Ok(U8OrU16::U16(number as u16)),
+            _ => Err(error()),
+        };
+    }
+    let hex = value
+        .as_str()
+        .and_then(|text| text.strip_prefix('$'))
+        .ok_or_else(error)?;
+    match hex.len() {
+        2 => u8::from_str_radix(hex, 16)
+            .map(U8OrU16::U8)
+            .map_err(|_| error()),
+        4 => u16::from_str_radix(hex, 16)
+            .map(U8OrU16::U16)
+            .map_err(|_| error()),
+        _ => Err(error()),
+    }
+}
+
+impl AsmProject {
+    pub fn load(path: &Path) -> Result<AsmProject, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {:?}: {}", path, err))?;
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        AsmProject::from_toml_str(&text, directory)
+            .map_err(|err| format!("{}: {}", path.to_string_lossy(), err))
+    }
+
+    /// Read a manifest, with the paths relative to `directory`.
+    pub fn from_toml_str(text: &str, directory: &Path) -> Result<AsmProject, String> {
+        let value: toml::Value = text
+            .parse()
+            .map_err(|err| format!("Failed to parse the project: {}", err))?;
+        let string = |key: &str| -> Result<Option<&str>, String> {
+            match value.get(key) {
+                Some(value) => value
+                    .as_str()
+                    .map(Some)
+                    .ok_or_else(|| format!("Expected {} to be a string", key)),
+                None => Ok(None),
+            }
+        };
+        let paths = |key: &str| -> Result<Vec<PathBuf>, String> {
+            let array = match value.get(key) {
+                Some(array) => array
+                    .as_array()
+                    .ok_or_else(|| format!("Expected {} to be a list", key))?,
+                None => return Ok(Vec::new()),
+            };
+            array
+                .iter()
+                .map(|path| {
+                    path.as_str()
+                        .map(|path| directory.join(path))
+                        .ok_or_else(|| format!("Expected {} to be a list of paths", key))
+                })
+                .collect()
+        };
+
+        let entry = directory.join(string("entry")?.ok_or("Expected an entry file")?);
+        let target = match string("target")? {
+            None | Some("nes") => AsmTarget::Nes,
+            Some("bin") => AsmTarget::Bin,
+            Some(target) => {
+                return Err(format!(
+                    "Unknown target \"{}\", it's either \"nes\" or \"bin\"",
+                    target
+                ))
+            }
+        };
+        let out = match string("out")? {
+            Some(out) => directory.join(out),
+            None => entry.with_extension(match target {
+                AsmTarget::Nes => "nes",
+                AsmTarget::Bin => "bin",
+            }),
+        };
+        let boolean = |key: &str| -> Result<bool, String> {
+            match value.get(key) {
+                Some(value) => value
+                    .as_bool()
+                    .ok_or_else(|| format!("Expected {} to be true or false", key)),
+                None => Ok(false),
+            }
+        };
+        let optimize = boolean("optimize")?;
+        let mut header = InesHeader {
+            has_battery: boolean("battery")?,
+            ..InesHeader::default()
+        };
+        if let Some(mapper) = value.get("mapper") {
+            header.mapper = mapper
+                .as_integer()
+                .filter(|mapper| (0..=0xff).contains(mapper))
+                .map(|mapper| mapper as u8)
+                .ok_or("Expected the mapper to be a number from 0 to 255")?;
+        }
+        if let Some(prg_banks) = value.get("prg_banks") {
+            header.prg_banks = match prg_banks.as_integer() {
+                Some(1) => 1,
+                Some(2) => 2,
+                _ => return Err("Expected prg_banks to be 1 or 2".into()),
+            };
+        }
+        if let Some(mirroring) = string("mirroring")? {
+            header.mirroring = InesHeader::mirroring_from_name(mirroring)?;
+        }
+        let mut defines = Vec::new();
+        if let Some(table) = value.get("defines") {
+            let table = table.as_table().ok_or("Expected [defines] to be a table")?;
+            for (name, value) in table {
+                defines.push((name.clone(), parse_define(name, value)?));
+            }
+        }
+
+        Ok(AsmProject {
+            entry,
+            include_paths: paths("include_paths")?,
+            defines,
+            target,
+            chr: paths("chr")?,
+            out,
+            header,
+            optimize,
+        })
+    }
+
+    /// Assemble the project, without writing the output.
+    pub fn build(&self) -> Result<AsmBuild, String> {
+        let sources = Sources::load(&self.entry, &self.include_paths)?;
+        let mut lexer = AsmLexer::with_sources(&sources);
+        for (name, value) in &self.defines {
+            lexer.define(name, *value)?;
+        }
+        if let Err(parse_error) = lexer.parse() {
+            return Err(parse_error.nice_message().to_string());
+        }
+        let mut messages: Vec<String> = lexer
+            .warnings()
+            .iter()
+            .chain(lexer.lint().iter())
+            .map(|warning| warning.nice_message().to_string())
+            .collect();
+        if self.optimize {
+            let optimizations = lexer.optimize()?;
+            for optimization in optimizations.iter() {
+                let span = optimization.span();
+                let (file, row) = sources.locate(span.row);
+                messages.push(format!(
+                    "{}:{}:{} {}",
+                    file.name,
+                    row,
+                    span.column,
+                    optimization.message()
+                ));
+            }
+            let bytes_saved: usize = optimizations.iter().map(|o| o.bytes_saved()).sum();
+            messages.push(format!("Bytes saved by the optimizer: {}", bytes_saved));
+        }
+        let bytes = match self.target {
+            AsmTarget::Bin => bin_from_segments(lexer.into_segments()?.segments),
+            AsmTarget::Nes => {
+                let bytes_labels = lexer.into_bytes()?;
+                let mut chr = Vec::new();
+                for path in &self.chr {
+                    chr.extend(std::fs::read(path).map_err(|err| {
+                        format!("Failed to read {}: {}", path.to_string_lossy(), err)
+                    })?);
+                }
+                ines_from_program(&bytes_labels, &chr, &self.header)?
+            }
+        };
+        Ok(AsmBuild { bytes, messages })
+    }
+
+    /// Assemble the project and write the output, printing the messages.
+    pub fn build_and_write(&self) -> Result<(), String> {
+        let build = self.build()?;
+        for message in build.messages.iter() {
+            eprintln!("{}", message.trim_end());
+        }
+        std::fs::write(&self.out, build.bytes).map_err(|err| {
+            format!("Failed to write {}: {}", self.out.to_string_lossy(), err)
+        })?;
+        println!("Wrote {}", self.out.to_string_lossy());
+        Ok(())
+    }
+}
+
+/// Lay out the segments from $8000, or from the lowest one when it's below that, with
+/// the gaps between them filled with zeros.
+fn bin_from_segments(mut segments: Vec<Segment>) -> Vec<u8> {
+    segments.sort_by_key(|segment| segment.origin);
+    let start = segments
+        .first()
+        .map_or(ORIGIN, |segment| segment.origin.min(ORIGIN));
+    let mut bytes = Vec::new();
+    for segment in segments {
+        // The segments don't overlap, so this only fills the gap.
+        bytes.resize((segment.origin - start) as usize, 0);
+        bytes.extend_from_slice(&segment.bytes);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_asm_project() {
+        let directory = Path::new("game");
+        let project = AsmProject::from_toml_str(
+            "
+            entry = \"main.asm\"
+            include_paths = [\"src\"]
+            target = \"bin\"
+            optimize = true
+            mapper = 2
+            mirroring = \"vertical\"
+
+            [defines]
+            LIVES = 3
+            PPU_STATUS = \"$2002\"
+            ",
+            directory,
+        )
+        .unwrap();
+        assert_eq!(project.entry, directory.join("main.asm"));
+        assert_eq!(project.include_paths, [directory.join("src")]);
+        assert_eq!(project.target, AsmTarget::Bin);
+        assert_eq!(project.out, directory.join("main.bin"));
+        assert!(project.optimize);
+        assert_eq!(project.header.mapper, 2);
+        assert_eq!(project.header.mirroring, crate::ppu::Mirroring::Vertical);
+        assert!(!project.header.has_battery);
+        assert!(project
+            .defines
+            .contains(&("PPU_STATUS".to_string(), U8OrU16::U16(0x2002))));
+        assert!(project
+            .defines
+            .contains(&("LIVES".to_string(), U8OrU16::U8(3))));
+
+        for text in [
+            "target = \"nes\"",
+            "entry = \"main.asm\"\ntarget = \"snes\"",
+            "entry = \"main.asm\"\nchr = \"game.chr\"",
+            "entry = \"main.asm\"\n[defines]\nBIG = 70000",
+            "entry = \"main.asm\"\n[defines]\nODD = \"$123\"",
+            "entry = \"main.asm\"\nmapper = 256",
+            "entry = \"main.asm\"\nmirroring = \"single\"",
+            "entry = \"main.asm\"\nbattery = 1",
+            "entry = \"main.asm\"\nprg_banks = 4",
+        ] {
+            assert!(
+                AsmProject::from_toml_str(text, directory).is_err(),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_asm_project_build() {
+        let directory = std::env::temp_dir()
+            .join(format!("cpu-6502-asm-project-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join("main.asm"),
+            "reset:\nlda #LIVES\n.include \"player.asm\"\njmp reset",
+        )
+        .unwrap();
+        std::fs::create_dir_all(directory.join("src")).unwrap();
+        std::fs::write(directory.join("src/player.asm"), "sta PLAYER").unwrap();
+        std::fs::write(
+            directory.join("asm.toml"),
+            "entry = \"main.asm\"\ninclude_paths = [\"src\"]\n\
+             [defines]\nLIVES = 3\nPLAYER = \"$0300\"",
+        )
+        .unwrap();
+
+        let project = AsmProject::load(&directory.join("asm.toml")).unwrap();
+        let rom = project.build().unwrap().bytes;
+        let rom = crate::rom::InesRom::from_ines_bytes(&rom).unwrap();
+        assert_eq!(rom.prg_rom[..5], [0xa9, 0x03, 0x8d, 0x00, 0x03]);
+
+        let project = AsmProject {
+            target: AsmTarget::Bin,
+            ..project
+        };
+        project.build_and_write().unwrap();
+        assert_eq!(std::fs::read(&project.out).unwrap().len(), 8);
+
+        // A program disassembled from $0600 builds back into the same bytes.
+        let program = [0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x4c, 0x00, 0x06];
+        let text =
+            crate::rom_export::disassemble_program(&program, 0x0600, &[0x0600]).unwrap();
+        std::fs::write(directory.join("low.asm"), text).unwrap();
+        let project = AsmProject {
+            entry: directory.join("low.asm"),
+            ..project
+        };
+        assert_eq!(project.build().unwrap().bytes, program);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
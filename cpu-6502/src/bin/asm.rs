@@ -0,0 +1,97 @@
+//! Assemble a program into an iNES file that can run in the emulator, or any other NES
+//! emulator.
+//!
+//!   cargo run -p cpu-6502 --bin asm -- main.asm --chr game.chr --out game.nes
+//!
+//! The code is placed at $8000, and the `reset`, `nmi`, and `irq` labels are written
+//! into the interrupt vectors. Without a --chr file the cartridge uses CHR RAM. With
+//! --optimize the peephole optimizer shrinks the code, and reports what it changed.
+//! The header is NROM with horizontal mirroring, unless --mapper, --mirroring, or
+//! --battery say otherwise. An --out path ending in .bin gets the assembled bytes
+//! instead, which can start below $8000, e.g. a program from `disasm --start $0600`.
+//!
+//! A project with an asm.toml is built from its settings instead, see
+//! src/asm_project.rs. Without any arguments, the asm.toml in the working directory is
+//! built.
+//!
+//!   cargo run -p cpu-6502 --bin asm -- asm.toml
+
+use cpu_6502::asm_project::{AsmProject, AsmTarget};
+use cpu_6502::rom::InesHeader;
+use std::path::{Path, PathBuf};
+use std::{env, process::exit};
+
+const MANIFEST: &str = "asm.toml";
+
+fn parse_cli_args() -> Result<AsmProject, String> {
+    let mut args = env::args().skip(1);
+    let mut asm = None;
+    let mut chr = None;
+    let mut out = None;
+    let mut optimize = false;
+    let mut header = InesHeader::default();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Expected a value after {}", arg))
+        };
+        match arg.as_str() {
+            "--chr" => chr = Some(value()?),
+            "--out" => out = Some(value()?),
+            "--optimize" => optimize = true,
+            "--mapper" => {
+                header.mapper = value()?
+                    .parse()
+                    .map_err(|_| "Expected the mapper to be a number from 0 to 255")?
+            }
+            "--mirroring" => {
+                header.mirroring = InesHeader::mirroring_from_name(&value()?)?
+            }
+            "--battery" => header.has_battery = true,
+            _ if asm.is_none() && !arg.starts_with("--") => asm = Some(arg),
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+    let asm = match asm {
+        Some(asm) if asm.ends_with(".toml") => return AsmProject::load(Path::new(&asm)),
+        Some(asm) => asm,
+        None if Path::new(MANIFEST).exists() => {
+            return AsmProject::load(Path::new(MANIFEST))
+        }
+        None => return Err("Expected the path to an .asm file, or an asm.toml.".into()),
+    };
+    let out = out.unwrap_or_else(|| asm.trim_end_matches(".asm").to_string() + ".nes");
+    let target = if out.ends_with(".bin") {
+        AsmTarget::Bin
+    } else {
+        AsmTarget::Nes
+    };
+    Ok(AsmProject {
+        entry: PathBuf::from(asm),
+        include_paths: Vec::new(),
+        defines: Vec::new(),
+        target,
+        chr: chr.into_iter().map(PathBuf::from).collect(),
+        out: PathBuf::from(out),
+        header,
+        optimize,
+    })
+}
+
+fn main() {
+    let project = match parse_cli_args() {
+        Ok(project) => project,
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!("cargo run -p cpu-6502 --bin asm -- main.asm --chr game.chr --out game.nes [--optimize]");
+            eprintln!("  [--mapper 2] [--mirroring horizontal|vertical|four_screen] [--battery]");
+            eprintln!("cargo run -p cpu-6502 --bin asm -- program.asm --out program.bin");
+            eprintln!("cargo run -p cpu-6502 --bin asm -- asm.toml");
+            exit(1);
+        }
+    };
+    if let Err(err) = project.build_and_write() {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
@@ -0,0 +1,90 @@
+//! Disassemble a program into assembly that the `asm` tool builds back into the same
+//! bytes.
+//!
+//!   cargo run -p cpu-6502 --bin disasm -- program.bin --start $0600 --out program.asm
+//!
+//! A raw binary is loaded at the --start address, which defaults to $8000, and the code
+//! is followed from there. An NROM .nes file is disassembled from its interrupt
+//! vectors, and --start adds another entry point, like a routine that's only reached
+//! through a jump table. The branch and jump targets get labels, and the bytes that
+//! aren't reached as code are written as data. Without --out the assembly is printed.
+//!
+//! A raw binary builds back with `asm program.asm --out program.bin`.
+
+use cpu_6502::disassembler::parse_address;
+use cpu_6502::rom::InesRom;
+use cpu_6502::rom_export::{disassemble_program, disassemble_rom};
+use std::{env, process::exit};
+
+const DEFAULT_START: u16 = 0x8000;
+
+struct Options {
+    path: String,
+    start: Option<u16>,
+    out: Option<String>,
+}
+
+fn parse_cli_args() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let mut path = None;
+    let mut start = None;
+    let mut out = None;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Expected a value after {}", arg))
+        };
+        match arg.as_str() {
+            "--start" => start = Some(parse_address(&value()?)?),
+            "--out" => out = Some(value()?),
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+    Ok(Options {
+        path: path.ok_or("Expected the path to a .bin or .nes file.")?,
+        start,
+        out,
+    })
+}
+
+fn disassemble(options: &Options) -> Result<String, String> {
+    let bytes = std::fs::read(&options.path)
+        .map_err(|err| format!("Failed to read {}: {}", options.path, err))?;
+    let entries: Vec<u16> = options.start.into_iter().collect();
+    if options.path.ends_with(".nes") {
+        disassemble_rom(&InesRom::from_ines_bytes(&bytes)?, &entries)
+    } else {
+        let start = options.start.unwrap_or(DEFAULT_START);
+        disassemble_program(&bytes, start, &[start])
+    }
+}
+
+fn main() {
+    let options = match parse_cli_args() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!("cargo run -p cpu-6502 --bin disasm -- program.bin [--start $0600] [--out program.asm]");
+            eprintln!("cargo run -p cpu-6502 --bin disasm -- game.nes [--start $c123] [--out game.asm]");
+            exit(1);
+        }
+    };
+    let text = match disassemble(&options) {
+        Ok(text) => text,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    };
+    match &options.out {
+        Some(out) => {
+            if let Err(err) = std::fs::write(out, text) {
+                eprintln!("Failed to write {}: {}", out, err);
+                exit(1);
+            }
+            println!("Wrote {}", out);
+        }
+        None => print!("{}", text),
+    }
+}
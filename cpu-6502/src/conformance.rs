@@ -0,0 +1,274 @@
+//! A conformance suite of specific hardware behaviors. Each behavior has a small asm
+//! program and the result that real hardware produces, and is tagged by how much
+//! software depends on it. Running the tests writes a markdown report to
+//! target/conformance.md, so contributors can see at a glance what is implemented.
+//!
+//! Behaviors that aren't implemented yet are marked as missing. The test fails when a
+//! missing behavior starts passing, so that the report stays honest.
+
+use crate::bus::Bus;
+use crate::cpu_6502::test_helpers::load_program;
+use crate::cpu_6502::Cpu6502;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Accuracy {
+    /// Almost every program relies on this.
+    Essential,
+    /// Some commercial games rely on this.
+    Game,
+    /// Mostly only noticed by test ROMs.
+    Exact,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Implemented,
+    Missing,
+}
+
+struct Behavior {
+    name: &'static str,
+    accuracy: Accuracy,
+    status: Status,
+    description: &'static str,
+    program: &'static str,
+    check: fn(&Cpu6502<Bus>) -> Result<(), String>,
+}
+
+fn expect(name: &str, actual: u8, expected: u8) -> Result<(), String> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} was ${:02x}, expected ${:02x}",
+            name, actual, expected
+        ))
+    }
+}
+
+const BEHAVIORS: &[Behavior] = &[
+    Behavior {
+        name: "RAM mirrors",
+        accuracy: Accuracy::Essential,
+        status: Status::Implemented,
+        description: "$0000-$07FF is mirrored up to $1FFF.",
+        program: "
+            lda #$42
+            sta $0001
+            lda $1801
+        ",
+        check: |cpu| expect("A", cpu.a, 0x42),
+    },
+    Behavior {
+        name: "Zero page index wrap",
+        accuracy: Accuracy::Essential,
+        status: Status::Implemented,
+        description:
+            "Zero page indexing stays in the zero page, $11,X with X=$FF is $10.",
+        program: "
+            lda #$42
+            sta $10
+            ldx #$ff
+            lda $11,x
+        ",
+        check: |cpu| expect("A", cpu.a, 0x42),
+    },
+    Behavior {
+        name: "Indirect Y pointer wrap",
+        accuracy: Accuracy::Game,
+        status: Status::Implemented,
+        description: "A zero page pointer at $FF reads its high byte from $00.",
+        program: "
+            lda #$00
+            sta $ff
+            lda #$03
+            sta $00
+            lda #$42
+            sta $0300
+            ldy #$00
+            lda ($ff),y
+        ",
+        check: |cpu| expect("A", cpu.a, 0x42),
+    },
+    Behavior {
+        name: "JMP indirect page bug",
+        accuracy: Accuracy::Game,
+        status: Status::Implemented,
+        description: "JMP ($02FF) reads the high byte from $0200 rather than $0300.",
+        // Write "lda #$01, kil" to $0400 and "lda #$02, kil" to $0500, then jump
+        // through a pointer that crosses a page.
+        program: "
+            ldx #$a9
+            stx $0400
+            stx $0500
+            lda #$01
+            sta $0401
+            lda #$02
+            sta $0402
+            sta $0501
+            sta $0502
+            lda #$00
+            sta $02ff
+            lda #$04
+            sta $0200
+            lda #$05
+            sta $0300
+            jmp ($02ff)
+        ",
+        check: |cpu| expect("A", cpu.a, 0x01),
+    },
+    Behavior {
+        name: "Palette mirrors",
+        accuracy: Accuracy::Game,
+        status: Status::Implemented,
+        description: "Palette entry $3F10 is a mirror of the backdrop at $3F00.",
+        program: "
+            lda #$3f
+            sta $2006
+            lda #$10
+            sta $2006
+            lda #$2a
+            sta $2007
+            lda #$3f
+            sta $2006
+            lda #$00
+            sta $2006
+            lda $2007
+        ",
+        check: |cpu| expect("A", cpu.a, 0x2a),
+    },
+    Behavior {
+        name: "PPUDATA read buffer",
+        accuracy: Accuracy::Game,
+        status: Status::Implemented,
+        description:
+            "Reads of $2007 below the palette return the previous buffered value.",
+        program: "
+            lda #$20
+            sta $2006
+            lda #$00
+            sta $2006
+            lda #$55
+            sta $2007
+            lda #$20
+            sta $2006
+            lda #$00
+            sta $2006
+            ldx $2007
+            lda $2007
+        ",
+        check: |cpu| {
+            expect("A", cpu.a, 0x55)?;
+            if cpu.x == 0x55 {
+                return Err("The first read of $2007 wasn't buffered".into());
+            }
+            Ok(())
+        },
+    },
+    Behavior {
+        name: "Open bus",
+        accuracy: Accuracy::Exact,
+        status: Status::Implemented,
+        description: "Reading an unmapped address returns the last value on the bus, \
+                      the high byte of the address.",
+        program: "
+            lda $5000
+        ",
+        check: |cpu| expect("A", cpu.a, 0x50),
+    },
+    Behavior {
+        name: "PPUADDR sets the scroll",
+        accuracy: Accuracy::Game,
+        status: Status::Missing,
+        description: "PPUADDR and the scroll share the PPU's address register, so \
+                      writing $20A5 to $2006 scrolls to (40, 42). Games use this to \
+                      change the scroll in the middle of a frame.",
+        program: "
+            lda #$20
+            sta $2006
+            lda #$a5
+            sta $2006
+        ",
+        check: |cpu| {
+            let (x, y) = cpu.bus.scroll_position();
+            expect("The X scroll", x as u8, 40)?;
+            expect("The Y scroll", y as u8, 42)
+        },
+    },
+];
+
+/// Missing behaviors can send a program off into the weeds, so stop it eventually.
+const MAX_TICKS: u64 = 1000;
+
+fn run_behavior(behavior: &Behavior) -> Result<(), String> {
+    let mut cpu = load_program(behavior.program);
+    while cpu.tick() {
+        if cpu.tick_count > MAX_TICKS {
+            return Err("The program didn't finish".into());
+        }
+    }
+    (behavior.check)(&cpu)
+}
+
+fn build_report(results: &[(&Behavior, Result<(), String>)]) -> String {
+    let mut report = String::from(
+        "# Conformance\n\n\
+         | Behavior | Accuracy | Result | Details |\n\
+         |----------|----------|--------|---------|\n",
+    );
+    let mut results: Vec<_> = results.iter().collect();
+    results.sort_by_key(|(behavior, _)| behavior.accuracy);
+    for (behavior, result) in results {
+        let (result, details) = match result {
+            Ok(_) => ("pass", behavior.description.to_string()),
+            Err(message) => ("**fail**", format!("{} {}", behavior.description, message)),
+        };
+        report.push_str(&format!(
+            "| {} | {:?} | {} | {} |\n",
+            behavior.name, behavior.accuracy, result, details
+        ));
+    }
+    report
+}
+
+fn report_path() -> PathBuf {
+    let target = match std::env::var("CARGO_TARGET_DIR") {
+        Ok(target) => PathBuf::from(target),
+        Err(_) => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../target"),
+    };
+    target.join("conformance.md")
+}
+
+#[test]
+fn test_conformance() {
+    let results: Vec<_> = BEHAVIORS
+        .iter()
+        .map(|behavior| (behavior, run_behavior(behavior)))
+        .collect();
+
+    let path = report_path();
+    if let Err(err) = std::fs::write(&path, build_report(&results)) {
+        eprintln!(
+            "Unable to write the conformance report to {:?}: {}",
+            path, err
+        );
+    }
+
+    let mut failures = vec![];
+    for (behavior, result) in results.iter() {
+        match (behavior.status, result) {
+            (Status::Implemented, Err(message)) => {
+                failures.push(format!("{}: {}", behavior.name, message));
+            }
+            (Status::Missing, Ok(_)) => {
+                failures.push(format!(
+                    "{}: This passes now, mark it as implemented.",
+                    behavior.name
+                ));
+            }
+            _ => {}
+        }
+    }
+    assert!(failures.is_empty(), "\n{}\n", failures.join("\n"));
+}
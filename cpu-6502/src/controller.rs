@@ -0,0 +1,131 @@
+//! The controllers are in nes-system, this adds reading and writing the controller
+//! mappings as TOML, so that the frontends can save them.
+
+pub use nes_system::controller::*;
+
+/// The tables in the controller mapping's TOML file.
+const MAPPING_TABLES: [(InputSource, &str); 2] = [
+    (InputSource::Keyboard, "keys"),
+    (InputSource::Gamepad, "gamepad"),
+];
+
+/// Serialize the mappings into a TOML file. Player 1's tables are at the top, and
+/// player 2's are under [player2].
+pub fn mappings_to_toml(mappings: &ControllerMappings) -> String {
+    let mut text = String::from(
+        "# The keyboard keys and gamepad buttons for each controller button.\n",
+    );
+    for (player, mapping) in mappings.players.iter().enumerate() {
+        let prefix = match player {
+            0 => String::new(),
+            _ => format!("player{}.", player + 1),
+        };
+        write_toml_tables(mapping, &prefix, &mut text);
+    }
+    text
+}
+
+/// Parse the mappings from TOML. The buttons that aren't in the file keep their
+/// default inputs, so files from before there was a second controller still load.
+pub fn mappings_from_toml(text: &str) -> Result<ControllerMappings, String> {
+    let value: toml::Value = text
+        .parse()
+        .map_err(|err| format!("Failed to parse the controller mapping: {}", err))?;
+    let mut mappings = ControllerMappings::default();
+    for (player, mapping) in mappings.players.iter_mut().enumerate() {
+        if player == 0 {
+            read_toml_tables(mapping, "", &value)?;
+            continue;
+        }
+        let table_name = format!("player{}", player + 1);
+        if let Some(table) = value.get(&table_name) {
+            read_toml_tables(mapping, &format!("{}.", table_name), table)?;
+        }
+    }
+    Ok(mappings)
+}
+
+/// Write a table for each input source, under the `prefix` for player 2.
+fn write_toml_tables(mapping: &ControllerMapping, prefix: &str, text: &mut String) {
+    for (source, table) in MAPPING_TABLES.iter() {
+        text.push_str(&format!("\n[{}{}]\n", prefix, table));
+        for ((_, name), inputs) in BUTTON_NAMES.iter().zip(mapping.inputs(*source)) {
+            let inputs: Vec<String> = inputs
+                .iter()
+                .map(|input| toml::Value::String(input.clone()).to_string())
+                .collect();
+            text.push_str(&format!("{} = [{}]\n", name, inputs.join(", ")));
+        }
+    }
+}
+
+/// Read the tables for each input source out of `value`. The buttons that aren't
+/// in the tables keep their current inputs.
+fn read_toml_tables(
+    mapping: &mut ControllerMapping,
+    prefix: &str,
+    value: &toml::Value,
+) -> Result<(), String> {
+    for (source, table_name) in MAPPING_TABLES.iter() {
+        let table = match value.get(table_name) {
+            Some(table) => table.as_table().ok_or_else(|| {
+                format!("Expected [{}{}] to be a table", prefix, table_name)
+            })?,
+            None => continue,
+        };
+        for (name, inputs) in table {
+            let index = BUTTON_NAMES
+                .iter()
+                .position(|(_, button_name)| button_name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("Unknown button \"{}\"", name))?;
+            let inputs = inputs
+                .as_array()
+                .and_then(|inputs| {
+                    inputs
+                        .iter()
+                        .map(|input| input.as_str().map(String::from))
+                        .collect::<Option<Vec<String>>>()
+                })
+                .ok_or_else(|| {
+                    format!(
+                        "Expected a list of names for {} in [{}{}]",
+                        name, prefix, table_name
+                    )
+                })?;
+            mapping.inputs_mut(*source)[index] = inputs;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mapping_toml() {
+        let mut mappings = ControllerMappings::default();
+        mappings.players[0].keys[button_index(Button::Select)].push("Tab".into());
+        mappings.players[1].gamepad[button_index(Button::A)].push("X".into());
+        let text = mappings_to_toml(&mappings);
+        assert_eq!(mappings_from_toml(&text), Ok(mappings.clone()));
+
+        // Missing buttons keep their defaults.
+        let mappings = mappings_from_toml(
+            "[keys]\nstart = [\"Space\"]\n[player2.keys]\nstart = [\"Tab\"]",
+        )
+        .unwrap();
+        let [player_1, player_2] = &mappings.players;
+        assert_eq!(
+            player_1.bound(InputSource::Keyboard, Button::Start),
+            ["Space"]
+        );
+        assert_eq!(player_1.bound(InputSource::Keyboard, Button::A), ["X"]);
+        assert_eq!(
+            player_2.bound(InputSource::Keyboard, Button::Start),
+            ["Tab"]
+        );
+        assert_eq!(player_2.bound(InputSource::Keyboard, Button::Up), ["W"]);
+        assert!(mappings_from_toml("[keys]\nturbo = [\"T\"]").is_err());
+    }
+}
@@ -0,0 +1,255 @@
+//! Which build of the emulator made something, like a save state, a movie, or a bug
+//! report. Artifacts keep it as a single line of "key=value" pairs:
+//!
+//!   version=0.1.0 save_state=6 features=debugger mappers=0,1,2,3,5 cpu=2a03
+//!   indirect_jump_bug=true dmc_dma_controller_glitch=true sprite_limit=true
+//!
+//! A frontend checks it with `CoreInfo::check_compatible` before loading the artifact,
+//! so that a movie isn't played back on a core that would run it differently.
+
+use crate::cpu_6502::CpuVariant;
+use crate::emulator::Emulator;
+use crate::mappers::SUPPORTED_MAPPERS;
+use crate::save_state::SAVE_STATE_VERSION;
+
+/// The options that trade accuracy for something else. All of them are on for a new
+/// Emulator.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accuracy {
+    pub cpu_variant: CpuVariant,
+    /// See Cpu6502::has_indirect_jump_bug.
+    pub indirect_jump_bug: bool,
+    /// See Bus::emulate_dmc_dma_controller_glitch.
+    pub dmc_dma_controller_glitch: bool,
+    /// See VideoSettings::sprite_limit. It only changes the picture.
+    pub sprite_limit: bool,
+}
+
+impl Default for Accuracy {
+    fn default() -> Self {
+        Accuracy {
+            cpu_variant: CpuVariant::Ricoh2A03,
+            indirect_jump_bug: true,
+            dmc_dma_controller_glitch: true,
+            sprite_limit: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoreInfo {
+    /// The cpu-6502 crate's version.
+    pub version: String,
+    pub save_state_version: u16,
+    /// The cargo features that were enabled.
+    pub features: Vec<String>,
+    /// The iNES mapper numbers that can be loaded.
+    pub mappers: Vec<u8>,
+    pub accuracy: Accuracy,
+}
+
+/// This build, with the accuracy options of a new Emulator.
+pub fn core_info() -> CoreInfo {
+    let features = [
+        ("debugger", cfg!(feature = "debugger")),
+        ("profile", cfg!(feature = "profile")),
+    ];
+    CoreInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        save_state_version: SAVE_STATE_VERSION,
+        features: features
+            .iter()
+            .filter(|(_, is_enabled)| *is_enabled)
+            .map(|(name, _)| name.to_string())
+            .collect(),
+        mappers: SUPPORTED_MAPPERS.to_vec(),
+        accuracy: Accuracy::default(),
+    }
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Expected true or false for {}, found \"{}\"", key, value))
+}
+
+/// A comma separated list, where nothing is an empty list.
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').filter(|item| !item.is_empty())
+}
+
+impl CoreInfo {
+    pub fn to_text(&self) -> String {
+        let mappers: Vec<String> = self.mappers.iter().map(u8::to_string).collect();
+        format!(
+            "version={} save_state={} features={} mappers={} cpu={} indirect_jump_bug={} \
+             dmc_dma_controller_glitch={} sprite_limit={}",
+            self.version,
+            self.save_state_version,
+            self.features.join(","),
+            mappers.join(","),
+            match self.accuracy.cpu_variant {
+                CpuVariant::Mos6502 => "6502",
+                CpuVariant::Ricoh2A03 => "2a03",
+            },
+            self.accuracy.indirect_jump_bug,
+            self.accuracy.dmc_dma_controller_glitch,
+            self.accuracy.sprite_limit,
+        )
+    }
+
+    /// Parse the line from `to_text`. Keys that are missing keep the values of a new
+    /// Emulator, and keys from newer versions are skipped.
+    pub fn from_text(text: &str) -> Result<CoreInfo, String> {
+        let mut info = CoreInfo {
+            version: String::new(),
+            save_state_version: 0,
+            features: Vec::new(),
+            mappers: Vec::new(),
+            accuracy: Accuracy::default(),
+        };
+        for pair in text.split_whitespace() {
+            let (key, value) = pair.split_once('=').ok_or_else(|| {
+                format!("Expected \"key=value\" but found \"{}\"", pair)
+            })?;
+            match key {
+                "version" => info.version = value.to_string(),
+                "save_state" => {
+                    info.save_state_version = value.parse().map_err(|_| {
+                        format!("Expected a save state version, found \"{}\"", value)
+                    })?
+                }
+                "features" => {
+                    info.features = split_list(value).map(str::to_string).collect()
+                }
+                "mappers" => {
+                    info.mappers = split_list(value)
+                        .map(|mapper| {
+                            mapper.parse().map_err(|_| {
+                                format!("Expected a mapper number, found \"{}\"", mapper)
+                            })
+                        })
+                        .collect::<Result<_, String>>()?
+                }
+                "cpu" => {
+                    info.accuracy.cpu_variant = match value {
+                        "6502" => CpuVariant::Mos6502,
+                        "2a03" => CpuVariant::Ricoh2A03,
+                        _ => {
+                            return Err(format!(
+                                "Expected 6502 or 2a03 for cpu, found \"{}\"",
+                                value
+                            ))
+                        }
+                    }
+                }
+                "indirect_jump_bug" => {
+                    info.accuracy.indirect_jump_bug = parse_bool(key, value)?
+                }
+                "dmc_dma_controller_glitch" => {
+                    info.accuracy.dmc_dma_controller_glitch = parse_bool(key, value)?
+                }
+                "sprite_limit" => info.accuracy.sprite_limit = parse_bool(key, value)?,
+                _ => {}
+            }
+        }
+        if info.version.is_empty() {
+            return Err("The core info is missing its version.".into());
+        }
+        Ok(info)
+    }
+
+    /// Whether something made by another core, e.g. a movie, runs the same way on this
+    /// one. Save states from newer versions can't be read, and the options that change
+    /// what the CPU sees have to match. The sprite limit only changes the picture.
+    pub fn check_compatible(&self, made_by: &CoreInfo) -> Result<(), String> {
+        if made_by.save_state_version > self.save_state_version {
+            return Err(format!(
+                "It was made by version {} of the emulator, which saves states in a \
+                 newer format than this one, {}.",
+                made_by.version, self.version
+            ));
+        }
+        let ours = &self.accuracy;
+        let theirs = &made_by.accuracy;
+        let differences = [
+            ("CPU variant", ours.cpu_variant == theirs.cpu_variant),
+            (
+                "indirect jump bug",
+                ours.indirect_jump_bug == theirs.indirect_jump_bug,
+            ),
+            (
+                "DMC DMA controller glitch",
+                ours.dmc_dma_controller_glitch == theirs.dmc_dma_controller_glitch,
+            ),
+        ];
+        let differences: Vec<&str> = differences
+            .iter()
+            .filter(|(_, is_same)| !is_same)
+            .map(|(name, _)| *name)
+            .collect();
+        if !differences.is_empty() {
+            return Err(format!(
+                "It was made with a different {}, so it would run differently.",
+                differences.join(", ")
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Emulator {
+    /// This build, with the emulator's current accuracy options.
+    pub fn core_info(&self) -> CoreInfo {
+        CoreInfo {
+            accuracy: Accuracy {
+                cpu_variant: self.cpu.variant,
+                indirect_jump_bug: self.cpu.has_indirect_jump_bug,
+                dmc_dma_controller_glitch: self.cpu.bus.emulate_dmc_dma_controller_glitch,
+                sprite_limit: self.cpu.bus.ppu.sprite_limit(),
+            },
+            ..core_info()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mappers::SimpleProgram;
+    use crate::rom::ines_bytes;
+
+    #[test]
+    fn test_core_info() {
+        let mut emulator = Emulator::new(Box::new(SimpleProgram::load(&[])));
+        let info = core_info();
+        assert_eq!(emulator.core_info(), info);
+        assert_eq!(info.version, "0.1.0");
+        assert_eq!(CoreInfo::from_text(&info.to_text()), Ok(info.clone()));
+        for mapper in SUPPORTED_MAPPERS {
+            let rom = ines_bytes(mapper << 4, 2, 1);
+            assert!(Emulator::from_ines_bytes(&rom).is_ok(), "mapper {}", mapper);
+        }
+
+        emulator.cpu.bus.ppu.set_sprite_limit(false);
+        assert_eq!(info.check_compatible(&emulator.core_info()), Ok(()));
+        emulator.cpu.variant = CpuVariant::Mos6502;
+        emulator.cpu.has_indirect_jump_bug = false;
+        let made_by = CoreInfo::from_text(&emulator.core_info().to_text()).unwrap();
+        assert_eq!(made_by, emulator.core_info());
+        assert_eq!(
+            info.check_compatible(&made_by),
+            Err(
+                "It was made with a different CPU variant, indirect jump bug, so it \
+                 would run differently."
+                    .into()
+            )
+        );
+
+        let newer = CoreInfo::from_text("version=9.0.0 save_state=99 extra=1").unwrap();
+        assert_eq!(newer.accuracy, Accuracy::default());
+        assert!(info.check_compatible(&newer).is_err());
+        assert!(CoreInfo::from_text("save_state=5").is_err());
+        assert!(CoreInfo::from_text("version=0.1.0 cpu=z80").is_err());
+    }
+}
@@ -0,0 +1,375 @@
+//! Faults can be injected into the bus while running programs in the test harness, or
+//! a cartridge in the headless runner. This verifies that the emulator and the programs
+//! it runs handle strange conditions without panicking. The faults are driven by a
+//! seeded schedule so that any failure can be reproduced.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use crate::bus::Bus;
+use crate::constants::memory_range;
+use crate::mappers::{Bank, FallbackReport, Mapper};
+use crate::ppu::Mirroring;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// Flip a single bit of the internal RAM, as if it were hit by a cosmic ray. The
+    /// address is mirrored like the CPU's, and addresses above the RAM are left alone.
+    FlipRamBit { address: u16, bit: u8 },
+    /// The next read of the address returns a floating value instead of memory.
+    OpenBusRead { address: u16, value: u8 },
+    /// The mapper is slow to respond, and the next reads of the address return the
+    /// last value that the mapper put on the bus.
+    DelayedMapperResponse { address: u16, reads: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledFault {
+    /// The fault is applied once the CPU has run this many ticks.
+    pub tick: u64,
+    pub fault: Fault,
+}
+
+/// A small xorshift generator, so that the schedule is stable for a given seed.
+struct Xorshift(u64);
+
+impl Xorshift {
+    fn new(seed: u64) -> Xorshift {
+        // Xorshift gets stuck on 0.
+        Xorshift(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn next_in(&mut self, range: Range<u64>) -> u64 {
+        range.start + self.next() % (range.end - range.start)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultSchedule {
+    /// Sorted by tick.
+    faults: Vec<ScheduledFault>,
+}
+
+impl FaultSchedule {
+    pub fn new(mut faults: Vec<ScheduledFault>) -> FaultSchedule {
+        faults.sort_by_key(|scheduled| scheduled.tick);
+        FaultSchedule { faults }
+    }
+
+    /// Create a schedule of random faults. The RAM faults only target the addresses in
+    /// the `ram` range, and the mapper faults target the `rom` range.
+    pub fn random(
+        seed: u64,
+        count: usize,
+        ticks: Range<u64>,
+        ram: Range<u16>,
+        rom: Range<u16>,
+    ) -> FaultSchedule {
+        let mut rng = Xorshift::new(seed);
+        let ram = ram.start as u64..ram.end as u64;
+        let rom = rom.start as u64..rom.end as u64;
+        let faults = (0..count)
+            .map(|_| {
+                let tick = rng.next_in(ticks.clone());
+                let fault = match rng.next_in(0..3) {
+                    0 => Fault::FlipRamBit {
+                        address: rng.next_in(ram.clone()) as u16,
+                        bit: rng.next_in(0..8) as u8,
+                    },
+                    1 => Fault::OpenBusRead {
+                        address: rng.next_in(ram.clone()) as u16,
+                        value: rng.next() as u8,
+                    },
+                    _ => Fault::DelayedMapperResponse {
+                        address: rng.next_in(rom.clone()) as u16,
+                        reads: rng.next_in(1..4) as u8,
+                    },
+                };
+                ScheduledFault { tick, fault }
+            })
+            .collect();
+        FaultSchedule::new(faults)
+    }
+}
+
+/// The faults that are waiting on the next reads from the bus.
+#[derive(Default)]
+struct ReadFaults {
+    open_bus: HashMap<u16, u8>,
+    delayed_reads: HashMap<u16, u8>,
+    last_cartridge_value: u8,
+}
+
+/// Decorate a mapper so that it can intercept reads. The bus asks the cartridge first
+/// for every address, so this is able to inject faults into RAM reads as well. The
+/// faults are shared with the injector through a mutex, as mappers need to be Send.
+pub struct FaultyMapper {
+    inner: Box<dyn Mapper>,
+    read_faults: Arc<Mutex<ReadFaults>>,
+}
+
+impl Mapper for FaultyMapper {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        let mut read_faults = self
+            .read_faults
+            .lock()
+            .expect("The read faults aren't poisoned.");
+        if let Some(value) = read_faults.open_bus.remove(&addr) {
+            return Some(value);
+        }
+        let value = self.inner.read_cpu(addr)?;
+        if let Some(reads) = read_faults.delayed_reads.get_mut(&addr) {
+            if *reads > 0 {
+                *reads -= 1;
+                return Some(read_faults.last_cartridge_value);
+            }
+        }
+        read_faults.last_cartridge_value = value;
+        Some(value)
+    }
+
+    /// Debuggers see the cartridge as it is, and don't use up the faults.
+    fn peek_cpu(&self, addr: u16) -> Option<u8> {
+        self.inner.peek_cpu(addr)
+    }
+
+    fn write_cpu(&mut self, addr: u16, value: u8) -> bool {
+        self.inner.write_cpu(addr, value)
+    }
+
+    // Everything else goes straight through, so a real cartridge still works.
+
+    fn mirroring(&self) -> Mirroring {
+        self.inner.mirroring()
+    }
+
+    fn irq(&self) -> bool {
+        self.inner.irq()
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.inner.read_chr(addr)
+    }
+
+    fn chr_offset(&self, addr: u16) -> Option<usize> {
+        self.inner.chr_offset(addr)
+    }
+
+    fn chr_banks(&self) -> Vec<Bank> {
+        self.inner.chr_banks()
+    }
+
+    fn chr_data(&self) -> &[u8] {
+        self.inner.chr_data()
+    }
+
+    fn prg_banks(&self) -> Vec<(&'static str, Bank)> {
+        self.inner.prg_banks()
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        self.inner.write_chr(addr, value)
+    }
+
+    fn read_nametable(&self, addr: u16) -> Option<u8> {
+        self.inner.read_nametable(addr)
+    }
+
+    fn write_nametable(&mut self, addr: u16, value: u8) -> bool {
+        self.inner.write_nametable(addr, value)
+    }
+
+    fn start_scanline(&mut self, scanline: u64, is_rendering: bool) {
+        self.inner.start_scanline(scanline, is_rendering)
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        self.inner.prg_ram()
+    }
+
+    fn load_prg_ram(&mut self, ram: &[u8]) -> Result<(), String> {
+        self.inner.load_prg_ram(ram)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.inner.save_state()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        self.inner.load_state(state)
+    }
+
+    fn fallback_report(&self) -> Option<FallbackReport> {
+        self.inner.fallback_report()
+    }
+}
+
+/// Applies the fault schedule as the CPU runs.
+pub struct FaultInjector {
+    schedule: FaultSchedule,
+    next_fault: usize,
+    read_faults: Arc<Mutex<ReadFaults>>,
+}
+
+impl FaultInjector {
+    pub fn wrap(
+        cartridge: Box<dyn Mapper>,
+        schedule: FaultSchedule,
+    ) -> (FaultyMapper, FaultInjector) {
+        let read_faults: Arc<Mutex<ReadFaults>> = Default::default();
+        (
+            FaultyMapper {
+                inner: cartridge,
+                read_faults: Arc::clone(&read_faults),
+            },
+            FaultInjector {
+                schedule,
+                next_fault: 0,
+                read_faults,
+            },
+        )
+    }
+
+    /// Apply all of the faults that are due by this tick.
+    pub fn apply(&mut self, bus: &mut Bus, tick: u64) {
+        while let Some(scheduled) = self.schedule.faults.get(self.next_fault) {
+            if scheduled.tick > tick {
+                break;
+            }
+            self.next_fault += 1;
+            match scheduled.fault {
+                Fault::FlipRamBit { address, bit } => {
+                    if address < memory_range::RAM.end {
+                        let index = address & memory_range::RAM_ACTUAL.mask();
+                        bus.ram_mut()[index as usize] ^= 1 << bit;
+                    }
+                }
+                Fault::OpenBusRead { address, value } => {
+                    self.read_faults
+                        .lock()
+                        .expect("The read faults aren't poisoned.")
+                        .open_bus
+                        .insert(address, value);
+                }
+                Fault::DelayedMapperResponse { address, reads } => {
+                    *self
+                        .read_faults
+                        .lock()
+                        .expect("The read faults aren't poisoned.")
+                        .delayed_reads
+                        .entry(address)
+                        .or_default() += reads;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu_6502::test_helpers::*;
+    use crate::mappers::SimpleProgram;
+
+    fn schedule(tick: u64, fault: Fault) -> FaultSchedule {
+        FaultSchedule::new(vec![ScheduledFault { tick, fault }])
+    }
+
+    #[test]
+    fn test_flip_ram_bit() {
+        let text = "
+            lda #$0f
+            sta $10
+            lda $10
+        ";
+        let fault = Fault::FlipRamBit {
+            address: 0x10,
+            bit: 7,
+        };
+        let cpu = run_program_with_faults(text, schedule(2, fault));
+        assert_eq!(cpu.a, 0x8f);
+    }
+
+    #[test]
+    fn test_open_bus_read() {
+        let text = "
+            lda #$0f
+            sta $10
+            lda $10
+            tax
+            lda $10
+        ";
+        let fault = Fault::OpenBusRead {
+            address: 0x10,
+            value: 0x20,
+        };
+        let cpu = run_program_with_faults(text, schedule(2, fault));
+        assert_eq!(cpu.x, 0x20);
+        // Only the first read is faulted.
+        assert_eq!(cpu.a, 0x0f);
+    }
+
+    #[test]
+    fn test_peek_leaves_the_faults() {
+        let fault = Fault::OpenBusRead {
+            address: 0x8000,
+            value: 0x20,
+        };
+        let cartridge = Box::new(SimpleProgram::load(&[0xea]));
+        let (cartridge, mut injector) =
+            FaultInjector::wrap(cartridge, schedule(0, fault));
+        let mut bus = Bus::new(Box::new(cartridge));
+        injector.apply(&mut bus, 0);
+        assert_eq!(bus.peek_u8(0x8000), 0xea);
+        assert_eq!(bus.read_u8(0x8000), 0x20);
+        assert_eq!(bus.read_u8(0x8000), 0xea);
+    }
+
+    #[test]
+    fn test_delayed_mapper_response() {
+        // $8001 holds the #$01, but the mapper responds with the $80 that was last
+        // read from the operand of the second lda.
+        let fault = Fault::DelayedMapperResponse {
+            address: 0x8001,
+            reads: 1,
+        };
+        let cpu = run_program_with_faults("lda #$01\nlda $8001", schedule(1, fault));
+        assert_eq!(cpu.a, 0x80);
+    }
+
+    #[test]
+    fn test_random_schedule_is_deterministic() {
+        let schedule =
+            |seed| FaultSchedule::random(seed, 20, 0..30, 0x10..0x20, 0x8000..0x8010);
+        assert_eq!(schedule(1234), schedule(1234));
+        assert_ne!(schedule(1234), schedule(4321));
+    }
+
+    #[test]
+    fn test_random_faults_do_not_panic() {
+        let text = "
+            ldx #$10
+          loop:
+            lda $10,x
+            adc #$03
+            sta $10,x
+            dex
+            bne loop
+        ";
+        for seed in 0..50 {
+            let schedule =
+                || FaultSchedule::random(seed, 10, 0..40, 0x10..0x20, 0x8000..0x800b);
+            let a = run_program_with_faults(text, schedule());
+            let b = run_program_with_faults(text, schedule());
+            assert_eq!((a.a, a.x, a.p), (b.a, b.x, b.p), "seed {}", seed);
+        }
+    }
+}
@@ -1,4 +1,6 @@
+use crate::bus::CpuBus;
 use crate::cpu_6502::test_helpers::*;
+use crate::cpu_6502::NesCpu;
 
 /// These tests assert the various operations the CPU can do. They use a high-level
 /// API based off of macros to tersely assert the behavior.
@@ -549,3 +551,538 @@ mod zero_page {
   //   sty
   // ");
 }
+
+/// Test stepping the CPU by instructions, scanlines, and frames.
+#[rustfmt::skip]
+mod stepping {
+  use super::*;
+  use crate::cpu_6502::{Step, StepResult};
+
+  // Each jmp takes 3 cycles.
+  const LOOP: &str = "
+    loop:
+      jmp loop
+  ";
+
+  #[test]
+  fn step_instructions() {
+    let mut cpu = load_program(LOOP);
+    assert_eq!(cpu.step(Step::Instructions(5)), StepResult::Done);
+    assert_eq!(cpu.tick_count, 5);
+    assert_eq!(cpu.cycle_count, 15);
+  }
+
+  #[test]
+  fn step_scanlines() {
+    let mut cpu = load_program(LOOP);
+    // 341 dots / 3 dots per cycle = 113.67 cycles.
+    assert_eq!(cpu.step(Step::Scanlines(1)), StepResult::Done);
+    assert_eq!(cpu.cycle_count, 114);
+    // Stepping again goes to the next scanline boundary, and doesn't drift.
+    assert_eq!(cpu.step(Step::Scanlines(1)), StepResult::Done);
+    assert_eq!(cpu.cycle_count, 228);
+    assert_eq!(cpu.step(Step::Scanlines(10)), StepResult::Done);
+    assert_eq!(cpu.cycle_count, 1365);
+  }
+
+  #[test]
+  fn step_frames() {
+    let mut cpu = load_program(LOOP);
+    // 341 * 262 dots / 3 dots per cycle = 29780.67 cycles.
+    assert_eq!(cpu.step(Step::Frames(1)), StepResult::Done);
+    assert_eq!(cpu.cycle_count, 29781);
+  }
+
+  #[test]
+  fn step_dots() {
+    let mut cpu = load_program(LOOP);
+    // A jmp is 9 dots, so a single dot runs one of them.
+    assert_eq!(cpu.step(Step::Dots(1)), StepResult::Done);
+    assert_eq!(cpu.cycle_count, 3);
+    assert_eq!(cpu.step(Step::Dots(10)), StepResult::Done);
+    assert_eq!(cpu.cycle_count, 9);
+    assert_eq!(cpu.bus.ppu.scanline_dot(), 27);
+  }
+
+  #[test]
+  fn step_stops_on_kil() {
+    let mut cpu = load_program("lda #$01");
+    assert_eq!(cpu.step(Step::Frames(1)), StepResult::Kil);
+    assert_eq!(cpu.a, 0x01);
+  }
+
+  const SUBROUTINES: &str = "
+      jsr outer    ; $8000
+      lda #$02     ; $8003
+    loop:
+      jmp loop     ; $8005
+    outer:
+      ldx #$01     ; $8008
+      jsr inner    ; $800a
+      rts          ; $800d
+    inner:
+      iny          ; $800e
+      rts          ; $800f
+  ";
+
+  #[test]
+  fn step_over() {
+    let mut cpu = load_program(SUBROUTINES);
+    assert_eq!(cpu.step(Step::Over), StepResult::Done);
+    assert_eq!((cpu.pc, cpu.s), (0x8003, 0xff));
+    assert_eq!((cpu.x, cpu.y), (0x01, 0x01));
+    // Anything else is a single instruction.
+    assert_eq!(cpu.step(Step::Over), StepResult::Done);
+    assert_eq!((cpu.pc, cpu.a), (0x8005, 0x02));
+  }
+
+  #[test]
+  fn step_out() {
+    let mut cpu = load_program(SUBROUTINES);
+    assert_eq!(cpu.step(Step::ToAddress(0x800e)), StepResult::Done);
+    assert_eq!(cpu.s, 0xfb);
+    assert_eq!(cpu.step(Step::Out), StepResult::Done);
+    assert_eq!((cpu.pc, cpu.s), (0x800d, 0xfd));
+    assert_eq!(cpu.step(Step::Out), StepResult::Done);
+    assert_eq!((cpu.pc, cpu.s), (0x8003, 0xff));
+  }
+
+  #[test]
+  fn step_out_past_the_stack() {
+    // The subroutine moves the stack around the call, which doesn't fool the step.
+    let mut cpu = load_program(
+      "
+        jsr outer    ; $8000
+      loop:
+        jmp loop     ; $8003
+      outer:
+        pha          ; $8006
+        pla          ; $8007
+        jsr inner    ; $8008
+        rts          ; $800b
+      inner:
+        rts          ; $800c
+      ",
+    );
+    assert_eq!(cpu.step(Step::ToAddress(0x8007)), StepResult::Done);
+    assert_eq!(cpu.step(Step::Out), StepResult::Done);
+    assert_eq!((cpu.pc, cpu.s), (0x8003, 0xff));
+  }
+
+  #[test]
+  fn step_to_address() {
+    let mut cpu = load_program(SUBROUTINES);
+    assert_eq!(cpu.step(Step::ToAddress(0x8005)), StepResult::Done);
+    assert_eq!(cpu.pc, 0x8005);
+    // It runs at least one instruction, so it goes around the loop.
+    let tick_count = cpu.tick_count;
+    assert_eq!(cpu.step(Step::ToAddress(0x8005)), StepResult::Done);
+    assert_eq!(cpu.tick_count, tick_count + 1);
+  }
+
+  #[test]
+  fn step_times_out() {
+    // The subroutine never returns, so stepping over it gives up.
+    let mut cpu = load_program(
+      "
+        jsr forever  ; $8000
+        lda #$01     ; $8003
+      forever:
+        jmp forever  ; $8005
+      ",
+    );
+    assert_eq!(cpu.step(Step::Over), StepResult::TimedOut);
+    assert_eq!(cpu.pc, 0x8005);
+    assert_eq!(cpu.step(Step::ToAddress(0x8003)), StepResult::TimedOut);
+    assert_eq!(cpu.step(Step::Out), StepResult::TimedOut);
+  }
+}
+
+/// Test the cycle counts of single instructions against the published timing tables,
+/// including the extra cycles for crossing pages and taking branches.
+/// http://www.6502.org/tutorials/6502opcodes.html
+#[rustfmt::skip]
+mod cycles {
+  use super::*;
+  use crate::cpu_6502::{Step, StepResult};
+
+  /// Run the setup, and then count the cycles of the instruction that follows it.
+  fn instruction_cycles(setup: &str, instruction: &str) -> u64 {
+    let setup_len = setup
+      .lines()
+      .map(str::trim)
+      .filter(|line| !line.is_empty() && !line.ends_with(':'))
+      .count();
+    let mut cpu = load_program(&format!("{}\n{}", setup, instruction));
+    assert_eq!(cpu.step(Step::Instructions(setup_len as u64)), StepResult::Done);
+    let cycle_count = cpu.cycle_count;
+    assert_eq!(cpu.step(Step::Instructions(1)), StepResult::Done);
+    cpu.cycle_count - cycle_count
+  }
+
+  #[test]
+  fn reads() {
+    assert_eq!(instruction_cycles("", "lda #$01"), 2);
+    assert_eq!(instruction_cycles("", "lda $10"), 3);
+    assert_eq!(instruction_cycles("ldx #$01", "lda $10,x"), 4);
+    assert_eq!(instruction_cycles("", "lda $0210"), 4);
+    assert_eq!(instruction_cycles("ldx #$01", "lda $0210,x"), 4);
+    assert_eq!(instruction_cycles("ldx #$01", "lda $02ff,x"), 5);
+    assert_eq!(instruction_cycles("ldy #$01", "lda $0210,y"), 4);
+    assert_eq!(instruction_cycles("ldy #$01", "lda $02ff,y"), 5);
+    assert_eq!(instruction_cycles("ldx #$01", "lda ($10,x)"), 6);
+    assert_eq!(instruction_cycles("ldx #$01", "ldy $02ff,x"), 5);
+    assert_eq!(instruction_cycles("ldy #$01", "ldx $02ff,y"), 5);
+    assert_eq!(instruction_cycles("ldx #$01", "cmp $02ff,x"), 5);
+  }
+
+  #[test]
+  fn indirect_indexed() {
+    let setup = "
+      lda #$10
+      sta $20
+      lda #$02
+      sta $21
+      lda #$ff
+      sta $22
+      lda #$02
+      sta $23
+      ldy #$01
+    ";
+    // ($20) points to $0210, and ($22) points to $02ff.
+    assert_eq!(instruction_cycles(setup, "lda ($20),y"), 5);
+    assert_eq!(instruction_cycles(setup, "lda ($22),y"), 6);
+    assert_eq!(instruction_cycles(setup, "adc ($22),y"), 6);
+    // Stores always take the extra cycle.
+    assert_eq!(instruction_cycles(setup, "sta ($20),y"), 6);
+    assert_eq!(instruction_cycles(setup, "sta ($22),y"), 6);
+  }
+
+  #[test]
+  fn writes_do_not_pay_for_pages() {
+    assert_eq!(instruction_cycles("ldx #$01", "sta $0210,x"), 5);
+    assert_eq!(instruction_cycles("ldx #$01", "sta $02ff,x"), 5);
+    assert_eq!(instruction_cycles("ldy #$01", "sta $02ff,y"), 5);
+    assert_eq!(instruction_cycles("ldx #$01", "inc $0210,x"), 7);
+    assert_eq!(instruction_cycles("ldx #$01", "inc $02ff,x"), 7);
+    assert_eq!(instruction_cycles("ldx #$01", "asl $02ff,x"), 7);
+  }
+
+  #[test]
+  fn branches() {
+    // Not taken.
+    assert_eq!(instruction_cycles("ldx #$00", "bne end\nend:"), 2);
+    // Taken, to the same page.
+    assert_eq!(instruction_cycles("ldx #$01", "bne end\nnop\nend:"), 3);
+    // Taken, from the next instruction at $80ff to $8102.
+    let setup = format!("ldx #$01\n{}", "nop\n".repeat(0xfb));
+    assert_eq!(instruction_cycles(&setup, "bne end\nnop\nnop\nnop\nend:"), 4);
+    // Not taken across the page, it doesn't matter where the branch would have gone.
+    let setup = format!("ldx #$00\n{}", "nop\n".repeat(0xfb));
+    assert_eq!(instruction_cycles(&setup, "bne end\nnop\nnop\nnop\nend:"), 2);
+  }
+
+  #[test]
+  fn jumps_and_subroutines() {
+    assert_eq!(instruction_cycles("", "jmp end\nend:"), 3);
+    assert_eq!(instruction_cycles("", "jsr end\nend:"), 6);
+    assert_eq!(instruction_cycles("jsr sub\nsub:", "rts"), 6);
+  }
+}
+
+/// Test the binary-coded decimal arithmetic of the original 6502, with the cases from:
+/// http://www.6502.org/tutorials/decimal_mode.html
+#[rustfmt::skip]
+mod decimal_mode {
+  use super::*;
+  use crate::cpu_6502::CpuVariant;
+
+  fn assert_decimal(text: &str, value: u8, status: u8) {
+    let mut cpu = load_program_as(text, CpuVariant::Mos6502);
+    cpu.run();
+    assert_eq!(cpu.a, value, "{}", text);
+    assert_status(&cpu, status);
+  }
+
+  #[test]
+  fn adc() {
+    assert_decimal("sed\nclc\nlda #$12\nadc #$34", 0x46, P | D);
+    assert_decimal("sed\nclc\nlda #$15\nadc #$26", 0x41, P | D);
+    assert_decimal("sed\nclc\nlda #$09\nadc #$01", 0x10, P | D);
+    // N and V come from the $a5 before the high digit is carried.
+    assert_decimal("sed\nsec\nlda #$58\nadc #$46", 0x05, P | D | C | N | V);
+    assert_decimal("sed\nclc\nlda #$81\nadc #$92", 0x73, P | D | C | V);
+    // The zero flag is from the binary sum $9a, and the negative flag is from $a0,
+    // before the high digit is carried.
+    assert_decimal("sed\nclc\nlda #$99\nadc #$01", 0x00, P | D | C | N);
+    // Invalid BCD digits still go through the same adjustments.
+    assert_decimal("sed\nclc\nlda #$0f\nadc #$01", 0x16, P | D);
+  }
+
+  #[test]
+  fn sbc() {
+    assert_decimal("sed\nsec\nlda #$46\nsbc #$12", 0x34, P | D | C);
+    assert_decimal("sed\nsec\nlda #$40\nsbc #$13", 0x27, P | D | C);
+    assert_decimal("sed\nclc\nlda #$32\nsbc #$02", 0x29, P | D | C);
+    assert_decimal("sed\nsec\nlda #$12\nsbc #$21", 0x91, P | D | N);
+    assert_decimal("sed\nsec\nlda #$21\nsbc #$34", 0x87, P | D | N);
+    assert_decimal("sed\nsec\nlda #$00\nsbc #$01", 0x99, P | D | N);
+    assert_decimal("sed\nsec\nlda #$50\nsbc #$50", 0x00, P | D | C | Z);
+  }
+
+  #[test]
+  fn binary_without_decimal_flag() {
+    assert_decimal("clc\nlda #$09\nadc #$01", 0x0a, P);
+  }
+
+  #[test]
+  fn ricoh_2a03_ignores_decimal_flag() {
+    assert_register_a("sed\nclc\nlda #$09\nadc #$01", 0x0a, P | D);
+    assert_register_a("sed\nsec\nlda #$10\nsbc #$01", 0x0f, P | D | C);
+  }
+}
+
+/// Test that addresses wrap around at the edges of memory rather than overflowing.
+#[rustfmt::skip]
+mod memory_edges {
+  use super::*;
+  use crate::bus::Bus;
+  use crate::cpu_6502::{Cpu6502, CpuVariant};
+  use crate::mappers::SimpleProgram;
+  use crate::opcodes::OpCode;
+
+  /// Load bytes into the program space starting at $8000, and start running at `pc`.
+  fn load_at(pc: u16, program: &[(u16, &[u8])]) -> Cpu6502<Bus> {
+    let mut bytes = vec![OpCode::KIL as u8; 0x8000];
+    for (address, values) in program {
+      for (i, value) in values.iter().enumerate() {
+        bytes[(*address - 0x8000) as usize + i] = *value;
+      }
+    }
+    let mut cpu = Cpu6502::new(
+      Bus::new(Box::new(SimpleProgram::load(&bytes))),
+      CpuVariant::Ricoh2A03,
+    );
+    cpu.pc = pc;
+    cpu
+  }
+
+  #[test]
+  fn pc_wraps_to_zero_page() {
+    // lda #$42 where the opcode is at $FFFF, and the operand is at $0000.
+    let mut cpu = load_at(0xffff, &[(0xffff, &[0xa9])]);
+    cpu.bus.set_u8(0x0000, 0x42);
+    cpu.tick();
+    assert_eq!(cpu.a, 0x42);
+    assert_eq!(cpu.pc, 0x0001);
+  }
+
+  #[test]
+  fn operand_crosses_page() {
+    // lda $0010 where the operand straddles $80FF and $8100.
+    let mut cpu = load_at(0x80fe, &[(0x80fe, &[0xad, 0x10, 0x00])]);
+    cpu.bus.set_u8(0x0010, 0x42);
+    cpu.tick();
+    assert_eq!(cpu.a, 0x42);
+    assert_eq!(cpu.pc, 0x8101);
+  }
+
+  #[test]
+  fn absolute_indexed_wraps() {
+    // ldx #$02, lda $ffff,x reads $0001.
+    let mut cpu = load_at(0x8000, &[(0x8000, &[0xa2, 0x02, 0xbd, 0xff, 0xff])]);
+    cpu.bus.set_u8(0x0001, 0x42);
+    cpu.run();
+    assert_eq!(cpu.a, 0x42);
+  }
+
+  #[test]
+  fn indirect_indexed_wraps() {
+    // ldy #$02, lda ($10),y where $10 points to $ffff, reads $0001.
+    let mut cpu = load_at(0x8000, &[(0x8000, &[0xa0, 0x02, 0xb1, 0x10])]);
+    cpu.bus.set_u16(0x0010, 0xffff);
+    cpu.bus.set_u8(0x0001, 0x42);
+    cpu.run();
+    assert_eq!(cpu.a, 0x42);
+  }
+
+  /// jmp ($02ff), where $02ff = $34, $0200 = $12, and $0300 = $56.
+  fn load_indirect_jump() -> Cpu6502<Bus> {
+    let mut cpu = load_at(0x8000, &[(0x8000, &[0x6c, 0xff, 0x02])]);
+    {
+      let bus = &mut cpu.bus;
+      bus.set_u8(0x02ff, 0x34);
+      bus.set_u8(0x0200, 0x12);
+      bus.set_u8(0x0300, 0x56);
+    }
+    cpu
+  }
+
+  #[test]
+  fn jmp_indirect_wraps_within_page() {
+    let mut cpu = load_indirect_jump();
+    cpu.tick();
+    assert_eq!(cpu.pc, 0x1234);
+  }
+
+  #[test]
+  fn jmp_indirect_without_bug() {
+    let mut cpu = load_indirect_jump();
+    cpu.has_indirect_jump_bug = false;
+    cpu.tick();
+    assert_eq!(cpu.pc, 0x5634);
+  }
+
+  #[test]
+  fn jmp_indirect_inside_page() {
+    // jmp ($0210) isn't affected either way.
+    for has_bug in [true, false].iter() {
+      let mut cpu = load_at(0x8000, &[(0x8000, &[0x6c, 0x10, 0x02])]);
+      cpu.bus.set_u16(0x0210, 0x1234);
+      cpu.has_indirect_jump_bug = *has_bug;
+      cpu.tick();
+      assert_eq!(cpu.pc, 0x1234);
+    }
+  }
+
+  #[test]
+  fn branch_from_next_instruction() {
+    // ldx #$03, dex, bne -3 goes back to the dex, as the offset is from the
+    // instruction after the branch.
+    let mut cpu = load_at(0x8000, &[(0x8000, &[0xa2, 0x03, 0xca, 0xd0, 0xfd])]);
+    cpu.run();
+    assert_eq!(cpu.x, 0);
+    assert_eq!(cpu.pc, 0x8005);
+    // ldx 2, dex 2 * 3, bne taken 3 * 2, bne not taken 2
+    assert_eq!(cpu.cycle_count, 2 + 6 + 6 + 2);
+  }
+
+  #[test]
+  fn branch_crosses_page_of_next_instruction() {
+    // ldx #$02 at $80FB, dex at $80FD, bne -3 at $80FE. The branch goes back to
+    // the dex on the branch's own page, but that's a page away from $8100.
+    let mut cpu = load_at(0x80fb, &[(0x80fb, &[0xa2, 0x02, 0xca, 0xd0, 0xfd])]);
+    cpu.run();
+    assert_eq!(cpu.pc, 0x8100);
+    // ldx 2, dex 2 * 2, bne taken across the page 4, bne not taken 2
+    assert_eq!(cpu.cycle_count, 2 + 4 + 4 + 2);
+  }
+
+  #[test]
+  fn stack_wraps_within_page() {
+    let cpu = run_program("
+      ldx #$00
+      txs
+      jsr sub
+      jmp end
+    sub:
+      rts
+    end:
+    ");
+    let bus = &cpu.bus;
+    // The return address $8006 was pushed to $0100 and then $01FF, it didn't leak into
+    // the zero page.
+    assert_eq!(bus.read_u8(0x0100), 0x80);
+    assert_eq!(bus.read_u8(0x01ff), 0x06);
+    assert_eq!(bus.read_u8(0x00ff), 0x00);
+    assert_eq!(cpu.s, 0x00);
+  }
+}
+
+/// Test servicing the IRQ line between instructions.
+#[rustfmt::skip]
+mod interrupts {
+  use super::*;
+  use crate::cpu_6502::{Step, StepResult};
+  use crate::irq::IrqSource;
+
+  // The SimpleProgram's IRQ vector is $0000, so write "ldx #$42, kil" there.
+  const PROGRAM: &str = "
+      lda #$a2
+      sta $00
+      lda #$42
+      sta $01
+      lda #$02
+      sta $02
+      cli
+    loop:
+      jmp loop
+  ";
+
+  #[test]
+  fn irq_runs_handler() {
+    let mut cpu = load_program(PROGRAM);
+    assert_eq!(cpu.step(Step::Instructions(10)), StepResult::Done);
+    assert_eq!(cpu.pc, 0x800d);
+    cpu.bus.irq.assert(IrqSource::ApuFrameCounter);
+    cpu.run();
+    assert_eq!(cpu.x, 0x42);
+    assert_eq!(cpu.p & I, I);
+
+    let bus = &cpu.bus;
+    // The return address, then the status without the break flag.
+    assert_eq!(bus.read_u16(0x01fe), 0x800d);
+    assert_eq!(bus.read_u8(0x01fd), T);
+  }
+
+  #[test]
+  fn irq_is_masked() {
+    let mut cpu = load_program(PROGRAM);
+    assert_eq!(cpu.step(Step::Instructions(6)), StepResult::Done);
+    cpu.bus.irq.assert(IrqSource::Dmc);
+    assert_eq!(cpu.step(Step::Instructions(1)), StepResult::Done);
+    // The line is sampled before the cli runs, so the loop is reached first.
+    assert_eq!(cpu.pc, 0x800d);
+    assert_eq!(cpu.step(Step::Instructions(1)), StepResult::Done);
+    assert_eq!(cpu.pc, 0x0000);
+  }
+}
+
+/// The core runs the same on the NES's bus as on a bare 64KB of RAM.
+#[rustfmt::skip]
+mod ram_bus {
+  use super::*;
+  use crate::bus::Bus;
+  use crate::cpu_6502::{Cpu6502, CpuVariant};
+  use crate::mappers::SimpleProgram;
+  use crate::ram_bus::RamBus;
+
+  #[test]
+  fn same_results_on_both_machines() {
+    // Sum 1 to 10 into $10, then copy it into what's PRG RAM and the APU's
+    // registers on the NES.
+    let bytes = assemble("
+      ldx #10
+      lda #0
+      loop:
+      clc
+      stx $11
+      adc $11
+      dex
+      bne loop
+      sta $10
+      sta $6000
+      sta $4000
+    ");
+
+    let mut cpu =
+      Cpu6502::new(RamBus::load(&bytes, 0x8000).unwrap(), CpuVariant::Mos6502);
+    cpu.run();
+    let mut nes = Cpu6502::new(
+      Bus::new(Box::new(SimpleProgram::load(&bytes))),
+      CpuVariant::Ricoh2A03,
+    );
+    nes.run();
+
+    assert_eq!((cpu.a, cpu.x, cpu.p), (nes.a, nes.x, nes.p));
+    assert_eq!(cpu.cycle_count, nes.cycle_count);
+    assert_eq!(cpu.bus.memory()[0x10], 55);
+    assert_eq!(nes.bus.read_u8(0x10), 55);
+    // Only the RAM machine keeps the writes to the registers.
+    assert_eq!(cpu.bus.memory()[0x4000], 55);
+    assert_eq!(cpu.bus.memory()[0x6000], 55);
+    assert_eq!(nes.bus.peek_u8(0x6000), 0);
+
+    assert!(RamBus::load(&[0; 4], 0xfffe).is_err());
+  }
+}
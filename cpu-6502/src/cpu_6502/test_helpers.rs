@@ -1,7 +1,10 @@
 #![macro_use]
 
 use crate::bus::Bus;
+use crate::cpu_6502::fault_injection::{FaultInjector, FaultSchedule};
 use crate::cpu_6502::*;
+use crate::emulator::Emulator;
+use crate::opcodes::OpCode;
 use crate::{
     asm::{AsmLexer, BytesLabels},
     mappers::SimpleProgram,
@@ -21,18 +24,14 @@ pub const T: u8 = StatusFlag::Push as u8;
 pub const V: u8 = StatusFlag::Overflow as u8;
 pub const N: u8 = StatusFlag::Negative as u8;
 
-pub fn run_program(text: &str) -> Cpu6502 {
+pub fn assemble(text: &str) -> Vec<u8> {
     let mut lexer = AsmLexer::new(text);
 
     match lexer.parse() {
         Ok(_) => {
             let BytesLabels { mut bytes, .. } = lexer.into_bytes().unwrap();
             bytes.push(OpCode::KIL as u8);
-            let mut cpu =
-                Cpu6502::new(Bus::new_shared_bus(Box::new(SimpleProgram::load(&bytes))));
-
-            cpu.run();
-            cpu
+            bytes
         }
         Err(parse_error) => {
             parse_error.panic_nicely();
@@ -41,6 +40,64 @@ pub fn run_program(text: &str) -> Cpu6502 {
     }
 }
 
+pub fn load_program(text: &str) -> Cpu6502<Bus> {
+    load_program_as(text, CpuVariant::Ricoh2A03)
+}
+
+pub fn load_program_as(text: &str, variant: CpuVariant) -> Cpu6502<Bus> {
+    let bytes = assemble(text);
+    Cpu6502::new(Bus::new(Box::new(SimpleProgram::load(&bytes))), variant)
+}
+
+pub fn run_program(text: &str) -> Cpu6502<Bus> {
+    let mut cpu = load_program(text);
+    cpu.run();
+    cpu
+}
+
+/// An emulator running the program from $8000, for the tests that run whole frames.
+pub fn emulator_from_asm(text: &str) -> Emulator {
+    Emulator::new(Box::new(SimpleProgram::load(&assemble(text))))
+}
+
+/// Add up the first button read from each controller into $10 and $11, so any change
+/// to the input changes the RAM from then on.
+pub const SUM_CONTROLLERS: &str = "
+    loop:
+    lda #$01
+    sta $4016
+    lda #$00
+    sta $4016
+    lda $4016
+    clc
+    adc $10
+    sta $10
+    lda $4017
+    clc
+    adc $11
+    sta $11
+    jmp loop
+";
+
+/// Faults can send a program into an infinite loop, so stop it eventually.
+const MAX_FAULTY_TICKS: u64 = 10_000;
+
+/// Run a program while injecting faults into the bus according to the schedule.
+pub fn run_program_with_faults(text: &str, schedule: FaultSchedule) -> Cpu6502<Bus> {
+    let bytes = assemble(text);
+    let (mapper, mut injector) =
+        FaultInjector::wrap(Box::new(SimpleProgram::load(&bytes)), schedule);
+    let mut cpu = Cpu6502::new(Bus::new(Box::new(mapper)), CpuVariant::Ricoh2A03);
+
+    loop {
+        injector.apply(&mut cpu.bus, cpu.tick_count);
+        if !cpu.tick() || cpu.tick_count > MAX_FAULTY_TICKS {
+            break;
+        }
+    }
+    cpu
+}
+
 /// Run two's complement on a u8.
 pub fn negative(n: u8) -> u8 {
     !n + 1
@@ -79,7 +136,7 @@ pub fn assert_register_y(text: &str, value: u8, status: u8) {
     assert_status(&cpu, status);
 }
 
-pub fn assert_status(cpu: &Cpu6502, value: u8) {
+pub fn assert_status(cpu: &Cpu6502<Bus>, value: u8) {
     let mut result = String::new();
 
     let expected_carry = value & StatusFlag::Carry as u8 == StatusFlag::Carry as u8;
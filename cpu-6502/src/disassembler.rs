@@ -0,0 +1,209 @@
+//! Turns the bytes in memory back into instructions, for the debuggers. The fields are
+//! kept separate so that each frontend can style them however it likes.
+
+use crate::asm::AddressToLabel;
+use crate::bus::Bus;
+use crate::cpu_6502::history::InstructionHistory;
+use crate::opcodes::{Mode, ADDRESSING_MODE_TABLE, OPCODE_STRING_TABLE};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisassembledInstruction {
+    pub address: u16,
+    /// The opcode, followed by the operand bytes.
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub mode: Mode,
+    /// The operand as it's written in assembly, e.g. "#$01" or "($20),y". Branches
+    /// show their offset, e.g. "-4". It's empty when there is no operand.
+    pub operand: String,
+    /// The address that a jump, branch, or absolute operand points at.
+    pub target: Option<u16>,
+    /// The label at this instruction's address.
+    pub label: Option<String>,
+    /// The label at the target address.
+    pub target_label: Option<String>,
+    /// A note about the instruction, see `Annotations`.
+    pub comment: Option<String>,
+}
+
+impl DisassembledInstruction {
+    /// The address of the instruction that follows this one in memory.
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.bytes.len() as u16)
+    }
+
+    /// A single line of text, e.g. "$8004 bne loop -4".
+    pub fn to_text(&self) -> String {
+        let mut text = format!("${:04x} {}", self.address, self.mnemonic);
+        if let Some(ref target_label) = self.target_label {
+            text.push(' ');
+            text.push_str(target_label);
+        }
+        if !self.operand.is_empty() {
+            text.push(' ');
+            text.push_str(&self.operand);
+        }
+        if let Some(ref comment) = self.comment {
+            text.push_str(" ; ");
+            text.push_str(comment);
+        }
+        text
+    }
+}
+
+/// The instructions as a listing to save, with each label on its own line.
+pub fn listing(instructions: &[DisassembledInstruction]) -> String {
+    let mut text = String::new();
+    for instruction in instructions {
+        if let Some(ref label) = instruction.label {
+            text.push_str(label);
+            text.push_str(":\n");
+        }
+        text.push_str("  ");
+        text.push_str(&instruction.to_text());
+        text.push('\n');
+    }
+    text
+}
+
+/// An address like $0600 or 0x0600, as the debuggers and tools take it.
+pub fn parse_address(text: &str) -> Result<u16, String> {
+    text.strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| format!("Expected an address like $8000, found \"{}\"", text))
+}
+
+/// Disassemble the single instruction at `address`. The memory is read through a
+/// function, so that the instructions in the history can be decoded from their own
+/// bytes.
+pub fn disassemble_instruction(
+    read_u8: impl Fn(u16) -> u8,
+    address: u16,
+    labels: Option<&AddressToLabel>,
+) -> DisassembledInstruction {
+    let opcode = read_u8(address);
+    let mode = ADDRESSING_MODE_TABLE[opcode as usize];
+    let bytes: Vec<u8> = (0..=mode.operand_len() as u16)
+        .map(|offset| read_u8(address.wrapping_add(offset)))
+        .collect();
+    let u8_operand = bytes.get(1).copied().unwrap_or(0);
+    let u16_operand =
+        u16::from_le_bytes([u8_operand, bytes.get(2).copied().unwrap_or(0)]);
+
+    let (operand, target) = match mode {
+        Mode::Absolute => (format!("${:04x}", u16_operand), Some(u16_operand)),
+        Mode::AbsoluteIndexedX => (format!("${:04x},x", u16_operand), Some(u16_operand)),
+        Mode::AbsoluteIndexedY => (format!("${:04x},y", u16_operand), Some(u16_operand)),
+        Mode::Indirect => (format!("(${:04x})", u16_operand), Some(u16_operand)),
+        Mode::Immediate => (format!("#${:02x}", u8_operand), None),
+        Mode::ZeroPage => (format!("${:02x}", u8_operand), None),
+        Mode::ZeroPageX => (format!("${:02x},x", u8_operand), None),
+        Mode::ZeroPageY => (format!("${:02x},y", u8_operand), None),
+        Mode::IndirectX => (format!("(${:02x},x)", u8_operand), None),
+        Mode::IndirectY => (format!("(${:02x}),y", u8_operand), None),
+        Mode::Relative => {
+            // Branches are offset from the next instruction, 2 bytes past the branch.
+            let offset = u8_operand as i8;
+            (
+                format!("{:+}", offset),
+                Some(address.wrapping_add(2).wrapping_add(offset as u16)),
+            )
+        }
+        Mode::Implied | Mode::None | Mode::RegisterA => (String::new(), None),
+    };
+
+    let label_at = |address: u16| labels.and_then(|labels| labels.get(&address)).cloned();
+    DisassembledInstruction {
+        address,
+        bytes,
+        mnemonic: OPCODE_STRING_TABLE[opcode as usize],
+        mode,
+        operand,
+        target,
+        label: label_at(address),
+        target_label: target.and_then(label_at),
+        comment: None,
+    }
+}
+
+/// The most recent `count` instructions that ran, as text, oldest first. They're
+/// decoded from the bytes that ran, rather than what's in memory now.
+pub fn disassemble_history(history: &InstructionHistory, count: usize) -> Vec<String> {
+    history
+        .last(count)
+        .map(|instruction| {
+            disassemble_instruction(
+                |address| instruction.read_u8(address),
+                instruction.address,
+                None,
+            )
+            .to_text()
+        })
+        .collect()
+}
+
+/// Disassemble `count` instructions forward from `pc`. The bus is read with
+/// `peek_u8`, so the registers aren't disturbed.
+pub fn disassemble(bus: &Bus, pc: u16, count: usize) -> Vec<DisassembledInstruction> {
+    disassemble_with_labels(bus, pc, count, None)
+}
+
+/// The same as `disassemble`, with the labels from the assembler filled in.
+pub fn disassemble_with_labels(
+    bus: &Bus,
+    pc: u16,
+    count: usize,
+    labels: Option<&AddressToLabel>,
+) -> Vec<DisassembledInstruction> {
+    let mut instructions = Vec::with_capacity(count);
+    let mut address = pc;
+    while instructions.len() < count {
+        let instruction =
+            disassemble_instruction(|address| bus.peek_u8(address), address, labels);
+        address = instruction.next_address();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mappers::SimpleProgram;
+
+    #[test]
+    fn test_disassemble() {
+        // $8000: lda #$01, $8002: sta $0200,x, $8005: bne $8000, $8007: jmp ($1234)
+        let program = [0xa9, 0x01, 0x9d, 0x00, 0x02, 0xd0, 0xf9, 0x6c, 0x34, 0x12];
+        let bus = Bus::new(Box::new(SimpleProgram::load(&program)));
+        let mut labels = AddressToLabel::new();
+        labels.insert(0x8000, "loop".to_string());
+
+        let instructions = disassemble_with_labels(&bus, 0x8000, 4, Some(&labels));
+        let text: Vec<String> = instructions.iter().map(|i| i.to_text()).collect();
+        assert_eq!(
+            text,
+            [
+                "$8000 lda #$01",
+                "$8002 sta $0200,x",
+                "$8005 bne loop -7",
+                "$8007 jmp ($1234)",
+            ]
+        );
+        assert_eq!(instructions[0].label.as_deref(), Some("loop"));
+        assert_eq!(instructions[1].bytes, [0x9d, 0x00, 0x02]);
+        assert_eq!(instructions[2].target, Some(0x8000));
+        assert_eq!(instructions[3].next_address(), 0x800a);
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(parse_address("$0600"), Ok(0x0600));
+        assert_eq!(parse_address("0x00FF"), Ok(0x00ff));
+        assert!(parse_address("$10000").is_err());
+        assert!(parse_address("$").is_err());
+        assert!(parse_address("8000").is_err());
+        assert!(parse_address("loop").is_err());
+    }
+}
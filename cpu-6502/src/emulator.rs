@@ -1,26 +1,535 @@
-use std::rc::Rc;
+use std::collections::VecDeque;
 
-use crate::cpu_6502::Cpu6502;
-use crate::ppu::Ppu;
-use crate::{
-    bus::{Bus, SharedBus},
-    mappers::Mapper,
-};
+use crate::cpu_6502::{Cpu6502, CpuVariant, NesCpu, Step, StepResult};
+use crate::movie::{Movie, MovieState};
+use crate::power_up::PowerUpPreset;
+use crate::save_state::SaveState;
+use crate::watchdog::Watchdog;
+use crate::write_log::LoggedWrite;
+use crate::{bus::Bus, mappers::Mapper, rom::InesRom};
 
+/// 10 seconds at 60fps, a good depth for frontends that turn on rewinding.
+pub const DEFAULT_REWIND_FRAMES: usize = 600;
+
+/// A stem render that's run a piece at a time, see `Emulator::start_stems`.
+pub struct StemsRender {
+    state: Vec<u8>,
+    rewind: VecDeque<RewindState>,
+    rewind_capacity: usize,
+    pub frames: u64,
+    pub rendered_frames: u64,
+}
+
+impl StemsRender {
+    /// From 0 to 1.
+    pub fn progress(&self) -> f32 {
+        if self.frames == 0 {
+            return 1.0;
+        }
+        self.rendered_frames as f32 / self.frames as f32
+    }
+}
+
+/// The CPU's cycle_count is kept next to the state, for finding the frame that a write
+/// happened in without loading every state.
+type RewindState = (u64, Vec<u8>);
+
+/// The whole machine, for frontends that just want to run a cartridge and show the
+/// frames. The PPU lives on the CPU's bus, and is stepped along with the CPU. Nothing
+/// in it is shared, so it can be moved to a worker thread.
 pub struct Emulator {
-    pub bus: SharedBus,
-    pub cpu: Cpu6502,
-    pub ppu: Ppu,
+    pub cpu: Cpu6502<Bus>,
+    /// Checks the CPU after every instruction for a program that has crashed. Frames
+    /// aren't run while it's tripped.
+    pub watchdog: Option<Watchdog>,
+    /// A save state from the start of each of the most recent frames, oldest first.
+    rewind: VecDeque<RewindState>,
+    rewind_capacity: usize,
+    movie: Option<MovieState>,
 }
 
 impl Emulator {
     pub fn new(cartridge: Box<dyn Mapper>) -> Emulator {
-        let bus = Bus::new_shared_bus(cartridge);
+        let mut bus = Bus::new(cartridge);
+        bus.power_up(PowerUpPreset::default());
         Emulator {
-            cpu: Cpu6502::new(Rc::clone(&bus)),
-            ppu: Ppu::new(Rc::clone(&bus)),
-            // Take ownership of the initial bus.
-            bus,
+            cpu: Cpu6502::new(bus, CpuVariant::Ricoh2A03),
+            watchdog: None,
+            rewind: VecDeque::new(),
+            rewind_capacity: 0,
+            movie: None,
+        }
+    }
+
+    /// Boot the contents of a .nes file.
+    pub fn from_ines_bytes(bytes: &[u8]) -> Result<Emulator, String> {
+        let mapper = InesRom::from_ines_bytes(bytes)?.into_mapper()?;
+        Ok(Emulator::new(mapper))
+    }
+
+    /// Run until the PPU finishes the current frame, or the CPU halts.
+    #[cfg_attr(feature = "profile", tracing::instrument(skip_all))]
+    pub fn run_frame(&mut self) {
+        if self.watchdog.as_ref().is_some_and(Watchdog::is_tripped) {
+            return;
+        }
+        if self.rewind_capacity > 0 {
+            if self.rewind.len() == self.rewind_capacity {
+                self.rewind.pop_front();
+            }
+            self.rewind
+                .push_back((self.cpu.cycle_count, self.cpu.save_state()));
+        }
+        self.update_movie();
+        let frame = self.cpu.bus.ppu.frame_count();
+        while self.cpu.bus.ppu.frame_count() == frame {
+            let mut is_running = self.cpu.tick();
+            if let Some(ref mut watchdog) = self.watchdog {
+                is_running = watchdog.after_tick(&mut self.cpu, is_running);
+            }
+            if !is_running {
+                break;
+            }
+        }
+    }
+
+    /// Run a single instruction, or the start of an interrupt. Returns false if the
+    /// CPU halted.
+    pub fn step_instruction(&mut self) -> bool {
+        self.cpu.step(Step::Instructions(1)) == StepResult::Done
+    }
+
+    /// Run to the start of the next scanline, for looking at the PPU part way through
+    /// a frame, e.g. around a raster effect.
+    pub fn step_scanline(&mut self) -> bool {
+        self.cpu.step(Step::Scanlines(1)) == StepResult::Done
+    }
+
+    /// Run the PPU forward by `dots`. The CPU can't stop in the middle of an
+    /// instruction, so this stops at the end of the first one that reaches the dot,
+    /// up to 21 dots past it.
+    pub fn step_ppu_dots(&mut self, dots: u64) -> bool {
+        self.cpu.step(Step::Dots(dots)) == StepResult::Done
+    }
+
+    pub fn rewind_capacity(&self) -> usize {
+        self.rewind_capacity
+    }
+
+    /// How many frames to remember for rewinding. Each one is a full save state, so
+    /// this costs memory and a little time every frame. 0 turns rewinding off, which
+    /// is the default.
+    pub fn set_rewind_capacity(&mut self, frames: usize) {
+        self.rewind_capacity = frames;
+        while self.rewind.len() > frames {
+            self.rewind.pop_front();
+        }
+    }
+
+    /// How many frames can currently be rewound.
+    pub fn rewind_len(&self) -> usize {
+        self.rewind.len().saturating_sub(1)
+    }
+
+    /// Go back in time by up to `frames`, and return how many frames were rewound.
+    /// The framebuffer isn't part of a save state, so the frame before the one that
+    /// is returned to is run again to draw it.
+    pub fn rewind_frames(&mut self, frames: usize) -> usize {
+        let frames = frames.min(self.rewind_len());
+        if frames == 0 {
+            return 0;
+        }
+        // The newest state is from the start of the frame on screen.
+        self.rewind_to_frame(self.rewind.len() - frames);
+        frames
+    }
+
+    /// Go back to the start of the frame at `index` in the rewind states, which can't
+    /// be the first. The frame before it is loaded and run again to draw it, which
+    /// leaves its state as the newest one.
+    fn rewind_to_frame(&mut self, index: usize) {
+        // The movie goes back by the same frames, including the one that's run again.
+        let frames = self.rewind.len() + 1 - index;
+        match self.movie {
+            Some(MovieState::Recording(ref mut movie)) => {
+                movie
+                    .frames
+                    .truncate(movie.frames.len().saturating_sub(frames));
+                movie.rerecords += 1;
+            }
+            Some(MovieState::Playing(_, ref mut frame)) => {
+                *frame = frame.saturating_sub(frames);
+            }
+            None => {}
+        }
+        self.rewind.truncate(index);
+        let (_, state) = self
+            .rewind
+            .pop_back()
+            .expect("There are states left to rewind to.");
+        self.cpu
+            .load_state(&state)
+            .expect("The rewind states were saved by this emulator.");
+        self.run_frame();
+    }
+
+    /// Go back to just after a write from the write log, with the written value in
+    /// memory, and the PC on the instruction after the one that wrote it. Returns false
+    /// when the write is older than the rewind states.
+    pub fn rewind_to_write(&mut self, write: &LoggedWrite) -> bool {
+        // The frame before the write's frame is needed to draw it.
+        let index = match self
+            .rewind
+            .iter()
+            .rposition(|(cycle_count, _)| *cycle_count <= write.cycle)
+        {
+            Some(index) if index > 0 => index,
+            _ => return false,
+        };
+        self.rewind_to_frame(index);
+        while self.cpu.cycle_count <= write.cycle {
+            if !self.cpu.tick() {
+                break;
+            }
+        }
+        true
+    }
+
+    /// Start recording the controllers into a movie, from the current state.
+    pub fn record_movie(&mut self, rom_filename: &str) {
+        self.movie = Some(MovieState::Recording(Movie {
+            rom_filename: rom_filename.to_string(),
+            start_state: Some(self.cpu.save_state()),
+            frames: Vec::new(),
+            rerecords: 0,
+            core: Some(self.core_info()),
+        }));
+    }
+
+    /// Carry on recording a movie that was cut off, e.g. when the session was
+    /// suspended. The emulator should already be in the state at its last frame.
+    pub fn resume_recording(&mut self, movie: Movie) {
+        self.movie = Some(MovieState::Recording(movie));
+    }
+
+    /// Load the movie's start state, and press its buttons from the next frame on. A
+    /// movie without a start state begins at power on, so it should only be played
+    /// right after the ROM is loaded. Movies recorded by a core that runs differently
+    /// are refused.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), String> {
+        if let Some(ref core) = movie.core {
+            self.core_info().check_compatible(core)?;
+        }
+        if let Some(ref state) = movie.start_state {
+            self.cpu.load_state(state)?;
+        }
+        self.movie = Some(MovieState::Playing(movie, 0));
+        Ok(())
+    }
+
+    /// Stop recording or playing, and return the movie.
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie.take().map(|state| match state {
+            MovieState::Recording(movie) | MovieState::Playing(movie, _) => movie,
+        })
+    }
+
+    pub fn movie_state(&self) -> Option<&MovieState> {
+        self.movie.as_ref()
+    }
+
+    /// Record or play the buttons for the frame that's about to run.
+    fn update_movie(&mut self) {
+        let bus = &mut self.cpu.bus;
+        match self.movie {
+            Some(MovieState::Recording(ref mut movie)) => {
+                movie
+                    .frames
+                    .push([bus.controller_1.buttons(), bus.controller_2.buttons()]);
+            }
+            Some(MovieState::Playing(ref movie, ref mut frame)) => {
+                let buttons = movie.frames.get(*frame).copied().unwrap_or_default();
+                bus.controller_1.set_buttons(buttons[0]);
+                bus.controller_2.set_buttons(buttons[1]);
+                *frame = (*frame + 1).min(movie.frames.len());
+            }
+            None => {}
+        }
+    }
+
+    /// Render the next `frames` of each APU channel on its own, in the order of
+    /// CHANNELS, and then go back to where the emulator was. Nothing is pressed on the
+    /// controllers while the frames are run, which suits music that plays by itself,
+    /// like a title screen or a sound test.
+    pub fn render_stems(&mut self, frames: u64) -> [Vec<f32>; 5] {
+        let mut render = self.start_stems(frames);
+        self.continue_stems(&mut render, frames);
+        self.finish_stems(render)
+    }
+
+    /// Render the stems like `render_stems`, but a few frames at a time, so that a
+    /// frontend can show the progress. Don't run any other frames until the render
+    /// is finished.
+    pub fn start_stems(&mut self, frames: u64) -> StemsRender {
+        let render = StemsRender {
+            state: self.cpu.save_state(),
+            rewind: std::mem::take(&mut self.rewind),
+            rewind_capacity: self.rewind_capacity,
+            frames,
+            rendered_frames: 0,
+        };
+        self.rewind_capacity = 0;
+        self.cpu.bus.apu.stems = Some(Default::default());
+        render
+    }
+
+    /// Render up to `frames` more frames, and return true when all of them are done.
+    pub fn continue_stems(&mut self, render: &mut StemsRender, frames: u64) -> bool {
+        let frames = frames.min(render.frames - render.rendered_frames);
+        for _ in 0..frames {
+            self.run_frame();
         }
+        render.rendered_frames += frames;
+        render.rendered_frames == render.frames
+    }
+
+    /// Take the stems, and go back to where the emulator was when they were started.
+    pub fn finish_stems(&mut self, render: StemsRender) -> [Vec<f32>; 5] {
+        let apu = &mut self.cpu.bus.apu;
+        let stems = apu.stems.take().expect("The stems were turned on.");
+        // The audio was only rendered for the stems, it shouldn't be played.
+        apu.samples.clear();
+
+        self.cpu
+            .load_state(&render.state)
+            .expect("The state was saved by this emulator.");
+        self.rewind = render.rewind;
+        self.rewind_capacity = render.rewind_capacity;
+        stems
+    }
+
+    /// The last complete frame, 256x240 pixels of RGBA, ready to be copied into a
+    /// texture.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.cpu.bus.ppu.framebuffer()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::{AsmLexer, BytesLabels};
+    use crate::mappers::SimpleProgram;
+
+    #[test]
+    fn test_framebuffer() {
+        // Set the backdrop to $21, point v away from the palette, and spin.
+        let mut lexer = AsmLexer::new(
+            "
+            lda #$3f
+            sta $2006
+            lda #$00
+            sta $2006
+            lda #$21
+            sta $2007
+            lda #$20
+            sta $2006
+            lda #$00
+            sta $2006
+            loop:
+            jmp loop
+            ",
+        );
+        lexer.parse().unwrap();
+        let BytesLabels { bytes, .. } = lexer.into_bytes().unwrap();
+        let mut emulator = Emulator::new(Box::new(SimpleProgram::load(&bytes)));
+
+        // The top of the first frame was drawn before the palette was written.
+        emulator.run_frame();
+        emulator.run_frame();
+        assert_eq!(emulator.framebuffer().len(), 256 * 240 * 4);
+        // With rendering disabled the whole frame is the backdrop, $21 is a light blue.
+        assert_eq!(emulator.framebuffer()[0..4], [0x4c, 0x9a, 0xec, 0xff]);
+    }
+
+    #[test]
+    fn test_step_scanline() {
+        let mut lexer = AsmLexer::new("loop:\njmp loop");
+        lexer.parse().unwrap();
+        let bytes = lexer.into_bytes().unwrap().bytes;
+        let mut emulator = Emulator::new(Box::new(SimpleProgram::load(&bytes)));
+        let position = |emulator: &Emulator| {
+            let ppu = &emulator.cpu.bus.ppu;
+            (ppu.scanline(), ppu.scanline_dot())
+        };
+
+        assert!(emulator.step_scanline());
+        assert!(emulator.step_scanline());
+        let (scanline, dot) = position(&emulator);
+        assert_eq!(scanline, 2);
+        assert!(dot < 9, "The jmp ends within 9 dots of the scanline.");
+
+        assert!(emulator.step_ppu_dots(100));
+        let (scanline, after) = position(&emulator);
+        assert_eq!(scanline, 2);
+        assert!((dot + 100..dot + 109).contains(&after));
+
+        let tick_count = emulator.cpu.tick_count;
+        assert!(emulator.step_instruction());
+        assert_eq!(emulator.cpu.tick_count, tick_count + 1);
+    }
+
+    #[test]
+    fn test_separate_emulators() {
+        let load = |text: &str| {
+            let mut lexer = AsmLexer::new(text);
+            lexer.parse().unwrap();
+            let BytesLabels { bytes, .. } = lexer.into_bytes().unwrap();
+            Emulator::new(Box::new(SimpleProgram::load(&bytes)))
+        };
+        // Frontends can run several cartridges in one session, and they don't share
+        // any state.
+        let mut first = load("loop:\ninc $10\njmp loop");
+        let mut second = load("loop:\ndec $10\njmp loop");
+        for _ in 0..3 {
+            first.run_frame();
+            second.run_frame();
+        }
+        let first_value = first.cpu.bus.read_u8(0x10);
+        let second_value = second.cpu.bus.read_u8(0x10);
+        assert_eq!(first_value, 0u8.wrapping_sub(second_value));
+
+        // Each one can run on its own thread.
+        let second = std::thread::spawn(move || {
+            second.run_frame();
+            second
+        })
+        .join()
+        .unwrap();
+        assert_ne!(second.cpu.bus.read_u8(0x10), second_value);
+        assert_eq!(first.cpu.bus.read_u8(0x10), first_value);
+    }
+
+    #[test]
+    fn test_render_stems() {
+        let mut lexer = AsmLexer::new(
+            "
+            lda #$04
+            sta $4015
+            lda #$ff
+            sta $4008
+            sta $400a
+            sta $400b
+            loop:
+            inc $10
+            jmp loop
+            ",
+        );
+        lexer.parse().unwrap();
+        let BytesLabels { bytes, .. } = lexer.into_bytes().unwrap();
+        let mut emulator = Emulator::new(Box::new(SimpleProgram::load(&bytes)));
+        emulator.run_frame();
+        let state = emulator.cpu.save_state();
+
+        let stems = emulator.render_stems(2);
+        let sample_rate = emulator.cpu.bus.apu.sample_rate() as usize;
+        // About 2 frames of samples, at 60fps.
+        assert!((stems[0].len() as i64 - sample_rate as i64 / 30).abs() < 10);
+        assert!(stems[2].iter().any(|sample| *sample > 0.0));
+        assert!(stems[3].iter().all(|sample| *sample == 0.0));
+        // The emulator is back where it was.
+        assert_eq!(emulator.cpu.save_state(), state);
+
+        // Rendering a frame at a time makes the same amount of audio.
+        let mut render = emulator.start_stems(2);
+        assert!(!emulator.continue_stems(&mut render, 1));
+        assert_eq!(render.progress(), 0.5);
+        assert!(emulator.continue_stems(&mut render, 10));
+        let again = emulator.finish_stems(render);
+        assert_eq!(again[0].len(), stems[0].len());
+        assert_eq!(emulator.cpu.save_state(), state);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut lexer = AsmLexer::new(
+            "
+            loop:
+            inc $10
+            jmp loop
+            ",
+        );
+        lexer.parse().unwrap();
+        let BytesLabels { bytes, .. } = lexer.into_bytes().unwrap();
+        let mut emulator = Emulator::new(Box::new(SimpleProgram::load(&bytes)));
+        emulator.set_rewind_capacity(3);
+        emulator.record_movie("rewind");
+
+        let mut states = vec![];
+        for _ in 0..5 {
+            emulator.run_frame();
+            states.push(emulator.cpu.save_state());
+        }
+        // Only the starts of the last 3 frames are kept, and the last one is on screen.
+        assert_eq!(emulator.rewind_len(), 2);
+
+        assert_eq!(emulator.rewind_frames(1), 1);
+        assert_eq!(emulator.cpu.save_state(), states[3]);
+        assert_eq!(emulator.rewind_frames(10), 1);
+        assert_eq!(emulator.cpu.save_state(), states[2]);
+        assert_eq!(emulator.rewind_frames(1), 0);
+        // The movie is recorded over from the frame that was rewound to.
+        let movie = emulator.movie_state().unwrap().movie();
+        assert_eq!((movie.frames.len(), movie.rerecords), (3, 2));
+
+        // Running forward again is the same as the first time.
+        emulator.run_frame();
+        assert_eq!(emulator.cpu.save_state(), states[3]);
+    }
+
+    #[cfg(feature = "debugger")]
+    #[test]
+    fn test_rewind_to_write() {
+        // $10 is written once a frame, on the first loop after the vblank flag is set.
+        let mut lexer = AsmLexer::new(
+            "
+            wait:
+            bit $2002
+            bpl wait
+            inc $10
+            jmp wait
+            ",
+        );
+        lexer.parse().unwrap();
+        let BytesLabels { bytes, .. } = lexer.into_bytes().unwrap();
+        let mut emulator = Emulator::new(Box::new(SimpleProgram::load(&bytes)));
+        emulator.set_rewind_capacity(10);
+        emulator.cpu.bus.write_log.set_capacity(100);
+        for _ in 0..5 {
+            emulator.run_frame();
+        }
+        let value = emulator.cpu.bus.peek_u8(0x10);
+        let write = emulator.cpu.bus.write_log.last_write(0x10).unwrap();
+        assert_eq!((write.pc, write.value), (0x8005, value));
+
+        // Go back to the write before it, which is left in memory.
+        let earlier = *emulator.cpu.bus.write_log.writes_to(0x10).nth(1).unwrap();
+        assert!(emulator.rewind_to_write(&earlier));
+        assert_eq!(emulator.cpu.bus.peek_u8(0x10), earlier.value);
+        assert_eq!(emulator.cpu.pc, 0x8007);
+        assert_eq!(emulator.cpu.bus.ppu.frame_count(), earlier.frame);
+        // The writes after it haven't happened yet.
+        assert_eq!(emulator.cpu.bus.write_log.last_write(0x10), Some(earlier));
+
+        // Running forward again, to the end of that frame and through the next, makes
+        // the same write.
+        emulator.run_frame();
+        emulator.run_frame();
+        assert_eq!(emulator.cpu.bus.write_log.last_write(0x10), Some(write));
+
+        // The oldest writes are from before the rewind states.
+        let first = *emulator.cpu.bus.write_log.iter().next().unwrap();
+        emulator.set_rewind_capacity(2);
+        assert!(!emulator.rewind_to_write(&first));
     }
 }
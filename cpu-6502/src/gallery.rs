@@ -0,0 +1,143 @@
+//! The example programs, built into the crate so that the frontends can offer them
+//! without looking for files. The CPU examples are the ones from the simple-game and
+//! the cpu-visualizer, and the PPU examples in src/gallery are NROM cartridges.
+
+use crate::asm::{AddressToLabel, AsmLexer, BytesLabels};
+use crate::emulator::Emulator;
+use crate::mappers::SimpleProgram;
+use crate::rom::nrom_from_program;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExampleKind {
+    /// A program for the CPU alone, loaded into a SimpleProgram cartridge. There's no
+    /// picture, so `watch` is the memory that shows what it's doing.
+    Cpu { watch: u16 },
+    /// An NROM cartridge with CHR RAM, that draws with the PPU.
+    Nes,
+}
+
+pub struct Example {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub kind: ExampleKind,
+    pub source: &'static str,
+}
+
+pub const EXAMPLES: [Example; 6] = [
+    Example {
+        name: "Backdrop cycle",
+        description: "Steps through the backdrop colors from the NMI handler.",
+        kind: ExampleKind::Nes,
+        source: include_str!("gallery/backdrop-cycle.asm"),
+    },
+    Example {
+        name: "Scrolling tiles",
+        description: "Draws tiles into CHR RAM, and scrolls the nametable sideways.",
+        kind: ExampleKind::Nes,
+        source: include_str!("gallery/scrolling-tiles.asm"),
+    },
+    Example {
+        name: "Bouncing sprite",
+        description: "Bounces sprite 0 around the screen through OAMDATA.",
+        kind: ExampleKind::Nes,
+        source: include_str!("gallery/bouncing-sprite.asm"),
+    },
+    Example {
+        name: "Snake",
+        description: "The easy6502 snake. The screen is the memory at $0200-$05FF, \
+                      and the simple-game draws it and feeds it keys.",
+        kind: ExampleKind::Cpu { watch: 0x0200 },
+        source: include_str!("../../simple-game/asm/snake.asm"),
+    },
+    Example {
+        name: "Fill zero page",
+        description: "Fills the zero page with incrementing values.",
+        kind: ExampleKind::Cpu { watch: 0x0000 },
+        source: include_str!("../../cpu-visualizer/src/asm/fill-zero-page.asm"),
+    },
+    Example {
+        name: "Add with carry",
+        description: "Adds with the carry flag set and cleared.",
+        kind: ExampleKind::Cpu { watch: 0x0000 },
+        source: include_str!("../../cpu-visualizer/src/asm/add-with-carry.asm"),
+    },
+];
+
+impl Example {
+    pub fn assemble(&self) -> Result<BytesLabels, String> {
+        let mut lexer = AsmLexer::new(self.source);
+        if let Err(parse_error) = lexer.parse() {
+            return Err(parse_error.nice_message().to_string());
+        }
+        lexer.into_bytes()
+    }
+
+    /// Assemble the example, and put it in a fresh emulator.
+    pub fn boot(&self) -> Result<Emulator, String> {
+        self.boot_with_labels().map(|(emulator, _)| emulator)
+    }
+
+    /// Boot the example, and keep the labels for the debuggers.
+    pub fn boot_with_labels(&self) -> Result<(Emulator, AddressToLabel), String> {
+        let program = self.assemble()?;
+        let emulator = match self.kind {
+            ExampleKind::Cpu { .. } => {
+                Emulator::new(Box::new(SimpleProgram::load(&program.bytes)))
+            }
+            ExampleKind::Nes => {
+                Emulator::from_ines_bytes(&nrom_from_program(&program, &[])?)?
+            }
+        };
+        Ok((emulator, program.address_to_label))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::replay::state_hash;
+
+    #[test]
+    fn test_examples_boot() {
+        for example in EXAMPLES.iter() {
+            let mut emulator = example.boot().unwrap();
+            for _ in 0..10 {
+                emulator.run_frame();
+            }
+            if example.kind != ExampleKind::Nes {
+                continue;
+            }
+            // Every PPU example animates.
+            let before = state_hash(emulator.framebuffer());
+            for _ in 0..10 {
+                emulator.run_frame();
+            }
+            let after = state_hash(emulator.framebuffer());
+            assert_ne!(before, after, "{} didn't change the picture", example.name);
+        }
+    }
+
+    #[test]
+    fn test_bouncing_sprite() {
+        let example = EXAMPLES
+            .iter()
+            .find(|e| e.name == "Bouncing sprite")
+            .unwrap();
+        let mut emulator = example.boot().unwrap();
+        let mut positions = vec![];
+        for _ in 0..100 {
+            emulator.run_frame();
+            let oam = &emulator.cpu.bus.ppu.state.oam;
+            positions.push((oam[3], oam[0]));
+        }
+        // The NMI doesn't draw the sprite until the PPU has warmed up. Then it
+        // bounces off the right edge, and stays on the screen.
+        let positions = &positions[4..];
+        assert!(positions.iter().any(|&(x, _)| x == 248));
+        assert!(positions.windows(2).any(|pair| pair[1].0 < pair[0].0));
+        assert!(positions.iter().all(|&(_, y)| (8..=224).contains(&y)));
+        assert!(emulator.cpu.bus.ppu.state.oam[4..]
+            .iter()
+            .all(|&byte| byte == 0xff));
+    }
+}
@@ -0,0 +1,66 @@
+; Backdrop cycle
+;
+; Step the backdrop color once every 8 frames. Nothing else is drawn, so the whole
+; screen shows the color at $3F00. This is about the smallest program that does
+; something visible on the PPU.
+
+PPUCTRL = $2000
+PPUMASK = $2001
+PPUSTATUS = $2002
+PPUSCROLL = $2005
+PPUADDR = $2006
+PPUDATA = $2007
+
+frames = $00
+color = $01
+
+reset:
+    sei
+    cld
+    ldx #$ff
+    txs
+
+    lda #$00
+    sta PPUCTRL
+    sta PPUMASK
+    sta frames
+    sta color
+
+    ; The PPU takes 2 frames to warm up.
+vblank_wait_1:
+    bit PPUSTATUS
+    bpl vblank_wait_1
+vblank_wait_2:
+    bit PPUSTATUS
+    bpl vblank_wait_2
+
+    ; Turn on the NMI and the background.
+    lda #%10000000
+    sta PPUCTRL
+    lda #%00001010
+    sta PPUMASK
+
+main_loop:
+    jmp main_loop
+
+nmi:
+    inc frames
+    lda frames
+    and #%00000111
+    bne nmi_done
+
+    inc color
+    lda #$3f
+    sta PPUADDR
+    lda #$00
+    sta PPUADDR
+    lda color
+    and #$3f
+    sta PPUDATA
+
+    ; PPUADDR shares its latch with the scroll, so set the scroll back.
+    lda #$00
+    sta PPUSCROLL
+    sta PPUSCROLL
+nmi_done:
+    rti
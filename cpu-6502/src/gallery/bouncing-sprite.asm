@@ -0,0 +1,142 @@
+; Bouncing sprite
+;
+; Bounce sprite 0 around the screen, moving it a pixel each frame. The sprite's
+; attributes are written through OAMADDR and OAMDATA during vblank.
+
+PPUCTRL = $2000
+PPUMASK = $2001
+PPUSTATUS = $2002
+OAMADDR = $2003
+OAMDATA = $2004
+PPUADDR = $2006
+PPUDATA = $2007
+
+x_position = $00
+y_position = $01
+; 1 to move right or down, and $ff to move left or up.
+x_speed = $02
+y_speed = $03
+
+; The sprite palettes, and a solid tile at $8010.
+palettes:
+    .byte $0f, $16, $27, $30
+    .byte $0f, $16, $27, $30
+    .byte $0f, $16, $27, $30
+    .byte $0f, $16, $27, $30
+tile:
+    .byte $ff, $ff, $ff, $ff, $ff, $ff, $ff, $ff
+    .byte $00, $00, $00, $00, $00, $00, $00, $00
+
+reset:
+    sei
+    cld
+    ldx #$ff
+    txs
+
+    lda #$00
+    sta PPUCTRL
+    sta PPUMASK
+    lda #200
+    sta x_position
+    lda #40
+    sta y_position
+    lda #$01
+    sta x_speed
+    sta y_speed
+
+vblank_wait_1:
+    bit PPUSTATUS
+    bpl vblank_wait_1
+vblank_wait_2:
+    bit PPUSTATUS
+    bpl vblank_wait_2
+
+    ; Copy the palettes to the sprite palettes at $3F10.
+    lda #$3f
+    sta PPUADDR
+    lda #$10
+    sta PPUADDR
+    ldx #$00
+load_palettes:
+    lda $8000,x
+    sta PPUDATA
+    inx
+    cpx #$10
+    bne load_palettes
+
+    ; Copy the tile to tile 1 in the pattern table, at $0010.
+    lda #$00
+    sta PPUADDR
+    lda #$10
+    sta PPUADDR
+    ldx #$00
+load_tile:
+    lda $8010,x
+    sta PPUDATA
+    inx
+    cpx #$10
+    bne load_tile
+
+    ; Move every sprite below the screen, so only sprite 0 shows.
+    lda #$00
+    sta OAMADDR
+    lda #$ff
+    ldx #$00
+hide_sprites:
+    sta OAMDATA
+    inx
+    bne hide_sprites
+
+    ; Turn on the NMI and the sprites.
+    lda #%10000000
+    sta PPUCTRL
+    lda #%00010100
+    sta PPUMASK
+
+main_loop:
+    jmp main_loop
+
+nmi:
+    ; Move across, and turn around at the edges of the screen.
+    lda x_position
+    clc
+    adc x_speed
+    sta x_position
+    cmp #248
+    beq turn_x
+    cmp #0
+    bne move_y
+turn_x:
+    lda #$00
+    sec
+    sbc x_speed
+    sta x_speed
+
+move_y:
+    lda y_position
+    clc
+    adc y_speed
+    sta y_position
+    cmp #224
+    beq turn_y
+    cmp #8
+    bne draw
+turn_y:
+    lda #$00
+    sec
+    sbc y_speed
+    sta y_speed
+
+draw:
+    ; Write sprite 0's y, tile, attributes, and x.
+    lda #$00
+    sta OAMADDR
+    lda y_position
+    sta OAMDATA
+    lda #$01
+    sta OAMDATA
+    lda #$00
+    sta OAMDATA
+    lda x_position
+    sta OAMDATA
+    rti
@@ -0,0 +1,111 @@
+; Scrolling tiles
+;
+; Draw 2 tiles into CHR RAM, fill the first nametable with them, then scroll it
+; sideways a pixel every frame. The cartridge mirrors horizontally, so $2400 shows
+; the same tiles as $2000, and the scroll wraps around without a seam.
+
+PPUCTRL = $2000
+PPUMASK = $2001
+PPUSTATUS = $2002
+PPUSCROLL = $2005
+PPUADDR = $2006
+PPUDATA = $2007
+
+scroll_x = $00
+
+; The data comes first, so that it's at the known address $8000 for indexing.
+palettes:
+    .byte $0f, $01, $11, $21
+    .byte $0f, $06, $16, $26
+    .byte $0f, $09, $19, $29
+    .byte $0f, $04, $14, $24
+; Tile 1 is solid, and tile 2 is a checkerboard, 16 bytes each at $8010.
+tiles:
+    .byte $ff, $ff, $ff, $ff, $ff, $ff, $ff, $ff
+    .byte $00, $00, $00, $00, $00, $00, $00, $00
+    .byte $aa, $55, $aa, $55, $aa, $55, $aa, $55
+    .byte $ff, $ff, $ff, $ff, $ff, $ff, $ff, $ff
+
+reset:
+    sei
+    cld
+    ldx #$ff
+    txs
+
+    lda #$00
+    sta PPUCTRL
+    sta PPUMASK
+    sta scroll_x
+
+vblank_wait_1:
+    bit PPUSTATUS
+    bpl vblank_wait_1
+vblank_wait_2:
+    bit PPUSTATUS
+    bpl vblank_wait_2
+
+    ; Copy the palettes to $3F00.
+    lda #$3f
+    sta PPUADDR
+    lda #$00
+    sta PPUADDR
+    ldx #$00
+load_palettes:
+    lda $8000,x
+    sta PPUDATA
+    inx
+    cpx #$10
+    bne load_palettes
+
+    ; Copy the tiles to tile 1 in the pattern table, at $0010.
+    lda #$00
+    sta PPUADDR
+    lda #$10
+    sta PPUADDR
+    ldx #$00
+load_tiles:
+    lda $8010,x
+    sta PPUDATA
+    inx
+    cpx #$20
+    bne load_tiles
+
+    ; Fill the nametable and its attributes, 4 pages of 256 bytes, with columns of
+    ; tile 1 and tile 2. The attributes get the same bytes, which mixes up the
+    ; palettes.
+    lda #$20
+    sta PPUADDR
+    lda #$00
+    sta PPUADDR
+    ldy #$04
+fill_page:
+    ldx #$00
+fill_byte:
+    txa
+    and #$01
+    clc
+    adc #$01
+    sta PPUDATA
+    inx
+    bne fill_byte
+    dey
+    bne fill_page
+
+    lda #$00
+    sta PPUSCROLL
+    sta PPUSCROLL
+    lda #%10000000
+    sta PPUCTRL
+    lda #%00001010
+    sta PPUMASK
+
+main_loop:
+    jmp main_loop
+
+nmi:
+    inc scroll_x
+    lda scroll_x
+    sta PPUSCROLL
+    lda #$00
+    sta PPUSCROLL
+    rti
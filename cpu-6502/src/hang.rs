@@ -0,0 +1,162 @@
+//! Spots a program that's stuck, for harnesses that run ROMs without anyone watching.
+//! A hang is when the picture and the instructions the CPU runs stay the same for many
+//! frames, while nobody presses anything. This saves writing a timeout for every test.
+
+use crate::disassembler::disassemble_history;
+use crate::emulator::Emulator;
+use crate::replay::state_hash;
+
+/// 5 seconds at 60fps, long enough for a title screen to start animating.
+pub const DEFAULT_HANG_FRAMES: u64 = 300;
+
+/// How many of the most recent instructions go into the report.
+const REPORTED_INSTRUCTIONS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hang {
+    /// The frame the hang was reported on, counting from when the detector started.
+    pub frame: u64,
+    /// How many frames in a row repeated.
+    pub frames: u64,
+    /// The lowest and highest PC in the loop.
+    pub pc_range: (u16, u16),
+    /// The most recent instructions, disassembled, oldest first.
+    pub instructions: Vec<String>,
+}
+
+impl Hang {
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "Probable hang at frame {}: the picture and the PCs ${:04x}-${:04x} repeated \
+             for {} frames without any input changes.\n",
+            self.frame, self.pc_range.0, self.pc_range.1, self.frames
+        );
+        if !self.instructions.is_empty() {
+            report.push_str("Recent instructions:\n");
+            for instruction in &self.instructions {
+                report.push_str("  ");
+                report.push_str(instruction);
+                report.push('\n');
+            }
+        }
+        report
+    }
+}
+
+/// What's compared from one frame to the next.
+#[derive(Debug, Clone, PartialEq)]
+struct FrameSummary {
+    framebuffer_hash: u64,
+    /// The distinct addresses of the instructions in the history, sorted.
+    pcs: Vec<u16>,
+    buttons: [u8; 2],
+}
+
+impl FrameSummary {
+    fn new(emulator: &Emulator) -> FrameSummary {
+        let cpu = &emulator.cpu;
+        let mut pcs: Vec<u16> = cpu
+            .history
+            .iter()
+            .map(|instruction| instruction.address)
+            .chain(std::iter::once(cpu.pc))
+            .collect();
+        pcs.sort_unstable();
+        pcs.dedup();
+        FrameSummary {
+            framebuffer_hash: state_hash(emulator.framebuffer()),
+            pcs,
+            buttons: [
+                cpu.bus.controller_1.buttons(),
+                cpu.bus.controller_2.buttons(),
+            ],
+        }
+    }
+}
+
+/// Call `end_frame` after every frame, or let `run` drive the emulator. The PCs come
+/// from the instruction history, so without the debugger feature only the PC at the
+/// end of each frame is compared.
+pub struct HangDetector {
+    hang_frames: u64,
+    frame: u64,
+    repeats: u64,
+    last: Option<FrameSummary>,
+}
+
+impl HangDetector {
+    /// Report a hang once `hang_frames` frames in a row repeat.
+    pub fn new(hang_frames: u64) -> HangDetector {
+        HangDetector {
+            hang_frames,
+            frame: 0,
+            repeats: 0,
+            last: None,
+        }
+    }
+
+    pub fn end_frame(&mut self, emulator: &Emulator) -> Option<Hang> {
+        self.frame += 1;
+        let summary = FrameSummary::new(emulator);
+        if self.last.as_ref() == Some(&summary) {
+            self.repeats += 1;
+        } else {
+            self.repeats = 0;
+        }
+        self.last = Some(summary);
+        if self.repeats < self.hang_frames {
+            return None;
+        }
+
+        let pcs = &self.last.as_ref().expect("The summary was just set.").pcs;
+        Some(Hang {
+            frame: self.frame,
+            frames: self.repeats,
+            pc_range: (pcs[0], pcs[pcs.len() - 1]),
+            instructions: disassemble_history(
+                &emulator.cpu.history,
+                REPORTED_INSTRUCTIONS,
+            ),
+        })
+    }
+
+    /// Run up to `frames` frames, and stop early with the hang if there is one.
+    pub fn run(&mut self, emulator: &mut Emulator, frames: u64) -> Result<(), Hang> {
+        for _ in 0..frames {
+            emulator.run_frame();
+            if let Some(hang) = self.end_frame(emulator) {
+                return Err(hang);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu_6502::test_helpers::emulator_from_asm;
+
+    #[test]
+    fn test_hang() {
+        let mut emulator = emulator_from_asm("lda #$01\nloop:\njmp loop");
+        let hang = HangDetector::new(10).run(&mut emulator, 100).unwrap_err();
+        assert_eq!(hang.frame, 11);
+        assert_eq!(hang.pc_range, (0x8002, 0x8002));
+        if emulator.cpu.history.is_enabled() {
+            assert_eq!(hang.instructions.last().unwrap(), "$8002 jmp $8002");
+        }
+        assert!(hang.report().contains("$8002-$8002"));
+    }
+
+    #[test]
+    fn test_input_resets() {
+        let mut emulator = emulator_from_asm("loop:\njmp loop");
+        let mut detector = HangDetector::new(10);
+        for frame in 0..30 {
+            emulator.cpu.bus.controller_1.set_buttons(frame % 9);
+            emulator.run_frame();
+            assert_eq!(detector.end_frame(&emulator), None);
+        }
+    }
+}
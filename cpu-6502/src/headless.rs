@@ -0,0 +1,247 @@
+//! Runs a cartridge for a number of frames without a frontend, with the input scripted
+//! by frame, and hashes the picture and RAM it ends up with. The same ROM, script, and
+//! frame count always give the same hashes, so a whole game can be regression tested
+//! by checking in its hashes, and a TAS-style run can be verified against the hashes
+//! it was published with. A fault schedule can be run alongside, to check that a game
+//! and the emulator hold up to bit flips and flaky reads.
+
+use crate::controller::{InputMacro, PLAYERS};
+use crate::cpu_6502::fault_injection::FaultInjector;
+use crate::emulator::Emulator;
+use crate::mappers::Mapper;
+use crate::replay::state_hash;
+use std::collections::BTreeMap;
+
+pub use crate::cpu_6502::fault_injection::{Fault, FaultSchedule, ScheduledFault};
+
+/// The buttons held on each controller, changing on the frames listed.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct InputScript {
+    /// The Button bits for each player, from the frame they're keyed by until the
+    /// next change. The frames count from 0 when the runner starts.
+    pub changes: BTreeMap<u64, [u8; PLAYERS]>,
+}
+
+impl InputScript {
+    /// Hold `buttons` on the controllers from `frame` on.
+    pub fn set_buttons(&mut self, frame: u64, buttons: [u8; PLAYERS]) {
+        self.changes.insert(frame, buttons);
+    }
+
+    /// The buttons held on each controller during `frame`.
+    pub fn buttons_at(&self, frame: u64) -> [u8; PLAYERS] {
+        self.changes
+            .range(..=frame)
+            .next_back()
+            .map_or([0; PLAYERS], |(_, buttons)| *buttons)
+    }
+
+    /// One change per line, "<frame> <player 1> [<player 2>]", where the buttons are
+    /// written like a macro frame, e.g. "120 Start" or "300 A+Right B". Nothing is
+    /// held on player 2 when it's left out. Blank lines and lines starting with # are
+    /// skipped.
+    pub fn from_text(text: &str) -> Result<InputScript, String> {
+        let mut script = InputScript::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let frame = parts
+                .next()
+                .and_then(|frame| frame.parse::<u64>().ok())
+                .ok_or_else(|| format!("Expected a frame number in \"{}\"", line))?;
+            let players: Vec<&str> = parts.collect();
+            if players.is_empty() || players.len() > PLAYERS {
+                return Err(format!(
+                    "Expected the buttons for 1 or {} players in \"{}\"",
+                    PLAYERS, line
+                ));
+            }
+            let mut buttons = [0; PLAYERS];
+            for (player, text) in players.iter().enumerate() {
+                buttons[player] = match InputMacro::from_text(text)?.frames[..] {
+                    [held] => held,
+                    _ => return Err(format!("Expected a single frame in \"{}\"", text)),
+                };
+            }
+            script.set_buttons(frame, buttons);
+        }
+        Ok(script)
+    }
+}
+
+/// What a run ended with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeadlessResult {
+    /// The frames run since the runner started.
+    pub frames: u64,
+    pub framebuffer_hash: u64,
+    pub ram_hash: u64,
+}
+
+impl HeadlessResult {
+    /// Both hashes combined, for tests that only keep one number.
+    pub fn hash(&self) -> u64 {
+        let mut bytes = self.framebuffer_hash.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.ram_hash.to_le_bytes());
+        state_hash(&bytes)
+    }
+}
+
+pub struct HeadlessRunner {
+    pub emulator: Emulator,
+    pub script: InputScript,
+    frame: u64,
+    faults: Option<FaultInjector>,
+}
+
+impl HeadlessRunner {
+    pub fn new(emulator: Emulator, script: InputScript) -> HeadlessRunner {
+        HeadlessRunner {
+            emulator,
+            script,
+            frame: 0,
+            faults: None,
+        }
+    }
+
+    /// Boot a cartridge with the faults from the schedule injected into its bus. The
+    /// schedule's ticks count the CPU's ticks from power on.
+    pub fn with_faults(
+        cartridge: Box<dyn Mapper>,
+        script: InputScript,
+        schedule: FaultSchedule,
+    ) -> HeadlessRunner {
+        let (cartridge, injector) = FaultInjector::wrap(cartridge, schedule);
+        let mut runner = HeadlessRunner::new(Emulator::new(Box::new(cartridge)), script);
+        runner.faults = Some(injector);
+        runner
+    }
+
+    /// Boot the contents of a .nes file.
+    pub fn from_ines_bytes(
+        bytes: &[u8],
+        script: InputScript,
+    ) -> Result<HeadlessRunner, String> {
+        Ok(HeadlessRunner::new(
+            Emulator::from_ines_bytes(bytes)?,
+            script,
+        ))
+    }
+
+    /// Run `frames` more frames, pressing the scripted buttons, and hash the result.
+    /// The frames carry on from where the last run stopped.
+    pub fn run(&mut self, frames: u64) -> HeadlessResult {
+        for _ in 0..frames {
+            let buttons = self.script.buttons_at(self.frame);
+            let bus = &mut self.emulator.cpu.bus;
+            bus.controller_1.set_buttons(buttons[0]);
+            bus.controller_2.set_buttons(buttons[1]);
+            match self.faults {
+                Some(ref mut injector) => run_faulty_frame(&mut self.emulator, injector),
+                None => self.emulator.run_frame(),
+            }
+            self.frame += 1;
+        }
+        HeadlessResult {
+            frames: self.frame,
+            framebuffer_hash: state_hash(self.emulator.framebuffer()),
+            ram_hash: state_hash(self.emulator.cpu.bus.ram()),
+        }
+    }
+}
+
+/// The faults land between instructions, so the frame is run a tick at a time here,
+/// rather than with `Emulator::run_frame`.
+fn run_faulty_frame(emulator: &mut Emulator, injector: &mut FaultInjector) {
+    let cpu = &mut emulator.cpu;
+    let frame = cpu.bus.ppu.frame_count();
+    while cpu.bus.ppu.frame_count() == frame {
+        injector.apply(&mut cpu.bus, cpu.tick_count);
+        if !cpu.tick() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu_6502::test_helpers::{assemble, emulator_from_asm, SUM_CONTROLLERS};
+    use crate::mappers::SimpleProgram;
+
+    fn runner(script: InputScript) -> HeadlessRunner {
+        let emulator = emulator_from_asm(SUM_CONTROLLERS);
+        HeadlessRunner::new(emulator, script)
+    }
+
+    #[test]
+    fn test_input_script() {
+        let script = InputScript::from_text(
+            "
+            # Start the game.
+            10 Start
+            11 .
+            20 A+Right B
+            30 . .
+            ",
+        )
+        .unwrap();
+        assert_eq!(script.buttons_at(0), [0, 0]);
+        assert_eq!(script.buttons_at(10), [0b1000, 0]);
+        assert_eq!(script.buttons_at(25), [0b1000_0001, 0b10]);
+        assert_eq!(script.buttons_at(100), [0, 0]);
+
+        assert!(InputScript::from_text("Start").is_err());
+        assert!(InputScript::from_text("10 A B C").is_err());
+        assert!(InputScript::from_text("10 Jump").is_err());
+        assert!(InputScript::from_text("10 A*0").is_err());
+    }
+
+    #[test]
+    fn test_headless_runner() {
+        let mut script = InputScript::default();
+        script.set_buttons(5, [0b1, 0]);
+        script.set_buttons(8, [0b1, 0b1]);
+
+        let result = runner(script.clone()).run(10);
+        assert_eq!(result.frames, 10);
+        // Running again in pieces gives the same hashes.
+        let mut pieces = runner(script.clone());
+        pieces.run(3);
+        assert_eq!(pieces.run(7), result);
+
+        let ram = pieces.emulator.cpu.bus.ram();
+        assert_ne!(ram[0x10], 0);
+        assert_ne!(ram[0x11], 0);
+
+        // The hashes change with the input.
+        script.set_buttons(9, [0b1, 0]);
+        let changed = runner(script).run(10);
+        assert_ne!(changed.ram_hash, result.ram_hash);
+        assert_ne!(changed.hash(), result.hash());
+    }
+
+    #[test]
+    fn test_faults() {
+        let faulty_runner = || {
+            let cartridge = SimpleProgram::load(&assemble(SUM_CONTROLLERS));
+            let fault = Fault::FlipRamBit {
+                address: 0x10,
+                bit: 7,
+            };
+            let schedule = FaultSchedule::new(vec![ScheduledFault { tick: 100, fault }]);
+            HeadlessRunner::with_faults(
+                Box::new(cartridge),
+                InputScript::default(),
+                schedule,
+            )
+        };
+        let result = faulty_runner().run(2);
+        assert_ne!(result, runner(InputScript::default()).run(2));
+        // The same schedule gives the same run.
+        assert_eq!(faulty_runner().run(2), result);
+    }
+}
@@ -1,11 +1,38 @@
 // Clippy rules to disable.
 #![allow(clippy::new_without_default)]
 
-pub mod asm;
-pub mod bus;
-pub mod constants;
+pub mod annotations;
+pub mod asm_project;
+#[cfg(test)]
+mod conformance;
+pub mod controller;
+pub mod core_info;
 pub mod cpu_6502;
+pub mod disassembler;
+pub mod emulator;
+pub mod gallery;
+pub mod hang;
+pub mod headless;
+pub mod lockstep;
 pub mod log;
-pub mod mappers;
-pub mod opcodes;
-pub mod ppu;
+pub mod movie;
+pub mod replay;
+pub mod rom;
+pub mod rom_export;
+pub mod screenshot;
+pub mod session;
+pub mod storage;
+pub mod symbols;
+pub mod watch;
+pub mod watchdog;
+
+// The CPU is in mos6502-core, and the NES around it is in nes-system. Re-export them
+// so that the frontends only need this crate. The controller and rom modules add the
+// parts that need TOML and the assembler.
+pub use mos6502_core::{opcodes, ram_bus};
+pub use nes_system::{
+    apu, bus, constants, irq, mappers, memory_map, power_up, ppu, save_state, write_log,
+};
+
+// The assembler is its own crate, re-export it for convenience.
+pub use mos6502_asm as asm;
@@ -0,0 +1,154 @@
+//! Runs two emulators side by side on the same input, and stops at the first frame
+//! where their pictures or states differ. Set them up differently, e.g. with another
+//! CPU variant or power up preset, or with a rewritten ROM, to check that a change
+//! only changes what it's meant to. To compare a run against an older build of the
+//! emulator, record a `replay::ReplayLog` with it instead.
+
+use crate::controller::PLAYERS;
+use crate::emulator::Emulator;
+use crate::headless::InputScript;
+use crate::replay::state_hash;
+use crate::save_state::{diff_states, SaveState, StateDifference};
+
+/// The first frame where the emulators differed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// The frames run since the lockstep started, counting the one that differed.
+    pub frame: u64,
+    /// The first pixel that's different, counting across then down, if the pictures
+    /// differ.
+    pub first_pixel: Option<usize>,
+    pub a_hash: u64,
+    pub b_hash: u64,
+    /// What's different in B's state, compared to A's.
+    pub differences: Vec<StateDifference>,
+}
+
+impl Divergence {
+    /// A description of the divergence with every difference, for dumping to a log.
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "The emulators diverged on frame {}, with the state hashes {:016x} and \
+             {:016x}.",
+            self.frame, self.a_hash, self.b_hash
+        );
+        if let Some(pixel) = self.first_pixel {
+            let width = crate::ppu::render::SCREEN_WIDTH;
+            report.push_str(&format!(
+                "\n  The pictures differ from x {}, y {}.",
+                pixel % width,
+                pixel / width
+            ));
+        }
+        for difference in &self.differences {
+            report.push_str("\n  ");
+            report.push_str(&difference.to_text());
+        }
+        report
+    }
+}
+
+pub struct Lockstep {
+    pub a: Emulator,
+    pub b: Emulator,
+    frame: u64,
+}
+
+impl Lockstep {
+    pub fn new(a: Emulator, b: Emulator) -> Lockstep {
+        Lockstep { a, b, frame: 0 }
+    }
+
+    /// The frames run since the lockstep started.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Run a frame on both emulators with the same buttons held, and compare them.
+    pub fn run_frame(
+        &mut self,
+        buttons: [u8; PLAYERS],
+    ) -> Result<Option<Divergence>, String> {
+        for emulator in [&mut self.a, &mut self.b] {
+            let bus = &mut emulator.cpu.bus;
+            bus.controller_1.set_buttons(buttons[0]);
+            bus.controller_2.set_buttons(buttons[1]);
+            emulator.run_frame();
+        }
+        self.frame += 1;
+
+        let first_pixel = self
+            .a
+            .framebuffer()
+            .chunks(4)
+            .zip(self.b.framebuffer().chunks(4))
+            .position(|(a, b)| a != b);
+        let a_state = self.a.cpu.save_state();
+        let b_state = self.b.cpu.save_state();
+        if first_pixel.is_none() && a_state == b_state {
+            return Ok(None);
+        }
+        Ok(Some(Divergence {
+            frame: self.frame,
+            first_pixel,
+            a_hash: state_hash(&a_state),
+            b_hash: state_hash(&b_state),
+            differences: diff_states(&a_state, &b_state)?,
+        }))
+    }
+
+    /// Run up to `frames` more frames, pressing the scripted buttons, and stop at the
+    /// first one that differs. The script's frames count from the start of the
+    /// lockstep.
+    pub fn run(
+        &mut self,
+        script: &InputScript,
+        frames: u64,
+    ) -> Result<Option<Divergence>, String> {
+        for _ in 0..frames {
+            if let Some(divergence) = self.run_frame(script.buttons_at(self.frame))? {
+                return Ok(Some(divergence));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu_6502::test_helpers::emulator_from_asm;
+
+    fn emulator() -> Emulator {
+        emulator_from_asm("loop:\ninc $10\njmp loop")
+    }
+
+    #[test]
+    fn test_lockstep() {
+        let mut lockstep = Lockstep::new(emulator(), emulator());
+        assert_eq!(lockstep.run(&InputScript::default(), 5), Ok(None));
+        assert_eq!(lockstep.frame(), 5);
+
+        // Memory that the program doesn't use is still compared.
+        lockstep.b.cpu.bus.set_u8(0x20, 1);
+        let divergence = lockstep.run_frame([0; PLAYERS]).unwrap().unwrap();
+        assert_eq!(divergence.frame, 6);
+        assert_eq!(divergence.first_pixel, None);
+        assert_eq!(
+            divergence.differences,
+            [StateDifference::Ram {
+                address: 0x20,
+                expected: 0,
+                actual: 1
+            }]
+        );
+        assert!(divergence.report().contains("frame 6"));
+
+        // The backdrop color changes the whole picture.
+        let mut lockstep = Lockstep::new(emulator(), emulator());
+        lockstep.b.cpu.bus.ppu.state.palette_ram.write(0x3f00, 0x21);
+        let divergence = lockstep.run_frame([0; PLAYERS]).unwrap().unwrap();
+        assert_eq!(divergence.first_pixel, Some(0));
+        assert!(divergence.report().contains("x 0, y 0"));
+    }
+}
@@ -0,0 +1,262 @@
+//! Movies are the controller input of every frame, from a known starting point, so a
+//! play session can be watched again exactly, or shared. They're saved in FCEUX's FM2
+//! text format, so the input can be read by other tools.
+//!
+//! https://fceux.com/web/FM2.html
+//!
+//! FCEUX's own save states can't be loaded here, so a movie that starts from a save
+//! state keeps this emulator's state under the "cpu6502SaveState" key, which FCEUX
+//! ignores. Movies without it start from power on. The core that recorded the movie
+//! is kept under "cpu6502Core", so it isn't played back on one that runs differently.
+
+use crate::controller::PLAYERS;
+use crate::core_info::CoreInfo;
+
+/// The FM2 button order, from the highest Button bit to the lowest.
+const FM2_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+
+/// The FM2 command bits for a soft and a hard reset.
+const FM2_RESETS: u8 = 0b11;
+
+const SAVE_STATE_KEY: &str = "cpu6502SaveState";
+const CORE_KEY: &str = "cpu6502Core";
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Movie {
+    pub rom_filename: String,
+    /// The state the movie starts from, or None to start from power on.
+    pub start_state: Option<Vec<u8>>,
+    /// The buttons held on each controller during each frame.
+    pub frames: Vec<[u8; PLAYERS]>,
+    /// How many times the recording was rewound and recorded over.
+    pub rerecords: u64,
+    /// The core that recorded it, or None for movies from other emulators.
+    pub core: Option<CoreInfo>,
+}
+
+/// What the emulator is doing with a movie.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MovieState {
+    Recording(Movie),
+    /// The movie, and the next frame to play. The controllers are let go after the
+    /// last frame.
+    Playing(Movie, usize),
+}
+
+impl MovieState {
+    pub fn movie(&self) -> &Movie {
+        match self {
+            MovieState::Recording(movie) | MovieState::Playing(movie, _) => movie,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        match self {
+            MovieState::Recording(_) => false,
+            MovieState::Playing(movie, frame) => *frame >= movie.frames.len(),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>, String> {
+    let error = || "The movie's save state isn't valid hex.".to_string();
+    if !text.len().is_multiple_of(2) {
+        return Err(error());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| {
+            text.get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(error)
+        })
+        .collect()
+}
+
+/// e.g. "R......A" for Right and A.
+fn fm2_port(buttons: u8) -> String {
+    FM2_BUTTONS
+        .iter()
+        .enumerate()
+        .map(|(index, name)| match buttons & (0x80 >> index) {
+            0 => '.',
+            _ => *name as char,
+        })
+        .collect()
+}
+
+fn parse_fm2_port(text: &str) -> Result<u8, String> {
+    if text.is_empty() {
+        // The port has nothing plugged in.
+        return Ok(0);
+    }
+    if text.len() != FM2_BUTTONS.len() {
+        return Err(format!("Expected 8 buttons in the port \"{}\"", text));
+    }
+    // Anything other than a space or a "." is pressed.
+    Ok(text
+        .bytes()
+        .enumerate()
+        .filter(|(_, byte)| *byte != b'.' && *byte != b' ')
+        .fold(0, |buttons, (index, _)| buttons | 0x80 >> index))
+}
+
+impl Movie {
+    pub fn to_fm2(&self) -> String {
+        let mut text = String::new();
+        let mut key = |key: &str, value: &str| {
+            text.push_str(key);
+            text.push(' ');
+            text.push_str(value);
+            text.push('\n');
+        };
+        key("version", "3");
+        key("emuVersion", "0");
+        key("rerecordCount", &self.rerecords.to_string());
+        key("palFlag", "0");
+        key("romFilename", &self.rom_filename);
+        key("guid", "00000000-0000-0000-0000-000000000000");
+        key("fourscore", "0");
+        key("port0", "1");
+        key("port1", "1");
+        key("port2", "0");
+        if let Some(ref state) = self.start_state {
+            key(SAVE_STATE_KEY, &to_hex(state));
+        }
+        if let Some(ref core) = self.core {
+            key(CORE_KEY, &core.to_text());
+        }
+        for [player_1, player_2] in &self.frames {
+            text.push_str(&format!(
+                "|0|{}|{}||\n",
+                fm2_port(*player_1),
+                fm2_port(*player_2)
+            ));
+        }
+        text
+    }
+
+    /// Read an FM2 movie. Only the standard controllers are supported, and a reset
+    /// can only be on the first frame, where it's the power on.
+    pub fn from_fm2(text: &str) -> Result<Movie, String> {
+        let mut movie = Movie::default();
+        for line in text.lines() {
+            if let Some(frame) = line.strip_prefix('|') {
+                let fields: Vec<&str> = frame.split('|').collect();
+                if fields.len() < 3 {
+                    return Err(format!(
+                        "Expected the input for a frame in \"{}\"",
+                        line
+                    ));
+                }
+                let commands: u8 = fields[0]
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Expected the commands in \"{}\"", line))?;
+                if commands & FM2_RESETS != 0 && !movie.frames.is_empty() {
+                    return Err(format!(
+                        "Frame {} resets the console, which isn't supported.",
+                        movie.frames.len()
+                    ));
+                }
+                movie
+                    .frames
+                    .push([parse_fm2_port(fields[1])?, parse_fm2_port(fields[2])?]);
+                continue;
+            }
+            let (key, value) = match line.split_once(' ') {
+                Some((key, value)) => (key, value.trim()),
+                None => continue,
+            };
+            match key {
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "rerecordCount" => movie.rerecords = value.parse().unwrap_or(0),
+                "fourscore" if value != "0" => {
+                    return Err("Four player movies aren't supported.".into())
+                }
+                "palFlag" if value != "0" => {
+                    return Err("PAL movies aren't supported.".into())
+                }
+                SAVE_STATE_KEY => movie.start_state = Some(from_hex(value)?),
+                CORE_KEY => movie.core = Some(CoreInfo::from_text(value)?),
+                _ => {}
+            }
+        }
+        Ok(movie)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu_6502::test_helpers::{emulator_from_asm, SUM_CONTROLLERS};
+    use crate::save_state::SaveState;
+
+    #[test]
+    fn test_fm2() {
+        let movie = Movie {
+            rom_filename: "game".into(),
+            start_state: Some(vec![0x01, 0xab]),
+            frames: vec![[0b1000_0001, 0], [0, 0b0000_1000]],
+            rerecords: 3,
+            core: Some(crate::core_info::core_info()),
+        };
+        let fm2 = movie.to_fm2();
+        assert!(fm2.contains("cpu6502SaveState 01ab\ncpu6502Core version="));
+        assert!(fm2.ends_with("|0|R......A|........||\n|0|........|....T...||\n"));
+        assert_eq!(Movie::from_fm2(&fm2), Ok(movie));
+
+        // As FCEUX writes them, with a power on, and nothing in the second port.
+        let movie = Movie::from_fm2(
+            "version 3\nromFilename smb\nport1 0\n|2|........|||\n|0|...UT..A|||\n",
+        )
+        .unwrap();
+        assert_eq!(movie.start_state, None);
+        assert_eq!(movie.core, None);
+        assert_eq!(movie.frames, [[0, 0], [0b0001_1001, 0]]);
+
+        assert!(Movie::from_fm2("|0|R|||").is_err());
+        assert!(Movie::from_fm2("|0|........|||\n|1|........|||").is_err());
+        assert!(Movie::from_fm2("fourscore 1").is_err());
+        assert!(Movie::from_fm2("cpu6502SaveState 0g").is_err());
+    }
+
+    #[test]
+    fn test_record_and_play() {
+        let mut emulator = emulator_from_asm(SUM_CONTROLLERS);
+        emulator.run_frame();
+
+        emulator.record_movie("test");
+        for frame in 0..6 {
+            emulator
+                .cpu
+                .bus
+                .controller_1
+                .set_buttons((frame % 3 == 0) as u8);
+            emulator.run_frame();
+        }
+        let end_state = emulator.cpu.save_state();
+        let movie = emulator.stop_movie().unwrap();
+        assert_eq!(movie.frames.len(), 6);
+        assert_eq!(movie.frames[3], [1, 0]);
+
+        // The movie plays back the same, from the saved file.
+        let movie = Movie::from_fm2(&movie.to_fm2()).unwrap();
+        emulator.cpu.bus.controller_1.set_buttons(0);
+        emulator.play_movie(movie).unwrap();
+        for _ in 0..6 {
+            emulator.run_frame();
+        }
+        assert!(emulator.movie_state().unwrap().is_finished());
+        assert_eq!(emulator.cpu.save_state(), end_state);
+
+        // It would run differently without the DMC DMA glitch.
+        let movie = emulator.stop_movie().unwrap();
+        emulator.cpu.bus.emulate_dmc_dma_controller_glitch = false;
+        assert!(emulator.play_movie(movie).is_err());
+    }
+}
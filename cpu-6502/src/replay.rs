@@ -0,0 +1,245 @@
+//! Checks that replaying a session's input reproduces the session exactly. While a
+//! session is recorded, the machine is hashed every few frames. Replaying the input
+//! from the same starting state must hit the same hashes, and the first checkpoint
+//! that doesn't is reported along with what changed in the machine. This catches
+//! emulation changes that break determinism, or that change what the game does.
+
+use crate::controller::{Controller, InputEvent, PLAYERS};
+use crate::emulator::Emulator;
+use crate::save_state::{diff_states, SaveState, StateDifference};
+
+/// A hash of the whole machine, from its save state.
+pub fn state_hash(state: &[u8]) -> u64 {
+    // FNV-1a, which is stable across Rust versions unlike the std hasher, so the
+    // hashes can be kept around.
+    state.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    /// The number of frames since the recording started.
+    pub frame: u64,
+    pub hash: u64,
+    /// The whole state, to show what diverged. Only the hash is needed to detect it.
+    pub state: Option<Vec<u8>>,
+}
+
+/// The input of a session, along with the checkpoints to verify a replay against.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayLog {
+    pub start_state: Vec<u8>,
+    /// The buttons that were already held when the recording started, which aren't
+    /// part of the save state.
+    pub start_buttons: [u8; PLAYERS],
+    /// The events for each controller, stamped with the frame relative to the start.
+    pub events: [Vec<InputEvent>; PLAYERS],
+    pub checkpoints: Vec<Checkpoint>,
+    /// The number of frames that were run.
+    pub frames: u64,
+}
+
+fn controller_mut(emulator: &mut Emulator, player: usize) -> &mut Controller {
+    let bus = &mut emulator.cpu.bus;
+    match player {
+        0 => &mut bus.controller_1,
+        _ => &mut bus.controller_2,
+    }
+}
+
+/// Records a session. The frontend queues the input through the recorder rather than
+/// directly on the controllers, and lets it know about each frame that was run.
+pub struct ReplayRecorder {
+    log: ReplayLog,
+    start_frames: [u64; PLAYERS],
+    interval: u64,
+    keep_states: bool,
+}
+
+impl ReplayRecorder {
+    /// Start recording between frames. A checkpoint is made every `interval` frames,
+    /// and `keep_states` keeps the whole state at each one so that a desync can be
+    /// diffed, which costs a few KB per checkpoint.
+    pub fn start(
+        emulator: &Emulator,
+        interval: u64,
+        keep_states: bool,
+    ) -> ReplayRecorder {
+        let bus = &emulator.cpu.bus;
+        let controllers = [&bus.controller_1, &bus.controller_2];
+        ReplayRecorder {
+            log: ReplayLog {
+                start_state: emulator.cpu.save_state(),
+                start_buttons: controllers.map(|controller| controller.buttons()),
+                events: Default::default(),
+                checkpoints: vec![],
+                frames: 0,
+            },
+            start_frames: controllers.map(|controller| controller.frame()),
+            interval: interval.max(1),
+            keep_states,
+        }
+    }
+
+    /// Queue an event on a controller, counting players from 0, and record it.
+    pub fn queue_event(
+        &mut self,
+        emulator: &mut Emulator,
+        player: usize,
+        event: InputEvent,
+    ) {
+        controller_mut(emulator, player).queue_event(event);
+        self.log.events[player].push(InputEvent {
+            frame: event.frame.saturating_sub(self.start_frames[player]),
+            ..event
+        });
+    }
+
+    /// Call this after each frame is run.
+    pub fn end_frame(&mut self, emulator: &Emulator) {
+        self.log.frames += 1;
+        if self.log.frames.is_multiple_of(self.interval) {
+            let state = emulator.cpu.save_state();
+            self.log.checkpoints.push(Checkpoint {
+                frame: self.log.frames,
+                hash: state_hash(&state),
+                state: self.keep_states.then_some(state),
+            });
+        }
+    }
+
+    pub fn finish(self) -> ReplayLog {
+        self.log
+    }
+}
+
+/// Where a replay first diverged from the recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Desync {
+    /// The checkpoint's frame, the divergence happened since the one before it.
+    pub frame: u64,
+    pub expected_hash: u64,
+    pub actual_hash: u64,
+    /// What's different, when the recording kept the states.
+    pub differences: Option<Vec<StateDifference>>,
+}
+
+impl Desync {
+    /// A description of the desync with every difference, for dumping to a log.
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "The replay desynced by frame {}, expected the hash {:016x} but found {:016x}.",
+            self.frame, self.expected_hash, self.actual_hash
+        );
+        match self.differences {
+            Some(ref differences) => {
+                for difference in differences {
+                    report.push_str("\n  ");
+                    report.push_str(&difference.to_text());
+                }
+            }
+            None => report.push_str("\nThe recording didn't keep the states to diff."),
+        }
+        report
+    }
+}
+
+impl ReplayLog {
+    /// Replay the input from the starting state, and stop at the first checkpoint that
+    /// doesn't match. The emulator must be running the same cartridge.
+    pub fn verify(&self, emulator: &mut Emulator) -> Result<Result<(), Desync>, String> {
+        emulator.cpu.load_state(&self.start_state)?;
+        for player in 0..PLAYERS {
+            let controller = controller_mut(emulator, player);
+            controller.set_buttons(self.start_buttons[player]);
+            let start_frame = controller.frame();
+            for event in &self.events[player] {
+                controller.queue_event(InputEvent {
+                    frame: start_frame + event.frame,
+                    ..*event
+                });
+            }
+        }
+
+        let mut checkpoints = self.checkpoints.iter().peekable();
+        for frame in 1..=self.frames {
+            emulator.run_frame();
+            let checkpoint =
+                match checkpoints.next_if(|checkpoint| checkpoint.frame == frame) {
+                    Some(checkpoint) => checkpoint,
+                    None => continue,
+                };
+            let state = emulator.cpu.save_state();
+            let hash = state_hash(&state);
+            if hash != checkpoint.hash {
+                let differences = match checkpoint.state {
+                    Some(ref expected) => Some(diff_states(expected, &state)?),
+                    None => None,
+                };
+                return Ok(Err(Desync {
+                    frame,
+                    expected_hash: checkpoint.hash,
+                    actual_hash: hash,
+                    differences,
+                }));
+            }
+        }
+        Ok(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::Button;
+    use crate::cpu_6502::test_helpers::{emulator_from_asm, SUM_CONTROLLERS};
+
+    fn emulator() -> Emulator {
+        emulator_from_asm(SUM_CONTROLLERS)
+    }
+
+    fn record() -> ReplayLog {
+        let mut emulator = emulator();
+        emulator.run_frame();
+        let mut recorder = ReplayRecorder::start(&emulator, 2, true);
+        for frame in 0..10 {
+            if frame == 3 || frame == 6 {
+                let controller_frame = emulator.cpu.bus.controller_1.frame();
+                recorder.queue_event(
+                    &mut emulator,
+                    0,
+                    InputEvent {
+                        frame: controller_frame,
+                        buttons: Button::A as u8,
+                        is_pressed: frame == 3,
+                    },
+                );
+            }
+            emulator.run_frame();
+            recorder.end_frame(&emulator);
+        }
+        recorder.finish()
+    }
+
+    #[test]
+    fn test_replay_matches() {
+        let log = record();
+        assert_eq!(log.checkpoints.len(), 5);
+        // A fresh emulator, whose controllers are on a different frame.
+        assert_eq!(log.verify(&mut emulator()), Ok(Ok(())));
+    }
+
+    #[test]
+    fn test_desync() {
+        let mut log = record();
+        log.events[0][0].frame += 1;
+        let desync = log.verify(&mut emulator()).unwrap().unwrap_err();
+        assert_eq!(desync.frame, 6);
+        let differences = desync.differences.unwrap();
+        assert!(differences.iter().any(|difference| matches!(
+            difference,
+            StateDifference::Ram { address: 0x10, .. }
+        )));
+    }
+}
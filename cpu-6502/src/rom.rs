@@ -0,0 +1,190 @@
+//! The ROM loading is in nes-system, this adds building iNES files out of assembled
+//! programs, which needs the assembler.
+
+pub use nes_system::rom::*;
+
+use crate::asm::{BytesLabels, ORIGIN};
+use crate::constants::InterruptVectors;
+
+/// Wrap an assembled program in an NROM iNES file with horizontal mirroring, see
+/// `ines_from_program`.
+pub fn nrom_from_program(program: &BytesLabels, chr: &[u8]) -> Result<Vec<u8>, String> {
+    ines_from_program(program, chr, &InesHeader::default())
+}
+
+/// Wrap an assembled program in an iNES file, so it runs here or in other emulators.
+/// The code is placed at $8000 in 32KB of PRG ROM, or at $c000 in 16KB, and the
+/// `reset`, `nmi`, and `irq` labels are written into the interrupt vectors, unless the
+/// program wrote them itself with a `.org $fffa`. The CHR ROM is in 8KB banks, and
+/// without any the cartridge uses CHR RAM.
+pub fn ines_from_program(
+    program: &BytesLabels,
+    chr: &[u8],
+    header: &InesHeader,
+) -> Result<Vec<u8>, String> {
+    let vectors_offset =
+        (InterruptVectors::NonMaskableInterrupt as u16 - ORIGIN) as usize;
+    let has_vectors = program.bytes.len() == NROM_PRG_SIZE;
+    if program.bytes.len() > vectors_offset && !has_vectors {
+        return Err(format!(
+            "The program is {} bytes, which runs into the interrupt vectors at $fffa.",
+            program.bytes.len()
+        ));
+    }
+    let chr_banks = chr.len() / CHR_BANK_SIZE;
+    if !chr.len().is_multiple_of(CHR_BANK_SIZE) || chr_banks > u8::MAX as usize {
+        return Err(format!(
+            "Expected the CHR to be up to 255 banks of {} bytes, but there are {} bytes.",
+            CHR_BANK_SIZE,
+            chr.len()
+        ));
+    }
+    if header.mapper == 0 && chr_banks > 1 {
+        return Err(format!(
+            "NROM only has one bank of CHR, but there are {} banks.",
+            chr_banks
+        ));
+    }
+
+    // The bytes that are left out below the PRG ROM.
+    let prg_start = match header.prg_banks {
+        1 | 2 => NROM_PRG_SIZE - header.prg_banks as usize * PRG_BANK_SIZE,
+        _ => return Err("The program is assembled into 1 or 2 banks of PRG ROM.".into()),
+    };
+    if program.bytes.iter().take(prg_start).any(|byte| *byte != 0) {
+        return Err(
+            "With one bank of PRG ROM the program starts at $c000, add an .org $c000."
+                .into(),
+        );
+    }
+
+    let mut ines = INES_MAGIC.to_vec();
+    ines.push(header.prg_banks);
+    ines.push(chr_banks as u8);
+    ines.extend_from_slice(&header.flags()?);
+    ines.resize(INES_HEADER_SIZE, 0);
+    ines.extend_from_slice(program.bytes.get(prg_start..).unwrap_or_default());
+    if !has_vectors {
+        let reset = label_address(program, "reset")
+            .ok_or("The program needs a \"reset:\" label to start from.")?;
+        // Without handlers, the interrupts restart the program.
+        let nmi = label_address(program, "nmi").unwrap_or(reset);
+        let irq = label_address(program, "irq").unwrap_or(reset);
+        ines.resize(INES_HEADER_SIZE + vectors_offset - prg_start, 0);
+        for vector in [nmi, reset, irq] {
+            ines.extend_from_slice(&vector.to_le_bytes());
+        }
+    }
+    ines.extend_from_slice(chr);
+    Ok(ines)
+}
+
+/// The address of a label, for the interrupt vectors.
+fn label_address(program: &BytesLabels, name: &str) -> Option<u16> {
+    program
+        .address_to_label
+        .iter()
+        .find(|(_, label)| label.as_str() == name)
+        .map(|(address, _)| *address)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::AsmLexer;
+    use crate::cpu_6502::{NesCpu, Step};
+    use crate::emulator::Emulator;
+    use crate::ppu::Mirroring;
+
+    #[test]
+    fn test_nrom_from_program() {
+        let mut lexer = AsmLexer::new("nmi:\nrti\nreset:\njmp reset");
+        lexer.parse().unwrap();
+        let program = lexer.into_bytes().unwrap();
+        let rom =
+            InesRom::from_ines_bytes(&nrom_from_program(&program, &[]).unwrap()).unwrap();
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.prg_rom.len(), NROM_PRG_SIZE);
+        assert!(rom.chr_rom.is_empty());
+        assert_eq!(rom.prg_rom[..4], [0x40, 0x4c, 0x01, 0x80]);
+        // NMI, RESET, and IRQ, which falls back to the reset.
+        assert_eq!(rom.prg_rom[0x7ffa..], [0x00, 0x80, 0x01, 0x80, 0x01, 0x80]);
+
+        assert!(nrom_from_program(&program, &[0; 10]).is_err());
+
+        // The vectors can be written in the asm instead.
+        let mut lexer = AsmLexer::new(
+            ".org $c000\nstart:\njmp start\n.org $fffa\n.word start, start, start",
+        );
+        lexer.parse().unwrap();
+        let program = lexer.into_bytes().unwrap();
+        let rom =
+            InesRom::from_ines_bytes(&nrom_from_program(&program, &[]).unwrap()).unwrap();
+        assert_eq!(rom.prg_rom[0x4000..0x4003], [0x4c, 0x00, 0xc0]);
+        assert_eq!(rom.prg_rom[0x7ffa..], [0x00, 0xc0, 0x00, 0xc0, 0x00, 0xc0]);
+        let mut lexer = AsmLexer::new("nop");
+        lexer.parse().unwrap();
+        assert!(nrom_from_program(&lexer.into_bytes().unwrap(), &[]).is_err());
+    }
+
+    #[test]
+    fn test_ines_from_program() {
+        let mut lexer = AsmLexer::new("reset:\njmp reset");
+        lexer.parse().unwrap();
+        let program = lexer.into_bytes().unwrap();
+        let header = InesHeader {
+            mapper: 0x42,
+            mirroring: InesHeader::mirroring_from_name("Vertical").unwrap(),
+            has_battery: true,
+            prg_banks: 2,
+        };
+        let chr: Vec<u8> = (0..2).flat_map(|bank| [bank; CHR_BANK_SIZE]).collect();
+        let rom = InesRom::from_ines_bytes(
+            &ines_from_program(&program, &chr, &header).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(rom.mapper, 0x42);
+        assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert!(rom.has_battery);
+        assert_eq!(rom.chr_rom, chr);
+
+        // An UxROM with four screens of VRAM, which runs here.
+        let header = InesHeader {
+            mapper: 2,
+            mirroring: Mirroring::FourScreen,
+            has_battery: false,
+            prg_banks: 2,
+        };
+        let mut emulator = Emulator::from_ines_bytes(
+            &ines_from_program(&program, &[], &header).unwrap(),
+        )
+        .unwrap();
+        emulator.cpu.step(Step::Frames(1));
+        assert_eq!(emulator.cpu.pc, 0x8000);
+
+        let header = InesHeader::default();
+        assert!(ines_from_program(&program, &chr, &header).is_err());
+        let header = InesHeader {
+            mirroring: Mirroring::SingleScreenLower,
+            ..header
+        };
+        assert!(ines_from_program(&program, &[], &header).is_err());
+        assert!(InesHeader::mirroring_from_name("diagonal").is_err());
+
+        // NROM-128, which is mirrored into $8000 as well.
+        let header = InesHeader {
+            prg_banks: 1,
+            ..InesHeader::default()
+        };
+        assert!(ines_from_program(&program, &[], &header).is_err());
+        let mut lexer = AsmLexer::new(".org $c000\nreset:\njmp reset");
+        lexer.parse().unwrap();
+        let program = lexer.into_bytes().unwrap();
+        let rom =
+            InesRom::from_ines_bytes(&ines_from_program(&program, &[], &header).unwrap())
+                .unwrap();
+        assert_eq!(rom.prg_rom.len(), PRG_BANK_SIZE);
+        assert_eq!(rom.prg_rom[..3], [0x4c, 0x00, 0xc0]);
+        assert_eq!(rom.prg_rom[0x3ffc..0x3ffe], [0x00, 0xc0]);
+    }
+}
@@ -0,0 +1,780 @@
+//! Turn an NROM ROM back into an asm project, so a game can be read, changed, and
+//! built again with the `asm` tool. The project builds back into the same PRG and CHR
+//! ROM, which is checked before it's written.
+//!
+//!   cargo task export-rom game.nes game [game.cdl]
+//!
+//! The code is found by following the interrupt vectors, and every jump and branch
+//! from there. A code/data log from a play session finds the code that's only reached
+//! through jump tables, and keeps the data out of the code. Everything else is data,
+//! with the long runs of it kept in `data.bin` and pulled in with `.incbin`. The CHR
+//! ROM is written to `game.chr`, and listed in the `asm.toml`.
+//!
+//! Only the instructions that the assembler encodes into the same bytes are written as
+//! code, so unofficial opcodes that share a mnemonic stay as `.byte`.
+//!
+//! The `disasm` tool uses the same tracing to write a single file, either for an NROM
+//! game, or a raw program that's loaded at any address.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::asm::{AsmLexer, Segment, Sources, ORIGIN};
+use crate::constants::InterruptVectors;
+use crate::disassembler::{disassemble_instruction, DisassembledInstruction};
+use crate::opcodes::Mode;
+use crate::ppu::Mirroring;
+use crate::rom::{ines_from_program, InesHeader, InesRom, PRG_BANK_SIZE};
+
+pub const MAIN_ASM: &str = "main.asm";
+pub const DATA_BIN: &str = "data.bin";
+pub const GAME_CHR: &str = "game.chr";
+pub const ASM_TOML: &str = "asm.toml";
+
+/// Runs of data at least this long go in the data.bin, rather than in `.byte` lines.
+const INCBIN_SIZE: usize = 64;
+const BYTES_PER_LINE: usize = 16;
+/// The vectors are written as a `.word`, so nothing else can run into them.
+const VECTORS: u16 = InterruptVectors::NonMaskableInterrupt as u16;
+
+/// Which bytes of the PRG ROM were used as code or as data in a play session. This is
+/// FCEUX's .cdl file, which has a byte of flags for each byte of the PRG ROM, and then
+/// of the CHR ROM.
+///
+/// https://fceux.com/web/help/CodeDataLogger.html
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+}
+
+impl CodeDataLog {
+    const CODE: u8 = 0b01;
+    const DATA: u8 = 0b10;
+
+    pub fn from_cdl_bytes(bytes: &[u8], prg_size: usize) -> Result<CodeDataLog, String> {
+        match bytes.get(..prg_size) {
+            Some(prg) => Ok(CodeDataLog { prg: prg.to_vec() }),
+            None => Err(format!(
+                "The code/data log is {} bytes, but the PRG ROM is {} bytes.",
+                bytes.len(),
+                prg_size
+            )),
+        }
+    }
+
+    fn flags(&self, offset: usize) -> u8 {
+        self.prg.get(offset).copied().unwrap_or(0)
+    }
+
+    fn is_code(&self, offset: usize) -> bool {
+        self.flags(offset) & CodeDataLog::CODE != 0
+    }
+
+    /// Read as data, and never run.
+    fn is_data(&self, offset: usize) -> bool {
+        self.flags(offset) & (CodeDataLog::CODE | CodeDataLog::DATA) == CodeDataLog::DATA
+    }
+}
+
+/// The files of an exported project.
+pub struct RomExport {
+    pub main_asm: String,
+    /// The long runs of data, which main.asm includes with `.incbin`.
+    pub data: Vec<u8>,
+    /// Empty when the cartridge uses CHR RAM.
+    pub chr: Vec<u8>,
+    pub asm_toml: String,
+}
+
+/// The operand as the assembler reads it. Branches and absolute addresses use the
+/// label at their target when there is one.
+fn asm_operand(
+    instruction: &DisassembledInstruction,
+    labels: &BTreeMap<u16, String>,
+) -> String {
+    let label = instruction.target.and_then(|target| labels.get(&target));
+    match (instruction.mode, label) {
+        (Mode::Relative | Mode::Absolute, Some(label)) => label.clone(),
+        // The assembler takes the raw offset of a branch.
+        (Mode::Relative, None) => format!("${:02x}", instruction.bytes[1]),
+        _ => instruction.operand.clone(),
+    }
+}
+
+fn asm_line(
+    instruction: &DisassembledInstruction,
+    labels: &BTreeMap<u16, String>,
+) -> String {
+    let operand = asm_operand(instruction, labels);
+    if operand.is_empty() {
+        instruction.mnemonic.to_string()
+    } else {
+        format!("{} {}", instruction.mnemonic, operand)
+    }
+}
+
+/// The opcodes that the assembler reads back from the disassembly as the same bytes.
+fn assemblable_opcodes() -> Vec<bool> {
+    (0..=0xff)
+        .map(|opcode: u8| {
+            // The operand bytes are arbitrary, but not zero, which would look like an
+            // empty operand.
+            let read_u8 = |address: u16| if address == 0x8000 { opcode } else { 0x12 };
+            let instruction = disassemble_instruction(read_u8, 0x8000, None);
+            let text = asm_line(&instruction, &BTreeMap::new());
+            let mut lexer = AsmLexer::new(&text);
+            lexer.parse().is_ok()
+                && lexer
+                    .into_bytes()
+                    .is_ok_and(|program| program.bytes == instruction.bytes)
+        })
+        .collect()
+}
+
+/// Follows the code from its entry points, and lays out the lines of the program.
+struct Exporter<'a> {
+    bytes: &'a [u8],
+    /// The address of the first byte.
+    origin: u16,
+    /// The address after the last line of code or data, which is before the vectors
+    /// when there are any.
+    end: usize,
+    has_vectors: bool,
+    /// The file that long runs of data go in, or None to write them as `.byte` lines.
+    data_file: Option<&'static str>,
+    code_data_log: Option<&'a CodeDataLog>,
+    assemblable: Vec<bool>,
+    /// The instructions, by address.
+    instructions: BTreeMap<u16, DisassembledInstruction>,
+    /// Which bytes are part of an instruction.
+    is_code: Vec<bool>,
+    labels: BTreeMap<u16, String>,
+}
+
+impl<'a> Exporter<'a> {
+    fn new(bytes: &'a [u8], origin: u16, has_vectors: bool) -> Exporter<'a> {
+        Exporter {
+            bytes,
+            origin,
+            end: if has_vectors {
+                VECTORS as usize
+            } else {
+                origin as usize + bytes.len()
+            },
+            has_vectors,
+            data_file: None,
+            code_data_log: None,
+            assemblable: assemblable_opcodes(),
+            instructions: BTreeMap::new(),
+            is_code: vec![false; bytes.len()],
+            labels: BTreeMap::new(),
+        }
+    }
+
+    fn offset(&self, address: u16) -> usize {
+        (address - self.origin) as usize
+    }
+
+    fn read_u8(&self, address: u16) -> u8 {
+        address
+            .checked_sub(self.origin)
+            .and_then(|offset| self.bytes.get(offset as usize))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn vector(&self, vector: InterruptVectors) -> u16 {
+        let address = vector as u16;
+        u16::from_le_bytes([self.read_u8(address), self.read_u8(address + 1)])
+    }
+
+    /// The instruction at an address, if it can be code.
+    fn instruction_at(&self, address: u16) -> Option<DisassembledInstruction> {
+        if address < self.origin || address as usize >= self.end {
+            return None;
+        }
+        if !self.assemblable[self.read_u8(address) as usize] {
+            return None;
+        }
+        let instruction = disassemble_instruction(|a| self.read_u8(a), address, None);
+        if address as usize + instruction.bytes.len() > self.end {
+            return None;
+        }
+        let offsets =
+            self.offset(address)..self.offset(address) + instruction.bytes.len();
+        let is_free = offsets.clone().all(|offset| !self.is_code[offset]);
+        let is_data = offsets.clone().any(|offset| {
+            self.code_data_log
+                .is_some_and(|code_data_log| code_data_log.is_data(offset))
+        });
+        if is_free && !is_data {
+            Some(instruction)
+        } else {
+            None
+        }
+    }
+
+    /// Follow the code from an address, through every branch and jump.
+    fn trace(&mut self, entry: u16) {
+        let mut addresses = vec![entry];
+        while let Some(address) = addresses.pop() {
+            if self.instructions.contains_key(&address) {
+                continue;
+            }
+            let instruction = match self.instruction_at(address) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            let offset = self.offset(address);
+            for is_code in &mut self.is_code[offset..offset + instruction.bytes.len()] {
+                *is_code = true;
+            }
+            let falls_through =
+                !matches!(instruction.mnemonic, "jmp" | "rts" | "rti" | "brk" | "kil");
+            if falls_through {
+                addresses.push(instruction.next_address());
+            }
+            let is_jump = instruction.mnemonic == "jsr"
+                || instruction.mode == Mode::Relative
+                || (instruction.mnemonic == "jmp" && instruction.mode == Mode::Absolute);
+            if let (true, Some(target)) = (is_jump, instruction.target) {
+                addresses.push(target);
+            }
+            self.instructions.insert(address, instruction);
+        }
+    }
+
+    /// Find the code from the vectors, the entry points, and the code/data log, and
+    /// then name the places that it uses.
+    fn run(&mut self, entries: &[u16]) {
+        if self.has_vectors {
+            for vector in [
+                InterruptVectors::ResetVector,
+                InterruptVectors::NonMaskableInterrupt,
+                InterruptVectors::IrqBrkVector,
+            ] {
+                self.trace(self.vector(vector));
+            }
+        }
+        for entry in entries {
+            self.trace(*entry);
+        }
+        if let Some(code_data_log) = self.code_data_log {
+            // Follow each run of logged code, like the targets of a jump table.
+            for offset in 0..self.bytes.len() {
+                if code_data_log.is_code(offset)
+                    && (offset == 0 || !code_data_log.is_code(offset - 1))
+                {
+                    self.trace(self.origin + offset as u16);
+                }
+            }
+        }
+        self.add_labels();
+    }
+
+    /// A line can start at any address outside of the middle of an instruction.
+    fn is_line_start(&self, address: u16) -> bool {
+        address >= self.origin
+            && (address as usize) < self.end
+            && (self.instructions.contains_key(&address)
+                || !self.is_code[self.offset(address)])
+    }
+
+    fn add_label(&mut self, address: u16, name: String) {
+        if self.is_line_start(address) && !self.labels.contains_key(&address) {
+            self.labels.insert(address, name);
+        }
+    }
+
+    fn add_labels(&mut self) {
+        if self.has_vectors {
+            self.add_label(self.vector(InterruptVectors::ResetVector), "reset".into());
+            self.add_label(
+                self.vector(InterruptVectors::NonMaskableInterrupt),
+                "nmi".into(),
+            );
+            self.add_label(self.vector(InterruptVectors::IrqBrkVector), "irq".into());
+        }
+        let targets: Vec<(u16, String)> = self
+            .instructions
+            .values()
+            .filter_map(|instruction| {
+                let target = instruction.target?;
+                let prefix = match instruction.mnemonic {
+                    "jsr" => "sub",
+                    _ if self.instructions.contains_key(&target) => "loc",
+                    _ => "data",
+                };
+                Some((target, format!("{}_{:04x}", prefix, target)))
+            })
+            .collect();
+        for (address, name) in targets {
+            self.add_label(address, name);
+        }
+    }
+
+    /// The assembly for the data from `start` up to `end`, which is broken up at the
+    /// labels.
+    fn write_data(&self, text: &mut String, data: &mut Vec<u8>, start: u16, end: usize) {
+        let breaks = self
+            .labels
+            .range(start..)
+            .map(|(address, _)| *address as usize)
+            .skip_while(|address| *address == start as usize)
+            .take_while(|address| *address < end);
+        let mut run_start = start as usize;
+        for run_end in breaks.chain(std::iter::once(end)) {
+            match self.labels.get(&(run_start as u16)) {
+                Some(label) => text.push_str(&format!("\n{}:\n", label)),
+                None if run_start == start as usize => text.push('\n'),
+                None => {}
+            }
+            let origin = self.origin as usize;
+            let bytes = &self.bytes[run_start - origin..run_end - origin];
+            match self.data_file {
+                Some(data_file) if bytes.len() >= INCBIN_SIZE => {
+                    text.push_str(&format!(
+                        "    .incbin \"{}\", ${:x}, {}\n",
+                        data_file,
+                        data.len(),
+                        bytes.len()
+                    ));
+                    data.extend_from_slice(bytes);
+                }
+                _ => {
+                    for line in bytes.chunks(BYTES_PER_LINE) {
+                        let values: Vec<String> =
+                            line.iter().map(|byte| format!("${:02x}", byte)).collect();
+                        text.push_str(&format!("    .byte {}\n", values.join(", ")));
+                    }
+                }
+            }
+            run_start = run_end;
+        }
+    }
+
+    /// The program, and the data that it includes.
+    fn write(&self) -> (String, Vec<u8>) {
+        let mut text = String::new();
+        let mut data = Vec::new();
+        if self.origin != ORIGIN {
+            text.push_str(&format!(".org ${:04x}\n", self.origin));
+        }
+        let mut address = self.origin as usize;
+        while address < self.end {
+            match self.instructions.get(&(address as u16)) {
+                Some(instruction) => {
+                    if let Some(label) = self.labels.get(&(address as u16)) {
+                        text.push_str(&format!("\n{}:\n", label));
+                    }
+                    text.push_str(&format!(
+                        "    {}\n",
+                        asm_line(instruction, &self.labels)
+                    ));
+                    address += instruction.bytes.len();
+                }
+                None => {
+                    let end = self
+                        .instructions
+                        .range(address as u16..)
+                        .next()
+                        .map_or(self.end, |(start, _)| *start as usize);
+                    self.write_data(&mut text, &mut data, address as u16, end);
+                    address = end;
+                }
+            }
+        }
+
+        if self.has_vectors {
+            let vectors: Vec<String> = [
+                self.vector(InterruptVectors::NonMaskableInterrupt),
+                self.vector(InterruptVectors::ResetVector),
+                self.vector(InterruptVectors::IrqBrkVector),
+            ]
+            .iter()
+            .map(|address| match self.labels.get(address) {
+                Some(label) => label.clone(),
+                None => format!("${:04x}", address),
+            })
+            .collect();
+            text.push_str(&format!("\n.org $fffa\n    .word {}\n", vectors.join(", ")));
+        }
+        (text, data)
+    }
+
+    /// A summary for the top of the program.
+    fn summary(&self) -> String {
+        format!(
+            "{} instructions and {} bytes of data",
+            self.instructions.len(),
+            self.bytes.len() - self.is_code.iter().filter(|c| **c).count(),
+        )
+    }
+}
+
+/// The PRG ROM of an NROM game, with the header it's built back into.
+fn nrom_exporter<'a>(
+    rom: &'a InesRom,
+    code_data_log: Option<&'a CodeDataLog>,
+    data_file: Option<&'static str>,
+    entries: &[u16],
+) -> Result<(Exporter<'a>, InesHeader), String> {
+    if rom.mapper != 0 {
+        return Err(format!(
+            "Only NROM games can be exported, but this one uses mapper {}.",
+            rom.mapper
+        ));
+    }
+    if rom.trainer.is_some() {
+        return Err("ROMs with a trainer can't be exported.".into());
+    }
+    let prg_banks = match rom.prg_rom.len() / PRG_BANK_SIZE {
+        1 => 1,
+        2 => 2,
+        _ => return Err("NROM has 1 or 2 banks of PRG ROM.".into()),
+    };
+    let mut exporter =
+        Exporter::new(&rom.prg_rom, (0x10000 - rom.prg_rom.len()) as u16, true);
+    exporter.code_data_log = code_data_log;
+    exporter.data_file = data_file;
+    exporter.run(entries);
+    let header = InesHeader {
+        mapper: 0,
+        mirroring: rom.mirroring,
+        has_battery: rom.has_battery,
+        prg_banks,
+    };
+    Ok((exporter, header))
+}
+
+/// Assemble a program, with the data file it includes, into an NROM's PRG ROM.
+fn build_prg(text: &str, data: &[u8], header: &InesHeader) -> Result<Vec<u8>, String> {
+    let sources = Sources::expand(MAIN_ASM, text, &mut |path, _| {
+        if path == DATA_BIN {
+            Ok((DATA_BIN.to_string(), data.to_vec()))
+        } else {
+            Err(format!("Unable to find the included file \"{}\"", path))
+        }
+    })?;
+    let mut lexer = AsmLexer::with_sources(&sources);
+    if let Err(parse_error) = lexer.parse() {
+        return Err(parse_error.nice_message().to_string());
+    }
+    let ines = ines_from_program(&lexer.into_bytes()?, &[], header)?;
+    Ok(InesRom::from_ines_bytes(&ines)?.prg_rom)
+}
+
+/// Disassemble the PRG ROM of an NROM game into a single program, with the data in
+/// `.byte` lines. The code is followed from the vectors and the entry points. It's
+/// checked to build back into the same PRG ROM.
+pub fn disassemble_rom(rom: &InesRom, entries: &[u16]) -> Result<String, String> {
+    let (exporter, header) = nrom_exporter(rom, None, None, entries)?;
+    let (code, _) = exporter.write();
+    let text = format!("; {}.\n{}", exporter.summary(), code);
+    if build_prg(&text, &[], &header)? != rom.prg_rom {
+        return Err("The disassembly doesn't build into the same PRG ROM.".into());
+    }
+    Ok(text)
+}
+
+/// Disassemble a program that's loaded at `origin`, following the code from the entry
+/// points. It's checked to assemble back into the same bytes.
+pub fn disassemble_program(
+    bytes: &[u8],
+    origin: u16,
+    entries: &[u16],
+) -> Result<String, String> {
+    if origin as usize + bytes.len() > 0x10000 {
+        return Err(format!(
+            "The program is {} bytes, which runs past $ffff from ${:04x}.",
+            bytes.len(),
+            origin
+        ));
+    }
+    let mut exporter = Exporter::new(bytes, origin, false);
+    exporter.run(entries);
+    let (code, _) = exporter.write();
+    let text = format!("; {}.\n{}", exporter.summary(), code);
+
+    let mut lexer = AsmLexer::new(&text);
+    if let Err(parse_error) = lexer.parse() {
+        return Err(parse_error.nice_message().to_string());
+    }
+    let segments: Vec<Segment> = lexer
+        .into_segments()?
+        .segments
+        .into_iter()
+        .filter(|segment| !segment.bytes.is_empty())
+        .collect();
+    let is_same = match segments.as_slice() {
+        [] => bytes.is_empty(),
+        [segment] => segment.origin == origin && segment.bytes == bytes,
+        _ => false,
+    };
+    if !is_same {
+        return Err("The disassembly doesn't assemble into the same bytes.".into());
+    }
+    Ok(text)
+}
+
+impl RomExport {
+    /// Export an NROM ROM. Without a code/data log the code is only found from the
+    /// interrupt vectors.
+    pub fn from_rom(
+        rom: &InesRom,
+        rom_name: &str,
+        code_data_log: Option<&CodeDataLog>,
+    ) -> Result<RomExport, String> {
+        let (exporter, header) = nrom_exporter(rom, code_data_log, Some(DATA_BIN), &[])?;
+        let (code, data) = exporter.write();
+        let main_asm = format!(
+            "; {}, exported as {}.\n{}",
+            rom_name,
+            exporter.summary(),
+            code
+        );
+
+        let mirroring = match header.mirroring {
+            Mirroring::Vertical => "vertical",
+            Mirroring::FourScreen => "four_screen",
+            _ => "horizontal",
+        };
+        let mut asm_toml = format!(
+            "# {} as an asm project, which builds back into the same ROM.\n\
+             entry = \"{}\"\n\
+             target = \"nes\"\n",
+            rom_name, MAIN_ASM
+        );
+        if !rom.chr_rom.is_empty() {
+            asm_toml.push_str(&format!("chr = [\"{}\"]\n", GAME_CHR));
+        }
+        asm_toml.push_str(&format!(
+            "out = \"{}.nes\"\nmirroring = \"{}\"\n",
+            Path::new(rom_name)
+                .file_stem()
+                .map_or("game".into(), |stem| stem.to_string_lossy()),
+            mirroring
+        ));
+        if header.has_battery {
+            asm_toml.push_str("battery = true\n");
+        }
+        if header.prg_banks == 1 {
+            asm_toml.push_str("prg_banks = 1\n");
+        }
+
+        if build_prg(&main_asm, &data, &header)? != rom.prg_rom {
+            return Err(
+                "The exported program doesn't build into the same PRG ROM.".into()
+            );
+        }
+        Ok(RomExport {
+            main_asm,
+            data,
+            chr: rom.chr_rom.clone(),
+            asm_toml,
+        })
+    }
+
+    /// The file names and their contents.
+    pub fn files(&self) -> Vec<(&'static str, &[u8])> {
+        let mut files = vec![
+            (MAIN_ASM, self.main_asm.as_bytes()),
+            (ASM_TOML, self.asm_toml.as_bytes()),
+        ];
+        if !self.data.is_empty() {
+            files.push((DATA_BIN, &self.data));
+        }
+        if !self.chr.is_empty() {
+            files.push((GAME_CHR, &self.chr));
+        }
+        files
+    }
+
+    pub fn write(&self, directory: &Path) -> Result<(), String> {
+        std::fs::create_dir_all(directory)
+            .map_err(|err| format!("Failed to create {:?}: {}", directory, err))?;
+        for (name, contents) in self.files() {
+            let path = directory.join(name);
+            std::fs::write(&path, contents)
+                .map_err(|err| format!("Failed to write {:?}: {}", path, err))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm_project::AsmProject;
+    use crate::gallery::{ExampleKind, EXAMPLES};
+    use crate::rom::ines_bytes;
+    use crate::rom::{nrom_from_program, CHR_BANK_SIZE};
+
+    /// Export a ROM, build it with the asm tool's project, and compare it.
+    fn round_trip(ines: &[u8], code_data_log: Option<&CodeDataLog>) -> RomExport {
+        let rom = InesRom::from_ines_bytes(ines).unwrap();
+        let export = RomExport::from_rom(&rom, "game.nes", code_data_log).unwrap();
+        let directory = std::env::temp_dir().join(format!(
+            "cpu-6502-rom-export-{}-{}",
+            std::process::id(),
+            crate::rom::database::crc32(ines)
+        ));
+        export.write(&directory).unwrap();
+        let project = AsmProject::load(&directory.join(ASM_TOML)).unwrap();
+        let build = project.build().unwrap().bytes;
+        std::fs::remove_dir_all(directory).unwrap();
+        assert_eq!(build, ines);
+        export
+    }
+
+    #[test]
+    fn test_export_examples() {
+        for example in EXAMPLES.iter().filter(|e| e.kind == ExampleKind::Nes) {
+            let program = example.assemble().unwrap();
+            let chr: Vec<u8> = (0..CHR_BANK_SIZE).map(|i| i as u8).collect();
+            let export = round_trip(&nrom_from_program(&program, &chr).unwrap(), None);
+            assert!(export.main_asm.contains("\nreset:\n"), "{}", example.name);
+            assert_eq!(export.chr, chr);
+        }
+    }
+
+    #[test]
+    fn test_export_code_and_data() {
+        let mut lexer = AsmLexer::new(
+            "
+            .org $c000
+            reset:
+                ldx #$00
+            loop:
+                lda $c016,x
+                sta $0200,x
+                inx
+                bne loop
+                lda #$01
+                jsr jump
+                jmp reset
+            jump:
+                .byte $6c, $00, $02 ; jmp ($0200), which the trace can't follow
+            table:
+                .byte $ff, $ff, $ff
+            logged:
+                rts
+            nmi:
+                .byte $87, $10 ; sax $10, which the assembler doesn't know
+                rti
+            ",
+        );
+        lexer.parse().unwrap();
+        let program = lexer.into_bytes().unwrap();
+        let header = InesHeader {
+            prg_banks: 1,
+            mirroring: Mirroring::Vertical,
+            ..InesHeader::default()
+        };
+        let ines = ines_from_program(&program, &[], &header).unwrap();
+
+        let export = round_trip(&ines, None);
+        assert!(export.main_asm.starts_with("; game.nes"));
+        assert!(export.main_asm.contains("\n.org $c000\n"));
+        assert!(export.main_asm.contains("\nloc_c002:\n    lda $c016,x\n"));
+        assert!(export.main_asm.contains("    bne loc_c002\n"));
+        assert!(export.main_asm.contains("    jsr sub_c013\n"));
+        // The sax stops the trace, so the rest of the bank is data.
+        assert!(export
+            .main_asm
+            .contains("\nnmi:\n    .incbin \"data.bin\", $0, 16352\n"));
+        assert!(export.main_asm.ends_with(".word nmi, reset, reset\n"));
+        assert!(export
+            .asm_toml
+            .contains("mirroring = \"vertical\"\nprg_banks = 1\n"));
+        assert!(export.chr.is_empty());
+
+        // The log finds the rts that's only reached through the jump.
+        let mut cdl = vec![0; PRG_BANK_SIZE];
+        cdl[0x19] = CodeDataLog::CODE;
+        cdl[0x16..0x19].copy_from_slice(&[CodeDataLog::DATA; 3]);
+        let cdl = CodeDataLog::from_cdl_bytes(&cdl, PRG_BANK_SIZE).unwrap();
+        let export = round_trip(&ines, Some(&cdl));
+        assert!(export
+            .main_asm
+            .contains("    .byte $ff, $ff, $ff\n    rts\n"));
+        assert!(CodeDataLog::from_cdl_bytes(&[0; 16], PRG_BANK_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_export_noise() {
+        // ines_bytes fills the banks with their index, so fill them with noise, which
+        // has every opcode, and jumps into the middle of other instructions.
+        let mut ines = ines_bytes(0b0000_0011, 2, 1);
+        let mut seed: u32 = 0x6502;
+        for byte in ines[16..].iter_mut() {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            *byte = seed as u8;
+        }
+        let export = round_trip(&ines, None);
+        assert!(export.asm_toml.contains("battery = true\n"));
+        assert!(!export.data.is_empty());
+
+        let mut ines = ines_bytes(0x10, 1, 0);
+        let rom = InesRom::from_ines_bytes(&ines).unwrap();
+        assert!(RomExport::from_rom(&rom, "mmc1.nes", None).is_err());
+        ines[6] = 0;
+        assert!(round_trip(&ines, None)
+            .main_asm
+            .contains(".incbin \"data.bin\", $0,"));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        for example in EXAMPLES.iter() {
+            let program = example.assemble().unwrap();
+            match example.kind {
+                ExampleKind::Cpu { .. } => {
+                    disassemble_program(&program.bytes, ORIGIN, &[ORIGIN]).unwrap();
+                }
+                ExampleKind::Nes => {
+                    let ines = nrom_from_program(&program, &[]).unwrap();
+                    let rom = InesRom::from_ines_bytes(&ines).unwrap();
+                    let text = disassemble_rom(&rom, &[]).unwrap();
+                    assert!(!text.contains(".incbin"), "{}", example.name);
+                }
+            }
+        }
+
+        // A program that's loaded into RAM, with a table after the code.
+        let mut lexer = AsmLexer::new(
+            "
+            .org $0600
+                ldx #$03
+            loop:
+                dex
+                bne loop
+                jsr done
+                brk
+            done:
+                rts
+                .byte $ff, $00
+            ",
+        );
+        lexer.parse().unwrap();
+        let bytes = lexer.into_segments().unwrap().segments[0].bytes.clone();
+        let text = disassemble_program(&bytes, 0x0600, &[0x0600]).unwrap();
+        assert!(text.contains("\n.org $0600\n    ldx #$03\n\nloc_0602:\n    dex\n"));
+        assert!(text.contains("    bne loc_0602\n    jsr sub_0609\n"));
+        assert!(text.ends_with("\nsub_0609:\n    rts\n\n    .byte $ff, $00\n"));
+
+        // As a real ROM encodes it, ldx #$03, dex, bne -3, brk, with the branch back to
+        // the dex.
+        let text =
+            disassemble_program(&[0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x00], 0x8000, &[0x8000])
+                .unwrap();
+        assert!(
+            text.contains("\nloc_8002:\n    dex\n    bne loc_8002\n"),
+            "{}",
+            text
+        );
+
+        assert!(disassemble_program(&[0xea; 3], 0xfffe, &[0xfffe]).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+//! Copies of the picture, for saving to an image file. The NES's pixels aren't square,
+//! an NTSC TV draws them 8/7 as wide as they are tall, so screenshots can be stretched
+//! to look like they did on a TV.
+//!
+//! https://www.nesdev.org/wiki/Overscan#NTSC
+
+use crate::emulator::Emulator;
+use crate::ppu::render::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// The width and height of an NTSC pixel.
+pub const PIXEL_ASPECT: (usize, usize) = (8, 7);
+
+/// An RGBA image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Screenshot {
+    pub width: usize,
+    pub height: usize,
+    pub rgba: Vec<u8>,
+}
+
+impl Emulator {
+    /// The last complete frame. With `is_aspect_corrected` it's stretched to 292x240,
+    /// repeating every 7th column or so.
+    pub fn screenshot(&self, is_aspect_corrected: bool) -> Screenshot {
+        let framebuffer = self.framebuffer();
+        if !is_aspect_corrected {
+            return Screenshot {
+                width: SCREEN_WIDTH,
+                height: SCREEN_HEIGHT,
+                rgba: framebuffer.to_vec(),
+            };
+        }
+        let (aspect_width, aspect_height) = PIXEL_ASPECT;
+        let width = SCREEN_WIDTH * aspect_width / aspect_height;
+        let mut rgba = Vec::with_capacity(width * SCREEN_HEIGHT * 4);
+        for row in framebuffer.chunks(SCREEN_WIDTH * 4) {
+            for x in 0..width {
+                let pixel = x * SCREEN_WIDTH / width * 4;
+                rgba.extend_from_slice(&row[pixel..pixel + 4]);
+            }
+        }
+        Screenshot {
+            width,
+            height: SCREEN_HEIGHT,
+            rgba,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mappers::SimpleProgram;
+
+    #[test]
+    fn test_screenshot() {
+        let mut emulator = Emulator::new(Box::new(SimpleProgram::load(&[])));
+        emulator.cpu.bus.ppu.state.palette_ram.write(0x3f00, 0x16);
+        emulator.run_frame();
+
+        let screenshot = emulator.screenshot(false);
+        assert_eq!((screenshot.width, screenshot.height), (256, 240));
+        assert_eq!(screenshot.rgba, emulator.framebuffer());
+
+        let stretched = emulator.screenshot(true);
+        assert_eq!((stretched.width, stretched.height), (292, 240));
+        assert_eq!(stretched.rgba.len(), 292 * 240 * 4);
+        assert_eq!(stretched.rgba[..4], screenshot.rgba[..4]);
+        // The last column is still the last column of the picture.
+        let end = 292 * 4;
+        assert_eq!(
+            stretched.rgba[end - 4..end],
+            screenshot.rgba[256 * 4 - 4..256 * 4]
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! A suspended session, so that a frontend can be closed and opened again right where
+//! it was. Everything goes into one container file of named sections, each holding
+//! what one of the other formats already writes:
+//!
+//!   "SESN" magic, u16 version, u16 section count, then the name and the bytes of
+//!   each section
+//!
+//! The emulator adds its save state under "core", and the movie it's recording under
+//! "movie", as FM2. The frontend adds its own sections alongside them, like its
+//! debugger and its windows. Sections that a reader doesn't know about are skipped.
+
+use std::collections::BTreeMap;
+
+use crate::emulator::Emulator;
+use crate::movie::{Movie, MovieState};
+use crate::save_state::{SaveState, StateReader, StateWriter};
+use crate::storage::StorageBackend;
+
+pub const SESSION_MAGIC: &[u8; 4] = b"SESN";
+pub const SESSION_VERSION: u16 = 1;
+
+const CORE_SECTION: &str = "core";
+const MOVIE_SECTION: &str = "movie";
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Session {
+    sections: BTreeMap<String, Vec<u8>>,
+}
+
+impl Session {
+    /// Add a section, replacing one with the same name.
+    pub fn insert(&mut self, name: &str, bytes: Vec<u8>) {
+        self.sections.insert(name.to_string(), bytes);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.sections.get(name).map(Vec::as_slice)
+    }
+
+    /// A section that was inserted as text.
+    pub fn get_string(&self, name: &str) -> Result<Option<&str>, String> {
+        match self.get(name) {
+            Some(bytes) => std::str::from_utf8(bytes)
+                .map(Some)
+                .map_err(|_| format!("The session's {:?} isn't valid UTF-8.", name)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        for byte in SESSION_MAGIC {
+            writer.u8(*byte);
+        }
+        writer.u16(SESSION_VERSION);
+        writer.u16(self.sections.len() as u16);
+        for (name, bytes) in &self.sections {
+            writer.bytes(name.as_bytes());
+            writer.bytes(bytes);
+        }
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Session, String> {
+        if !bytes.starts_with(SESSION_MAGIC) {
+            return Err("This isn't a suspended session.".into());
+        }
+        let mut reader = StateReader::new(&bytes[SESSION_MAGIC.len()..]);
+        let version = reader.u16()?;
+        if version != SESSION_VERSION {
+            return Err(format!(
+                "The session is version {}, but only version {} is supported.",
+                version, SESSION_VERSION
+            ));
+        }
+        let mut session = Session::default();
+        for _ in 0..reader.u16()? {
+            let name = String::from_utf8(reader.bytes()?.to_vec())
+                .map_err(|_| "A section of the session has an invalid name.")?;
+            session.sections.insert(name, reader.bytes()?.to_vec());
+        }
+        reader.finish()?;
+        Ok(session)
+    }
+
+    /// Load the session that was suspended under the key, if there is one.
+    pub fn load(
+        storage: &dyn StorageBackend,
+        key: &str,
+    ) -> Result<Option<Session>, String> {
+        match storage.read(key)? {
+            Some(bytes) => Session::from_bytes(&bytes)
+                .map(Some)
+                .map_err(|err| format!("{}: {}", key, err)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save(
+        &self,
+        storage: &mut dyn StorageBackend,
+        key: &str,
+    ) -> Result<(), String> {
+        storage.write(key, &self.to_bytes())
+    }
+}
+
+impl Emulator {
+    /// Add the emulator's sections to the session. A movie that's being played isn't
+    /// kept, as it can be played again from its file.
+    pub fn suspend(&self, session: &mut Session) {
+        session.insert(CORE_SECTION, self.cpu.save_state());
+        if let Some(MovieState::Recording(movie)) = self.movie_state() {
+            session.insert(MOVIE_SECTION, movie.to_fm2().into_bytes());
+        }
+    }
+
+    /// Pick up from a suspended session, which has to be for the same program. A
+    /// movie that was being recorded carries on recording.
+    pub fn resume(&mut self, session: &Session) -> Result<(), String> {
+        let state = session
+            .get(CORE_SECTION)
+            .ok_or("The session doesn't have the emulator's state.")?;
+        self.cpu.load_state(state)?;
+        if let Some(fm2) = session.get_string(MOVIE_SECTION)? {
+            self.resume_recording(Movie::from_fm2(fm2)?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::ines_bytes;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_session() {
+        let rom = ines_bytes(0, 2, 1);
+        let mut emulator = Emulator::from_ines_bytes(&rom).unwrap();
+        emulator.run_frame();
+        emulator.record_movie("game.nes");
+        for frame in 0..3 {
+            emulator.cpu.bus.controller_1.set_buttons(frame);
+            emulator.run_frame();
+        }
+
+        let mut session = Session::default();
+        emulator.suspend(&mut session);
+        session.insert("layout", b"oam = true\n".to_vec());
+        let mut storage = MemoryStorage::default();
+        assert_eq!(Session::load(&storage, "game.session"), Ok(None));
+        session.save(&mut storage, "game.session").unwrap();
+        let session = Session::load(&storage, "game.session").unwrap().unwrap();
+        assert_eq!(session.get_string("layout"), Ok(Some("oam = true\n")));
+        assert_eq!(session.get("debugger"), None);
+
+        // A new launch carries on recording from the same frame.
+        let mut resumed = Emulator::from_ines_bytes(&rom).unwrap();
+        resumed.resume(&session).unwrap();
+        assert_eq!(resumed.cpu.save_state(), emulator.cpu.save_state());
+        assert_eq!(resumed.movie_state(), emulator.movie_state());
+        for emulator in [&mut emulator, &mut resumed] {
+            emulator.cpu.bus.controller_1.set_buttons(0x80);
+            emulator.run_frame();
+        }
+        let movie = resumed.stop_movie().unwrap();
+        assert_eq!(movie.frames, [[0, 0], [1, 0], [2, 0], [0x80, 0]]);
+        assert_eq!(Some(movie), emulator.stop_movie());
+
+        let bytes = session.to_bytes();
+        assert!(Session::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Session::from_bytes(b"6502").is_err());
+        assert!(Emulator::from_ines_bytes(&rom)
+            .unwrap()
+            .resume(&Session::default())
+            .is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Everything that's kept between sessions, like battery saves and the frontend's
+//! settings, goes through a `StorageBackend`. The desktop frontend keeps them in files,
+//! while the tests, and builds without a filesystem like wasm, keep them in memory.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::emulator::Emulator;
+
+/// Named blobs of bytes. A key is a file name, e.g. "zelda.sav" or "controls.toml".
+pub trait StorageBackend {
+    /// None when nothing has been stored under the key.
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, String>;
+    fn write(&mut self, key: &str, bytes: &[u8]) -> Result<(), String>;
+    fn remove(&mut self, key: &str) -> Result<(), String>;
+
+    fn read_string(&self, key: &str) -> Result<Option<String>, String> {
+        match self.read(key)? {
+            Some(bytes) => String::from_utf8(bytes)
+                .map(Some)
+                .map_err(|_| format!("{:?} isn't valid UTF-8.", key)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Keys are paths relative to the directory. An absolute path is used as it is.
+pub struct FileStorage {
+    pub directory: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(directory: impl Into<PathBuf>) -> FileStorage {
+        FileStorage {
+            directory: directory.into(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(key)
+    }
+}
+
+impl StorageBackend for FileStorage {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let path = self.path(key);
+        match std::fs::read(&path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(format!("Failed to read {:?}: {}", path, err)),
+        }
+    }
+
+    fn write(&mut self, key: &str, bytes: &[u8]) -> Result<(), String> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|err| format!("Failed to create {:?}: {}", parent, err))?;
+        }
+        std::fs::write(&path, bytes)
+            .map_err(|err| format!("Failed to write {:?}: {}", path, err))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), String> {
+        let path = self.path(key);
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(format!("Failed to remove {:?}: {}", path, err))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Forgets everything when it's dropped.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    pub entries: HashMap<String, Vec<u8>>,
+}
+
+impl StorageBackend for MemoryStorage {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn write(&mut self, key: &str, bytes: &[u8]) -> Result<(), String> {
+        self.entries.insert(key.to_string(), bytes.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), String> {
+        self.entries.remove(key);
+        Ok(())
+    }
+}
+
+impl Emulator {
+    /// Store the cartridge's PRG RAM. Cartridges without any have nothing to store.
+    pub fn save_prg_ram(
+        &self,
+        storage: &mut dyn StorageBackend,
+        key: &str,
+    ) -> Result<(), String> {
+        match self.cpu.bus.prg_ram() {
+            Some(ram) => storage.write(key, ram),
+            None => Ok(()),
+        }
+    }
+
+    /// Restore the PRG RAM from an earlier session. Returns false when there wasn't
+    /// one.
+    pub fn load_prg_ram(
+        &mut self,
+        storage: &dyn StorageBackend,
+        key: &str,
+    ) -> Result<bool, String> {
+        match storage.read(key)? {
+            Some(ram) => {
+                self.cpu
+                    .bus
+                    .load_prg_ram(&ram)
+                    .map_err(|err| format!("The save {:?} doesn't fit: {}", key, err))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::ines_bytes;
+
+    fn check_backend(storage: &mut dyn StorageBackend) {
+        assert_eq!(storage.read("a.sav"), Ok(None));
+        storage.write("a.sav", &[1, 2, 3]).unwrap();
+        storage.write("saves/b.sav", b"text").unwrap();
+        assert_eq!(storage.read("a.sav"), Ok(Some(vec![1, 2, 3])));
+        assert_eq!(storage.read_string("saves/b.sav"), Ok(Some("text".into())));
+        storage.remove("a.sav").unwrap();
+        storage.remove("a.sav").unwrap();
+        assert_eq!(storage.read("a.sav"), Ok(None));
+    }
+
+    #[test]
+    fn test_storage_backends() {
+        check_backend(&mut MemoryStorage::default());
+
+        let directory =
+            std::env::temp_dir().join(format!("cpu-6502-storage-{}", std::process::id()));
+        check_backend(&mut FileStorage::new(&directory));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        // An MMC1 with a battery.
+        let rom = ines_bytes(0x12, 2, 1);
+        let mut storage = MemoryStorage::default();
+        let mut emulator = Emulator::from_ines_bytes(&rom).unwrap();
+        assert_eq!(emulator.load_prg_ram(&storage, "game.sav"), Ok(false));
+        emulator.cpu.bus.set_u8(0x6000, 0x42);
+        emulator.save_prg_ram(&mut storage, "game.sav").unwrap();
+
+        let mut emulator = Emulator::from_ines_bytes(&rom).unwrap();
+        assert_eq!(emulator.load_prg_ram(&storage, "game.sav"), Ok(true));
+        assert_eq!(emulator.cpu.bus.read_u8(0x6000), 0x42);
+
+        storage.write("game.sav", &[0; 3]).unwrap();
+        assert!(emulator.load_prg_ram(&storage, "game.sav").is_err());
+    }
+}
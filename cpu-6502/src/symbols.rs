@@ -0,0 +1,281 @@
+//! Label files for FCEUX and Mesen, so the names from the assembler can be taken to
+//! their debuggers, and the names from a ROM that was reverse engineered in them can
+//! be brought back here.
+//!
+//! FCEUX keeps a .nl file for each 16KB bank of PRG ROM, named after the ROM, e.g.
+//! "game.nes.0.nl", and "game.nes.ram.nl" for the addresses below $8000:
+//!
+//!   $C000#reset#Comment
+//!
+//! Mesen keeps them all in one .mlb file, e.g. "game.mlb", where each label has the
+//! kind of memory and the offset into it: P for PRG ROM, R for the internal RAM, S for
+//! the PRG RAM at $6000, and G for the registers.
+//!
+//!   P:4000:reset:Comment
+//!   R:0010:temp
+//!
+//! The labels here are by CPU address, so the ROM banks are taken to be where most
+//! mappers power up: the first bank at $8000-$BFFF, and the last at $C000-$FFFF.
+
+use std::path::Path;
+
+use crate::asm::AddressToLabel;
+use crate::rom::PRG_BANK_SIZE;
+use crate::storage::StorageBackend;
+
+/// The bank and the offset into the PRG ROM for an address from $8000.
+fn prg_bank(address: u16, prg_banks: usize) -> (usize, usize) {
+    let bank = match address {
+        0x8000..=0xbfff => 0,
+        _ => prg_banks.saturating_sub(1),
+    };
+    (
+        bank,
+        bank * PRG_BANK_SIZE + (address as usize % PRG_BANK_SIZE),
+    )
+}
+
+/// The labels sorted by address, which is how both debuggers list them.
+fn sorted(labels: &AddressToLabel) -> Vec<(u16, &str)> {
+    let mut labels: Vec<(u16, &str)> = labels
+        .iter()
+        .map(|(address, label)| (*address, label.as_str()))
+        .collect();
+    labels.sort_unstable();
+    labels
+}
+
+fn parse_hex(text: &str, line: &str) -> Result<usize, String> {
+    usize::from_str_radix(text, 16)
+        .map_err(|_| format!("Expected a hex address in \"{}\"", line))
+}
+
+/// The FCEUX files for a ROM, as the file names and their text. Files without any
+/// labels are left out.
+pub fn to_fceux_nl(
+    labels: &AddressToLabel,
+    rom_filename: &str,
+    prg_banks: usize,
+) -> Vec<(String, String)> {
+    let mut files: Vec<(String, String)> = Vec::new();
+    for (address, label) in sorted(labels) {
+        let suffix = match address {
+            0x0000..=0x7fff => "ram".to_string(),
+            _ => prg_bank(address, prg_banks).0.to_string(),
+        };
+        let name = format!("{}.{}.nl", rom_filename, suffix);
+        let line = format!("${:04X}#{}#\n", address, label);
+        match files.iter_mut().find(|(file, _)| *file == name) {
+            Some((_, text)) => text.push_str(&line),
+            None => files.push((name, line)),
+        }
+    }
+    files
+}
+
+/// Read the labels from an FCEUX file. The lines that only have a comment are skipped.
+pub fn from_fceux_nl(text: &str) -> Result<AddressToLabel, String> {
+    let mut labels = AddressToLabel::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.splitn(3, '#');
+        let address = fields.next().unwrap_or_default();
+        let label = fields.next().unwrap_or_default().trim();
+        // Arrays are written like $0300/10, the label is on the first address.
+        let hex = match address.strip_prefix('$') {
+            Some(hex) => hex.split('/').next().unwrap_or_default(),
+            None => {
+                return Err(format!("Expected \"$C000#name#\" but found \"{}\"", line))
+            }
+        };
+        let address = parse_hex(hex, line)?;
+        if !label.is_empty() && address <= 0xffff {
+            labels.insert(address as u16, label.to_string());
+        }
+    }
+    Ok(labels)
+}
+
+/// The text of a Mesen .mlb file.
+pub fn to_mesen_mlb(labels: &AddressToLabel, prg_banks: usize) -> String {
+    let mut text = String::new();
+    for (address, label) in sorted(labels) {
+        let (kind, offset) = match address {
+            0x0000..=0x1fff => ('R', address as usize % 0x800),
+            0x6000..=0x7fff => ('S', address as usize - 0x6000),
+            0x8000..=0xffff => ('P', prg_bank(address, prg_banks).1),
+            _ => ('G', address as usize),
+        };
+        text.push_str(&format!("{}:{:04X}:{}\n", kind, offset, label));
+    }
+    text
+}
+
+/// Read the labels from a Mesen .mlb file. PRG ROM labels in the banks that aren't
+/// switched in at power up don't have a CPU address, so they're skipped, as are the
+/// kinds of memory that the CPU can't see, like CHR.
+pub fn from_mesen_mlb(text: &str, prg_banks: usize) -> Result<AddressToLabel, String> {
+    let mut labels = AddressToLabel::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.splitn(4, ':');
+        let kind = fields.next().unwrap_or_default();
+        let (offset, label) = match (fields.next(), fields.next()) {
+            (Some(offset), Some(label)) => (offset, label.trim()),
+            _ => return Err(format!("Expected \"P:4000:name\" but found \"{}\"", line)),
+        };
+        // A range like 0300-030F is labeled at its start.
+        let offset = parse_hex(offset.split('-').next().unwrap_or_default(), line)?;
+        let address = match kind {
+            "R" => Some(offset % 0x800),
+            "S" => Some(0x6000 + offset % 0x2000),
+            "G" => Some(offset),
+            "P" => match offset / PRG_BANK_SIZE {
+                0 => Some(0x8000 + offset),
+                bank if bank + 1 == prg_banks => Some(0xc000 + offset % PRG_BANK_SIZE),
+                _ => None,
+            },
+            _ => None,
+        };
+        match address {
+            Some(address) if !label.is_empty() && address <= 0xffff => {
+                labels.insert(address as u16, label.to_string());
+            }
+            _ => {}
+        }
+    }
+    Ok(labels)
+}
+
+/// Load the labels from the Mesen or FCEUX files next to a ROM, e.g. "game.mlb" or
+/// "game.nes.0.nl" for "game.nes". Mesen's file is used when there are both.
+pub fn load_labels(
+    storage: &dyn StorageBackend,
+    rom_key: &str,
+    prg_banks: usize,
+) -> Result<AddressToLabel, String> {
+    let mlb_key = Path::new(rom_key).with_extension("mlb");
+    let mlb_key = mlb_key.to_string_lossy();
+    if let Some(text) = storage.read_string(&mlb_key)? {
+        return from_mesen_mlb(&text, prg_banks)
+            .map_err(|err| format!("{}: {}", mlb_key, err));
+    }
+    let mut labels = AddressToLabel::new();
+    let suffixes = std::iter::once("ram".to_string())
+        .chain((0..prg_banks).map(|bank| bank.to_string()));
+    for suffix in suffixes {
+        let key = format!("{}.{}.nl", rom_key, suffix);
+        if let Some(text) = storage.read_string(&key)? {
+            labels
+                .extend(from_fceux_nl(&text).map_err(|err| format!("{}: {}", key, err))?);
+        }
+    }
+    Ok(labels)
+}
+
+/// Write the labels next to a ROM in both formats, and return the keys they were
+/// written to.
+pub fn save_labels(
+    labels: &AddressToLabel,
+    storage: &mut dyn StorageBackend,
+    rom_key: &str,
+    prg_banks: usize,
+) -> Result<Vec<String>, String> {
+    let mlb_key = Path::new(rom_key)
+        .with_extension("mlb")
+        .to_string_lossy()
+        .to_string();
+    storage.write(&mlb_key, to_mesen_mlb(labels, prg_banks).as_bytes())?;
+    let mut keys = vec![mlb_key];
+    for (key, text) in to_fceux_nl(labels, rom_key, prg_banks) {
+        storage.write(&key, text.as_bytes())?;
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_symbols() {
+        let mut labels = AddressToLabel::new();
+        for (address, label) in [
+            (0x0010, "temp"),
+            (0x2000, "PPUCTRL"),
+            (0x6000, "save"),
+            (0x8003, "wait_vblank"),
+            (0xc000, "reset"),
+        ] {
+            labels.insert(address, label.to_string());
+        }
+
+        let files = to_fceux_nl(&labels, "game.nes", 4);
+        assert_eq!(
+            files,
+            [
+                (
+                    "game.nes.ram.nl".to_string(),
+                    "$0010#temp#\n$2000#PPUCTRL#\n$6000#save#\n".to_string()
+                ),
+                (
+                    "game.nes.0.nl".to_string(),
+                    "$8003#wait_vblank#\n".to_string()
+                ),
+                ("game.nes.3.nl".to_string(), "$C000#reset#\n".to_string()),
+            ]
+        );
+        let mlb = to_mesen_mlb(&labels, 4);
+        assert_eq!(
+            mlb,
+            "R:0010:temp\nG:2000:PPUCTRL\nS:0000:save\nP:0003:wait_vblank\nP:C000:reset\n"
+        );
+        assert_eq!(from_mesen_mlb(&mlb, 4), Ok(labels.clone()));
+
+        // As the debuggers write them, with comments, arrays, and other banks.
+        let labels =
+            from_fceux_nl("$0300/10#buffer#The OAM buffer\n$0400##Only a comment\n")
+                .unwrap();
+        assert_eq!(labels.get(&0x0300).map(String::as_str), Some("buffer"));
+        assert_eq!(labels.len(), 1);
+        let labels =
+            from_mesen_mlb("P:4010:banked\nR:0300-030F:buffer:OAM\nC:0000:tiles\n", 4)
+                .unwrap();
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels.get(&0x0300).map(String::as_str), Some("buffer"));
+        assert!(from_fceux_nl("C000#reset#").is_err());
+        assert!(from_mesen_mlb("P:reset", 2).is_err());
+
+        // A ROM picks up the labels that were saved next to it.
+        let mut storage = MemoryStorage::default();
+        assert_eq!(
+            load_labels(&storage, "roms/game.nes", 2),
+            Ok(AddressToLabel::new())
+        );
+        let mut labels = AddressToLabel::new();
+        labels.insert(0x0010, "temp".to_string());
+        labels.insert(0xfffa, "nmi_vector".to_string());
+        assert_eq!(
+            save_labels(&labels, &mut storage, "roms/game.nes", 2),
+            Ok(vec![
+                "roms/game.mlb".to_string(),
+                "roms/game.nes.ram.nl".to_string(),
+                "roms/game.nes.1.nl".to_string()
+            ])
+        );
+        assert_eq!(
+            load_labels(&storage, "roms/game.nes", 2),
+            Ok(labels.clone())
+        );
+        storage.remove("roms/game.mlb").unwrap();
+        assert_eq!(load_labels(&storage, "roms/game.nes", 2), Ok(labels));
+    }
+}
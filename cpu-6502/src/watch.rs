@@ -0,0 +1,182 @@
+//! Memory locations that the debuggers keep an eye on while the program runs. A watch
+//! is written as an address like `$00ff`, a label from the assembler, or a name for an
+//! address like `sprite_x (=$0203)`.
+
+use crate::asm::AddressToLabel;
+use crate::bus::Bus;
+use crate::disassembler::parse_address;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchFormat {
+    Hex,
+    Decimal,
+    Binary,
+}
+
+impl WatchFormat {
+    pub const ALL: [WatchFormat; 3] =
+        [WatchFormat::Hex, WatchFormat::Decimal, WatchFormat::Binary];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WatchFormat::Hex => "Hex",
+            WatchFormat::Decimal => "Decimal",
+            WatchFormat::Binary => "Binary",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<WatchFormat> {
+        WatchFormat::ALL
+            .iter()
+            .find(|format| format.name() == name)
+            .copied()
+    }
+
+    pub fn format(self, value: u8) -> String {
+        match self {
+            WatchFormat::Hex => format!("${:02x}", value),
+            WatchFormat::Decimal => format!("{}", value),
+            WatchFormat::Binary => format!("%{:08b}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watch {
+    /// The label or the name it was given, if it has one.
+    pub name: Option<String>,
+    pub address: u16,
+    pub format: WatchFormat,
+    /// The value from the last update.
+    pub value: Option<u8>,
+    /// The frame that the value last changed on, for highlighting it.
+    pub changed_frame: Option<u64>,
+}
+
+impl Watch {
+    /// Parse the expression that the watch was written as. Labels are looked up in
+    /// `labels`, when the program was assembled.
+    pub fn parse(
+        expression: &str,
+        labels: Option<&AddressToLabel>,
+    ) -> Result<Watch, String> {
+        let expression = expression.trim();
+        let (name, address) = if let Some(open) = expression.find("(=") {
+            let address = match expression[open + 2..].strip_suffix(')') {
+                Some(address) => parse_address(address.trim())?,
+                None => return Err("The address is missing a closing ).".into()),
+            };
+            let name = expression[..open].trim();
+            if name.is_empty() {
+                return Err("Put a name before the (=$address).".into());
+            }
+            (Some(name.to_string()), address)
+        } else if expression.starts_with('$') {
+            (None, parse_address(expression)?)
+        } else if expression.is_empty() {
+            return Err("Type an address like $00ff, or a label.".into());
+        } else {
+            let address = labels.and_then(|labels| {
+                labels
+                    .iter()
+                    .find(|(_, label)| label.as_str() == expression)
+                    .map(|(address, _)| *address)
+            });
+            match address {
+                Some(address) => (Some(expression.to_string()), address),
+                None => {
+                    return Err(format!("There's no label named \"{}\".", expression))
+                }
+            }
+        };
+        Ok(Watch {
+            name,
+            address,
+            format: WatchFormat::Hex,
+            value: None,
+            changed_frame: None,
+        })
+    }
+
+    /// e.g. "sprite_x $0203", or just the address when it doesn't have a name.
+    pub fn label(&self) -> String {
+        match self.name {
+            Some(ref name) => format!("{} ${:04x}", name, self.address),
+            None => format!("${:04x}", self.address),
+        }
+    }
+
+    /// What the watch parses back from, e.g. "sprite_x (=$0203)", so that it can be
+    /// saved without the labels it was looked up in.
+    pub fn expression(&self) -> String {
+        match self.name {
+            Some(ref name) => format!("{} (=${:04x})", name, self.address),
+            None => format!("${:04x}", self.address),
+        }
+    }
+
+    /// Read the value without side effects, and return whether it changed. The first
+    /// read isn't a change.
+    pub fn update(&mut self, bus: &Bus, frame: u64) -> bool {
+        let value = bus.peek_u8(self.address);
+        let is_changed = self.value.is_some_and(|last| last != value);
+        if is_changed {
+            self.changed_frame = Some(frame);
+        }
+        self.value = Some(value);
+        is_changed
+    }
+
+    pub fn formatted_value(&self) -> String {
+        match self.value {
+            Some(value) => self.format.format(value),
+            None => "-".into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mappers::SimpleProgram;
+
+    #[test]
+    fn test_watch() {
+        let mut labels = AddressToLabel::new();
+        labels.insert(0x8003, "loop".to_string());
+        let parse = |text: &str| Watch::parse(text, Some(&labels));
+
+        assert_eq!(parse("$00FF").unwrap().address, 0x00ff);
+        let named = parse(" sprite_x (=$0203) ").unwrap();
+        assert_eq!(
+            (named.name.as_deref(), named.address),
+            (Some("sprite_x"), 0x0203)
+        );
+        assert_eq!(named.label(), "sprite_x $0203");
+        assert_eq!(parse("loop").unwrap().address, 0x8003);
+        assert_eq!(parse("loop").unwrap().expression(), "loop (=$8003)");
+        assert_eq!(Watch::parse(&named.expression(), None), Ok(named));
+        assert!(parse("$10000").is_err());
+        assert!(parse("(=$0203)").is_err());
+        assert_eq!(
+            parse("missing").unwrap_err(),
+            "There's no label named \"missing\"."
+        );
+
+        let mut bus = Bus::new(Box::new(SimpleProgram::load(&[])));
+        let mut watch = parse("$0010").unwrap();
+        assert!(!watch.update(&bus, 1));
+        bus.set_u8(0x0010, 0x05);
+        assert!(watch.update(&bus, 2));
+        assert!(!watch.update(&bus, 3));
+        assert_eq!(watch.changed_frame, Some(2));
+
+        let values: Vec<String> = WatchFormat::ALL
+            .iter()
+            .map(|format| format.format(5))
+            .collect();
+        assert_eq!(values, ["$05", "5", "%00000101"]);
+        assert_eq!(WatchFormat::from_name("Binary"), Some(WatchFormat::Binary));
+        assert_eq!(WatchFormat::from_name("Octal"), None);
+    }
+}
@@ -0,0 +1,247 @@
+//! Catches a program that has run off into memory with no code in it, or keeps hitting
+//! KIL instructions. A broken test program can otherwise spin forever in a headless
+//! run. It's off by default, see `Emulator::watchdog`.
+
+use crate::bus::Bus;
+use crate::cpu_6502::Cpu6502;
+use crate::disassembler::disassemble_history;
+
+/// Real code doesn't run from open bus at all, so this only allows for a jump table
+/// that's a little off before the watchdog steps in.
+pub const DEFAULT_OPEN_BUS_INSTRUCTIONS: u64 = 100;
+
+/// How many of the most recent instructions go into the report.
+const REPORTED_INSTRUCTIONS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchdogAction {
+    /// Reset the CPU like the reset button, and keep running.
+    Reset,
+    /// Stop running frames until the trip is cleared, so a frontend can show it.
+    Pause,
+    /// Stop like `Pause`. A headless harness exits with this code.
+    Exit(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchdogConfig {
+    pub action: WatchdogAction,
+    /// How many instructions in a row have to run from outside of RAM and the
+    /// cartridge.
+    pub open_bus_instructions: u64,
+    /// How many KIL instructions can run before the watchdog trips. They're counted
+    /// until the watchdog resets the CPU.
+    pub kils: u64,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> WatchdogConfig {
+        WatchdogConfig {
+            action: WatchdogAction::Pause,
+            open_bus_instructions: DEFAULT_OPEN_BUS_INSTRUCTIONS,
+            kils: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchdogReason {
+    /// The CPU is running from an address that nothing is mapped to.
+    OpenBus { address: u16 },
+    /// The last KIL was at this address.
+    Kil { address: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchdogTrip {
+    pub reason: WatchdogReason,
+    pub action: WatchdogAction,
+    /// The PPU frame count when it tripped.
+    pub frame: u64,
+    /// The most recent instructions, disassembled, oldest first. They're only
+    /// recorded with the debugger feature.
+    pub instructions: Vec<String>,
+}
+
+impl WatchdogTrip {
+    /// The code for a harness to exit with, when the action is `Exit`.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self.action {
+            WatchdogAction::Exit(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let mut report = match self.reason {
+            WatchdogReason::OpenBus { address } => format!(
+                "Watchdog tripped at frame {}: the CPU is running from open bus at \
+                 ${:04x}.\n",
+                self.frame, address
+            ),
+            WatchdogReason::Kil { address } => format!(
+                "Watchdog tripped at frame {}: the CPU keeps halting, the last KIL was at \
+                 ${:04x}.\n",
+                self.frame, address
+            ),
+        };
+        if !self.instructions.is_empty() {
+            report.push_str("Recent instructions:\n");
+            for instruction in &self.instructions {
+                report.push_str("  ");
+                report.push_str(instruction);
+                report.push('\n');
+            }
+        }
+        report
+    }
+}
+
+pub struct Watchdog {
+    pub config: WatchdogConfig,
+    open_bus_instructions: u64,
+    kils: u64,
+    /// The trip that's stopping the emulator, for the `Pause` and `Exit` actions.
+    pub tripped: Option<WatchdogTrip>,
+    /// The trips that reset the CPU, oldest first.
+    pub resets: Vec<WatchdogTrip>,
+}
+
+impl Watchdog {
+    pub fn new(config: WatchdogConfig) -> Watchdog {
+        Watchdog {
+            config,
+            open_bus_instructions: 0,
+            kils: 0,
+            tripped: None,
+            resets: Vec::new(),
+        }
+    }
+
+    pub fn is_tripped(&self) -> bool {
+        self.tripped.is_some()
+    }
+
+    /// Clear the trip to let the emulator run again, and start counting over.
+    pub fn resume(&mut self) -> Option<WatchdogTrip> {
+        self.open_bus_instructions = 0;
+        self.kils = 0;
+        self.tripped.take()
+    }
+
+    /// Check the CPU after each tick, where `is_running` is false for a KIL. Returns
+    /// whether the emulator should keep running.
+    pub fn after_tick(&mut self, cpu: &mut Cpu6502<Bus>, is_running: bool) -> bool {
+        let reason = if is_running {
+            if cpu.bus.is_mapped_memory(cpu.pc) {
+                self.open_bus_instructions = 0;
+                return true;
+            }
+            self.open_bus_instructions += 1;
+            if self.open_bus_instructions < self.config.open_bus_instructions {
+                return true;
+            }
+            WatchdogReason::OpenBus { address: cpu.pc }
+        } else {
+            self.kils += 1;
+            if self.kils < self.config.kils {
+                return false;
+            }
+            // The PC has moved past the KIL.
+            WatchdogReason::Kil {
+                address: cpu.pc.wrapping_sub(1),
+            }
+        };
+
+        let trip = WatchdogTrip {
+            reason,
+            action: self.config.action,
+            frame: cpu.bus.ppu.frame_count(),
+            instructions: disassemble_history(&cpu.history, REPORTED_INSTRUCTIONS),
+        };
+        match self.config.action {
+            WatchdogAction::Reset => {
+                cpu.reset();
+                self.open_bus_instructions = 0;
+                self.kils = 0;
+                self.resets.push(trip);
+                true
+            }
+            WatchdogAction::Pause | WatchdogAction::Exit(_) => {
+                self.tripped = Some(trip);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu_6502::test_helpers::emulator_from_asm;
+    use crate::emulator::Emulator;
+
+    fn emulator(text: &str, config: WatchdogConfig) -> Emulator {
+        let mut emulator = emulator_from_asm(text);
+        emulator.watchdog = Some(Watchdog::new(config));
+        emulator
+    }
+
+    #[test]
+    fn test_open_bus() {
+        // Nothing is at $5000, so it reads back the $50 of the operand from the open
+        // bus, a bvc further into nothing. Trip on the first instruction.
+        let mut emulator = emulator(
+            "jmp $5000",
+            WatchdogConfig {
+                action: WatchdogAction::Exit(3),
+                open_bus_instructions: 1,
+                ..WatchdogConfig::default()
+            },
+        );
+        emulator.run_frame();
+
+        let watchdog = emulator.watchdog.as_mut().unwrap();
+        let trip = watchdog.tripped.clone().unwrap();
+        assert_eq!(trip.reason, WatchdogReason::OpenBus { address: 0x5000 });
+        assert_eq!(trip.exit_code(), Some(3));
+        assert!(trip.report().contains("open bus at $5000"));
+
+        // Nothing runs until the trip is cleared.
+        let cycle_count = emulator.cpu.cycle_count;
+        emulator.run_frame();
+        assert_eq!(emulator.cpu.cycle_count, cycle_count);
+        emulator.watchdog.as_mut().unwrap().resume();
+        emulator.run_frame();
+        assert_ne!(emulator.cpu.cycle_count, cycle_count);
+    }
+
+    #[test]
+    fn test_kil_reset() {
+        let mut emulator = emulator(
+            "
+            inc $00
+            kil
+            jmp $8000
+            ",
+            WatchdogConfig {
+                action: WatchdogAction::Reset,
+                kils: 3,
+                ..WatchdogConfig::default()
+            },
+        );
+        // Each KIL stops the frame early. The third one resets the CPU, and it runs
+        // to the next KIL.
+        for _ in 0..3 {
+            emulator.run_frame();
+        }
+        assert_eq!(emulator.cpu.bus.peek_u8(0x0000), 4);
+        let watchdog = emulator.watchdog.as_ref().unwrap();
+        assert!(!watchdog.is_tripped());
+        assert_eq!(
+            watchdog.resets[0].reason,
+            WatchdogReason::Kil { address: 0x8002 }
+        );
+        assert_eq!(emulator.cpu.pc, 0x8003);
+    }
+}
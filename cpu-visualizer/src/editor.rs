@@ -0,0 +1,198 @@
+use crate::load_cpu::{create_cpu, ProgramSource};
+use cpu_6502::{
+    asm::{AddressToLabel, AsmLexer, SourceFile},
+    bus::Bus,
+    cpu_6502::Cpu6502,
+};
+
+/// An error from building the program, shown inline in the editor.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    /// The 0-based line of the error. Errors from resolving labels happen after
+    /// parsing, and don't have a line.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+/// A minimal line based text editor for the asm source, so that a program can be
+/// changed and rebuilt without leaving the visualizer.
+pub struct Editor {
+    pub lines: Vec<String>,
+    /// The cursor position, where the column is counted in characters.
+    pub row: usize,
+    pub column: usize,
+    /// The first line that is visible.
+    pub scroll: usize,
+    pub diagnostic: Option<Diagnostic>,
+}
+
+impl Editor {
+    pub fn new(text: &str) -> Editor {
+        let mut lines: Vec<String> = text.lines().map(String::from).collect();
+        if lines.is_empty() {
+            lines.push(String::new());
+        }
+        Editor {
+            lines,
+            row: 0,
+            column: 0,
+            scroll: 0,
+            diagnostic: None,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        let mut text = self.lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    /// The byte index of the cursor in the current line.
+    fn byte_index(&self) -> usize {
+        let line = &self.lines[self.row];
+        line.char_indices()
+            .nth(self.column)
+            .map_or(line.len(), |(index, _)| index)
+    }
+
+    fn line_length(&self, row: usize) -> usize {
+        self.lines[row].chars().count()
+    }
+
+    pub fn insert(&mut self, character: char) {
+        if character == '\n' {
+            let index = self.byte_index();
+            let rest = self.lines[self.row].split_off(index);
+            self.row += 1;
+            self.column = 0;
+            self.lines.insert(self.row, rest);
+            return;
+        }
+        let index = self.byte_index();
+        self.lines[self.row].insert(index, character);
+        self.column += 1;
+    }
+
+    pub fn backspace(&mut self) {
+        if self.column > 0 {
+            self.column -= 1;
+            let index = self.byte_index();
+            self.lines[self.row].remove(index);
+        } else if self.row > 0 {
+            // Join this line onto the end of the previous one.
+            let line = self.lines.remove(self.row);
+            self.row -= 1;
+            self.column = self.line_length(self.row);
+            self.lines[self.row].push_str(&line);
+        }
+    }
+
+    pub fn move_left(&mut self) {
+        if self.column > 0 {
+            self.column -= 1;
+        } else if self.row > 0 {
+            self.row -= 1;
+            self.column = self.line_length(self.row);
+        }
+    }
+
+    pub fn move_right(&mut self) {
+        if self.column < self.line_length(self.row) {
+            self.column += 1;
+        } else if self.row + 1 < self.lines.len() {
+            self.row += 1;
+            self.column = 0;
+        }
+    }
+
+    pub fn move_up(&mut self) {
+        self.row = self.row.saturating_sub(1);
+        self.column = self.column.min(self.line_length(self.row));
+    }
+
+    pub fn move_down(&mut self) {
+        self.row = (self.row + 1).min(self.lines.len() - 1);
+        self.column = self.column.min(self.line_length(self.row));
+    }
+
+    /// Keep the cursor inside of the visible lines.
+    pub fn scroll_to_cursor(&mut self, height: usize) {
+        if self.row < self.scroll {
+            self.scroll = self.row;
+        } else if height > 0 && self.row >= self.scroll + height {
+            self.scroll = self.row + 1 - height;
+        }
+    }
+
+    /// Assemble the text into a new CPU. On failure the diagnostic is kept so that it
+    /// can be shown next to the offending line. The filename is where the text will be
+    /// saved.
+    pub fn build(
+        &mut self,
+        filename: &str,
+    ) -> Option<(Cpu6502<Bus>, AddressToLabel, ProgramSource)> {
+        let text = self.text();
+        let mut lexer = AsmLexer::new(&text);
+        let result = match lexer.parse() {
+            Ok(_) => lexer.into_bytes().map_err(|message| Diagnostic {
+                line: None,
+                message,
+            }),
+            Err(parse_error) => Err(Diagnostic {
+                line: Some((parse_error.row() as usize).saturating_sub(1)),
+                message: parse_error.message().into(),
+            }),
+        };
+        match result {
+            Ok(bytes_labels) => {
+                self.diagnostic = None;
+                let file = SourceFile {
+                    name: filename.to_string(),
+                    text,
+                };
+                Some(create_cpu(bytes_labels, vec![file]))
+            }
+            Err(diagnostic) => {
+                self.diagnostic = Some(diagnostic);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_editing() {
+        let mut editor = Editor::new("lda #$01\nsta $10");
+        editor.move_down();
+        editor.backspace();
+        assert_eq!(editor.text(), "lda #$01sta $10\n");
+        editor.insert('\n');
+        editor.insert('t');
+        editor.insert('a');
+        editor.insert('x');
+        editor.insert('\n');
+        assert_eq!(editor.text(), "lda #$01\ntax\nsta $10\n");
+        assert_eq!((editor.row, editor.column), (2, 0));
+    }
+
+    #[test]
+    fn test_build() {
+        let mut editor = Editor::new("lda #$01\nldq #$02\n");
+        assert!(editor.build("test.asm").is_none());
+        assert_eq!(editor.diagnostic.as_ref().and_then(|d| d.line), Some(1));
+
+        editor.row = 1;
+        editor.column = 3;
+        editor.backspace();
+        editor.insert('x');
+        let (mut cpu, _, source) = editor.build("test.asm").expect("The program builds");
+        assert_eq!(editor.diagnostic, None);
+        while cpu.tick() {}
+        assert_eq!((cpu.a, cpu.x), (0x01, 0x02));
+        assert_eq!(source.address_to_source_line[&0x8002].line, 2);
+    }
+}
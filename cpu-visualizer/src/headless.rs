@@ -0,0 +1,158 @@
+use termion::event::Key;
+use tui::buffer::Buffer;
+
+/// Options for running the visualizer without a terminal. The screen is drawn with
+/// the same code as the interactive mode, into an in-memory buffer, so this can be
+/// used to make screenshots of the debug views from scripts and CI.
+#[derive(Debug, PartialEq)]
+pub struct HeadlessOptions {
+    pub keys: Vec<Key>,
+    pub width: u16,
+    pub height: u16,
+    /// Where to write the screenshot, or stdout when not provided.
+    pub out: Option<String>,
+}
+
+impl Default for HeadlessOptions {
+    fn default() -> Self {
+        HeadlessOptions {
+            keys: Vec::new(),
+            width: 120,
+            height: 40,
+            out: None,
+        }
+    }
+}
+
+/// Parse the keys to press, using the same keys as the interactive mode. Special keys
+/// are written in angle brackets, e.g. "10n<esc>i<down><down>" or "e<ctrl-b>".
+pub fn parse_key_script(script: &str) -> Result<Vec<Key>, String> {
+    let mut keys = vec![];
+    let mut characters = script.chars();
+    while let Some(character) = characters.next() {
+        if character != '<' {
+            keys.push(Key::Char(character));
+            continue;
+        }
+        let name: String = characters.by_ref().take_while(|c| *c != '>').collect();
+        let key = match name.to_lowercase().as_str() {
+            "lt" => Key::Char('<'),
+            "enter" => Key::Char('\n'),
+            "tab" => Key::Char('\t'),
+            "esc" => Key::Esc,
+            "backspace" => Key::Backspace,
+            "up" => Key::Up,
+            "down" => Key::Down,
+            "left" => Key::Left,
+            "right" => Key::Right,
+            lowercase => {
+                if let Some(letter) = lowercase.strip_prefix("ctrl-") {
+                    let mut letters = letter.chars();
+                    match (letters.next(), letters.next()) {
+                        (Some(letter), None) => Key::Ctrl(letter),
+                        _ => return Err(format!("Unknown key <{}>", name)),
+                    }
+                } else if let Some(number) = lowercase.strip_prefix('f') {
+                    Key::F(
+                        number
+                            .parse()
+                            .map_err(|_| format!("Unknown key <{}>", name))?,
+                    )
+                } else {
+                    return Err(format!("Unknown key <{}>", name));
+                }
+            }
+        };
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+/// Parse the "--headless" arguments that follow the filename.
+pub fn parse_headless_args(args: &[String]) -> Result<Option<HeadlessOptions>, String> {
+    let mut options: Option<HeadlessOptions> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Expected a value after {}", arg))
+        };
+        match arg.as_str() {
+            "--headless" => {
+                options.get_or_insert_with(Default::default).keys =
+                    parse_key_script(value()?)?;
+            }
+            "--size" => {
+                let size = value()?;
+                let (width, height) = size
+                    .split_once('x')
+                    .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                    .ok_or_else(|| {
+                        format!("Expected a size like 120x40, found {}", size)
+                    })?;
+                let options = options.get_or_insert_with(Default::default);
+                options.width = width;
+                options.height = height;
+            }
+            "--out" => {
+                options.get_or_insert_with(Default::default).out = Some(value()?.clone());
+            }
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+/// Turn the drawn buffer into plain text, with the trailing whitespace removed.
+pub fn buffer_to_text(buffer: &Buffer) -> String {
+    let width = buffer.area.width as usize;
+    let mut text = String::new();
+    for row in buffer.content.chunks(width.max(1)) {
+        let line: String = row.iter().map(|cell| cell.symbol.as_str()).collect();
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_key_script() {
+        assert_eq!(
+            parse_key_script("10n<esc>i<Down><ctrl-b><F2><lt>"),
+            Ok(vec![
+                Key::Char('1'),
+                Key::Char('0'),
+                Key::Char('n'),
+                Key::Esc,
+                Key::Char('i'),
+                Key::Down,
+                Key::Ctrl('b'),
+                Key::F(2),
+                Key::Char('<'),
+            ])
+        );
+        assert_eq!(parse_key_script("<jump>"), Err("Unknown key <jump>".into()));
+    }
+
+    #[test]
+    fn test_parse_headless_args() {
+        let args: Vec<String> = ["--headless", "5n", "--size", "80x24"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert_eq!(
+            parse_headless_args(&args),
+            Ok(Some(HeadlessOptions {
+                keys: vec![Key::Char('5'), Key::Char('n')],
+                width: 80,
+                height: 24,
+                out: None,
+            }))
+        );
+        assert_eq!(parse_headless_args(&[]), Ok(None));
+    }
+}
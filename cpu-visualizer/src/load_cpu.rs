@@ -1,28 +1,29 @@
 use std::path::Path;
 
 use cpu_6502::{
-    asm::{AddressToLabel, AsmLexer, BytesLabels},
+    asm::{
+        AddressToLabel, AddressToSourceLine, AsmLexer, BytesLabels, SourceFile, Sources,
+    },
     bus::Bus,
-    cpu_6502::Cpu6502,
-    mappers::SimpleProgram,
-    opcodes::OpCode,
+    cpu_6502::{Cpu6502, NesCpu},
 };
 
-pub fn load_cpu<P: AsRef<Path>>(filename: P) -> (Cpu6502, AddressToLabel) {
-    let contents = std::fs::read_to_string(filename).unwrap();
-    let mut lexer = AsmLexer::new(&contents);
+/// The asm that a program was assembled from, to show the line that's running.
+pub struct ProgramSource {
+    pub files: Vec<SourceFile>,
+    pub address_to_source_line: AddressToSourceLine,
+}
+
+pub fn load_cpu<P: AsRef<Path>>(
+    filename: P,
+) -> (Cpu6502<Bus>, AddressToLabel, ProgramSource) {
+    let sources = Sources::load(filename.as_ref(), &[]).unwrap();
+    let mut lexer = AsmLexer::with_sources(&sources);
 
     match lexer.parse() {
         Ok(_) => {
-            let BytesLabels {
-                mut bytes,
-                address_to_label,
-            } = lexer.into_bytes().unwrap();
-            bytes.push(OpCode::KIL as u8);
-            (
-                Cpu6502::new(Bus::new_shared_bus(Box::new(SimpleProgram::load(&bytes)))),
-                address_to_label,
-            )
+            let bytes_labels = lexer.into_bytes().unwrap();
+            create_cpu(bytes_labels, sources.files)
         }
         Err(parse_error) => {
             parse_error.panic_nicely();
@@ -31,13 +32,33 @@ pub fn load_cpu<P: AsRef<Path>>(filename: P) -> (Cpu6502, AddressToLabel) {
     }
 }
 
+/// Load the assembled program into a fresh CPU.
+pub fn create_cpu(
+    bytes_labels: BytesLabels,
+    files: Vec<SourceFile>,
+) -> (Cpu6502<Bus>, AddressToLabel, ProgramSource) {
+    let BytesLabels {
+        bytes,
+        address_to_label,
+        address_to_source_line,
+    } = bytes_labels;
+    (
+        Cpu6502::from_program(&bytes),
+        address_to_label,
+        ProgramSource {
+            files,
+            address_to_source_line,
+        },
+    )
+}
+
 #[cfg(test)]
 mod test_cpu {
     use super::*;
     use std::path::PathBuf;
     pub const MAX_TICKS: usize = 1000;
 
-    fn run_cpu_n_ticks(cpu: &mut Cpu6502, ticks: usize) {
+    fn run_cpu_n_ticks(cpu: &mut Cpu6502<Bus>, ticks: usize) {
         for _ in 0..ticks {
             if !cpu.tick() {
                 panic!("The CPU quit before the end.");
@@ -45,7 +66,7 @@ mod test_cpu {
         }
     }
 
-    fn run_cpu_max_ticks(cpu: &mut Cpu6502) {
+    fn run_cpu_max_ticks(cpu: &mut Cpu6502<Bus>) {
         for _ in 0..MAX_TICKS {
             if !cpu.tick() {
                 return;
@@ -54,9 +75,9 @@ mod test_cpu {
         panic!("The CPU ran for too many ticks");
     }
 
-    fn get_ram_page_text(cpu: &Cpu6502, page_u8: u8, width: u16) -> Vec<String> {
+    fn get_ram_page_text(cpu: &Cpu6502<Bus>, page_u8: u8, width: u16) -> Vec<String> {
         let mut strings = vec![];
-        let bus = cpu.bus.borrow();
+        let bus = &cpu.bus;
 
         // Decide how many columns to make.
         let col_width = "$0000 0011 2233 4455 6677 8899 aabb ccdd eeff ".len();
@@ -82,7 +103,8 @@ mod test_cpu {
             // ^^^
             parts.push(format!("${:02x}{:x}_ ", page_u8, i));
             for j in 0..8 {
-                let [le, be] = bus.read_u16(page_u16 + i * 16 + j * 2).to_le_bytes();
+                let address = page_u16 + i * 16 + j * 2;
+                let (le, be) = (bus.peek_u8(address), bus.peek_u8(address + 1));
                 // $0000 0011 2233 4455 6677 8899 aabb ccdd eeff
                 //       ^^^^
                 parts.push(format!("{:02x}{:02x} ", le, be));
@@ -100,17 +122,17 @@ mod test_cpu {
         strings
     }
 
-    fn get_zero_page(cpu: &Cpu6502) -> Vec<String> {
+    fn get_zero_page(cpu: &Cpu6502<Bus>) -> Vec<String> {
         get_ram_page_text(cpu, 0, 100)
     }
 
-    fn run_cpu(filename: &str, ticks: Option<usize>) -> Cpu6502 {
+    fn run_cpu(filename: &str, ticks: Option<usize>) -> Cpu6502<Bus> {
         let mut path = PathBuf::new();
         path.push(std::env::var("CARGO_MANIFEST_DIR").unwrap());
         path.push("src/asm/");
         path.push(filename);
 
-        let (mut cpu, _) = load_cpu(&path);
+        let (mut cpu, _, _) = load_cpu(&path);
 
         match ticks {
             Some(ticks) => run_cpu_n_ticks(&mut cpu, ticks),
@@ -119,7 +141,7 @@ mod test_cpu {
         cpu
     }
 
-    fn get_cpu_text(cpu: &Cpu6502) -> Vec<String> {
+    fn get_cpu_text(cpu: &Cpu6502<Bus>) -> Vec<String> {
         vec![
             format!("Ticks: {}", cpu.tick_count),
             format!(" A: 0x{:02x} 0b{:08b}", cpu.a, cpu.a),
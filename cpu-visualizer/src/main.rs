@@ -1,30 +1,34 @@
+mod editor;
+mod headless;
 mod load_cpu;
+mod reference;
 #[allow(dead_code)]
 mod util;
 
+use crate::editor::Editor;
+use crate::headless::{buffer_to_text, parse_headless_args, HeadlessOptions};
+use crate::load_cpu::ProgramSource;
+use crate::reference::{build_reference, filter_reference, ReferenceRow};
 use crate::util::event::{Event, Events};
 use cpu_6502::{
-    asm::AddressToLabel,
-    cpu_6502::Cpu6502,
+    annotations::Annotations,
+    asm::{highlight_line, AddressToLabel, Highlight, SourceLine},
+    bus::{Bus, VectorTarget},
+    controller::MacroBindings,
+    cpu_6502::backward::{disassemble_backward, Confidence},
+    cpu_6502::{Cpu6502, NesCpu, Step, StepResult, STEP_LIMIT_FRAMES},
+    disassembler::{disassemble_instruction, disassemble_with_labels, listing},
     log::{init_log, log},
-    opcodes::{Mode, ADDRESSING_MODE_TABLE, OPCODE_STRING_TABLE},
+    storage::{FileStorage, StorageBackend},
 };
 use std::io::stdout;
 use std::io::Write;
-use std::{
-    collections::{HashMap, VecDeque},
-    env,
-    error::Error,
-    io::{self, Stdout},
-};
+use std::{collections::HashMap, env, error::Error, io};
 use termion::{
-    event::Key,
-    input::MouseTerminal,
-    raw::{IntoRawMode, RawTerminal},
-    screen::AlternateScreen,
+    event::Key, input::MouseTerminal, raw::IntoRawMode, screen::AlternateScreen,
 };
 use tui::{
-    backend::CrosstermBackend,
+    backend::{Backend, CrosstermBackend, TestBackend},
     layout::{Alignment, Rect},
     style::{Color, Modifier, Style},
     text::{Span, Spans},
@@ -36,12 +40,25 @@ const BORDER_COLOR: Color = Color::Rgb(150, 150, 150);
 const CYAN: Color = Color::Rgb(0, 200, 200);
 const MAGENTA: Color = Color::Rgb(200, 100, 200);
 const GRAY: Color = Color::Rgb(170, 170, 170);
+const DARK_GRAY: Color = Color::Rgb(120, 120, 120);
 const DIM_WHITE: Color = Color::Rgb(200, 200, 200);
 
-fn parse_cli_args() -> String {
+/// How many instructions the disassembly export writes.
+const EXPORTED_INSTRUCTIONS: usize = 256;
+
+fn parse_cli_args() -> (String, Option<HeadlessOptions>) {
     let args: Vec<String> = env::args().collect();
     match args.get(1) {
-        Some(filename) => filename.clone(),
+        Some(filename) => match parse_headless_args(&args[2..]) {
+            Ok(headless) => (filename.clone(), headless),
+            Err(message) => {
+                eprintln!("{}", message);
+                eprintln!(
+                    "Run headless with: --headless \"10n<esc>i\" --size 120x40 --out screen.txt"
+                );
+                std::process::exit(1);
+            }
+        },
         None => {
             eprintln!(
                 "The CPU visualizer expects the first argument to be a path to a raw .asm file."
@@ -54,41 +71,91 @@ fn parse_cli_args() -> String {
     }
 }
 
+/// Controller macros can be bound to the function keys in a file next to the asm, e.g.
+/// add-with-carry.macros containing "F1 = A*2 B".
+fn load_macro_bindings(filename: &str) -> MacroBindings {
+    let path = std::path::Path::new(filename).with_extension("macros");
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(_) => return MacroBindings::default(),
+    };
+    match MacroBindings::from_config_str(&text) {
+        Ok(bindings) => bindings,
+        Err(message) => {
+            eprintln!("Unable to load the macros in {:?}: {}", path, message);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The labels and comments are kept next to the asm, e.g. add-with-carry.labels, so
+/// they're still there the next time the program is opened.
+fn annotations_key(filename: &str) -> String {
+    std::path::Path::new(filename)
+        .with_extension("labels")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// What's being typed in for the instruction at the top of the view.
+#[derive(PartialEq, Clone, Debug, Copy)]
+enum AnnotationKind {
+    Label,
+    Comment,
+}
+
 /// Determines how the Visualizer operates.
 #[derive(PartialEq, Clone, Debug, Copy)]
 enum VisMode {
     Visualizer,
     Help,
     AddPageMemory,
+    Reference,
+    Editor,
+    Annotate(AnnotationKind),
     Quit,
 }
 
 struct Visualizer {
     last_drawn_tick_count: u64,
     last_drawn_mode: Option<VisMode>,
-    cpu: Cpu6502,
+    cpu: Cpu6502<Bus>,
     address_to_label: HashMap<u16, String>,
     mode: VisMode,
-    events: Events,
-    executed_instructions: VecDeque<Spans<'static>>,
     add_page_address: String,
     draw_is_dirty: bool,
     last_size: Rect,
     pages: Vec<u8>,
+    reference_rows: Vec<ReferenceRow>,
+    reference_query: String,
+    reference_scroll: usize,
+    // The digits typed in before a step command.
+    step_count: String,
+    // The last step gave up before getting where it was going.
+    step_timed_out: bool,
+    filename: String,
+    // The editor is created the first time it's opened, and keeps its text after.
+    editor: Option<Editor>,
+    macro_bindings: MacroBindings,
+    // The asm that the program was built from, and whether it's shown in place of the
+    // disassembly.
+    source: ProgramSource,
+    is_source_shown: bool,
+    // Which interrupt vector's target is disassembled in place of the PC, if any.
+    vector_index: Option<usize>,
+    // The labels and comments given in the visualizer, and the one being typed in.
+    annotations: Annotations,
+    storage: FileStorage,
+    annotation_text: String,
 }
 
-type VisTerminal =
-    Terminal<CrosstermBackend<AlternateScreen<MouseTerminal<RawTerminal<Stdout>>>>>;
-
 impl Visualizer {
-    pub fn new() -> Result<Visualizer, Box<dyn Error>> {
-        // Load the CPU first, as this can exit the process.
-        let filename = parse_cli_args();
+    pub fn new(filename: String) -> Result<Visualizer, Box<dyn Error>> {
         log(&format!("Loading file {}", filename));
-        let (cpu, address_to_label) = load_cpu::load_cpu(&filename);
-        let mut events = Events::new();
-        // Our event processing handles exiting.
-        events.disable_exit_key();
+        let (cpu, address_to_label, source) = load_cpu::load_cpu(&filename);
+        let macro_bindings = load_macro_bindings(&filename);
+        let storage = FileStorage::new("");
+        let annotations = Annotations::load(&storage, &annotations_key(&filename))?;
 
         Ok(Visualizer {
             last_drawn_tick_count: u64::MAX,
@@ -96,12 +163,24 @@ impl Visualizer {
             cpu,
             address_to_label,
             mode: VisMode::Visualizer,
-            events,
-            executed_instructions: VecDeque::new(),
             add_page_address: String::new(),
             draw_is_dirty: false,
             last_size: Default::default(),
             pages: Vec::new(),
+            reference_rows: build_reference(),
+            reference_query: String::new(),
+            reference_scroll: 0,
+            step_count: String::new(),
+            step_timed_out: false,
+            filename,
+            editor: None,
+            macro_bindings,
+            source,
+            is_source_shown: false,
+            vector_index: None,
+            annotations,
+            storage,
+            annotation_text: String::new(),
         })
     }
 
@@ -113,6 +192,9 @@ impl Visualizer {
             let backend = CrosstermBackend::new(stdout);
             Terminal::new(backend)?
         };
+        let mut events = Events::new();
+        // Our event processing handles exiting.
+        events.disable_exit_key();
 
         loop {
             let size = terminal.size().expect("Unable to get the terminal size");
@@ -123,38 +205,92 @@ impl Visualizer {
                 || self.last_size != size
                 || self.draw_is_dirty
             {
-                match self.mode {
-                    VisMode::Visualizer => {
-                        self.draw_cpu_visualizer(&mut terminal)?;
-                    }
-                    VisMode::Help => {
-                        self.draw_help(&mut terminal)?;
-                    }
-                    VisMode::AddPageMemory => {
-                        self.draw_add_page_memory(&mut terminal)?;
-                    }
-                    VisMode::Quit => return Ok(()),
-                };
+                if self.mode == VisMode::Quit {
+                    return Ok(());
+                }
+                self.draw(&mut terminal)?;
                 self.draw_is_dirty = false;
                 self.last_size = size;
             }
 
             self.last_drawn_mode = Some(self.mode);
 
-            self.process_events()?;
+            // Handle all of the keyboard events.
+            if let Event::Input(key) = events.next()? {
+                self.process_key(key)?;
+            }
         }
     }
 
-    fn draw_help(&mut self, terminal: &mut VisTerminal) -> Result<(), Box<dyn Error>> {
+    /// Press the keys from the script, and then draw the screen into a buffer rather
+    /// than a terminal.
+    pub fn run_headless(
+        &mut self,
+        options: HeadlessOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut terminal =
+            Terminal::new(TestBackend::new(options.width, options.height))?;
+        for key in options.keys {
+            self.process_key(key)?;
+            if self.mode == VisMode::Quit {
+                // The program ended, show where it stopped.
+                self.mode = VisMode::Visualizer;
+                break;
+            }
+        }
+        self.draw(&mut terminal)?;
+
+        let text = buffer_to_text(terminal.backend().buffer());
+        match options.out {
+            Some(out) => std::fs::write(out, text)?,
+            None => print!("{}", text),
+        }
+        Ok(())
+    }
+
+    fn draw<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+    ) -> Result<(), Box<dyn Error>> {
+        match self.mode {
+            VisMode::Visualizer => self.draw_cpu_visualizer(terminal),
+            VisMode::Help => self.draw_help(terminal),
+            VisMode::AddPageMemory => self.draw_add_page_memory(terminal),
+            VisMode::Reference => self.draw_reference(terminal),
+            VisMode::Editor => self.draw_editor(terminal),
+            VisMode::Annotate(kind) => self.draw_annotate(terminal, kind),
+            VisMode::Quit => Ok(()),
+        }
+    }
+
+    fn draw_help<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+    ) -> Result<(), Box<dyn Error>> {
         terminal.draw(|frame| {
             let help = vec![
                 //
-                "   n - next instruction",
-                " 1-9 - next instructions exponentionally ",
+                " 0-9 - type a count for the next step command",
+                "   n - step instructions",
+                "   s - step scanlines",
+                "   f - step frames",
+                "   d - step PPU dots, to the end of an instruction",
+                "   o - step over a jsr",
+                "   u - step out of the subroutine",
+                "   t - run to the vector shown with v",
+                " esc - clear the count",
                 " h/? - show help",
                 "   q - quit",
                 "   a - add a page of memory",
                 "   r - remove a page of memory",
+                "   i - instruction set reference",
+                "   e - edit the asm, ctrl-b to build and run, ctrl-s to save",
+                "  F* - play a controller macro from the .macros file",
+                "   v - disassemble at the NMI, RESET, then IRQ vector",
+                "   l - label the instruction at the top of the view",
+                "   ; - comment on the instruction at the top of the view",
+                "   x - export the disassembly from the top of the view",
+                "   c - show the asm source in place of the disassembly",
             ];
             let mut width = 0;
             for s in help.iter() {
@@ -170,9 +306,9 @@ impl Visualizer {
         Ok(())
     }
 
-    fn draw_add_page_memory(
+    fn draw_add_page_memory<B: Backend>(
         &mut self,
-        terminal: &mut VisTerminal,
+        terminal: &mut Terminal<B>,
     ) -> Result<(), Box<dyn Error>> {
         terminal.draw(|frame| {
             frame.set_cursor(
@@ -194,12 +330,198 @@ impl Visualizer {
         Ok(())
     }
 
-    fn draw_cpu_visualizer(
+    fn draw_annotate<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+        kind: AnnotationKind,
+    ) -> Result<(), Box<dyn Error>> {
+        let title = format!(
+            "{} for ${:04x}, empty removes it",
+            match kind {
+                AnnotationKind::Label => "Label",
+                AnnotationKind::Comment => "Comment",
+            },
+            self.annotated_address()
+        );
+        terminal.draw(|frame| {
+            let width = (title.len().max(self.annotation_text.len() + 1) + 2) as u16;
+            frame.set_cursor(self.annotation_text.len() as u16 + 1, 1);
+            frame.render_widget(
+                Paragraph::new(self.annotation_text.clone())
+                    .block(create_block(&title))
+                    .alignment(Alignment::Left),
+                Rect::new(0, 0, width.min(frame.size().width), 3),
+            );
+        })?;
+        Ok(())
+    }
+
+    fn draw_reference<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+    ) -> Result<(), Box<dyn Error>> {
+        terminal.draw(|frame| {
+            let frame_rect = frame.size();
+            let rows = filter_reference(&self.reference_rows, &self.reference_query);
+            let inner_height = frame_rect.height.saturating_sub(4) as usize;
+
+            // Keep the scroll position inside of the filtered results.
+            self.reference_scroll = self
+                .reference_scroll
+                .min(rows.len().saturating_sub(inner_height));
+
+            let mut text = vec![
+                Spans::from(vec![
+                    Span::styled("Search: ", Style::default().fg(Color::DarkGray)),
+                    Span::styled(
+                        self.reference_query.clone(),
+                        Style::default().fg(Color::White),
+                    ),
+                ]),
+                Spans::from(Span::styled(
+                    "op  ins  operand   cyc  flags",
+                    Style::default().fg(Color::DarkGray),
+                )),
+            ];
+
+            for row in rows.iter().skip(self.reference_scroll).take(inner_height) {
+                let style = if row.is_illegal {
+                    Style::default().fg(GRAY)
+                } else {
+                    Style::default().fg(DIM_WHITE)
+                };
+                text.push(Spans::from(Span::styled(row.to_text(), style)));
+            }
+
+            frame.set_cursor(
+                // Put the cursor past the end of "Search: "
+                self.reference_query.len() as u16 + 9,
+                1,
+            );
+            frame.render_widget(
+                Paragraph::new(text)
+                    .block(create_block(
+                        "Instruction Reference (search mnemonics or flag:nz, esc to close)",
+                    ))
+                    .alignment(Alignment::Left),
+                frame_rect,
+            );
+        })?;
+        Ok(())
+    }
+
+    fn draw_editor<B: Backend>(
         &mut self,
-        terminal: &mut VisTerminal,
+        terminal: &mut Terminal<B>,
+    ) -> Result<(), Box<dyn Error>> {
+        let editor = match self.editor.as_mut() {
+            Some(editor) => editor,
+            None => return Ok(()),
+        };
+        let title = format!("Editor - {}", self.filename);
+        terminal.draw(|frame| {
+            let frame_rect = frame.size();
+            // Leave room for the borders and the diagnostic line.
+            let inner_height = frame_rect.height.saturating_sub(3) as usize;
+            editor.scroll_to_cursor(inner_height);
+            let gutter_width = 5;
+
+            let mut text = vec![];
+            for (row, line) in editor
+                .lines
+                .iter()
+                .enumerate()
+                .skip(editor.scroll)
+                .take(inner_height)
+            {
+                let has_error = match &editor.diagnostic {
+                    Some(diagnostic) => diagnostic.line == Some(row),
+                    None => false,
+                };
+                let mut spans = vec![Span::styled(
+                    format!("{:>4} ", row + 1),
+                    Style::default().fg(if has_error { Color::Red } else { GRAY }),
+                )];
+                for (highlight, part) in highlight_line(line) {
+                    spans.push(Span::styled(
+                        part.to_string(),
+                        Style::default().fg(highlight_color(highlight)),
+                    ));
+                }
+                text.push(Spans::from(spans));
+            }
+            while text.len() < inner_height {
+                text.push(Spans::from(""));
+            }
+
+            text.push(Spans::from(match &editor.diagnostic {
+                Some(diagnostic) => Span::styled(
+                    match diagnostic.line {
+                        Some(line) => {
+                            format!("Line {}: {}", line + 1, diagnostic.message)
+                        }
+                        None => diagnostic.message.clone(),
+                    },
+                    Style::default().fg(Color::Red),
+                ),
+                None => Span::styled(
+                    "ctrl-b build and run, ctrl-s save, esc close",
+                    Style::default().fg(Color::DarkGray),
+                ),
+            }));
+
+            frame.set_cursor(
+                editor.column as u16 + gutter_width + 1,
+                (editor.row - editor.scroll) as u16 + 1,
+            );
+            frame.render_widget(
+                Paragraph::new(text)
+                    .block(create_block(&title))
+                    .alignment(Alignment::Left),
+                frame_rect,
+            );
+        })?;
+        Ok(())
+    }
+
+    /// Build the editor's text, and swap the program into a fresh CPU.
+    fn build_and_run(&mut self) {
+        let editor = match self.editor.as_mut() {
+            Some(editor) => editor,
+            None => return,
+        };
+        match editor.build(&self.filename) {
+            Some((cpu, address_to_label, source)) => {
+                log("Built the program, swapping in the new CPU");
+                self.cpu = cpu;
+                self.address_to_label = address_to_label;
+                self.source = source;
+                self.last_drawn_tick_count = u64::MAX;
+                self.mode = VisMode::Visualizer;
+            }
+            None => {
+                log(&format!("Build failed {:?}", editor.diagnostic));
+            }
+        }
+        self.draw_is_dirty = true;
+    }
+
+    fn save_editor(&mut self) {
+        if let Some(editor) = &self.editor {
+            match std::fs::write(&self.filename, editor.text()) {
+                Ok(_) => log(&format!("Saved {}", self.filename)),
+                Err(err) => log(&format!("Unable to save {}: {}", self.filename, err)),
+            }
+        }
+    }
+
+    fn draw_cpu_visualizer<B: Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
     ) -> Result<(), Box<dyn Error>> {
         let registers_rect_width = 40;
         let instructions_rect_width = 40;
+        let labels = self.annotations.labels_over(&self.address_to_label);
 
         terminal.draw(|frame| {
             self.last_drawn_tick_count = self.cpu.tick_count;
@@ -303,22 +625,59 @@ impl Visualizer {
                 }
             }
 
-            // Instructions.
+            // Instructions, or the code at an interrupt vector.
+            let vectors = self.cpu.bus.interrupt_vectors();
+            let source_text = match self.is_source_shown {
+                true => {
+                    get_source_text(&self.source, self.cpu.pc, main_rect_inner_height)
+                }
+                false => None,
+            };
+            let (instructions_text, instructions_title) =
+                match (self.vector_index, source_text) {
+                    (None, Some(source_text)) => source_text,
+                    (Some(index), _) => {
+                        let vector = &vectors[index];
+                        (
+                            get_vector_instructions_text(
+                                &self.cpu,
+                                vector.target,
+                                main_rect_inner_height,
+                                &labels,
+                                &self.annotations,
+                            ),
+                            format!("Instructions at {}", vector.name),
+                        )
+                    }
+                    (None, None) => (
+                        get_instructions_text(
+                            &self.cpu,
+                            main_rect_inner_height,
+                            &labels,
+                            &self.annotations,
+                        ),
+                        "Instructions".into(),
+                    ),
+                };
             frame.render_widget(
-                Paragraph::new(get_instructions_text(
-                    &self.cpu,
-                    main_rect_inner_height,
-                    &mut self.executed_instructions,
-                    &self.address_to_label,
-                ))
-                .block(create_block("Instructions"))
-                .alignment(Alignment::Left),
+                Paragraph::new(instructions_text)
+                    .block(create_block(&instructions_title))
+                    .alignment(Alignment::Left),
                 instructions_rect,
             );
 
             // Registeres
-            let registers_text = vec![
-                add_tick_count(self.cpu.tick_count),
+            let mut registers_text = vec![
+                add_count_span("Ticks", self.cpu.tick_count.to_string()),
+                add_count_span("Cycles", self.cpu.cycle_count.to_string()),
+                add_count_span(
+                    "Step",
+                    if self.step_count.is_empty() {
+                        "1".into()
+                    } else {
+                        self.step_count.clone()
+                    },
+                ),
                 add_register_span("A", self.cpu.a),
                 add_register_span("X", self.cpu.x),
                 add_register_span("Y", self.cpu.y),
@@ -333,7 +692,18 @@ impl Visualizer {
                 add_status_register_info("||  +---- Decimal"),
                 add_status_register_info("|+-------- Overflow"),
                 add_status_register_info("+--------- Negative"),
+                Spans::default(),
             ];
+            if self.step_timed_out {
+                // Under the step count, so it's clear which step gave up.
+                registers_text.insert(
+                    3,
+                    add_count_span("Timed out", format!("{} frames", STEP_LIMIT_FRAMES)),
+                );
+            }
+            for vector in vectors.iter() {
+                registers_text.extend(add_vector_spans(vector, &labels));
+            }
 
             frame.render_widget(
                 Paragraph::new(registers_text)
@@ -346,9 +716,98 @@ impl Visualizer {
         Ok(())
     }
 
-    fn process_events(&mut self) -> Result<(), Box<dyn Error>> {
-        // Handle all of the keyboard events.
-        if let Event::Input(key) = self.events.next()? {
+    /// The instruction at the top of the view, which is the PC unless a vector is
+    /// being shown.
+    fn annotated_address(&self) -> u16 {
+        match self.vector_index {
+            Some(index) => self.cpu.bus.interrupt_vectors()[index].target,
+            None => self.cpu.pc,
+        }
+    }
+
+    fn start_annotation(&mut self, kind: AnnotationKind) {
+        let address = self.annotated_address();
+        let current = match kind {
+            AnnotationKind::Label => self
+                .annotations
+                .labels_over(&self.address_to_label)
+                .remove(&address),
+            AnnotationKind::Comment => {
+                self.annotations.comment(address).map(String::from)
+            }
+        };
+        self.annotation_text = current.unwrap_or_default();
+        self.mode = VisMode::Annotate(kind);
+    }
+
+    fn finish_annotation(&mut self, kind: AnnotationKind) {
+        let address = self.annotated_address();
+        let text = std::mem::take(&mut self.annotation_text);
+        match kind {
+            AnnotationKind::Label => {
+                if let Err(err) = self.annotations.rename_label(address, &text) {
+                    log(&err);
+                }
+            }
+            AnnotationKind::Comment => self.annotations.set_comment(address, &text),
+        }
+        let key = annotations_key(&self.filename);
+        if let Err(err) = self.annotations.save(&mut self.storage, &key) {
+            log(&format!("Unable to save the labels: {}", err));
+        }
+        self.mode = VisMode::Visualizer;
+    }
+
+    /// Write the disassembly from the top of the view, with the labels and comments,
+    /// next to the asm.
+    fn export_disassembly(&mut self) {
+        let mut instructions = disassemble_with_labels(
+            &self.cpu.bus,
+            self.annotated_address(),
+            EXPORTED_INSTRUCTIONS,
+            Some(&self.address_to_label),
+        );
+        for instruction in instructions.iter_mut() {
+            self.annotations.annotate(instruction);
+        }
+        let key = std::path::Path::new(&self.filename)
+            .with_extension("dis.asm")
+            .to_string_lossy()
+            .into_owned();
+        match self.storage.write(&key, listing(&instructions).as_bytes()) {
+            Ok(_) => log(&format!("Exported the disassembly to {}", key)),
+            Err(err) => log(&format!("Unable to export the disassembly: {}", err)),
+        }
+    }
+
+    /// Use up the typed in count, which defaults to 1.
+    fn take_step_count(&mut self) -> u64 {
+        let count = self.step_count.parse().unwrap_or(1).max(1);
+        self.step_count.clear();
+        self.draw_is_dirty = true;
+        count
+    }
+
+    /// Returns false if the step didn't finish, so that repeated steps stop.
+    fn step(&mut self, step: Step) -> bool {
+        log(&format!("Step {:?} from ${:x}", step, self.cpu.pc));
+        let result = self.cpu.step(step);
+        self.step_timed_out = result == StepResult::TimedOut;
+        match result {
+            StepResult::Done => return true,
+            StepResult::Kil => {
+                log("CPU instructions ended, quitting.");
+                self.mode = VisMode::Quit;
+            }
+            StepResult::TimedOut => {
+                log(&format!("Step gave up after {} frames", STEP_LIMIT_FRAMES));
+            }
+        }
+        false
+    }
+
+    fn process_key(&mut self, key: Key) -> Result<(), Box<dyn Error>> {
+        {
             match self.mode {
                 VisMode::Visualizer => match key {
                     Key::Char('a') => {
@@ -369,29 +828,98 @@ impl Visualizer {
                         log("Go to help");
                         self.mode = VisMode::Help;
                     }
-                    Key::Char('n') | Key::Char('1') => {
-                        log(&format!("Next instruction ${:x}", self.cpu.pc));
-                        if !self.cpu.tick() {
-                            log("CPU instructions ended, quitting.");
-                            self.mode = VisMode::Quit;
+                    Key::Char('i') => {
+                        log("Go to the instruction reference");
+                        self.mode = VisMode::Reference;
+                    }
+                    Key::Char('e') => {
+                        log("Go to the editor");
+                        if self.editor.is_none() {
+                            let text = std::fs::read_to_string(&self.filename)?;
+                            self.editor = Some(Editor::new(&text));
                         }
+                        self.mode = VisMode::Editor;
                     }
-                    // Skip through instructions much quicker.
-                    Key::Char(c) => {
-                        if let Some(n) = c.to_digit(10) {
-                            if n != 0 {
-                                let count = (n + 1).pow(2);
-                                log(&format!("Next {} instructions", count));
-                                for _ in 0..count {
-                                    if !self.cpu.tick() {
-                                        log("CPU instructions ended, quitting.");
-                                        self.mode = VisMode::Quit;
-                                        break;
-                                    }
-                                }
+                    Key::Char('n') => {
+                        let count = self.take_step_count();
+                        self.step(Step::Instructions(count));
+                    }
+                    Key::Char('s') => {
+                        let count = self.take_step_count();
+                        self.step(Step::Scanlines(count));
+                    }
+                    Key::Char('f') => {
+                        let count = self.take_step_count();
+                        self.step(Step::Frames(count));
+                    }
+                    Key::Char('d') => {
+                        let count = self.take_step_count();
+                        self.step(Step::Dots(count));
+                    }
+                    Key::Char('o') => {
+                        for _ in 0..self.take_step_count() {
+                            if !self.step(Step::Over) {
+                                break;
                             }
                         }
                     }
+                    Key::Char('u') => {
+                        for _ in 0..self.take_step_count() {
+                            if !self.step(Step::Out) {
+                                break;
+                            }
+                        }
+                    }
+                    Key::Char('t') => match self.vector_index.take() {
+                        Some(index) => {
+                            let target = self.cpu.bus.interrupt_vectors()[index].target;
+                            self.step(Step::ToAddress(target));
+                            self.draw_is_dirty = true;
+                        }
+                        None => log("Pick a vector to run to with v first"),
+                    },
+                    Key::Char('l') => self.start_annotation(AnnotationKind::Label),
+                    Key::Char(';') => self.start_annotation(AnnotationKind::Comment),
+                    Key::Char('x') => self.export_disassembly(),
+                    Key::Char('c') => {
+                        self.is_source_shown = !self.is_source_shown;
+                        self.draw_is_dirty = true;
+                    }
+                    Key::Char('v') => {
+                        // Cycle through the vectors, then back to the PC.
+                        self.vector_index = match self.vector_index {
+                            None => Some(0),
+                            Some(2) => None,
+                            Some(index) => Some(index + 1),
+                        };
+                        log(&format!("Disassemble at vector {:?}", self.vector_index));
+                        self.draw_is_dirty = true;
+                    }
+                    Key::F(number) => {
+                        let key = format!("F{}", number);
+                        match self.macro_bindings.get(&key) {
+                            Some(input_macro) => {
+                                log(&format!("Play the macro bound to {}", key));
+                                self.cpu.bus.controller_1.play(input_macro);
+                            }
+                            None => log(&format!("No macro is bound to {}", key)),
+                        }
+                    }
+                    Key::Backspace => {
+                        self.step_count.pop();
+                        self.draw_is_dirty = true;
+                    }
+                    Key::Esc => {
+                        self.step_count.clear();
+                        self.vector_index = None;
+                        self.draw_is_dirty = true;
+                    }
+                    // Build up the count for the next step, e.g. "25s" steps 25
+                    // scanlines.
+                    Key::Char(c) if c.is_ascii_digit() && self.step_count.len() < 9 => {
+                        self.step_count.push(c);
+                        self.draw_is_dirty = true;
+                    }
                     _ => {}
                 },
                 VisMode::Help => match key {
@@ -456,6 +984,71 @@ impl Visualizer {
                     }
                     _ => {}
                 },
+                VisMode::Annotate(kind) => {
+                    match key {
+                        Key::Char('\n') => self.finish_annotation(kind),
+                        Key::Esc => {
+                            self.annotation_text.clear();
+                            self.mode = VisMode::Visualizer;
+                        }
+                        Key::Backspace => {
+                            self.annotation_text.pop();
+                        }
+                        Key::Char(c) => self.annotation_text.push(c),
+                        _ => {}
+                    }
+                    self.draw_is_dirty = true;
+                }
+                VisMode::Reference => match key {
+                    Key::Esc => {
+                        log("Go back to visualizer");
+                        self.mode = VisMode::Visualizer;
+                    }
+                    Key::Up => {
+                        self.reference_scroll = self.reference_scroll.saturating_sub(1);
+                        self.draw_is_dirty = true;
+                    }
+                    Key::Down => {
+                        self.reference_scroll += 1;
+                        self.draw_is_dirty = true;
+                    }
+                    Key::Backspace => {
+                        self.reference_query.pop();
+                        self.reference_scroll = 0;
+                        self.draw_is_dirty = true;
+                    }
+                    Key::Char(c) if c != '\n' => {
+                        self.reference_query.push(c);
+                        self.reference_scroll = 0;
+                        self.draw_is_dirty = true;
+                    }
+                    _ => {}
+                },
+                VisMode::Editor => {
+                    if let Some(editor) = self.editor.as_mut() {
+                        match key {
+                            Key::Esc => {
+                                log("Go back to visualizer");
+                                self.mode = VisMode::Visualizer;
+                            }
+                            Key::Ctrl('b') => self.build_and_run(),
+                            Key::Ctrl('s') => self.save_editor(),
+                            Key::Up => editor.move_up(),
+                            Key::Down => editor.move_down(),
+                            Key::Left => editor.move_left(),
+                            Key::Right => editor.move_right(),
+                            Key::Backspace => editor.backspace(),
+                            Key::Char('\t') => {
+                                for _ in 0..4 {
+                                    editor.insert(' ');
+                                }
+                            }
+                            Key::Char(c) => editor.insert(c),
+                            _ => {}
+                        }
+                    }
+                    self.draw_is_dirty = true;
+                }
                 VisMode::Quit => {}
             }
         }
@@ -464,6 +1057,14 @@ impl Visualizer {
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
+    // Load the CPU first, as this can exit the process.
+    let (filename, headless) = parse_cli_args();
+    init_log();
+    let mut visualizer = Visualizer::new(filename)?;
+    if let Some(options) = headless {
+        return visualizer.run_headless(options);
+    }
+
     std::panic::set_hook(Box::new(move |x| {
         stdout()
             .into_raw_mode()
@@ -479,8 +1080,6 @@ fn main() -> Result<(), Box<dyn Error>> {
         write!(stdout(), "{:?}", x).unwrap();
     }));
 
-    init_log();
-    let mut visualizer = Visualizer::new()?;
     visualizer.run()
 }
 
@@ -526,20 +1125,56 @@ fn add_pc_register_span(value: u16) -> Spans<'static> {
     Spans::from(parts)
 }
 
-fn add_tick_count(count: u64) -> Spans<'static> {
+fn add_count_span(name: &str, count: String) -> Spans<'static> {
     let mut parts = vec![];
     parts.push(Span::styled(
-        "Ticks: ",
+        format!("{}: ", name),
         Style::default()
             .fg(Color::Yellow)
             .add_modifier(Modifier::BOLD),
     ));
+    parts.push(Span::styled(count, Style::default().fg(Color::White)));
+
+    Spans::from(parts)
+}
+
+/// e.g. "NMI $fffa -> nmi_handler $8040", with a warning for broken targets.
+fn add_vector_spans(
+    vector: &VectorTarget,
+    address_to_label: &AddressToLabel,
+) -> Vec<Spans<'static>> {
+    let mut parts = vec![
+        Span::styled(
+            format!("{:5}", vector.name),
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        ),
+        Span::styled(
+            format!(" ${:04x} -> ", vector.vector),
+            Style::default().fg(Color::DarkGray),
+        ),
+    ];
+    if let Some(label) = address_to_label.get(&vector.target) {
+        parts.push(Span::styled(
+            format!("{} ", label),
+            Style::default().fg(MAGENTA),
+        ));
+    }
     parts.push(Span::styled(
-        count.to_string(),
+        format!("${:04x}", vector.target),
         Style::default().fg(Color::White),
     ));
 
-    Spans::from(parts)
+    let mut lines = vec![Spans::from(parts)];
+    if let Some(problem) = vector.problem {
+        // The paragraph trims leading whitespace, so indent with a hidden character.
+        lines.push(Spans::from(vec![
+            Span::styled("·     ", Style::default().fg(Color::Black)),
+            Span::styled(format!("! {}", problem), Style::default().fg(Color::Red)),
+        ]));
+    }
+    lines
 }
 
 fn add_status_register_info(info: &str) -> Spans {
@@ -552,197 +1187,247 @@ fn add_status_register_info(info: &str) -> Spans {
     Spans::from(parts)
 }
 
-fn get_instructions_text<'a>(
-    cpu: &'a Cpu6502,
+fn highlight_color(highlight: Highlight) -> Color {
+    match highlight {
+        Highlight::Instruction => CYAN,
+        Highlight::Label => MAGENTA,
+        Highlight::Directive => Color::Yellow,
+        Highlight::Number => Color::Green,
+        Highlight::Comment => Color::DarkGray,
+        Highlight::Plain => DIM_WHITE,
+    }
+}
+
+/// The lines of asm around the one that the PC was assembled from, with its title. It's
+/// None when the PC isn't in the assembled code.
+fn get_source_text(
+    source: &ProgramSource,
+    pc: u16,
+    height: u16,
+) -> Option<(Vec<Spans<'static>>, String)> {
+    let SourceLine { file, line } = *source.address_to_source_line.get(&pc)?;
+    let file = &source.files[file];
+    // Like the instructions, the current line is a third of the way down.
+    let current = line as usize - 1;
+    let first = current.saturating_sub(height as usize / 3);
+    let lines = file
+        .text
+        .lines()
+        .enumerate()
+        .skip(first)
+        .take(height as usize)
+        .map(|(row, text)| {
+            let is_current = row == current;
+            let base_style = match is_current {
+                true => Style::default().add_modifier(Modifier::BOLD),
+                false => Style::default(),
+            };
+            let mut spans = vec![Span::styled(
+                format!("{}{:>4} ", if is_current { ">" } else { " " }, row + 1),
+                base_style.fg(if is_current { Color::Yellow } else { GRAY }),
+            )];
+            for (highlight, part) in highlight_line(text) {
+                spans.push(Span::styled(
+                    part.to_string(),
+                    base_style.fg(highlight_color(highlight)),
+                ));
+            }
+            Spans::from(spans)
+        })
+        .collect();
+    let name = std::path::Path::new(&file.name)
+        .file_name()
+        .map_or(file.name.clone(), |name| name.to_string_lossy().to_string());
+    Some((lines, format!("Source - {}", name)))
+}
+
+fn get_instructions_text(
+    cpu: &Cpu6502<Bus>,
     height: u16,
-    executed_instructions: &'a mut VecDeque<Spans<'static>>,
     address_to_label: &AddressToLabel,
-) -> Vec<Spans<'a>> {
+    annotations: &Annotations,
+) -> Vec<Spans<'static>> {
     let mut spans_list: Vec<Spans> = vec![];
-    let bus = cpu.bus.borrow();
-    let mut pc = cpu.pc;
-
-    // Make sure the VecDeque is sized correctly to the available of back buffer.
-    let executed_len = height / 3;
-    executed_instructions.truncate(executed_len as usize);
 
-    let next_instructructions_len =
-        height - executed_instructions.len() as u16 + height % 3;
-
-    for spans in executed_instructions.iter().rev() {
-        spans_list.push(spans.clone());
+    // The top third shows the instructions that actually ran, from the history.
+    // Decoding backwards from the PC is unreliable, as it doesn't know where the
+    // instructions start, or that a jump was taken.
+    let executed_len = (height / 3) as usize;
+    let mut executed_spans = vec![];
+    for instruction in cpu.history.last(executed_len) {
+        let read_u8 = |address| instruction.read_u8(address);
+        let (lines, _) = instruction_spans(
+            instruction.address,
+            read_u8,
+            address_to_label,
+            annotations,
+            false,
+        );
+        for mut spans in lines {
+            for span in spans.0.iter_mut() {
+                span.style = Style::default().fg(GRAY);
+            }
+            executed_spans.push(spans);
+        }
     }
 
-    for i in 0..next_instructructions_len {
-        let mut parts = vec![];
-
-        let base_style = {
-            if i == 0 {
-                Style::default().add_modifier(Modifier::BOLD)
-            } else {
-                Style::default()
+    let bus = &cpu.bus;
+    let read_u8 = |address| bus.peek_u8(address);
+
+    // Without a history, e.g. right after loading, make a best guess at what comes
+    // before the PC. The guesses that could have decoded another way are marked.
+    if executed_spans.is_empty() {
+        for instruction in
+            disassemble_backward(read_u8, cpu.pc, executed_len, &cpu.history)
+        {
+            let (mut lines, _) = instruction_spans(
+                instruction.address,
+                read_u8,
+                address_to_label,
+                annotations,
+                false,
+            );
+            if let Some(spans) = lines.last_mut() {
+                let (marker, color) = match instruction.confidence {
+                    Confidence::Executed | Confidence::Likely => (" ", GRAY),
+                    Confidence::Ambiguous => ("?", Color::Yellow),
+                };
+                spans
+                    .0
+                    .insert(0, Span::styled(marker, Style::default().fg(color)));
+                spans.0[1].content = spans.0[1].content[1..].to_string().into();
             }
-        };
-
-        // label:
-        // ^^^^^^
-        //   $4027 clc
-        if let Some(pc_label) = address_to_label.get(&pc) {
-            let mut span =
-                Span::styled(format!("{}: ", pc_label), base_style.fg(MAGENTA));
-
-            // Is this selected?
-            if i == 0 {
-                // Remember this for in the list of executed instructions.
-                let mut dim_span = span.clone();
-                dim_span.style = base_style.fg(GRAY);
-                executed_instructions.push_front(Spans::from(dim_span));
-
-                // Bold the current label too.
-                span.style = span.style.clone().add_modifier(Modifier::BOLD);
+            for mut spans in lines {
+                for span in spans.0.iter_mut().skip(1) {
+                    span.style = Style::default().fg(DARK_GRAY);
+                }
+                executed_spans.push(spans);
             }
+        }
+    }
 
-            spans_list.push(Spans::from(span));
-        };
-
-        let instruction_pc = pc;
-
-        // label:
-        //   $4027 clc
-        //   ^^^^^
-        parts.push(Span::styled(
-            format!("  ${:02x} ", pc.clone()),
-            base_style.fg(CYAN),
-        ));
-
-        let operation = bus.read_u8(pc);
-        pc = pc.wrapping_add(1);
-
-        let opcode = OPCODE_STRING_TABLE[operation as usize];
-        let mode = ADDRESSING_MODE_TABLE[operation as usize];
-        parts.push(Span::styled(opcode, base_style.fg(Color::Yellow)));
-
-        // let get_u16 = || {
-        //     let value = bus.read_u8(pc);
-        //     pc += 1
-        // };
-        let mut get_u8 = || {
-            let value = bus.read_u8(pc);
-            pc += 1;
-            value
-        };
-        let mut add_operand = |string| {
-            parts.push(Span::styled(string, base_style.fg(Color::White)));
-        };
-
-        match mode {
-            Mode::Absolute
-            | Mode::AbsoluteIndexedX
-            | Mode::AbsoluteIndexedY
-            | Mode::Indirect => {
-                let a = bus.read_u8(pc);
-                let b = bus.read_u8(pc + 1);
-                pc += 2;
-                let value = u16::from_le_bytes([a, b]);
-
-                let mut address_style = base_style.fg(Color::White);
-
-                //   $4023 jmp section2 $4029
-                //             ^^^^^^^^
-                if let Some(label) = address_to_label.get(&value) {
-                    parts.push(Span::styled(
-                        format!(" {}", label),
-                        base_style.fg(MAGENTA),
-                    ));
-                    // Dim out the address.
-                    address_style = base_style.fg(GRAY);
-                };
+    // Labels can add extra lines, only keep the most recent ones.
+    let skip = executed_spans.len().saturating_sub(executed_len);
+    spans_list.extend(executed_spans.into_iter().skip(skip));
 
-                if mode == Mode::Indirect {
-                    //   $4023 jmp ($4029)
-                    //             ^
-                    parts.push(Span::styled("(", base_style.fg(Color::White)));
-                }
+    let mut pc = cpu.pc;
+    let mut is_current = true;
+    while spans_list.len() < height as usize {
+        let (lines, next_pc) =
+            instruction_spans(pc, read_u8, address_to_label, annotations, is_current);
+        spans_list.extend(lines);
+        pc = next_pc;
+        is_current = false;
+    }
+    spans_list.truncate(height as usize);
 
-                //   $4023 jmp section2 $4029
-                //                      ^^^^^
-                //   $4023 jmp $4029
-                //             ^^^^^
-                parts.push(Span::styled(format!(" ${:04x}\n", value), address_style));
-
-                // Handle indexed modes.
-                if mode == Mode::AbsoluteIndexedX {
-                    //   $4023 jmp $4029,X
-                    //                  ^^
-                    parts.push(Span::styled(",X", base_style.fg(Color::White)));
-                }
-                if mode == Mode::AbsoluteIndexedX {
-                    //   $4023 jmp $4029,Y
-                    //                  ^^
-                    parts.push(Span::styled(",Y", base_style.fg(Color::White)));
-                }
+    spans_list
+}
 
-                if mode == Mode::Indirect {
-                    //   $4023 jmp ($4029)
-                    //                   ^
-                    parts.push(Span::styled(")", base_style.fg(Color::White)));
-                }
-            }
+/// Disassemble forward from an interrupt vector's target, rather than the PC.
+fn get_vector_instructions_text(
+    cpu: &Cpu6502<Bus>,
+    target: u16,
+    height: u16,
+    address_to_label: &AddressToLabel,
+    annotations: &Annotations,
+) -> Vec<Spans<'static>> {
+    let mut spans_list: Vec<Spans> = vec![];
+    let bus = &cpu.bus;
+    let read_u8 = |address| bus.peek_u8(address);
+    let mut pc = target;
+    while spans_list.len() < height as usize {
+        let (lines, next_pc) =
+            instruction_spans(pc, read_u8, address_to_label, annotations, pc == cpu.pc);
+        spans_list.extend(lines);
+        pc = next_pc;
+    }
+    spans_list.truncate(height as usize);
 
-            // u8 operands:
-            Mode::Immediate => add_operand(format!(" #${:02x}\n", get_u8())),
-            Mode::ZeroPage => add_operand(format!(" ${:02x}\n", get_u8())),
-            Mode::ZeroPageX => add_operand(format!(" ${:02x},X\n", get_u8())),
-            Mode::ZeroPageY => add_operand(format!(" ${:02x},Y\n", get_u8())),
-            Mode::IndirectX => add_operand(format!(" (${:02x},X)\n", get_u8())),
-            Mode::IndirectY => add_operand(format!(" (${:02x}),Y\n", get_u8())),
-
-            Mode::Relative => {
-                let relative_value = get_u8() as i8;
-                let address: u16 = (instruction_pc as i32 + relative_value as i32) as u16;
-
-                match address_to_label.get(&address) {
-                    Some(label) => {
-                        parts.push(Span::styled(
-                            format!(" {}", label),
-                            base_style.fg(MAGENTA),
-                        ));
-                        // Dim out the address.
-                        parts.push(Span::styled(
-                            format!(" {:+}\n", relative_value),
-                            base_style.fg(GRAY),
-                        ))
-                    }
-                    None => add_operand(format!(" {:+}\n", get_u8() as i8)),
-                }
-            }
+    spans_list
+}
 
-            Mode::Implied | Mode::None | Mode::RegisterA => {}
-        }
+/// Disassemble a single instruction into lines of text, including its label. Returns
+/// the address of the next instruction.
+fn instruction_spans(
+    pc: u16,
+    read_u8: impl Fn(u16) -> u8,
+    address_to_label: &AddressToLabel,
+    annotations: &Annotations,
+    is_current: bool,
+) -> (Vec<Spans<'static>>, u16) {
+    let mut instruction = disassemble_instruction(read_u8, pc, Some(address_to_label));
+    annotations.annotate(&mut instruction);
+    let mut lines = vec![];
+    let mut parts = vec![];
 
-        if i == 0 {
-            let mut span_dimmed = parts.clone();
-            for span in span_dimmed.iter_mut() {
-                span.style = base_style.fg(GRAY);
-            }
-            // Remember this instruction for the next tick.
-            executed_instructions.push_front(Spans::from(span_dimmed));
+    let base_style = {
+        if is_current {
+            Style::default().add_modifier(Modifier::BOLD)
+        } else {
+            Style::default()
         }
+    };
+
+    // label:
+    // ^^^^^^
+    //   $4027 clc
+    if let Some(ref label) = instruction.label {
+        lines.push(Spans::from(Span::styled(
+            format!("{}: ", label),
+            base_style.fg(MAGENTA),
+        )));
+    };
+
+    // label:
+    //   $4027 clc
+    //   ^^^^^
+    parts.push(Span::styled(
+        format!("  ${:02x} ", instruction.address),
+        base_style.fg(CYAN),
+    ));
+    parts.push(Span::styled(
+        instruction.mnemonic,
+        base_style.fg(Color::Yellow),
+    ));
 
-        spans_list.push(Spans::from(parts));
+    let mut operand_style = base_style.fg(Color::White);
+    //   $4023 jmp section2 $4029
+    //             ^^^^^^^^
+    if let Some(ref label) = instruction.target_label {
+        parts.push(Span::styled(format!(" {}", label), base_style.fg(MAGENTA)));
+        // Dim out the address.
+        operand_style = base_style.fg(GRAY);
+    }
+    //   $4023 jmp section2 $4029
+    //                      ^^^^^
+    if !instruction.operand.is_empty() {
+        parts.push(Span::styled(
+            format!(" {}", instruction.operand),
+            operand_style,
+        ));
+    }
+    //   $4023 jmp section2 $4029 ; Wait for input
+    //                            ^^^^^^^^^^^^^^^^
+    if let Some(ref comment) = instruction.comment {
+        parts.push(Span::styled(
+            format!(" ; {}", comment),
+            base_style.fg(Color::DarkGray),
+        ));
     }
 
-    spans_list
+    lines.push(Spans::from(parts));
+    (lines, instruction.next_address())
 }
 
 fn get_ram_page_text(
-    cpu: &Cpu6502,
+    cpu: &Cpu6502<Bus>,
     page_u8: u8,
     width: u16,
     _height: u16,
 ) -> Vec<Spans<'static>> {
     let mut spans = vec![];
-    let bus = cpu.bus.borrow();
+    let bus = &cpu.bus;
     let style = Style::default();
     let cyan = style.fg(CYAN);
     let dim_white = style.fg(DIM_WHITE);
@@ -774,7 +1459,7 @@ fn get_ram_page_text(
         parts.push(Span::styled(format!("${:02x}{:x}_ ", page_u8, i), cyan));
         for j in 0..8 {
             let address = page_u16 + i * 16 + j * 2;
-            let [le, be] = bus.read_u16(address).to_le_bytes();
+            let (le, be) = (bus.peek_u8(address), bus.peek_u8(address + 1));
             // $0000 0011 2233 4455 6677 8899 aabb ccdd eeff
             //       ^^^^
             let color = if j % 2 == 0 {
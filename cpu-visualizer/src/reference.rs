@@ -0,0 +1,132 @@
+use cpu_6502::opcodes::{
+    flags_affected, match_instruction, Mode, ADDRESSING_MODE_TABLE, CYCLES_TABLE,
+    OPCODE_STRING_TABLE,
+};
+
+/// A single row in the instruction set quick-reference, built from the opcode tables.
+pub struct ReferenceRow {
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub mode: Mode,
+    pub cycles: u8,
+    pub flags: &'static str,
+    pub is_illegal: bool,
+}
+
+impl ReferenceRow {
+    /// e.g. "$bd lda  $nnnn,X     4  NZ"
+    pub fn to_text(&self) -> String {
+        format!(
+            "${:02x} {}{} {:<10} {:>2}  {}",
+            self.opcode,
+            self.mnemonic,
+            if self.is_illegal { "*" } else { " " },
+            mode_syntax(self.mode),
+            self.cycles,
+            self.flags
+        )
+    }
+}
+
+/// Show how the operand is written in asm for each addressing mode.
+pub fn mode_syntax(mode: Mode) -> &'static str {
+    match mode {
+        Mode::Absolute => "$nnnn",
+        Mode::AbsoluteIndexedX => "$nnnn,X",
+        Mode::AbsoluteIndexedY => "$nnnn,Y",
+        Mode::Immediate => "#$nn",
+        Mode::Implied | Mode::None => "",
+        Mode::Indirect => "($nnnn)",
+        Mode::IndirectX => "($nn,X)",
+        Mode::IndirectY => "($nn),Y",
+        Mode::Relative => "label",
+        Mode::RegisterA => "A",
+        Mode::ZeroPage => "$nn",
+        Mode::ZeroPageX => "$nn,X",
+        Mode::ZeroPageY => "$nn,Y",
+    }
+}
+
+/// Build a row for every opcode, sorted by mnemonic so that all of the addressing
+/// modes for an instruction are grouped together.
+pub fn build_reference() -> Vec<ReferenceRow> {
+    let mut rows: Vec<ReferenceRow> = (0..=255u8)
+        .map(|opcode| {
+            let mnemonic = OPCODE_STRING_TABLE[opcode as usize];
+            ReferenceRow {
+                opcode,
+                mnemonic,
+                mode: ADDRESSING_MODE_TABLE[opcode as usize],
+                cycles: CYCLES_TABLE[opcode as usize],
+                flags: flags_affected(mnemonic),
+                is_illegal: match_instruction(mnemonic).is_none(),
+            }
+        })
+        .collect();
+
+    // Legal instructions come first.
+    rows.sort_by_key(|row| (row.is_illegal, row.mnemonic, row.opcode));
+    rows
+}
+
+/// Filter the rows with a search query. Each whitespace separated term must match.
+/// A term matches part of the mnemonic, or when written as "flag:c" it matches the
+/// instructions that affect that status flag.
+pub fn filter_reference<'a>(
+    rows: &'a [ReferenceRow],
+    query: &str,
+) -> Vec<&'a ReferenceRow> {
+    let query = query.to_lowercase();
+    rows.iter()
+        .filter(|row| {
+            query
+                .split_whitespace()
+                .all(|term| match term.strip_prefix("flag:") {
+                    Some(flags) => flags
+                        .chars()
+                        .all(|flag| row.flags.to_lowercase().contains(flag)),
+                    None => row.mnemonic.contains(term),
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn filter_text(query: &str) -> Vec<String> {
+        filter_reference(&build_reference(), query)
+            .iter()
+            .map(|row| row.to_text())
+            .collect()
+    }
+
+    #[test]
+    fn test_filter_mnemonic() {
+        insta::assert_debug_snapshot!(filter_text("lda"), @r###"
+        [
+            "$a1 lda  ($nn,X)     6  NZ",
+            "$a5 lda  $nn         3  NZ",
+            "$a9 lda  #$nn        2  NZ",
+            "$ad lda  $nnnn       4  NZ",
+            "$b1 lda  ($nn),Y     5  NZ",
+            "$b5 lda  $nn,X       4  NZ",
+            "$b9 lda  $nnnn,Y     4  NZ",
+            "$bd lda  $nnnn,X     4  NZ",
+        ]
+        "###);
+    }
+
+    #[test]
+    fn test_filter_flags() {
+        insta::assert_debug_snapshot!(filter_text("flag:d"), @r###"
+        [
+            "$d8 cld              2  D",
+            "$28 plp              4  NVDIZC",
+            "$40 rti              6  NVDIZC",
+            "$f8 sed              2  D",
+        ]
+        "###);
+    }
+}
@@ -0,0 +1,12 @@
+//! Parse arbitrary bytes as an iNES file, and build the mapper it describes.
+
+#![no_main]
+
+use cpu_6502::rom::InesRom;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|bytes: &[u8]| {
+    if let Ok(rom) = InesRom::from_ines_bytes(bytes) {
+        let _ = rom.into_mapper();
+    }
+});
@@ -0,0 +1,25 @@
+//! Boot arbitrary bytes as an iNES file, and run a few frames of whatever program is
+//! in it. Random programs write to the mapper, PPU, and APU registers, and jump
+//! anywhere in memory. A save state is taken and loaded back at the end.
+
+#![no_main]
+
+use cpu_6502::emulator::Emulator;
+use cpu_6502::save_state::SaveState;
+use libfuzzer_sys::fuzz_target;
+
+/// Enough frames for the PPU to render, and for the mappers' IRQs to fire.
+const FRAMES: usize = 3;
+
+fuzz_target!(|bytes: &[u8]| {
+    if let Ok(mut emulator) = Emulator::from_ines_bytes(bytes) {
+        for _ in 0..FRAMES {
+            emulator.run_frame();
+        }
+        let state = emulator.cpu.save_state();
+        emulator
+            .cpu
+            .load_state(&state)
+            .expect("A save state can be loaded back.");
+    }
+});
@@ -0,0 +1,256 @@
+//! `.include "file.asm"` splits a program across files, and `.incbin "file.chr"` pulls
+//! in binary data. The includes are expanded into a single text before it's lexed,
+//! with the binary files as `.byte` lines, and each line of it remembers which file and
+//! row it came from, so that errors and warnings point at the right place.
+
+use std::path::{Path, PathBuf};
+
+pub struct SourceFile {
+    pub name: String,
+    pub text: String,
+}
+
+/// Finds an included file, from the path in the directive and the name of the file
+/// that it's in, and returns the file's name and contents.
+pub type ReadInclude<'a> =
+    dyn FnMut(&str, &str) -> Result<(String, Vec<u8>), String> + 'a;
+
+/// Where a line of the expanded text came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SourceRow {
+    file: usize,
+    row: u64,
+}
+
+/// A line in one of the source files, for showing the code that an address was
+/// assembled from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceLine {
+    /// The index into `Sources::files`. It's always 0 for a lexer without sources.
+    pub file: usize,
+    /// The 1-based line in the file.
+    pub line: u64,
+}
+
+pub struct Sources {
+    /// The entry file first, and then the files in the order they were included.
+    pub files: Vec<SourceFile>,
+    text: String,
+    rows: Vec<SourceRow>,
+}
+
+/// A line that pulls in another file.
+enum Include<'a> {
+    Asm(&'a str),
+    /// The path, and the offset and length of the bytes to take from it. Without a
+    /// length, the bytes run to the end of the file.
+    Binary(&'a str, usize, Option<usize>),
+}
+
+/// A number in an `.incbin`, e.g. 16, $10, or %10000.
+fn parse_number(text: &str) -> Result<usize, String> {
+    let number = if let Some(hex) = text.strip_prefix('$') {
+        usize::from_str_radix(hex, 16)
+    } else if let Some(binary) = text.strip_prefix('%') {
+        usize::from_str_radix(binary, 2)
+    } else {
+        text.parse()
+    };
+    number.map_err(|_| format!("Expected a number, but found \"{}\"", text))
+}
+
+/// The `.include` or `.incbin` on a line, or None for any other line.
+fn parse_include(line: &str) -> Option<Result<Include<'_>, String>> {
+    let rest = line.trim_start().strip_prefix('.')?;
+    let end = rest
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(rest.len());
+    let directive = rest[..end].to_ascii_lowercase();
+    if directive != "include" && directive != "incbin" {
+        return None;
+    }
+    Some(parse_include_arguments(&directive, &rest[end..]))
+}
+
+fn parse_include_arguments<'a>(
+    directive: &str,
+    text: &'a str,
+) -> Result<Include<'a>, String> {
+    let error = || format!("Expected a quoted file name after the .{}", directive);
+    let text = text.trim_start().strip_prefix('"').ok_or_else(error)?;
+    let end = text.find('"').ok_or_else(error)?;
+    let path = &text[..end];
+    let rest = &text[end + 1..];
+    let comment = [rest.find(';'), rest.find("//")]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+        .unwrap_or(rest.len());
+    // The rest is "" or e.g. ", $10, 16", which splits with an empty first argument.
+    let arguments: Vec<&str> = rest[..comment].split(',').map(str::trim).collect();
+    match (directive, arguments.as_slice()) {
+        ("include", [""]) => Ok(Include::Asm(path)),
+        ("include", _) => Err("Expected only a file name after the .include".into()),
+        (_, [""]) => Ok(Include::Binary(path, 0, None)),
+        (_, ["", offset]) => Ok(Include::Binary(path, parse_number(offset)?, None)),
+        (_, ["", offset, length]) => Ok(Include::Binary(
+            path,
+            parse_number(offset)?,
+            Some(parse_number(length)?),
+        )),
+        _ => Err(
+            "Expected a file name, and then an optional offset and length, e.g. \
+             .incbin \"tiles.chr\", $10, 16"
+                .into(),
+        ),
+    }
+}
+
+impl Sources {
+    /// Expand the includes in a text, with `read` finding the included files.
+    pub fn expand(
+        name: &str,
+        text: &str,
+        read: &mut ReadInclude,
+    ) -> Result<Sources, String> {
+        let mut sources = Sources {
+            files: Vec::new(),
+            text: String::new(),
+            rows: Vec::new(),
+        };
+        sources.add_file(name.to_string(), text.to_string(), read, &mut Vec::new())?;
+        Ok(sources)
+    }
+
+    /// Read the files from the filesystem. An include is looked for next to the file
+    /// that it's in first, and then in each of the include paths.
+    pub fn load(path: &Path, include_paths: &[PathBuf]) -> Result<Sources, String> {
+        let error = |path: &Path, err: std::io::Error| {
+            format!("Failed to read {}: {}", path.to_string_lossy(), err)
+        };
+        let text = std::fs::read_to_string(path).map_err(|err| error(path, err))?;
+        Sources::expand(&path.to_string_lossy(), &text, &mut |include, from| {
+            let directory = Path::new(from).parent().unwrap_or_else(|| Path::new(""));
+            let path = std::iter::once(directory)
+                .chain(include_paths.iter().map(PathBuf::as_path))
+                .map(|directory| directory.join(include))
+                .find(|path| path.is_file())
+                .ok_or_else(|| {
+                    format!("Unable to find the included file \"{}\"", include)
+                })?;
+            let bytes = std::fs::read(&path).map_err(|err| error(&path, err))?;
+            Ok((path.to_string_lossy().into_owned(), bytes))
+        })
+    }
+
+    fn push_line(&mut self, line: &str, row: SourceRow) {
+        self.text.push_str(line);
+        self.text.push('\n');
+        self.rows.push(row);
+    }
+
+    /// `including` is the chain of files that led to this one, to stop a file from
+    /// including itself.
+    fn add_file(
+        &mut self,
+        name: String,
+        text: String,
+        read: &mut ReadInclude,
+        including: &mut Vec<String>,
+    ) -> Result<(), String> {
+        let file = match self.files.iter().position(|file| file.name == name) {
+            Some(file) => file,
+            None => {
+                self.files.push(SourceFile {
+                    name: name.clone(),
+                    text: text.clone(),
+                });
+                self.files.len() - 1
+            }
+        };
+        including.push(name.clone());
+        for (index, line) in text.lines().enumerate() {
+            let row = index as u64 + 1;
+            let at = |err: String| format!("{}:{}: {}", name, row, err);
+            let include = match parse_include(line) {
+                Some(include) => include.map_err(at)?,
+                None => {
+                    self.push_line(line, SourceRow { file, row });
+                    continue;
+                }
+            };
+            let path = match include {
+                Include::Asm(path) | Include::Binary(path, ..) => path,
+            };
+            let (included_name, bytes) = read(path, &name).map_err(at)?;
+            match include {
+                Include::Asm(_) => {
+                    if including.contains(&included_name) {
+                        return Err(at(format!(
+                            "{} is already being included, so this would never end",
+                            included_name
+                        )));
+                    }
+                    let text = String::from_utf8(bytes).map_err(|_| {
+                        at(format!(
+                            "{} isn't text, binary files are included with .incbin",
+                            included_name
+                        ))
+                    })?;
+                    self.add_file(included_name, text, read, including)?;
+                }
+                Include::Binary(_, offset, length) => {
+                    let end = match length {
+                        Some(length) => offset.checked_add(length),
+                        None => Some(bytes.len()),
+                    };
+                    let bytes =
+                        end.and_then(|end| bytes.get(offset..end)).ok_or_else(|| {
+                            at(format!(
+                                "{} is only {} bytes long",
+                                included_name,
+                                bytes.len()
+                            ))
+                        })?;
+                    for chunk in bytes.chunks(16) {
+                        let values: Vec<String> =
+                            chunk.iter().map(|byte| format!("${:02x}", byte)).collect();
+                        self.push_line(
+                            &format!(".byte {}", values.join(", ")),
+                            SourceRow { file, row },
+                        );
+                    }
+                }
+            }
+        }
+        including.pop();
+        Ok(())
+    }
+
+    /// The program with the includes expanded, which is what the lexer reads.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The file that a row of the expanded text came from, and its row in that file.
+    /// Both rows are 1-based. The row after the end is past the end of the entry file.
+    pub fn locate(&self, row: u64) -> (&SourceFile, u64) {
+        let source_line = self.locate_line(row);
+        (&self.files[source_line.file], source_line.line)
+    }
+
+    /// Like `locate`, but with the index of the file.
+    pub fn locate_line(&self, row: u64) -> SourceLine {
+        match self.rows.get(row.saturating_sub(1) as usize) {
+            Some(source_row) => SourceLine {
+                file: source_row.file,
+                line: source_row.row,
+            },
+            None => SourceLine {
+                file: 0,
+                line: self.files[0].text.lines().count() as u64 + 1,
+            },
+        }
+    }
+}
@@ -0,0 +1,2397 @@
+//! A 6502 assembler that turns asm text into bytes. It only depends on the opcode
+//! definitions from mos6502-core, so it can be used without an emulator.
+
+// Clippy rules to disable.
+#![allow(clippy::new_without_default)]
+
+use colored::*;
+use mos6502_core::opcodes::{
+    instruction_mode_to_op_code, match_instruction, Instruction, Mode, TokenMode,
+    ADDRESSING_MODE_TABLE, CYCLES_TABLE, EXTRA_CYCLES_TABLE,
+};
+use std::{collections::HashMap, str::Chars};
+
+mod include;
+mod lint;
+mod optimize;
+pub use include::*;
+pub use lint::*;
+pub use optimize::*;
+
+/// The address that the assembled code is placed at, until a `.org` moves it. This is
+/// the start of the PRG ROM in the NES memory map.
+pub const ORIGIN: u16 = 0x8000;
+
+/// Directives from ca65 and asm6 that only matter to their linkers or listings. They're
+/// skipped with a warning, so that tutorial code assembles with fewer edits.
+const IGNORED_DIRECTIVES: [&str; 16] = [
+    "autoimport",
+    "debuginfo",
+    "export",
+    "exportzp",
+    "feature",
+    "fopt",
+    "global",
+    "globalzp",
+    "ident",
+    "import",
+    "importzp",
+    "list",
+    "listbytes",
+    "p02",
+    "setcpu",
+    "smart",
+];
+
+/// The ca65 segments that hold code or data, which are assembled in the order they
+/// appear. Other segments, like the iNES header or the vectors, are handled by the asm
+/// binary instead.
+const CODE_SEGMENTS: [&str; 3] = ["CODE", "RODATA", "STARTUP"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Instruction(Instruction),
+    Mode(TokenMode),
+    U8(u8),
+    U16(u16),
+    LabelDefinition(StringIndex),
+    LabelOperand(StringIndex),
+    /// A label's address as data, from `.word`.
+    LabelWord(StringIndex),
+    /// The address that the code after a `.org` is placed at.
+    Origin(u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Character {
+    Whitespace,
+    Newline,
+    Alpha,
+    Numeric,
+    NewLine,
+    Value(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum U8OrU16 {
+    U8(u8),
+    U16(u16),
+}
+
+impl std::fmt::Display for U8OrU16 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            U8OrU16::U8(value) => write!(f, "${:02x}", value),
+            U8OrU16::U16(value) => write!(f, "${:04x}", value),
+        }
+    }
+}
+
+pub type StringIndex = usize;
+pub type ByteOffset = usize;
+
+pub enum LabelMappingType {
+    Absolute,
+    Relative,
+}
+
+/// This struct is a string table that will hold a unique reference to a string.
+/// This makes it easy to use simple numeric indexes to refer to a string rather
+/// that duplicating a string and worrying about ownership. There
+/// is no duplication of strings.
+///
+/// It provides a mechanism for labeling the byte address of the label. Constants share
+/// the table, but have a value rather than an address, and don't show up as labels.
+pub struct LabelTable {
+    strings: Vec<String>,
+    /// The address of each label, or None for the labels that are only used as an
+    /// operand, and never defined.
+    addresses: Option<Vec<Option<ByteOffset>>>,
+    pub addresses_to_label: Vec<(StringIndex, ByteOffset, LabelMappingType)>,
+    constants: HashMap<StringIndex, U8OrU16>,
+}
+
+impl LabelTable {
+    pub fn new() -> LabelTable {
+        LabelTable {
+            strings: Vec::new(),
+            addresses: None,
+            addresses_to_label: Vec::new(),
+            constants: HashMap::new(),
+        }
+    }
+
+    /// A constant can be defined again, but only with the same value.
+    pub fn define_constant(
+        &mut self,
+        name: String,
+        value: U8OrU16,
+    ) -> Result<(), String> {
+        let index = self.take_string(name);
+        match self.constants.insert(index, value) {
+            Some(previous) if previous != value => Err(format!(
+                "The constant \"{}\" was already defined as {}",
+                self.strings[index], previous
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn constant(&self, name: &str) -> Option<U8OrU16> {
+        self.find(name)
+            .and_then(|index| self.constants.get(&index))
+            .copied()
+    }
+
+    pub fn is_constant(&self, index: StringIndex) -> bool {
+        self.constants.contains_key(&index)
+    }
+
+    pub fn take_string(&mut self, string: String) -> StringIndex {
+        match self.strings.iter().position(|s| *s == string) {
+            Some(index) => index,
+            None => {
+                let index = self.strings.len();
+                self.strings.push(string);
+                index
+            }
+        }
+    }
+
+    pub fn index(&mut self, string: &str) -> StringIndex {
+        match self.strings.iter().position(|s| s == string) {
+            Some(index) => index,
+            None => {
+                let index = self.strings.len();
+                self.strings.push(string.to_string());
+                index
+            }
+        }
+    }
+
+    pub fn string(&self, index: StringIndex) -> Option<&String> {
+        self.strings.get(index)
+    }
+
+    /// Look up a string without adding it to the table.
+    pub fn find(&self, string: &str) -> Option<StringIndex> {
+        self.strings.iter().position(|s| s == string)
+    }
+
+    pub fn set_address(&mut self, address: usize, index: StringIndex) {
+        match &self.addresses {
+            Some(addresses) => {
+                debug_assert_eq!(
+                    addresses.len(),
+                    self.strings.len(),
+                    "Expected the StringTable to not changes size with computing addresses"
+                );
+            }
+            None => {
+                let addresses = vec![None; self.strings.len()];
+                self.addresses = Some(addresses);
+            }
+        };
+        match self.addresses {
+            Some(ref mut addresses) => addresses[index] = Some(address),
+            None => panic!("self.addresses not found"),
+        }
+    }
+
+    pub fn get_address(&self, index: StringIndex) -> Result<usize, String> {
+        match self
+            .addresses
+            .as_ref()
+            .and_then(|addresses| addresses.get(index))
+        {
+            Some(Some(address)) => Ok(*address),
+            _ if self.is_constant(index) => Err(format!(
+                "The constant \"{}\" needs to be defined before it's used",
+                self.strings[index]
+            )),
+            _ => Err(format!(
+                "Unable to find the address for the label {}",
+                self.strings.get(index).unwrap()
+            )),
+        }
+    }
+}
+
+fn char_to_enum(character: &char) -> Character {
+    if character.is_numeric() {
+        return Character::Numeric;
+    }
+    if character.is_alphabetic() {
+        return Character::Alpha;
+    }
+    if character.is_whitespace() {
+        return Character::Whitespace;
+    }
+    Character::Value(*character)
+}
+
+// This is convenience sugar over peeking and matching an enum.
+macro_rules! iter_peek_match {
+    ($iterator:expr, $item:ident => $match_body:tt) => {
+        loop {
+            match $iterator.peek() {
+                Some(&$item) => {
+                    match char_to_enum(&$item) $match_body;
+                },
+                None => break,
+            }
+        };
+    }
+}
+
+type TokenizerResult = Result<(), String>;
+
+#[derive(Debug)]
+pub struct ParseError {
+    message: String,
+    nice_message: String,
+    column: u64,
+    row: u64,
+    file: Option<String>,
+}
+
+impl ParseError {
+    fn new(message: String, parser: &AsmLexer) -> ParseError {
+        let (file, _, row) = parser.locate(parser.row);
+        ParseError {
+            nice_message: parser.annotate(
+                parser.row,
+                parser.column,
+                "parse error",
+                &message,
+                Color::BrightRed,
+            ),
+            message,
+            column: parser.column,
+            row,
+            file: file.map(String::from),
+        }
+    }
+
+    pub fn panic_nicely(self) {
+        panic!("{}", self.nice_message);
+    }
+
+    /// The message with the surrounding source, for printing in a terminal.
+    pub fn nice_message(&self) -> &str {
+        &self.nice_message
+    }
+
+    /// The plain message, without the surrounding source, for showing inline in an
+    /// editor.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The 1-based row of the error, in the file that it's in.
+    pub fn row(&self) -> u64 {
+        self.row
+    }
+
+    /// The name of the file with the error, when the program came from `Sources`.
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn column(&self) -> u64 {
+        self.column
+    }
+}
+
+/// Print the lines around a row of the source, and point at the column with the
+/// message.
+fn annotate_source(
+    text: &str,
+    row: u64,
+    column: u64,
+    heading: &str,
+    message: &str,
+    color: Color,
+) -> String {
+    let annotated_row_index = row as usize - 1;
+    let range = 3;
+    let min = (annotated_row_index as i64 - range).max(0) as usize;
+    let max = (annotated_row_index as i64 + range) as usize;
+
+    let mut nice_message = String::from("\n\n");
+    for (row_index, row_text) in text.lines().enumerate() {
+        if row_index > max {
+            break;
+        }
+        if row_index < min {
+            continue;
+        }
+
+        // Lazypad.
+        let col_string = if row_index < 9 {
+            format!("   {}: ", row_index + 1)
+        } else if row_index < 99 {
+            format!("  {}: ", row_index + 1)
+        } else if row_index < 999 {
+            format!(" {}: ", row_index + 1)
+        } else {
+            format!("{}: ", row_index + 1)
+        };
+        nice_message.push_str(&format!("{}", &col_string.cyan()));
+
+        nice_message.push_str(&format!("{}", &row_text.bright_white()));
+        nice_message.push('\n');
+
+        if row_index == annotated_row_index {
+            let indent = " ".repeat((column + 5) as usize);
+            let heading = &format!("^ {} on row {} column {} ", heading, row, column);
+            nice_message.push_str(&indent);
+            nice_message.push_str(&format!("{}", heading.color(color)));
+            nice_message.push('\n');
+            nice_message.push_str(&indent);
+            nice_message.push_str(&format!("{}", message.color(color)));
+            nice_message.push('\n');
+        }
+    }
+
+    nice_message.push('\n');
+    nice_message
+}
+
+/// How a span of asm text is colored by an editor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Highlight {
+    Instruction,
+    Label,
+    Directive,
+    Number,
+    Comment,
+    Plain,
+}
+
+/// Split a single line of asm into highlighted spans, using the same rules as the
+/// lexer for what counts as an instruction, a label, or a number. This works on
+/// partial or invalid lines, as it's used while the text is being edited.
+pub fn highlight_line(line: &str) -> Vec<(Highlight, &str)> {
+    // Spans are built as (highlight, start, end) byte ranges into the line.
+    let mut spans: Vec<(Highlight, usize, usize)> = vec![];
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    let mut has_instruction = false;
+    let mut index = 0;
+
+    while let Some(character) = line[index..].chars().next() {
+        let rest = &line[index..];
+        let (highlight, length) = if character == ';' || rest.starts_with("//") {
+            (Highlight::Comment, rest.len())
+        } else if matches!(character, '.' | '$' | '%' | '#') || character.is_ascii_digit()
+        {
+            let length = 1 + rest[1..]
+                .find(|c| !is_word_char(c))
+                .unwrap_or(rest.len() - 1);
+            if character == '.' {
+                (Highlight::Directive, length)
+            } else {
+                (Highlight::Number, length)
+            }
+        } else if is_word_char(character) {
+            let length = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+            if rest[length..].starts_with(':') {
+                (Highlight::Label, length + 1)
+            } else if !has_instruction && match_instruction(&rest[..length]).is_some() {
+                has_instruction = true;
+                (Highlight::Instruction, length)
+            } else {
+                (Highlight::Plain, length)
+            }
+        } else {
+            (Highlight::Plain, character.len_utf8())
+        };
+
+        match spans.last_mut() {
+            // Merge the plain text together, e.g. "),y " is a single span.
+            Some((Highlight::Plain, _, end)) if highlight == Highlight::Plain => {
+                *end += length;
+            }
+            _ => spans.push((highlight, index, index + length)),
+        }
+        index += length;
+    }
+
+    spans
+        .into_iter()
+        .map(|(highlight, start, end)| (highlight, &line[start..end]))
+        .collect()
+}
+
+pub type AddressToLabel = HashMap<u16, String>;
+
+/// The line that each instruction was assembled from, by the address of its opcode.
+pub type AddressToSourceLine = HashMap<u16, SourceLine>;
+
+pub struct BytesLabels {
+    pub bytes: Vec<u8>,
+    pub address_to_label: AddressToLabel,
+    pub address_to_source_line: AddressToSourceLine,
+}
+
+/// The bytes between one `.org` and the next.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+}
+
+pub struct SegmentsLabels {
+    /// The segments in the order they're in the file, without the empty ones.
+    pub segments: Vec<Segment>,
+    pub address_to_label: AddressToLabel,
+    pub address_to_source_line: AddressToSourceLine,
+}
+
+/// The address of a byte, from the segment that it's in. The segments are the offset
+/// into the bytes that each one starts at, and its origin.
+fn segment_address(segment_starts: &[(ByteOffset, u16)], offset: ByteOffset) -> usize {
+    match segment_starts
+        .iter()
+        .rev()
+        .find(|(start, _)| *start <= offset)
+    {
+        Some((start, origin)) => *origin as usize + offset - start,
+        None => ORIGIN as usize + offset,
+    }
+}
+
+/// A region between `.assert_cycles` and `.endassert`, which has to run within its
+/// budget. Raster effects have a fixed number of cycles before the PPU moves on.
+#[derive(Debug, Clone)]
+struct CycleBudget {
+    /// The label before the pragma, for the error message.
+    label: String,
+    budget: u64,
+    /// Count the extra cycles for crossing a page, instead of assuming that nothing
+    /// does.
+    is_pessimistic: bool,
+    start_row: u64,
+    end_row: Option<u64>,
+}
+
+pub struct AsmLexer<'a> {
+    text: &'a str,
+    lines: std::str::Lines<'a>,
+    characters: std::iter::Peekable<Chars<'a>>,
+    tokens: Vec<Token>,
+    /// Where each Token::Instruction starts in the text, in the same order.
+    instruction_spans: Vec<Span>,
+    /// The labels, and the constants from `NAME = value` lines, which are substituted
+    /// into operands. The constants need to be defined before they're used.
+    labels: LabelTable,
+    /// The names of the enclosing .proc directives, outermost first. Labels defined in
+    /// a .proc are prefixed with them, e.g. "main::loop".
+    scopes: Vec<String>,
+    /// The label operands that were used inside a .proc, with its prefix. They're
+    /// resolved once the whole file is parsed, as the label can come later.
+    scoped_operands: Vec<(usize, String)>,
+    /// The directives from other assemblers that were skipped.
+    warnings: Vec<AsmWarning>,
+    cycle_budgets: Vec<CycleBudget>,
+    /// Where each instruction's opcode ended up in the bytes, in the same order as
+    /// the instruction_spans.
+    opcode_offsets: Vec<usize>,
+    /// Where each `.org` segment starts in the bytes, and its origin.
+    segment_starts: Vec<(ByteOffset, u16)>,
+    /// The files that the text was expanded from, when it has includes.
+    sources: Option<&'a Sources>,
+    row: u64,
+    column: u64,
+}
+
+impl<'a> AsmLexer<'a> {
+    pub fn new(text: &'a str) -> AsmLexer {
+        AsmLexer {
+            text,
+            characters: IntoIterator::into_iter("".chars()).peekable(),
+            lines: IntoIterator::into_iter(text.lines()),
+            tokens: Vec::new(),
+            instruction_spans: Vec::new(),
+            labels: LabelTable::new(),
+            scopes: Vec::new(),
+            scoped_operands: Vec::new(),
+            warnings: Vec::new(),
+            cycle_budgets: Vec::new(),
+            opcode_offsets: Vec::new(),
+            segment_starts: Vec::new(),
+            sources: None,
+            column: 0,
+            row: 1,
+        }
+    }
+
+    /// Lex a program that was split across files with `.include`.
+    pub fn with_sources(sources: &'a Sources) -> AsmLexer<'a> {
+        let mut lexer = AsmLexer::new(sources.text());
+        lexer.sources = Some(sources);
+        lexer
+    }
+
+    /// The file that a row came from, with its text and the row in it.
+    fn locate(&self, row: u64) -> (Option<&'a str>, &'a str, u64) {
+        match self.sources {
+            Some(sources) => {
+                let (file, row) = sources.locate(row);
+                (Some(&file.name), &file.text, row)
+            }
+            None => (None, self.text, row),
+        }
+    }
+
+    /// Point at a row and column, in the file that the row came from.
+    fn annotate(
+        &self,
+        row: u64,
+        column: u64,
+        heading: &str,
+        message: &str,
+        color: Color,
+    ) -> String {
+        let (file, text, row) = self.locate(row);
+        let heading = match file {
+            Some(file) => format!("{} in {}", heading, file),
+            None => heading.to_string(),
+        };
+        annotate_source(text, row, column, &heading, message, color)
+    }
+
+    fn next_character(&mut self) -> Option<char> {
+        let character = self.characters.next();
+        if character.is_some() {
+            self.column += 1;
+        }
+        character
+    }
+
+    /// Run the lexer by parsing the characters into tokens. Things like labels
+    /// will be computed later.
+    pub fn parse(&mut self) -> Result<(), ParseError> {
+        loop {
+            match self.lines.next() {
+                Some(line) => {
+                    self.characters = IntoIterator::into_iter(line.chars()).peekable();
+
+                    if let Err(message) = self.parse_root_level() {
+                        return Err(ParseError::new(message, self));
+                    }
+                }
+                None => {
+                    if self.open_cycle_budget().is_some() {
+                        return Err(ParseError::new(
+                            "Found a .assert_cycles without a .endassert".into(),
+                            self,
+                        ));
+                    }
+                    self.resolve_scoped_operands();
+                    return Ok(());
+                }
+            };
+            self.row += 1;
+            self.column = 0;
+        }
+    }
+
+    /// Define a constant before parsing, e.g. from a project's settings, as if the
+    /// source started with `NAME = value`.
+    pub fn define(&mut self, name: &str, value: U8OrU16) -> Result<(), String> {
+        self.labels.define_constant(name.to_string(), value)
+    }
+
+    /// Warnings from parsing, for the directives from other assemblers that were
+    /// skipped. The lint has its own warnings.
+    pub fn warnings(&self) -> &[AsmWarning] {
+        &self.warnings
+    }
+
+    fn warn(&mut self, message: String, column: u64) {
+        let span = Span {
+            row: self.row,
+            column,
+        };
+        let warning = AsmWarning::new(message, span, self);
+        self.warnings.push(warning);
+    }
+
+    /// The name of a label defined at this point, including the .proc scopes.
+    fn scoped_name(&self, name: String) -> String {
+        if self.scopes.is_empty() {
+            name
+        } else {
+            format!("{}::{}", self.scopes.join("::"), name)
+        }
+    }
+
+    /// Point the label operands inside a .proc at the innermost label with the same
+    /// name. Labels that aren't defined in any of the scopes are left as global ones.
+    fn resolve_scoped_operands(&mut self) {
+        let defined: Vec<StringIndex> = self
+            .tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::LabelDefinition(index) => Some(*index),
+                _ => None,
+            })
+            .collect();
+        for (token_index, scope) in std::mem::take(&mut self.scoped_operands) {
+            let name = match self.tokens[token_index] {
+                Token::LabelOperand(index) | Token::LabelWord(index) => {
+                    match self.labels.string(index) {
+                        Some(name) => name.clone(),
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+            let mut prefix = Some(scope.as_str());
+            while let Some(scope) = prefix {
+                let scoped = self.labels.find(&format!("{}::{}", scope, name));
+                if let Some(index) = scoped.filter(|index| defined.contains(index)) {
+                    self.tokens[token_index] = match self.tokens[token_index] {
+                        Token::LabelWord(_) => Token::LabelWord(index),
+                        _ => Token::LabelOperand(index),
+                    };
+                    break;
+                }
+                prefix = scope.rfind("::").map(|end| &scope[..end]);
+            }
+        }
+    }
+
+    /// Look ahead for a `NAME = value` or `NAME equ value` constant, without consuming
+    /// anything.
+    fn is_constant_assignment(&self) -> bool {
+        let mut characters = self.characters.clone();
+        let mut word = String::new();
+        loop {
+            match characters.next() {
+                Some(character) if character.is_whitespace() && word.is_empty() => {
+                    continue
+                }
+                Some('=') if word.is_empty() => return true,
+                Some(character) if character.is_alphabetic() || character == '.' => {
+                    word.push(character)
+                }
+                Some(character) if character.is_whitespace() => break,
+                _ => return false,
+            }
+        }
+        word.eq_ignore_ascii_case("equ") || word.eq_ignore_ascii_case(".equ")
+    }
+
+    fn constant(&self, name: &str) -> Result<U8OrU16, String> {
+        self.labels
+            .constant(name)
+            .ok_or_else(|| format!("Unknown constant \"{}\"", name))
+    }
+
+    /// Parse the value of a constant, after its name and the `=`.
+    fn define_constant(&mut self, name: String) -> TokenizerResult {
+        self.skip_whitespace();
+        let value = self.next_characters_u8_or_u16()?;
+        let is_label = self
+            .labels
+            .find(&name)
+            .is_some_and(|index| self.tokens.contains(&Token::LabelDefinition(index)));
+        if is_label {
+            return Err(format!("\"{}\" is already a label", name));
+        }
+        self.labels.define_constant(name, value)?;
+        self.continue_to_end_of_line()
+    }
+
+    fn parse_root_level(&mut self) -> Result<(), String> {
+        loop {
+            match self.next_character() {
+                Some(character) => match char_to_enum(&character) {
+                    Character::Whitespace => {}
+                    Character::Value(';') => {
+                        return self.ignore_comment_contents();
+                    }
+                    Character::Value('/') => {
+                        self.expect_next_character_ignore_casing('/')?;
+                        return self.ignore_comment_contents();
+                    }
+                    Character::Alpha => {
+                        let word = self.get_word(Some(&character))?;
+                        match match_instruction(&word) {
+                            Some(instruction) => {
+                                self.instruction_spans.push(Span {
+                                    row: self.row,
+                                    column: self.column + 1 - word.len() as u64,
+                                });
+                                self.tokens.push(Token::Instruction(instruction.clone()));
+                                self.parse_operand(instruction)?;
+                            }
+                            None if self.is_constant_assignment() => {
+                                // PPUCTRL = $2000
+                                // PPUCTRL equ $2000
+                                self.skip_whitespace();
+                                if self.peek_is_next_character('=') {
+                                    self.next_character();
+                                } else {
+                                    if self.peek_is_next_character('.') {
+                                        self.next_character();
+                                    }
+                                    self.get_word(None)?;
+                                }
+                                return self.define_constant(word);
+                            }
+                            None if word.eq_ignore_ascii_case("define")
+                                && self
+                                    .characters
+                                    .peek()
+                                    .is_some_and(|c| c.is_whitespace()) =>
+                            {
+                                // define PPUCTRL $2000
+                                self.skip_whitespace();
+                                let name = self.get_word(None)?;
+                                return self.define_constant(name);
+                            }
+                            None => {
+                                self.expect_next_character_ignore_casing(':')?;
+                                if self.labels.constant(&word).is_some() {
+                                    return Err(format!(
+                                        "\"{}\" is already a constant",
+                                        word
+                                    ));
+                                }
+                                let name = self.scoped_name(word);
+                                let label =
+                                    Token::LabelDefinition(self.labels.take_string(name));
+                                self.tokens.push(label);
+                            }
+                        }
+                    }
+                    Character::Value('.') => {
+                        match self.get_word(None)?.to_ascii_lowercase().as_ref() {
+                            // .db and .dw are from asm6.
+                            "byte" | "db" => loop {
+                                self.skip_whitespace();
+                                let value = self.next_characters_u8()?;
+                                self.tokens.push(Token::U8(value));
+                                if !self.find_comma()? {
+                                    // No comma was found, and we skipped to the end of the line.
+                                    break;
+                                }
+                            },
+                            "word" | "dw" => loop {
+                                self.skip_whitespace();
+                                let is_name = self
+                                    .characters
+                                    .peek()
+                                    .is_some_and(|c| c.is_alphabetic() || *c == '_');
+                                if is_name {
+                                    // A label, e.g. for the interrupt vectors.
+                                    let word = self.get_word(None)?;
+                                    match self.labels.constant(&word) {
+                                        Some(U8OrU16::U8(value)) => {
+                                            self.tokens.push(Token::U16(value as u16))
+                                        }
+                                        Some(U8OrU16::U16(value)) => {
+                                            self.tokens.push(Token::U16(value))
+                                        }
+                                        None => {
+                                            let index = self.labels.take_string(word);
+                                            self.tokens.push(Token::LabelWord(index));
+                                            if !self.scopes.is_empty() {
+                                                let scope = self.scopes.join("::");
+                                                self.scoped_operands
+                                                    .push((self.tokens.len() - 1, scope));
+                                            }
+                                        }
+                                    }
+                                } else {
+                                    let value = self.next_characters_u16()?;
+                                    self.tokens.push(Token::U16(value));
+                                }
+                                if !self.find_comma()? {
+                                    // No comma was found, and we skipped to the end of the line.
+                                    break;
+                                }
+                            },
+                            "proc" => {
+                                // The .proc is a label, and the labels inside it are local
+                                // to it.
+                                self.skip_whitespace();
+                                let name = self.get_word(None)?;
+                                let label = self.scoped_name(name.clone());
+                                let label = Token::LabelDefinition(
+                                    self.labels.take_string(label),
+                                );
+                                self.tokens.push(label);
+                                self.scopes.push(name);
+                                return self.continue_to_end_of_line();
+                            }
+                            "define" => {
+                                // .define PPUCTRL $2000
+                                self.skip_whitespace();
+                                let name = self.get_word(None)?;
+                                return self.define_constant(name);
+                            }
+                            "endproc" => {
+                                if self.scopes.pop().is_none() {
+                                    return Err("Found a .endproc without a .proc".into());
+                                }
+                                return self.continue_to_end_of_line();
+                            }
+                            "org" => {
+                                // .org $c000
+                                self.skip_whitespace();
+                                let origin = self.next_characters_u16()?;
+                                self.tokens.push(Token::Origin(origin));
+                                return self.continue_to_end_of_line();
+                            }
+                            "assert_cycles" => return self.parse_assert_cycles(),
+                            "endassert" => {
+                                let row = self.row;
+                                match self.open_cycle_budget() {
+                                    Some(budget) => budget.end_row = Some(row),
+                                    None => {
+                                        return Err(
+                                            "Found a .endassert without a .assert_cycles"
+                                                .into(),
+                                        )
+                                    }
+                                }
+                                return self.continue_to_end_of_line();
+                            }
+                            "segment" => {
+                                let column = self.column - "segment".len() as u64;
+                                self.skip_whitespace();
+                                self.expect_next_character_ignore_casing('"')?;
+                                let segment = self.get_word(None)?;
+                                self.expect_next_character_ignore_casing('"')?;
+                                if !CODE_SEGMENTS.contains(&segment.as_str()) {
+                                    self.warn(
+                                        format!(
+                                        "The \"{}\" segment is assembled in place with \
+                                         the code, as segments aren't supported.",
+                                        segment
+                                    ),
+                                        column,
+                                    );
+                                }
+                                return self.continue_to_end_of_line();
+                            }
+                            directive if IGNORED_DIRECTIVES.contains(&directive) => {
+                                self.warn(
+                                    format!("The .{} directive was ignored.", directive),
+                                    self.column - directive.len() as u64,
+                                );
+                                return self.skip_directive_arguments();
+                            }
+                            directive @ ("include" | "incbin") => {
+                                return Err(format!(
+                                    "An .{} can only be assembled from a file, where \
+                                     there are other files to include",
+                                    directive
+                                ))
+                            }
+                            pragma => {
+                                return Err(format!("Unknown pragma \".{}\"", pragma))
+                            }
+                        }
+                    }
+                    _ => return Err(format!("Unknown next token. {}", character)),
+                },
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// .assert_cycles 30
+    /// .assert_cycles 30, pessimistic
+    fn parse_assert_cycles(&mut self) -> TokenizerResult {
+        if self.open_cycle_budget().is_some() {
+            return Err("The .assert_cycles regions can't be nested.".into());
+        }
+        let label = self
+            .tokens
+            .iter()
+            .rev()
+            .find_map(|token| match token {
+                Token::LabelDefinition(index) => self.labels.string(*index).cloned(),
+                _ => None,
+            })
+            .ok_or("A .assert_cycles needs a label before it to name the region.")?;
+
+        self.skip_whitespace();
+        let number = self.get_word(None)?;
+        let budget = number
+            .parse::<u64>()
+            .map_err(|_| format!("Unable to parse the cycle budget \"{}\"", number))?;
+        let is_pessimistic = if self.find_comma()? {
+            self.skip_whitespace();
+            match self.get_word(None)?.as_ref() {
+                "pessimistic" => true,
+                flag => return Err(format!("Unknown .assert_cycles flag \"{}\"", flag)),
+            }
+        } else {
+            false
+        };
+
+        self.cycle_budgets.push(CycleBudget {
+            label,
+            budget,
+            is_pessimistic,
+            start_row: self.row,
+            end_row: None,
+        });
+        self.continue_to_end_of_line()
+    }
+
+    fn open_cycle_budget(&mut self) -> Option<&mut CycleBudget> {
+        self.cycle_budgets
+            .last_mut()
+            .filter(|budget| budget.end_row.is_none())
+    }
+
+    /// Add up the worst case of every instruction in each .assert_cycles region, as if
+    /// every branch is taken. The loops aren't followed, so each instruction counts
+    /// once.
+    fn check_cycle_budgets(&self, bytes: &[u8]) -> Result<(), String> {
+        for budget in &self.cycle_budgets {
+            let end_row = budget.end_row.expect("The regions are closed by parse.");
+            let cycles: u64 = self
+                .instruction_spans
+                .iter()
+                .zip(&self.opcode_offsets)
+                .filter(|(span, _)| budget.start_row < span.row && span.row < end_row)
+                .map(|(_, offset)| {
+                    let opcode = bytes[*offset] as usize;
+                    let mut cycles = CYCLES_TABLE[opcode] as u64;
+                    if ADDRESSING_MODE_TABLE[opcode] == Mode::Relative {
+                        cycles += 1;
+                    }
+                    if budget.is_pessimistic {
+                        cycles += EXTRA_CYCLES_TABLE[opcode] as u64;
+                    }
+                    cycles
+                })
+                .sum();
+            if cycles > budget.budget {
+                return Err(format!(
+                    "The code in \"{}\" at row {} takes up to {} cycles {}, which is \
+                     over its budget of {}.",
+                    budget.label,
+                    budget.start_row,
+                    cycles,
+                    if budget.is_pessimistic {
+                        "with page crossings"
+                    } else {
+                        "without page crossings"
+                    },
+                    budget.budget
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Assemble the program into one run of bytes that starts at ORIGIN, with any gaps
+    /// between the `.org` segments filled with zeros.
+    pub fn into_bytes(self) -> Result<BytesLabels, String> {
+        let SegmentsLabels {
+            mut segments,
+            address_to_label,
+            address_to_source_line,
+        } = self.into_segments()?;
+        segments.sort_by_key(|segment| segment.origin);
+        let mut bytes = Vec::new();
+        for segment in segments {
+            if segment.origin < ORIGIN {
+                return Err(format!(
+                    "The .org ${:04x} is before ${:04x}, where the bytes start.",
+                    segment.origin, ORIGIN
+                ));
+            }
+            // The segments don't overlap, so this only fills the gap.
+            bytes.resize((segment.origin - ORIGIN) as usize, 0);
+            bytes.extend_from_slice(&segment.bytes);
+        }
+        Ok(BytesLabels {
+            bytes,
+            address_to_label,
+            address_to_source_line,
+        })
+    }
+
+    /// Assemble the program into the segments started by each `.org`. The code before
+    /// the first one is placed at ORIGIN.
+    pub fn into_segments(mut self) -> Result<SegmentsLabels, String> {
+        let mut bytes = self.as_bytes_before_labels()?;
+        self.check_cycle_budgets(&bytes)?;
+        let address_to_source_line: AddressToSourceLine = self
+            .instruction_spans
+            .iter()
+            .zip(&self.opcode_offsets)
+            .map(|(span, offset)| {
+                let address = segment_address(&self.segment_starts, *offset) as u16;
+                let source_line = match self.sources {
+                    Some(sources) => sources.locate_line(span.row),
+                    None => SourceLine {
+                        file: 0,
+                        line: span.row,
+                    },
+                };
+                (address, source_line)
+            })
+            .collect();
+
+        // Consume self to move the data we still care about, at the end, the rest
+        // of the data will be dropped.
+        let AsmLexer {
+            mut labels,
+            segment_starts,
+            ..
+        } = self;
+
+        // The start and end of each segment in the bytes, and its origin.
+        let bounds: Vec<(ByteOffset, ByteOffset, u16)> = segment_starts
+            .iter()
+            .enumerate()
+            .map(
+                |(index, (start, origin))| match segment_starts.get(index + 1) {
+                    Some((next_start, _)) => (*start, *next_start, *origin),
+                    None => (*start, bytes.len(), *origin),
+                },
+            )
+            .filter(|(start, end, _)| start < end)
+            .collect();
+        let mut ranges: Vec<(usize, usize)> = vec![];
+        for (start, end, origin) in &bounds {
+            let origin = *origin as usize;
+            if origin + end - start > 0x10000 {
+                return Err(format!(
+                    "The segment at ${:04x} is {} bytes, which runs past $ffff.",
+                    origin,
+                    end - start
+                ));
+            }
+            ranges.push((origin, origin + end - start));
+        }
+        ranges.sort();
+        for pair in ranges.windows(2) {
+            if pair[1].0 < pair[0].1 {
+                return Err(format!(
+                    "The segment at ${:04x} overlaps the one at ${:04x}.",
+                    pair[1].0, pair[0].0
+                ));
+            }
+        }
+
+        // Fill in the proper addresses for the labels, from the segment that they're
+        // in.
+        for (string_index, byte_offset, label_mapping_type) in
+            labels.addresses_to_label.iter()
+        {
+            match label_mapping_type {
+                LabelMappingType::Relative => {
+                    // Map relative ranges by performing the arithmetic to get the relative
+                    // difference between the next instruction and the label. This
+                    // relative jump in memory gets stored as the operand.
+                    let label_value_u16 = labels.get_address(*string_index)? as u16;
+                    let offset: i32 = label_value_u16 as i32
+                        - segment_address(&segment_starts, *byte_offset) as i32
+                        // The byte offset is for the operand, the next instruction is
+                        // right after it.
+                        - 1;
+
+                    if offset > 127 || offset < -128 {
+                        return Err(
+                            "A relative label was used too far away to be generated."
+                                .into(),
+                        );
+                    }
+
+                    // Take only the least significant byte of the offset, which really
+                    // contains an i8.
+                    bytes[*byte_offset] = offset as u8;
+                }
+                LabelMappingType::Absolute => {
+                    let label_value_u16 = labels.get_address(*string_index)? as u16;
+
+                    let [low, high] = label_value_u16.to_le_bytes();
+                    bytes[*byte_offset] = low;
+                    bytes[*byte_offset + 1] = high;
+                }
+            };
+        }
+
+        // Convert the labels to a HashMap data structure that makes it easy to go
+        // from an address to the string. This new data structure will own the strings.
+        let mut address_to_label: AddressToLabel = HashMap::new();
+        if let Some(addresses) = labels.addresses {
+            for string_index in 0..labels.strings.len() {
+                let address = match addresses.get(string_index) {
+                    Some(Some(address)) => address,
+                    // The label was only used as an operand.
+                    _ => continue,
+                };
+
+                // Take ownership of the string.
+                let old_string = labels
+                    .strings
+                    .get_mut(string_index)
+                    .expect("Unable to get string");
+                let mut new_string = String::with_capacity(0);
+
+                std::mem::swap(&mut new_string, old_string);
+
+                address_to_label.insert(*address as u16, new_string);
+            }
+        }
+
+        let segments = bounds
+            .iter()
+            .map(|(start, end, origin)| Segment {
+                origin: *origin,
+                bytes: bytes[*start..*end].to_vec(),
+            })
+            .collect();
+
+        Ok(SegmentsLabels {
+            segments,
+            address_to_label,
+            address_to_source_line,
+        })
+    }
+
+    fn as_bytes_before_labels(&mut self) -> Result<Vec<u8>, String> {
+        let mut bytes: Vec<u8> = Vec::new();
+        self.opcode_offsets.clear();
+        self.segment_starts = vec![(0, ORIGIN)];
+        let mut tokens = self.tokens.iter().peekable();
+        while let Some(token) = tokens.next() {
+            if let Token::Instruction(_) = token {
+                self.opcode_offsets.push(bytes.len());
+            }
+            match token {
+                Token::Instruction(instruction) => match tokens.peek() {
+                    Some(Token::LabelOperand(string_index)) => {
+                        match instruction {
+                            Instruction::BPL
+                            | Instruction::BMI
+                            | Instruction::BVC
+                            | Instruction::BVS
+                            | Instruction::BCC
+                            | Instruction::BCS
+                            | Instruction::BNE
+                            | Instruction::BEQ => {
+                                // labelname:
+                                //   clc
+                                //   bcc labelname; branch using a relative instruction
+                                //   ^^^ ^^^^^^^^^
+                                //   |   |
+                                //   |   relative label
+                                //   instruction
+                                let opcode = instruction_mode_to_op_code(
+                                    instruction,
+                                    &TokenMode::Relative,
+                                )?;
+
+                                bytes.push(opcode as u8);
+
+                                // Go back and fill this label in with a relative address.
+                                self.labels.addresses_to_label.push((
+                                    *string_index,
+                                    bytes.len(),
+                                    LabelMappingType::Relative,
+                                ));
+
+                                // Push on a u8 address which will be filled in later.
+                                bytes.push(0);
+                                tokens.next();
+                            }
+                            _ => {
+                                let opcode = instruction_mode_to_op_code(
+                                    instruction,
+                                    &TokenMode::Absolute,
+                                )?;
+                                bytes.push(opcode as u8);
+
+                                // Go back and fill this label in.
+                                self.labels.addresses_to_label.push((
+                                    *string_index,
+                                    bytes.len(),
+                                    LabelMappingType::Absolute,
+                                ));
+
+                                // Push on a u16 address which will be filled in later.
+                                bytes.push(0);
+                                bytes.push(0);
+                                tokens.next();
+                            }
+                        };
+                    }
+                    Some(Token::Mode(mode)) => {
+                        bytes.push(instruction_mode_to_op_code(instruction, mode)? as u8);
+                        tokens.next();
+
+                        match mode {
+                            TokenMode::Absolute
+                            | TokenMode::AbsoluteIndexedX
+                            | TokenMode::AbsoluteIndexedY
+                            | TokenMode::Indirect => {
+                                match tokens.next() {
+                                        Some(Token::U16(value)) => {
+                                            let [le, be] = value.to_le_bytes();
+                                            bytes.push(le);
+                                            bytes.push(be);
+                                        },
+                                        Some(token) => return Err(
+                                            format!("Expected a u16 to be the operand of an operation, but found a: {:#x?}", token)
+                                        ),
+                                        None => return Err(
+                                            "Expected a u16 to be the operand of an operation, but found nothing".to_string()
+                                        )
+                                    };
+                            }
+                            TokenMode::ZeroPageOrRelative
+                            | TokenMode::Relative
+                            | TokenMode::ZeroPageX
+                            | TokenMode::ZeroPageY
+                            | TokenMode::Immediate
+                            | TokenMode::IndirectX
+                            | TokenMode::IndirectY => {
+                                match tokens.next() {
+                                        Some(Token::U8(value)) => bytes.push(*value),
+                                        Some(token) => return Err(format!("Expected a u8 to be the operand of an operation, but found a: {:#x?}", token)),
+                                        None => return Err("Expected a u8 to be the operand of an operation, but found nothing".to_string())
+                                    };
+                            }
+                            TokenMode::Implied
+                            | TokenMode::None
+                            | TokenMode::RegisterA => {}
+                        }
+                    }
+                    _ => {
+                        bytes.push(instruction_mode_to_op_code(
+                            instruction,
+                            &TokenMode::None,
+                        )? as u8);
+                    }
+                },
+                Token::LabelDefinition(string_index) => {
+                    let address = segment_address(&self.segment_starts, bytes.len());
+                    self.labels.set_address(address, *string_index);
+                }
+                Token::LabelWord(string_index) => {
+                    self.labels.addresses_to_label.push((
+                        *string_index,
+                        bytes.len(),
+                        LabelMappingType::Absolute,
+                    ));
+                    bytes.push(0);
+                    bytes.push(0);
+                }
+                Token::Origin(origin) => match self.segment_starts.last_mut() {
+                    // Nothing is in the segment yet, so move it.
+                    Some((start, current)) if *start == bytes.len() => *current = *origin,
+                    _ => self.segment_starts.push((bytes.len(), *origin)),
+                },
+                Token::LabelOperand(string_index) => {
+                    return Err(format!(
+                            "Unexpected LabelOperand operand found. Operands are assumed to follow instructions: {:#x?}",
+                            self.labels.strings.get(*string_index).unwrap()
+                        ));
+                }
+                Token::U8(value) => bytes.push(*value),
+                Token::U16(value) => {
+                    let [le, be] = value.to_le_bytes();
+                    bytes.push(le);
+                    bytes.push(be);
+                }
+                token => {
+                    return Err(format!(
+                        "Unexpected token at the root level: {:#x?}",
+                        token
+                    ))
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Attempts to find a comma after a number. Returns true on success, or false
+    /// if the end of the line is reached
+    fn find_comma(&mut self) -> Result<bool, String> {
+        self.skip_whitespace();
+        iter_peek_match!(self.characters, character => {
+            Character::Value(',') => {
+                // Skip past the comma and any whitespace.
+                self.next_character();
+                self.skip_whitespace();
+                // A comma was found!
+                return Ok(true)
+            },
+            Character::Value(';') | Character::Value('/') => {
+                self.continue_to_end_of_line()?;
+            },
+            value => return Err(format!("Unknown character when expecting a comma or semi-colon \"{:?}\"", value))
+        });
+        Ok(false)
+    }
+
+    fn skip_whitespace(&mut self) {
+        iter_peek_match!(self.characters, character => {
+            Character::Whitespace => {
+                self.next_character();
+            },
+            _ => return
+        });
+    }
+
+    fn next_characters_u8(&mut self) -> Result<u8, String> {
+        match self.next_character_or_err()? {
+            '$' => {
+                // e.g. $33
+                let string = self.get_word(None)?;
+                match u8::from_str_radix(&string, 16) {
+                    Err(err) => {
+                        Err(format!("Unable to parse hex string as number {:?}", err))
+                    }
+                    Ok(value) => Ok(value),
+                }
+            }
+            '%' => {
+                // e.g. %11110000
+                let string = self.get_word(None)?;
+                match u8::from_str_radix(&string, 2) {
+                    Err(err) => {
+                        Err(format!("Unable to parse binary string as number {:?}", err))
+                    }
+                    Ok(value) => Ok(value),
+                }
+            }
+            character if character.is_alphabetic() || character == '_' => {
+                let name = self.get_word(Some(&character))?;
+                match self.constant(&name)? {
+                    U8OrU16::U8(value) => Ok(value),
+                    U8OrU16::U16(value) if value <= 0xff => Ok(value as u8),
+                    U8OrU16::U16(_) => {
+                        Err(format!("The constant \"{}\" doesn't fit in a byte", name))
+                    }
+                }
+            }
+            character => {
+                let number = self.get_word(Some(&character))?;
+                match u8::from_str_radix(&number, 10) {
+                    Ok(number) => Ok(number),
+                    Err(_) => Err(format!("Unable to parse as integer \"{}\"", number)),
+                }
+            }
+        }
+    }
+
+    fn next_characters_u16(&mut self) -> Result<u16, String> {
+        match self.next_character_or_err()? {
+            '$' => {
+                // e.g. $3344
+                let string = self.get_word(None)?;
+                match u16::from_str_radix(&string, 16) {
+                    Ok(value) => Ok(value),
+                    Err(_) => Err(format!(
+                        "Unable to parse hex string as integer \"${}\"",
+                        string
+                    )),
+                }
+            }
+            '%' => {
+                // e.g. %11110000111100000
+                let string = self.get_word(None)?;
+                match u16::from_str_radix(&string, 2) {
+                    Err(err) => Err(format!(
+                        "Unable to parse binary string as number \"%{:?}\"",
+                        err
+                    )),
+                    Ok(value) => Ok(value),
+                }
+            }
+            character if character.is_alphabetic() || character == '_' => {
+                let name = self.get_word(Some(&character))?;
+                match self.constant(&name)? {
+                    U8OrU16::U8(value) => Ok(value as u16),
+                    U8OrU16::U16(value) => Ok(value),
+                }
+            }
+            character => {
+                let number = self.get_word(Some(&character))?;
+                match u16::from_str_radix(&number, 10) {
+                    Ok(number) => Ok(number),
+                    Err(_) => Err(format!("Unable to parse as integer \"{}\"", number)),
+                }
+            }
+        }
+    }
+
+    fn next_characters_u8_or_u16(&mut self) -> Result<U8OrU16, String> {
+        match self.next_character_or_err()? {
+            '$' => {
+                // e.g. $33
+                let word = self.get_word(None)?;
+                match word.len() {
+                    2 => match u8::from_str_radix(&word, 16) {
+                        Err(err) => {
+                            Err(format!("Unable to parse hex string as number {:?}", err))
+                        }
+                        Ok(number) => Ok(U8OrU16::U8(number)),
+                    },
+                    4 => match u16::from_str_radix(&word, 16) {
+                        Err(err) => {
+                            Err(format!("Unable to parse hex string as number {:?}", err))
+                        }
+                        Ok(number) => Ok(U8OrU16::U16(number)),
+                    },
+                    _ => {
+                        Err("This hex number must be either 2 or 4 digits long."
+                            .to_string())
+                    }
+                }
+            }
+            '%' => {
+                // e.g. %11110000
+                let word = self.get_word(None)?;
+                match word.len() {
+                    8 => match u8::from_str_radix(&word, 2) {
+                        Err(err) => Err(format!(
+                            "Unable to parse binary string as number {:?}",
+                            err
+                        )),
+                        Ok(number) => Ok(U8OrU16::U8(number)),
+                    },
+                    16 => match u16::from_str_radix(&word, 2) {
+                        Err(err) => Err(format!(
+                            "Unable to parse binary string as number {:?}",
+                            err
+                        )),
+                        Ok(number) => Ok(U8OrU16::U16(number)),
+                    },
+                    _ => Err("This binary number must be either 8 or 16 digits long."
+                        .to_string()),
+                }
+            }
+            character if character.is_alphabetic() || character == '_' => {
+                let name = self.get_word(Some(&character))?;
+                self.constant(&name)
+            }
+            character => {
+                // TODO - Is it possible to differentiate U8 or U16 here? For now assume
+                // that it's u8.
+                let number = self.get_word(Some(&character))?;
+                match u8::from_str_radix(&number, 10) {
+                    Ok(number) => Ok(U8OrU16::U8(number)),
+                    Err(_) => Err(format!("Unable to parse as integer \"{}\"", number)),
+                }
+            }
+        }
+    }
+
+    fn next_character_or_err(&mut self) -> Result<char, String> {
+        match self.next_character() {
+            Some(character) => Ok(character),
+            None => Err("Unexpected end of file.".to_string()),
+        }
+    }
+
+    fn peek_is_next_character(&mut self, value: char) -> bool {
+        match self.characters.peek() {
+            Some(character) => *character == value,
+            None => false,
+        }
+    }
+
+    fn expect_next_character_ignore_casing(&mut self, value: char) -> Result<(), String> {
+        let next_char = self.next_character_or_err()?;
+        if next_char.to_ascii_lowercase() == value.to_ascii_lowercase() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Expected the character {} but found {}",
+                value, next_char
+            ))
+        }
+    }
+
+    /// imm = #$00
+    /// zp = $00
+    /// zpx = $00,X
+    /// zpy = $00,Y
+    /// izx = ($00,X)
+    /// izy = ($00),Y
+    /// abs = $0000
+    /// abx = $0000,X
+    /// aby = $0000,Y
+    /// ind = ($0000)
+    /// rel = $0000 (PC-relative)
+    fn parse_operand(&mut self, instruction: Instruction) -> Result<(), String> {
+        iter_peek_match!(self.characters, character => {
+            Character::Whitespace => {
+                self.next_character()
+            },
+            Character::Alpha => {
+                let word = self.get_word(None)?;
+                if word == "A" || word == "a" {
+                    self.tokens.push(Token::Mode(TokenMode::RegisterA));
+                } else if let Some(value) = self.labels.constant(&word) {
+                    self.push_value_operand(value)?;
+                } else {
+                    let label = Token::LabelOperand(self.labels.take_string(word));
+                    self.tokens.push(label);
+                    if !self.scopes.is_empty() {
+                        let scope = self.scopes.join("::");
+                        self.scoped_operands.push((self.tokens.len() - 1, scope));
+                    }
+                }
+                return self.continue_to_end_of_line();
+            }
+            Character::Value(';') | Character::Value('/') => {
+                // Check operand.
+                self.verify_instruction_needs_no_operand(instruction)?;
+                return self.continue_to_end_of_line();
+            }
+            Character::Value('#') => {
+                // Immediate mode, match #$00.
+                self.next_character();
+                self.tokens.push(Token::Mode(TokenMode::Immediate));
+                let value = self.next_characters_u8()?;
+                self.tokens.push(Token::U8(value));
+                return self.continue_to_end_of_line();
+            }
+            Character::Value('$')
+            | Character::Value('%')
+            | Character::Numeric => {
+                let value = self.next_characters_u8_or_u16()?;
+                self.push_value_operand(value)?;
+                return self.continue_to_end_of_line();
+            }
+            Character::Value('(') => {
+                // jmp ($1234) ; indirect
+                // and ($aa,X) ; indirect indexed x
+                // and ($aa),Y ; indirect indexed y
+                self.next_character();
+                match self.next_characters_u8_or_u16()? {
+                    U8OrU16::U8(value_u8) => {
+                        // and ($aa,X) ; indirect indexed x
+                        // and ($aa),Y ; indirect indexed y
+                        let character = self.next_character_or_err()?;
+                        match char_to_enum(&character) {
+                            Character::Value(',') => {
+                                // and ($aa,X) ; indirect indexed x
+                                self.expect_next_character_ignore_casing('X')?;
+                                self.expect_next_character_ignore_casing(')')?;
+                                self.tokens.push(Token::Mode(TokenMode::IndirectX));
+                            }
+                            Character::Value(')') => {
+                                // and ($aa),Y ; indirect indexed y
+                                self.expect_next_character_ignore_casing(',')?;
+                                self.expect_next_character_ignore_casing('Y')?;
+                                self.tokens.push(Token::Mode(TokenMode::IndirectY));
+                            }
+                            _ => {
+                                return Err(format!(
+                                    "Unexpected character {:?}",
+                                    character
+                                ))
+                            }
+                        }
+                        self.tokens.push(Token::U8(value_u8));
+                    }
+                    U8OrU16::U16(value_u16) => {
+                        // jmp ($1234) ; indirect
+                        self.tokens.push(Token::Mode(TokenMode::Indirect));
+                        self.tokens.push(Token::U16(value_u16));
+                        self.expect_next_character_ignore_casing(')')?;
+                    }
+                }
+                return self.continue_to_end_of_line();
+            }
+            value => {
+                return Err(format!(
+                    "Unknown character type when attempting to parse an operand. {:?}",
+                    value
+                ))
+            }
+        });
+        self.verify_instruction_needs_no_operand(instruction)
+    }
+
+    /// Push the mode and the value for a numeric operand, which is zero page or
+    /// absolute depending on its size, e.g. $00,x or $0000,x.
+    fn push_value_operand(&mut self, value: U8OrU16) -> TokenizerResult {
+        match value {
+            U8OrU16::U8(value_u8) => {
+                // Figure out the mode.
+                if self.peek_is_next_character(',') {
+                    // Skip the ","
+                    self.next_character_or_err()?;
+                    let character = self.next_character_or_err()?;
+                    self.tokens.push(match character {
+                        'x' | 'X' => Token::Mode(TokenMode::ZeroPageX),
+                        'y' | 'Y' => Token::Mode(TokenMode::ZeroPageY),
+                        _ => return Err(format!("Unexpected index mode: {}", character)),
+                    });
+                } else {
+                    self.tokens.push(Token::Mode(TokenMode::ZeroPageOrRelative));
+                }
+
+                self.tokens.push(Token::U8(value_u8));
+            }
+            U8OrU16::U16(value_u16) => {
+                // Figure out the mode.
+                if self.peek_is_next_character(',') {
+                    // Skip the ","
+                    self.next_character_or_err()?;
+                    let character = self.next_character_or_err()?;
+                    self.tokens.push(match character {
+                        'x' | 'X' => Token::Mode(TokenMode::AbsoluteIndexedX),
+                        'y' | 'Y' => Token::Mode(TokenMode::AbsoluteIndexedY),
+                        _ => return Err(format!("Unexpected index mode: {}", character)),
+                    });
+                } else {
+                    self.tokens.push(Token::Mode(TokenMode::Absolute));
+                }
+
+                self.tokens.push(Token::U16(value_u16));
+            }
+        }
+        Ok(())
+    }
+
+    fn verify_instruction_needs_no_operand(
+        &self,
+        instruction: Instruction,
+    ) -> Result<(), String> {
+        match instruction {
+            // Register A
+            Instruction::ASL => Ok(()),
+            Instruction::LSR => Ok(()),
+            Instruction::ROR => Ok(()),
+            Instruction::ROL => Ok(()),
+            // Implied
+            Instruction::DEX => Ok(()),
+            Instruction::DEY => Ok(()),
+            Instruction::INX => Ok(()),
+            Instruction::INY => Ok(()),
+            Instruction::BRK => Ok(()),
+            Instruction::RTI => Ok(()),
+            Instruction::RTS => Ok(()),
+            Instruction::CLC => Ok(()),
+            Instruction::SEC => Ok(()),
+            Instruction::CLD => Ok(()),
+            Instruction::SED => Ok(()),
+            Instruction::CLI => Ok(()),
+            Instruction::SEI => Ok(()),
+            Instruction::CLV => Ok(()),
+            Instruction::NOP => Ok(()),
+            Instruction::TAX => Ok(()),
+            Instruction::TXA => Ok(()),
+            Instruction::TAY => Ok(()),
+            Instruction::TYA => Ok(()),
+            Instruction::TSX => Ok(()),
+            Instruction::TXS => Ok(()),
+            Instruction::PLA => Ok(()),
+            Instruction::PHA => Ok(()),
+            Instruction::PLP => Ok(()),
+            Instruction::PHP => Ok(()),
+            Instruction::KIL => Ok(()),
+            _ => Err(format!("Instruction {:?} expected an operand", instruction)),
+        }
+    }
+
+    /// Skip the arguments of an ignored directive, which may have strings in them.
+    fn skip_directive_arguments(&mut self) -> TokenizerResult {
+        let mut is_in_string = false;
+        while let Some(character) = self.next_character() {
+            match character {
+                '"' => is_in_string = !is_in_string,
+                ';' if !is_in_string => return self.ignore_comment_contents(),
+                '/' if !is_in_string && self.peek_is_next_character('/') => {
+                    return self.ignore_comment_contents()
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn ignore_comment_contents(&mut self) -> Result<(), String> {
+        loop {
+            // This effectively runs ".last()" without consuming the iterator.
+            if self.next_character().is_none() {
+                return Ok(());
+            };
+        }
+    }
+
+    /// Run this method when the line is expected to contain nothing except whitespace
+    /// or a comment.
+    fn continue_to_end_of_line(&mut self) -> TokenizerResult {
+        loop {
+            match self.next_character() {
+                Some(character) => match char_to_enum(&character) {
+                    Character::Whitespace => continue,
+                    Character::Value(';') => return self.ignore_comment_contents(),
+                    Character::Value('/') if self.peek_is_next_character('/') => {
+                        return self.ignore_comment_contents()
+                    }
+                    value => {
+                        return Err(format!(
+                            "Unknown character encountered \"{:?}\".",
+                            value
+                        ))
+                    }
+                },
+                None => {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn get_word(&mut self, starting_char: Option<&char>) -> Result<String, String> {
+        let mut word = String::new();
+
+        if let Some(starting_char) = starting_char {
+            word.push(*starting_char);
+        }
+
+        iter_peek_match!(self.characters, character => {
+            Character::Alpha | Character::Numeric | Character::Value('_') => {
+                word.push(character);
+                self.next_character();
+            },
+            value => {
+                if word.is_empty() {
+                    return Err(format!("Expected to find an alpha-numeric value, but instead found {:?}", value));
+                }
+                break
+            },
+        });
+
+        Ok(word)
+    }
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mos6502_core::opcodes::OpCode::*;
+
+    macro_rules! assert_program {
+        ( $text:expr, [$( $bytes:expr ),*] ) => {
+            let mut parser = AsmLexer::new($text);
+
+            match parser.parse() {
+                Ok(_) => {
+                    let BytesLabels { bytes, .. } = parser.into_bytes().unwrap();
+                    // Here's the biggest reason for the macro, this will add the `as u8`
+                    // to the bytes generated.
+                    assert_eq!(vec![$( $bytes as u8, )*], bytes);
+                }
+                Err(parse_error) => {
+                    parse_error.panic_nicely();
+                }
+            };
+        };
+    }
+
+    #[test]
+    fn test_immediate_mode() {
+        assert_program!(
+            "lda #$66    ; Load 0x66 into the A register",
+            [LDA_imm, 0x66]
+        );
+    }
+
+    #[test]
+    fn test_multiple_lines() {
+        assert_program!(
+            "
+                lda #$66    ; Load 0x66 into the A register
+                adc #$55    ; Add 0x55 to it
+            ",
+            [LDA_imm, 0x66, ADC_imm, 0x55]
+        );
+    }
+
+    #[test]
+    fn test_various_codes_individually() {
+        assert_program!("jmp ($1234)", [JMP_ind, 0x34, 0x12]);
+        assert_program!("lda #$22", [LDA_imm, 0x22]);
+    }
+    #[test]
+    fn test_all_modes() {
+        assert_program!(
+            "
+                lda #$66    ; immediate
+
+                ora $1234   ; absolute
+                asl $1234,x ; absolute indexed X
+                eor $1234,y ; absolute indexed Y
+
+                bpl $03     ; relative
+                sty $04     ; zero page
+                sta $05,x   ; zero page indexed X
+                stx $06,y   ; zero page indexed Y
+
+                jmp ($1234) ; indirect
+                and ($aa,X) ; indirect indexed x
+                and ($bb),Y ; indirect indexed y
+
+                kil;
+            ",
+            [
+                LDA_imm, 0x66, ORA_abs, 0x34, 0x12, ASL_abx, 0x34, 0x12, EOR_aby, 0x34,
+                0x12, BPL_rel, 0x03, STY_zp, 0x4, STA_zpx, 0x05, STX_zpy, 0x06, JMP_ind,
+                0x34, 0x12, AND_izx, 0xaa, AND_izy, 0xbb, KIL
+            ]
+        );
+    }
+
+    #[test]
+    fn test_all_modes_binary() {
+        assert_program!(
+            "
+                lda #%11110000    ; immediate
+
+                ora %1111000101010101   ; absolute
+                asl %1111001001010101,x ; absolute indexed X
+                eor %1111001101010101,y ; absolute indexed Y
+
+                bpl %11110100     ; relative
+                sty %11110101     ; zero page
+                sta %11110110,x   ; zero page indexed X
+                stx %11110111,y   ; zero page indexed Y
+
+                jmp (%1111100001010101) ; indirect
+                and (%11111001,X) ; indirect indexed x
+                and (%11111010),Y ; indirect indexed y
+            ",
+            [
+                LDA_imm, 0b11110000, ORA_abs, 0b01010101, 0b11110001, ASL_abx,
+                0b01010101, 0b11110010, EOR_aby, 0b01010101, 0b11110011, BPL_rel,
+                0b11110100, STY_zp, 0b11110101, STA_zpx, 0b11110110, STX_zpy, 0b11110111,
+                JMP_ind, 0b01010101, 0b11111000, AND_izx, 0b11111001, AND_izy,
+                0b11111010
+            ]
+        );
+    }
+
+    #[test]
+    fn test_u8_digits() {
+        assert_program!(
+            "
+                lda #123    ; immediate
+                bpl 234     ; relative
+            ",
+            [LDA_imm, 123, BPL_rel, 234]
+        );
+    }
+
+    #[test]
+    fn test_relative_labels() {
+        assert_program!(
+            "
+                root:
+                  clc ; -5 byte = 251 u8
+                  clc ; -4 byte = 252 u8
+                  clc ; -3 byte = 253 u8
+                  bpl root     ; relative
+                  clc ; 0, the offset is from the next instruction
+            ",
+            [CLC, CLC, CLC, BPL_rel, 251, CLC]
+        );
+    }
+
+    #[test]
+    fn test_relative_labels_positive() {
+        assert_program!(
+            "
+                  clc
+                  bpl root     ; relative
+                  clc ; 0
+                  clc ; 1
+                  clc ; 2
+                  root:
+                  clc ; 3
+            ",
+            [CLC, BPL_rel, 3, CLC, CLC, CLC, CLC]
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        assert_program!(
+            "
+                jmp mylabel
+                lda #$11
+                mylabel: ; This is address 0x8005
+                lda #$22
+            ",
+            [JMP_abs, 0x05, 0x80, LDA_imm, 0x11, LDA_imm, 0x22]
+        );
+    }
+
+    #[test]
+    fn test_pragmas() {
+        assert_program!(
+            "
+                             jmp mylabel
+                            .byte $11
+                            .byte $22, $33
+                mylabel:    .word $5544      ; This is address 0x8006
+            ",
+            [JMP_abs, 0x06, 0x80, 0x11, 0x22, 0x33, 0x44, 0x55]
+        );
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_program!(
+            "
+                .byte 5
+                .byte 255
+                .byte %10101010
+                .byte %10
+                .word $ff
+                .word %1111000011110000
+            ",
+            [
+                0x05,
+                0xff,
+                0b1010_1010,
+                0x2,
+                0xff,
+                0x00,
+                0b11110000,
+                0b11110000
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let mut parser = AsmLexer::new("lda #$01\nldq #$02");
+        let error = parser.parse().unwrap_err();
+        assert_eq!(error.row(), 2);
+        // Unknown words are parsed as labels.
+        assert_eq!(error.message(), "Expected the character : but found  ");
+    }
+
+    #[test]
+    fn test_highlight_line() {
+        use Highlight::*;
+        assert_eq!(
+            highlight_line("loop: lda ($10),y ; Load it"),
+            [
+                (Label, "loop:"),
+                (Plain, " "),
+                (Instruction, "lda"),
+                (Plain, " ("),
+                (Number, "$10"),
+                (Plain, "),y "),
+                (Comment, "; Load it"),
+            ]
+        );
+        assert_eq!(
+            highlight_line("  .byte %1010"),
+            [
+                (Plain, "  "),
+                (Directive, ".byte"),
+                (Plain, " "),
+                (Number, "%1010")
+            ]
+        );
+        assert_eq!(
+            highlight_line("jmp loop"),
+            [(Instruction, "jmp"), (Plain, " loop")]
+        );
+    }
+
+    #[test]
+    fn test_register_a_mode() {
+        assert_program!(
+            "
+                asl
+                asl A
+                lsr
+                lsr A
+                ror
+                ror A
+                rol
+                rol A
+                asl a ; Lowercase
+            ",
+            [0x0A, 0x0A, 0x4A, 0x4A, 0x6A, 0x6A, 0x2A, 0x2A, 0x0A]
+        );
+    }
+
+    #[test]
+    fn test_ca65_syntax() {
+        let text = "
+            .setcpu \"6502\"
+            .segment \"CODE\"
+            PPUCTRL = $2000
+            COUNT = 16
+            .proc reset
+              lda #$80
+              sta PPUCTRL // asm6 style comment
+              ldx #COUNT
+            loop:
+              dex
+              bne loop
+              jmp main
+            .endproc
+            .proc main
+            loop:
+              jmp loop
+            .endproc
+        ";
+        let mut lexer = AsmLexer::new(text);
+        lexer.parse().unwrap();
+        let warnings: Vec<&str> = lexer.warnings().iter().map(|w| w.message()).collect();
+        assert_eq!(warnings, ["The .setcpu directive was ignored."]);
+        assert_eq!(lexer.warnings()[0].span().row, 2);
+
+        let BytesLabels {
+            bytes,
+            address_to_label,
+            ..
+        } = lexer.into_bytes().unwrap();
+        assert_eq!(
+            bytes,
+            [
+                LDA_imm as u8,
+                0x80,
+                STA_abs as u8,
+                0x00,
+                0x20,
+                LDX_imm as u8,
+                16,
+                DEX as u8,
+                BNE_rel as u8,
+                0xfd,
+                JMP_abs as u8,
+                0x0d,
+                0x80,
+                JMP_abs as u8,
+                0x0d,
+                0x80
+            ]
+        );
+        assert_eq!(address_to_label[&0x8000], "reset");
+        assert_eq!(address_to_label[&0x8007], "reset::loop");
+        // The labels in each .proc are separate, main::loop shares $800d with main.
+        assert!(address_to_label.values().any(|label| label == "main::loop"));
+    }
+
+    #[test]
+    fn test_constants() {
+        assert_program!(
+            "
+                PTR = $10
+                OAM = $0200
+                lda (PTR),y
+                sta OAM,x
+                .db PTR
+                .dw OAM
+            ",
+            [LDA_izy, 0x10, STA_abx, 0x00, 0x02, 0x10, 0x00, 0x02]
+        );
+
+        let mut lexer = AsmLexer::new("lda #BIG\nBIG = $0100");
+        assert!(lexer.parse().is_err());
+
+        // The other spellings, in every addressing mode.
+        assert_program!(
+            "
+                PPU_STATUS equ $2002
+                define SPRITE $04
+                .define VECTOR $fffc
+                BASE .equ $10
+                PTR = BASE
+                PTR = $10
+                lda PPU_STATUS
+                lda #SPRITE
+                ldx SPRITE,y
+                lda (PTR,x)
+                jmp (VECTOR)
+            ",
+            [
+                LDA_abs, 0x02, 0x20, LDA_imm, 0x04, LDX_zpy, 0x04, LDA_izx, 0x10,
+                JMP_ind, 0xfc, 0xff
+            ]
+        );
+
+        // Constants are kept apart from the labels.
+        let mut lexer = AsmLexer::new("PTR = $10\nloop:\nlda PTR\njmp loop");
+        lexer.parse().unwrap();
+        let BytesLabels {
+            address_to_label, ..
+        } = lexer.into_bytes().unwrap();
+        assert_eq!(address_to_label.len(), 1);
+        assert_eq!(address_to_label[&0x8000], "loop");
+
+        let error = |text: &str| {
+            let mut lexer = AsmLexer::new(text);
+            match lexer.parse() {
+                Ok(_) => lexer.into_bytes().err().unwrap(),
+                Err(err) => err.message().to_string(),
+            }
+        };
+        assert!(error("PTR = $10\nPTR = $20").contains("already defined as $10"));
+        assert!(error("PTR = $10\nPTR:").contains("already a constant"));
+        assert!(error("PTR:\nPTR = $10").contains("already a label"));
+        assert!(error("jmp LATER\nLATER = $8000").contains("before it's used"));
+    }
+
+    #[test]
+    fn test_unknown_directives() {
+        let mut lexer = AsmLexer::new(".segment \"HEADER\"\n.export reset ; comment");
+        lexer.parse().unwrap();
+        assert_eq!(lexer.warnings().len(), 2);
+
+        // Directives that change what's assembled can't be skipped.
+        let mut lexer = AsmLexer::new(".macro load value");
+        assert!(lexer.parse().is_err());
+        let mut lexer = AsmLexer::new(".endproc");
+        assert!(lexer.parse().is_err());
+    }
+
+    #[test]
+    fn test_assert_cycles() {
+        // lda #$00 (2), sta $2005,x (5), dex (2), and a taken bne (3) are 12 cycles. The
+        // rts is after the region. Branching to another page adds 1 when pessimistic.
+        let text = |budget: &str| {
+            format!(
+                "
+                scanline:
+                .assert_cycles {}
+                  lda #$00
+                loop:
+                  sta $2005,x
+                  dex
+                  bne loop
+                .endassert
+                  rts
+                ",
+                budget
+            )
+        };
+        let into_bytes = |text: &str| {
+            let mut lexer = AsmLexer::new(text);
+            lexer.parse().unwrap();
+            lexer.into_bytes()
+        };
+        assert!(into_bytes(&text("12")).is_ok());
+        let error = into_bytes(&text("11")).err().unwrap();
+        assert!(
+            error.contains("\"scanline\" at row 3 takes up to 12 cycles"),
+            "{}",
+            error
+        );
+        assert!(into_bytes(&text("12, pessimistic")).is_err());
+        assert!(into_bytes(&text("13, pessimistic")).is_ok());
+
+        for text in [
+            ".assert_cycles 10\nnop\n.endassert",
+            "a:\n.assert_cycles 10\nnop",
+            "a:\n.endassert",
+            "a:\n.assert_cycles 10, optimistic\nnop\n.endassert",
+        ] {
+            let mut lexer = AsmLexer::new(text);
+            assert!(lexer.parse().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_undefined_label() {
+        let mut lexer = AsmLexer::new("jmp nowhere");
+        lexer.parse().unwrap();
+        assert!(lexer.into_bytes().is_err());
+    }
+
+    #[test]
+    fn test_org() {
+        let text = "
+            .org $8002
+            reset:
+                jmp reset
+            nmi:
+                rti
+            .org $fffa
+                .word nmi, reset, reset
+            .org $0200
+            ram:
+                bcc ram
+        ";
+        let mut lexer = AsmLexer::new(text);
+        lexer.parse().unwrap();
+        let SegmentsLabels {
+            segments,
+            address_to_label,
+            ..
+        } = lexer.into_segments().unwrap();
+        assert_eq!(
+            segments,
+            [
+                Segment {
+                    origin: 0x8002,
+                    bytes: vec![JMP_abs as u8, 0x02, 0x80, RTI as u8],
+                },
+                Segment {
+                    origin: 0xfffa,
+                    bytes: vec![0x05, 0x80, 0x02, 0x80, 0x02, 0x80],
+                },
+                Segment {
+                    origin: 0x0200,
+                    bytes: vec![BCC_rel as u8, 0xfe],
+                },
+            ]
+        );
+        assert_eq!(address_to_label[&0x8005], "nmi");
+        assert_eq!(address_to_label[&0x0200], "ram");
+
+        // The bytes start at $8000, so the RAM code only fits in the segments.
+        let mut lexer = AsmLexer::new(text);
+        lexer.parse().unwrap();
+        assert!(lexer.into_bytes().is_err());
+
+        let mut lexer = AsmLexer::new(&text[..text.find(".org $0200").unwrap()]);
+        lexer.parse().unwrap();
+        let BytesLabels { bytes, .. } = lexer.into_bytes().unwrap();
+        assert_eq!(bytes.len(), 0x8000);
+        assert_eq!(bytes[..6], [0, 0, JMP_abs as u8, 0x02, 0x80, RTI as u8]);
+        assert_eq!(bytes[0x7ffa..], [0x05, 0x80, 0x02, 0x80, 0x02, 0x80]);
+
+        for text in [
+            ".org $fffe\n.word $1234, $5678",
+            ".org $8000\nnop\nnop\n.org $8001\nnop",
+        ] {
+            let mut lexer = AsmLexer::new(text);
+            lexer.parse().unwrap();
+            assert!(lexer.into_segments().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_include() {
+        let files: HashMap<&str, &str> = [
+            (
+                "player.asm",
+                "; The player.\n.export move\nmove:\n  inx\n  ldz #$01",
+            ),
+            ("tiles.asm", ".include \"player.asm\" ; Nested.\n.db $ff"),
+            ("loop.asm", ".include \"loop.asm\""),
+            ("sprite.asm", "draw:\n  inx\n  rts"),
+        ]
+        .iter()
+        .copied()
+        .collect();
+        let mut read = |path: &str, _: &str| match files.get(path) {
+            Some(text) => Ok((path.to_string(), text.as_bytes().to_vec())),
+            None if path == "tiles.chr" => Ok((path.to_string(), (0..20).collect())),
+            None => Err(format!("Unable to find {}", path)),
+        };
+
+        let sources =
+            Sources::expand("main.asm", "lda #$01\n.include \"tiles.asm\"", &mut read)
+                .unwrap();
+        assert_eq!(sources.files.len(), 3);
+        let (file, row) = sources.locate(4);
+        assert_eq!((file.name.as_str(), row), ("player.asm", 3));
+
+        // The error points at the row in the included file.
+        let mut lexer = AsmLexer::with_sources(&sources);
+        let error = lexer.parse().unwrap_err();
+        assert_eq!((error.file(), error.row()), (Some("player.asm"), 5));
+        assert!(error
+            .nice_message()
+            .contains("parse error in player.asm on row 5"));
+        let warning = &lexer.warnings()[0];
+        assert_eq!(
+            (warning.file(), warning.span().row),
+            (Some("player.asm"), 2)
+        );
+
+        let sources = Sources::expand(
+            "main.asm",
+            ".include \"player.asm\"\n.include \"player.asm\"",
+            &mut read,
+        )
+        .unwrap();
+        assert_eq!(sources.files.len(), 2);
+
+        // The instructions point back at their lines, in the files they came from.
+        let sources = Sources::expand(
+            "main.asm",
+            "lda #$01\n.include \"sprite.asm\"\nnop",
+            &mut read,
+        )
+        .unwrap();
+        let mut lexer = AsmLexer::with_sources(&sources);
+        lexer.parse().unwrap();
+        let lines = lexer.into_bytes().unwrap().address_to_source_line;
+        let line = |address| {
+            let SourceLine { file, line } = lines[&address];
+            (sources.files[file].name.as_str(), line)
+        };
+        assert_eq!(lines.len(), 4);
+        assert_eq!(line(0x8000), ("main.asm", 1));
+        assert_eq!(line(0x8002), ("sprite.asm", 2));
+        assert_eq!(line(0x8003), ("sprite.asm", 3));
+        assert_eq!(line(0x8004), ("main.asm", 3));
+
+        for text in &[
+            ".include \"missing.asm\"",
+            ".include \"loop.asm\"",
+            ".include player.asm",
+            ".include \"player.asm\" nop",
+        ] {
+            assert!(
+                Sources::expand("main.asm", text, &mut read).is_err(),
+                "{}",
+                text
+            );
+        }
+        assert!(AsmLexer::new(".include \"player.asm\"").parse().is_err());
+
+        // The binary data is placed as bytes, from the offset for the length.
+        for (text, expected) in &[
+            (".incbin \"tiles.chr\"", (0..20).collect::<Vec<u8>>()),
+            (".INCBIN \"tiles.chr\", $12 ; The end.", vec![18, 19]),
+            (".incbin \"tiles.chr\", 2, %11", vec![2, 3, 4]),
+            (".incbin \"tiles.chr\", 20, 0", vec![]),
+        ] {
+            let sources = Sources::expand("main.asm", text, &mut read).unwrap();
+            let mut lexer = AsmLexer::with_sources(&sources);
+            lexer.parse().unwrap();
+            assert_eq!(&lexer.into_bytes().unwrap().bytes, expected, "{}", text);
+        }
+        let sources =
+            Sources::expand("main.asm", "nop\n.incbin \"tiles.chr\"\nnop", &mut read)
+                .unwrap();
+        assert_eq!(sources.locate(3).1, 2);
+        assert_eq!(sources.locate(4).1, 3);
+        for text in &[
+            ".incbin \"tiles.chr\", 21",
+            ".incbin \"tiles.chr\", 10, 11",
+            ".incbin \"tiles.chr\", 1, 2, 3",
+            ".incbin \"tiles.chr\", x",
+            ".incbin tiles.chr",
+        ] {
+            assert!(
+                Sources::expand("main.asm", text, &mut read).is_err(),
+                "{}",
+                text
+            );
+        }
+        assert!(AsmLexer::new(".incbin \"tiles.chr\"").parse().is_err());
+    }
+}
@@ -0,0 +1,437 @@
+//! Warnings for programs that assemble fine, but likely don't do what was intended.
+//! The lint follows the code from the reset label, and tracks which registers and
+//! flags have been set along the way.
+
+use super::{AsmLexer, StringIndex, Token};
+use colored::Color;
+use mos6502_core::opcodes::{Instruction, TokenMode};
+use std::collections::HashMap;
+
+/// A position in the asm text. Both the row and column are 1-based.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub row: u64,
+    pub column: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AsmWarning {
+    message: String,
+    nice_message: String,
+    span: Span,
+    file: Option<String>,
+}
+
+impl AsmWarning {
+    /// The span is in the lexer's text, and is kept as the row in the file it's in.
+    pub(crate) fn new(message: String, span: Span, lexer: &AsmLexer) -> AsmWarning {
+        let (file, _, row) = lexer.locate(span.row);
+        AsmWarning {
+            nice_message: lexer.annotate(
+                span.row,
+                span.column,
+                "warning",
+                &message,
+                Color::BrightYellow,
+            ),
+            message,
+            span: Span { row, ..span },
+            file: file.map(String::from),
+        }
+    }
+
+    /// The message with the surrounding source, for printing in a terminal.
+    pub fn nice_message(&self) -> &str {
+        &self.nice_message
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Where the instruction that caused the warning starts.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// The name of the file the span is in, when the program came from `Sources`.
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+}
+
+const REGISTER_A: u8 = 0b001;
+const REGISTER_X: u8 = 0b010;
+const REGISTER_Y: u8 = 0b100;
+
+const FLAG_CARRY: u8 = 0b0001;
+const FLAG_ZERO: u8 = 0b0010;
+const FLAG_OVERFLOW: u8 = 0b0100;
+const FLAG_NEGATIVE: u8 = 0b1000;
+const FLAGS_NZ: u8 = FLAG_NEGATIVE | FLAG_ZERO;
+const FLAGS_NZC: u8 = FLAGS_NZ | FLAG_CARRY;
+
+/// The registers and flags that are known to have been set at a point in the
+/// program, as bit sets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Known {
+    registers: u8,
+    flags: u8,
+}
+
+impl Known {
+    /// Nothing is set at reset. The interrupt disable flag is, but it can't be branched
+    /// on.
+    const RESET: Known = Known {
+        registers: 0,
+        flags: 0,
+    };
+
+    /// When the path to the code isn't known, assume that everything was set.
+    const ALL: Known = Known {
+        registers: REGISTER_A | REGISTER_X | REGISTER_Y,
+        flags: FLAGS_NZC | FLAG_OVERFLOW,
+    };
+
+    /// Where paths meet, only what was set on all of them is known.
+    fn join(self, other: Known) -> Known {
+        Known {
+            registers: self.registers & other.registers,
+            flags: self.flags & other.flags,
+        }
+    }
+}
+
+fn register_name(register: u8) -> &'static str {
+    match register {
+        REGISTER_A => "A",
+        REGISTER_X => "X",
+        _ => "Y",
+    }
+}
+
+pub(crate) fn is_accumulator_mode(mode: Option<&TokenMode>) -> bool {
+    matches!(
+        mode,
+        None | Some(TokenMode::RegisterA)
+            | Some(TokenMode::Implied)
+            | Some(TokenMode::None)
+    )
+}
+
+/// The registers that an instruction reads, including the index for its mode.
+fn registers_read(instruction: &Instruction, mode: Option<&TokenMode>) -> u8 {
+    use Instruction::*;
+    let registers = match instruction {
+        ADC | SBC | AND | ORA | EOR | CMP | STA | TAX | TAY | PHA => REGISTER_A,
+        ASL | LSR | ROL | ROR if is_accumulator_mode(mode) => REGISTER_A,
+        CPX | STX | TXA | TXS | INX | DEX => REGISTER_X,
+        CPY | STY | TYA | INY | DEY => REGISTER_Y,
+        _ => 0,
+    };
+    let index = match mode {
+        Some(TokenMode::ZeroPageX)
+        | Some(TokenMode::AbsoluteIndexedX)
+        | Some(TokenMode::IndirectX) => REGISTER_X,
+        Some(TokenMode::ZeroPageY)
+        | Some(TokenMode::AbsoluteIndexedY)
+        | Some(TokenMode::IndirectY) => REGISTER_Y,
+        _ => 0,
+    };
+    registers | index
+}
+
+/// The registers and flags that an instruction sets. The illegal instructions are
+/// assumed to set everything, so that they don't cause warnings.
+fn sets(instruction: &Instruction, mode: Option<&TokenMode>) -> Known {
+    use Instruction::*;
+    let (registers, flags) = match instruction {
+        LDA | TXA | TYA | PLA | AND | ORA | EOR => (REGISTER_A, FLAGS_NZ),
+        ADC | SBC => (REGISTER_A, FLAGS_NZC | FLAG_OVERFLOW),
+        LDX | TAX | TSX | INX | DEX => (REGISTER_X, FLAGS_NZ),
+        LDY | TAY | INY | DEY => (REGISTER_Y, FLAGS_NZ),
+        ASL | LSR | ROL | ROR if is_accumulator_mode(mode) => (REGISTER_A, FLAGS_NZC),
+        ASL | LSR | ROL | ROR | CMP | CPX | CPY => (0, FLAGS_NZC),
+        INC | DEC => (0, FLAGS_NZ),
+        BIT => (0, FLAGS_NZ | FLAG_OVERFLOW),
+        CLC | SEC => (0, FLAG_CARRY),
+        CLV => (0, FLAG_OVERFLOW),
+        PLP => (0, Known::ALL.flags),
+        SLO | RLA | SRE | RRA | SAX | LAX | DCP | ISC | ANC | ALR | ARR | XAA | AXS
+        | AHX | SHY | SHX | TAS | LAS => return Known::ALL,
+        _ => (0, 0),
+    };
+    Known { registers, flags }
+}
+
+/// The flag that a branch tests, and its name.
+fn branch_flag(instruction: &Instruction) -> Option<(u8, &'static str)> {
+    use Instruction::*;
+    match instruction {
+        BPL | BMI => Some((FLAG_NEGATIVE, "negative")),
+        BVC | BVS => Some((FLAG_OVERFLOW, "overflow")),
+        BCC | BCS => Some((FLAG_CARRY, "carry")),
+        BNE | BEQ => Some((FLAG_ZERO, "zero")),
+        _ => None,
+    }
+}
+
+/// The compare that always finds the value that the load just put in the register.
+fn matching_compare(load: &Instruction) -> Option<Instruction> {
+    match load {
+        Instruction::LDA => Some(Instruction::CMP),
+        Instruction::LDX => Some(Instruction::CPX),
+        Instruction::LDY => Some(Instruction::CPY),
+        _ => None,
+    }
+}
+
+impl<'a> AsmLexer<'a> {
+    /// Look for likely bugs in the parsed program. This needs to run after `parse`
+    /// and before `into_bytes`.
+    ///
+    /// Only the code that runs straight through from reset, and the labels that it
+    /// branches or jumps to, is checked. The code after an unconditional jump is only
+    /// reachable from somewhere else, so nothing is assumed to be unset there.
+    pub fn lint(&self) -> Vec<AsmWarning> {
+        let mut warnings = vec![];
+        let warn = |warnings: &mut Vec<AsmWarning>, message: String, span: Span| {
+            warnings.push(AsmWarning::new(message, span, self));
+        };
+
+        // Start at the reset label if there is one, as there could be data or
+        // subroutines before it.
+        let start = self
+            .tokens
+            .iter()
+            .position(|token| match token {
+                Token::LabelDefinition(index) => {
+                    self.labels.string(*index).map(String::as_str) == Some("reset")
+                }
+                _ => false,
+            })
+            .unwrap_or(0);
+        let mut instruction_index = self.tokens[..start]
+            .iter()
+            .filter(|token| matches!(token, Token::Instruction(_)))
+            .count();
+
+        // None means that the code can't be reached by running into it.
+        let mut known = Some(Known::RESET);
+        // What's known at the labels that were jumped to before they were defined.
+        let mut label_known: HashMap<StringIndex, Known> = HashMap::new();
+        let mut warned_registers = 0;
+
+        for (token_index, token) in self.tokens.iter().enumerate().skip(start) {
+            let instruction = match token {
+                Token::Instruction(instruction) => instruction,
+                Token::LabelDefinition(index) => {
+                    known = match (known, label_known.get(index)) {
+                        (Some(known), Some(jumped)) => Some(known.join(*jumped)),
+                        (Some(known), None) => Some(known),
+                        (None, Some(jumped)) => Some(*jumped),
+                        (None, None) => Some(Known::ALL),
+                    };
+                    continue;
+                }
+                // The code after a .org is only reached through a label.
+                Token::Origin(_) => {
+                    known = None;
+                    continue;
+                }
+                _ => continue,
+            };
+            let span = self.instruction_spans[instruction_index];
+            instruction_index += 1;
+
+            let mode = match self.tokens.get(token_index + 1) {
+                Some(Token::Mode(mode)) => Some(mode),
+                _ => None,
+            };
+            let label_operand = match self.tokens.get(token_index + 1) {
+                Some(Token::LabelOperand(index)) => Some(*index),
+                _ => None,
+            };
+            let current = match known {
+                Some(current) => current,
+                None => continue,
+            };
+
+            let unset = registers_read(instruction, mode) & !current.registers;
+            for register in [REGISTER_A, REGISTER_X, REGISTER_Y].iter() {
+                if unset & register != 0 && warned_registers & register == 0 {
+                    warned_registers |= register;
+                    warn(
+                        &mut warnings,
+                        format!(
+                            "The {} register is read before anything sets it after reset.",
+                            register_name(*register)
+                        ),
+                        span,
+                    );
+                }
+            }
+
+            if let Some((flag, flag_name)) = branch_flag(instruction) {
+                if current.flags & flag == 0 {
+                    warn(
+                        &mut warnings,
+                        format!(
+                            "This branch uses the {} flag, but nothing before it sets the flag.",
+                            flag_name
+                        ),
+                        span,
+                    );
+                }
+            }
+
+            if mode == Some(&TokenMode::Immediate) {
+                if let Some(compare) = matching_compare(instruction) {
+                    // e.g. lda #$10, cmp #$10
+                    let next_tokens = self.tokens.get(token_index + 1..token_index + 6);
+                    if let Some(
+                        [Token::Mode(_), Token::U8(loaded), Token::Instruction(next), Token::Mode(TokenMode::Immediate), Token::U8(compared)],
+                    ) = next_tokens
+                    {
+                        if *next == compare && loaded == compared {
+                            warn(
+                                &mut warnings,
+                                format!(
+                                    "The {:?} compares the value that was just loaded, so it's always equal.",
+                                    compare
+                                ),
+                                self.instruction_spans[instruction_index],
+                            );
+                        }
+                    }
+                }
+            }
+
+            let set = sets(instruction, mode);
+            let next = Known {
+                registers: current.registers | set.registers,
+                flags: current.flags | set.flags,
+            };
+
+            let is_jump = matches!(instruction, Instruction::JMP | Instruction::JSR);
+            if let Some(index) =
+                label_operand.filter(|_| is_jump || branch_flag(instruction).is_some())
+            {
+                // The target starts with what's known here.
+                label_known
+                    .entry(index)
+                    .and_modify(|known| *known = known.join(current))
+                    .or_insert(current);
+            }
+
+            known = match instruction {
+                // The subroutine could set anything.
+                Instruction::JSR => Some(Known::ALL),
+                Instruction::JMP
+                | Instruction::RTS
+                | Instruction::RTI
+                | Instruction::BRK
+                | Instruction::KIL => None,
+                _ => Some(next),
+            };
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lint(text: &str) -> Vec<(u64, u64, String)> {
+        let mut lexer = AsmLexer::new(text);
+        lexer.parse().unwrap();
+        lexer
+            .lint()
+            .iter()
+            .map(|warning| {
+                let Span { row, column } = warning.span();
+                (row, column, warning.message().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_uninitialized_registers() {
+        assert_eq!(
+            lint("lda #$01\nsta $10,x\ntxa\nsty $11"),
+            [
+                (
+                    2,
+                    1,
+                    "The X register is read before anything sets it after reset.".into()
+                ),
+                (
+                    4,
+                    1,
+                    "The Y register is read before anything sets it after reset.".into()
+                ),
+            ]
+        );
+        assert_eq!(lint("ldx #$00\nlda $10,x\n  tay\nsty $10"), []);
+    }
+
+    #[test]
+    fn test_paths() {
+        // The forward branch skips over setting Y.
+        let text = "
+            lda #$01
+            beq skip
+            ldy #$01
+          skip:
+            sty $10
+        ";
+        assert_eq!(lint(text).len(), 1);
+        assert_eq!(lint(text)[0].0, 6);
+
+        // The subroutine is after a jmp, so it's only reached from the jsr.
+        let text = "
+            jmp main
+          sub:
+            stx $10
+            rts
+          main:
+            ldx #$01
+            jsr sub
+        ";
+        assert_eq!(lint(text), []);
+
+        // Code before the reset label isn't run first.
+        assert_eq!(lint(".byte $01\ndata:\n stx $10\nreset:\n ldx #$01"), []);
+    }
+
+    #[test]
+    fn test_compare_after_load() {
+        assert_eq!(
+            lint("lda #$05\n  cmp #$05\nldx #$01\ncpx #$02"),
+            [(
+                2,
+                3,
+                "The CMP compares the value that was just loaded, so it's always equal."
+                    .into()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_branch_flags() {
+        assert_eq!(
+            lint("bcc end\nend:"),
+            [(
+                1,
+                1,
+                "This branch uses the carry flag, but nothing before it sets the flag."
+                    .into()
+            )]
+        );
+        assert_eq!(lint("lda #$01\nbvs end\nend:")[0].0, 2);
+        assert_eq!(lint("lda #$01\nbne end\nclc\nbcc end\nend:"), []);
+        // bit sets the negative flag from memory, like when waiting on the PPU.
+        assert_eq!(lint("wait:\nbit $2002\nbpl wait"), []);
+    }
+}
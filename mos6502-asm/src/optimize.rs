@@ -0,0 +1,518 @@
+//! An opt-in peephole pass that rewrites the parsed instructions into smaller ones
+//! that leave the registers and flags the same. It's for squeezing code into a small
+//! cartridge, like the 32KB of NROM.
+
+use super::lint::is_accumulator_mode;
+use super::{AsmLexer, Span, Token, ORIGIN};
+use mos6502_core::opcodes::{Instruction, TokenMode};
+
+/// A rewrite that the optimizer made.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Optimization {
+    message: String,
+    span: Span,
+    bytes_saved: usize,
+}
+
+impl Optimization {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Where the instruction that was rewritten started in the source.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    pub fn bytes_saved(&self) -> usize {
+        self.bytes_saved
+    }
+}
+
+enum Rewrite {
+    Remove(String),
+    Replace(Instruction, String),
+}
+
+/// The values that are known to be in the registers and flags. Everything is
+/// forgotten at labels, as they can be reached from anywhere.
+#[derive(Debug, Clone, Copy, Default)]
+struct Values {
+    a: Option<u8>,
+    x: Option<u8>,
+    y: Option<u8>,
+    /// The value that the negative and zero flags were last set from.
+    nz: Option<u8>,
+    carry: Option<bool>,
+    decimal: Option<bool>,
+}
+
+impl Values {
+    fn update(&mut self, instruction: &Instruction, tokens: &[Token]) {
+        use Instruction::*;
+        let immediate = match tokens {
+            [_, Token::Mode(TokenMode::Immediate), Token::U8(value)] => Some(*value),
+            _ => None,
+        };
+        let accumulator = is_accumulator_mode(match tokens.get(1) {
+            Some(Token::Mode(mode)) => Some(mode),
+            _ => None,
+        });
+        match instruction {
+            LDA => {
+                self.a = immediate;
+                self.nz = immediate;
+            }
+            LDX => {
+                self.x = immediate;
+                self.nz = immediate;
+            }
+            LDY => {
+                self.y = immediate;
+                self.nz = immediate;
+            }
+            TAX => {
+                self.x = self.a;
+                self.nz = self.a;
+            }
+            TAY => {
+                self.y = self.a;
+                self.nz = self.a;
+            }
+            TXA => {
+                self.a = self.x;
+                self.nz = self.x;
+            }
+            TYA => {
+                self.a = self.y;
+                self.nz = self.y;
+            }
+            INX | DEX => {
+                let step = if *instruction == INX { 1 } else { 0xff };
+                self.x = self.x.map(|x| x.wrapping_add(step));
+                self.nz = self.x;
+            }
+            INY | DEY => {
+                let step = if *instruction == INY { 1 } else { 0xff };
+                self.y = self.y.map(|y| y.wrapping_add(step));
+                self.nz = self.y;
+            }
+            TSX => {
+                self.x = None;
+                self.nz = None;
+            }
+            AND | ORA | EOR | PLA => {
+                self.a = None;
+                self.nz = None;
+            }
+            ADC | SBC => {
+                self.a = None;
+                self.nz = None;
+                self.carry = None;
+            }
+            ASL | LSR | ROL | ROR => {
+                if accumulator {
+                    self.a = None;
+                }
+                self.nz = None;
+                self.carry = None;
+            }
+            CMP | CPX | CPY => {
+                self.nz = None;
+                self.carry = None;
+            }
+            INC | DEC | BIT => self.nz = None,
+            CLC => self.carry = Some(false),
+            SEC => self.carry = Some(true),
+            CLD => self.decimal = Some(false),
+            SED => self.decimal = Some(true),
+            PLP => {
+                self.nz = None;
+                self.carry = None;
+                self.decimal = None;
+            }
+            STA | STX | STY | PHA | PHP | NOP | SEI | CLI | CLV | TXS | BPL | BMI
+            | BVC | BVS | BCC | BCS | BNE | BEQ => {}
+            // Jumps, returns, and the illegal instructions.
+            _ => *self = Values::default(),
+        }
+    }
+
+    /// The register that already holds a value, and the instruction that copies it
+    /// into the target register.
+    fn transfer_from(
+        &self,
+        target: &Instruction,
+        value: u8,
+    ) -> Option<(Instruction, char)> {
+        let candidates = match target {
+            Instruction::LDA => vec![
+                (self.x, Instruction::TXA, 'X'),
+                (self.y, Instruction::TYA, 'Y'),
+            ],
+            Instruction::LDX => vec![(self.a, Instruction::TAX, 'A')],
+            Instruction::LDY => vec![(self.a, Instruction::TAY, 'A')],
+            _ => vec![],
+        };
+        candidates
+            .into_iter()
+            .find(|(held, _, _)| *held == Some(value))
+            .map(|(_, transfer, register)| (transfer, register))
+    }
+}
+
+fn name(instruction: &Instruction) -> String {
+    format!("{:?}", instruction).to_lowercase()
+}
+
+/// The number of tokens after an instruction that are its operand.
+fn operand_token_count(tokens: &[Token], index: usize) -> usize {
+    match tokens.get(index + 1) {
+        Some(Token::Mode(TokenMode::Implied))
+        | Some(Token::Mode(TokenMode::None))
+        | Some(Token::Mode(TokenMode::RegisterA)) => 1,
+        Some(Token::Mode(_)) => 2,
+        Some(Token::LabelOperand(_)) => 1,
+        _ => 0,
+    }
+}
+
+fn is_branch(instruction: &Instruction) -> bool {
+    use Instruction::*;
+    matches!(instruction, BPL | BMI | BVC | BVS | BCC | BCS | BNE | BEQ)
+}
+
+/// The number of bytes that an instruction's tokens assemble into.
+fn byte_len(instruction: &Instruction, tokens: &[Token]) -> usize {
+    1 + match tokens.get(1) {
+        Some(Token::Mode(TokenMode::Absolute))
+        | Some(Token::Mode(TokenMode::AbsoluteIndexedX))
+        | Some(Token::Mode(TokenMode::AbsoluteIndexedY))
+        | Some(Token::Mode(TokenMode::Indirect)) => 2,
+        Some(Token::Mode(TokenMode::Implied))
+        | Some(Token::Mode(TokenMode::None))
+        | Some(Token::Mode(TokenMode::RegisterA)) => 0,
+        Some(Token::Mode(_)) => 1,
+        Some(Token::LabelOperand(_)) if is_branch(instruction) => 1,
+        Some(Token::LabelOperand(_)) => 2,
+        _ => 0,
+    }
+}
+
+fn rewrite(
+    instruction: &Instruction,
+    tokens: &[Token],
+    next: Option<&Token>,
+    values: &Values,
+) -> Option<Rewrite> {
+    use Instruction::*;
+    match (instruction, tokens) {
+        (LDA, [_, Token::Mode(TokenMode::Immediate), Token::U8(value)])
+        | (LDX, [_, Token::Mode(TokenMode::Immediate), Token::U8(value)])
+        | (LDY, [_, Token::Mode(TokenMode::Immediate), Token::U8(value)]) => {
+            let (held, register) = match instruction {
+                LDA => (values.a, 'A'),
+                LDX => (values.x, 'X'),
+                _ => (values.y, 'Y'),
+            };
+            // The flags have to match too, as the load would set them.
+            if held == Some(*value) && values.nz == Some(*value) {
+                return Some(Rewrite::Remove(format!(
+                    "Removed the {} #${:02x}, {} already holds ${:02x}.",
+                    name(instruction),
+                    value,
+                    register,
+                    value
+                )));
+            }
+            values
+                .transfer_from(instruction, *value)
+                .map(|(transfer, source)| {
+                    Rewrite::Replace(
+                        transfer.clone(),
+                        format!(
+                            "Replaced the {} #${:02x} with {}, {} already holds ${:02x}.",
+                            name(instruction),
+                            value,
+                            name(&transfer),
+                            source,
+                            value
+                        ),
+                    )
+                })
+        }
+        (CLC, _) | (SEC, _) | (CLD, _) | (SED, _) => {
+            let (flag, known, is_set) = match instruction {
+                CLC => ("carry", values.carry, false),
+                SEC => ("carry", values.carry, true),
+                CLD => ("decimal", values.decimal, false),
+                _ => ("decimal", values.decimal, true),
+            };
+            if known == Some(is_set) {
+                return Some(Rewrite::Remove(format!(
+                    "Removed the {}, the {} flag is already {}.",
+                    name(instruction),
+                    flag,
+                    if is_set { "set" } else { "clear" }
+                )));
+            }
+            let overwritten_by = match (instruction, next) {
+                (CLC, Some(Token::Instruction(next @ SEC)))
+                | (CLC, Some(Token::Instruction(next @ CLC)))
+                | (SEC, Some(Token::Instruction(next @ SEC)))
+                | (SEC, Some(Token::Instruction(next @ CLC)))
+                | (CLD, Some(Token::Instruction(next @ SED)))
+                | (CLD, Some(Token::Instruction(next @ CLD)))
+                | (SED, Some(Token::Instruction(next @ SED)))
+                | (SED, Some(Token::Instruction(next @ CLD))) => next,
+                _ => return None,
+            };
+            Some(Rewrite::Remove(format!(
+                "Removed the {}, the {} after it sets the {} flag again.",
+                name(instruction),
+                name(overwritten_by),
+                flag
+            )))
+        }
+        _ => None,
+    }
+}
+
+impl<'a> AsmLexer<'a> {
+    /// Rewrite the parsed instructions into smaller ones that leave the registers and
+    /// flags the same, and report each change. This runs after `parse` and before
+    /// `into_bytes`.
+    ///
+    /// The removed bytes move the code after them, which is fine for labels, but not
+    /// for numeric addresses. Nothing before the last numeric address in the PRG ROM
+    /// is changed, and numeric branch offsets are an error.
+    pub fn optimize(&mut self) -> Result<Vec<Optimization>, String> {
+        if self
+            .tokens
+            .iter()
+            .any(|token| matches!(token, Token::Origin(_)))
+        {
+            return Err("Code with a .org can't be optimized yet.".into());
+        }
+        let tokens = std::mem::take(&mut self.tokens);
+        let spans = std::mem::take(&mut self.instruction_spans);
+
+        // Find the first byte offset that no numeric address points at or before.
+        let mut movable_from = 0;
+        let mut span_index = 0;
+        for (index, token) in tokens.iter().enumerate() {
+            let instruction = match token {
+                Token::Instruction(instruction) => instruction,
+                Token::U16(address) if *address >= ORIGIN => {
+                    // Writes to the PRG ROM are for the mapper, not the code.
+                    let is_store = index >= 2
+                        && matches!(
+                            tokens[index - 2],
+                            Token::Instruction(Instruction::STA)
+                                | Token::Instruction(Instruction::STX)
+                                | Token::Instruction(Instruction::STY)
+                        );
+                    if !is_store {
+                        movable_from = movable_from.max((address - ORIGIN) as usize + 1);
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            if is_branch(instruction)
+                && matches!(tokens.get(index + 1), Some(Token::Mode(_)))
+            {
+                self.tokens = tokens;
+                self.instruction_spans = spans;
+                return Err(format!(
+                    "The branch on row {} uses a numeric offset, which removing code \
+                     would break. Branch to a label instead.",
+                    self.instruction_spans[span_index].row
+                ));
+            }
+            span_index += 1;
+        }
+
+        let mut optimizations = vec![];
+        let mut values = Values::default();
+        let mut offset = 0;
+        let mut span_index = 0;
+        let mut index = 0;
+        while index < tokens.len() {
+            let instruction = match &tokens[index] {
+                Token::Instruction(instruction) => instruction,
+                token => {
+                    match token {
+                        Token::LabelDefinition(_) => values = Values::default(),
+                        Token::U8(_) => offset += 1,
+                        Token::U16(_) => offset += 2,
+                        _ => {}
+                    }
+                    self.tokens.push(token.clone());
+                    index += 1;
+                    continue;
+                }
+            };
+            let end = index + 1 + operand_token_count(&tokens, index);
+            let instruction_tokens = &tokens[index..end];
+            let span = spans[span_index];
+            let len = byte_len(instruction, instruction_tokens);
+
+            let rewritten = if offset >= movable_from {
+                rewrite(instruction, instruction_tokens, tokens.get(end), &values)
+            } else {
+                None
+            };
+            match rewritten {
+                Some(Rewrite::Remove(message)) => {
+                    // The removed instructions don't change anything that's known.
+                    optimizations.push(Optimization {
+                        message,
+                        span,
+                        bytes_saved: len,
+                    });
+                }
+                Some(Rewrite::Replace(replacement, message)) => {
+                    self.tokens.push(Token::Instruction(replacement));
+                    self.instruction_spans.push(span);
+                    optimizations.push(Optimization {
+                        message,
+                        span,
+                        bytes_saved: len - 1,
+                    });
+                    values.update(instruction, instruction_tokens);
+                }
+                None => {
+                    self.tokens.extend_from_slice(instruction_tokens);
+                    self.instruction_spans.push(span);
+                    values.update(instruction, instruction_tokens);
+                }
+            }
+
+            offset += len;
+            span_index += 1;
+            index = end;
+        }
+        Ok(optimizations)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::BytesLabels;
+
+    fn optimize(text: &str) -> (Vec<u8>, Vec<(u64, String)>) {
+        let mut lexer = AsmLexer::new(text);
+        lexer.parse().unwrap();
+        let optimizations = lexer
+            .optimize()
+            .unwrap()
+            .iter()
+            .map(|optimization| {
+                (optimization.span().row, optimization.message().to_string())
+            })
+            .collect();
+        let BytesLabels { bytes, .. } = lexer.into_bytes().unwrap();
+        (bytes, optimizations)
+    }
+
+    fn assemble(text: &str) -> Vec<u8> {
+        let mut lexer = AsmLexer::new(text);
+        lexer.parse().unwrap();
+        lexer.into_bytes().unwrap().bytes
+    }
+
+    #[test]
+    fn test_reuse_loaded_values() {
+        let (bytes, optimizations) = optimize(
+            "lda #$00
+             sta $2000
+             lda #$00
+             sta $2001
+             ldx #$00
+             stx $10",
+        );
+        assert_eq!(
+            bytes,
+            assemble("lda #$00\nsta $2000\nsta $2001\ntax\nstx $10")
+        );
+        assert_eq!(
+            optimizations,
+            [
+                (3, "Removed the lda #$00, A already holds $00.".into()),
+                (
+                    5,
+                    "Replaced the ldx #$00 with tax, A already holds $00.".into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_flags_must_match() {
+        // The ldx changes the zero flag, so the lda still needs to set it.
+        let text = "lda #$00\nldx #$05\nlda #$00";
+        let (bytes, optimizations) = optimize(text);
+        assert_eq!(bytes, assemble(text));
+        assert_eq!(optimizations, []);
+    }
+
+    #[test]
+    fn test_labels_forget_values() {
+        let text = "lda #$00\nloop:\nlda #$00\nsta $10,x\ninx\nbne loop";
+        let (bytes, optimizations) = optimize(text);
+        assert_eq!(bytes, assemble(text));
+        assert_eq!(optimizations, []);
+    }
+
+    #[test]
+    fn test_carry_flags() {
+        let (bytes, optimizations) = optimize(
+            "clc
+             sec
+             lda #$01
+             sec
+             adc #$01
+             clc
+             clc",
+        );
+        assert_eq!(bytes, assemble("sec\nlda #$01\nadc #$01\nclc"));
+        assert_eq!(
+            optimizations,
+            [
+                (
+                    1,
+                    "Removed the clc, the sec after it sets the carry flag again.".into()
+                ),
+                (4, "Removed the sec, the carry flag is already set.".into()),
+                (
+                    6,
+                    "Removed the clc, the clc after it sets the carry flag again.".into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_numeric_addresses() {
+        // The jmp goes to the second lda, so the code up to it can't move.
+        let (bytes, optimizations) = optimize(
+            "lda #$00
+             sta $10
+             lda #$00
+             sta $11
+             lda #$00
+             jmp $8004",
+        );
+        assert_eq!(
+            bytes,
+            assemble("lda #$00\nsta $10\nlda #$00\nsta $11\njmp $8004")
+        );
+        assert_eq!(optimizations.len(), 1);
+
+        let mut lexer = AsmLexer::new("bne $02\nnop");
+        lexer.parse().unwrap();
+        assert!(lexer.optimize().is_err());
+    }
+}
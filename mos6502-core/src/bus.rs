@@ -0,0 +1,55 @@
+/// What the CPU sees of the machine that it's in. The NES's `Bus` is one, and
+/// `RamBus` is a bare 64KB of RAM, so the same CPU core can run on either. Only the
+/// reads and writes are needed, the rest are hooks for the hardware around the CPU.
+pub trait CpuBus {
+    fn read_u8(&self, address: u16) -> u8;
+
+    fn set_u8(&mut self, address: u16, value: u8);
+
+    /// Read without side effects, for debuggers.
+    fn peek_u8(&self, address: u16) -> u8 {
+        self.read_u8(address)
+    }
+
+    fn read_u16(&self, address: u16) -> u16 {
+        // Recreate the bug of reading a u16 over a page wraps it back
+        // to the beginning of the page.
+        let [address_low, address_high] = address.to_le_bytes();
+        let address2 = u16::from_le_bytes([address_low.wrapping_add(1), address_high]);
+        self.read_u16_disjoint(address, address2)
+    }
+
+    /// Words are little endian. Use rust's built-in features rather than relying on
+    /// bit shifting.
+    ///     /// e.g.
+    /// Little-Endian:  0x1000  00 10
+    ///    Big-Endian:  0x1000  10 00
+    fn read_u16_disjoint(&self, address_a: u16, address_b: u16) -> u16 {
+        let a = self.read_u8(address_a);
+        let b = self.read_u8(address_b);
+        u16::from_le_bytes([a, b])
+    }
+
+    /// Called before each instruction or interrupt, with where it starts.
+    fn start_instruction(&mut self, _pc: u16, _cycle_count: u64) {}
+
+    /// Whether an NMI is waiting to be serviced. Taking it clears it.
+    fn take_nmi(&mut self) -> bool {
+        false
+    }
+
+    /// Whether the IRQ line is asserted, sampled before each instruction.
+    fn poll_irq(&mut self) -> bool {
+        false
+    }
+
+    /// Run the rest of the machine for the cycles that the CPU just took, starting from
+    /// `cycle_count`.
+    fn tick(&mut self, _cycles: u64, _cycle_count: u64) {}
+
+    /// The cycles that the CPU is halted for after an instruction, like for a DMA that
+    /// it started, from `cycle_count`. The rest of the machine is ticked through them.
+    fn take_stall(&mut self, _cycle_count: u64) -> u64 {
+        0
+    }
+}
@@ -0,0 +1,589 @@
+use crate::bus::CpuBus;
+use crate::opcodes::{self, Mode, OpCode};
+use history::{ExecutedInstruction, InstructionHistory, DEFAULT_HISTORY_CAPACITY};
+pub mod backward;
+pub mod history;
+pub mod opcodes_illegal;
+pub mod opcodes_jump;
+pub mod opcodes_logical;
+pub mod opcodes_move;
+pub mod operations;
+
+/// The stack is hard coded to $0100-$01FF.
+pub const STACK_PAGE: u8 = 0x01;
+
+pub enum InterruptVectors {
+    // The Non-Maskable Interrupt or NMI ($FFFA)
+    NonMaskableInterrupt = 0xFFFA,
+    ResetVector = 0xFFFC,
+    IrqBrkVector = 0xFFFE,
+}
+
+pub const RESET_STATUS_FLAG: u8 = 0b00110100;
+
+#[rustfmt::skip]
+pub enum StatusFlag {
+  Carry            = 0b00000001,
+  Zero             = 0b00000010,
+  InterruptDisable = 0b00000100,
+  Decimal          = 0b00001000,
+  Break            = 0b00010000,
+  Push             = 0b00100000,
+  Overflow         = 0b01000000,
+  Negative         = 0b10000000,
+}
+
+/// Which chip the CPU behaves like.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuVariant {
+    /// The original 6502, where ADC and SBC do binary-coded decimal arithmetic while
+    /// the Decimal flag is set.
+    Mos6502,
+    /// The 6502 in the NES. The Decimal flag can still be set and cleared, but the
+    /// decimal circuitry was removed, so ADC and SBC are always binary.
+    /// http://wiki.nesdev.com/w/index.php/CPU
+    Ricoh2A03,
+}
+
+/// This struct implements the MOS Technology 6502 central processing unit.
+///
+/// http://www.6502.org/
+/// https://en.wikipedia.org/wiki/MOS_Technology_6502
+/// http://wiki.nesdev.com/w/index.php/CPU
+///
+/// It runs on any `CpuBus`, like the NES's bus, or a bare `RamBus`.
+pub struct Cpu6502<B> {
+    // The bus is what holds all the memory access for the program.
+    pub bus: B,
+    // "A" register - The accumulator. Typical results of operations are stored here.
+    // In combination with the status register, supports using the status register for
+    // carrying, overflow detection, and so on.
+    pub a: u8,
+    /// "X" register.
+    /// Used for several addressing modes  They can be used as loop counters easily, using
+    /// INC/DEC and branch instructions. Not being the accumulator, they have limited
+    /// addressing modes themselves when loading and saving.
+    pub x: u8,
+    /// "Y" register.
+    pub y: u8,
+
+    /// "PC" - Program counter.
+    /// The 2-byte program counter PC supports 65536 direct (unbanked) memory locations,
+    /// however not all values are sent to the cartridge. It can be accessed either by
+    /// allowing CPU's internal fetch logic increment the address bus, an interrupt
+    /// (NMI, Reset, IRQ/BRQ), and using the RTS/JMP/JSR/Branch instructions.
+    /// "PC"
+    pub pc: u16,
+
+    /// "S" - Stack pointer
+    ///
+    /// The 6502 has hardware support for a stack implemented using a 256-byte array
+    /// whose location is hardcoded at page 0x01 (0x0100-0x01FF), using the S register
+    /// for a stack pointer.
+    ///
+    /// The 6502 uses a descending stack (it grows downwards)
+    /// https://wiki.nesdev.com/w/index.php/Stack
+    pub s: u8,
+
+    /// "P" - Status register.
+    /// P has 6 bits used by the ALU but is byte-wide. PHP, PLP, arithmetic, testing,
+    /// and branch instructions can access this register.
+    ///
+    /// http://wiki.nesdev.com/w/index.php/Status_flags
+    ///
+    ///   7  bit  0
+    /// ---- ----
+    /// NVss DIZC
+    /// |||| ||||
+    /// |||| |||+- Carry
+    /// |||| ||+-- Zero
+    /// |||| |+--- Interrupt Disable
+    /// |||| +---- Decimal
+    /// ||++------ No CPU effect, see: the B flag
+    /// |+-------- Overflow
+    /// +--------- Negative
+    pub p: u8,
+
+    /// The number of cycles that were done while operating on an instruction. The
+    /// emulator will then need to wait the proper amount of time after executing
+    /// the commands.
+    pub cycles: u8,
+
+    pub tick_count: u64,
+
+    /// The total number of cycles that have run, this is used to keep the CPU in sync
+    /// with the timing of the PPU.
+    pub cycle_count: u64,
+
+    /// The recently executed instructions, for debuggers.
+    pub history: InstructionHistory,
+
+    pub variant: CpuVariant,
+
+    /// The NMOS 6502 reads the target of JMP ($xxFF) from the wrong page, and games
+    /// rely on it. The 65C02 fixed this, turn it off to run code written for it.
+    /// http://www.6502.org/tutorials/6502opcodes.html#JMP
+    pub has_indirect_jump_bug: bool,
+}
+
+/// What a tick ran, for the steps that follow subroutine calls and returns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ticked {
+    /// The instruction with this opcode ran.
+    Instruction(u8),
+    /// An NMI or IRQ was serviced in place of an instruction.
+    Interrupt,
+    /// A KIL operation stopped the CPU.
+    Kil,
+}
+
+impl<B: CpuBus> Cpu6502<B> {
+    pub fn new(bus: B, variant: CpuVariant) -> Cpu6502<B> {
+        // Go ahead and read the first instruction from the reset vector. If the reset
+        // vector is set again, the program will end.
+        let pc = bus.read_u16(InterruptVectors::ResetVector as u16);
+
+        Cpu6502 {
+            bus,
+            // Accumulator
+            a: 0,
+            // X & Y Registers.
+            x: 0,
+            y: 0,
+            // The program counter.
+            pc,
+            // Stack pointer - It grows down, so initialize it at the top.
+            s: 0xFF,
+            // Status register
+            p: 0b0011_0100,
+            cycles: 0,
+            tick_count: 0,
+            cycle_count: 0,
+            history: InstructionHistory::new(DEFAULT_HISTORY_CAPACITY),
+            variant,
+            has_indirect_jump_bug: true,
+        }
+    }
+
+    /// Read the PC without incrementing.
+    fn peek_u8(&mut self) -> u8 {
+        self.bus.read_u8(self.pc)
+    }
+
+    /// Increment the program counter and read the next u8 value following
+    /// the current pc.
+    fn next_u8(&mut self) -> u8 {
+        let value = self.bus.read_u8(self.pc);
+        // The program counter wraps around from $FFFF to $0000.
+        self.pc = self.pc.wrapping_add(1);
+        value
+    }
+
+    /// Increment the program counter and read the next u16 value following
+    /// the current pc.
+    fn next_u16(&mut self) -> u16 {
+        // Operands don't have the page wrapping bug of bus.read_u16, they are read
+        // as the program counter increments.
+        let value = self.bus.read_u16_disjoint(self.pc, self.pc.wrapping_add(1));
+        self.pc = self.pc.wrapping_add(2);
+        value
+    }
+
+    /// This function is useful for testing the emulator. It will only run while the
+    /// predicate is true.
+    pub fn run_until<F>(&mut self, predicate: F)
+    where
+        F: Fn(&Self) -> bool,
+    {
+        while !predicate(self) {
+            self.tick();
+        }
+    }
+
+    /// Press the reset button. The registers keep their values, except that the stack
+    /// pointer goes down by 3 as if the PC and status were pushed, without the
+    /// writes, and interrupts are disabled. The PPU and APU aren't reset.
+    pub fn reset(&mut self) {
+        self.s = self.s.wrapping_sub(3);
+        self.set_status_flag(StatusFlag::InterruptDisable, true);
+        self.pc = self.bus.read_u16(InterruptVectors::ResetVector as u16);
+    }
+
+    /// Run the emulator until the "KIL" command is issued.
+    pub fn run(&mut self) {
+        while self.peek_u8() != OpCode::KIL as u8 {
+            self.tick();
+        }
+    }
+
+    /// The source for the comments on the modes is coming from:
+    /// http://www.emulator101.com/6502-addressing-modes.html
+    fn get_operand_address(&mut self, mode: Mode, page_boundary_cycle: u8) -> u16 {
+        match mode {
+            // Absolute addressing specifies the memory location explicitly in the two bytes
+            // following the opcode. So JMP $4032 will set the PC to $4032. The hex for
+            // this is 4C 32 40, here 4C is the opcode. The 6502 is a little endian machine,
+            // so any 16 bit (2 byte) value is stored with the LSB first. All instructions
+            // that use absolute addressing are 3 bytes including the opcode.
+            Mode::Absolute => self.next_u16(),
+            // Absolute indexing gets the target address by adding the contents of the X or Y
+            // register to an absolute address. For example, this 6502 code can be used
+            // to fill 10 bytes with $FF starting at address $1009, counting down to
+            // address $1000.
+            //
+            //    LDA #$FF    ; Load 0xff into the A register
+            //    LDY #$09    ; Load 0x09 ito the Y register
+            //    loop:       ; Create a label
+            //    STA $1000,Y ; Store 0xff at address 0x1000 + Y
+            //    DEY         ; Decrement Y
+            //    BPL loop    ; Loop until Y is 0
+            Mode::AbsoluteIndexedX => {
+                let base_address = self.next_u16();
+                let offset_address = base_address.wrapping_add(self.x as u16);
+                self.incur_extra_cycle_on_page_boundary(
+                    base_address,
+                    offset_address,
+                    page_boundary_cycle,
+                );
+                offset_address
+            }
+            Mode::AbsoluteIndexedY => {
+                let base_address = self.next_u16();
+                let offset_address = base_address.wrapping_add(self.y as u16);
+                self.incur_extra_cycle_on_page_boundary(
+                    base_address,
+                    offset_address,
+                    page_boundary_cycle,
+                );
+                offset_address
+            }
+            // These instructions have their data defined as the next byte after the
+            // opcode. ORA #$B2 will perform a logical (also called bitwise) of the
+            // value B2 with the accumulator. Remember that in assembly when you see
+            // a # sign, it indicates an immediate value. If $B2 was written without
+            // a #, it would indicate an address or offset.
+            Mode::Immediate => {
+                // Return the current program counter as the address, but also increment
+                // the program counter.
+                let address = self.pc;
+                self.pc = self.pc.wrapping_add(1);
+                address
+            }
+            // In an implied instruction, the data and/or destination is mandatory for
+            // the instruction. For example, the CLC instruction is implied, it is going
+            // to clear the processor's Carry flag.
+            Mode::Implied => {
+                panic!("Attempting to get the operand address for an implied mode.")
+            }
+            Mode::RegisterA => {
+                panic!("Register A has no address.")
+            }
+            // The indirect addressing mode is similar to the absolute mode, but the
+            // next u16 is actually a pointer to another address. Use this next address
+            // for the operation.
+            Mode::Indirect => {
+                let address = self.next_u16();
+                let bus = &self.bus;
+                if self.has_indirect_jump_bug {
+                    // The high byte of the pointer doesn't carry, so JMP ($10FF) reads
+                    // from $10FF and $1000. CpuBus::read_u16 wraps the same way.
+                    bus.read_u16(address)
+                } else {
+                    bus.read_u16_disjoint(address, address.wrapping_add(1))
+                }
+            }
+            Mode::IndirectX => {
+                let zero_page_address = self.next_u8().wrapping_add(self.x) as u16;
+                self.bus.read_u16(zero_page_address)
+            }
+            Mode::IndirectY => {
+                let zero_page_address = self.next_u8() as u16;
+                let base_address = self.bus.read_u16(zero_page_address);
+                let offset_address = base_address.wrapping_add(self.y as u16);
+                self.incur_extra_cycle_on_page_boundary(
+                    base_address,
+                    offset_address,
+                    page_boundary_cycle,
+                );
+                offset_address
+            }
+            // Relative addressing on the 6502 is only used for branch operations. The byte
+            // after the opcode is the branch offset. If the branch is taken, the new address
+            // will the the current PC plus the offset. The offset is a signed byte, so it can
+            // jump a maximum of 127 bytes forward, or 128 bytes backward.
+            //
+            // For more info about signed numbers, check here:
+            // http://www.emulator101.com/more-about-binary-numbers.html
+            Mode::Relative => {
+                let relative_offset = self.next_u8() as i8;
+                // The instruction and operand were already read, so the pc is at the
+                // next instruction, which is what the offset is from.
+                let base_address = self.pc;
+
+                // Due to the nature of binary representaion of numbers, just adding the
+                // negative number will result in it being subtract. It will wrap,
+                // hence allow the wrapping operation.
+                let offset_address = base_address.wrapping_add(relative_offset as u16);
+
+                // A branch costs a cycle more when it leaves the page of the next
+                // instruction.
+                self.incur_extra_cycle_on_page_boundary(
+                    base_address,
+                    offset_address,
+                    page_boundary_cycle,
+                );
+                offset_address
+            }
+            // Zero-Page is an addressing mode that is only capable of addressing the
+            // first 256 bytes of the CPU's memory map. You can think of it as absolute
+            // addressing for the first 256 bytes. The instruction LDA $35 will put the
+            // value stored in memory location $35 into A. The advantage of zero-page are
+            // two - the instruction takes one less byte to specify, and it executes in
+            // less CPU cycles. Most programs are written to store the most frequently
+            // used variables in the first 256 memory locations so they can take advantage
+            // of zero page addressing.
+            Mode::ZeroPage => self.next_u8() as u16,
+            // This works just like absolute indexed, but the target address is limited to
+            // the first 0xFF bytes. The target address will wrap around and will always
+            // be in the zero page. If the instruction is LDA $C0,X, and X is $60, then
+            // the target address will be $20. $C0+$60 = $120, but the carry is discarded
+            // in the calculation of the target address.
+            //
+            // 6502 bug: Zeropage index will not leave zeropage when page boundary is crossed.
+            //           Make sure and do a wrapping add in u8 space.
+            Mode::ZeroPageX => (self.next_u8().wrapping_add(self.x)) as u16,
+            Mode::ZeroPageY => (self.next_u8().wrapping_add(self.y)) as u16,
+            // For some reason NOP is being called with this. Originally it was
+            // a panic.
+            Mode::None => 0,
+        }
+    }
+
+    fn get_address_and_maybe_operand(
+        &mut self,
+        mode: Mode,
+        extra_cycle: u8,
+    ) -> (Option<u16>, u8) {
+        if mode == Mode::RegisterA {
+            return (None, self.a);
+        }
+        let address = self.get_operand_address(mode, extra_cycle);
+        let value = self.bus.read_u8(address);
+        (Some(address), value)
+    }
+
+    fn get_address_and_operand(&mut self, mode: Mode, extra_cycle: u8) -> (u16, u8) {
+        let address = self.get_operand_address(mode, extra_cycle);
+        let value = self.bus.read_u8(address);
+        (address, value)
+    }
+
+    fn incur_extra_cycle_on_page_boundary(
+        &mut self,
+        base_address: u16,
+        offset_address: u16,
+        extra_cycles: u8,
+    ) {
+        let [_, base_page] = base_address.to_le_bytes();
+        let [_, offset_page] = offset_address.to_le_bytes();
+        if base_page != offset_page {
+            self.cycles += extra_cycles;
+        }
+    }
+
+    /// Does one operational tick of the CPU. Returns true if there are more
+    /// instructions, and false if a KIL operation was encountered.
+    pub fn tick(&mut self) -> bool {
+        self.run_tick() != Ticked::Kil
+    }
+
+    /// Like `tick`, but says what ran, for stepping over and out of subroutines.
+    pub fn run_tick(&mut self) -> Ticked {
+        self.tick_count += 1;
+        self.cycles = 0;
+        self.bus.start_instruction(self.pc, self.cycle_count);
+
+        // Interrupts are sampled between instructions, and servicing one takes the
+        // place of an instruction. The NMI can't be masked.
+        let is_nmi_pending = self.bus.take_nmi();
+        let is_irq_pending =
+            !self.is_status_flag_set(StatusFlag::InterruptDisable) && self.bus.poll_irq();
+        let ticked = if is_nmi_pending {
+            self.handle_interrupt(InterruptVectors::NonMaskableInterrupt);
+            Ticked::Interrupt
+        } else if is_irq_pending {
+            self.handle_interrupt(InterruptVectors::IrqBrkVector);
+            Ticked::Interrupt
+        } else {
+            match self.execute_instruction() {
+                Ticked::Kil => return Ticked::Kil,
+                ticked => ticked,
+            }
+        };
+        self.bus.tick(self.cycles as u64, self.cycle_count);
+        self.cycle_count += self.cycles as u64;
+        // A DMA halts the CPU once the instruction is done, while the rest of the
+        // machine runs on. The DMC can fetch again in the middle of a long halt.
+        loop {
+            let stall = self.bus.take_stall(self.cycle_count);
+            if stall == 0 {
+                break;
+            }
+            self.bus.tick(stall, self.cycle_count);
+            self.cycle_count += stall;
+        }
+        ticked
+    }
+
+    /// Fetch and run the next instruction.
+    fn execute_instruction(&mut self) -> Ticked {
+        let address = self.pc;
+        let opcode = self.next_u8();
+
+        if opcode == OpCode::KIL as u8 {
+            return Ticked::Kil;
+        }
+        let opcode_index = opcode as usize;
+
+        if self.history.is_enabled() {
+            self.record_history(address, opcode);
+        }
+
+        // The operations are all contained in tables that match up the opcode to its
+        // particular implementation details.
+        self.cycles += opcodes::CYCLES_TABLE[opcode_index];
+        let operation_fn = operations::Operations::<B>::TABLE[opcode_index];
+        let mode = opcodes::ADDRESSING_MODE_TABLE[opcode_index];
+        let extra_cycles = opcodes::EXTRA_CYCLES_TABLE[opcode_index];
+
+        operation_fn(self, mode, extra_cycles);
+        Ticked::Instruction(opcode)
+    }
+
+    /// Remember the instruction before it runs, while its operand bytes are still
+    /// the ones that will be executed.
+    fn record_history(&mut self, address: u16, opcode: u8) {
+        let len = 1 + opcodes::ADDRESSING_MODE_TABLE[opcode as usize].operand_len();
+        let mut bytes = [opcode, 0, 0];
+        let bus = &self.bus;
+        for i in 1..len {
+            bytes[i as usize] = bus.peek_u8(address.wrapping_add(i as u16));
+        }
+        self.history.push(ExecutedInstruction {
+            address,
+            bytes,
+            len,
+            tick: self.tick_count,
+        });
+    }
+
+    /// These flags are commonly set together.
+    fn update_zero_and_negative_flag(&mut self, value: u8) {
+        // Numbers can be interpreted as signed or unsigned. The negative flag only
+        // cares if the most-significant bit is 1 or 0.
+        let negative = 0b1000_0000;
+        self.set_status_flag(StatusFlag::Zero, value == 0);
+        self.set_status_flag(StatusFlag::Negative, value & negative == negative);
+    }
+
+    /// ADC and SBC operate on 9 bits. 8 of them are the register A, while the last bit
+    /// is the carry flag. Store this 9th bit onto the status flag.
+    fn update_carry_flag(&mut self, result: u16) {
+        let carry = 0b1_0000_0000;
+        self.set_status_flag(StatusFlag::Carry, result & carry == carry);
+    }
+
+    /// Overflow for ADC and SBC indicates if we overflow from bit 6 to bit 7 of the u8,
+    /// and change the meaning of a number from being negative or positive.
+    /// e.g. 0b0111_1111 + 0b0000_0001 = 0b1000_0000
+    ///        |             |             |
+    ///        positive      positive      negative result
+    fn update_overflow_flag(&mut self, operand: u8, result: u8) {
+        let bit_7_mask = 0b1000_0000;
+
+        let does_overflow = (
+            // Only look at bit 7, the most significant bit (MSB)
+            bit_7_mask &
+        // A and operand have the same MSB.
+        !(self.a ^ operand) &
+        // A and result have a different MSB
+        (self.a ^ result)
+        ) == bit_7_mask; // Are both conditions correct as commented above?
+
+        self.set_status_flag(StatusFlag::Overflow, does_overflow);
+    }
+
+    fn set_status_flag(&mut self, status_flag: StatusFlag, value: bool) {
+        if value {
+            self.p |= status_flag as u8;
+        } else {
+            self.p &= !(status_flag as u8);
+        }
+    }
+
+    fn get_carry(&self) -> u8 {
+        self.p & (StatusFlag::Carry as u8)
+    }
+
+    /// ADC and SBC only use decimal arithmetic on chips that have it.
+    fn is_decimal_mode(&self) -> bool {
+        self.variant == CpuVariant::Mos6502
+            && self.is_status_flag_set(StatusFlag::Decimal)
+    }
+
+    pub fn is_status_flag_set(&self, status_flag: StatusFlag) -> bool {
+        let flag = status_flag as u8;
+        self.p & (flag as u8) == flag as u8
+    }
+
+    /// This function implements pushing to the stack.
+    /// See the "S" register for more details.
+    fn push_stack_u8(&mut self, value: u8) {
+        // The stack page is hard coded.
+        let address = u16::from_le_bytes([self.s, STACK_PAGE]);
+        // The stack points to the next available memory.
+        self.bus.set_u8(address, value);
+        // Grow down only after setting the memory.
+        self.s = self.s.wrapping_sub(1);
+    }
+
+    /// This function implements pulling to the stack.
+    /// See the "S" register for more details.
+    fn pull_stack_u8(&mut self) -> u8 {
+        // The current stack pointer points at available memory, decrement it first.
+        self.s = self.s.wrapping_add(1);
+        // Now read out the memory that is being pulled.
+        let address = u16::from_le_bytes([self.s, STACK_PAGE]);
+        self.bus.read_u8(address)
+    }
+
+    /// This function implements pushing to the stack.
+    /// See the "S" register for more details.
+    fn push_stack_u16(&mut self, value: u16) {
+        // Push the bytes one at a time so that they wrap around within the stack page.
+        // The high byte goes first, so that the u16 ends up little endian in memory.
+        let [low, high] = value.to_le_bytes();
+        self.push_stack_u8(high);
+        self.push_stack_u8(low);
+    }
+
+    /// This function implements pulling to the stack.
+    /// See the "S" register for more details.
+    fn pull_stack_u16(&mut self) -> u16 {
+        let low = self.pull_stack_u8();
+        let high = self.pull_stack_u8();
+        u16::from_le_bytes([low, high])
+    }
+
+    /// Jump to the NMI or IRQ handler. Unlike BRK, the pushed status has the break flag
+    /// cleared, so the handler can tell the two apart.
+    fn handle_interrupt(&mut self, vector: InterruptVectors) {
+        self.push_stack_u16(self.pc);
+        self.push_stack_u8(
+            (self.p & !(StatusFlag::Break as u8)) | StatusFlag::Push as u8,
+        );
+        self.set_status_flag(StatusFlag::InterruptDisable, true);
+        self.pc = self.bus.read_u16(vector as u16);
+        self.cycles += 7;
+    }
+}
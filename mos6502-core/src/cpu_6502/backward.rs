@@ -0,0 +1,177 @@
+use super::history::InstructionHistory;
+use crate::opcodes::{ADDRESSING_MODE_TABLE, OPCODE_STRING_TABLE};
+use std::collections::HashSet;
+
+/// How sure the backward disassembly is that an instruction starts at an address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Confidence {
+    /// The instruction is in the history, with the same bytes that are in memory.
+    Executed,
+    /// It's the only instruction that decodes into the next one.
+    Likely,
+    /// Other instructions also decode into the next one, this one has the most
+    /// decodings that run through it.
+    Ambiguous,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackwardInstruction {
+    pub address: u16,
+    pub confidence: Confidence,
+}
+
+/// The address after the instruction at `address`, or None for the opcodes that jam
+/// the CPU, which real code doesn't run into.
+fn next_address(read_u8: &impl Fn(u16) -> u8, address: u16) -> Option<u16> {
+    let opcode = read_u8(address);
+    if OPCODE_STRING_TABLE[opcode as usize] == "kil" {
+        return None;
+    }
+    let len = 1 + ADDRESSING_MODE_TABLE[opcode as usize].operand_len();
+    Some(address.wrapping_add(len as u16))
+}
+
+/// Find up to `count` instructions that come before `pc`, from oldest to newest.
+///
+/// The bytes before the PC can be decoded starting from many different addresses,
+/// and only some of them line up with the PC. Every address in the search window that
+/// decodes into a chain of instructions ending at the PC is a candidate, and the chain
+/// is built backwards from the PC by picking among them. The executed instructions in
+/// the history are known boundaries, so they win. Otherwise the candidate that the
+/// most chains run through wins, as misaligned decodes tend to resync with it. The
+/// disassembly stops early when nothing decodes into the next instruction.
+pub fn disassemble_backward(
+    read_u8: impl Fn(u16) -> u8,
+    pc: u16,
+    count: usize,
+    history: &InstructionHistory,
+) -> Vec<BackwardInstruction> {
+    // Instructions are at most 3 bytes.
+    let window = (count * 3).min(pc as usize) as u16;
+    let start = pc - window;
+
+    let executed: HashSet<u16> = history
+        .iter()
+        .filter(|instruction| {
+            (instruction.address
+                ..instruction.address.wrapping_add(instruction.len as u16))
+                .zip(instruction.bytes())
+                .all(|(address, byte)| read_u8(address) == *byte)
+        })
+        .map(|instruction| instruction.address)
+        .collect();
+
+    // Decode every address in the window, and keep the ones that reach the PC.
+    // votes[i] is how many decodings starting at or before start + i run through it.
+    let mut next = vec![None; window as usize];
+    let mut votes = vec![0; window as usize];
+    for offset in (0..window).rev() {
+        let address = start + offset;
+        let reaches_pc = |after: u16| {
+            after == pc
+                || (after > address
+                    && after < pc
+                    && next[(after - start) as usize].is_some())
+        };
+        next[offset as usize] =
+            next_address(&read_u8, address).filter(|&after| reaches_pc(after));
+    }
+    for offset in 0..window as usize {
+        if let Some(next) = next[offset] {
+            votes[offset] += 1;
+            if next != pc {
+                votes[(next - start) as usize] += votes[offset];
+            }
+        }
+    }
+
+    let mut instructions = vec![];
+    let mut target = pc;
+    while instructions.len() < count {
+        let candidates: Vec<u16> = (start..target)
+            .filter(|&address| next[(address - start) as usize] == Some(target))
+            .collect();
+        let best = candidates
+            .iter()
+            .copied()
+            .find(|address| executed.contains(address))
+            .or_else(|| {
+                candidates
+                    .iter()
+                    .copied()
+                    .max_by_key(|&address| votes[(address - start) as usize])
+            });
+        let address = match best {
+            Some(address) => address,
+            None => break,
+        };
+        let confidence = if executed.contains(&address) {
+            Confidence::Executed
+        } else if candidates.len() == 1 {
+            Confidence::Likely
+        } else {
+            Confidence::Ambiguous
+        };
+        instructions.push(BackwardInstruction {
+            address,
+            confidence,
+        });
+        target = address;
+    }
+    instructions.reverse();
+    instructions
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu_6502::history::ExecutedInstruction;
+
+    fn memory(bytes: &[u8]) -> impl Fn(u16) -> u8 + '_ {
+        move |address| bytes.get(address as usize).copied().unwrap_or(0)
+    }
+
+    #[test]
+    fn test_disassemble_backward() {
+        // $0: lda #$01, $2: sta $0200, $5: clc, $6: pc
+        let bytes = [0xa9, 0x01, 0x8d, 0x00, 0x02, 0x18, 0xea];
+        let history = InstructionHistory::new(0);
+        let addresses: Vec<u16> = disassemble_backward(memory(&bytes), 6, 3, &history)
+            .iter()
+            .map(|instruction| instruction.address)
+            .collect();
+        assert_eq!(addresses, [0, 2, 5]);
+    }
+
+    #[test]
+    fn test_ambiguous_decodes() {
+        // $0: lda $18a9, $3: pc. Decoding from $1 gives lda #$18, and from $2 gives
+        // clc, which both also end at the PC, so the last instruction is only a guess.
+        let bytes = [0xad, 0xa9, 0x18, 0xea];
+        let history = InstructionHistory::new(0);
+        let instructions = disassemble_backward(memory(&bytes), 3, 1, &history);
+        assert_eq!(instructions[0].confidence, Confidence::Ambiguous);
+    }
+
+    #[test]
+    fn test_history_wins() {
+        let bytes = [0xad, 0xa9, 0x18, 0xea];
+        let mut history = InstructionHistory::new(4);
+        history.push(ExecutedInstruction {
+            address: 0,
+            bytes: [0xad, 0xa9, 0x18],
+            len: 3,
+            tick: 0,
+        });
+        let instructions = disassemble_backward(memory(&bytes), 3, 2, &history);
+        if history.is_enabled() {
+            assert_eq!(
+                instructions,
+                [BackwardInstruction {
+                    address: 0,
+                    confidence: Confidence::Executed,
+                }]
+            );
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::VecDeque;
+
+/// The number of instructions that are remembered by default.
+pub const DEFAULT_HISTORY_CAPACITY: usize = 256;
+
+/// An instruction as it was executed, including the operand bytes that were in memory
+/// at the time, so that self-modifying code is still shown correctly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExecutedInstruction {
+    pub address: u16,
+    /// The opcode followed by the operand bytes, only `len` are used.
+    pub bytes: [u8; 3],
+    pub len: u8,
+    /// The tick_count of the CPU when this instruction ran.
+    pub tick: u64,
+}
+
+impl ExecutedInstruction {
+    pub fn opcode(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    /// Read a byte of the instruction by its address, which makes it easy to run
+    /// through the same disassembly code as memory.
+    pub fn read_u8(&self, address: u16) -> u8 {
+        self.bytes()
+            .get(address.wrapping_sub(self.address) as usize)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// The address after this instruction. When the next executed instruction isn't
+    /// here, then a jump, branch, or interrupt happened.
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.len as u16)
+    }
+}
+
+/// A ring buffer of the most recently executed instructions. Disassembling backwards
+/// from the PC is ambiguous, so debuggers use this to show what actually ran.
+pub struct InstructionHistory {
+    entries: VecDeque<ExecutedInstruction>,
+    capacity: usize,
+}
+
+impl InstructionHistory {
+    pub fn new(capacity: usize) -> InstructionHistory {
+        InstructionHistory {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// A capacity of 0 turns off the history.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Always false without the "debugger" feature, so the recording is optimized out.
+    pub fn is_enabled(&self) -> bool {
+        cfg!(feature = "debugger") && self.capacity > 0
+    }
+
+    pub fn push(&mut self, instruction: ExecutedInstruction) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(instruction);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Iterate from the oldest to the most recent instruction.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &ExecutedInstruction> {
+        self.entries.iter()
+    }
+
+    /// The most recent instructions, up to `count`, from oldest to newest.
+    pub fn last(&self, count: usize) -> impl Iterator<Item = &ExecutedInstruction> {
+        self.entries
+            .iter()
+            .skip(self.entries.len().saturating_sub(count))
+    }
+}
+
+#[cfg(all(test, feature = "debugger"))]
+mod test {
+    use super::*;
+    use crate::cpu_6502::{Cpu6502, CpuVariant};
+    use crate::ram_bus::RamBus;
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut history = InstructionHistory::new(2);
+        for address in 0..3 {
+            history.push(ExecutedInstruction {
+                address,
+                bytes: [0xea, 0, 0],
+                len: 1,
+                tick: address as u64,
+            });
+        }
+        let addresses: Vec<u16> = history.iter().map(|i| i.address).collect();
+        assert_eq!(addresses, [1, 2]);
+
+        history.set_capacity(0);
+        assert!(history.is_empty());
+        history.push(ExecutedInstruction {
+            address: 0,
+            bytes: [0xea, 0, 0],
+            len: 1,
+            tick: 0,
+        });
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn test_history_across_jumps() {
+        let program = [
+            0xa9, 0x01, // lda #$01
+            0x4c, 0x07, 0x80, // jmp skip
+            0xa9, 0x02, // lda #$02
+            0x85, 0x10, // skip: sta $10
+            0x02, // kil
+        ];
+        let bus = RamBus::load(&program, 0x8000).unwrap();
+        let mut cpu = Cpu6502::new(bus, CpuVariant::Mos6502);
+        cpu.run();
+        let executed: Vec<(u16, &[u8])> = cpu
+            .history
+            .iter()
+            .map(|instruction| (instruction.address, instruction.bytes()))
+            .collect();
+        assert_eq!(
+            executed,
+            [
+                (0x8000, &[0xa9, 0x01][..]),
+                (0x8002, &[0x4c, 0x07, 0x80][..]),
+                (0x8007, &[0x85, 0x10][..]),
+            ]
+        );
+        // The KIL at the end isn't executed.
+        assert_eq!(cpu.history.len(), 3);
+    }
+}
@@ -2,11 +2,11 @@ use crate::cpu_6502::*;
 
 /// Function: {adr}:={adr}*2 A:=A or {adr}
 /// Flags: N Z C
-pub fn slo(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn slo<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     let result_u16 = operand as u16 * 2;
     let result_u8 = result_u16 as u8;
-    cpu.bus.borrow_mut().set_u8(address, result_u8);
+    cpu.bus.set_u8(address, result_u8);
     cpu.a |= result_u8;
     cpu.update_zero_and_negative_flag(result_u8);
     cpu.update_carry_flag(result_u16);
@@ -15,7 +15,7 @@ pub fn slo(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: {adr}:={adr}rol A:=A and {adr}
 /// Flags: N Z C
 #[allow(unused)]
-pub fn rla(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn rla<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -24,7 +24,7 @@ pub fn rla(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: {adr}:={adr}/2 A:=A exor {adr}
 /// Flags: N Z C
 #[allow(unused)]
-pub fn sre(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn sre<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -33,7 +33,7 @@ pub fn sre(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: {adr}:={adr}ror A:=A adc {adr}
 /// Flags: N V Z C
 #[allow(unused)]
-pub fn rra(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn rra<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -42,7 +42,7 @@ pub fn rra(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: {adr}:=A&X
 /// Flags:
 #[allow(unused)]
-pub fn sax(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn sax<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -51,7 +51,7 @@ pub fn sax(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: A,X:={adr}
 /// Flags: N Z
 #[allow(unused)]
-pub fn lax(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn lax<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -60,7 +60,7 @@ pub fn lax(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: {adr}:={adr}-1 A-{adr}
 /// Flags: N Z C
 #[allow(unused)]
-pub fn dcp(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn dcp<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -69,7 +69,7 @@ pub fn dcp(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: {adr}:={adr}+1 A:=A-{adr}
 /// Flags: N V Z C
 #[allow(unused)]
-pub fn isc(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn isc<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -78,7 +78,7 @@ pub fn isc(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: A:=A&#{imm}
 /// Flags: N Z C
 #[allow(unused)]
-pub fn anc(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn anc<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -87,7 +87,7 @@ pub fn anc(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: A:=(A&#{imm})/2
 /// Flags: N Z C
 #[allow(unused)]
-pub fn alr(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn alr<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -96,7 +96,7 @@ pub fn alr(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: A:=(A&#{imm})/2
 /// Flags: N V Z C
 #[allow(unused)]
-pub fn arr(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn arr<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -105,7 +105,7 @@ pub fn arr(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: A:=X&#{imm}
 /// Flags: N Z
 #[allow(unused)]
-pub fn xaa(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn xaa<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -114,7 +114,7 @@ pub fn xaa(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: X:=A&X-#{imm}
 /// Flags: N Z C
 #[allow(unused)]
-pub fn axs(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn axs<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -123,7 +123,7 @@ pub fn axs(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: {adr}:=A&X&H
 /// Flags:
 #[allow(unused)]
-pub fn ahx(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn ahx<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -132,7 +132,7 @@ pub fn ahx(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: {adr}:=Y&H
 /// Flags:
 #[allow(unused)]
-pub fn shy(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn shy<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -141,7 +141,7 @@ pub fn shy(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: {adr}:=X&H
 /// Flags:
 #[allow(unused)]
-pub fn shx(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn shx<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -150,7 +150,7 @@ pub fn shx(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: S:=A&X {adr}:=S&H
 /// Flags:
 #[allow(unused)]
-pub fn tas(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn tas<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -159,7 +159,7 @@ pub fn tas(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: A,X,S:={adr}&S
 /// Flags: N Z
 #[allow(unused)]
-pub fn las(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn las<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -168,7 +168,7 @@ pub fn las(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Function: halts the CPU. the data bus will be set to #$FF
 /// Flags: N Z
 #[allow(unused)]
-pub fn kil(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn kil<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // TODO
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -3,7 +3,7 @@ use crate::cpu_6502::*;
 /// Apply the logical "or" operator on the accumulator.
 /// Function: A:=A or {adr}
 /// Flags: N Z
-pub fn ora(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn ora<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (_, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.a |= operand;
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -12,7 +12,7 @@ pub fn ora(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Apply the logical "and" operator on the accumulator.
 /// Function: A:=A&{adr}
 /// Flags: N Z
-pub fn and(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn and<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (_, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.a &= operand;
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -21,13 +21,13 @@ pub fn and(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Logical Exclusive OR
 /// Function: A:=A exor {adr}
 /// Flags: N Z
-pub fn eor(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn eor<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (_, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.a ^= operand;
     cpu.update_zero_and_negative_flag(cpu.a);
 }
 
-fn add_impl(cpu: &mut Cpu6502, operand: u8) {
+fn add_impl<B: CpuBus>(cpu: &mut Cpu6502<B>, operand: u8) {
     // Translating to u16 means that the values won't wrap, so wrapping
     // add is not needed.
     let result_u16 =
@@ -47,18 +47,64 @@ fn add_impl(cpu: &mut Cpu6502, operand: u8) {
     cpu.a = result_u8;
 }
 
+/// Binary-coded decimal addition, where each nibble is a digit from 0 to 9. The flags
+/// follow the NMOS 6502, where Z is from the binary sum, and N and V are from the sum
+/// before the high digit is adjusted.
+/// http://www.6502.org/tutorials/decimal_mode.html#A
+fn add_decimal<B: CpuBus>(cpu: &mut Cpu6502<B>, operand: u8) {
+    let carry = cpu.get_carry();
+    let binary = cpu.a.wrapping_add(operand).wrapping_add(carry);
+
+    let mut low = (cpu.a & 0x0f) + (operand & 0x0f) + carry;
+    if low >= 0x0a {
+        low = ((low + 0x06) & 0x0f) + 0x10;
+    }
+    let mut result = (cpu.a & 0xf0) as u16 + (operand & 0xf0) as u16 + low as u16;
+    let signed = (cpu.a & 0xf0) as i8 as i16 + (operand & 0xf0) as i8 as i16 + low as i16;
+
+    cpu.set_status_flag(StatusFlag::Zero, binary == 0);
+    cpu.set_status_flag(StatusFlag::Negative, result & 0x80 == 0x80);
+    cpu.set_status_flag(StatusFlag::Overflow, !(-128..=127).contains(&signed));
+    if result >= 0xa0 {
+        result += 0x60;
+    }
+    cpu.update_carry_flag(result);
+    cpu.a = result as u8;
+}
+
+/// Binary-coded decimal subtraction. On the NMOS 6502 all of the flags are the same
+/// as the binary subtraction, only the result is different.
+/// http://www.6502.org/tutorials/decimal_mode.html#A
+fn subtract_decimal<B: CpuBus>(cpu: &mut Cpu6502<B>, operand: u8) {
+    let borrow = 1 - cpu.get_carry() as i16;
+    let mut low = (cpu.a & 0x0f) as i16 - (operand & 0x0f) as i16 - borrow;
+    if low < 0 {
+        low = ((low - 0x06) & 0x0f) - 0x10;
+    }
+    let mut result = (cpu.a & 0xf0) as i16 - (operand & 0xf0) as i16 + low;
+    if result < 0 {
+        result -= 0x60;
+    }
+    add_impl(cpu, !operand);
+    cpu.a = result as u8;
+}
+
 /// Add with Carry
 /// Function: A:=A+{adr}+C
 /// Flags: N V Z C
-pub fn adc(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn adc<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (_, operand) = cpu.get_address_and_operand(mode, extra_cycle);
-    add_impl(cpu, operand);
+    if cpu.is_decimal_mode() {
+        add_decimal(cpu, operand);
+    } else {
+        add_impl(cpu, operand);
+    }
 }
 
 /// Subtract with Carry
 /// Function: A:=A-{adr}+C
 /// Flags: N V Z C
-pub fn sbc(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn sbc<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     // Signed numbers range: -128 to 127
     // 0b0000_0000, 0
     // 0b0000_0001, 1
@@ -80,14 +126,18 @@ pub fn sbc(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
     // the carry flag be the + 1.
     //
     // Because of this, it's assumed the assembly will run SEC before running sbc.
-    add_impl(cpu, !operand);
+    if cpu.is_decimal_mode() {
+        subtract_decimal(cpu, operand);
+    } else {
+        add_impl(cpu, !operand);
+    }
 }
 
 /// Compare A with source
 /// http://6502.org/tutorials/compare_instructions.html
 /// Function: A-{adr}
 /// Flags: N Z C
-pub fn cmp(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn cmp<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (_, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.a.wrapping_sub(operand));
     cpu.set_status_flag(StatusFlag::Carry, cpu.a >= operand);
@@ -97,7 +147,7 @@ pub fn cmp(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// http://6502.org/tutorials/compare_instructions.html
 /// Function: X-{adr}
 /// Flags: N Z C
-pub fn cpx(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn cpx<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (_, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.x.wrapping_sub(operand));
     cpu.set_status_flag(StatusFlag::Carry, cpu.x >= operand);
@@ -107,7 +157,7 @@ pub fn cpx(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// http://6502.org/tutorials/compare_instructions.html
 /// Function: Y-{adr}
 /// Flags: N Z C
-pub fn cpy(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn cpy<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (_, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.update_zero_and_negative_flag(cpu.y.wrapping_sub(operand));
     cpu.set_status_flag(StatusFlag::Carry, cpu.y >= operand);
@@ -116,17 +166,17 @@ pub fn cpy(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Decrement at an address
 /// Function: {adr}:={adr}-1
 /// Flags: N Z
-pub fn dec(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn dec<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     let result = operand.wrapping_sub(1);
     cpu.update_zero_and_negative_flag(result);
-    cpu.bus.borrow_mut().set_u8(address, result);
+    cpu.bus.set_u8(address, result);
 }
 
 /// Decrement X
 /// Function: X:=X-1
 /// Flags: N Z
-pub fn dex(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn dex<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.x = cpu.x.wrapping_sub(1);
     cpu.update_zero_and_negative_flag(cpu.x);
 }
@@ -134,7 +184,7 @@ pub fn dex(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
 /// Decrement Y
 /// Function: Y:=Y-1
 /// Flags: N Z
-pub fn dey(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn dey<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.y = cpu.y.wrapping_sub(1);
     cpu.update_zero_and_negative_flag(cpu.x);
 }
@@ -142,17 +192,17 @@ pub fn dey(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
 /// Increment the address
 /// Function: {adr}:={adr}+1
 /// Flags: N Z
-pub fn inc(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn inc<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     let result = operand.wrapping_add(1);
     cpu.update_zero_and_negative_flag(result);
-    cpu.bus.borrow_mut().set_u8(address, result);
+    cpu.bus.set_u8(address, result);
 }
 
 /// Increment X
 /// Function: X:=X+1
 /// Flags: N Z
-pub fn inx(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn inx<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.x = cpu.x.wrapping_add(1);
     cpu.update_zero_and_negative_flag(cpu.x);
 }
@@ -160,7 +210,7 @@ pub fn inx(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
 /// Increment Y
 /// Function: Y:=Y+1
 /// Flags: N Z
-pub fn iny(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn iny<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.y = cpu.y.wrapping_add(1);
     cpu.update_zero_and_negative_flag(cpu.y);
 }
@@ -168,14 +218,14 @@ pub fn iny(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
 /// Arithmetic shift left
 /// Function: {adr}:={adr}*2
 /// Flags: N Z C
-pub fn asl(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn asl<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (address, operand) = cpu.get_address_and_maybe_operand(mode, extra_cycle);
     let result = operand << 1;
     cpu.update_zero_and_negative_flag(result);
     // The Carry flag contains the bit that was shifted out:
     cpu.set_status_flag(StatusFlag::Carry, operand & 0b1000_0000 != 0);
     if let Some(address) = address {
-        cpu.bus.borrow_mut().set_u8(address, result);
+        cpu.bus.set_u8(address, result);
     } else {
         cpu.a = result;
     }
@@ -184,14 +234,14 @@ pub fn asl(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Rotate left
 /// Function: {adr}:={adr}*2+C
 /// Flags: N Z C
-pub fn rol(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn rol<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (address, operand) = cpu.get_address_and_maybe_operand(mode, extra_cycle);
     let result = (operand << 1) | cpu.get_carry();
     cpu.update_zero_and_negative_flag(result);
     // The Carry flag contains the bit that was shifted out:
     cpu.set_status_flag(StatusFlag::Carry, operand & 0b1000_0000 != 0);
     if let Some(address) = address {
-        cpu.bus.borrow_mut().set_u8(address, result);
+        cpu.bus.set_u8(address, result);
     } else {
         cpu.a = result;
     }
@@ -200,14 +250,14 @@ pub fn rol(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Logical shift right
 /// Function: {adr}:={adr}/2
 /// Flags: N Z C
-pub fn lsr(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn lsr<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (address, operand) = cpu.get_address_and_maybe_operand(mode, extra_cycle);
     let result = operand >> 1;
     cpu.update_zero_and_negative_flag(result);
     // The Carry flag contains the bit that was shifted out:
     cpu.set_status_flag(StatusFlag::Carry, operand & 0b0000_0001 != 0);
     if let Some(address) = address {
-        cpu.bus.borrow_mut().set_u8(address, result);
+        cpu.bus.set_u8(address, result);
     } else {
         cpu.a = result;
     }
@@ -216,7 +266,7 @@ pub fn lsr(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Rotate right
 /// Function: {adr}:={adr}/2+C*128
 /// Flags: N Z C
-pub fn ror(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn ror<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (address, operand) = cpu.get_address_and_maybe_operand(mode, extra_cycle);
 
     let result =
@@ -230,7 +280,7 @@ pub fn ror(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
     // The Carry flag contains the bit that was shifted out:
     cpu.set_status_flag(StatusFlag::Carry, operand & 0b0000_0001 != 0);
     if let Some(address) = address {
-        cpu.bus.borrow_mut().set_u8(address, result);
+        cpu.bus.set_u8(address, result);
     } else {
         cpu.a = result;
     }
@@ -3,7 +3,7 @@ use crate::cpu_6502::*;
 /// Load the value into register A
 /// Function: A:={adr}
 /// Flags: N Z
-pub fn lda(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn lda<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (_address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.a = operand;
     cpu.update_zero_and_negative_flag(cpu.a);
@@ -12,15 +12,17 @@ pub fn lda(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Store register A at address
 /// Function: {adr}:=A
 /// Flags:
-pub fn sta(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
-    let (address, _operand) = cpu.get_address_and_operand(mode, extra_cycle);
-    cpu.bus.borrow_mut().set_u8(address, cpu.a);
+pub fn sta<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
+    // Stores don't read the address first, which matters for registers like $2007
+    // where reads have side effects.
+    let address = cpu.get_operand_address(mode, extra_cycle);
+    cpu.bus.set_u8(address, cpu.a);
 }
 
 /// Load register X with the value
 /// Function: X:={adr}
 /// Flags: N Z
-pub fn ldx(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn ldx<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (_address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.x = operand;
     cpu.update_zero_and_negative_flag(cpu.x);
@@ -29,15 +31,15 @@ pub fn ldx(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Store register X at address
 /// Function: {adr}:=X
 /// Flags:
-pub fn stx(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
-    let (address, _operand) = cpu.get_address_and_operand(mode, extra_cycle);
-    cpu.bus.borrow_mut().set_u8(address, cpu.x);
+pub fn stx<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
+    let address = cpu.get_operand_address(mode, extra_cycle);
+    cpu.bus.set_u8(address, cpu.x);
 }
 
 /// Load register Y with the value
 /// Function: Y:={adr}
 /// Flags: N Z
-pub fn ldy(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
+pub fn ldy<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
     let (_address, operand) = cpu.get_address_and_operand(mode, extra_cycle);
     cpu.y = operand;
     cpu.update_zero_and_negative_flag(cpu.y);
@@ -46,15 +48,15 @@ pub fn ldy(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
 /// Store register Y at address
 /// Function: {adr}:=Y
 /// Flags:
-pub fn sty(cpu: &mut Cpu6502, mode: Mode, extra_cycle: u8) {
-    let (address, _operand) = cpu.get_address_and_operand(mode, extra_cycle);
-    cpu.bus.borrow_mut().set_u8(address, cpu.y);
+pub fn sty<B: CpuBus>(cpu: &mut Cpu6502<B>, mode: Mode, extra_cycle: u8) {
+    let address = cpu.get_operand_address(mode, extra_cycle);
+    cpu.bus.set_u8(address, cpu.y);
 }
 
 /// Transfer A to X
 /// Function: X:=A
 /// Flags: N Z
-pub fn tax(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn tax<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.x = cpu.a;
     cpu.update_zero_and_negative_flag(cpu.x)
 }
@@ -62,7 +64,7 @@ pub fn tax(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
 /// Transfer X to A
 /// Function: A:=X
 /// Flags: N Z
-pub fn txa(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn txa<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.a = cpu.x;
     cpu.update_zero_and_negative_flag(cpu.a)
 }
@@ -70,7 +72,7 @@ pub fn txa(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
 /// Transfer A to Y
 /// Function: Y:=A
 /// Flags: N Z
-pub fn tay(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn tay<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.y = cpu.a;
     cpu.update_zero_and_negative_flag(cpu.y)
 }
@@ -78,7 +80,7 @@ pub fn tay(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
 /// Transfer Y to A
 /// Function: A:=Y
 /// Flags: N Z
-pub fn tya(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn tya<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.a = cpu.y;
     cpu.update_zero_and_negative_flag(cpu.a)
 }
@@ -86,7 +88,7 @@ pub fn tya(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
 /// Transfer S to X
 /// Function: X:=S
 /// Flags: N Z
-pub fn tsx(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn tsx<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.x = cpu.s;
     cpu.update_zero_and_negative_flag(cpu.x)
 }
@@ -94,7 +96,7 @@ pub fn tsx(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
 /// Transfer X to S
 /// Function: S:=X
 /// Flags:
-pub fn txs(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn txs<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.s = cpu.x;
     cpu.update_zero_and_negative_flag(cpu.s)
 }
@@ -102,7 +104,7 @@ pub fn txs(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
 /// Pull A
 /// Function: A:=+(S)
 /// Flags: N Z
-pub fn pla(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn pla<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.a = cpu.pull_stack_u8();
     cpu.update_zero_and_negative_flag(cpu.a);
 }
@@ -110,20 +112,20 @@ pub fn pla(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
 /// Push A to the stack
 /// Function: (S)-:=A
 /// Flags:
-pub fn pha(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn pha<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.push_stack_u8(cpu.a);
 }
 
 /// Pull the status register from the stack
 /// Function: P:=+(S)
 /// Flags: N V D I Z C
-pub fn plp(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn plp<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.p = cpu.pull_stack_u8();
 }
 
 /// Push the status register to the stack
 /// Function: (S)-:=P
 /// Flags:
-pub fn php(cpu: &mut Cpu6502, _mode: Mode, _extra_cycle: u8) {
+pub fn php<B: CpuBus>(cpu: &mut Cpu6502<B>, _mode: Mode, _extra_cycle: u8) {
     cpu.push_stack_u8(cpu.p);
 }
@@ -0,0 +1,35 @@
+use crate::bus::CpuBus;
+use crate::cpu_6502::opcodes_illegal::*;
+use crate::cpu_6502::opcodes_jump::*;
+use crate::cpu_6502::opcodes_logical::*;
+use crate::cpu_6502::opcodes_move::*;
+use crate::cpu_6502::Cpu6502;
+use crate::opcodes::Mode;
+use std::marker::PhantomData;
+
+type OperationFn<B> = fn(&mut Cpu6502<B>, Mode, u8);
+
+/// The operations that the opcodes run, for a CPU on the bus `B`. A const can't be
+/// generic on its own, so the table hangs off of this type.
+pub struct Operations<B>(PhantomData<B>);
+
+impl<B: CpuBus> Operations<B> {
+    pub const TABLE: [OperationFn<B>; 256] = [
+        brk, ora, kil, slo, nop, ora, asl, slo, php, ora, asl, anc, nop, ora, asl, slo,
+        bpl, ora, kil, slo, nop, ora, asl, slo, clc, ora, nop, slo, nop, ora, asl, slo,
+        jsr, and, kil, rla, bit, and, rol, rla, plp, and, rol, anc, bit, and, rol, rla,
+        bmi, and, kil, rla, nop, and, rol, rla, sec, and, nop, rla, nop, and, rol, rla,
+        rti, eor, kil, sre, nop, eor, lsr, sre, pha, eor, lsr, alr, jmp, eor, lsr, sre,
+        bvc, eor, kil, sre, nop, eor, lsr, sre, cli, eor, nop, sre, nop, eor, lsr, sre,
+        rts, adc, kil, rra, nop, adc, ror, rra, pla, adc, ror, arr, jmp, adc, ror, rra,
+        bvs, adc, kil, rra, nop, adc, ror, rra, sei, adc, nop, rra, nop, adc, ror, rra,
+        nop, sta, nop, sax, sty, sta, stx, sax, dey, nop, txa, xaa, sty, sta, stx, sax,
+        bcc, sta, kil, ahx, sty, sta, stx, sax, tya, sta, txs, tas, shy, sta, shx, ahx,
+        ldy, lda, ldx, lax, ldy, lda, ldx, lax, tay, lda, tax, lax, ldy, lda, ldx, lax,
+        bcs, lda, kil, lax, ldy, lda, ldx, lax, clv, lda, tsx, las, ldy, lda, ldx, lax,
+        cpy, cmp, nop, dcp, cpy, cmp, dec, dcp, iny, cmp, dex, axs, cpy, cmp, dec, dcp,
+        bne, cmp, kil, dcp, nop, cmp, dec, dcp, cld, cmp, nop, dcp, nop, cmp, dec, dcp,
+        cpx, sbc, nop, isc, cpx, sbc, inc, isc, inx, sbc, nop, sbc, cpx, sbc, inc, isc,
+        beq, sbc, kil, isc, nop, sbc, inc, isc, sed, sbc, nop, isc, nop, sbc, inc, isc,
+    ];
+}
@@ -0,0 +1,10 @@
+//! The MOS 6502 instruction set and a CPU core that runs it. The CPU runs on any
+//! `CpuBus`, so it doesn't bring along the machine around it, like the NES.
+
+// Clippy rules to disable.
+#![allow(clippy::new_without_default)]
+
+pub mod bus;
+pub mod cpu_6502;
+pub mod opcodes;
+pub mod ram_bus;
@@ -1,9 +1,3 @@
-use crate::cpu_6502::opcodes_illegal::*;
-use crate::cpu_6502::opcodes_jump::*;
-use crate::cpu_6502::opcodes_logical::*;
-use crate::cpu_6502::opcodes_move::*;
-use crate::cpu_6502::Cpu6502;
-
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Mode {
     Absolute,         // abs
@@ -22,6 +16,19 @@ pub enum Mode {
     None,             // non - This last one is fake.
 }
 
+impl Mode {
+    /// The number of bytes that follow the opcode.
+    pub fn operand_len(&self) -> u8 {
+        match self {
+            Mode::Absolute | Mode::AbsoluteIndexedX | Mode::AbsoluteIndexedY => 2,
+            Mode::Indirect => 2,
+            Mode::Immediate | Mode::IndirectX | Mode::IndirectY | Mode::Relative => 1,
+            Mode::ZeroPage | Mode::ZeroPageX | Mode::ZeroPageY => 1,
+            Mode::Implied | Mode::RegisterA | Mode::None => 0,
+        }
+    }
+}
+
 /**
  * Tokens don't necessarily have enough information to know the mode.
  */
@@ -710,18 +717,31 @@ pub const CYCLES_TABLE: [u8; 256] = [
     4, 4, 7, 7,
 ];
 
-// TODO
+/// The cycles added when an instruction's indexed address crosses a page, as the
+/// 6502 has to fix up the high byte. Only the reads pay this, the writes and
+/// read-modify-writes always take the extra cycle, which is in CYCLES_TABLE. For the
+/// branches this is the cycle for branching to another page, a taken branch always
+/// costs 1 more.
+///
+/// http://www.6502.org/tutorials/6502opcodes.html
+#[rustfmt::skip]
 pub const EXTRA_CYCLES_TABLE: [u8; 256] = [
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // $0_
+    1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0, // $1_
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // $2_
+    1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0, // $3_
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // $4_
+    1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0, // $5_
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // $6_
+    1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0, // $7_
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // $8_
+    1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // $9_
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // $a_
+    1, 1, 0, 1, 0, 0, 0, 0, 0, 1, 0, 1, 1, 1, 1, 1, // $b_
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // $c_
+    1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0, // $d_
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // $e_
+    1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 1, 0, 0, // $f_
 ];
 
 pub const ADDRESSING_MODE_TABLE: [Mode; 256] = [
@@ -1008,23 +1028,23 @@ pub const OPCODE_STRING_TABLE: [&str; 256] = [
     "nop", "sbc", "inc", "isc",
 ];
 
-type OperationFn = fn(&mut Cpu6502, Mode, u8);
-
-pub const OPERATION_FN_TABLE: [OperationFn; 256] = [
-    brk, ora, kil, slo, nop, ora, asl, slo, php, ora, asl, anc, nop, ora, asl, slo, bpl,
-    ora, kil, slo, nop, ora, asl, slo, clc, ora, nop, slo, nop, ora, asl, slo, jsr, and,
-    kil, rla, bit, and, rol, rla, plp, and, rol, anc, bit, and, rol, rla, bmi, and, kil,
-    rla, nop, and, rol, rla, sec, and, nop, rla, nop, and, rol, rla, rti, eor, kil, sre,
-    nop, eor, lsr, sre, pha, eor, lsr, alr, jmp, eor, lsr, sre, bvc, eor, kil, sre, nop,
-    eor, lsr, sre, cli, eor, nop, sre, nop, eor, lsr, sre, rts, adc, kil, rra, nop, adc,
-    ror, rra, pla, adc, ror, arr, jmp, adc, ror, rra, bvs, adc, kil, rra, nop, adc, ror,
-    rra, sei, adc, nop, rra, nop, adc, ror, rra, nop, sta, nop, sax, sty, sta, stx, sax,
-    dey, nop, txa, xaa, sty, sta, stx, sax, bcc, sta, kil, ahx, sty, sta, stx, sax, tya,
-    sta, txs, tas, shy, sta, shx, ahx, ldy, lda, ldx, lax, ldy, lda, ldx, lax, tay, lda,
-    tax, lax, ldy, lda, ldx, lax, bcs, lda, kil, lax, ldy, lda, ldx, lax, clv, lda, tsx,
-    las, ldy, lda, ldx, lax, cpy, cmp, nop, dcp, cpy, cmp, dec, dcp, iny, cmp, dex, axs,
-    cpy, cmp, dec, dcp, bne, cmp, kil, dcp, nop, cmp, dec, dcp, cld, cmp, nop, dcp, nop,
-    cmp, dec, dcp, cpx, sbc, nop, isc, cpx, sbc, inc, isc, inx, sbc, nop, sbc, cpx, sbc,
-    inc, isc, beq, sbc, kil, isc, nop, sbc, inc, isc, sed, sbc, nop, isc, nop, sbc, inc,
-    isc,
-];
+/// The status flags that an instruction can change, given its lowercase mnemonic from
+/// the OPCODE_STRING_TABLE. The flags use the same order as the status register,
+/// NV__DIZC.
+pub fn flags_affected(mnemonic: &str) -> &'static str {
+    match mnemonic {
+        "adc" | "sbc" | "rra" | "isc" | "arr" => "NVZC",
+        "bit" => "NVZ",
+        "plp" | "rti" => "NVDIZC",
+        "asl" | "lsr" | "rol" | "ror" | "cmp" | "cpx" | "cpy" | "slo" | "rla" | "sre"
+        | "dcp" | "anc" | "alr" | "axs" => "NZC",
+        "and" | "ora" | "eor" | "dec" | "dex" | "dey" | "inc" | "inx" | "iny" | "lda"
+        | "ldx" | "ldy" | "pla" | "tax" | "tay" | "tsx" | "txa" | "tya" | "lax"
+        | "xaa" | "las" => "NZ",
+        "brk" | "cli" | "sei" => "I",
+        "cld" | "sed" => "D",
+        "clc" | "sec" => "C",
+        "clv" => "V",
+        _ => "",
+    }
+}
@@ -0,0 +1,49 @@
+//! A machine that's nothing but a CPU and 64KB of RAM. There are no registers or
+//! mirrors, so it suits 6502 code that isn't written for the NES, like test suites
+//! that use the whole address space, or a start on other machines.
+
+use crate::bus::CpuBus;
+use crate::cpu_6502::InterruptVectors;
+
+pub struct RamBus {
+    memory: Vec<u8>,
+}
+
+impl RamBus {
+    pub fn new() -> RamBus {
+        RamBus {
+            memory: vec![0; 0x10000],
+        }
+    }
+
+    /// Copy the program in at the address, and point the reset vector at it.
+    pub fn load(program: &[u8], address: u16) -> Result<RamBus, String> {
+        let start = address as usize;
+        if start + program.len() > 0x10000 {
+            return Err(format!(
+                "The program is {} bytes, which doesn't fit from ${:04x}.",
+                program.len(),
+                address
+            ));
+        }
+        let mut bus = RamBus::new();
+        bus.memory[start..start + program.len()].copy_from_slice(program);
+        let reset = InterruptVectors::ResetVector as usize;
+        bus.memory[reset..reset + 2].copy_from_slice(&address.to_le_bytes());
+        Ok(bus)
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+}
+
+impl CpuBus for RamBus {
+    fn read_u8(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn set_u8(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+    }
+}
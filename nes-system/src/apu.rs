@@ -0,0 +1,596 @@
+pub mod channels;
+pub mod samples;
+pub mod wav;
+
+use crate::irq::IrqSource;
+use crate::save_state::{StateReader, StateWriter};
+use channels::{Dmc, Noise, Pulse, Triangle};
+use samples::SampleBuffer;
+use std::cell::Cell;
+
+/// The NTSC CPU clock, which also drives the APU.
+pub const CPU_CLOCK_HZ: f64 = 1_789_773.0;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+// A little under 200ms at the default sample rate.
+const SAMPLE_BUFFER_CAPACITY: usize = 8192;
+
+pub const APU_STATUS: u16 = 0x4015;
+pub const APU_FRAME_COUNTER: u16 = 0x4017;
+
+/// $4000-$4013, $4015, and $4017. The gaps are OAM DMA and the controller port.
+pub fn is_apu_register(address: u16) -> bool {
+    matches!(address, 0x4000..=0x4013 | APU_STATUS | APU_FRAME_COUNTER)
+}
+
+/// The APU has 5 channels, which are mixed together into a single output.
+///
+/// https://www.nesdev.org/wiki/APU
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+pub const CHANNELS: [Channel; 5] = [
+    Channel::Pulse1,
+    Channel::Pulse2,
+    Channel::Triangle,
+    Channel::Noise,
+    Channel::Dmc,
+];
+
+impl Channel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+        }
+    }
+}
+
+/// The user facing volume controls, applied when the channels are mixed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MixerSettings {
+    /// 0.0 to 1.0
+    pub master_volume: f32,
+    pub is_muted: bool,
+    /// The gain for each channel, indexed in the same order as CHANNELS. This is
+    /// handy for isolating a channel when debugging a music engine.
+    pub channel_gain: [f32; 5],
+    /// When any channel is soloed, only the soloed channels are heard. Unlike the gain,
+    /// this isn't saved in the config.
+    pub channel_solo: [bool; 5],
+}
+
+impl Default for MixerSettings {
+    fn default() -> Self {
+        MixerSettings {
+            master_volume: 1.0,
+            is_muted: false,
+            channel_gain: [1.0; 5],
+            channel_solo: [false; 5],
+        }
+    }
+}
+
+impl MixerSettings {
+    pub fn gain(&self, channel: Channel) -> f32 {
+        self.channel_gain[channel as usize]
+    }
+
+    pub fn set_gain(&mut self, channel: Channel, gain: f32) {
+        self.channel_gain[channel as usize] = gain.max(0.0);
+    }
+
+    pub fn is_soloed(&self, channel: Channel) -> bool {
+        self.channel_solo[channel as usize]
+    }
+
+    pub fn toggle_solo(&mut self, channel: Channel) {
+        self.channel_solo[channel as usize] = !self.is_soloed(channel);
+    }
+
+    /// Whether the channel is heard at all, given the solos.
+    pub fn is_audible(&self, channel: Channel) -> bool {
+        self.is_soloed(channel) || !self.channel_solo.contains(&true)
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.is_muted = !self.is_muted;
+    }
+
+    /// Serialize the settings into "key = value" lines for the config file.
+    pub fn to_config_string(&self) -> String {
+        let mut string = format!(
+            "master_volume = {}\nmuted = {}\n",
+            self.master_volume, self.is_muted
+        );
+        for channel in CHANNELS.iter() {
+            string.push_str(&format!("{} = {}\n", channel.name(), self.gain(*channel)));
+        }
+        string
+    }
+
+    /// Parse the settings from the config file. Keys that are missing keep their
+    /// default values.
+    pub fn from_config_str(text: &str) -> Result<MixerSettings, String> {
+        let mut settings = MixerSettings::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => {
+                    return Err(format!(
+                        "Expected \"key = value\" but found \"{}\"",
+                        line
+                    ))
+                }
+            };
+            let parse_f32 = || {
+                value.parse::<f32>().map_err(|_| {
+                    format!("Expected a number for \"{}\", found \"{}\"", key, value)
+                })
+            };
+            match key {
+                "master_volume" => settings.master_volume = parse_f32()?.clamp(0.0, 1.0),
+                "muted" => {
+                    settings.is_muted = value.parse().map_err(|_| {
+                        format!(
+                            "Expected true or false for \"muted\", found \"{}\"",
+                            value
+                        )
+                    })?
+                }
+                _ => match CHANNELS.iter().find(|channel| channel.name() == key) {
+                    Some(channel) => settings.set_gain(*channel, parse_f32()?),
+                    None => return Err(format!("Unknown mixer setting \"{}\"", key)),
+                },
+            }
+        }
+        Ok(settings)
+    }
+}
+
+/// Mix the current output levels of the channels into a single sample from 0.0 to 1.0,
+/// using the non-linear approximation of the NES mixer. The pulse levels range from
+/// 0-15, the triangle 0-15, the noise 0-15, and the DMC 0-127.
+///
+/// https://www.nesdev.org/wiki/APU_Mixer
+#[cfg_attr(feature = "profile", tracing::instrument(skip_all))]
+pub fn mix(settings: &MixerSettings, levels: [u8; 5]) -> f32 {
+    if settings.is_muted {
+        return 0.0;
+    }
+    let level = |channel: Channel| {
+        if settings.is_audible(channel) {
+            levels[channel as usize] as f32 * settings.gain(channel)
+        } else {
+            0.0
+        }
+    };
+
+    let pulse = level(Channel::Pulse1) + level(Channel::Pulse2);
+    let pulse_out = if pulse == 0.0 {
+        0.0
+    } else {
+        95.88 / (8128.0 / pulse + 100.0)
+    };
+
+    let tnd = level(Channel::Triangle) / 8227.0
+        + level(Channel::Noise) / 12241.0
+        + level(Channel::Dmc) / 22638.0;
+    let tnd_out = if tnd == 0.0 {
+        0.0
+    } else {
+        159.79 / (1.0 / tnd + 100.0)
+    };
+
+    (pulse_out + tnd_out) * settings.master_volume
+}
+
+/// The 2A03's audio processing unit. It runs off the CPU clock, and mixes its channels
+/// into the sample buffer at the sample rate.
+pub struct Apu {
+    pub pulse_1: Pulse,
+    pub pulse_2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+    pub mixer: MixerSettings,
+    pub samples: SampleBuffer,
+    /// When set, each channel is also mixed on its own into a stem, at full volume and
+    /// in the same order as CHANNELS. The mixer isn't linear, so the stems don't add
+    /// up to exactly the full mix.
+    pub stems: Option<[Vec<f32>; 5]>,
+    sample_rate: u32,
+    // Counts up by the sample rate every CPU cycle, a sample is due when it reaches
+    // the CPU clock rate.
+    sample_clock: f64,
+    // The CPU cycles since the start of the frame counter's sequence.
+    frame_cycle: u64,
+    is_five_step: bool,
+    is_irq_inhibited: bool,
+    // Reading $4015 acknowledges the interrupt.
+    frame_interrupt: Cell<bool>,
+    // The pulse timers only run on every other CPU cycle.
+    is_odd_cycle: bool,
+    // The IRQ sources the bus was last told about, see take_irq_changes.
+    reported_irq: u8,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            pulse_1: Pulse::new(true),
+            pulse_2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            mixer: MixerSettings::default(),
+            samples: SampleBuffer::new(SAMPLE_BUFFER_CAPACITY),
+            stems: None,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_clock: 0.0,
+            frame_cycle: 0,
+            is_five_step: false,
+            is_irq_inhibited: false,
+            frame_interrupt: Cell::new(false),
+            is_odd_cycle: false,
+            reported_irq: 0,
+        }
+    }
+
+    /// Start the frame counter part way into its sequence, see `PowerUpState`.
+    pub fn power_up(&mut self, frame_cycle: u64) {
+        self.frame_cycle = frame_cycle;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Match the sample rate of the audio device. The buffered samples were made for
+    /// the old rate, so they're dropped.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate.max(1);
+        self.sample_clock = 0.0;
+        self.samples.clear();
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulse_1.write(address, value),
+            0x4004..=0x4007 => self.pulse_2.write(address, value),
+            0x4008..=0x400b => self.triangle.write(address, value),
+            0x400c..=0x400f => self.noise.write(address, value),
+            0x4010..=0x4013 => self.dmc.write(address, value),
+            APU_STATUS => {
+                // ---D NT21
+                self.pulse_1.length.set_enabled(value & 0b0001 != 0);
+                self.pulse_2.length.set_enabled(value & 0b0010 != 0);
+                self.triangle.length.set_enabled(value & 0b0100 != 0);
+                self.noise.length.set_enabled(value & 0b1000 != 0);
+                self.dmc.set_enabled(value & 0b1_0000 != 0);
+                self.dmc.is_interrupting = false;
+            }
+            APU_FRAME_COUNTER => {
+                // MI-- ----
+                self.is_five_step = value & 0b1000_0000 != 0;
+                self.is_irq_inhibited = value & 0b0100_0000 != 0;
+                if self.is_irq_inhibited {
+                    self.frame_interrupt.set(false);
+                }
+                self.frame_cycle = 0;
+                // The 5 step mode clocks the units right away.
+                if self.is_five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Read $4015, which reports the active channels and the interrupts. This
+    /// acknowledges the frame interrupt, but not the DMC's.
+    pub fn read_status(&self) -> u8 {
+        let value = self.peek_status();
+        self.frame_interrupt.set(false);
+        value
+    }
+
+    /// Read $4015 without acknowledging the frame interrupt, for debuggers.
+    pub fn peek_status(&self) -> u8 {
+        // IF-D NT21
+        (self.dmc.is_interrupting as u8) << 7
+            | (self.frame_interrupt.get() as u8) << 6
+            | (self.dmc.is_active() as u8) << 4
+            | (self.noise.length.is_active() as u8) << 3
+            | (self.triangle.length.is_active() as u8) << 2
+            | (self.pulse_2.length.is_active() as u8) << 1
+            | self.pulse_1.length.is_active() as u8
+    }
+
+    /// Run the APU for one CPU cycle. If the DMC needs a sample byte, the bus fetches it
+    /// beforehand with Dmc::pending_fetch.
+    pub fn tick(&mut self) {
+        self.is_odd_cycle = !self.is_odd_cycle;
+        if !self.is_odd_cycle {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        self.tick_frame_counter();
+
+        self.sample_clock += self.sample_rate as f64;
+        if self.sample_clock >= CPU_CLOCK_HZ {
+            self.sample_clock -= CPU_CLOCK_HZ;
+            let levels = self.levels();
+            self.samples.push(mix(&self.mixer, levels));
+            if let Some(ref mut stems) = self.stems {
+                let settings = MixerSettings::default();
+                for (index, stem) in stems.iter_mut().enumerate() {
+                    let mut solo_levels = [0; 5];
+                    solo_levels[index] = levels[index];
+                    stem.push(mix(&settings, solo_levels));
+                }
+            }
+        }
+    }
+
+    /// The frame counter clocks the envelopes and linear counter every quarter frame,
+    /// and the length counters and sweeps every half frame.
+    ///
+    /// https://www.nesdev.org/wiki/APU_Frame_Counter
+    fn tick_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        let (is_half_frame, is_last_step) = match (self.is_five_step, self.frame_cycle) {
+            (_, 7457) | (_, 22371) => (false, false),
+            (_, 14913) => (true, false),
+            (false, 29829) | (true, 37281) => (true, true),
+            _ => return,
+        };
+        self.clock_quarter_frame();
+        if is_half_frame {
+            self.clock_half_frame();
+        }
+        if is_last_step {
+            self.frame_cycle = 0;
+            if !self.is_five_step && !self.is_irq_inhibited {
+                self.frame_interrupt.set(true);
+            }
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.clock_quarter_frame();
+        self.pulse_2.clock_quarter_frame();
+        self.triangle.clock_quarter_frame();
+        self.noise.clock_quarter_frame();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.clock_half_frame();
+        self.pulse_2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+    /// The current output level of each channel, in the same order as CHANNELS.
+    pub fn levels(&self) -> [u8; 5] {
+        [
+            self.pulse_1.output(),
+            self.pulse_2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        ]
+    }
+
+    /// The APU's IRQ sources whose level changed since the last call. The bus applies
+    /// these to the shared line, rather than setting the level every time, so that
+    /// other code asserting the line isn't clobbered.
+    pub fn take_irq_changes(&mut self) -> Vec<(IrqSource, bool)> {
+        let irq = (self.frame_interrupt.get() as u8 * IrqSource::ApuFrameCounter as u8)
+            | (self.dmc.is_interrupting as u8 * IrqSource::Dmc as u8);
+        let changed = irq ^ self.reported_irq;
+        self.reported_irq = irq;
+        [IrqSource::ApuFrameCounter, IrqSource::Dmc]
+            .iter()
+            .copied()
+            .filter(|source| changed & *source as u8 != 0)
+            .map(|source| (source, irq & source as u8 != 0))
+            .collect()
+    }
+
+    /// The samples, mixer, and sample rate belong to the frontend and aren't saved.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.pulse_1.save_state(writer);
+        self.pulse_2.save_state(writer);
+        self.triangle.save_state(writer);
+        self.noise.save_state(writer);
+        self.dmc.save_state(writer);
+        writer.u64(self.frame_cycle);
+        writer.bool(self.is_five_step);
+        writer.bool(self.is_irq_inhibited);
+        writer.bool(self.frame_interrupt.get());
+        writer.bool(self.is_odd_cycle);
+        writer.u8(self.reported_irq);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.pulse_1.load_state(reader)?;
+        self.pulse_2.load_state(reader)?;
+        self.triangle.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.dmc.load_state(reader)?;
+        self.frame_cycle = reader.u64()?;
+        self.is_five_step = reader.bool()?;
+        self.is_irq_inhibited = reader.bool()?;
+        self.frame_interrupt.set(reader.bool()?);
+        self.is_odd_cycle = reader.bool()?;
+        self.reported_irq = reader.u8()?;
+        self.samples.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_interrupt() {
+        let mut apu = Apu::new();
+        for _ in 0..29828 {
+            apu.tick();
+        }
+        assert_eq!(apu.peek_status() & 0b0100_0000, 0);
+        apu.tick();
+        assert_eq!(apu.take_irq_changes(), [(IrqSource::ApuFrameCounter, true)]);
+        assert_eq!(apu.take_irq_changes(), []);
+
+        // Reading the status acknowledges it.
+        assert_eq!(apu.read_status(), 0b0100_0000);
+        assert_eq!(apu.read_status(), 0);
+        assert_eq!(
+            apu.take_irq_changes(),
+            [(IrqSource::ApuFrameCounter, false)]
+        );
+
+        // Inhibiting the IRQ, or the 5 step mode, keep it from firing.
+        for value in [0b0100_0000, 0b1000_0000] {
+            apu.write_register(APU_FRAME_COUNTER, value);
+            for _ in 0..40000 {
+                apu.tick();
+            }
+            assert_eq!(apu.peek_status(), 0);
+        }
+    }
+
+    #[test]
+    fn test_samples() {
+        let mut apu = Apu::new();
+        // Enable pulse 1 with a constant volume, at about 440hz.
+        apu.write_register(APU_STATUS, 0b0001);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0xfd);
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.peek_status(), 0b0001);
+
+        // A frame's worth of CPU cycles.
+        for _ in 0..29781 {
+            apu.tick();
+        }
+        let mut samples = vec![0.0; 1000];
+        let count = apu.samples.drain_into(&mut samples);
+        assert_eq!(count, 733);
+        let high = mix(&apu.mixer, [15, 0, 15, 0, 0]);
+        let low = mix(&apu.mixer, [0, 0, 15, 0, 0]);
+        assert!(samples[..count].contains(&high));
+        assert!(samples[..count].contains(&low));
+    }
+
+    #[test]
+    fn test_mix() {
+        let settings = MixerSettings::default();
+        assert_eq!(mix(&settings, [0, 0, 0, 0, 0]), 0.0);
+        // Every channel at full volume is close to 1.0.
+        let full = mix(&settings, [15, 15, 15, 15, 127]);
+        assert!((full - 1.0).abs() < 0.01, "{}", full);
+    }
+
+    #[test]
+    fn test_gain_and_mute() {
+        let mut settings = MixerSettings::default();
+        let levels = [15, 15, 15, 0, 0];
+        let full = mix(&settings, levels);
+
+        // Isolate the triangle.
+        settings.set_gain(Channel::Pulse1, 0.0);
+        settings.set_gain(Channel::Pulse2, 0.0);
+        assert_eq!(mix(&settings, levels), mix(&settings, [0, 0, 15, 0, 0]));
+
+        settings.set_gain(Channel::Pulse1, 1.0);
+        settings.set_gain(Channel::Pulse2, 1.0);
+        settings.master_volume = 0.5;
+        assert_eq!(mix(&settings, levels), full * 0.5);
+
+        settings.toggle_mute();
+        assert_eq!(mix(&settings, levels), 0.0);
+    }
+
+    #[test]
+    fn test_solo() {
+        let mut settings = MixerSettings::default();
+        let levels = [15, 15, 15, 15, 0];
+        settings.toggle_solo(Channel::Triangle);
+        assert!(!settings.is_audible(Channel::Pulse1));
+        assert_eq!(mix(&settings, levels), mix(&settings, [0, 0, 15, 0, 0]));
+
+        settings.toggle_solo(Channel::Noise);
+        assert_eq!(mix(&settings, levels), mix(&settings, [0, 0, 15, 15, 0]));
+
+        settings.toggle_solo(Channel::Triangle);
+        settings.toggle_solo(Channel::Noise);
+        assert!(CHANNELS.iter().all(|channel| settings.is_audible(*channel)));
+    }
+
+    #[test]
+    fn test_stems() {
+        let mut apu = Apu::new();
+        apu.stems = Some(Default::default());
+        // Turn on the triangle with a long period, and let its level ramp.
+        apu.write_register(APU_STATUS, 0b0100);
+        apu.write_register(0x4008, 0xff);
+        apu.write_register(0x400a, 0xff);
+        apu.write_register(0x400b, 0xff);
+        for _ in 0..20_000 {
+            apu.tick();
+        }
+        let stems = apu.stems.take().unwrap();
+        assert_eq!(stems[0].len(), apu.samples.len());
+        assert!(stems[0].iter().all(|sample| *sample == 0.0));
+        assert!(stems[2].iter().any(|sample| *sample > 0.0));
+    }
+
+    #[test]
+    fn test_config_round_trip() {
+        let mut settings = MixerSettings {
+            master_volume: 0.25,
+            is_muted: true,
+            ..Default::default()
+        };
+        settings.set_gain(Channel::Noise, 0.5);
+        let text = settings.to_config_string();
+        assert_eq!(
+            text,
+            "master_volume = 0.25\nmuted = true\npulse1 = 1\npulse2 = 1\ntriangle = 1\n\
+             noise = 0.5\ndmc = 1\n"
+        );
+        assert_eq!(MixerSettings::from_config_str(&text), Ok(settings));
+    }
+
+    #[test]
+    fn test_config_errors() {
+        assert_eq!(
+            MixerSettings::from_config_str("reverb = 1"),
+            Err("Unknown mixer setting \"reverb\"".into())
+        );
+        assert_eq!(
+            MixerSettings::from_config_str("dmc = loud"),
+            Err("Expected a number for \"dmc\", found \"loud\"".into())
+        );
+    }
+}
@@ -0,0 +1,740 @@
+use crate::save_state::{StateReader, StateWriter};
+
+/// The length counter is loaded through an index into this table, in APU half frames.
+///
+/// https://www.nesdev.org/wiki/APU_Length_Counter
+#[rustfmt::skip]
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20,  2, 40,  4, 80,  6, 160,  8, 60, 10, 14, 12, 26, 14,
+    12,  16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// The waveforms of the pulse channels, selected by the duty cycle.
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+#[rustfmt::skip]
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10,  9,  8,  7,  6,  5,  4,  3,  2,  1,  0,
+     0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15,
+];
+
+/// The NTSC noise periods, in CPU cycles.
+const NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+/// The NTSC DMC rates, in CPU cycles.
+const DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+/// Silences a channel after a number of half frames, unless it's halted.
+#[derive(Debug, Default)]
+pub struct LengthCounter {
+    pub is_enabled: bool,
+    pub is_halted: bool,
+    pub value: u8,
+}
+
+impl LengthCounter {
+    pub fn set_enabled(&mut self, is_enabled: bool) {
+        self.is_enabled = is_enabled;
+        if !is_enabled {
+            self.value = 0;
+        }
+    }
+
+    /// The top 5 bits of the channel's 4th register.
+    pub fn load(&mut self, register: u8) {
+        if self.is_enabled {
+            self.value = LENGTH_TABLE[(register >> 3) as usize];
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.is_halted && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.value > 0
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.is_enabled);
+        writer.bool(self.is_halted);
+        writer.u8(self.value);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.is_enabled = reader.bool()?;
+        self.is_halted = reader.bool()?;
+        self.value = reader.u8()?;
+        Ok(())
+    }
+}
+
+/// Either a constant volume, or a sawtooth that decays from 15 to 0.
+///
+/// https://www.nesdev.org/wiki/APU_Envelope
+#[derive(Debug, Default)]
+pub struct Envelope {
+    is_start: bool,
+    is_looping: bool,
+    is_constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    /// The --LC VVVV bits shared by the pulse and noise registers.
+    fn write(&mut self, value: u8) {
+        self.is_looping = value & 0b0010_0000 != 0;
+        self.is_constant = value & 0b0001_0000 != 0;
+        self.volume = value & 0b1111;
+    }
+
+    fn restart(&mut self) {
+        self.is_start = true;
+    }
+
+    fn clock(&mut self) {
+        if self.is_start {
+            self.is_start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.is_looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.is_constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.is_start);
+        writer.bool(self.is_looping);
+        writer.bool(self.is_constant);
+        writer.u8(self.volume);
+        writer.u8(self.divider);
+        writer.u8(self.decay);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.is_start = reader.bool()?;
+        self.is_looping = reader.bool()?;
+        self.is_constant = reader.bool()?;
+        self.volume = reader.u8()?;
+        self.divider = reader.u8()?;
+        self.decay = reader.u8()?;
+        Ok(())
+    }
+}
+
+/// The two square wave channels at $4000-$4003 and $4004-$4007.
+///
+/// https://www.nesdev.org/wiki/APU_Pulse
+#[derive(Debug, Default)]
+pub struct Pulse {
+    // The sweep units differ by how they negate, pulse 1 uses the ones' complement.
+    is_pulse_1: bool,
+    pub length: LengthCounter,
+    envelope: Envelope,
+    duty: u8,
+    step: u8,
+    timer: u16,
+    period: u16,
+    sweep_is_enabled: bool,
+    sweep_period: u8,
+    sweep_is_negated: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub fn new(is_pulse_1: bool) -> Pulse {
+        Pulse {
+            is_pulse_1,
+            ..Default::default()
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register & 0b11 {
+            0 => {
+                // DDLC VVVV
+                self.duty = value >> 6;
+                self.length.is_halted = value & 0b0010_0000 != 0;
+                self.envelope.write(value);
+            }
+            1 => {
+                // EPPP NSSS
+                self.sweep_is_enabled = value & 0b1000_0000 != 0;
+                self.sweep_period = (value >> 4) & 0b111;
+                self.sweep_is_negated = value & 0b1000 != 0;
+                self.sweep_shift = value & 0b111;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x700) | value as u16,
+            _ => {
+                // LLLL LTTT
+                self.period = (self.period & 0xff) | ((value as u16 & 0b111) << 8);
+                self.length.load(value);
+                self.envelope.restart();
+                self.step = 0;
+            }
+        }
+    }
+
+    /// The pulse timers run at half the CPU clock.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+        if self.sweep_divider == 0
+            && self.sweep_is_enabled
+            && self.sweep_shift > 0
+            && !self.is_sweep_muting()
+        {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if self.sweep_is_negated {
+            let change = change + self.is_pulse_1 as u16;
+            self.period.saturating_sub(change)
+        } else {
+            self.period + change
+        }
+    }
+
+    /// The sweep unit mutes the channel even when it's disabled.
+    fn is_sweep_muting(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7ff
+    }
+
+    /// The level from 0-15.
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active()
+            || self.is_sweep_muting()
+            || DUTY_TABLE[self.duty as usize][self.step as usize] == 0
+        {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.u8(self.duty);
+        writer.u8(self.step);
+        writer.u16(self.timer);
+        writer.u16(self.period);
+        writer.bool(self.sweep_is_enabled);
+        writer.u8(self.sweep_period);
+        writer.bool(self.sweep_is_negated);
+        writer.u8(self.sweep_shift);
+        writer.u8(self.sweep_divider);
+        writer.bool(self.sweep_reload);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.duty = reader.u8()? & 0b11;
+        self.step = reader.u8()? % 8;
+        self.timer = reader.u16()?;
+        self.period = reader.u16()? & 0x7ff;
+        self.sweep_is_enabled = reader.bool()?;
+        self.sweep_period = reader.u8()?;
+        self.sweep_is_negated = reader.bool()?;
+        self.sweep_shift = reader.u8()? & 0b111;
+        self.sweep_divider = reader.u8()?;
+        self.sweep_reload = reader.bool()?;
+        Ok(())
+    }
+}
+
+/// The triangle channel at $4008-$400B. It has no volume control, but a second linear
+/// counter for finer control over the note length.
+///
+/// https://www.nesdev.org/wiki/APU_Triangle
+#[derive(Debug, Default)]
+pub struct Triangle {
+    pub length: LengthCounter,
+    step: u8,
+    timer: u16,
+    period: u16,
+    linear_counter: u8,
+    linear_reload_value: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register & 0b11 {
+            0 => {
+                // CRRR RRRR, the control flag doubles as the length counter halt.
+                self.length.is_halted = value & 0b1000_0000 != 0;
+                self.linear_reload_value = value & 0b0111_1111;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x700) | value as u16,
+            _ => {
+                self.period = (self.period & 0xff) | ((value as u16 & 0b111) << 8);
+                self.length.load(value);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    /// The triangle timer runs at the CPU clock.
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length.is_active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        // The control flag keeps the counter reloading.
+        if !self.length.is_halted {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    /// The level from 0-15. A silenced triangle holds its last level rather than
+    /// dropping to 0, which avoids a pop.
+    pub fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.step as usize]
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.length.save_state(writer);
+        writer.u8(self.step);
+        writer.u16(self.timer);
+        writer.u16(self.period);
+        writer.u8(self.linear_counter);
+        writer.u8(self.linear_reload_value);
+        writer.bool(self.linear_reload);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.length.load_state(reader)?;
+        self.step = reader.u8()? % 32;
+        self.timer = reader.u16()?;
+        self.period = reader.u16()? & 0x7ff;
+        self.linear_counter = reader.u8()?;
+        self.linear_reload_value = reader.u8()?;
+        self.linear_reload = reader.bool()?;
+        Ok(())
+    }
+}
+
+/// The noise channel at $400C-$400F, a pseudo-random bit stream from a linear feedback
+/// shift register.
+///
+/// https://www.nesdev.org/wiki/APU_Noise
+#[derive(Debug)]
+pub struct Noise {
+    pub length: LengthCounter,
+    envelope: Envelope,
+    // The short mode taps bit 6 rather than bit 1, for a metallic tone.
+    is_short_mode: bool,
+    timer: u16,
+    period: u16,
+    shift_register: u16,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+            is_short_mode: false,
+            timer: 0,
+            period: NOISE_PERIODS[0],
+            // The register is loaded with 1 on power up, it would be stuck at 0
+            // otherwise.
+            shift_register: 1,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register & 0b11 {
+            0 => {
+                self.length.is_halted = value & 0b0010_0000 != 0;
+                self.envelope.write(value);
+            }
+            1 => {}
+            2 => {
+                // M--- PPPP
+                self.is_short_mode = value & 0b1000_0000 != 0;
+                self.period = NOISE_PERIODS[(value & 0b1111) as usize];
+            }
+            _ => {
+                self.length.load(value);
+                self.envelope.restart();
+            }
+        }
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.is_short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        self.length.clock();
+    }
+
+    /// The level from 0-15.
+    pub fn output(&self) -> u8 {
+        if !self.length.is_active() || self.shift_register & 1 == 1 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.bool(self.is_short_mode);
+        writer.u16(self.timer);
+        writer.u16(self.period);
+        writer.u16(self.shift_register);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.is_short_mode = reader.bool()?;
+        self.timer = reader.u16()?;
+        self.period = reader.u16()?.max(1);
+        self.shift_register = reader.u16()? & 0x7fff;
+        Ok(())
+    }
+}
+
+/// The delta modulation channel at $4010-$4013 plays 1-bit delta encoded samples that
+/// are read from the cartridge.
+///
+/// https://www.nesdev.org/wiki/APU_DMC
+#[derive(Debug)]
+pub struct Dmc {
+    pub is_irq_enabled: bool,
+    pub is_interrupting: bool,
+    is_looping: bool,
+    timer: u16,
+    period: u16,
+    level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    is_silent: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Dmc {
+        Dmc {
+            is_irq_enabled: false,
+            is_interrupting: false,
+            is_looping: false,
+            timer: 0,
+            period: DMC_RATES[0],
+            level: 0,
+            sample_address: 0xc000,
+            sample_length: 1,
+            current_address: 0xc000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            is_silent: true,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register & 0b11 {
+            0 => {
+                // IL-- RRRR
+                self.is_irq_enabled = value & 0b1000_0000 != 0;
+                self.is_looping = value & 0b0100_0000 != 0;
+                self.period = DMC_RATES[(value & 0b1111) as usize];
+                if !self.is_irq_enabled {
+                    self.is_interrupting = false;
+                }
+            }
+            1 => self.level = value & 0b0111_1111,
+            2 => self.sample_address = 0xc000 + value as u16 * 64,
+            _ => self.sample_length = value as u16 * 16 + 1,
+        }
+    }
+
+    /// Writing to $4015 starts or stops the sample.
+    pub fn set_enabled(&mut self, is_enabled: bool) {
+        if !is_enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    /// The address of the next sample byte, if the reader needs one. The bus does the
+    /// read, as the sample lives in the cartridge.
+    pub fn pending_fetch(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    pub fn fill_sample_buffer(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+        // The address wraps around to $8000 rather than $0000.
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.is_looping {
+                self.restart();
+            } else if self.is_irq_enabled {
+                self.is_interrupting = true;
+            }
+        }
+    }
+
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+
+        if !self.is_silent {
+            // Each bit moves the level up or down by 2, but it doesn't wrap around.
+            if self.shift_register & 1 == 1 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(value) => {
+                    self.is_silent = false;
+                    self.shift_register = value;
+                }
+                None => self.is_silent = true,
+            }
+        }
+    }
+
+    /// The level from 0-127.
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.is_irq_enabled);
+        writer.bool(self.is_interrupting);
+        writer.bool(self.is_looping);
+        writer.u16(self.timer);
+        writer.u16(self.period);
+        writer.u8(self.level);
+        writer.u16(self.sample_address);
+        writer.u16(self.sample_length);
+        writer.u16(self.current_address);
+        writer.u16(self.bytes_remaining);
+        // The empty buffer is written as a flag and a value.
+        writer.bool(self.sample_buffer.is_some());
+        writer.u8(self.sample_buffer.unwrap_or(0));
+        writer.u8(self.shift_register);
+        writer.u8(self.bits_remaining);
+        writer.bool(self.is_silent);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.is_irq_enabled = reader.bool()?;
+        self.is_interrupting = reader.bool()?;
+        self.is_looping = reader.bool()?;
+        self.timer = reader.u16()?;
+        self.period = reader.u16()?.max(1);
+        self.level = reader.u8()? & 0b0111_1111;
+        self.sample_address = reader.u16()?;
+        self.sample_length = reader.u16()?;
+        self.current_address = reader.u16()?;
+        self.bytes_remaining = reader.u16()?;
+        let has_sample = reader.bool()?;
+        let sample = reader.u8()?;
+        self.sample_buffer = has_sample.then_some(sample);
+        self.shift_register = reader.u8()?;
+        self.bits_remaining = reader.u8()?.clamp(1, 8);
+        self.is_silent = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pulse_sweep_mutes() {
+        let mut pulse = Pulse::new(true);
+        pulse.length.set_enabled(true);
+        fn levels(pulse: &mut Pulse) -> Vec<u8> {
+            (0..8)
+                .map(|_| {
+                    // Skip through the timer to the next step.
+                    pulse.timer = 0;
+                    pulse.clock_timer();
+                    pulse.output()
+                })
+                .collect()
+        }
+        // Constant volume 15, 50% duty, and a period of $3ff.
+        pulse.write(0x4000, 0b1011_1111);
+        pulse.write(0x4002, 0xff);
+        pulse.write(0x4003, 0b0000_1011);
+        assert_eq!(pulse.length.value, 254);
+        assert!(levels(&mut pulse).contains(&15));
+
+        // With a shift of 0 the target of $400 is $800, which is out of range. This
+        // mutes the channel even though the sweep is disabled.
+        pulse.write(0x4002, 0x00);
+        pulse.write(0x4003, 0b0000_1100);
+        assert_eq!(levels(&mut pulse), [0; 8]);
+
+        // Negating the sweep brings the target back into range.
+        pulse.write(0x4001, 0b0000_1000);
+        assert!(levels(&mut pulse).contains(&15));
+    }
+
+    #[test]
+    fn test_length_counter() {
+        let mut noise = Noise::new();
+        noise.write(0x400f, 0b0000_1000);
+        // The channel is disabled, so the load is ignored.
+        assert_eq!(noise.length.value, 0);
+
+        noise.length.set_enabled(true);
+        noise.write(0x400f, 0b0000_1000);
+        assert_eq!(noise.length.value, 254);
+        noise.clock_half_frame();
+        assert_eq!(noise.length.value, 253);
+
+        // Halting keeps the note playing.
+        noise.write(0x400c, 0b0010_0000);
+        noise.clock_half_frame();
+        assert_eq!(noise.length.value, 253);
+    }
+
+    #[test]
+    fn test_dmc_sample() {
+        let mut dmc = Dmc::new();
+        // Rate 15, $c040, 17 bytes.
+        dmc.write(0x4010, 0b1000_1111);
+        dmc.write(0x4012, 0x01);
+        dmc.write(0x4013, 0x01);
+        dmc.write(0x4011, 64);
+        dmc.set_enabled(true);
+
+        let mut fetches = vec![];
+        for _ in 0..(54 * 8 * 17 + 54) {
+            if let Some(address) = dmc.pending_fetch() {
+                fetches.push(address);
+                // Every bit set, so the level rises.
+                dmc.fill_sample_buffer(0xff);
+            }
+            dmc.clock_timer();
+        }
+        assert_eq!(fetches.len(), 17);
+        assert_eq!(fetches[0], 0xc040);
+        assert_eq!(fetches[16], 0xc050);
+        assert!(!dmc.is_active());
+        assert!(dmc.is_interrupting);
+        assert_eq!(dmc.output(), 126);
+    }
+}
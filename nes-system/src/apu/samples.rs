@@ -0,0 +1,94 @@
+/// A fixed size ring buffer of mixed samples, from 0.0 to 1.0. The emulator pushes
+/// samples as it runs, and the frontend drains them into its audio device. If the
+/// frontend falls behind, the oldest samples are overwritten so that the latency
+/// doesn't keep growing.
+pub struct SampleBuffer {
+    samples: Vec<f32>,
+    // The index of the oldest sample.
+    start: usize,
+    len: usize,
+}
+
+impl SampleBuffer {
+    pub fn new(capacity: usize) -> SampleBuffer {
+        SampleBuffer {
+            samples: vec![0.0; capacity.max(1)],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        let end = (self.start + self.len) % self.capacity();
+        self.samples[end] = sample;
+        if self.len == self.capacity() {
+            // Overwrite the oldest sample.
+            self.start = (self.start + 1) % self.capacity();
+        } else {
+            self.len += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<f32> {
+        if self.len == 0 {
+            return None;
+        }
+        let sample = self.samples[self.start];
+        self.start = (self.start + 1) % self.capacity();
+        self.len -= 1;
+        Some(sample)
+    }
+
+    /// Move as many samples as fit into the output, oldest first, and return how many
+    /// were written. This is the shape an audio callback wants.
+    pub fn drain_into(&mut self, output: &mut [f32]) -> usize {
+        let count = output.len().min(self.len);
+        for sample in output.iter_mut().take(count) {
+            *sample = self.pop().expect("The count is limited by the length.");
+        }
+        count
+    }
+
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut buffer = SampleBuffer::new(4);
+        assert_eq!(buffer.pop(), None);
+        for sample in [0.1, 0.2, 0.3] {
+            buffer.push(sample);
+        }
+        assert_eq!(buffer.pop(), Some(0.1));
+
+        // Wrap around, and then overwrite the oldest sample.
+        for sample in [0.4, 0.5, 0.6] {
+            buffer.push(sample);
+        }
+        assert_eq!(buffer.len(), 4);
+
+        let mut output = [0.0; 6];
+        assert_eq!(buffer.drain_into(&mut output), 4);
+        assert_eq!(output, [0.3, 0.4, 0.5, 0.6, 0.0, 0.0]);
+        assert!(buffer.is_empty());
+    }
+}
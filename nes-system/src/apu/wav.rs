@@ -0,0 +1,51 @@
+//! Writes samples out as a WAV file, for exporting the audio. It's always mono 16 bit
+//! PCM, which every audio tool can open.
+//!
+//! http://soundfile.sapp.org/doc/WaveFormat/
+
+const BITS_PER_SAMPLE: u16 = 16;
+
+/// Encode samples from 0.0 to 1.0, like the APU mixes, into a WAV file. Silence stays
+/// at 0 rather than being centered, so that quiet stems don't have a DC offset.
+pub fn encode_wav(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    let bytes_per_sample = (BITS_PER_SAMPLE / 8) as u32;
+    let data_len = samples.len() as u32 * bytes_per_sample;
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, with 1 channel.
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * bytes_per_sample).to_le_bytes());
+    wav.extend_from_slice(&(bytes_per_sample as u16).to_le_bytes());
+    wav.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        let value = (sample.clamp(0.0, 1.0) * i16::MAX as f32) as i16;
+        wav.extend_from_slice(&value.to_le_bytes());
+    }
+    wav
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_wav() {
+        let wav = encode_wav(&[0.0, 1.0, 0.5], 44_100);
+        assert_eq!(wav.len(), 44 + 6);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[4..8], &42u32.to_le_bytes());
+        assert_eq!(&wav[24..28], &44_100u32.to_le_bytes());
+        assert_eq!(&wav[40..44], &6u32.to_le_bytes());
+        assert_eq!(&wav[44..], [0x00, 0x00, 0xff, 0x7f, 0xff, 0x3f]);
+    }
+}
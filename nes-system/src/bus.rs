@@ -0,0 +1,814 @@
+use super::constants::{memory_range, InterruptVectors};
+use crate::apu::{is_apu_register, Apu, APU_STATUS};
+use crate::controller::Controller;
+use crate::irq::{IrqLine, IrqSource};
+use crate::mappers::{BankedChr, CartridgeChr, ChrBankFrame, FallbackReport, Mapper};
+use crate::memory_map::{self, MemoryRegion};
+use crate::ppu::registers::OAMDATA;
+use crate::ppu::render::{
+    self, PatternTables, PixelInspection, Ppu, PpuState, RenderStrategy, SCREEN_HEIGHT,
+};
+use crate::ppu::{Mirroring, DOTS_PER_CPU_CYCLE, DOTS_PER_FRAME, DOTS_PER_SCANLINE};
+use crate::save_state::{StateReader, StateWriter};
+use crate::write_log::WriteLog;
+pub use mos6502_core::bus::CpuBus;
+use std::cell::Cell;
+
+/// The bus contains the actual memory used by the emulator. The CPU owns it, and
+/// everything else reaches it through the CPU, so the whole emulator is Send and can
+/// be moved to another thread.
+pub struct Bus {
+    // Includes the zero page, stack, and ram.
+    //
+    // $2000 |-------------------------|-------------------------| $2000
+    //       |   Mirrors $0000 - $07FF |                         |
+    // $0800 |-------------------------|                         |
+    //       |   General RAM           |                         |
+    // $0200 |-------------------------|           RAM           |
+    //       |   Stack                 |                         |
+    // $0100 |-------------------------|                         |
+    //       |   Zero Page             |                         |
+    // $0000 |-------------------------|-------------------------| $0000
+    ram: [u8; memory_range::RAM.end as usize],
+    cartridge: Box<dyn Mapper>,
+    pub controller_1: Controller,
+    pub controller_2: Controller,
+    /// The recent writes, for debuggers. It's off until it's given a capacity.
+    pub write_log: WriteLog,
+    pub irq: IrqLine,
+    pub ppu: Ppu,
+    pub apu: Apu,
+    // When the DMC DMA halts the CPU on a read from a controller port, the extra read
+    // clocks the controller's shift register, and a button is lost. Games like Super
+    // Mario Bros. 3 read the controller multiple times to work around this.
+    pub emulate_dmc_dma_controller_glitch: bool,
+    /// The page that was written to OAMDMA, which is copied once the instruction that
+    /// wrote it is done.
+    oam_dma_page: Option<u8>,
+    /// The cycles that the DMC's sample fetches have taken from the CPU, which it's
+    /// halted for once the instruction is done.
+    dmc_dma_stall: u64,
+    /// The controller port that the current instruction read, see
+    /// `Bus::dmc_dma_read_conflict`.
+    controller_read: Cell<Option<u16>>,
+    /// The dots the PPU started ahead of the CPU, see `Bus::align_ppu`.
+    pub(crate) ppu_alignment: u8,
+    /// The last value driven on the data bus. Nothing drives it for an unmapped
+    /// address, so reading one gets this value back, which is usually the high byte of
+    /// the address from the operand.
+    /// https://www.nesdev.org/wiki/Open_bus_behavior
+    open_bus: Cell<u8>,
+    // The CHR banks of the frame that's being drawn, and of the last complete frame.
+    drawing_chr_bank_frame: ChrBankFrame,
+    chr_bank_frame: ChrBankFrame,
+}
+
+/// The controller port registers. Writes to $4016 strobe both controllers, as they
+/// share the OUT0 line, while writes to $4017 go to the APU frame counter.
+pub const CONTROLLER_1: u16 = 0x4016;
+pub const CONTROLLER_2: u16 = 0x4017;
+
+/// Writing a page number here copies that page of CPU memory into OAM, and halts the
+/// CPU while it does.
+/// https://www.nesdev.org/wiki/PPU_registers#OAMDMA
+pub const OAM_DMA: u16 = 0x4014;
+
+/// The cycles that the DMC halts the CPU for to fetch a sample byte. It's fewer when
+/// the halt lands on a write cycle, or in the middle of an OAM DMA, which isn't
+/// tracked, as the CPU runs whole instructions.
+/// https://www.nesdev.org/wiki/DMA#DMC_DMA
+pub const DMC_DMA_CYCLES: u64 = 4;
+
+/// An interrupt vector as seen by a debugger, along with where it points.
+pub struct VectorTarget {
+    pub name: &'static str,
+    pub vector: u16,
+    pub target: u16,
+    // Describes an obviously broken target, which usually means the vector was never
+    // set in the assembly.
+    pub problem: Option<&'static str>,
+}
+
+/// $2000-$2007, mirrored every 8 bytes up to $3FFF.
+fn is_ppu_register(address: u16) -> bool {
+    (memory_range::PPU_ACTUAL.start..memory_range::PPU.end).contains(&address)
+}
+
+impl Bus {
+    pub fn new(cartridge: Box<dyn Mapper>) -> Bus {
+        let ppu = Ppu::new(PpuState::new(cartridge.mirroring()), RenderStrategy::Dot);
+        let drawing_chr_bank_frame = ChrBankFrame {
+            banks: cartridge.chr_banks(),
+            switches: vec![],
+        };
+        Bus {
+            // Little endian memory store, 2 kilobytes in size.
+            ram: [0; memory_range::RAM.end as usize],
+            cartridge,
+            controller_1: Controller::new(),
+            controller_2: Controller::new(),
+            write_log: WriteLog::new(0),
+            irq: IrqLine::new(),
+            ppu,
+            apu: Apu::new(),
+            emulate_dmc_dma_controller_glitch: true,
+            oam_dma_page: None,
+            dmc_dma_stall: 0,
+            controller_read: Cell::new(None),
+            ppu_alignment: 0,
+            open_bus: Cell::new(0),
+            drawing_chr_bank_frame,
+            chr_bank_frame: ChrBankFrame::default(),
+        }
+    }
+
+    // The bus behaves similar to an NES, as the address range is larger than the actual
+    // bits that are pointed at. This function maps the address to the actual bit range.
+    fn map_ram_address(&self, address: u16) -> u16 {
+        if address < memory_range::RAM.end {
+            // $0000-$07FF  $0800  2KB internal RAM
+            // $0800-$0FFF  $0800  Mirrors of $0000-$07FF
+            // $1000-$17FF  $0800
+            // $1800-$1FFF  $0800
+            return memory_range::RAM_ACTUAL.mask() & address;
+        }
+
+        address
+    }
+
+    pub fn read_u8(&self, address: u16) -> u8 {
+        if address == APU_STATUS {
+            // The APU is inside the CPU, so its status doesn't go out on the data bus,
+            // and bit 5 isn't connected.
+            return self.apu.read_status() & !0b0010_0000
+                | self.open_bus.get() & 0b0010_0000;
+        }
+        let value = self.read_data_bus(address);
+        self.open_bus.set(value);
+        value
+    }
+
+    fn read_data_bus(&self, address: u16) -> u8 {
+        if let Some(value) = self.cartridge.read_cpu(address) {
+            return value;
+        }
+        // The controllers only drive the low bits.
+        if address == CONTROLLER_1 {
+            self.controller_read.set(Some(address));
+            return self.controller_1.read() | self.open_bus.get() & 0b1110_0000;
+        }
+        if address == CONTROLLER_2 {
+            self.controller_read.set(Some(address));
+            return self.controller_2.read() | self.open_bus.get() & 0b1110_0000;
+        }
+        if is_ppu_register(address) {
+            let chr = CartridgeChr(&*self.cartridge);
+            return self.ppu.read_register(&chr, address);
+        }
+        if address >= memory_range::RAM.end {
+            return self.open_bus.get();
+        }
+        self.ram[self.map_ram_address(address) as usize]
+    }
+
+    /// Read the CPU address space for debuggers. Reading the PPU, APU, and controller
+    /// registers changes their state, so they read as 0 here.
+    pub fn peek_u8(&self, address: u16) -> u8 {
+        if let Some(value) = self.cartridge.peek_cpu(address) {
+            return value;
+        }
+        if address >= memory_range::RAM.end {
+            return 0;
+        }
+        self.ram[self.map_ram_address(address) as usize]
+    }
+
+    /// The 2KB of internal RAM, without the mirrors.
+    pub fn ram(&self) -> &[u8] {
+        &self.ram[..memory_range::RAM_ACTUAL.end as usize]
+    }
+
+    /// Change the internal RAM without the side effects of a write, like the open bus
+    /// and the write log.
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram[..memory_range::RAM_ACTUAL.end as usize]
+    }
+
+    pub fn set_u8(&mut self, address: u16, value: u8) {
+        self.open_bus.set(value);
+        if self.write_log.is_enabled() {
+            let frame = self.ppu.frame_count();
+            self.write_log.push(address, value, frame);
+        }
+        if address == CONTROLLER_1 {
+            self.controller_1.write(value);
+            self.controller_2.write(value);
+            return;
+        }
+        if address == OAM_DMA {
+            self.oam_dma_page = Some(value);
+            return;
+        }
+        if is_apu_register(address) {
+            self.apu.write_register(address, value);
+            self.update_apu_irq();
+            return;
+        }
+        if self.cartridge.write_cpu(address, value) {
+            return;
+        }
+        if is_ppu_register(address) {
+            let mut chr = CartridgeChr(&mut *self.cartridge);
+            self.ppu.write_register(&mut chr, address, value);
+            return;
+        }
+        if address >= memory_range::RAM.end {
+            // Nothing is mapped here yet, drop the write.
+            return;
+        }
+        self.ram[self.map_ram_address(address) as usize] = value;
+    }
+
+    /// Read the PPU address space without the side effects of going through PPUDATA,
+    /// for debuggers.
+    pub fn peek_ppu(&self, address: u16) -> u8 {
+        let address = address & 0x3fff;
+        match address {
+            0x0000..=0x1fff => CartridgeChr(&*self.cartridge).read_chr(address),
+            0x2000..=0x3eff => self
+                .ppu
+                .state
+                .read_nametable(&CartridgeChr(&*self.cartridge), address),
+            _ => self.ppu.state.palette_ram.read(address),
+        }
+    }
+
+    /// Write the PPU address space without moving the PPUADDR or the read buffer, for
+    /// debuggers that change the graphics while the game is paused.
+    pub fn poke_ppu(&mut self, address: u16, value: u8) {
+        let address = address & 0x3fff;
+        let mut chr = CartridgeChr(&mut *self.cartridge);
+        match address {
+            0x0000..=0x1fff => chr.write_chr(address, value),
+            0x2000..=0x3eff => self.ppu.state.write_nametable(&mut chr, address, value),
+            _ => self.ppu.state.palette_ram.write(address, value),
+        }
+    }
+
+    /// Draw a pattern table through the CHR banks that are switched in right now, see
+    /// `pattern_table_pixels`.
+    pub fn pattern_table_pixels(&self, base: u16) -> Vec<u8> {
+        render::pattern_table_pixels(&CartridgeChr(&*self.cartridge), base)
+    }
+
+    /// Draw a pattern table through the CHR banks that a scanline of the last frame was
+    /// drawn with, see `chr_bank_frame`. Cartridges that don't report their banks are
+    /// drawn through the ones that are switched in right now.
+    pub fn frame_pattern_table_pixels(&self, base: u16, scanline: u64) -> Vec<u8> {
+        let banks = self.chr_bank_frame.banks_at(scanline);
+        if banks.is_empty() {
+            return self.pattern_table_pixels(base);
+        }
+        let chr = BankedChr {
+            data: self.cartridge.chr_data(),
+            banks,
+        };
+        render::pattern_table_pixels(&chr, base)
+    }
+
+    /// Draw the 4 logical nametables from the live VRAM, see `nametable_colors`.
+    pub fn nametable_colors(&self) -> Vec<u8> {
+        self.ppu
+            .state
+            .nametable_colors(&CartridgeChr(&*self.cartridge))
+    }
+
+    /// Draw one of the 64 sprites from OAM, see `sprite_pixels`.
+    pub fn sprite_pixels(&self, sprite: usize) -> Vec<u8> {
+        self.ppu
+            .state
+            .sprite_pixels(&CartridgeChr(&*self.cartridge), sprite)
+    }
+
+    /// Where the screen is in the 4 logical nametables, see `scroll_position`.
+    pub fn scroll_position(&self) -> (u16, u16) {
+        self.ppu.state.scroll_position()
+    }
+
+    /// Read the NMI, RESET, and IRQ/BRK vectors for debuggers.
+    pub fn interrupt_vectors(&self) -> [VectorTarget; 3] {
+        [
+            ("NMI", InterruptVectors::NonMaskableInterrupt),
+            ("RESET", InterruptVectors::ResetVector),
+            ("IRQ", InterruptVectors::IrqBrkVector),
+        ]
+        .map(|(name, vector)| {
+            let vector = vector as u16;
+            let target =
+                u16::from_le_bytes([self.peek_u8(vector), self.peek_u8(vector + 1)]);
+            VectorTarget {
+                name,
+                vector,
+                target,
+                problem: self.vector_target_problem(target),
+            }
+        })
+    }
+
+    /// RAM, or memory that the cartridge maps in. The rest is registers and open bus,
+    /// which no program runs code from on purpose.
+    pub fn is_mapped_memory(&self, address: u16) -> bool {
+        address < memory_range::RAM.end || self.cartridge.read_cpu(address).is_some()
+    }
+
+    fn vector_target_problem(&self, target: u16) -> Option<&'static str> {
+        if target == 0x0000 {
+            return Some("points at $0000");
+        }
+        if self.is_mapped_memory(target) {
+            return None;
+        }
+        if target < memory_range::CARTRIDGE_SPACE.start {
+            return Some("points at I/O registers");
+        }
+        Some("points at open bus")
+    }
+
+    /// Find the background tile behind a pixel of the screen.
+    pub fn inspect_pixel(&self, x: u8, y: u8) -> PixelInspection {
+        let chr = CartridgeChr(&*self.cartridge);
+        let mut inspection = self.ppu.state.inspect_pixel(&chr, x, y);
+        inspection.chr_offset = self.cartridge.chr_offset(inspection.pattern_address);
+        inspection
+    }
+
+    /// The DMC DMA halts the CPU in order to fetch a sample byte. If the CPU was in the
+    /// middle of a read cycle, then the read is repeated while the CPU is halted. This is
+    /// harmless for memory, but a controller register will be clocked an extra time.
+    /// This is called by `Bus::tick_apu` when a fetch lands on a controller read.
+    ///
+    /// https://www.nesdev.org/wiki/DMA#Register_conflicts
+    fn dmc_dma_read_conflict(&self, address: u16) {
+        if !self.emulate_dmc_dma_controller_glitch {
+            return;
+        }
+        match address {
+            CONTROLLER_1 => {
+                self.controller_1.read();
+            }
+            CONTROLLER_2 => {
+                self.controller_2.read();
+            }
+            _ => {}
+        }
+    }
+
+    /// Copy a page of CPU memory into OAM, starting at OAMADDR, and return the cycles
+    /// that the CPU is halted for. That's a cycle to halt, and a read and a write for
+    /// each byte, plus a cycle to line up with a read if the DMA starts on an odd cycle.
+    ///
+    /// https://www.nesdev.org/wiki/DMA#OAM_DMA
+    pub fn oam_dma(&mut self, page: u8, cycle_count: u64) -> u64 {
+        for low in 0..=0xff {
+            let value = self.read_u8(u16::from_le_bytes([low, page]));
+            let mut chr = CartridgeChr(&mut *self.cartridge);
+            self.ppu.write_register(&mut chr, OAMDATA, value);
+        }
+        513 + cycle_count % 2
+    }
+
+    /// Sample the IRQ line the way the CPU does before each instruction. The mapper is
+    /// polled here, while the other sources assert the line directly.
+    pub fn poll_irq(&mut self) -> bool {
+        let is_mapper_asserting = self.cartridge.irq();
+        self.irq.set(IrqSource::Mapper, is_mapper_asserting);
+        self.irq.is_asserted()
+    }
+
+    /// Run the PPU alongside the CPU. The mirroring is picked up from the cartridge
+    /// first, as mappers like the MMC1 can change it at any time.
+    pub fn tick_ppu(&mut self, dots: u64) {
+        self.ppu.state.mirroring = self.cartridge.mirroring();
+        // Run up to each scanline boundary, so the mapper sees every one of them.
+        let mut dots = dots;
+        while dots > 0 {
+            let until_scanline = DOTS_PER_SCANLINE - self.ppu.scanline_dot();
+            let step = dots.min(until_scanline);
+            self.ppu.tick(&CartridgeChr(&*self.cartridge), step);
+            dots -= step;
+            if step == until_scanline {
+                let is_rendering = self.ppu.state.mask.is_rendering_enabled();
+                self.cartridge
+                    .start_scanline(self.ppu.scanline(), is_rendering);
+                if cfg!(feature = "debugger") {
+                    self.track_chr_banks();
+                }
+            }
+        }
+    }
+
+    /// Sample the CHR banks at the start of each visible scanline, so that switches in
+    /// the middle of a frame are caught. This is only done for debuggers, as the banks
+    /// are collected into a new list each time.
+    fn track_chr_banks(&mut self) {
+        let scanline = self.ppu.scanline();
+        if scanline > SCREEN_HEIGHT as u64 {
+            return;
+        }
+        let banks = self.cartridge.chr_banks();
+        if scanline == 0 {
+            self.drawing_chr_bank_frame = ChrBankFrame {
+                banks,
+                switches: vec![],
+            };
+        } else if scanline == SCREEN_HEIGHT as u64 {
+            self.chr_bank_frame = std::mem::take(&mut self.drawing_chr_bank_frame);
+        } else if banks != self.drawing_chr_bank_frame.banks_at(scanline) {
+            self.drawing_chr_bank_frame.switches.push((scanline, banks));
+        }
+    }
+
+    /// The CHR banks that the last complete frame was drawn with. It's left empty
+    /// without the "debugger" feature.
+    pub fn chr_bank_frame(&self) -> &ChrBankFrame {
+        &self.chr_bank_frame
+    }
+
+    /// Run the APU alongside the CPU, one CPU cycle at a time. The DMC's sample bytes
+    /// are read through the bus, as they live in the cartridge, and each one takes
+    /// cycles from the CPU.
+    pub fn tick_apu(&mut self, cycles: u64) {
+        for cycle in 0..cycles {
+            if let Some(address) = self.apu.dmc.pending_fetch() {
+                // The DMA halts the CPU on a read cycle. When a load reads a controller
+                // port, that's its last cycle, and the read is repeated.
+                if cycle + 1 == cycles {
+                    if let Some(port) = self.controller_read.get() {
+                        self.dmc_dma_read_conflict(port);
+                    }
+                }
+                let value = self.read_u8(address);
+                self.apu.dmc.fill_sample_buffer(value);
+                self.dmc_dma_stall += DMC_DMA_CYCLES;
+            }
+            self.apu.tick();
+        }
+        self.controller_read.set(None);
+        self.update_apu_irq();
+    }
+
+    fn update_apu_irq(&mut self) {
+        for (source, is_asserted) in self.apu.take_irq_changes() {
+            self.irq.set(source, is_asserted);
+        }
+    }
+
+    /// The CPU memory map, with the PRG banks that are switched in right now.
+    pub fn memory_map(&self) -> Vec<MemoryRegion> {
+        memory_map::memory_map(&self.cartridge.prg_banks())
+    }
+
+    /// See Mapper::fallback_report.
+    pub fn fallback_report(&self) -> Option<FallbackReport> {
+        self.cartridge.fallback_report()
+    }
+
+    /// The cartridge's PRG RAM, for battery saves.
+    pub fn prg_ram(&self) -> Option<&[u8]> {
+        self.cartridge.prg_ram()
+    }
+
+    pub fn load_prg_ram(&mut self, ram: &[u8]) -> Result<(), String> {
+        self.cartridge.load_prg_ram(ram)
+    }
+
+    pub fn ppu_alignment(&self) -> u8 {
+        self.ppu_alignment
+    }
+
+    /// The cartridge controls the nametable mirroring.
+    pub fn mirroring(&self) -> Mirroring {
+        self.cartridge.mirroring()
+    }
+
+    pub fn set_u16(&mut self, address: u16, value: u16) {
+        let [le, be] = value.to_le_bytes();
+        self.set_u8(address, le);
+        self.set_u8(address.wrapping_add(1), be);
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.ram[..memory_range::RAM_ACTUAL.end as usize]);
+        self.irq.save_state(writer);
+        self.controller_1.save_state(writer);
+        writer.bytes(&self.cartridge.save_state());
+        self.ppu.save_state(writer);
+        self.apu.save_state(writer);
+        self.controller_2.save_state(writer);
+        writer.u8(self.ppu_alignment);
+        writer.u8(self.open_bus.get());
+        self.ppu.latch.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        let ram = reader.bytes()?;
+        if ram.len() != memory_range::RAM_ACTUAL.end as usize {
+            return Err(format!(
+                "Expected 2KB of RAM but found {} bytes.",
+                ram.len()
+            ));
+        }
+        self.ram[..ram.len()].copy_from_slice(ram);
+        self.irq.load_state(reader)?;
+        self.controller_1.load_state(reader)?;
+        self.cartridge.load_state(reader.bytes()?)?;
+        self.ppu.load_state(reader)?;
+        self.apu.load_state(reader)?;
+        self.controller_2.load_state(reader)?;
+        self.ppu_alignment = reader.u8()?;
+        self.open_bus.set(reader.u8()?);
+        self.ppu.latch.load_state(reader)?;
+        Ok(())
+    }
+}
+
+impl CpuBus for Bus {
+    fn read_u8(&self, address: u16) -> u8 {
+        Bus::read_u8(self, address)
+    }
+
+    fn set_u8(&mut self, address: u16, value: u8) {
+        Bus::set_u8(self, address, value)
+    }
+
+    fn peek_u8(&self, address: u16) -> u8 {
+        Bus::peek_u8(self, address)
+    }
+
+    fn start_instruction(&mut self, pc: u16, cycle_count: u64) {
+        if self.write_log.is_enabled() {
+            self.write_log.start_instruction(pc, cycle_count);
+        }
+    }
+
+    fn take_nmi(&mut self) -> bool {
+        self.ppu.registers.take_nmi()
+    }
+
+    fn poll_irq(&mut self) -> bool {
+        Bus::poll_irq(self)
+    }
+
+    /// The PPU and APU run alongside the CPU, and the controller macros are advanced by
+    /// the emulated frames.
+    fn tick(&mut self, cycles: u64, cycle_count: u64) {
+        self.tick_ppu(cycles * DOTS_PER_CPU_CYCLE);
+        self.tick_apu(cycles);
+        let frame = |cycle_count| cycle_count * DOTS_PER_CPU_CYCLE / DOTS_PER_FRAME;
+        if frame(cycle_count + cycles) != frame(cycle_count) {
+            self.controller_1.end_frame();
+            self.controller_2.end_frame();
+        }
+    }
+
+    fn take_stall(&mut self, cycle_count: u64) -> u64 {
+        let stall = std::mem::take(&mut self.dmc_dma_stall);
+        match self.oam_dma_page.take() {
+            Some(page) => stall + self.oam_dma(page, cycle_count + stall),
+            None => stall,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::Button;
+    use crate::cpu::NesCpu;
+    use crate::mappers::SimpleProgram;
+    use mos6502_core::cpu_6502::Cpu6502;
+
+    fn read_controller(bus: &Bus) -> Vec<u8> {
+        (0..4).map(|_| bus.read_u8(CONTROLLER_1)).collect()
+    }
+
+    #[test]
+    fn test_dmc_dma_controller_glitch() {
+        let mut bus = Bus::new(Box::new(SimpleProgram::new()));
+        bus.controller_1.set_button(Button::B, true);
+
+        bus.set_u8(CONTROLLER_1, 1);
+        bus.set_u8(CONTROLLER_1, 0);
+        // The DMA lands on the read of A, and B is lost.
+        bus.dmc_dma_read_conflict(CONTROLLER_1);
+        assert_eq!(read_controller(&bus), [1, 0, 0, 0]);
+
+        bus.emulate_dmc_dma_controller_glitch = false;
+        bus.set_u8(CONTROLLER_1, 1);
+        bus.set_u8(CONTROLLER_1, 0);
+        bus.dmc_dma_read_conflict(CONTROLLER_1);
+        assert_eq!(read_controller(&bus), [0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_dmc_dma_controller_read() {
+        let read_buttons = |emulate_glitch: bool| {
+            // Wait for the DMC's second fetch, which lands on the read of B, then
+            // read the 8 buttons into $10.
+            // nop * 186, (lda $4016, lsr a, rol $10) * 8, kil
+            let mut program = vec![0xea; 186];
+            for _ in 0..8 {
+                program.extend_from_slice(&[0xad, 0x16, 0x40, 0x4a, 0x26, 0x10]);
+            }
+            let mut cpu = Cpu6502::from_program(&program);
+            cpu.bus.emulate_dmc_dma_controller_glitch = emulate_glitch;
+            cpu.bus.controller_1.set_button(Button::Start, true);
+            cpu.bus.set_u8(CONTROLLER_1, 1);
+            cpu.bus.set_u8(CONTROLLER_1, 0);
+            // Play a 17 byte sample at the fastest rate.
+            cpu.bus.set_u8(0x4010, 0x0f);
+            cpu.bus.set_u8(0x4013, 0x01);
+            cpu.bus.set_u8(APU_STATUS, 0x10);
+            while cpu.tick() {}
+            cpu.bus.peek_u8(0x0010)
+        };
+        assert_eq!(read_buttons(false), 0b0001_0000);
+        // Select is lost, so Start moves up a bit and the last read is past the end.
+        assert_eq!(read_buttons(true), 0b0010_0001);
+    }
+
+    #[test]
+    fn test_second_controller() {
+        let mut bus = Bus::new(Box::new(SimpleProgram::new()));
+        bus.controller_1.set_button(Button::A, true);
+        bus.controller_2.set_button(Button::B, true);
+
+        // One strobe latches both controllers, and then they shift independently.
+        bus.set_u8(CONTROLLER_1, 1);
+        bus.set_u8(CONTROLLER_1, 0);
+        assert_eq!(bus.read_u8(CONTROLLER_2), 0);
+        assert_eq!(read_controller(&bus), [1, 0, 0, 0]);
+        assert_eq!(bus.read_u8(CONTROLLER_2), 1);
+
+        // Writing to $4017 is the APU frame counter, and doesn't strobe.
+        bus.set_u8(CONTROLLER_2, 1);
+        assert_eq!(bus.read_u8(CONTROLLER_2), 0);
+    }
+
+    #[test]
+    fn test_peek_u8() {
+        let mut bus = Bus::new(Box::new(SimpleProgram::new()));
+        bus.set_u8(0x0010, 0x42);
+        assert_eq!(bus.peek_u8(0x0810), 0x42);
+
+        // Peeking doesn't clock the controller's shift register.
+        bus.controller_1.set_button(Button::A, true);
+        bus.set_u8(CONTROLLER_1, 1);
+        bus.set_u8(CONTROLLER_1, 0);
+        assert_eq!(bus.peek_u8(CONTROLLER_1), 0);
+        assert_eq!(read_controller(&bus), [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        // lda #$02, sta $4014, kil
+        let mut cpu = Cpu6502::from_program(&[0xa9, 0x02, 0x8d, 0x14, 0x40]);
+        for i in 0..=0xff {
+            cpu.bus.set_u8(0x0200 + i, i as u8);
+        }
+        // The copy starts at OAMADDR, and wraps around.
+        cpu.bus.set_u8(0x2003, 0x10);
+        let dot = |cpu: &Cpu6502<Bus>| {
+            cpu.bus.ppu.scanline() * DOTS_PER_SCANLINE + cpu.bus.ppu.scanline_dot()
+        };
+
+        cpu.tick();
+        assert_eq!(cpu.cycle_count, 2);
+        let dot_before = dot(&cpu);
+        // The write ends on an even cycle, so the DMA doesn't need to line up.
+        cpu.tick();
+        assert_eq!(cpu.cycle_count, 2 + 4 + 513);
+        assert_eq!(cpu.bus.ppu.state.oam[0x10], 0x00);
+        assert_eq!(cpu.bus.ppu.state.oam[0x0f], 0xff);
+        assert_eq!(cpu.bus.ppu.registers.oam_address, 0x10);
+        // The PPU kept running while the CPU was halted.
+        assert_eq!(dot(&cpu) - dot_before, (4 + 513) * DOTS_PER_CPU_CYCLE);
+
+        // Starting on an odd cycle takes one more.
+        cpu.bus.set_u8(OAM_DMA, 0x02);
+        assert_eq!(cpu.bus.take_stall(cpu.cycle_count), 514);
+        assert_eq!(cpu.bus.take_stall(cpu.cycle_count), 0);
+    }
+
+    #[test]
+    fn test_dmc_dma_stall() {
+        // Play a 1 byte sample from $C000.
+        // lda #$00, sta $4013, lda #$10, sta $4015, nop, kil
+        let mut cpu = Cpu6502::from_program(&[
+            0xa9, 0x00, 0x8d, 0x13, 0x40, 0xa9, 0x10, 0x8d, 0x15, 0x40, 0xea,
+        ]);
+        for _ in 0..3 {
+            cpu.tick();
+        }
+        assert_eq!(cpu.cycle_count, 2 + 4 + 2);
+        // The sample byte is fetched as soon as the channel is enabled.
+        cpu.tick();
+        assert_eq!(cpu.cycle_count, 2 + 4 + 2 + 4 + DMC_DMA_CYCLES);
+        assert_eq!(cpu.bus.take_stall(cpu.cycle_count), 0);
+        // There's nothing left to fetch.
+        cpu.tick();
+        assert_eq!(cpu.cycle_count, 2 + 4 + 2 + 4 + DMC_DMA_CYCLES + 2);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        // lda $5000, lda $4016, lda $2000, kil
+        let mut cpu = Cpu6502::from_program(&[
+            0xad, 0x00, 0x50, 0xad, 0x16, 0x40, 0xad, 0x00, 0x20,
+        ]);
+        cpu.bus.controller_1.set_button(Button::A, true);
+        cpu.bus.set_u8(CONTROLLER_1, 1);
+        cpu.bus.set_u8(CONTROLLER_1, 0);
+        // The last value on the bus was the high byte of the address.
+        cpu.tick();
+        assert_eq!(cpu.a, 0x50);
+        cpu.tick();
+        assert_eq!(cpu.a, 0x41);
+        // PPUCTRL is write only, so it reads back from the PPU's own latch.
+        cpu.bus.set_u8(0x2000, 0x1a);
+        cpu.tick();
+        assert_eq!(cpu.a, 0x1a);
+        cpu.bus.ppu.state.ctrl = 0;
+
+        // Bit 5 of the APU status comes from the bus, which reading it doesn't change.
+        cpu.bus.set_u8(0x0010, 0xff);
+        assert_eq!(cpu.bus.read_u8(APU_STATUS), 0b0010_0000);
+        assert_eq!(cpu.bus.read_u8(0x5000), 0xff);
+        // Debuggers see the unmapped addresses as 0.
+        assert_eq!(cpu.bus.peek_u8(0x5000), 0);
+    }
+
+    #[test]
+    fn test_poke_ppu() {
+        let mut bus = Bus::new(Box::new(SimpleProgram::new()));
+        bus.poke_ppu(0x2005, 0x42);
+        assert_eq!(bus.peek_ppu(0x2005), 0x42);
+        // $3F10 is a mirror of the backdrop, and only 6 bits are kept.
+        bus.poke_ppu(0x3f10, 0xe1);
+        assert_eq!(bus.peek_ppu(0x3f00), 0x21);
+    }
+
+    #[test]
+    #[cfg(feature = "debugger")]
+    fn test_mid_frame_chr_switch() {
+        use crate::mappers::create_mapper;
+        use crate::rom::{ines_bytes, InesRom};
+
+        let rom = InesRom::from_ines_bytes(&ines_bytes(0x30, 2, 4)).unwrap();
+        let mut bus = Bus::new(create_mapper(3, rom).unwrap());
+        bus.tick_ppu(DOTS_PER_SCANLINE * 100 + 10);
+        bus.set_u8(0x8000, 2);
+        bus.tick_ppu(DOTS_PER_SCANLINE * 150);
+
+        let frame = bus.chr_bank_frame();
+        assert_eq!(frame.banks_at(100)[0].bank, 0);
+        // The switch shows up from the next scanline.
+        assert_eq!(frame.switches.len(), 1);
+        assert_eq!(frame.banks_at(101)[0].bank, 2);
+        // Each bank is filled with $80 | bank, so the tiles have a column for each bit.
+        assert_eq!(
+            bus.frame_pattern_table_pixels(0x0000, 100)[..8],
+            [3, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            bus.frame_pattern_table_pixels(0x0000, 101)[..8],
+            [3, 0, 0, 0, 0, 0, 3, 0]
+        );
+    }
+
+    #[test]
+    fn test_interrupt_vectors() {
+        let mut program = vec![0; 0x8000];
+        // NMI -> $5000, which nothing is mapped to.
+        program[0x7ffa] = 0x00;
+        program[0x7ffb] = 0x50;
+        // The reset vector is filled in by the SimpleProgram, and IRQ is left as $0000.
+        let bus = Bus::new(Box::new(SimpleProgram::load(&program)));
+
+        let vectors = bus.interrupt_vectors();
+        // Debuggers don't leave the vectors on the bus.
+        assert_eq!(bus.open_bus.get(), 0);
+        let summary: Vec<_> = vectors
+            .iter()
+            .map(|v| (v.name, v.vector, v.target, v.problem))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("NMI", 0xfffa, 0x5000, Some("points at open bus")),
+                ("RESET", 0xfffc, 0x8000, None),
+                ("IRQ", 0xfffe, 0x0000, Some("points at $0000")),
+            ]
+        );
+    }
+}
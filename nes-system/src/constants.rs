@@ -36,31 +36,26 @@ pub mod memory_range {
     };
     pub const APU_AND_IO_REGISTERES: Range = Range {
         start: 0x4000,
-        end: 0x4017,
+        end: 0x4018,
     };
     // APU and I/O functionality that is normally disabled. See CPU Test Mode.
     pub const DISABLED_APU_IO_FEATURES: Range = Range {
         start: 0x4018,
-        end: 0x401F,
+        end: 0x4020,
     };
     // Cartridge space: PRG ROM, PRG RAM, and mapper registers (See Note)
-    // Size: 0xBFE0
+    // Size: 0xBFE0. The end is inclusive here, as $10000 doesn't fit in a u16.
     pub const CARTRIDGE_SPACE: Range = Range {
         start: 0x4020,
         end: 0xFFFF,
     };
 
+    // The end is inclusive, like the cartridge space.
     pub const PRG_ROM: Range = Range {
         start: 0x8000,
         end: 0xFFFF,
     };
-
-    pub const STACK_PAGE: u8 = 0x01;
 }
 
-pub enum InterruptVectors {
-    // The Non-Maskable Interrupt or NMI ($FFFA)
-    NonMaskableInterrupt = 0xFFFA,
-    ResetVector = 0xFFFC,
-    IrqBrkVector = 0xFFFE,
-}
+// The vectors are part of the CPU, re-export them alongside the memory map.
+pub use mos6502_core::cpu_6502::InterruptVectors;
@@ -0,0 +1,611 @@
+use crate::save_state::{StateReader, StateWriter};
+use std::{cell::Cell, collections::VecDeque};
+
+/// The standard NES controller, read through $4016 for player 1 and $4017 for player 2.
+/// Writing to $4016 sets the strobe bit of both, which continuously reloads the shift
+/// register with the current button state. Once the strobe is cleared, each read
+/// returns the next button in the order: A, B, Select, Start, Up, Down, Left, Right.
+///
+/// https://www.nesdev.org/wiki/Standard_controller
+pub struct Controller {
+    // The buttons held by the player.
+    buttons: u8,
+    // The buttons from the macro that is playing, which are combined with the held
+    // buttons.
+    macro_buttons: u8,
+    strobe: bool,
+    // Reads are done through a shared reference from the bus, but they still clock the
+    // shift register.
+    shift_register: Cell<u8>,
+    recording: Option<Vec<u8>>,
+    playback: VecDeque<u8>,
+    // The number of emulated frames, for stamping the input events.
+    frame: u64,
+    // The input events waiting for their frame, in frame order.
+    events: VecDeque<InputEvent>,
+}
+
+/// A button going down or up. Frontends queue these as the host's key events come
+/// in, and the controller applies them at the frame boundary, so the game never sees
+/// the buttons change in the middle of a frame. Since the events are stamped with the
+/// emulated frame rather than the host time, a log of them replays exactly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputEvent {
+    /// The event is applied at the end of this frame.
+    pub frame: u64,
+    /// The Button bits that changed.
+    pub buttons: u8,
+    pub is_pressed: bool,
+}
+
+#[rustfmt::skip]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Button {
+  A      = 0b00000001,
+  B      = 0b00000010,
+  Select = 0b00000100,
+  Start  = 0b00001000,
+  Up     = 0b00010000,
+  Down   = 0b00100000,
+  Left   = 0b01000000,
+  Right  = 0b10000000,
+}
+
+/// The names of the buttons, as used in the macro text format.
+pub const BUTTON_NAMES: [(u8, &str); 8] = [
+    (Button::A as u8, "A"),
+    (Button::B as u8, "B"),
+    (Button::Select as u8, "Select"),
+    (Button::Start as u8, "Start"),
+    (Button::Up as u8, "Up"),
+    (Button::Down as u8, "Down"),
+    (Button::Left as u8, "Left"),
+    (Button::Right as u8, "Right"),
+];
+
+/// A recorded sequence of button states, one for each frame. Macros are played back
+/// frame by frame rather than in host time, so the same macro always produces the
+/// same input for the game.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputMacro {
+    pub frames: Vec<u8>,
+}
+
+impl InputMacro {
+    /// The text format lists the frames separated by whitespace. Each frame is the
+    /// held buttons joined with "+", or "." for no buttons. A frame can be repeated
+    /// with "*", e.g. a hadouken is "Down Down+Right B+Right .*4".
+    pub fn from_text(text: &str) -> Result<InputMacro, String> {
+        let mut frames = vec![];
+        for frame in text.split_whitespace() {
+            let (buttons_text, count) = match frame.split_once('*') {
+                Some((buttons_text, count)) => (
+                    buttons_text,
+                    count.parse::<usize>().map_err(|_| {
+                        format!("Expected a repeat count in \"{}\"", frame)
+                    })?,
+                ),
+                None => (frame, 1),
+            };
+            let mut buttons = 0;
+            if buttons_text != "." {
+                for name in buttons_text.split('+') {
+                    match BUTTON_NAMES
+                        .iter()
+                        .find(|(_, button_name)| button_name.eq_ignore_ascii_case(name))
+                    {
+                        Some((button, _)) => buttons |= button,
+                        None => return Err(format!("Unknown button \"{}\"", name)),
+                    }
+                }
+            }
+            frames.resize(frames.len() + count, buttons);
+        }
+        Ok(InputMacro { frames })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut parts: Vec<String> = vec![];
+        let mut index = 0;
+        while let Some(&buttons) = self.frames.get(index) {
+            let count = self.frames[index..]
+                .iter()
+                .take_while(|&&frame| frame == buttons)
+                .count();
+            let mut part = if buttons == 0 {
+                String::from(".")
+            } else {
+                BUTTON_NAMES
+                    .iter()
+                    .filter(|(button, _)| buttons & button != 0)
+                    .map(|(_, name)| *name)
+                    .collect::<Vec<_>>()
+                    .join("+")
+            };
+            if count > 1 {
+                part.push_str(&format!("*{}", count));
+            }
+            parts.push(part);
+            index += count;
+        }
+        parts.join(" ")
+    }
+}
+
+/// Macros bound to host keys, e.g. "F1". The keys are just names here, so that each
+/// frontend can map them onto its own key events.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MacroBindings {
+    pub bindings: Vec<(String, InputMacro)>,
+}
+
+impl MacroBindings {
+    pub fn get(&self, key: &str) -> Option<&InputMacro> {
+        self.bindings
+            .iter()
+            .find(|(bound_key, _)| bound_key == key)
+            .map(|(_, input_macro)| input_macro)
+    }
+
+    /// Bind a macro to a key, replacing any macro that was already bound to it.
+    pub fn bind(&mut self, key: &str, input_macro: InputMacro) {
+        self.bindings.retain(|(bound_key, _)| bound_key != key);
+        self.bindings.push((key.into(), input_macro));
+    }
+
+    /// Serialize the bindings into "key = macro" lines for the config file.
+    pub fn to_config_string(&self) -> String {
+        self.bindings
+            .iter()
+            .map(|(key, input_macro)| format!("{} = {}\n", key, input_macro.to_text()))
+            .collect()
+    }
+
+    pub fn from_config_str(text: &str) -> Result<MacroBindings, String> {
+        let mut bindings = MacroBindings::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) => {
+                    bindings.bind(key.trim(), InputMacro::from_text(value)?);
+                }
+                None => {
+                    return Err(format!(
+                        "Expected \"key = macro\" but found \"{}\"",
+                        line
+                    ))
+                }
+            }
+        }
+        Ok(bindings)
+    }
+}
+
+/// Where a host input comes from, for the controller mapping.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputSource {
+    Keyboard,
+    Gamepad,
+}
+
+/// The host inputs that press each button, so that players can rebind them. Like the
+/// macro bindings, the inputs are names, e.g. "RightShift" or "DPadUp", and each
+/// frontend matches them against its own key and gamepad events.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControllerMapping {
+    /// The keys for each button, in the order of BUTTON_NAMES.
+    pub keys: [Vec<String>; 8],
+    /// The gamepad buttons for each button, in the order of BUTTON_NAMES.
+    pub gamepad: [Vec<String>; 8],
+}
+
+/// The gamepad buttons are the same for both players, as each player has their own pad.
+const DEFAULT_GAMEPAD: [&str; 8] = [
+    "A",
+    "B",
+    "Back",
+    "Start",
+    "DPadUp",
+    "DPadDown",
+    "DPadLeft",
+    "DPadRight",
+];
+
+fn names(names: [&str; 8]) -> [Vec<String>; 8] {
+    names.map(|name| vec![name.to_string()])
+}
+
+impl Default for ControllerMapping {
+    fn default() -> Self {
+        ControllerMapping {
+            keys: names([
+                "X",
+                "Z",
+                "RightShift",
+                "Enter",
+                "Up",
+                "Down",
+                "Left",
+                "Right",
+            ]),
+            gamepad: names(DEFAULT_GAMEPAD),
+        }
+    }
+}
+
+/// The index of a button in BUTTON_NAMES, and in the mapping's lists of inputs.
+pub fn button_index(button: Button) -> usize {
+    (button as u8).trailing_zeros() as usize
+}
+
+impl ControllerMapping {
+    /// The inputs for each button, in the order of BUTTON_NAMES.
+    pub fn inputs(&self, source: InputSource) -> &[Vec<String>; 8] {
+        match source {
+            InputSource::Keyboard => &self.keys,
+            InputSource::Gamepad => &self.gamepad,
+        }
+    }
+
+    pub fn inputs_mut(&mut self, source: InputSource) -> &mut [Vec<String>; 8] {
+        match source {
+            InputSource::Keyboard => &mut self.keys,
+            InputSource::Gamepad => &mut self.gamepad,
+        }
+    }
+
+    /// The inputs that press a button.
+    pub fn bound(&self, source: InputSource, button: Button) -> &[String] {
+        &self.inputs(source)[button_index(button)]
+    }
+
+    /// The Button bits that an input presses.
+    pub fn buttons(&self, source: InputSource, input: &str) -> u8 {
+        BUTTON_NAMES
+            .iter()
+            .zip(self.inputs(source).iter())
+            .filter(|(_, inputs)| inputs.iter().any(|bound| bound == input))
+            .fold(0, |buttons, ((button, _), _)| buttons | button)
+    }
+
+    /// Make an input the only one for a button. It's taken off of the other buttons,
+    /// so that one key doesn't press two buttons.
+    pub fn bind(&mut self, source: InputSource, button: Button, input: &str) {
+        let inputs = self.inputs_mut(source);
+        for bound in inputs.iter_mut() {
+            bound.retain(|bound| bound != input);
+        }
+        inputs[button_index(button)] = vec![input.to_string()];
+    }
+}
+
+/// The number of controller ports.
+pub const PLAYERS: usize = 2;
+
+/// The mappings for both controllers. The keyboard is shared, so the players need
+/// different keys, while each player's gamepad buttons are read from their own pad,
+/// in the order that the pads were plugged in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControllerMappings {
+    pub players: [ControllerMapping; PLAYERS],
+}
+
+impl Default for ControllerMappings {
+    fn default() -> Self {
+        ControllerMappings {
+            players: [
+                ControllerMapping::default(),
+                ControllerMapping {
+                    keys: names(["G", "F", "Q", "E", "W", "S", "A", "D"]),
+                    gamepad: names(DEFAULT_GAMEPAD),
+                },
+            ],
+        }
+    }
+}
+
+impl ControllerMappings {
+    /// Make an input the only one for a player's button. A key is taken off of both
+    /// players, so that one key doesn't press two buttons, while gamepad buttons are
+    /// only taken off of the same player's pad.
+    pub fn bind(
+        &mut self,
+        player: usize,
+        source: InputSource,
+        button: Button,
+        input: &str,
+    ) {
+        if source == InputSource::Keyboard {
+            for mapping in self.players.iter_mut() {
+                for bound in mapping.keys.iter_mut() {
+                    bound.retain(|bound| bound != input);
+                }
+            }
+        }
+        self.players[player].bind(source, button, input);
+    }
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller {
+            buttons: 0,
+            macro_buttons: 0,
+            strobe: false,
+            shift_register: Cell::new(0),
+            recording: None,
+            playback: VecDeque::new(),
+            frame: 0,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// The number of frames that have ended, which the input events are stamped with.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Press or release a button at the end of the current frame.
+    pub fn queue_button(&mut self, button: Button, is_pressed: bool) {
+        self.queue_event(InputEvent {
+            frame: self.frame,
+            buttons: button as u8,
+            is_pressed,
+        });
+    }
+
+    /// Queue an event, e.g. from a replay. Events for frames that have already ended
+    /// are applied at the end of the current frame.
+    pub fn queue_event(&mut self, event: InputEvent) {
+        // Keep the queue in frame order, after any events for the same frame.
+        let index = self
+            .events
+            .iter()
+            .position(|queued| queued.frame > event.frame)
+            .unwrap_or(self.events.len());
+        self.events.insert(index, event);
+    }
+
+    /// Change a button right away, even in the middle of a frame. This is for tests
+    /// and debuggers, frontends should queue the input instead.
+    pub fn set_button(&mut self, button: Button, is_pressed: bool) {
+        if is_pressed {
+            self.buttons |= button as u8;
+        } else {
+            self.buttons &= !(button as u8);
+        }
+        if self.strobe {
+            self.shift_register.set(self.buttons());
+        }
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift_register.set(self.buttons());
+        }
+    }
+
+    /// The buttons that the game sees, including the macro that is playing.
+    pub fn buttons(&self) -> u8 {
+        self.buttons | self.macro_buttons
+    }
+
+    /// Record the held buttons on every frame until the recording is stopped.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Vec::new());
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn stop_recording(&mut self) -> Option<InputMacro> {
+        self.recording.take().map(|frames| InputMacro { frames })
+    }
+
+    /// Play a macro starting on the next frame. Playing another macro replaces the
+    /// one that is playing.
+    pub fn play(&mut self, input_macro: &InputMacro) {
+        self.playback = input_macro.frames.iter().copied().collect();
+    }
+
+    pub fn is_playing(&self) -> bool {
+        !self.playback.is_empty() || self.macro_buttons != 0
+    }
+
+    /// Advance the recording and playback by a frame. This is driven by the emulated
+    /// frames, so macros are deterministic no matter how fast the host runs.
+    pub fn end_frame(&mut self) {
+        if let Some(recording) = &mut self.recording {
+            recording.push(self.buttons);
+        }
+        while let Some(event) = self.events.front() {
+            if event.frame > self.frame {
+                break;
+            }
+            if event.is_pressed {
+                self.buttons |= event.buttons;
+            } else {
+                self.buttons &= !event.buttons;
+            }
+            self.events.pop_front();
+        }
+        self.frame += 1;
+        self.macro_buttons = self.playback.pop_front().unwrap_or(0);
+        if self.strobe {
+            self.shift_register.set(self.buttons());
+        }
+    }
+
+    /// Only the lowest bit of the value written to $4016 is used for the strobe.
+    pub fn write(&mut self, value: u8) {
+        self.strobe = value & 0b1 == 0b1;
+        if self.strobe {
+            self.shift_register.set(self.buttons());
+        }
+    }
+
+    /// Read the next button, and clock the shift register. After all 8 buttons are
+    /// read, an official controller will return 1s.
+    pub fn read(&self) -> u8 {
+        if self.strobe {
+            // The shift register is constantly reloaded, so only A is ever returned.
+            return self.buttons() & 0b1;
+        }
+        let shift_register = self.shift_register.get();
+        self.shift_register.set((shift_register >> 1) | 0b1000_0000);
+        shift_register & 0b1
+    }
+
+    /// Look at the next button without clocking the shift register, for debuggers.
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            return self.buttons() & 0b1;
+        }
+        self.shift_register.get() & 0b1
+    }
+
+    /// The held buttons come from the player, and the macros from the host, so only
+    /// the state of the port is saved.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.strobe);
+        writer.u8(self.shift_register.get());
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.strobe = reader.bool()?;
+        self.shift_register.set(reader.u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mapping_bind() {
+        let mut mapping = ControllerMapping::default();
+        mapping.bind(InputSource::Keyboard, Button::B, "X");
+        assert_eq!(mapping.buttons(InputSource::Keyboard, "X"), Button::B as u8);
+        assert!(mapping.bound(InputSource::Keyboard, Button::A).is_empty());
+        // The gamepad is bound separately.
+        assert_eq!(mapping.buttons(InputSource::Gamepad, "A"), Button::A as u8);
+
+        // A key moves between the players, but the gamepads are separate.
+        let mut mappings = ControllerMappings::default();
+        mappings.bind(1, InputSource::Keyboard, Button::A, "X");
+        mappings.bind(1, InputSource::Gamepad, Button::B, "A");
+        let [player_1, player_2] = &mappings.players;
+        assert_eq!(player_1.buttons(InputSource::Keyboard, "X"), 0);
+        assert_eq!(
+            player_2.buttons(InputSource::Keyboard, "X"),
+            Button::A as u8
+        );
+        assert_eq!(player_1.buttons(InputSource::Gamepad, "A"), Button::A as u8);
+    }
+
+    fn read_all(controller: &Controller) -> Vec<u8> {
+        (0..8).map(|_| controller.read()).collect()
+    }
+
+    #[test]
+    fn test_read_buttons() {
+        let mut controller = Controller::new();
+        controller.set_button(Button::A, true);
+        controller.set_button(Button::Start, true);
+        controller.set_button(Button::Right, true);
+        controller.write(1);
+        controller.write(0);
+        assert_eq!(read_all(&controller), [1, 0, 0, 1, 0, 0, 0, 1]);
+        // The official controller returns 1s after the buttons are read.
+        assert_eq!(controller.read(), 1);
+    }
+
+    #[test]
+    fn test_macro_text() {
+        let text = "Down Down+Right B+Right .*4";
+        let input_macro = InputMacro::from_text(text).unwrap();
+        assert_eq!(input_macro.frames.len(), 7);
+        assert_eq!(
+            input_macro.frames[1],
+            Button::Down as u8 | Button::Right as u8
+        );
+        assert_eq!(input_macro.to_text(), text);
+        assert_eq!(
+            InputMacro::from_text("A+Jump"),
+            Err("Unknown button \"Jump\"".into())
+        );
+    }
+
+    #[test]
+    fn test_record_and_play() {
+        let mut controller = Controller::new();
+        controller.start_recording();
+        controller.set_button(Button::A, true);
+        controller.end_frame();
+        controller.end_frame();
+        controller.set_button(Button::A, false);
+        controller.end_frame();
+        let input_macro = controller.stop_recording().unwrap();
+        assert_eq!(input_macro.to_text(), "A*2 .");
+
+        // The macro starts on the next frame, and is combined with the held buttons.
+        controller.set_button(Button::B, true);
+        controller.play(&input_macro);
+        assert_eq!(controller.buttons(), Button::B as u8);
+        controller.end_frame();
+        assert_eq!(controller.buttons(), Button::A as u8 | Button::B as u8);
+        controller.end_frame();
+        controller.end_frame();
+        assert_eq!(controller.buttons(), Button::B as u8);
+        controller.end_frame();
+        assert!(!controller.is_playing());
+    }
+
+    #[test]
+    fn test_macro_bindings() {
+        let bindings = MacroBindings::from_config_str("F1 = A*2 B\nF2 = Start").unwrap();
+        assert_eq!(bindings.get("F2").unwrap().frames, [Button::Start as u8]);
+        assert_eq!(bindings.to_config_string(), "F1 = A*2 B\nF2 = Start\n");
+    }
+
+    #[test]
+    fn test_strobe_held() {
+        let mut controller = Controller::new();
+        controller.write(1);
+        controller.set_button(Button::A, true);
+        assert_eq!(read_all(&controller), [1, 1, 1, 1, 1, 1, 1, 1]);
+        controller.set_button(Button::A, false);
+        assert_eq!(controller.read(), 0);
+    }
+
+    #[test]
+    fn test_input_events() {
+        let mut controller = Controller::new();
+        controller.queue_button(Button::A, true);
+        controller.queue_button(Button::Start, true);
+        controller.queue_button(Button::Start, false);
+        // The buttons don't change until the frame ends.
+        assert_eq!(controller.buttons(), 0);
+        controller.end_frame();
+        assert_eq!(controller.buttons(), Button::A as u8);
+
+        // A replayed event waits for its frame.
+        controller.queue_event(InputEvent {
+            frame: 3,
+            buttons: Button::A as u8,
+            is_pressed: false,
+        });
+        controller.end_frame();
+        controller.end_frame();
+        assert_eq!(controller.buttons(), Button::A as u8);
+        assert_eq!(controller.frame(), 3);
+        controller.end_frame();
+        assert_eq!(controller.buttons(), 0);
+    }
+}
@@ -0,0 +1,143 @@
+//! The 6502 core lives in mos6502-core, and doesn't know about the NES. This adds the
+//! parts of running it that follow the NES, like stepping by the PPU's scanlines.
+
+use crate::bus::Bus;
+use crate::mappers::SimpleProgram;
+use crate::ppu;
+use mos6502_core::cpu_6502::{Cpu6502, CpuVariant, Ticked};
+use mos6502_core::opcodes::OpCode;
+
+/// How far to step the CPU forward. Scanlines and frames are stepped to the next
+/// boundary, based on the PPU timing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    Instructions(u64),
+    Scanlines(u64),
+    Frames(u64),
+    /// Step forward by a number of PPU dots. The CPU runs whole instructions, so it
+    /// stops on the first instruction that ends on or past the dot.
+    Dots(u64),
+    /// Step a single instruction, except that a JSR runs until it returns to the next
+    /// instruction with the stack back where it was.
+    Over,
+    /// Run until the current subroutine or interrupt handler returns.
+    Out,
+    /// Run until the PC is at the address, after running at least one instruction.
+    ToAddress(u16),
+}
+
+/// How a step ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepResult {
+    Done,
+    /// A KIL operation was encountered, and the CPU halted.
+    Kil,
+    /// The program didn't get to where the step was waiting for within
+    /// `STEP_LIMIT_FRAMES`, e.g. a step over a subroutine that never returns.
+    TimedOut,
+}
+
+/// The steps that wait for the program to get somewhere give up after this many
+/// frames, 10 seconds at 60fps, in case it never does.
+pub const STEP_LIMIT_FRAMES: u64 = 600;
+
+/// The CPU in the NES. Stepping by scanlines, frames, and dots follows the NES's PPU.
+pub trait NesCpu: Sized {
+    /// A fresh NES CPU that runs the bytes of an assembled program from $8000, like the
+    /// frontends load their .asm files. A KIL is added at the end so that the CPU
+    /// stops once the program is done.
+    fn from_program(program: &[u8]) -> Self;
+
+    /// Step forward by a number of instructions, scanlines, or frames.
+    fn step(&mut self, step: Step) -> StepResult;
+}
+
+impl NesCpu for Cpu6502<Bus> {
+    fn from_program(program: &[u8]) -> Cpu6502<Bus> {
+        let mut bytes = program.to_vec();
+        bytes.push(OpCode::KIL as u8);
+        Cpu6502::new(
+            Bus::new(Box::new(SimpleProgram::load(&bytes))),
+            CpuVariant::Ricoh2A03,
+        )
+    }
+
+    fn step(&mut self, step: Step) -> StepResult {
+        let target_dot = |dots_per_unit: u64, count: u64, dot: u64| {
+            (dot / dots_per_unit + count) * dots_per_unit
+        };
+        let dot = self.cycle_count * ppu::DOTS_PER_CPU_CYCLE;
+        let target_dot = match step {
+            Step::Instructions(count) => {
+                for _ in 0..count {
+                    if !self.tick() {
+                        return StepResult::Kil;
+                    }
+                }
+                return StepResult::Done;
+            }
+            Step::Scanlines(count) => target_dot(ppu::DOTS_PER_SCANLINE, count, dot),
+            Step::Frames(count) => target_dot(ppu::DOTS_PER_FRAME, count, dot),
+            Step::Dots(count) => dot + count,
+            Step::Over => {
+                if self.bus.peek_u8(self.pc) != OpCode::JSR_abs as u8 {
+                    return match self.tick() {
+                        true => StepResult::Done,
+                        false => StepResult::Kil,
+                    };
+                }
+                let (return_address, s) = (self.pc.wrapping_add(3), self.s);
+                return step_until(self, |cpu, _| cpu.pc == return_address && cpu.s == s);
+            }
+            Step::Out => {
+                // Follow the calls and returns, rather than the stack pointer, as
+                // programs push and pull their own values around the calls. Interrupts
+                // along the way return to the same depth.
+                let is_call = |opcode| {
+                    opcode == OpCode::JSR_abs as u8 || opcode == OpCode::BRK as u8
+                };
+                let is_return =
+                    |opcode| opcode == OpCode::RTS as u8 || opcode == OpCode::RTI as u8;
+                let mut depth: i64 = 0;
+                return step_until(self, |_, ticked| {
+                    depth += match ticked {
+                        Ticked::Interrupt => 1,
+                        Ticked::Instruction(opcode) if is_call(opcode) => 1,
+                        Ticked::Instruction(opcode) if is_return(opcode) => -1,
+                        _ => 0,
+                    };
+                    depth < 0
+                });
+            }
+            Step::ToAddress(address) => {
+                return step_until(self, |cpu, _| cpu.pc == address);
+            }
+        };
+        while self.cycle_count * ppu::DOTS_PER_CPU_CYCLE < target_dot {
+            if !self.tick() {
+                return StepResult::Kil;
+            }
+        }
+        StepResult::Done
+    }
+}
+
+/// Tick until `is_done` is true, and give up after `STEP_LIMIT_FRAMES`. It's called
+/// after each tick with what the tick ran.
+fn step_until(
+    cpu: &mut Cpu6502<Bus>,
+    mut is_done: impl FnMut(&Cpu6502<Bus>, Ticked) -> bool,
+) -> StepResult {
+    let limit = cpu.cycle_count
+        + STEP_LIMIT_FRAMES * ppu::DOTS_PER_FRAME / ppu::DOTS_PER_CPU_CYCLE;
+    while cpu.cycle_count < limit {
+        let ticked = cpu.run_tick();
+        if ticked == Ticked::Kil {
+            return StepResult::Kil;
+        }
+        if is_done(cpu, ticked) {
+            return StepResult::Done;
+        }
+    }
+    StepResult::TimedOut
+}
@@ -0,0 +1,105 @@
+use crate::save_state::{StateReader, StateWriter};
+
+/// The devices that can pull the shared IRQ line low.
+///
+/// https://www.nesdev.org/wiki/IRQ
+#[rustfmt::skip]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IrqSource {
+  ApuFrameCounter = 0b00000001,
+  Dmc             = 0b00000010,
+  Mapper          = 0b00000100,
+}
+
+pub const IRQ_SOURCES: [IrqSource; 3] = [
+    IrqSource::ApuFrameCounter,
+    IrqSource::Dmc,
+    IrqSource::Mapper,
+];
+
+impl IrqSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IrqSource::ApuFrameCounter => "apu frame counter",
+            IrqSource::Dmc => "dmc",
+            IrqSource::Mapper => "mapper",
+        }
+    }
+}
+
+/// On the hardware the IRQ line is open collector, so every source can hold it low and
+/// the line stays asserted until all of them release it. The IRQ is level triggered,
+/// and a source keeps asserting it until the program acknowledges the source, e.g. by
+/// reading $4015 for the frame counter.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct IrqLine {
+    sources: u8,
+}
+
+impl IrqLine {
+    pub fn new() -> IrqLine {
+        IrqLine { sources: 0 }
+    }
+
+    pub fn assert(&mut self, source: IrqSource) {
+        self.sources |= source as u8;
+    }
+
+    pub fn release(&mut self, source: IrqSource) {
+        self.sources &= !(source as u8);
+    }
+
+    /// Sources that are polled, like the mapper, set their level every time.
+    pub fn set(&mut self, source: IrqSource, is_asserted: bool) {
+        if is_asserted {
+            self.assert(source);
+        } else {
+            self.release(source);
+        }
+    }
+
+    pub fn is_asserted_by(&self, source: IrqSource) -> bool {
+        self.sources & source as u8 != 0
+    }
+
+    /// Is any source holding the line?
+    pub fn is_asserted(&self) -> bool {
+        self.sources != 0
+    }
+
+    /// The sources currently holding the line, for debuggers.
+    pub fn sources(&self) -> impl Iterator<Item = IrqSource> + '_ {
+        IRQ_SOURCES
+            .iter()
+            .copied()
+            .filter(move |source| self.is_asserted_by(*source))
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.sources);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.sources = reader.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sources_are_aggregated() {
+        let mut line = IrqLine::new();
+        line.assert(IrqSource::ApuFrameCounter);
+        line.assert(IrqSource::Mapper);
+        // Acknowledging one source doesn't clobber the other.
+        line.release(IrqSource::ApuFrameCounter);
+        assert!(line.is_asserted());
+        assert_eq!(line.sources().collect::<Vec<_>>(), [IrqSource::Mapper]);
+
+        line.set(IrqSource::Mapper, false);
+        assert!(!line.is_asserted());
+    }
+}
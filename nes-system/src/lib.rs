@@ -0,0 +1,21 @@
+//! The NES that the 6502 runs in: the bus, the PPU, the APU, the controllers, and the
+//! cartridge mappers. The CPU itself is in mos6502-core.
+
+// Clippy rules to disable.
+#![allow(clippy::new_without_default)]
+
+pub mod apu;
+pub mod bus;
+pub mod constants;
+pub mod controller;
+pub mod cpu;
+pub mod irq;
+pub mod mappers;
+pub mod memory_map;
+pub mod power_up;
+pub mod ppu;
+pub mod rom;
+pub mod save_state;
+#[cfg(test)]
+mod test_helpers;
+pub mod write_log;
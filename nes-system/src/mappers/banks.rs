@@ -0,0 +1,241 @@
+use crate::ppu::render::PatternTables;
+use crate::rom::{InesRom, CHR_BANK_SIZE, PRG_RAM_BANK_SIZE};
+
+/// A window of the CPU or PPU address space, and the bank that's switched into it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bank {
+    /// The address the window starts at.
+    pub address: u16,
+    pub size: usize,
+    pub bank: usize,
+}
+
+/// The CHR banks that a frame was drawn with. Games switch banks in the middle of a
+/// frame for split screens and status bars, which a pattern table viewer can't show
+/// with a single set of banks.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChrBankFrame {
+    /// The banks at the start of the frame.
+    pub banks: Vec<Bank>,
+    /// The banks that were switched to while the frame was drawn, along with the first
+    /// scanline that was drawn with them.
+    pub switches: Vec<(u64, Vec<Bank>)>,
+}
+
+/// The CHR data seen through a set of banks, e.g. the ones that a scanline of a
+/// `ChrBankFrame` was drawn with.
+pub struct BankedChr<'a> {
+    pub data: &'a [u8],
+    pub banks: &'a [Bank],
+}
+
+impl PatternTables for BankedChr<'_> {
+    fn read_chr(&self, address: u16) -> u8 {
+        self.banks
+            .iter()
+            .find(|bank| {
+                address >= bank.address && ((address - bank.address) as usize) < bank.size
+            })
+            .and_then(|bank| {
+                let offset = bank.bank * bank.size + (address - bank.address) as usize;
+                self.data.get(offset).copied()
+            })
+            .unwrap_or(0)
+    }
+}
+
+/// The PRG RAM at $6000 and the PRG ROM at $8000, for `Mapper::prg_banks`. Carts
+/// without PRG RAM leave it out.
+pub fn prg_banks(
+    prg_ram: Option<&BankedMemory>,
+    prg_rom: &BankedMemory,
+) -> Vec<(&'static str, Bank)> {
+    let ram = prg_ram
+        .filter(|ram| !ram.data().is_empty())
+        .map(|ram| ram.banks(0x6000))
+        .unwrap_or_default();
+    ram.into_iter()
+        .map(|bank| ("PRG RAM", bank))
+        .chain(
+            prg_rom
+                .banks(0x8000)
+                .into_iter()
+                .map(|bank| ("PRG ROM", bank)),
+        )
+        .collect()
+}
+
+impl ChrBankFrame {
+    pub fn is_switched_mid_frame(&self) -> bool {
+        !self.switches.is_empty()
+    }
+
+    /// The banks that a scanline was drawn with.
+    pub fn banks_at(&self, scanline: u64) -> &[Bank] {
+        self.switches
+            .iter()
+            .rev()
+            .find(|(switched_at, _)| *switched_at <= scanline)
+            .map_or(&self.banks, |(_, banks)| banks)
+    }
+}
+
+/// ROM or RAM that the mapper shows through a few fixed size windows. Each window
+/// points at one bank, so the memory can be any number of banks, no matter how small
+/// the address range it's seen through. The windows are laid out back to back, e.g.
+/// two 16KB windows cover $8000-$FFFF.
+pub struct BankedMemory {
+    data: Vec<u8>,
+    bank_size: usize,
+    // The bank selected by each window.
+    windows: Vec<usize>,
+}
+
+impl BankedMemory {
+    /// The windows all start out at bank 0.
+    pub fn new(
+        data: Vec<u8>,
+        bank_size: usize,
+        window_count: usize,
+    ) -> Result<BankedMemory, String> {
+        if data.is_empty() || !data.len().is_multiple_of(bank_size) {
+            return Err(format!(
+                "The memory is {} bytes, which isn't a multiple of the {}KB bank size.",
+                data.len(),
+                bank_size / 1024
+            ));
+        }
+        Ok(BankedMemory {
+            data,
+            bank_size,
+            windows: vec![0; window_count],
+        })
+    }
+
+    /// The pattern tables, which are 8KB of CHR RAM when the cartridge has no CHR ROM.
+    pub fn chr(
+        chr_rom: Vec<u8>,
+        bank_size: usize,
+        window_count: usize,
+    ) -> Result<BankedMemory, String> {
+        let chr = if chr_rom.is_empty() {
+            vec![0; CHR_BANK_SIZE]
+        } else {
+            chr_rom
+        };
+        BankedMemory::new(chr, bank_size, window_count)
+    }
+
+    /// The PRG RAM at $6000-$7FFF, switched in 8KB banks. The ROM loader rounds the
+    /// size up to a whole bank, but an `InesRom` can also be built by hand.
+    pub fn prg_ram(rom: &InesRom) -> Result<BankedMemory, String> {
+        BankedMemory::new(vec![0; rom.prg_ram_size], PRG_RAM_BANK_SIZE, 1)
+    }
+
+    pub fn bank_count(&self) -> usize {
+        self.data.len() / self.bank_size
+    }
+
+    pub fn last_bank(&self) -> usize {
+        self.bank_count() - 1
+    }
+
+    /// Point a window at a bank. Bank numbers past the end wrap around, which is what
+    /// happens when the high bits of a bank register aren't connected.
+    pub fn set_bank(&mut self, window: usize, bank: usize) {
+        self.windows[window] = bank % self.bank_count();
+    }
+
+    pub fn bank(&self, window: usize) -> usize {
+        self.windows[window]
+    }
+
+    /// The bank in each window, when the first window starts at `address`.
+    pub fn banks(&self, address: u16) -> Vec<Bank> {
+        self.windows
+            .iter()
+            .enumerate()
+            .map(|(window, bank)| Bank {
+                address: address + (window * self.bank_size) as u16,
+                size: self.bank_size,
+                bank: *bank,
+            })
+            .collect()
+    }
+
+    /// Where an address, relative to the start of the first window, lands in the data.
+    pub fn offset(&self, address: usize) -> usize {
+        let window = (address / self.bank_size) % self.windows.len();
+        self.windows[window] * self.bank_size + address % self.bank_size
+    }
+
+    pub fn read(&self, address: usize) -> u8 {
+        self.data[self.offset(address)]
+    }
+
+    pub fn write(&mut self, address: usize, value: u8) {
+        let offset = self.offset(address);
+        self.data[offset] = value;
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Replace all of the data, e.g. RAM from a save state.
+    pub fn load_data(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() != self.data.len() {
+            return Err(format!(
+                "Expected {} bytes of banked memory, found {}.",
+                self.data.len(),
+                data.len()
+            ));
+        }
+        self.data.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_windows() {
+        // 64 banks of 16KB, 1MB in total.
+        let data = (0..64u8).flat_map(|bank| [bank; 0x4000]).collect();
+        let mut memory = BankedMemory::new(data, 0x4000, 2).unwrap();
+        assert_eq!(memory.last_bank(), 63);
+
+        memory.set_bank(0, 42);
+        memory.set_bank(1, memory.last_bank());
+        assert_eq!(memory.read(0x0000), 42);
+        assert_eq!(memory.read(0x7fff), 63);
+        assert_eq!(memory.offset(0x4001), 63 * 0x4000 + 1);
+
+        // Bank numbers wrap around.
+        memory.set_bank(0, 64 + 3);
+        assert_eq!(memory.read(0x0000), 3);
+
+        assert!(BankedMemory::new(vec![0; 0x3000], 0x2000, 1).is_err());
+    }
+
+    #[test]
+    fn test_chr_bank_frame() {
+        let bank = |bank| {
+            vec![Bank {
+                address: 0,
+                size: 0x2000,
+                bank,
+            }]
+        };
+        let frame = ChrBankFrame {
+            banks: bank(0),
+            switches: vec![(100, bank(1)), (200, bank(2))],
+        };
+        assert!(frame.is_switched_mid_frame());
+        assert_eq!(frame.banks_at(99), bank(0));
+        assert_eq!(frame.banks_at(100), bank(1));
+        assert_eq!(frame.banks_at(239), bank(2));
+    }
+}
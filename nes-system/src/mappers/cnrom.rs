@@ -0,0 +1,118 @@
+use super::{prg_banks, Bank, BankedMemory, Mapper};
+use crate::ppu::Mirroring;
+use crate::rom::{InesRom, CHR_BANK_SIZE, PRG_BANK_SIZE};
+use crate::save_state::{StateReader, StateWriter};
+
+/// Mapper 3, used by games like Gradius and Arkanoid. The PRG ROM is fixed like
+/// NROM, and writing to $8000-$FFFF switches the 8KB CHR bank.
+///
+/// Bus conflicts aren't emulated, the written value is always the one used.
+///
+/// https://www.nesdev.org/wiki/CNROM
+pub struct Cnrom {
+    prg_rom: BankedMemory,
+    chr_rom: BankedMemory,
+    mirroring: Mirroring,
+    chr_bank: u8,
+}
+
+impl Cnrom {
+    pub fn new(rom: InesRom) -> Result<Cnrom, String> {
+        let prg_size = rom.prg_rom.len();
+        if prg_size != PRG_BANK_SIZE && prg_size != 2 * PRG_BANK_SIZE {
+            return Err(format!(
+                "CNROM cartridges have 16KB or 32KB of PRG ROM, but this one has {}KB.",
+                prg_size / 1024
+            ));
+        }
+        if rom.chr_rom.is_empty() {
+            return Err("CNROM cartridges need CHR ROM to switch between.".into());
+        }
+        let mut prg_rom = BankedMemory::new(rom.prg_rom, PRG_BANK_SIZE, 2)?;
+        prg_rom.set_bank(1, prg_rom.last_bank());
+        Ok(Cnrom {
+            prg_rom,
+            chr_rom: BankedMemory::new(rom.chr_rom, CHR_BANK_SIZE, 1)?,
+            mirroring: rom.mirroring,
+            chr_bank: 0,
+        })
+    }
+}
+
+impl Mapper for Cnrom {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xffff => Some(self.prg_rom.read(addr as usize - 0x8000)),
+            _ => None,
+        }
+    }
+
+    fn write_cpu(&mut self, addr: u16, value: u8) -> bool {
+        if addr >= 0x8000 {
+            self.chr_bank = value;
+            self.chr_rom.set_bank(0, value as usize);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr_rom.read(addr as usize)
+    }
+
+    fn chr_offset(&self, addr: u16) -> Option<usize> {
+        Some(self.chr_rom.offset(addr as usize))
+    }
+
+    fn prg_banks(&self) -> Vec<(&'static str, Bank)> {
+        prg_banks(None, &self.prg_rom)
+    }
+
+    fn chr_banks(&self) -> Vec<Bank> {
+        self.chr_rom.banks(0)
+    }
+
+    fn chr_data(&self) -> &[u8] {
+        self.chr_rom.data()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.u8(self.chr_bank);
+        writer.into_bytes()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(state);
+        let chr_bank = reader.u8()?;
+        reader.finish()?;
+        self.chr_bank = chr_bank;
+        self.chr_rom.set_bank(0, chr_bank as usize);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mappers::create_mapper;
+    use crate::rom::ines_bytes;
+
+    #[test]
+    fn test_chr_banks() {
+        let rom = InesRom::from_ines_bytes(&ines_bytes(0x30, 2, 4)).unwrap();
+        let mut mapper = create_mapper(3, rom).unwrap();
+        assert_eq!(mapper.read_chr(0x0000), 0x80);
+
+        mapper.write_cpu(0xffff, 2);
+        assert_eq!(mapper.read_chr(0x1fff), 0x82);
+        assert_eq!(mapper.chr_offset(0x0010), Some(2 * CHR_BANK_SIZE + 0x10));
+        assert_eq!(mapper.read_cpu(0xc000), Some(1));
+        assert_eq!(mapper.save_state(), [2]);
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+
+use super::{prg_banks, Bank, BankedMemory, Mapper};
+use crate::ppu::Mirroring;
+use crate::rom::{InesRom, CHR_BANK_SIZE, PRG_BANK_SIZE};
+
+/// The writes to one of the registers that a cartridge might have.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegisterWrites {
+    pub address: u16,
+    pub count: u64,
+    pub last_value: u8,
+}
+
+/// What a game tried to do with a mapper that isn't supported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FallbackReport {
+    pub mapper_id: u8,
+    /// The registers that were written, by address.
+    pub writes: Vec<RegisterWrites>,
+}
+
+/// Boots a game whose mapper isn't supported, as if it were NROM. Most mappers power up
+/// with the last PRG bank at $C000, where the reset vector is, so the first and last
+/// 16KB banks are switched in, along with the first 8KB of CHR. Bank switches are
+/// ignored, so games often crash or draw garbage after the title screen, but the
+/// writes to $4020-$5FFF and $8000-$FFFF are counted, which shows what the mapper would need to do.
+pub struct FallbackMapper {
+    mapper_id: u8,
+    prg_rom: BankedMemory,
+    prg_ram: BankedMemory,
+    chr: BankedMemory,
+    is_chr_ram: bool,
+    mirroring: Mirroring,
+    writes: BTreeMap<u16, RegisterWrites>,
+}
+
+impl FallbackMapper {
+    pub fn new(rom: InesRom) -> Result<FallbackMapper, String> {
+        if rom.prg_rom.len() < PRG_BANK_SIZE {
+            return Err("The cartridge needs at least one PRG ROM bank.".into());
+        }
+        let is_chr_ram = rom.chr_rom.is_empty();
+        let prg_ram = BankedMemory::prg_ram(&rom)?;
+        let mut prg_rom = BankedMemory::new(rom.prg_rom, PRG_BANK_SIZE, 2)?;
+        prg_rom.set_bank(1, prg_rom.last_bank());
+        Ok(FallbackMapper {
+            mapper_id: rom.mapper,
+            prg_rom,
+            prg_ram,
+            chr: BankedMemory::chr(rom.chr_rom, CHR_BANK_SIZE, 1)?,
+            is_chr_ram,
+            mirroring: rom.mirroring,
+            writes: BTreeMap::new(),
+        })
+    }
+}
+
+impl Mapper for FallbackMapper {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => Some(self.prg_ram.read(addr as usize - 0x6000)),
+            0x8000..=0xffff => Some(self.prg_rom.read(addr as usize - 0x8000)),
+            _ => None,
+        }
+    }
+
+    fn write_cpu(&mut self, addr: u16, value: u8) -> bool {
+        match addr {
+            0x0000..=0x401f => return false,
+            0x6000..=0x7fff => {
+                self.prg_ram.write(addr as usize - 0x6000, value);
+                return true;
+            }
+            _ => {}
+        }
+        let writes = self.writes.entry(addr).or_insert(RegisterWrites {
+            address: addr,
+            count: 0,
+            last_value: value,
+        });
+        writes.count += 1;
+        writes.last_value = value;
+        true
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn chr_offset(&self, addr: u16) -> Option<usize> {
+        Some(self.chr.offset(addr as usize))
+    }
+
+    fn prg_banks(&self) -> Vec<(&'static str, Bank)> {
+        prg_banks(Some(&self.prg_ram), &self.prg_rom)
+    }
+
+    fn chr_banks(&self) -> Vec<Bank> {
+        self.chr.banks(0)
+    }
+
+    fn chr_data(&self) -> &[u8] {
+        self.chr.data()
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        if self.is_chr_ram {
+            self.chr.write(addr as usize, value);
+        }
+    }
+
+    /// The PRG RAM, followed by the CHR RAM when there is some. The writes are only
+    /// for diagnostics, and aren't saved.
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = self.prg_ram.data().to_vec();
+        if self.is_chr_ram {
+            state.extend_from_slice(self.chr.data());
+        }
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let prg_ram_size = self.prg_ram.data().len();
+        let chr_size = if self.is_chr_ram { CHR_BANK_SIZE } else { 0 };
+        if state.len() != prg_ram_size + chr_size {
+            return Err(format!(
+                "Expected {} bytes of fallback mapper state, found {}.",
+                prg_ram_size + chr_size,
+                state.len()
+            ));
+        }
+        let (prg_ram, chr) = state.split_at(prg_ram_size);
+        self.prg_ram.load_data(prg_ram)?;
+        if self.is_chr_ram {
+            self.chr.load_data(chr)?;
+        }
+        Ok(())
+    }
+
+    fn fallback_report(&self) -> Option<FallbackReport> {
+        Some(FallbackReport {
+            mapper_id: self.mapper_id,
+            writes: self.writes.values().copied().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::ines_bytes;
+    use crate::test_helpers::{nes, nes_from_ines_bytes};
+
+    #[test]
+    fn test_fallback() {
+        // An MMC3, with 4 PRG banks, where the last one has the reset vector.
+        let mut bytes = ines_bytes(0x40, 4, 1);
+        let reset = 16 + 4 * PRG_BANK_SIZE - 4;
+        bytes[reset..reset + 2].copy_from_slice(&[0x00, 0xc0]);
+        assert!(nes_from_ines_bytes(&bytes).is_err());
+
+        let rom = InesRom::from_ines_bytes(&bytes).unwrap();
+        let mut cpu = nes(rom.into_mapper_or_fallback().unwrap());
+        assert_eq!(cpu.pc, 0xc000);
+        let bus = &mut cpu.bus;
+        assert_eq!(bus.read_u8(0x8000), 0);
+        assert_eq!(bus.read_u8(0xc000), 3);
+        bus.set_u8(0x8000, 0x06);
+        bus.set_u8(0x8001, 0x02);
+        bus.set_u8(0x8000, 0x07);
+        bus.set_u8(0x6000, 0x42);
+        assert_eq!(bus.read_u8(0x6000), 0x42);
+        assert_eq!(bus.read_u8(0xc000), 3);
+
+        let report = bus.fallback_report().unwrap();
+        assert_eq!(report.mapper_id, 4);
+        assert_eq!(
+            report.writes,
+            [
+                RegisterWrites {
+                    address: 0x8000,
+                    count: 2,
+                    last_value: 0x07
+                },
+                RegisterWrites {
+                    address: 0x8001,
+                    count: 1,
+                    last_value: 0x02
+                },
+            ]
+        );
+
+        let rom = InesRom::from_ines_bytes(&ines_bytes(0, 2, 1)).unwrap();
+        let bus = nes(rom.into_mapper_or_fallback().unwrap()).bus;
+        assert_eq!(bus.fallback_report(), None);
+    }
+}
@@ -0,0 +1,356 @@
+use super::{prg_banks, Bank, BankedMemory, Mapper};
+use crate::ppu::Mirroring;
+use crate::rom::{InesRom, CHR_BANK_SIZE, PRG_BANK_SIZE};
+use crate::save_state::{StateReader, StateWriter};
+/// CHR is switched in 4KB banks, half of the iNES CHR bank size.
+const CHR_HALF_BANK_SIZE: usize = CHR_BANK_SIZE / 2;
+/// The shift register is empty when this bit reaches the bottom.
+const SHIFT_RESET: u8 = 0b10000;
+
+/// Mapper 1, the Nintendo MMC1, used by games like The Legend of Zelda and Metroid.
+///
+/// The CPU writes the registers one bit at a time through a serial port. Every write
+/// to $8000-$FFFF shifts in bit 0, and the fifth write copies the value into the
+/// register picked by the address of that write.
+///
+///   $8000-$9FFF  Control: mirroring, PRG bank mode, CHR bank mode
+///   $A000-$BFFF  CHR bank 0
+///   $C000-$DFFF  CHR bank 1
+///   $E000-$FFFF  PRG bank, and the PRG RAM enable
+///
+/// The SxROM boards with 8KB of CHR repurpose the upper bits of CHR bank 0. Bit 4
+/// picks the 256KB half of a 512KB PRG ROM, and bits 2-3 switch the PRG RAM bank.
+///
+/// https://www.nesdev.org/wiki/MMC1
+pub struct Mmc1 {
+    prg_rom: BankedMemory,
+    prg_ram: BankedMemory,
+    chr: BankedMemory,
+    is_chr_ram: bool,
+    shift: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    pub fn new(rom: InesRom) -> Result<Mmc1, String> {
+        if !rom.prg_rom.len().is_multiple_of(PRG_BANK_SIZE) {
+            return Err("The MMC1 PRG ROM must be made of 16KB banks.".into());
+        }
+        let is_chr_ram = rom.chr_rom.is_empty();
+        let prg_ram = BankedMemory::prg_ram(&rom)?;
+        let mut mmc1 = Mmc1 {
+            prg_rom: BankedMemory::new(rom.prg_rom, PRG_BANK_SIZE, 2)?,
+            prg_ram,
+            chr: BankedMemory::chr(rom.chr_rom, CHR_HALF_BANK_SIZE, 2)?,
+            is_chr_ram,
+            shift: SHIFT_RESET,
+            // Games can't rely on the power up state, but the last bank is commonly
+            // fixed at $C000 so that the reset vector is there.
+            control: 0b01100,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        };
+        mmc1.update_banks();
+        Ok(mmc1)
+    }
+
+    fn write_register(&mut self, addr: u16, value: u8) {
+        if value & 0b1000_0000 != 0 {
+            // Writing bit 7 resets the shift register, and fixes the last PRG bank.
+            self.shift = SHIFT_RESET;
+            self.control |= 0b01100;
+            return;
+        }
+        let is_full = self.shift & 1 == 1;
+        self.shift = (self.shift >> 1) | ((value & 1) << 4);
+        if !is_full {
+            return;
+        }
+        let register = self.shift;
+        self.shift = SHIFT_RESET;
+        match addr {
+            0x8000..=0x9fff => self.control = register,
+            0xa000..=0xbfff => self.chr_bank_0 = register,
+            0xc000..=0xdfff => self.chr_bank_1 = register,
+            _ => self.prg_bank = register,
+        }
+        self.update_banks();
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0b10000 == 0
+    }
+
+    /// Point the bank windows at what the registers select.
+    fn update_banks(&mut self) {
+        // The 256KB half of a 512KB PRG ROM.
+        let outer_bank = if self.prg_rom.bank_count() > 16 {
+            (self.chr_bank_0 & 0b10000) as usize
+        } else {
+            0
+        };
+        let bank = outer_bank + (self.prg_bank & 0b1111) as usize;
+        let (lower, upper) = match (self.control >> 2) & 0b11 {
+            // Switch 32KB at $8000, ignoring the low bit of the bank number.
+            0 | 1 => (bank & !1, (bank & !1) + 1),
+            // Fix the first bank at $8000, and switch $C000.
+            2 => (outer_bank, bank),
+            // Switch $8000, and fix the last bank at $C000.
+            _ => (bank, outer_bank + 15),
+        };
+        self.prg_rom.set_bank(0, lower);
+        self.prg_rom
+            .set_bank(1, upper.min(outer_bank + self.prg_rom.last_bank()));
+
+        let (chr_lower, chr_upper) = if self.control & 0b10000 == 0 {
+            // Switch 8KB at a time, ignoring the low bit of the bank number.
+            let bank = (self.chr_bank_0 & !1) as usize;
+            (bank, bank + 1)
+        } else {
+            (self.chr_bank_0 as usize, self.chr_bank_1 as usize)
+        };
+        self.chr.set_bank(0, chr_lower);
+        self.chr.set_bank(1, chr_upper);
+
+        let prg_ram_bank = match self.prg_ram.bank_count() {
+            1 => 0,
+            // SOROM only wires up bit 3.
+            2 => (self.chr_bank_0 >> 3) & 1,
+            _ => (self.chr_bank_0 >> 2) & 0b11,
+        };
+        self.prg_ram.set_bank(0, prg_ram_bank as usize);
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff if self.is_prg_ram_enabled() => {
+                Some(self.prg_ram.read(addr as usize - 0x6000))
+            }
+            0x8000..=0xffff => Some(self.prg_rom.read(addr as usize - 0x8000)),
+            _ => None,
+        }
+    }
+
+    fn write_cpu(&mut self, addr: u16, value: u8) -> bool {
+        match addr {
+            0x6000..=0x7fff => {
+                if self.is_prg_ram_enabled() {
+                    self.prg_ram.write(addr as usize - 0x6000, value);
+                }
+                true
+            }
+            0x8000..=0xffff => {
+                self.write_register(addr, value);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn chr_offset(&self, addr: u16) -> Option<usize> {
+        Some(self.chr.offset(addr as usize))
+    }
+
+    fn prg_banks(&self) -> Vec<(&'static str, Bank)> {
+        prg_banks(Some(&self.prg_ram), &self.prg_rom)
+    }
+
+    fn chr_banks(&self) -> Vec<Bank> {
+        self.chr.banks(0)
+    }
+
+    fn chr_data(&self) -> &[u8] {
+        self.chr.data()
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        if self.is_chr_ram {
+            self.chr.write(addr as usize, value);
+        }
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        match self.prg_ram.data() {
+            [] => None,
+            ram => Some(ram),
+        }
+    }
+
+    fn load_prg_ram(&mut self, ram: &[u8]) -> Result<(), String> {
+        self.prg_ram.load_data(ram)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.u8(self.shift);
+        writer.u8(self.control);
+        writer.u8(self.chr_bank_0);
+        writer.u8(self.chr_bank_1);
+        writer.u8(self.prg_bank);
+        writer.bytes(self.prg_ram.data());
+        writer.bytes(if self.is_chr_ram {
+            self.chr.data()
+        } else {
+            &[]
+        });
+        writer.into_bytes()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(state);
+        let shift = reader.u8()?;
+        let control = reader.u8()?;
+        let chr_bank_0 = reader.u8()?;
+        let chr_bank_1 = reader.u8()?;
+        let prg_bank = reader.u8()?;
+        let prg_ram = reader.bytes()?;
+        let chr_ram = reader.bytes()?;
+        reader.finish()?;
+        let chr_ram_size = if self.is_chr_ram {
+            self.chr.data().len()
+        } else {
+            0
+        };
+        if prg_ram.len() != self.prg_ram.data().len() || chr_ram.len() != chr_ram_size {
+            return Err("The MMC1 save state has the wrong amount of RAM.".into());
+        }
+
+        self.shift = shift;
+        self.control = control;
+        self.chr_bank_0 = chr_bank_0;
+        self.chr_bank_1 = chr_bank_1;
+        self.prg_bank = prg_bank;
+        self.prg_ram.load_data(prg_ram)?;
+        if self.is_chr_ram {
+            self.chr.load_data(chr_ram)?;
+        }
+        self.update_banks();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::ines_bytes;
+
+    fn mmc1(prg_banks: u8, chr_banks: u8) -> Mmc1 {
+        let rom = InesRom::from_ines_bytes(&ines_bytes(0x10, prg_banks, chr_banks));
+        Mmc1::new(rom.unwrap()).unwrap()
+    }
+
+    /// Shift a 5 bit value into a register, the low bit first.
+    fn write_serial(mapper: &mut Mmc1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.write_cpu(addr, (value >> bit) & 1);
+        }
+    }
+
+    #[test]
+    fn test_prg_banks() {
+        let mut mapper = mmc1(8, 1);
+        // At power up the last bank is fixed at $C000.
+        assert_eq!(mapper.read_cpu(0xc000), Some(7));
+
+        write_serial(&mut mapper, 0xe000, 3);
+        assert_eq!(mapper.read_cpu(0x8000), Some(3));
+        assert_eq!(mapper.read_cpu(0xffff), Some(7));
+
+        // Fix the first bank at $8000 instead.
+        write_serial(&mut mapper, 0x8000, 0b01000);
+        assert_eq!(mapper.read_cpu(0x8000), Some(0));
+        assert_eq!(mapper.read_cpu(0xc000), Some(3));
+
+        // 32KB mode ignores the low bit.
+        write_serial(&mut mapper, 0x8000, 0b00000);
+        assert_eq!(mapper.read_cpu(0x8000), Some(2));
+        assert_eq!(mapper.read_cpu(0xc000), Some(3));
+    }
+
+    #[test]
+    fn test_reset_and_mirroring() {
+        let mut mapper = mmc1(2, 1);
+        write_serial(&mut mapper, 0x8000, 0b00010);
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+
+        // A partial write is thrown away by a reset.
+        mapper.write_cpu(0x8000, 1);
+        mapper.write_cpu(0x8000, 0x80);
+        write_serial(&mut mapper, 0x8000, 0b00011);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_chr_banks() {
+        let mut mapper = mmc1(2, 4);
+        // The CHR banks from ines_bytes are 8KB, so 4KB bank 3 is in the second one.
+        write_serial(&mut mapper, 0x8000, 0b10000);
+        write_serial(&mut mapper, 0xa000, 3);
+        write_serial(&mut mapper, 0xc000, 6);
+        assert_eq!(mapper.read_chr(0x0000), 0x81);
+        assert_eq!(mapper.read_chr(0x1000), 0x83);
+
+        // 8KB mode ignores the low bit, and uses CHR bank 0 for both halves.
+        write_serial(&mut mapper, 0x8000, 0b00000);
+        assert_eq!(mapper.read_chr(0x0000), 0x81);
+        assert_eq!(mapper.read_chr(0x1fff), 0x81);
+    }
+
+    #[test]
+    fn test_sxrom() {
+        // SXROM: 512KB of PRG ROM, 32KB of PRG RAM, and CHR RAM.
+        let mut bytes = ines_bytes(0x10, 32, 0);
+        bytes[8] = 4;
+        let mut mapper = Mmc1::new(InesRom::from_ines_bytes(&bytes).unwrap()).unwrap();
+        assert_eq!(mapper.read_cpu(0xc000), Some(15));
+
+        // Bit 4 of CHR bank 0 switches to the upper 256KB, bits 2-3 the RAM bank.
+        write_serial(&mut mapper, 0xa000, 0b11000);
+        write_serial(&mut mapper, 0xe000, 2);
+        assert_eq!(mapper.read_cpu(0x8000), Some(18));
+        assert_eq!(mapper.read_cpu(0xc000), Some(31));
+        mapper.write_cpu(0x6000, 0x42);
+
+        write_serial(&mut mapper, 0xa000, 0b00000);
+        assert_eq!(mapper.read_cpu(0x8000), Some(2));
+        assert_eq!(mapper.read_cpu(0x6000), Some(0));
+        write_serial(&mut mapper, 0xa000, 0b01000);
+        assert_eq!(mapper.read_cpu(0x6000), Some(0x42));
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut mapper = mmc1(8, 0);
+        write_serial(&mut mapper, 0xe000, 5);
+        mapper.write_cpu(0x6000, 0x42);
+        mapper.write_chr(0x0010, 0x24);
+        // Leave a write half way through the shift register.
+        mapper.write_cpu(0x8000, 1);
+
+        let mut loaded = mmc1(8, 0);
+        loaded.load_state(&mapper.save_state()).unwrap();
+        assert_eq!(loaded.read_cpu(0x8000), Some(5));
+        assert_eq!(loaded.read_cpu(0x6000), Some(0x42));
+        assert_eq!(loaded.read_chr(0x0010), 0x24);
+        assert_eq!(loaded.shift, mapper.shift);
+    }
+}
@@ -0,0 +1,520 @@
+use super::{prg_banks, Bank, BankedMemory, Mapper};
+use crate::ppu::render::SCREEN_HEIGHT;
+use crate::ppu::Mirroring;
+use crate::rom::InesRom;
+use crate::save_state::{StateReader, StateWriter};
+use std::cell::Cell;
+
+const PRG_WINDOW_SIZE: usize = 0x2000;
+const CHR_WINDOW_SIZE: usize = 0x400;
+const EXRAM_SIZE: usize = 0x400;
+
+/// Mapper 5, the Nintendo MMC5, used by games like Castlevania III and the later Koei
+/// strategy games. This covers the parts most games rely on:
+///
+///   $5100        PRG mode, 32KB, 16KB, 16KB + 8KB, or 8KB windows
+///   $5101        CHR mode, 8KB, 4KB, 2KB, or 1KB windows
+///   $5102-$5103  PRG RAM write protection
+///   $5104        ExRAM mode
+///   $5105        Nametable mapping: CIRAM page 0 or 1, ExRAM, or the fill mode
+///   $5106-$5107  Fill mode tile and attribute
+///   $5113        PRG RAM bank at $6000
+///   $5114-$5117  PRG banks
+///   $5120-$5127  CHR banks
+///   $5130        Upper CHR bank bits
+///   $5203-$5204  Scanline IRQ
+///   $5205-$5206  8 x 8 bit multiplier
+///   $5C00-$5FFF  ExRAM
+///
+/// Not implemented: the extended attribute mode of ExRAM, the vertical split screen,
+/// separate background CHR banks for 8x16 sprites ($5128-$512B), PRG RAM mapped into
+/// $8000-$DFFF, and the expansion audio. The writes to those registers are ignored.
+///
+/// https://www.nesdev.org/wiki/MMC5
+pub struct Mmc5 {
+    prg_rom: BankedMemory,
+    prg_ram: BankedMemory,
+    chr: BankedMemory,
+    is_chr_ram: bool,
+    exram: Vec<u8>,
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    prg_ram_bank: u8,
+    prg_banks: [u8; 4],
+    chr_banks: [u8; 8],
+    chr_upper: u8,
+    irq_scanline: u8,
+    is_irq_enabled: bool,
+    // Reading $5204 acknowledges the IRQ.
+    is_irq_pending: Cell<bool>,
+    is_in_frame: bool,
+    scanline_counter: u8,
+    multiplicand: u8,
+    multiplier: u8,
+}
+
+impl Mmc5 {
+    pub fn new(rom: InesRom) -> Result<Mmc5, String> {
+        let is_chr_ram = rom.chr_rom.is_empty();
+        let prg_ram = BankedMemory::prg_ram(&rom)?;
+        let mut mmc5 = Mmc5 {
+            prg_rom: BankedMemory::new(rom.prg_rom, PRG_WINDOW_SIZE, 4)?,
+            prg_ram,
+            chr: BankedMemory::chr(rom.chr_rom, CHR_WINDOW_SIZE, 8)?,
+            is_chr_ram,
+            exram: vec![0; EXRAM_SIZE],
+            // Only $5117 is reliably set at power up, to the last bank.
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0, 0],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_ram_bank: 0,
+            prg_banks: [0xff; 4],
+            chr_banks: [0; 8],
+            chr_upper: 0,
+            irq_scanline: 0,
+            is_irq_enabled: false,
+            is_irq_pending: Cell::new(false),
+            is_in_frame: false,
+            scanline_counter: 0,
+            multiplicand: 0xff,
+            multiplier: 0xff,
+        };
+        mmc5.update_banks();
+        Ok(mmc5)
+    }
+
+    /// Point the bank windows at what the registers select.
+    fn update_banks(&mut self) {
+        // The PRG banks are in 8KB units, the top bit picks ROM or RAM.
+        let prg = |window: usize| (self.prg_banks[window] & 0x7f) as usize;
+        let prg_windows = match self.prg_mode {
+            0 => {
+                let bank = prg(3) & !0b11;
+                [bank, bank + 1, bank + 2, bank + 3]
+            }
+            1 => {
+                let (lower, upper) = (prg(1) & !1, prg(3) & !1);
+                [lower, lower + 1, upper, upper + 1]
+            }
+            2 => {
+                let lower = prg(1) & !1;
+                [lower, lower + 1, prg(2), prg(3)]
+            }
+            _ => [prg(0), prg(1), prg(2), prg(3)],
+        };
+        for (window, bank) in prg_windows.iter().enumerate() {
+            self.prg_rom.set_bank(window, *bank);
+        }
+
+        // The CHR banks are in units of the window size, and the windows are 1KB.
+        let (chr_upper, chr_banks) = (self.chr_upper as usize, self.chr_banks);
+        let chr = |register: usize| chr_upper << 8 | chr_banks[register] as usize;
+        for window in 0..8 {
+            let bank = match self.chr_mode {
+                0 => chr(7) * 8 + window,
+                1 => chr(3 | (window & 0b100)) * 4 + window % 4,
+                2 => chr(1 | (window & 0b110)) * 2 + window % 2,
+                _ => chr(window),
+            };
+            self.chr.set_bank(window, bank);
+        }
+
+        self.prg_ram
+            .set_bank(0, (self.prg_ram_bank & 0b111) as usize);
+    }
+
+    fn is_prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0b10, 0b01]
+    }
+
+    /// The IRQ status at $5204.
+    fn irq_status(&self) -> u8 {
+        (self.is_irq_pending.get() as u8) << 7 | (self.is_in_frame as u8) << 6
+    }
+
+    /// The nametable mode for one of the 4 logical nametables, from $5105.
+    fn nametable_mode(&self, addr: u16) -> u8 {
+        let logical = (addr & 0x0fff) / 0x400;
+        (self.nametable_mapping >> (logical * 2)) & 0b11
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5204 => {
+                let status = self.irq_status();
+                self.is_irq_pending.set(false);
+                Some(status)
+            }
+            0x5205 => Some((self.multiplicand as u16 * self.multiplier as u16) as u8),
+            0x5206 => {
+                Some(((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8)
+            }
+            // ExRAM can only be read by the CPU in the RAM modes.
+            0x5c00..=0x5fff if self.exram_mode >= 2 => {
+                Some(self.exram[addr as usize - 0x5c00])
+            }
+            0x6000..=0x7fff => Some(self.prg_ram.read(addr as usize - 0x6000)),
+            0x8000..=0xffff => Some(self.prg_rom.read(addr as usize - 0x8000)),
+            _ => None,
+        }
+    }
+
+    fn peek_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5204 => Some(self.irq_status()),
+            _ => self.read_cpu(addr),
+        }
+    }
+
+    fn write_cpu(&mut self, addr: u16, value: u8) -> bool {
+        match addr {
+            0x5100 => self.prg_mode = value & 0b11,
+            0x5101 => self.chr_mode = value & 0b11,
+            0x5102 => self.prg_ram_protect[0] = value & 0b11,
+            0x5103 => self.prg_ram_protect[1] = value & 0b11,
+            0x5104 => self.exram_mode = value & 0b11,
+            0x5105 => self.nametable_mapping = value,
+            0x5106 => self.fill_tile = value,
+            0x5107 => self.fill_attribute = value & 0b11,
+            0x5113 => self.prg_ram_bank = value,
+            0x5114..=0x5117 => self.prg_banks[addr as usize - 0x5114] = value,
+            0x5120..=0x5127 => self.chr_banks[addr as usize - 0x5120] = value,
+            0x5130 => self.chr_upper = value & 0b11,
+            0x5203 => self.irq_scanline = value,
+            0x5204 => self.is_irq_enabled = value & 0b1000_0000 != 0,
+            0x5205 => self.multiplicand = value,
+            0x5206 => self.multiplier = value,
+            // ExRAM is read only in mode 3.
+            0x5c00..=0x5fff => {
+                if self.exram_mode != 3 {
+                    self.exram[addr as usize - 0x5c00] = value;
+                }
+            }
+            0x6000..=0x7fff => {
+                if self.is_prg_ram_writable() {
+                    self.prg_ram.write(addr as usize - 0x6000, value);
+                }
+            }
+            // The unimplemented registers, and the ROM.
+            0x5000..=0x5fff | 0x8000..=0xffff => {}
+            _ => return false,
+        }
+        self.update_banks();
+        true
+    }
+
+    /// The CIRAM pages are mapped through the mirroring, and read_nametable covers the
+    /// ExRAM and fill mode nametables.
+    fn mirroring(&self) -> Mirroring {
+        let page = |logical: u8| (self.nametable_mapping >> (logical * 2)) & 0b1;
+        Mirroring::Mapped([page(0), page(1), page(2), page(3)])
+    }
+
+    fn irq(&self) -> bool {
+        self.is_irq_enabled && self.is_irq_pending.get()
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn chr_offset(&self, addr: u16) -> Option<usize> {
+        Some(self.chr.offset(addr as usize))
+    }
+
+    fn prg_banks(&self) -> Vec<(&'static str, Bank)> {
+        prg_banks(Some(&self.prg_ram), &self.prg_rom)
+    }
+
+    fn chr_banks(&self) -> Vec<Bank> {
+        self.chr.banks(0)
+    }
+
+    fn chr_data(&self) -> &[u8] {
+        self.chr.data()
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        if self.is_chr_ram {
+            self.chr.write(addr as usize, value);
+        }
+    }
+
+    fn read_nametable(&self, addr: u16) -> Option<u8> {
+        let offset = addr as usize & (EXRAM_SIZE - 1);
+        match self.nametable_mode(addr) {
+            // ExRAM is only a nametable in the first two modes, otherwise it reads 0.
+            2 if self.exram_mode <= 1 => Some(self.exram[offset]),
+            2 => Some(0),
+            3 if offset < 0x3c0 => Some(self.fill_tile),
+            // Copy the fill attribute into every quadrant.
+            3 => Some(self.fill_attribute * 0b0101_0101),
+            _ => None,
+        }
+    }
+
+    fn write_nametable(&mut self, addr: u16, value: u8) -> bool {
+        match self.nametable_mode(addr) {
+            2 => {
+                if self.exram_mode <= 1 {
+                    self.exram[addr as usize & (EXRAM_SIZE - 1)] = value;
+                }
+                true
+            }
+            3 => true,
+            _ => false,
+        }
+    }
+
+    /// The MMC5 counts the scanlines that are rendered. The real chip detects them by
+    /// watching the PPU fetch the same nametable byte 3 times at the end of a line.
+    fn start_scanline(&mut self, scanline: u64, is_rendering: bool) {
+        if !is_rendering || scanline >= SCREEN_HEIGHT as u64 {
+            self.is_in_frame = false;
+            return;
+        }
+        if self.is_in_frame {
+            self.scanline_counter = self.scanline_counter.wrapping_add(1);
+            if self.scanline_counter == self.irq_scanline {
+                self.is_irq_pending.set(true);
+            }
+        } else {
+            self.is_in_frame = true;
+            self.scanline_counter = 0;
+        }
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        match self.prg_ram.data() {
+            [] => None,
+            ram => Some(ram),
+        }
+    }
+
+    fn load_prg_ram(&mut self, ram: &[u8]) -> Result<(), String> {
+        self.prg_ram.load_data(ram)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        for value in [
+            self.prg_mode,
+            self.chr_mode,
+            self.prg_ram_protect[0],
+            self.prg_ram_protect[1],
+            self.exram_mode,
+            self.nametable_mapping,
+            self.fill_tile,
+            self.fill_attribute,
+            self.prg_ram_bank,
+            self.chr_upper,
+            self.irq_scanline,
+            self.scanline_counter,
+            self.multiplicand,
+            self.multiplier,
+        ] {
+            writer.u8(value);
+        }
+        writer.bool(self.is_irq_enabled);
+        writer.bool(self.is_irq_pending.get());
+        writer.bool(self.is_in_frame);
+        writer.bytes(&self.prg_banks);
+        writer.bytes(&self.chr_banks);
+        writer.bytes(&self.exram);
+        writer.bytes(self.prg_ram.data());
+        writer.bytes(if self.is_chr_ram {
+            self.chr.data()
+        } else {
+            &[]
+        });
+        writer.into_bytes()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(state);
+        let mut registers = [0; 14];
+        for register in registers.iter_mut() {
+            *register = reader.u8()?;
+        }
+        let is_irq_enabled = reader.bool()?;
+        let is_irq_pending = reader.bool()?;
+        let is_in_frame = reader.bool()?;
+        let prg_banks = reader.bytes()?;
+        let chr_banks = reader.bytes()?;
+        let exram = reader.bytes()?;
+        let prg_ram = reader.bytes()?;
+        let chr_ram = reader.bytes()?;
+        reader.finish()?;
+        let chr_ram_size = if self.is_chr_ram {
+            self.chr.data().len()
+        } else {
+            0
+        };
+        if prg_banks.len() != self.prg_banks.len()
+            || chr_banks.len() != self.chr_banks.len()
+            || exram.len() != EXRAM_SIZE
+            || prg_ram.len() != self.prg_ram.data().len()
+            || chr_ram.len() != chr_ram_size
+        {
+            return Err("The MMC5 save state has the wrong amount of memory.".into());
+        }
+
+        let [prg_mode, chr_mode, protect_1, protect_2, exram_mode, nametable_mapping, fill_tile, fill_attribute, prg_ram_bank, chr_upper, irq_scanline, scanline_counter, multiplicand, multiplier] =
+            registers;
+        self.prg_mode = prg_mode & 0b11;
+        self.chr_mode = chr_mode & 0b11;
+        self.prg_ram_protect = [protect_1, protect_2];
+        self.exram_mode = exram_mode & 0b11;
+        self.nametable_mapping = nametable_mapping;
+        self.fill_tile = fill_tile;
+        self.fill_attribute = fill_attribute & 0b11;
+        self.prg_ram_bank = prg_ram_bank;
+        self.chr_upper = chr_upper & 0b11;
+        self.irq_scanline = irq_scanline;
+        self.scanline_counter = scanline_counter;
+        self.multiplicand = multiplicand;
+        self.multiplier = multiplier;
+        self.is_irq_enabled = is_irq_enabled;
+        self.is_irq_pending.set(is_irq_pending);
+        self.is_in_frame = is_in_frame;
+        self.prg_banks.copy_from_slice(prg_banks);
+        self.chr_banks.copy_from_slice(chr_banks);
+        self.exram.copy_from_slice(exram);
+        self.prg_ram.load_data(prg_ram)?;
+        if self.is_chr_ram {
+            self.chr.load_data(chr_ram)?;
+        }
+        self.update_banks();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::ines_bytes;
+
+    /// 128KB of PRG ROM and 64KB of CHR ROM.
+    fn mmc5() -> Mmc5 {
+        let rom = InesRom::from_ines_bytes(&ines_bytes(0x50, 8, 8)).unwrap();
+        Mmc5::new(rom).unwrap()
+    }
+
+    #[test]
+    fn test_prg_modes() {
+        let mut mapper = mmc5();
+        // At power up, the last 8KB bank is at $E000. The 16KB test banks are 2 of
+        // the MMC5's 8KB banks each.
+        assert_eq!(mapper.read_cpu(0xffff), Some(7));
+
+        // 8KB mode.
+        mapper.write_cpu(0x5114, 0x80 | 2);
+        assert_eq!(mapper.read_cpu(0x8000), Some(1));
+        // 16KB + 8KB + 8KB mode ignores the low bit of $5115.
+        mapper.write_cpu(0x5100, 2);
+        mapper.write_cpu(0x5115, 0x80 | 5);
+        mapper.write_cpu(0x5116, 0x80 | 6);
+        assert_eq!(mapper.read_cpu(0x8000), Some(2));
+        assert_eq!(mapper.read_cpu(0xa000), Some(2));
+        assert_eq!(mapper.read_cpu(0xc000), Some(3));
+        // 32KB mode uses $5117.
+        mapper.write_cpu(0x5100, 0);
+        mapper.write_cpu(0x5117, 0x80 | 9);
+        assert_eq!(mapper.read_cpu(0x8000), Some(4));
+        assert_eq!(mapper.read_cpu(0xe000), Some(5));
+    }
+
+    #[test]
+    fn test_chr_modes() {
+        let mut mapper = mmc5();
+        // 1KB mode, the 8KB test banks are 8 of the MMC5's 1KB banks.
+        mapper.write_cpu(0x5101, 3);
+        mapper.write_cpu(0x5123, 8 * 5 + 1);
+        assert_eq!(mapper.read_chr(0x0c00), 0x85);
+        assert_eq!(mapper.chr_offset(0x0c01), Some((8 * 5 + 1) * 0x400 + 1));
+        // 4KB mode, $5127 sets $1000-$1FFF.
+        mapper.write_cpu(0x5101, 1);
+        mapper.write_cpu(0x5127, 2 * 3 + 1);
+        assert_eq!(mapper.read_chr(0x1fff), 0x83);
+    }
+
+    #[test]
+    fn test_nametables() {
+        let mut mapper = mmc5();
+        // $2000 CIRAM page 0, $2400 page 1, $2800 ExRAM, $2C00 fill mode.
+        mapper.write_cpu(0x5105, 0b11_10_01_00);
+        assert_eq!(mapper.mirroring(), Mirroring::Mapped([0, 1, 0, 1]));
+        assert_eq!(mapper.read_nametable(0x2000), None);
+
+        assert!(mapper.write_nametable(0x2805, 0x42));
+        assert_eq!(mapper.read_nametable(0x2805), Some(0x42));
+        assert_eq!(mapper.read_cpu(0x5c05), None);
+
+        mapper.write_cpu(0x5106, 0x24);
+        mapper.write_cpu(0x5107, 0b10);
+        assert_eq!(mapper.read_nametable(0x2c00), Some(0x24));
+        assert_eq!(mapper.read_nametable(0x2fc0), Some(0b1010_1010));
+
+        // In the RAM mode, the CPU can use ExRAM, and it's no longer a nametable.
+        mapper.write_cpu(0x5104, 2);
+        assert_eq!(mapper.read_cpu(0x5c05), Some(0x42));
+        assert_eq!(mapper.read_nametable(0x2805), Some(0));
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mapper = mmc5();
+        mapper.write_cpu(0x5203, 100);
+        mapper.write_cpu(0x5204, 0x80);
+        for scanline in 0..100 {
+            mapper.start_scanline(scanline, true);
+        }
+        assert!(!mapper.irq());
+        assert_eq!(mapper.read_cpu(0x5204), Some(0b0100_0000));
+
+        mapper.start_scanline(100, true);
+        assert!(mapper.irq());
+        // Peeking leaves the IRQ pending, while reading the status acknowledges it.
+        assert_eq!(mapper.peek_cpu(0x5204), Some(0b1100_0000));
+        assert!(mapper.irq());
+        assert_eq!(mapper.read_cpu(0x5204), Some(0b1100_0000));
+        assert!(!mapper.irq());
+
+        mapper.start_scanline(240, true);
+        assert_eq!(mapper.read_cpu(0x5204), Some(0));
+    }
+
+    #[test]
+    fn test_multiplier_and_save_state() {
+        let mut mapper = mmc5();
+        mapper.write_cpu(0x5205, 200);
+        mapper.write_cpu(0x5206, 100);
+        assert_eq!(mapper.read_cpu(0x5205), Some((20000 & 0xff) as u8));
+        assert_eq!(mapper.read_cpu(0x5206), Some((20000 >> 8) as u8));
+
+        // PRG RAM is only writable after both protect registers are set.
+        mapper.write_cpu(0x6000, 0x11);
+        assert_eq!(mapper.read_cpu(0x6000), Some(0));
+        mapper.write_cpu(0x5102, 0b10);
+        mapper.write_cpu(0x5103, 0b01);
+        mapper.write_cpu(0x6000, 0x11);
+        mapper.write_cpu(0x5114, 0x80 | 4);
+
+        let mut loaded = mmc5();
+        loaded.load_state(&mapper.save_state()).unwrap();
+        assert_eq!(loaded.read_cpu(0x6000), Some(0x11));
+        assert_eq!(loaded.read_cpu(0x8000), Some(2));
+        assert_eq!(loaded.read_cpu(0x5206), Some((20000 >> 8) as u8));
+    }
+}
@@ -0,0 +1,165 @@
+mod banks;
+mod cnrom;
+mod fallback;
+mod mmc1;
+mod mmc5;
+mod nrom;
+mod simple;
+mod uxrom;
+
+use crate::ppu::{render::PatternTables, Mirroring};
+use crate::rom::InesRom;
+
+// Re-export the mappers.
+pub use banks::*;
+pub use cnrom::*;
+pub use fallback::*;
+pub use mmc1::*;
+pub use mmc5::*;
+pub use nrom::*;
+pub use simple::*;
+pub use uxrom::*;
+
+/// Mappers are Send so that the emulator that owns them can move between threads.
+pub trait Mapper: Send {
+    fn read_cpu(&self, addr: u16) -> Option<u8>;
+    fn write_cpu(&mut self, addr: u16, value: u8) -> bool;
+
+    /// Read without side effects, for debuggers. Only the mappers with registers that
+    /// change when they're read need to override it.
+    fn peek_cpu(&self, addr: u16) -> Option<u8> {
+        self.read_cpu(addr)
+    }
+
+    /// The nametable mirroring, this can change at runtime for mappers like the MMC1.
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+
+    /// Mappers with a scanline or cycle counter hold the IRQ line until the program
+    /// acknowledges it.
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Read the pattern tables at $0000-$1FFF of the PPU address space.
+    fn read_chr(&self, _addr: u16) -> u8 {
+        0
+    }
+
+    /// Where a pattern table address lands in the cartridge's CHR ROM or RAM, after
+    /// bank switching, for debuggers.
+    fn chr_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    /// The banks that are switched into the pattern tables, for debuggers that follow
+    /// the bank switching. It's empty for cartridges without banked CHR.
+    fn chr_banks(&self) -> Vec<Bank> {
+        Vec::new()
+    }
+
+    /// All of the CHR ROM or RAM, before bank switching, for debuggers that draw it
+    /// through other banks than the ones that are switched in.
+    fn chr_data(&self) -> &[u8] {
+        &[]
+    }
+
+    /// The banks that are switched into the CPU address space, named for memory maps,
+    /// e.g. "PRG ROM" at $8000. It's empty for mappers that don't report them.
+    fn prg_banks(&self) -> Vec<(&'static str, Bank)> {
+        Vec::new()
+    }
+
+    /// Only cartridges with CHR RAM keep the written value.
+    fn write_chr(&mut self, _addr: u16, _value: u8) {}
+
+    /// Read a nametable from the cartridge's own memory. None uses the console's VRAM,
+    /// through the mirroring.
+    fn read_nametable(&self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    /// Returns true when the cartridge's memory took the write.
+    fn write_nametable(&mut self, _addr: u16, _value: u8) -> bool {
+        false
+    }
+
+    /// Called when the PPU moves on to a new scanline, for mappers that count them.
+    fn start_scanline(&mut self, _scanline: u64, _is_rendering: bool) {}
+
+    /// The PRG RAM at $6000-$7FFF, which a battery keeps between sessions on some
+    /// cartridges. None when the cartridge doesn't have any.
+    fn prg_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn load_prg_ram(&mut self, _ram: &[u8]) -> Result<(), String> {
+        Err("The cartridge doesn't have any PRG RAM.".into())
+    }
+
+    /// Bank registers and PRG RAM go into save states. The ROM itself isn't saved.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        if state.is_empty() {
+            Ok(())
+        } else {
+            Err("The save state has mapper data, but this mapper has no state.".into())
+        }
+    }
+
+    /// What the game wrote to the cartridge, when it's booted with the FallbackMapper
+    /// because its own mapper isn't supported.
+    fn fallback_report(&self) -> Option<FallbackReport> {
+        None
+    }
+}
+
+/// The iNES mapper numbers that `create_mapper` builds.
+pub const SUPPORTED_MAPPERS: [u8; 5] = [0, 1, 2, 3, 5];
+
+/// Build the cartridge hardware for an iNES mapper number.
+pub fn create_mapper(mapper_id: u8, rom: InesRom) -> Result<Box<dyn Mapper>, String> {
+    Ok(match mapper_id {
+        0 => Box::new(Nrom::new(rom)?),
+        1 => Box::new(Mmc1::new(rom)?),
+        2 => Box::new(Uxrom::new(rom)?),
+        3 => Box::new(Cnrom::new(rom)?),
+        5 => Box::new(Mmc5::new(rom)?),
+        _ => return Err(format!("Mapper {} isn't supported yet.", mapper_id)),
+    })
+}
+
+/// Lets the PPU read the pattern tables through the cartridge.
+pub struct CartridgeChr<M>(pub M);
+
+impl<M: Mapper + ?Sized> PatternTables for CartridgeChr<&M> {
+    fn read_chr(&self, address: u16) -> u8 {
+        self.0.read_chr(address)
+    }
+
+    fn read_nametable(&self, address: u16) -> Option<u8> {
+        self.0.read_nametable(address)
+    }
+}
+
+impl<M: Mapper + ?Sized> PatternTables for CartridgeChr<&mut M> {
+    fn read_chr(&self, address: u16) -> u8 {
+        self.0.read_chr(address)
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        self.0.write_chr(address, value);
+    }
+
+    fn read_nametable(&self, address: u16) -> Option<u8> {
+        self.0.read_nametable(address)
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8) -> bool {
+        self.0.write_nametable(address, value)
+    }
+}
@@ -0,0 +1,156 @@
+use super::{prg_banks, Bank, BankedMemory, Mapper};
+use crate::ppu::Mirroring;
+use crate::rom::{InesRom, CHR_BANK_SIZE, PRG_BANK_SIZE};
+
+/// Mapper 0, the cartridge used by the first generation of games like Super Mario
+/// Bros. There is no bank switching, the PRG ROM is 16KB or 32KB, and a 16KB ROM is
+/// mirrored into both halves of $8000-$FFFF.
+///
+/// https://www.nesdev.org/wiki/NROM
+pub struct Nrom {
+    prg_rom: BankedMemory,
+    /// Only Family Basic had PRG RAM, but providing it is harmless.
+    prg_ram: BankedMemory,
+    chr: BankedMemory,
+    is_chr_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: InesRom) -> Result<Nrom, String> {
+        let prg_size = rom.prg_rom.len();
+        if prg_size != PRG_BANK_SIZE && prg_size != 2 * PRG_BANK_SIZE {
+            return Err(format!(
+                "NROM cartridges have 16KB or 32KB of PRG ROM, but this one has {}KB.",
+                prg_size / 1024
+            ));
+        }
+        let is_chr_ram = rom.chr_rom.is_empty();
+        let prg_ram = BankedMemory::prg_ram(&rom)?;
+        let mut prg_rom = BankedMemory::new(rom.prg_rom, PRG_BANK_SIZE, 2)?;
+        // A 16KB ROM shows up in both windows.
+        prg_rom.set_bank(1, prg_rom.last_bank());
+        Ok(Nrom {
+            prg_rom,
+            prg_ram,
+            chr: BankedMemory::chr(rom.chr_rom, CHR_BANK_SIZE, 1)?,
+            is_chr_ram,
+            mirroring: rom.mirroring,
+        })
+    }
+}
+
+impl Mapper for Nrom {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => Some(self.prg_ram.read(addr as usize - 0x6000)),
+            0x8000..=0xffff => Some(self.prg_rom.read(addr as usize - 0x8000)),
+            _ => None,
+        }
+    }
+
+    fn write_cpu(&mut self, addr: u16, value: u8) -> bool {
+        match addr {
+            0x6000..=0x7fff => {
+                self.prg_ram.write(addr as usize - 0x6000, value);
+                true
+            }
+            // Writes to ROM go nowhere.
+            0x8000..=0xffff => true,
+            _ => false,
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn chr_offset(&self, addr: u16) -> Option<usize> {
+        Some(self.chr.offset(addr as usize))
+    }
+
+    fn prg_banks(&self) -> Vec<(&'static str, Bank)> {
+        prg_banks(Some(&self.prg_ram), &self.prg_rom)
+    }
+
+    fn chr_banks(&self) -> Vec<Bank> {
+        self.chr.banks(0)
+    }
+
+    fn chr_data(&self) -> &[u8] {
+        self.chr.data()
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        if self.is_chr_ram {
+            self.chr.write(addr as usize, value);
+        }
+    }
+
+    /// The PRG RAM, followed by the CHR RAM when there is some.
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = self.prg_ram.data().to_vec();
+        if self.is_chr_ram {
+            state.extend_from_slice(self.chr.data());
+        }
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let prg_ram_size = self.prg_ram.data().len();
+        let chr_size = if self.is_chr_ram { CHR_BANK_SIZE } else { 0 };
+        if state.len() != prg_ram_size + chr_size {
+            return Err(format!(
+                "Expected {} bytes of NROM state, found {}.",
+                prg_ram_size + chr_size,
+                state.len()
+            ));
+        }
+        let (prg_ram, chr) = state.split_at(prg_ram_size);
+        self.prg_ram.load_data(prg_ram)?;
+        if self.is_chr_ram {
+            self.chr.load_data(chr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::ines_bytes;
+
+    #[test]
+    fn test_mirrored_prg_rom() {
+        let mut bytes = ines_bytes(0b0000_0001, 1, 1);
+        bytes[16] = 0x42;
+        let mapper = InesRom::from_ines_bytes(&bytes)
+            .unwrap()
+            .into_mapper()
+            .unwrap();
+        assert_eq!(mapper.read_cpu(0x8000), Some(0x42));
+        assert_eq!(mapper.read_cpu(0xc000), Some(0x42));
+        assert_eq!(mapper.read_chr(0x1fff), 0x80);
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let rom = InesRom::from_ines_bytes(&ines_bytes(0, 2, 0)).unwrap();
+        let mut mapper = Nrom::new(rom).unwrap();
+        mapper.write_chr(0x0123, 0x55);
+        mapper.write_cpu(0x6000, 0x66);
+        assert_eq!(mapper.read_chr(0x0123), 0x55);
+
+        let state = mapper.save_state();
+        let rom = InesRom::from_ines_bytes(&ines_bytes(0, 2, 0)).unwrap();
+        let mut loaded = Nrom::new(rom).unwrap();
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.read_chr(0x0123), 0x55);
+        assert_eq!(loaded.read_cpu(0x6000), Some(0x66));
+    }
+}
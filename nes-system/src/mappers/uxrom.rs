@@ -0,0 +1,147 @@
+use super::{prg_banks, Bank, BankedMemory, Mapper};
+use crate::ppu::Mirroring;
+use crate::rom::{InesRom, CHR_BANK_SIZE, PRG_BANK_SIZE};
+use crate::save_state::{StateReader, StateWriter};
+
+/// Mapper 2, used by games like Mega Man and Castlevania. Writing to $8000-$FFFF
+/// switches the 16KB PRG bank at $8000, and the last bank is fixed at $C000. The
+/// pattern tables are almost always CHR RAM.
+///
+/// Bus conflicts aren't emulated, the written value is always the one used.
+///
+/// https://www.nesdev.org/wiki/UxROM
+pub struct Uxrom {
+    prg_rom: BankedMemory,
+    chr: BankedMemory,
+    is_chr_ram: bool,
+    mirroring: Mirroring,
+    prg_bank: u8,
+}
+
+impl Uxrom {
+    pub fn new(rom: InesRom) -> Result<Uxrom, String> {
+        if rom.prg_rom.len() < PRG_BANK_SIZE {
+            return Err("UxROM cartridges need at least one PRG ROM bank.".into());
+        }
+        let is_chr_ram = rom.chr_rom.is_empty();
+        let mut prg_rom = BankedMemory::new(rom.prg_rom, PRG_BANK_SIZE, 2)?;
+        prg_rom.set_bank(1, prg_rom.last_bank());
+        Ok(Uxrom {
+            prg_rom,
+            chr: BankedMemory::chr(rom.chr_rom, CHR_BANK_SIZE, 1)?,
+            is_chr_ram,
+            mirroring: rom.mirroring,
+            prg_bank: 0,
+        })
+    }
+}
+
+impl Mapper for Uxrom {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xffff => Some(self.prg_rom.read(addr as usize - 0x8000)),
+            _ => None,
+        }
+    }
+
+    fn write_cpu(&mut self, addr: u16, value: u8) -> bool {
+        if addr >= 0x8000 {
+            self.prg_bank = value;
+            self.prg_rom.set_bank(0, value as usize);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn chr_offset(&self, addr: u16) -> Option<usize> {
+        Some(self.chr.offset(addr as usize))
+    }
+
+    fn prg_banks(&self) -> Vec<(&'static str, Bank)> {
+        prg_banks(None, &self.prg_rom)
+    }
+
+    fn chr_banks(&self) -> Vec<Bank> {
+        self.chr.banks(0)
+    }
+
+    fn chr_data(&self) -> &[u8] {
+        self.chr.data()
+    }
+
+    fn write_chr(&mut self, addr: u16, value: u8) {
+        if self.is_chr_ram {
+            self.chr.write(addr as usize, value);
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.u8(self.prg_bank);
+        writer.bytes(if self.is_chr_ram {
+            self.chr.data()
+        } else {
+            &[]
+        });
+        writer.into_bytes()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(state);
+        let prg_bank = reader.u8()?;
+        let chr_ram = reader.bytes()?;
+        reader.finish()?;
+        let chr_ram_size = if self.is_chr_ram {
+            self.chr.data().len()
+        } else {
+            0
+        };
+        if chr_ram.len() != chr_ram_size {
+            return Err("The UxROM save state has the wrong amount of CHR RAM.".into());
+        }
+        self.prg_bank = prg_bank;
+        self.prg_rom.set_bank(0, prg_bank as usize);
+        if self.is_chr_ram {
+            self.chr.load_data(chr_ram)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mappers::create_mapper;
+    use crate::rom::ines_bytes;
+
+    #[test]
+    fn test_prg_banks() {
+        let rom = InesRom::from_ines_bytes(&ines_bytes(0x20, 8, 0)).unwrap();
+        let mut mapper = create_mapper(2, rom).unwrap();
+        assert_eq!(mapper.read_cpu(0x8000), Some(0));
+        assert_eq!(mapper.read_cpu(0xc000), Some(7));
+
+        mapper.write_cpu(0x8000, 5);
+        assert_eq!(mapper.read_cpu(0xbfff), Some(5));
+        assert_eq!(mapper.read_cpu(0xffff), Some(7));
+
+        mapper.write_chr(0x1000, 0x42);
+        let mut loaded = create_mapper(
+            2,
+            InesRom::from_ines_bytes(&ines_bytes(0x20, 8, 0)).unwrap(),
+        )
+        .unwrap();
+        loaded.load_state(&mapper.save_state()).unwrap();
+        assert_eq!(loaded.read_cpu(0x8000), Some(5));
+        assert_eq!(loaded.read_chr(0x1000), 0x42);
+    }
+}
@@ -0,0 +1,167 @@
+//! The CPU memory map, built from the `memory_range` constants and the banks that the
+//! cartridge has switched in, so that the debuggers document the same layout that the
+//! bus uses.
+
+use crate::constants::memory_range::{self, Range};
+use crate::mappers::Bank;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryRegion {
+    pub start: u16,
+    /// The last address in the region, as the cartridge space ends at $FFFF.
+    pub end: u16,
+    pub name: String,
+    pub description: String,
+}
+
+impl MemoryRegion {
+    pub fn contains(&self, address: u16) -> bool {
+        (self.start..=self.end).contains(&address)
+    }
+
+    pub fn size(&self) -> usize {
+        (self.end - self.start) as usize + 1
+    }
+}
+
+fn region(range: &Range, name: &str, description: &str) -> MemoryRegion {
+    MemoryRegion {
+        start: range.start,
+        end: range.end - 1,
+        name: name.into(),
+        description: description.into(),
+    }
+}
+
+fn cartridge_space(start: u16, end: u16) -> MemoryRegion {
+    MemoryRegion {
+        start,
+        end,
+        name: "Cartridge space".into(),
+        description: "Mapper registers and expansion memory, if the cartridge has any"
+            .into(),
+    }
+}
+
+/// Lay out the memory map, with the cartridge space split up by the mapper's
+/// `prg_banks`. The regions are in address order and cover all of $0000-$FFFF.
+pub fn memory_map(prg_banks: &[(&'static str, Bank)]) -> Vec<MemoryRegion> {
+    let mut regions = vec![
+        region(&memory_range::RAM_ACTUAL, "RAM", "2KB of internal RAM"),
+        region(
+            &Range {
+                start: memory_range::RAM_ACTUAL.end,
+                end: memory_range::RAM.end,
+            },
+            "RAM mirrors",
+            "Mirrors of $0000-$07FF",
+        ),
+        region(
+            &memory_range::PPU_ACTUAL,
+            "PPU registers",
+            "PPUCTRL, PPUMASK, PPUSTATUS, OAMADDR, OAMDATA, PPUSCROLL, PPUADDR, PPUDATA",
+        ),
+        region(
+            &memory_range::PPU,
+            "PPU register mirrors",
+            "Mirrors of $2000-$2007, every 8 bytes",
+        ),
+        region(
+            &memory_range::APU_AND_IO_REGISTERES,
+            "APU and I/O registers",
+            "The sound channels, OAMDMA at $4014, and the controllers at $4016-$4017",
+        ),
+        region(
+            &memory_range::DISABLED_APU_IO_FEATURES,
+            "CPU test mode",
+            "APU and I/O functionality that is normally disabled",
+        ),
+    ];
+
+    let mut banks: Vec<&(&'static str, Bank)> = prg_banks.iter().collect();
+    banks.sort_by_key(|(_, bank)| bank.address);
+    let mut next = memory_range::CARTRIDGE_SPACE.start;
+    for (name, bank) in banks {
+        if bank.address < next {
+            continue;
+        }
+        if bank.address > next {
+            regions.push(cartridge_space(next, bank.address - 1));
+        }
+        let end = bank.address + (bank.size - 1) as u16;
+        regions.push(MemoryRegion {
+            start: bank.address,
+            end,
+            name: format!("{} bank {}", name, bank.bank),
+            description: format!("{}KB window", bank.size / 1024),
+        });
+        match end.checked_add(1) {
+            Some(after) => next = after,
+            None => return regions,
+        }
+    }
+    regions.push(cartridge_space(next, memory_range::CARTRIDGE_SPACE.end));
+    regions
+}
+
+/// The memory map as a markdown table, for pasting into notes.
+pub fn to_markdown(regions: &[MemoryRegion]) -> String {
+    let mut markdown = String::from(
+        "| Start | End | Size | Region | Description |\n\
+         |-------|-----|------|--------|-------------|\n",
+    );
+    for region in regions {
+        markdown.push_str(&format!(
+            "| ${:04X} | ${:04X} | {} | {} | {} |\n",
+            region.start,
+            region.end,
+            region.size(),
+            region.name,
+            region.description
+        ));
+    }
+    markdown
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_memory_map() {
+        let bank = |address, size, bank| Bank {
+            address,
+            size,
+            bank,
+        };
+        let regions = memory_map(&[
+            ("PRG ROM", bank(0x8000, 0x4000, 3)),
+            ("PRG ROM", bank(0xc000, 0x4000, 7)),
+            ("PRG RAM", bank(0x6000, 0x2000, 0)),
+        ]);
+
+        // The regions cover the whole address space without gaps.
+        assert_eq!(regions[0].start, 0x0000);
+        for pair in regions.windows(2) {
+            assert_eq!(pair[0].end + 1, pair[1].start);
+        }
+        assert_eq!(regions.last().unwrap().end, 0xffff);
+
+        let names: Vec<&str> = regions[5..].iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "CPU test mode",
+                "Cartridge space",
+                "PRG RAM bank 0",
+                "PRG ROM bank 3",
+                "PRG ROM bank 7"
+            ]
+        );
+        let apu = regions.iter().find(|r| r.contains(0x4017)).unwrap();
+        assert_eq!(apu.name, "APU and I/O registers");
+
+        let markdown = to_markdown(&regions);
+        assert!(markdown.contains("| $C000 | $FFFF | 16384 | PRG ROM bank 7 |"));
+    }
+}
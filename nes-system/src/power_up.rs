@@ -0,0 +1,282 @@
+//! What the PPU and APU hold when the console is switched on. Most of it is
+//! unspecified, and each console comes up a little differently, so the presets follow
+//! the values that were measured on real hardware. Test ROMs check them, and a few
+//! games only work because of them.
+//!
+//! https://www.nesdev.org/wiki/PPU_power_up_state
+//! https://www.nesdev.org/wiki/CPU_power_up_state
+//!
+//! The CPU registers aren't part of the presets, see `Cpu6502::new`.
+//!
+//! The CPU and PPU clocks are both divided down from the master clock, and where the
+//! dividers start relative to each other is random on a real console. It moves which
+//! of the PPU's dots the CPU's reads and writes land on, which timing test ROMs can
+//! tell apart. It's picked separately from the presets, see `PpuAlignment`.
+//!
+//! https://www.nesdev.org/wiki/PPU_frame_timing#CPU-PPU_Clock_Alignment
+
+use crate::bus::Bus;
+use crate::ppu::registers::PpuStatusFlag;
+
+/// The palette RAM of a front loading NES, as read by blargg's power up test.
+#[rustfmt::skip]
+pub const MEASURED_PALETTE_RAM: [u8; 0x20] = [
+    0x09, 0x01, 0x00, 0x01, 0x00, 0x02, 0x02, 0x0d,
+    0x08, 0x10, 0x08, 0x24, 0x00, 0x00, 0x04, 0x2c,
+    0x09, 0x01, 0x34, 0x03, 0x00, 0x04, 0x00, 0x14,
+    0x08, 0x3a, 0x00, 0x02, 0x00, 0x20, 0x2c, 0x08,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PowerUpPreset {
+    /// Everything cleared to 0, for comparing with recordings made before the
+    /// presets.
+    Zeroed,
+    /// A front loading NES, with a 2A03G CPU and a 2C02G PPU.
+    #[default]
+    Nes,
+    /// An early Famicom, with a letterless 2A03 and a 2C02 PPU.
+    Famicom,
+}
+
+/// The PPU runs 3 dots for each CPU cycle. The alignments that fall between dots
+/// aren't modeled, so the phase is a whole number of dots.
+pub const PPU_ALIGNMENTS: u8 = 3;
+
+/// How many dots the PPU is ahead of the CPU when the console is switched on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PpuAlignment {
+    Fixed(u8),
+    /// Picked from a seed, like a real console that comes up differently each time.
+    Random(u64),
+}
+
+impl Default for PpuAlignment {
+    fn default() -> PpuAlignment {
+        PpuAlignment::Fixed(0)
+    }
+}
+
+impl PpuAlignment {
+    /// The phase in dots, from 0 to 2. The same seed always picks the same phase.
+    pub fn phase(self) -> u8 {
+        match self {
+            PpuAlignment::Fixed(phase) => phase % PPU_ALIGNMENTS,
+            PpuAlignment::Random(seed) => {
+                // A round of splitmix64, so that nearby seeds are spread out.
+                let mut value = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                ((value ^ (value >> 31)) % PPU_ALIGNMENTS as u64) as u8
+            }
+        }
+    }
+
+    /// "0" to "2", or "random" with a seed from the clock.
+    pub fn from_name(name: &str) -> Result<PpuAlignment, String> {
+        if name.eq_ignore_ascii_case("random") {
+            let seed = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|duration| duration.as_nanos() as u64)
+                .unwrap_or(0);
+            return Ok(PpuAlignment::Random(seed));
+        }
+        match name.parse() {
+            Ok(phase) if phase < PPU_ALIGNMENTS => Ok(PpuAlignment::Fixed(phase)),
+            _ => Err(format!(
+                "Unknown PPU alignment \"{}\", expected 0 to {}, or random",
+                name,
+                PPU_ALIGNMENTS - 1
+            )),
+        }
+    }
+}
+
+/// The values that the presets set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowerUpState {
+    pub palette_ram: [u8; 0x20],
+    /// The vblank and sprite overflow flags are usually set at power up.
+    pub ppu_status: u8,
+    /// The letterless 2A03 doesn't reset its frame counter, it comes up part way into
+    /// the sequence.
+    pub apu_frame_cycle: u64,
+}
+
+impl PowerUpPreset {
+    pub const ALL: [PowerUpPreset; 3] = [
+        PowerUpPreset::Zeroed,
+        PowerUpPreset::Nes,
+        PowerUpPreset::Famicom,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PowerUpPreset::Zeroed => "zeroed",
+            PowerUpPreset::Nes => "nes",
+            PowerUpPreset::Famicom => "famicom",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<PowerUpPreset, String> {
+        PowerUpPreset::ALL
+            .iter()
+            .copied()
+            .find(|preset| preset.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                let names: Vec<&str> = PowerUpPreset::ALL
+                    .iter()
+                    .map(|preset| preset.name())
+                    .collect();
+                format!(
+                    "Unknown power up preset \"{}\", expected one of: {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+
+    pub fn state(self) -> PowerUpState {
+        let status = PpuStatusFlag::VBlank as u8 | PpuStatusFlag::SpriteOverflow as u8;
+        match self {
+            PowerUpPreset::Zeroed => PowerUpState {
+                palette_ram: [0; 0x20],
+                ppu_status: 0,
+                apu_frame_cycle: 0,
+            },
+            PowerUpPreset::Nes => PowerUpState {
+                palette_ram: MEASURED_PALETTE_RAM,
+                ppu_status: status,
+                apu_frame_cycle: 0,
+            },
+            PowerUpPreset::Famicom => PowerUpState {
+                palette_ram: MEASURED_PALETTE_RAM,
+                ppu_status: status,
+                apu_frame_cycle: 15,
+            },
+        }
+    }
+}
+
+impl Bus {
+    /// Put the PPU and APU in their power up state. It should be applied before
+    /// anything runs.
+    pub fn power_up(&mut self, preset: PowerUpPreset) {
+        let state = preset.state();
+        // The mirrored entries at $3F10/$3F14/$3F18/$3F1C are written last, and the
+        // measurements agree with the entries they mirror.
+        for (offset, value) in state.palette_ram.iter().enumerate() {
+            self.ppu
+                .state
+                .palette_ram
+                .write(0x3f00 + offset as u16, *value);
+        }
+        for flag in [
+            PpuStatusFlag::SpriteOverflow,
+            PpuStatusFlag::Sprite0Hit,
+            PpuStatusFlag::VBlank,
+        ] {
+            self.ppu
+                .registers
+                .set_status_flag(flag, state.ppu_status & flag as u8 != 0);
+        }
+        self.apu.power_up(state.apu_frame_cycle);
+    }
+
+    /// Start the PPU a few dots ahead of the CPU. Like the presets, it should be
+    /// applied before anything runs.
+    pub fn align_ppu(&mut self, alignment: PpuAlignment) {
+        let phase = alignment.phase();
+        self.tick_ppu(phase as u64);
+        self.ppu_alignment = phase;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mappers::SimpleProgram;
+    use crate::ppu::registers::PPUSTATUS;
+    use crate::save_state::SaveState;
+    use crate::test_helpers::nes;
+    use mos6502_core::cpu_6502::Cpu6502;
+
+    fn power_up(preset: PowerUpPreset) -> Cpu6502<Bus> {
+        let mut cpu = nes(Box::new(SimpleProgram::load(&[])));
+        cpu.bus.power_up(preset);
+        cpu
+    }
+
+    #[test]
+    fn test_power_up() {
+        let cpu = power_up(PowerUpPreset::Nes);
+        let bus = &cpu.bus;
+        let palette: Vec<u8> = (0x3f00..0x3f20).map(|a| bus.peek_ppu(a)).collect();
+        assert_eq!(palette, MEASURED_PALETTE_RAM);
+        assert_eq!(bus.ppu.registers.status(), 0b1010_0000);
+
+        let mut cpu = power_up(PowerUpPreset::Zeroed);
+        let bus = &mut cpu.bus;
+        assert!((0x3f00..0x3f20).all(|a| bus.peek_ppu(a) == 0));
+        assert_eq!(bus.read_u8(PPUSTATUS), 0);
+
+        // The Famicom's frame counter is ahead, so the length counters run out
+        // sooner.
+        let length_cycles = |preset| {
+            let mut cpu = power_up(preset);
+            let apu = &mut cpu.bus.apu;
+            apu.write_register(0x4015, 0b0001);
+            // A length of 2, which runs out on the second half frame.
+            apu.write_register(0x4003, 0b0001_1000);
+            (1u64..)
+                .find(|_| {
+                    apu.tick();
+                    !apu.pulse_1.length.is_active()
+                })
+                .unwrap()
+        };
+        assert_eq!(length_cycles(PowerUpPreset::Nes), 29829);
+        assert_eq!(length_cycles(PowerUpPreset::Famicom), 29829 - 15);
+
+        assert_eq!(PowerUpPreset::default(), PowerUpPreset::Nes);
+        assert_eq!(
+            PowerUpPreset::from_name("Famicom"),
+            Ok(PowerUpPreset::Famicom)
+        );
+        assert!(PowerUpPreset::from_name("twin famicom").is_err());
+    }
+
+    #[test]
+    fn test_ppu_alignment() {
+        for phase in 0..PPU_ALIGNMENTS {
+            let mut cpu = power_up(PowerUpPreset::Nes);
+            cpu.bus.align_ppu(PpuAlignment::Fixed(phase));
+            cpu.tick();
+            let dots = cpu.bus.ppu.scanline_dot();
+
+            // The phase stays put through a save state, and the dots stay ahead.
+            let state = cpu.save_state();
+            let mut loaded = power_up(PowerUpPreset::Nes);
+            loaded.load_state(&state).unwrap();
+            assert_eq!(loaded.bus.ppu_alignment(), phase);
+            assert_eq!(dots % PPU_ALIGNMENTS as u64, phase as u64);
+        }
+
+        let phases: Vec<u8> = (0..30)
+            .map(|seed| PpuAlignment::Random(seed).phase())
+            .collect();
+        assert!((0..PPU_ALIGNMENTS).all(|phase| phases.contains(&phase)));
+        assert_eq!(
+            PpuAlignment::Random(7).phase(),
+            PpuAlignment::Random(7).phase()
+        );
+
+        assert_eq!(PpuAlignment::default().phase(), 0);
+        assert_eq!(PpuAlignment::from_name("2"), Ok(PpuAlignment::Fixed(2)));
+        assert!(matches!(
+            PpuAlignment::from_name("Random"),
+            Ok(PpuAlignment::Random(_))
+        ));
+        assert!(PpuAlignment::from_name("3").is_err());
+    }
+}
@@ -0,0 +1,506 @@
+pub mod palette_file;
+pub mod registers;
+pub mod render;
+
+use crate::save_state::{StateReader, StateWriter};
+
+// The palette used by the 2C02 PPU.
+// https://www.nesdev.org/wiki/PPU_palettes
+pub const NTSC_PALETTE: [[u8; 3]; 0x40] = [
+    // 0x00
+    [84, 84, 84],
+    [0, 30, 116],
+    [8, 16, 144],
+    [48, 0, 136],
+    [68, 0, 100],
+    [92, 0, 48],
+    [84, 4, 0],
+    [60, 24, 0],
+    // 0x08
+    [32, 42, 0],
+    [8, 58, 0],
+    [0, 64, 0],
+    [0, 60, 0],
+    [0, 50, 60],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    // 0x10
+    [152, 150, 152],
+    [8, 76, 196],
+    [48, 50, 236],
+    [92, 30, 228],
+    [136, 20, 176],
+    [160, 20, 100],
+    [152, 34, 32],
+    [120, 60, 0],
+    // 0x18
+    [84, 90, 0],
+    [40, 114, 0],
+    [8, 124, 0],
+    [0, 118, 40],
+    [0, 102, 120],
+    [0, 0, 0],
+    [0, 0, 0],
+    [0, 0, 0],
+    // 0x20
+    [236, 238, 236],
+    [76, 154, 236],
+    [120, 124, 236],
+    [176, 98, 236],
+    [228, 84, 236],
+    [236, 88, 180],
+    [236, 106, 100],
+    [212, 136, 32],
+    // 0x28
+    [160, 170, 0],
+    [116, 196, 0],
+    [76, 208, 32],
+    [56, 204, 108],
+    [56, 180, 204],
+    [60, 60, 60],
+    [0, 0, 0],
+    [0, 0, 0],
+    // 0x30
+    [236, 238, 236],
+    [168, 204, 236],
+    [188, 188, 236],
+    [212, 178, 236],
+    [236, 174, 236],
+    [236, 174, 212],
+    [236, 180, 176],
+    [228, 196, 144],
+    // 0x38
+    [204, 210, 120],
+    [180, 222, 120],
+    [168, 226, 144],
+    [152, 226, 180],
+    [160, 214, 228],
+    [160, 162, 160],
+    [0, 0, 0],
+    [0, 0, 0],
+];
+
+/// The NTSC PPU draws 3 dots for every CPU cycle.
+/// https://www.nesdev.org/wiki/Cycle_reference_chart
+pub const DOTS_PER_CPU_CYCLE: u64 = 3;
+pub const DOTS_PER_SCANLINE: u64 = 341;
+pub const SCANLINES_PER_FRAME: u64 = 262;
+pub const DOTS_PER_FRAME: u64 = DOTS_PER_SCANLINE * SCANLINES_PER_FRAME;
+
+/// PPUMASK ($2001) - This register controls the rendering of sprites and backgrounds,
+/// as well as colour effects.
+///
+/// https://www.nesdev.org/wiki/PPU_registers#PPUMASK
+///
+/// 7  bit  0
+/// ---- ----
+/// BGRs bMmG
+/// |||| ||||
+/// |||| |||+- Greyscale (0: normal color, 1: produce a greyscale display)
+/// |||| ||+-- 1: Show background in leftmost 8 pixels of screen, 0: Hide
+/// |||| |+--- 1: Show sprites in leftmost 8 pixels of screen, 0: Hide
+/// |||| +---- 1: Show background
+/// |||+------ 1: Show sprites
+/// ||+------- Emphasize red (green on PAL/Dendy)
+/// |+-------- Emphasize green (red on PAL/Dendy)
+/// +--------- Emphasize blue
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PpuMask(pub u8);
+
+#[rustfmt::skip]
+pub enum PpuMaskFlag {
+  Greyscale          = 0b00000001,
+  ShowBackgroundLeft = 0b00000010,
+  ShowSpritesLeft    = 0b00000100,
+  ShowBackground     = 0b00001000,
+  ShowSprites        = 0b00010000,
+  EmphasizeRed       = 0b00100000,
+  EmphasizeGreen     = 0b01000000,
+  EmphasizeBlue      = 0b10000000,
+}
+
+/// The left column that can be clipped by PPUMASK is 8 pixels wide.
+pub const LEFT_CLIP_WIDTH: u8 = 8;
+
+impl PpuMask {
+    pub fn is_set(&self, flag: PpuMaskFlag) -> bool {
+        let flag = flag as u8;
+        self.0 & flag == flag
+    }
+
+    /// Rendering is considered enabled when either the background or the sprites are
+    /// turned on. When both are off, the PPU is in "forced blank", and the CPU can
+    /// freely access the PPU memory.
+    pub fn is_rendering_enabled(&self) -> bool {
+        self.is_set(PpuMaskFlag::ShowBackground) || self.is_set(PpuMaskFlag::ShowSprites)
+    }
+
+    /// Games commonly hide the leftmost 8 pixels of the background to cover up the
+    /// attribute glitches that happen when scrolling horizontally.
+    pub fn is_background_visible_at(&self, x: u8) -> bool {
+        self.is_set(PpuMaskFlag::ShowBackground)
+            && (x >= LEFT_CLIP_WIDTH || self.is_set(PpuMaskFlag::ShowBackgroundLeft))
+    }
+
+    /// Sprites can't be partially moved off the left side of the screen, so games
+    /// hide the leftmost 8 pixels to let them smoothly scroll off.
+    pub fn is_sprite_visible_at(&self, x: u8) -> bool {
+        self.is_set(PpuMaskFlag::ShowSprites)
+            && (x >= LEFT_CLIP_WIDTH || self.is_set(PpuMaskFlag::ShowSpritesLeft))
+    }
+
+    /// Greyscale mode works by dropping the hue bits of the color, only keeping
+    /// the brightness bits. This selects a color from the grey column $x0.
+    pub fn apply_greyscale(&self, color: u8) -> u8 {
+        if self.is_set(PpuMaskFlag::Greyscale) {
+            color & 0x30
+        } else {
+            color
+        }
+    }
+}
+
+/// The palette RAM lives in the PPU at $3F00-$3F1F, and is mirrored up to $3FFF.
+/// Each entry is an index into the NTSC_PALETTE.
+///
+/// https://www.nesdev.org/wiki/PPU_palettes
+///
+/// $3F00           Universal background color (the backdrop)
+/// $3F01-$3F03     Background palette 0
+/// $3F05-$3F07     Background palette 1
+/// $3F09-$3F0B     Background palette 2
+/// $3F0D-$3F0F     Background palette 3
+/// $3F11-$3F13     Sprite palette 0
+/// $3F15-$3F17     Sprite palette 1
+/// $3F19-$3F1B     Sprite palette 2
+/// $3F1D-$3F1F     Sprite palette 3
+pub struct PaletteRam {
+    data: [u8; 0x20],
+}
+
+impl PaletteRam {
+    pub fn new() -> PaletteRam {
+        PaletteRam { data: [0; 0x20] }
+    }
+
+    /// Entries $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C.
+    fn map_address(address: u16) -> usize {
+        let index = (address & 0x1f) as usize;
+        if index & 0b11 == 0 {
+            index & 0x0f
+        } else {
+            index
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        // Only 6 bits are stored, the top 2 bits are open bus.
+        self.data[PaletteRam::map_address(address)] & 0x3f
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        self.data[PaletteRam::map_address(address)] = value & 0x3f;
+    }
+
+    pub fn backdrop(&self) -> u8 {
+        self.read(0x3f00)
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.data);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        let data = reader.bytes()?;
+        if data.len() != self.data.len() {
+            return Err(format!(
+                "Expected 32 palette entries, found {}.",
+                data.len()
+            ));
+        }
+        self.data.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// When rendering is disabled, the PPU outputs the backdrop color. The exception is
+/// when the current VRAM address "v" points into the palette RAM, then the color at
+/// that address is drawn instead. This is the "background palette hack" that some
+/// palette test ROMs use to show every color on screen.
+pub fn rendering_disabled_color(mask: PpuMask, palette_ram: &PaletteRam, v: u16) -> u8 {
+    let v = v & 0x3fff;
+    let color = if v >= 0x3f00 {
+        palette_ram.read(v)
+    } else {
+        palette_ram.backdrop()
+    };
+    mask.apply_greyscale(color)
+}
+
+/// Pixels coming from the background and sprite pipelines are 4 bit palette addresses,
+/// where the low 2 bits are the pattern value, and 0 means transparent.
+///
+/// 3210
+/// ||||
+/// ||++- Pixel value from the pattern table
+/// ++--- Palette number from the attribute table or sprite attributes
+pub struct SpritePixel {
+    pub value: u8,
+    pub is_behind_background: bool,
+    /// The first sprite in OAM is the only one that can set the sprite 0 hit flag.
+    pub is_sprite_zero: bool,
+}
+
+/// Sprite 0 hits when one of its opaque pixels lands on an opaque background pixel,
+/// even when it's behind the background. Games wait for the hit to time a scroll
+/// change partway down the screen, e.g. for a status bar. It can't hit at x = 255, or
+/// in the left column when either layer is clipped there.
+///
+/// https://www.nesdev.org/wiki/PPU_OAM#Sprite_zero_hits
+pub fn is_sprite_zero_hit(
+    mask: PpuMask,
+    x: u8,
+    background: u8,
+    sprite: &Option<SpritePixel>,
+) -> bool {
+    let is_sprite_zero = match sprite {
+        Some(sprite) => sprite.is_sprite_zero && sprite.value & 0b11 != 0,
+        None => false,
+    };
+    is_sprite_zero
+        && x != 255
+        && background & 0b11 != 0
+        && mask.is_background_visible_at(x)
+        && mask.is_sprite_visible_at(x)
+}
+
+/// Combine the background and sprite pixels at screen position x into the final color
+/// index into the NTSC_PALETTE, applying the PPUMASK left column clipping.
+///
+/// https://www.nesdev.org/wiki/PPU_rendering#Preface
+pub fn pixel_color(
+    mask: PpuMask,
+    palette_ram: &PaletteRam,
+    v: u16,
+    x: u8,
+    background: u8,
+    sprite: Option<SpritePixel>,
+) -> u8 {
+    if !mask.is_rendering_enabled() {
+        return rendering_disabled_color(mask, palette_ram, v);
+    }
+
+    // The clipped pixels are treated as transparent.
+    let background = if mask.is_background_visible_at(x) {
+        background & 0x0f
+    } else {
+        0
+    };
+    let sprite = match sprite {
+        Some(sprite) if mask.is_sprite_visible_at(x) && sprite.value & 0b11 != 0 => {
+            Some(sprite)
+        }
+        _ => None,
+    };
+
+    let is_background_opaque = background & 0b11 != 0;
+    let palette_address = match sprite {
+        Some(sprite) if !(sprite.is_behind_background && is_background_opaque) => {
+            // Sprites use the second half of the palette RAM.
+            0x3f10 | (sprite.value & 0x0f) as u16
+        }
+        _ if is_background_opaque => 0x3f00 | background as u16,
+        // Both are transparent, use the backdrop.
+        _ => 0x3f00,
+    };
+
+    mask.apply_greyscale(palette_ram.read(palette_address))
+}
+
+/// The PPU has 2KB of internal VRAM (CIRAM), which is enough for 2 nametables. The
+/// PPU address space has room for 4 nametables, so the cartridge decides how the 4
+/// logical nametables map onto the physical ones.
+///
+/// https://www.nesdev.org/wiki/Mirroring#Nametable_Mirroring
+///
+/// Horizontal            Vertical
+/// $2000 $2400           $2000 $2400
+/// +-----+-----+         +-----+-----+
+/// |  A  |  A  |         |  A  |  B  |
+/// +-----+-----+         +-----+-----+
+/// |  B  |  B  |         |  A  |  B  |
+/// +-----+-----+         +-----+-----+
+/// $2800 $2C00           $2800 $2C00
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    /// Every nametable points to the first page of VRAM.
+    SingleScreenLower,
+    /// Every nametable points to the second page of VRAM.
+    SingleScreenUpper,
+    /// The cartridge provides an extra 2KB of VRAM, so every nametable is unique.
+    FourScreen,
+    /// The cartridge picks the VRAM page behind each nametable, like the MMC5.
+    Mapped([u8; 4]),
+}
+
+pub const NAMETABLE_SIZE: u16 = 0x400;
+
+impl Mirroring {
+    /// Map a logical nametable (0-3, for $2000, $2400, $2800, $2C00) to the physical
+    /// nametable in VRAM that backs it.
+    pub fn physical_nametable(&self, logical: u8) -> u8 {
+        let logical = logical & 0b11;
+        match self {
+            Mirroring::Horizontal => logical >> 1,
+            Mirroring::Vertical => logical & 0b1,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => logical,
+            Mirroring::Mapped(pages) => pages[logical as usize] & 0b11,
+        }
+    }
+
+    /// Map a PPU address in the nametable space ($2000-$3EFF) to an offset into VRAM.
+    pub fn vram_offset(&self, address: u16) -> u16 {
+        // $3000-$3EFF mirrors $2000-$2EFF.
+        let address = address & 0x0fff;
+        let logical = (address / NAMETABLE_SIZE) as u8;
+        self.physical_nametable(logical) as u16 * NAMETABLE_SIZE
+            + address % NAMETABLE_SIZE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SHOW_ALL: u8 = PpuMaskFlag::ShowBackground as u8
+        | PpuMaskFlag::ShowSprites as u8
+        | PpuMaskFlag::ShowBackgroundLeft as u8
+        | PpuMaskFlag::ShowSpritesLeft as u8;
+
+    fn palette_ram() -> PaletteRam {
+        let mut palette_ram = PaletteRam::new();
+        for i in 0..0x20 {
+            palette_ram.write(0x3f00 + i, i as u8 + 0x10);
+        }
+        // Write the backdrop last, as $3F10 mirrors it.
+        palette_ram.write(0x3f00, 0x0f);
+        palette_ram
+    }
+
+    #[test]
+    fn test_palette_mirrors() {
+        let mut palette_ram = PaletteRam::new();
+        palette_ram.write(0x3f10, 0x22);
+        assert_eq!(palette_ram.read(0x3f00), 0x22);
+        palette_ram.write(0x3f1c, 0x23);
+        assert_eq!(palette_ram.read(0x3f0c), 0x23);
+        // $3F20-$3FFF mirrors $3F00-$3F1F.
+        assert_eq!(palette_ram.read(0x3fe0), 0x22);
+        // Only 6 bits are stored.
+        palette_ram.write(0x3f01, 0xff);
+        assert_eq!(palette_ram.read(0x3f01), 0x3f);
+    }
+
+    #[test]
+    fn test_left_column_clipping() {
+        let palette_ram = palette_ram();
+        let background = 0b0101;
+        let sprite = || {
+            Some(SpritePixel {
+                value: 0b0110,
+                is_behind_background: false,
+                is_sprite_zero: false,
+            })
+        };
+
+        let mask = PpuMask(SHOW_ALL);
+        assert_eq!(
+            pixel_color(mask, &palette_ram, 0, 0, background, None),
+            0x15
+        );
+        assert_eq!(
+            pixel_color(mask, &palette_ram, 0, 0, background, sprite()),
+            0x26
+        );
+
+        // Hide the background in the left column.
+        let mask = PpuMask(SHOW_ALL & !(PpuMaskFlag::ShowBackgroundLeft as u8));
+        assert_eq!(
+            pixel_color(mask, &palette_ram, 0, 7, background, None),
+            0x0f
+        );
+        assert_eq!(
+            pixel_color(mask, &palette_ram, 0, 8, background, None),
+            0x15
+        );
+
+        // Hide the sprites in the left column, the background shows through.
+        let mask = PpuMask(SHOW_ALL & !(PpuMaskFlag::ShowSpritesLeft as u8));
+        assert_eq!(
+            pixel_color(mask, &palette_ram, 0, 7, background, sprite()),
+            0x15
+        );
+        assert_eq!(
+            pixel_color(mask, &palette_ram, 0, 8, background, sprite()),
+            0x26
+        );
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let palette_ram = palette_ram();
+        let mask = PpuMask(SHOW_ALL);
+        let behind = || {
+            Some(SpritePixel {
+                value: 0b0110,
+                is_behind_background: true,
+                is_sprite_zero: false,
+            })
+        };
+        assert_eq!(
+            pixel_color(mask, &palette_ram, 0, 20, 0b0101, behind()),
+            0x15
+        );
+        assert_eq!(
+            pixel_color(mask, &palette_ram, 0, 20, 0b0100, behind()),
+            0x26
+        );
+    }
+
+    #[test]
+    fn test_mirroring() {
+        assert_eq!(Mirroring::Horizontal.vram_offset(0x2400), 0x0000);
+        assert_eq!(Mirroring::Horizontal.vram_offset(0x2801), 0x0401);
+        assert_eq!(Mirroring::Vertical.vram_offset(0x2400), 0x0400);
+        assert_eq!(Mirroring::Vertical.vram_offset(0x2801), 0x0001);
+        assert_eq!(Mirroring::SingleScreenUpper.vram_offset(0x2000), 0x0400);
+        assert_eq!(Mirroring::FourScreen.vram_offset(0x2c00), 0x0c00);
+        assert_eq!(Mirroring::Mapped([0, 1, 1, 0]).vram_offset(0x2c01), 0x0001);
+        // $3000-$3EFF mirrors the nametables.
+        assert_eq!(Mirroring::Vertical.vram_offset(0x3401), 0x0401);
+    }
+
+    #[test]
+    fn test_rendering_disabled() {
+        let palette_ram = palette_ram();
+        let mask = PpuMask(0);
+        // The backdrop is output when v doesn't point into the palette.
+        assert_eq!(
+            pixel_color(mask, &palette_ram, 0x2000, 20, 0b0101, None),
+            0x0f
+        );
+        // The palette entry pointed at by v is output.
+        assert_eq!(
+            pixel_color(mask, &palette_ram, 0x3f05, 20, 0b0101, None),
+            0x15
+        );
+        // Greyscale is still applied.
+        let mask = PpuMask(PpuMaskFlag::Greyscale as u8);
+        assert_eq!(rendering_disabled_color(mask, &palette_ram, 0x3f05), 0x10);
+    }
+}
@@ -0,0 +1,127 @@
+//! The .pal files that NES tools pass around. The same extension is used for a few
+//! different things, so the format is detected by the size of the file.
+//!
+//! https://www.nesdev.org/wiki/.pal
+
+/// The RGB colors for the 64 palette indexes, like the NTSC_PALETTE.
+pub type MasterPalette = [[u8; 3]; 0x40];
+
+/// The number of master palettes in a file with the emphasis sets, one for each
+/// combination of the PPUMASK emphasis bits.
+pub const EMPHASIS_SETS: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaletteFile {
+    /// 16 bytes, the 4 background palettes as indexes into the master palette. This is
+    /// what NES Screen Tool saves.
+    Background([[u8; 4]; 4]),
+    /// 32 bytes, a dump of the palette RAM at $3F00-$3F1F, with the background
+    /// palettes followed by the sprite palettes.
+    PaletteRam([u8; 0x20]),
+    /// 192 bytes, the RGB colors of the master palette.
+    Master(MasterPalette),
+    /// 1536 bytes, 8 master palettes. The set is picked by the emphasis bits, in the
+    /// order of PPUMASK bits 5-7, so the first one has no emphasis.
+    Emphasis(Vec<MasterPalette>),
+}
+
+fn palettes(data: &[u8]) -> [[u8; 4]; 4] {
+    let mut palettes = [[0; 4]; 4];
+    for (i, v) in data.iter().take(16).enumerate() {
+        palettes[i / 4][i % 4] = *v;
+    }
+    palettes
+}
+
+fn master_palette(data: &[u8]) -> MasterPalette {
+    let mut master = [[0; 3]; 0x40];
+    for (color, rgb) in master.iter_mut().zip(data.chunks_exact(3)) {
+        color.copy_from_slice(rgb);
+    }
+    master
+}
+
+impl PaletteFile {
+    pub fn parse(data: &[u8]) -> Result<PaletteFile, String> {
+        const MASTER_BYTES: usize = 0x40 * 3;
+        match data.len() {
+            16 => Ok(PaletteFile::Background(palettes(data))),
+            0x20 => {
+                let mut ram = [0; 0x20];
+                ram.copy_from_slice(data);
+                Ok(PaletteFile::PaletteRam(ram))
+            }
+            MASTER_BYTES => Ok(PaletteFile::Master(master_palette(data))),
+            len if len == MASTER_BYTES * EMPHASIS_SETS => Ok(PaletteFile::Emphasis(
+                data.chunks_exact(MASTER_BYTES)
+                    .map(master_palette)
+                    .collect(),
+            )),
+            len => Err(format!(
+                "Invalid palette file. Expected 16 or 32 bytes of palette indexes, or \
+                 {} or {} bytes of RGB colors, but a {} byte file was received.",
+                MASTER_BYTES,
+                MASTER_BYTES * EMPHASIS_SETS,
+                len
+            )),
+        }
+    }
+
+    /// The 4 background palettes, as indexes into the master palette.
+    pub fn background_palettes(&self) -> Option<[[u8; 4]; 4]> {
+        match self {
+            PaletteFile::Background(palettes) => Some(*palettes),
+            PaletteFile::PaletteRam(ram) => Some(palettes(&ram[..0x10])),
+            _ => None,
+        }
+    }
+
+    /// The 4 sprite palettes, only palette RAM dumps have them.
+    pub fn sprite_palettes(&self) -> Option<[[u8; 4]; 4]> {
+        match self {
+            PaletteFile::PaletteRam(ram) => Some(palettes(&ram[0x10..])),
+            _ => None,
+        }
+    }
+
+    /// The master palette without any emphasis.
+    pub fn master_palette(&self) -> Option<MasterPalette> {
+        self.emphasis_palette(0)
+    }
+
+    /// The master palette for the PPUMASK emphasis bits, shifted down to 0-7. Files
+    /// without the emphasis sets only have the palette for 0.
+    pub fn emphasis_palette(&self, emphasis: u8) -> Option<MasterPalette> {
+        match (self, emphasis) {
+            (PaletteFile::Master(master), 0) => Some(*master),
+            (PaletteFile::Emphasis(sets), _) => sets.get(emphasis as usize).copied(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_detect_by_size() {
+        let ram: Vec<u8> = (0..0x20).collect();
+        let file = PaletteFile::parse(&ram).unwrap();
+        assert_eq!(file.background_palettes().unwrap()[1], [4, 5, 6, 7]);
+        assert_eq!(file.sprite_palettes().unwrap()[0], [0x10, 0x11, 0x12, 0x13]);
+        assert_eq!(file.master_palette(), None);
+
+        let rgb: Vec<u8> = (0..192 * 8).map(|i| (i / 192) as u8).collect();
+        let file = PaletteFile::parse(&rgb).unwrap();
+        assert_eq!(file.master_palette().unwrap()[0x3f], [0, 0, 0]);
+        assert_eq!(file.emphasis_palette(7).unwrap()[0], [7, 7, 7]);
+        assert_eq!(file.background_palettes(), None);
+
+        let file = PaletteFile::parse(&rgb[..192]).unwrap();
+        assert!(file.master_palette().is_some());
+        assert_eq!(file.emphasis_palette(1), None);
+
+        assert!(PaletteFile::parse(&[0; 512]).is_err());
+    }
+}
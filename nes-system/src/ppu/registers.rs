@@ -0,0 +1,347 @@
+//! The CPU talks to the PPU through 8 registers at $2000-$2007, which are mirrored every
+//! 8 bytes up to $3FFF.
+//!
+//! https://www.nesdev.org/wiki/PPU_registers
+//!
+//! The scroll is kept as the separate x and y values of the renderers, rather than the
+//! shared "t" and "v" address registers of the hardware. Writes to PPUADDR don't
+//! affect the scroll, which only matters for games that set the scroll mid-frame
+//! through $2006.
+
+use super::render::{PatternTables, PpuCtrlFlag, PpuState};
+use super::PpuMask;
+use crate::save_state::{StateReader, StateWriter};
+use std::cell::Cell;
+
+pub const PPUCTRL: u16 = 0x2000;
+pub const PPUMASK: u16 = 0x2001;
+pub const PPUSTATUS: u16 = 0x2002;
+pub const OAMADDR: u16 = 0x2003;
+pub const OAMDATA: u16 = 0x2004;
+pub const PPUSCROLL: u16 = 0x2005;
+pub const PPUADDR: u16 = 0x2006;
+pub const PPUDATA: u16 = 0x2007;
+
+#[rustfmt::skip]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PpuStatusFlag {
+  SpriteOverflow = 0b00100000,
+  Sprite0Hit     = 0b01000000,
+  VBlank         = 0b10000000,
+}
+
+/// The bits of the I/O latch fade to 0 when they aren't refreshed for about 600ms.
+pub const LATCH_DECAY_FRAMES: u64 = 36;
+
+/// The PPU's own open bus. Every write to a register fills it, and reads fill the bits
+/// that the register drives. The bits that a register doesn't drive, like the low 5
+/// bits of PPUSTATUS or all of the write only registers, read back from here.
+///
+/// https://www.nesdev.org/wiki/Open_bus_behavior#PPU_open_bus
+pub struct IoLatch {
+    value: Cell<u8>,
+    /// The frame that each bit was last refreshed on.
+    refreshed: Cell<[u64; 8]>,
+}
+
+impl IoLatch {
+    pub fn new() -> IoLatch {
+        IoLatch {
+            value: Cell::new(0),
+            refreshed: Cell::new([0; 8]),
+        }
+    }
+
+    /// The latch as of the frame, without the bits that have decayed.
+    pub fn read(&self, frame: u64) -> u8 {
+        let refreshed = self.refreshed.get();
+        (0..8)
+            .filter(|&bit| frame.saturating_sub(refreshed[bit]) < LATCH_DECAY_FRAMES)
+            .fold(0, |value, bit| value | (self.value.get() & 1 << bit))
+    }
+
+    /// Drive the bits in the mask with the value.
+    pub fn refresh(&self, value: u8, mask: u8, frame: u64) {
+        let mut refreshed = self.refreshed.get();
+        for (bit, refreshed) in refreshed.iter_mut().enumerate() {
+            if mask & 1 << bit != 0 {
+                *refreshed = frame;
+            }
+        }
+        self.refreshed.set(refreshed);
+        self.value.set((self.value.get() & !mask) | (value & mask));
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.value.get());
+        for frame in self.refreshed.get() {
+            writer.u64(frame);
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.value.set(reader.u8()?);
+        let mut refreshed = [0; 8];
+        for frame in refreshed.iter_mut() {
+            *frame = reader.u64()?;
+        }
+        self.refreshed.set(refreshed);
+        Ok(())
+    }
+}
+
+/// The latches behind the registers. Reads have side effects, but the bus is read
+/// through a shared reference, so those latches are kept in cells.
+pub struct PpuRegisters {
+    status: Cell<u8>,
+    /// The write toggle shared by PPUSCROLL and PPUADDR. Reading PPUSTATUS resets it.
+    w: Cell<bool>,
+    /// The high byte from the first write to PPUADDR.
+    address_high: u8,
+    /// PPUDATA reads below the palette return the value from the previous read.
+    read_buffer: Cell<u8>,
+    pub oam_address: u8,
+    is_nmi_pending: bool,
+}
+
+impl PpuRegisters {
+    pub fn new() -> PpuRegisters {
+        PpuRegisters {
+            status: Cell::new(0),
+            w: Cell::new(false),
+            address_high: 0,
+            read_buffer: Cell::new(0),
+            oam_address: 0,
+            is_nmi_pending: false,
+        }
+    }
+
+    /// Look at PPUSTATUS without clearing the vblank flag, for debuggers.
+    pub fn status(&self) -> u8 {
+        self.status.get()
+    }
+
+    pub fn set_vblank(&mut self, is_vblank: bool, state: &PpuState) {
+        let vblank = PpuStatusFlag::VBlank as u8;
+        if is_vblank {
+            self.status.set(self.status.get() | vblank);
+            if state.is_ctrl_set(PpuCtrlFlag::GenerateNmi) {
+                self.is_nmi_pending = true;
+            }
+        } else {
+            self.status.set(self.status.get() & !vblank);
+        }
+    }
+
+    pub fn set_status_flag(&mut self, flag: PpuStatusFlag, value: bool) {
+        let status = self.status.get();
+        self.status.set(if value {
+            status | flag as u8
+        } else {
+            status & !(flag as u8)
+        });
+    }
+
+    /// The NMI is edge triggered, so it's handed to the CPU once.
+    pub fn take_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.is_nmi_pending)
+    }
+
+    /// The bits that a read of the register drives, the rest come from the I/O latch.
+    pub fn driven_bits(&self, state: &PpuState, address: u16) -> u8 {
+        match PPUCTRL | (address & 0b111) {
+            PPUSTATUS => 0b1110_0000,
+            OAMDATA => 0xff,
+            // Palette entries are only 6 bits.
+            PPUDATA if state.v.get() & 0x3fff >= 0x3f00 => 0b0011_1111,
+            PPUDATA => 0xff,
+            _ => 0,
+        }
+    }
+
+    pub fn read(&self, state: &PpuState, chr: &dyn PatternTables, address: u16) -> u8 {
+        match PPUCTRL | (address & 0b111) {
+            PPUSTATUS => {
+                let status = self.status.get();
+                self.status.set(status & !(PpuStatusFlag::VBlank as u8));
+                self.w.set(false);
+                status
+            }
+            OAMDATA => state.oam[self.oam_address as usize],
+            PPUDATA => {
+                let v = state.v.get() & 0x3fff;
+                let value = if v >= 0x3f00 {
+                    // Palette reads come straight back, but the buffer is still filled
+                    // with the nametable byte that sits "under" the palette.
+                    self.read_buffer.set(read_vram(state, chr, v - 0x1000));
+                    state.palette_ram.read(v)
+                } else {
+                    self.read_buffer.replace(read_vram(state, chr, v))
+                };
+                increment_v(state);
+                value
+            }
+            // The rest of the registers are write only.
+            _ => 0,
+        }
+    }
+
+    pub fn write(
+        &mut self,
+        state: &mut PpuState,
+        chr: &mut dyn PatternTables,
+        address: u16,
+        value: u8,
+    ) {
+        match PPUCTRL | (address & 0b111) {
+            PPUCTRL => {
+                let was_nmi_enabled = state.is_ctrl_set(PpuCtrlFlag::GenerateNmi);
+                state.ctrl = value;
+                // Turning on the NMI during vblank fires it right away.
+                let is_vblank = self.status.get() & PpuStatusFlag::VBlank as u8 != 0;
+                if !was_nmi_enabled
+                    && state.is_ctrl_set(PpuCtrlFlag::GenerateNmi)
+                    && is_vblank
+                {
+                    self.is_nmi_pending = true;
+                }
+            }
+            PPUMASK => state.mask = PpuMask(value),
+            OAMADDR => self.oam_address = value,
+            OAMDATA => {
+                state.oam[self.oam_address as usize] = value;
+                self.oam_address = self.oam_address.wrapping_add(1);
+            }
+            PPUSCROLL => {
+                if self.w.get() {
+                    state.scroll_y = value;
+                } else {
+                    state.scroll_x = value;
+                }
+                self.w.set(!self.w.get());
+            }
+            PPUADDR => {
+                if self.w.get() {
+                    state.v.set(u16::from_le_bytes([value, self.address_high]));
+                } else {
+                    self.address_high = value & 0x3f;
+                }
+                self.w.set(!self.w.get());
+            }
+            PPUDATA => {
+                let v = state.v.get() & 0x3fff;
+                match v {
+                    0x0000..=0x1fff => chr.write_chr(v, value),
+                    0x2000..=0x3eff => state.write_nametable(chr, v, value),
+                    _ => state.palette_ram.write(v, value),
+                }
+                increment_v(state);
+            }
+            // PPUSTATUS is read only.
+            _ => {}
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.status.get());
+        writer.bool(self.w.get());
+        writer.u8(self.address_high);
+        writer.u8(self.read_buffer.get());
+        writer.u8(self.oam_address);
+        writer.bool(self.is_nmi_pending);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.status.set(reader.u8()?);
+        self.w.set(reader.bool()?);
+        self.address_high = reader.u8()?;
+        self.read_buffer.set(reader.u8()?);
+        self.oam_address = reader.u8()?;
+        self.is_nmi_pending = reader.bool()?;
+        Ok(())
+    }
+}
+
+fn read_vram(state: &PpuState, chr: &dyn PatternTables, address: u16) -> u8 {
+    match address {
+        0x0000..=0x1fff => chr.read_chr(address),
+        0x2000..=0x3eff => state.read_nametable(chr, address),
+        _ => state.palette_ram.read(address),
+    }
+}
+
+/// PPUDATA moves across a nametable row, or down a column.
+fn increment_v(state: &PpuState) {
+    let increment = if state.is_ctrl_set(PpuCtrlFlag::IncrementDown) {
+        32
+    } else {
+        1
+    };
+    state.v.set(state.v.get().wrapping_add(increment) & 0x3fff);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ppu::Mirroring;
+
+    #[test]
+    fn test_ppudata() {
+        let mut registers = PpuRegisters::new();
+        let mut state = PpuState::new(Mirroring::Vertical);
+        let mut chr = vec![0; 0x2000];
+
+        // Write 2 bytes down a column of the first nametable.
+        registers.write(&mut state, &mut chr, PPUCTRL, 0b100);
+        registers.write(&mut state, &mut chr, PPUADDR, 0x20);
+        registers.write(&mut state, &mut chr, PPUADDR, 0x05);
+        registers.write(&mut state, &mut chr, PPUDATA, 0x11);
+        registers.write(&mut state, &mut chr, PPUDATA, 0x22);
+        assert_eq!(state.read_nametable(&chr, 0x2005), 0x11);
+        assert_eq!(state.read_nametable(&chr, 0x2025), 0x22);
+
+        // Reads are delayed by the buffer, and the mirrors of the registers work.
+        registers.write(&mut state, &mut chr, PPUCTRL, 0);
+        registers.write(&mut state, &mut chr, 0x3ffe, 0x20);
+        registers.write(&mut state, &mut chr, 0x3ffe, 0x25);
+        assert_eq!(registers.read(&state, &chr, PPUDATA), 0x00);
+        assert_eq!(registers.read(&state, &chr, PPUDATA), 0x22);
+
+        // CHR RAM can be written.
+        registers.write(&mut state, &mut chr, PPUADDR, 0x00);
+        registers.write(&mut state, &mut chr, PPUADDR, 0x10);
+        registers.write(&mut state, &mut chr, PPUDATA, 0xff);
+        assert_eq!(chr[0x10], 0xff);
+    }
+
+    #[test]
+    fn test_io_latch() {
+        let latch = IoLatch::new();
+        latch.refresh(0xff, 0xff, 10);
+        latch.refresh(0x00, 0b1110_0000, 20);
+        assert_eq!(latch.read(20), 0x1f);
+        // The bits fade separately, from when they were last refreshed.
+        assert_eq!(latch.read(10 + LATCH_DECAY_FRAMES - 1), 0x1f);
+        assert_eq!(latch.read(10 + LATCH_DECAY_FRAMES), 0x00);
+        latch.refresh(0xa0, 0b1110_0000, 30);
+        assert_eq!(latch.read(20 + LATCH_DECAY_FRAMES), 0xa0);
+    }
+
+    #[test]
+    fn test_vblank_and_nmi() {
+        let mut registers = PpuRegisters::new();
+        let mut state = PpuState::new(Mirroring::Vertical);
+        let mut chr = vec![];
+
+        registers.set_vblank(true, &state);
+        assert!(!registers.take_nmi());
+        // Enabling the NMI during vblank triggers it.
+        registers.write(&mut state, &mut chr, PPUCTRL, 0x80);
+        assert!(registers.take_nmi());
+        assert!(!registers.take_nmi());
+
+        // Reading the status clears the vblank flag.
+        assert_eq!(registers.read(&state, &chr, PPUSTATUS), 0x80);
+        assert_eq!(registers.read(&state, &chr, PPUSTATUS), 0x00);
+    }
+}
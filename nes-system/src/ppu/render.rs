@@ -0,0 +1,1323 @@
+//! The PPU can render a frame with two different strategies. The dot renderer draws
+//! one pixel per PPU dot from the current state, so register writes that land in the
+//! middle of a scanline take effect on the very next pixel. The scanline renderer
+//! draws a whole scanline at once, which is much cheaper, and is meant for low power
+//! devices. Both share the same `PpuState` and the same tile and sprite fetching
+//! code, so they produce the same pixels unless the state changes mid-scanline.
+
+use super::{
+    is_sprite_zero_hit, pixel_color, rendering_disabled_color, Mirroring, PaletteRam,
+    PpuMask, SpritePixel, DOTS_PER_FRAME, DOTS_PER_SCANLINE, NTSC_PALETTE,
+};
+use crate::ppu::registers::{IoLatch, PpuRegisters, PpuStatusFlag, PPUCTRL, PPUDATA};
+use crate::save_state::{StateReader, StateWriter};
+use std::cell::Cell;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+/// The vblank flag is set on the first scanline after the visible ones, and cleared
+/// on the pre-render scanline.
+pub const VBLANK_SCANLINE: u64 = 241;
+pub const PRE_RENDER_SCANLINE: u64 = 261;
+
+/// Only 8 sprites can be drawn on a single scanline.
+/// https://www.nesdev.org/wiki/PPU_sprite_evaluation
+pub const SPRITES_PER_SCANLINE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderStrategy {
+    /// Draw a pixel for every visible dot. This reproduces mid-scanline effects.
+    Dot,
+    /// Draw a whole scanline when it starts. This is several times faster, but it
+    /// can't reproduce:
+    ///  - Mid-scanline writes to PPUMASK, PPUCTRL, the scroll, or the palette, which
+    ///    are only seen on the next scanline.
+    ///  - Changes to OAM during the scanline.
+    ///  - Anything that relies on the exact dot a pixel is drawn on, such as the
+    ///    timing of mapper IRQs that watch the PPU address bus.
+    Scanline,
+}
+
+impl RenderStrategy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RenderStrategy::Dot => "dot",
+            RenderStrategy::Scanline => "scanline",
+        }
+    }
+}
+
+/// The user facing video options, stored in the config file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoSettings {
+    pub render_strategy: RenderStrategy,
+    /// The hardware only draws 8 sprites per scanline, and games flicker sprites to
+    /// work around it. Turning the limit off removes the flicker, but it's less
+    /// accurate: sprites that games hide on purpose, e.g. behind a doorway by filling
+    /// the scanline with blank sprites, will show up. The sprite overflow flag is
+    /// still set either way, so game logic sees the same thing.
+    pub sprite_limit: bool,
+}
+
+impl Default for VideoSettings {
+    fn default() -> Self {
+        VideoSettings {
+            render_strategy: RenderStrategy::Dot,
+            sprite_limit: true,
+        }
+    }
+}
+
+impl VideoSettings {
+    /// Serialize the settings into "key = value" lines for the config file.
+    pub fn to_config_string(&self) -> String {
+        format!(
+            "render_strategy = {}\nsprite_limit = {}\n",
+            self.render_strategy.name(),
+            self.sprite_limit
+        )
+    }
+
+    /// Parse the settings from the config file. Keys that are missing keep their
+    /// default values.
+    pub fn from_config_str(text: &str) -> Result<VideoSettings, String> {
+        let mut settings = VideoSettings::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => {
+                    return Err(format!(
+                        "Expected \"key = value\" but found \"{}\"",
+                        line
+                    ))
+                }
+            };
+            match key {
+                "render_strategy" => {
+                    settings.render_strategy = match value {
+                        "dot" => RenderStrategy::Dot,
+                        "scanline" => RenderStrategy::Scanline,
+                        _ => {
+                            return Err(format!(
+                                "Expected dot or scanline for \"render_strategy\", \
+                                 found \"{}\"",
+                                value
+                            ))
+                        }
+                    }
+                }
+                "sprite_limit" => {
+                    settings.sprite_limit = value.parse().map_err(|_| {
+                        format!(
+                            "Expected true or false for \"sprite_limit\", found \"{}\"",
+                            value
+                        )
+                    })?
+                }
+                _ => return Err(format!("Unknown video setting \"{}\"", key)),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+/// The pattern tables at $0000-$1FFF live on the cartridge, as CHR ROM or CHR RAM, so
+/// the PPU reads them through the mapper.
+pub trait PatternTables {
+    fn read_chr(&self, address: u16) -> u8;
+
+    /// Writes only stick for cartridges with CHR RAM.
+    fn write_chr(&mut self, _address: u16, _value: u8) {}
+
+    /// Cartridges like the MMC5 can put their own memory behind a nametable. None
+    /// falls back to the console's VRAM.
+    fn read_nametable(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    /// Returns true when the cartridge took the write.
+    fn write_nametable(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+}
+
+/// The size of the 4 logical nametables laid out in a 2x2 grid, which is the area that
+/// the scroll moves around in.
+pub const NAMETABLES_WIDTH: usize = SCREEN_WIDTH * 2;
+pub const NAMETABLES_HEIGHT: usize = SCREEN_HEIGHT * 2;
+
+/// The width and height of a pattern table, drawn as a 16x16 grid of 8x8 tiles.
+pub const PATTERN_TABLE_SIZE: usize = 128;
+
+/// Draw the pattern table at `base`, $0000 or $1000, for viewers. Each pixel is the
+/// 2 bit value from the tile, which a palette turns into a color.
+pub fn pattern_table_pixels(chr: &dyn PatternTables, base: u16) -> Vec<u8> {
+    let mut pixels = vec![0; PATTERN_TABLE_SIZE * PATTERN_TABLE_SIZE];
+    for tile in 0..256 {
+        let (tile_x, tile_y) = (tile % 16 * 8, tile / 16 * 8);
+        for row in 0..8 {
+            let address = base + (tile * 16 + row) as u16;
+            let low = chr.read_chr(address);
+            let high = chr.read_chr(address + 8);
+            for column in 0..8 {
+                let bit = 7 - column;
+                let value = (low >> bit & 1) | (high >> bit & 1) << 1;
+                pixels[(tile_y + row) * PATTERN_TABLE_SIZE + tile_x + column] = value;
+            }
+        }
+    }
+    pixels
+}
+
+/// A plain buffer behaves like 8KB of CHR RAM, which is handy for tools and tests.
+impl PatternTables for Vec<u8> {
+    fn read_chr(&self, address: u16) -> u8 {
+        self.get(address as usize).copied().unwrap_or(0)
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        if let Some(byte) = self.get_mut(address as usize) {
+            *byte = value;
+        }
+    }
+}
+
+#[rustfmt::skip]
+pub enum PpuCtrlFlag {
+  NametableX             = 0b00000001,
+  NametableY             = 0b00000010,
+  IncrementDown          = 0b00000100,
+  SpritePatternTable     = 0b00001000,
+  BackgroundPatternTable = 0b00010000,
+  TallSprites            = 0b00100000,
+  MasterSlave            = 0b01000000,
+  GenerateNmi            = 0b10000000,
+}
+
+/// The memory and registers that the renderers read from.
+pub struct PpuState {
+    /// PPUCTRL ($2000)
+    /// https://www.nesdev.org/wiki/PPU_registers#PPUCTRL
+    pub ctrl: u8,
+    pub mask: PpuMask,
+    pub scroll_x: u8,
+    pub scroll_y: u8,
+    /// The current VRAM address, set through PPUADDR. Reading PPUDATA increments it
+    /// through a shared reference, like the other register side effects.
+    pub v: Cell<u16>,
+    /// Room for 4 nametables, although only the first 2 are used unless the cartridge
+    /// uses four screen mirroring.
+    pub vram: [u8; 0x1000],
+    pub palette_ram: PaletteRam,
+    /// The sprite attributes, 4 bytes per sprite: y, tile, attributes, x.
+    /// https://www.nesdev.org/wiki/PPU_OAM
+    pub oam: [u8; 0x100],
+    pub mirroring: Mirroring,
+}
+
+/// Where the background pixel at a screen position comes from, after scrolling.
+struct BackgroundTile {
+    /// The nametable entry, $2000-$2FBF.
+    nametable_address: u16,
+    attribute_address: u16,
+    /// The shift of the tile's 2 bits in the attribute byte.
+    attribute_shift: u16,
+    /// The first byte of the tile in the pattern table.
+    pattern_address: u16,
+    /// The row within the tile, 0-7.
+    fine_y: u16,
+}
+
+/// Everything behind a background pixel on the screen, for debuggers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PixelInspection {
+    pub x: u8,
+    pub y: u8,
+    pub nametable_address: u16,
+    pub tile_index: u8,
+    pub attribute_address: u16,
+    pub attribute: u8,
+    /// The background palette, 0-3.
+    pub palette: u8,
+    /// The pixel value from the pattern table, 0-3, where 0 is the backdrop.
+    pub pixel_value: u8,
+    /// The NTSC color that is drawn.
+    pub color: u8,
+    /// The first byte of the tile in the pattern tables, $0000-$1FFF.
+    pub pattern_address: u16,
+    /// The 2 bit planes of the tile, 8 bytes each.
+    pub pattern_bytes: [u8; 16],
+    /// Where the tile is in the cartridge's CHR memory, after bank switching.
+    pub chr_offset: Option<usize>,
+}
+
+/// A row of 8 pixels of a tile, as it's fetched from the pattern table.
+struct TileRow {
+    palette: u8,
+    low: u8,
+    high: u8,
+}
+
+impl TileRow {
+    /// Get the 4 bit palette address of a pixel, where x is 0 on the left.
+    fn pixel(&self, x: u8) -> u8 {
+        let bit = 7 - (x & 0b111);
+        let value = ((self.high >> bit) & 0b1) << 1 | ((self.low >> bit) & 0b1);
+        self.palette << 2 | value
+    }
+}
+
+impl PpuState {
+    pub fn new(mirroring: Mirroring) -> PpuState {
+        PpuState {
+            ctrl: 0,
+            mask: PpuMask(0),
+            scroll_x: 0,
+            scroll_y: 0,
+            v: Cell::new(0),
+            vram: [0; 0x1000],
+            palette_ram: PaletteRam::new(),
+            oam: [0; 0x100],
+            mirroring,
+        }
+    }
+
+    pub fn is_ctrl_set(&self, flag: PpuCtrlFlag) -> bool {
+        let flag = flag as u8;
+        self.ctrl & flag == flag
+    }
+
+    pub fn read_nametable(&self, chr: &dyn PatternTables, address: u16) -> u8 {
+        chr.read_nametable(address)
+            .unwrap_or_else(|| self.vram[self.mirroring.vram_offset(address) as usize])
+    }
+
+    pub fn write_nametable(
+        &mut self,
+        chr: &mut dyn PatternTables,
+        address: u16,
+        value: u8,
+    ) {
+        if !chr.write_nametable(address, value) {
+            self.vram[self.mirroring.vram_offset(address) as usize] = value;
+        }
+    }
+
+    /// The top left corner of the screen in the 512x480 area of the 4 logical
+    /// nametables, from the scroll and the nametable select bits of PPUCTRL.
+    pub fn scroll_position(&self) -> (u16, u16) {
+        let nametable_x = self.is_ctrl_set(PpuCtrlFlag::NametableX) as u16 * 256;
+        let nametable_y = self.is_ctrl_set(PpuCtrlFlag::NametableY) as u16 * 240;
+        (
+            self.scroll_x as u16 + nametable_x,
+            self.scroll_y as u16 + nametable_y,
+        )
+    }
+
+    fn background_tile(&self, chr: &dyn PatternTables, x: u8, y: u8) -> BackgroundTile {
+        // Scroll across the 512x480 area of the 4 logical nametables.
+        let (scroll_x, scroll_y) = self.scroll_position();
+        let scrolled_x = (x as u16 + scroll_x) % NAMETABLES_WIDTH as u16;
+        let scrolled_y = (y as u16 + scroll_y) % NAMETABLES_HEIGHT as u16;
+
+        let logical = scrolled_x / 256 + (scrolled_y / 240) * 2;
+        let base = 0x2000 + logical * 0x400;
+        let column = (scrolled_x % 256) / 8;
+        let row = (scrolled_y % 240) / 8;
+
+        let nametable_address = base + row * 32 + column;
+        let tile = self.read_nametable(chr, nametable_address);
+
+        let table = if self.is_ctrl_set(PpuCtrlFlag::BackgroundPatternTable) {
+            0x1000
+        } else {
+            0
+        };
+        BackgroundTile {
+            nametable_address,
+            // Each attribute byte covers a 4x4 tile area, split into 2x2 quadrants.
+            attribute_address: base + 0x3c0 + (row / 4) * 8 + column / 4,
+            attribute_shift: ((row % 4) / 2) * 4 + ((column % 4) / 2) * 2,
+            pattern_address: table + tile as u16 * 16,
+            fine_y: scrolled_y % 8,
+        }
+    }
+
+    /// Fetch the background tile row under the screen position, after scrolling.
+    fn fetch_background(&self, chr: &dyn PatternTables, x: u8, y: u8) -> TileRow {
+        let tile = self.background_tile(chr, x, y);
+        let attribute = self.read_nametable(chr, tile.attribute_address);
+        let address = tile.pattern_address + tile.fine_y;
+        TileRow {
+            palette: (attribute >> tile.attribute_shift) & 0b11,
+            low: chr.read_chr(address),
+            high: chr.read_chr(address + 8),
+        }
+    }
+
+    /// Draw all 4 logical nametables as NTSC colors, in a 512x480 grid with $2000 at the
+    /// top left, reading through the mirroring like the renderer does. The background
+    /// pattern table and palettes are the ones that are set right now.
+    pub fn nametable_colors(&self, chr: &dyn PatternTables) -> Vec<u8> {
+        let mut colors = vec![0; NAMETABLES_WIDTH * NAMETABLES_HEIGHT];
+        let table = if self.is_ctrl_set(PpuCtrlFlag::BackgroundPatternTable) {
+            0x1000
+        } else {
+            0
+        };
+        for logical in 0..4u16 {
+            let base = 0x2000 + logical * 0x400;
+            let left = (logical % 2) as usize * SCREEN_WIDTH;
+            let top = (logical / 2) as usize * SCREEN_HEIGHT;
+            for row in 0..30u16 {
+                for column in 0..32u16 {
+                    let tile = self.read_nametable(chr, base + row * 32 + column);
+                    let attribute = self
+                        .read_nametable(chr, base + 0x3c0 + (row / 4) * 8 + column / 4);
+                    let shift = ((row % 4) / 2) * 4 + ((column % 4) / 2) * 2;
+                    for fine_y in 0..8 {
+                        let address = table + tile as u16 * 16 + fine_y;
+                        let tile_row = TileRow {
+                            palette: (attribute >> shift) & 0b11,
+                            low: chr.read_chr(address),
+                            high: chr.read_chr(address + 8),
+                        };
+                        let y = top + (row * 8 + fine_y) as usize;
+                        for fine_x in 0..8 {
+                            let pixel = tile_row.pixel(fine_x);
+                            let x = left + (column * 8) as usize + fine_x as usize;
+                            colors[y * NAMETABLES_WIDTH + x] = if pixel & 0b11 == 0 {
+                                self.palette_ram.backdrop()
+                            } else {
+                                self.palette_ram.read(0x3f00 + pixel as u16)
+                            };
+                        }
+                    }
+                }
+            }
+        }
+        colors
+    }
+
+    /// Reverse map a screen pixel through the scroll to the background tile behind
+    /// it. This uses the current scroll, so games that change the scroll mid-frame,
+    /// e.g. for a status bar, are only correct below the last change. Sprites aren't
+    /// considered.
+    pub fn inspect_pixel(
+        &self,
+        chr: &dyn PatternTables,
+        x: u8,
+        y: u8,
+    ) -> PixelInspection {
+        let tile = self.background_tile(chr, x, y);
+        let attribute = self.read_nametable(chr, tile.attribute_address);
+        let row = self.fetch_background(chr, x, y);
+        // The scroll shifts the tile under the pixel, so look up the bit in the tile
+        // rather than at the screen position.
+        let pixel = row.pixel(x.wrapping_add(self.scroll_x));
+        let mut pattern_bytes = [0; 16];
+        for (i, byte) in pattern_bytes.iter_mut().enumerate() {
+            *byte = chr.read_chr(tile.pattern_address + i as u16);
+        }
+        PixelInspection {
+            x,
+            y,
+            nametable_address: tile.nametable_address,
+            tile_index: self.read_nametable(chr, tile.nametable_address),
+            attribute_address: tile.attribute_address,
+            attribute,
+            palette: row.palette,
+            pixel_value: pixel & 0b11,
+            color: pixel_color(
+                self.mask,
+                &self.palette_ram,
+                self.v.get(),
+                x,
+                pixel,
+                None,
+            ),
+            pattern_address: tile.pattern_address,
+            pattern_bytes,
+            chr_offset: None,
+        }
+    }
+
+    /// The mirroring comes from the cartridge, so it isn't saved.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.ctrl);
+        writer.u8(self.mask.0);
+        writer.u8(self.scroll_x);
+        writer.u8(self.scroll_y);
+        writer.u16(self.v.get());
+        writer.bytes(&self.vram);
+        self.palette_ram.save_state(writer);
+        writer.bytes(&self.oam);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.ctrl = reader.u8()?;
+        self.mask = PpuMask(reader.u8()?);
+        self.scroll_x = reader.u8()?;
+        self.scroll_y = reader.u8()?;
+        self.v.set(reader.u16()?);
+        let vram = reader.bytes()?;
+        if vram.len() != self.vram.len() {
+            return Err(format!("Expected 4KB of VRAM, found {} bytes.", vram.len()));
+        }
+        self.vram.copy_from_slice(vram);
+        self.palette_ram.load_state(reader)?;
+        let oam = reader.bytes()?;
+        if oam.len() != self.oam.len() {
+            return Err(format!("Expected 256 bytes of OAM, found {}.", oam.len()));
+        }
+        self.oam.copy_from_slice(oam);
+        Ok(())
+    }
+
+    pub fn sprite_height(&self) -> u8 {
+        if self.is_ctrl_set(PpuCtrlFlag::TallSprites) {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// Find the sprites on a scanline, in OAM order, returning their indexes, and
+    /// whether there were more than 8 of them, for the sprite overflow flag. Without
+    /// the sprite limit every sprite on the scanline is returned.
+    ///
+    /// The hardware's evaluation has a bug that makes the overflow flag unreliable
+    /// past the 8th sprite. This reports the intended behavior instead.
+    pub fn evaluate_sprites(&self, y: u8, sprite_limit: bool) -> (Vec<usize>, bool) {
+        let height = self.sprite_height() as u16;
+        let mut sprites: Vec<usize> = (0..64)
+            .filter(|sprite| {
+                // The sprite is drawn one scanline below its y value.
+                let top = self.oam[sprite * 4] as u16 + 1;
+                (top..top + height).contains(&(y as u16))
+            })
+            .collect();
+        let is_overflow = sprites.len() > SPRITES_PER_SCANLINE;
+        if sprite_limit {
+            sprites.truncate(SPRITES_PER_SCANLINE);
+        }
+        (sprites, is_overflow)
+    }
+
+    fn fetch_sprite(&self, chr: &dyn PatternTables, sprite: usize, y: u8) -> TileRow {
+        let [top, tile, attributes, _] = [0, 1, 2, 3].map(|i| self.oam[sprite * 4 + i]);
+        let height = self.sprite_height();
+        let mut row = y.wrapping_sub(top.wrapping_add(1));
+        if attributes & 0b1000_0000 != 0 {
+            // Flip vertically.
+            row = height - 1 - row;
+        }
+
+        let address = if height == 16 {
+            // Tall sprites choose the pattern table with the low bit of the tile.
+            let table = (tile as u16 & 0b1) * 0x1000;
+            let tile = (tile & 0b1111_1110) as u16 + (row as u16 / 8);
+            table + tile * 16 + (row as u16 % 8)
+        } else {
+            let table = if self.is_ctrl_set(PpuCtrlFlag::SpritePatternTable) {
+                0x1000
+            } else {
+                0
+            };
+            table + tile as u16 * 16 + row as u16
+        };
+
+        let (mut low, mut high) = (chr.read_chr(address), chr.read_chr(address + 8));
+        if attributes & 0b0100_0000 != 0 {
+            // Flip horizontally.
+            low = low.reverse_bits();
+            high = high.reverse_bits();
+        }
+        TileRow {
+            palette: attributes & 0b11,
+            low,
+            high,
+        }
+    }
+
+    /// Draw a sprite as it shows on the screen, with the flips applied, for viewers.
+    /// The pixels are 4 bit palette addresses into the sprite palettes, in rows of 8,
+    /// and the sprite is 8 or 16 rows tall.
+    pub fn sprite_pixels(&self, chr: &dyn PatternTables, sprite: usize) -> Vec<u8> {
+        let top = self.oam[sprite * 4].wrapping_add(1);
+        (0..self.sprite_height())
+            .flat_map(|row| {
+                let tile_row = self.fetch_sprite(chr, sprite, top.wrapping_add(row));
+                (0..8).map(move |x| tile_row.pixel(x))
+            })
+            .collect()
+    }
+
+    /// The first opaque sprite pixel at x wins, even if it is behind the background.
+    fn sprite_pixel(
+        &self,
+        chr: &dyn PatternTables,
+        sprites: &[usize],
+        x: u8,
+        y: u8,
+    ) -> Option<SpritePixel> {
+        sprites.iter().find_map(|&sprite| {
+            let left = self.oam[sprite * 4 + 3];
+            if x < left || x as u16 >= left as u16 + 8 {
+                return None;
+            }
+            let value = self.fetch_sprite(chr, sprite, y).pixel(x - left);
+            if value & 0b11 == 0 {
+                return None;
+            }
+            Some(SpritePixel {
+                value,
+                is_behind_background: self.oam[sprite * 4 + 2] & 0b0010_0000 != 0,
+                is_sprite_zero: sprite == 0,
+            })
+        })
+    }
+
+    /// The color of the pixel, and whether sprite 0 hit on it.
+    fn color_at(
+        &self,
+        chr: &dyn PatternTables,
+        sprites: &[usize],
+        background: u8,
+        x: u8,
+        y: u8,
+    ) -> (u8, bool) {
+        let sprite = self.sprite_pixel(chr, sprites, x, y);
+        let is_hit = is_sprite_zero_hit(self.mask, x, background, &sprite);
+        let color = pixel_color(
+            self.mask,
+            &self.palette_ram,
+            self.v.get(),
+            x,
+            background,
+            sprite,
+        );
+        (color, is_hit)
+    }
+}
+
+/// A sprite 0 hit or sprite overflow flag being set or cleared, for the debuggers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatusFlagChange {
+    pub flag: PpuStatusFlag,
+    pub is_set: bool,
+    pub scanline: u64,
+    pub dot: u64,
+}
+
+/// A write to the palette RAM while the visible scanlines were being drawn. Palettes
+/// are meant to be changed in vblank, and a change mid-frame shows up as a line or a
+/// flash of color, so the debuggers point them out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaletteWrite {
+    /// $3F00-$3FFF, mirrors included.
+    pub address: u16,
+    pub value: u8,
+    pub scanline: u64,
+    pub dot: u64,
+    /// Games turn rendering off before writing, as the hardware is using the VRAM
+    /// address to fetch tiles while it's on.
+    pub is_rendering_enabled: bool,
+}
+
+/// Runs the PPU dot by dot, and renders into the frame with the current strategy.
+pub struct Ppu {
+    pub state: PpuState,
+    strategy: RenderStrategy,
+    next_strategy: Option<RenderStrategy>,
+    sprite_limit: bool,
+    /// The dot in the current frame, from 0 to DOTS_PER_FRAME.
+    dot: u64,
+    frame_count: u64,
+    /// The sprites on the current scanline.
+    sprites: Vec<usize>,
+    /// The colors of the frame, as indexes into the NTSC_PALETTE.
+    frame: Vec<u8>,
+    /// The last complete frame as RGBA.
+    framebuffer: Vec<u8>,
+    /// The flag changes in the frame being drawn, and in the last complete frame.
+    pending_status_changes: Vec<StatusFlagChange>,
+    status_changes: Vec<StatusFlagChange>,
+    /// The scroll position at the start of each visible scanline, for the frame being
+    /// drawn and the last complete frame.
+    pending_scroll_lines: Vec<(u16, u16)>,
+    scroll_lines: Vec<(u16, u16)>,
+    /// The number of sprites on each visible scanline, for the frame being drawn and
+    /// the last complete frame.
+    pending_sprite_counts: Vec<u8>,
+    sprite_counts: Vec<u8>,
+    /// The mid-frame palette writes, for the frame being drawn and the last complete
+    /// frame.
+    pending_palette_writes: Vec<PaletteWrite>,
+    palette_writes: Vec<PaletteWrite>,
+    palette_write_artifacts: bool,
+    pub registers: PpuRegisters,
+    pub latch: IoLatch,
+}
+
+impl Ppu {
+    pub fn new(state: PpuState, strategy: RenderStrategy) -> Ppu {
+        Ppu {
+            state,
+            strategy,
+            next_strategy: None,
+            sprite_limit: true,
+            dot: 0,
+            frame_count: 0,
+            sprites: Vec::new(),
+            frame: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+            pending_status_changes: Vec::new(),
+            status_changes: Vec::new(),
+            pending_scroll_lines: Vec::with_capacity(SCREEN_HEIGHT),
+            scroll_lines: Vec::new(),
+            pending_sprite_counts: Vec::with_capacity(SCREEN_HEIGHT),
+            sprite_counts: Vec::new(),
+            pending_palette_writes: Vec::new(),
+            palette_writes: Vec::new(),
+            palette_write_artifacts: false,
+            registers: PpuRegisters::new(),
+            latch: IoLatch::new(),
+        }
+    }
+
+    pub fn strategy(&self) -> RenderStrategy {
+        self.strategy
+    }
+
+    /// Changing the strategy mid-frame would mix the two renderers in one frame, so
+    /// the change is applied at the start of the next frame.
+    pub fn set_strategy(&mut self, strategy: RenderStrategy) {
+        self.next_strategy = Some(strategy);
+    }
+
+    pub fn sprite_limit(&self) -> bool {
+        self.sprite_limit
+    }
+
+    /// See VideoSettings::sprite_limit.
+    pub fn set_sprite_limit(&mut self, sprite_limit: bool) {
+        self.sprite_limit = sprite_limit;
+    }
+
+    /// The frame being drawn, as indexes into the NTSC_PALETTE.
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// The last complete frame, 256x240 pixels with 4 bytes of RGBA each. It's updated
+    /// when vblank starts, so it never contains a partially drawn frame. The color
+    /// emphasis bits of PPUMASK aren't applied.
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    /// Read a register, with the bits that it doesn't drive coming from the I/O latch.
+    pub fn read_register(&self, chr: &dyn PatternTables, address: u16) -> u8 {
+        let driven = self.registers.driven_bits(&self.state, address);
+        let value = self.registers.read(&self.state, chr, address) & driven
+            | self.latch.read(self.frame_count) & !driven;
+        self.latch.refresh(value, driven, self.frame_count);
+        value
+    }
+
+    pub fn write_register(
+        &mut self,
+        chr: &mut dyn PatternTables,
+        address: u16,
+        value: u8,
+    ) {
+        if PPUCTRL | (address & 0b111) == PPUDATA
+            && self.state.v.get() & 0x3fff >= 0x3f00
+            && self.scanline() < SCREEN_HEIGHT as u64
+        {
+            self.pending_palette_writes.push(PaletteWrite {
+                address: self.state.v.get() & 0x3fff,
+                value,
+                scanline: self.scanline(),
+                dot: self.scanline_dot(),
+                is_rendering_enabled: self.state.mask.is_rendering_enabled(),
+            });
+        }
+        self.latch.refresh(value, 0xff, self.frame_count);
+        self.registers.write(&mut self.state, chr, address, value);
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn scanline(&self) -> u64 {
+        self.dot / DOTS_PER_SCANLINE
+    }
+
+    /// The dot within the current scanline, from 0 to 340.
+    pub fn scanline_dot(&self) -> u64 {
+        self.dot % DOTS_PER_SCANLINE
+    }
+
+    /// The sprite 0 hit and sprite overflow flag changes in the last complete frame,
+    /// in order. A frame runs from the first visible scanline through the pre-render
+    /// scanline, where both flags are cleared.
+    pub fn status_changes(&self) -> &[StatusFlagChange] {
+        &self.status_changes
+    }
+
+    /// The scanline and dot where sprite 0 hit in the last complete frame.
+    pub fn sprite_zero_hit(&self) -> Option<(u64, u64)> {
+        self.status_changes
+            .iter()
+            .find(|change| change.flag == PpuStatusFlag::Sprite0Hit && change.is_set)
+            .map(|change| (change.scanline, change.dot))
+    }
+
+    /// Where the top left corner of each scanline was in the 4 logical nametables in
+    /// the last complete frame, see `PpuState::scroll_position`. Split screens and
+    /// status bars show up as jumps between scanlines.
+    pub fn scroll_lines(&self) -> &[(u16, u16)] {
+        &self.scroll_lines
+    }
+
+    /// How many sprites were on each visible scanline of the last complete frame,
+    /// counting past the 8 that the hardware draws. The scanlines with more are where
+    /// sprites drop out, or flicker when a game cycles them. It's 0 while rendering is
+    /// off, as nothing is evaluated.
+    pub fn sprite_counts(&self) -> &[u8] {
+        &self.sprite_counts
+    }
+
+    /// The palette writes that landed on the visible scanlines of the last complete
+    /// frame, in order.
+    pub fn palette_writes(&self) -> &[PaletteWrite] {
+        &self.palette_writes
+    }
+
+    pub fn palette_write_artifacts(&self) -> bool {
+        self.palette_write_artifacts
+    }
+
+    /// Draw each mid-frame palette write into the framebuffer, as a pixel of the
+    /// written color at the dot it landed on. While the CPU uploads a palette, the
+    /// backdrop shows the entry that the VRAM address points at rather than the
+    /// backdrop color, which is what makes the streak across the screen. It's off by
+    /// default, as it's a debugging aid rather than an accurate emulation.
+    pub fn set_palette_write_artifacts(&mut self, palette_write_artifacts: bool) {
+        self.palette_write_artifacts = palette_write_artifacts;
+    }
+
+    /// Only the changes are noted, so setting a flag that's already set is ignored.
+    fn set_status_flag(&mut self, flag: PpuStatusFlag, is_set: bool, dot: u64) {
+        if (self.registers.status() & flag as u8 != 0) == is_set {
+            return;
+        }
+        self.registers.set_status_flag(flag, is_set);
+        self.pending_status_changes.push(StatusFlagChange {
+            flag,
+            is_set,
+            scanline: self.scanline(),
+            dot,
+        });
+    }
+
+    pub fn tick(&mut self, chr: &dyn PatternTables, dots: u64) {
+        for _ in 0..dots {
+            self.tick_dot(chr);
+        }
+    }
+
+    fn tick_dot(&mut self, chr: &dyn PatternTables) {
+        let scanline = self.scanline();
+        let dot = self.scanline_dot();
+
+        // Dot 0 is idle, and dots 1-256 output the pixels of the visible scanlines.
+        if scanline < SCREEN_HEIGHT as u64 && (1..=SCREEN_WIDTH as u64).contains(&dot) {
+            let y = scanline as u8;
+            if dot == 1 {
+                self.pending_scroll_lines.push(self.state.scroll_position());
+                let mut sprite_count = 0;
+                if self.state.mask.is_rendering_enabled() {
+                    // Every sprite is found for the count, and then the limit applies.
+                    let (mut sprites, is_overflow) =
+                        self.state.evaluate_sprites(y, false);
+                    sprite_count = sprites.len() as u8;
+                    if self.sprite_limit {
+                        sprites.truncate(SPRITES_PER_SCANLINE);
+                    }
+                    self.sprites = sprites;
+                    if is_overflow {
+                        self.set_status_flag(PpuStatusFlag::SpriteOverflow, true, dot);
+                    }
+                }
+                self.pending_sprite_counts.push(sprite_count);
+            }
+            match self.strategy {
+                RenderStrategy::Dot => self.render_pixel(chr, (dot - 1) as u8, y),
+                RenderStrategy::Scanline => {
+                    if dot == 1 {
+                        self.render_scanline(chr, y);
+                    }
+                }
+            }
+        }
+
+        if dot == 1 {
+            if scanline == VBLANK_SCANLINE {
+                self.update_framebuffer();
+                self.registers.set_vblank(true, &self.state);
+            } else if scanline == PRE_RENDER_SCANLINE {
+                self.registers.set_vblank(false, &self.state);
+                self.set_status_flag(PpuStatusFlag::Sprite0Hit, false, dot);
+                self.set_status_flag(PpuStatusFlag::SpriteOverflow, false, dot);
+            }
+        }
+
+        self.dot += 1;
+        if self.dot == DOTS_PER_FRAME {
+            self.dot = 0;
+            self.frame_count += 1;
+            self.status_changes = std::mem::take(&mut self.pending_status_changes);
+            self.palette_writes = std::mem::take(&mut self.pending_palette_writes);
+            std::mem::swap(&mut self.scroll_lines, &mut self.pending_scroll_lines);
+            self.pending_scroll_lines.clear();
+            std::mem::swap(&mut self.sprite_counts, &mut self.pending_sprite_counts);
+            self.pending_sprite_counts.clear();
+            if let Some(strategy) = self.next_strategy.take() {
+                self.strategy = strategy;
+            }
+        }
+    }
+
+    fn render_pixel(&mut self, chr: &dyn PatternTables, x: u8, y: u8) {
+        let state = &self.state;
+        if !state.mask.is_rendering_enabled() {
+            // Skip the fetches, only the backdrop can be drawn.
+            self.frame[y as usize * SCREEN_WIDTH + x as usize] =
+                rendering_disabled_color(state.mask, &state.palette_ram, state.v.get());
+            return;
+        }
+        let fine_x = x.wrapping_add(state.scroll_x);
+        let background = state.fetch_background(chr, x, y).pixel(fine_x);
+        let (color, is_hit) = state.color_at(chr, &self.sprites, background, x, y);
+        self.frame[y as usize * SCREEN_WIDTH + x as usize] = color;
+        if is_hit {
+            self.set_status_flag(PpuStatusFlag::Sprite0Hit, true, x as u64 + 1);
+        }
+    }
+
+    #[cfg_attr(feature = "profile", tracing::instrument(skip_all))]
+    fn render_scanline(&mut self, chr: &dyn PatternTables, y: u8) {
+        let state = &self.state;
+        let row = &mut self.frame[y as usize * SCREEN_WIDTH..][..SCREEN_WIDTH];
+        // Only fetch a tile when crossing into it, rather than for every pixel.
+        let mut tile = state.fetch_background(chr, 0, y);
+        let fine_x = state.scroll_x;
+        let mut hit_x = None;
+        for x in 0..=255u8 {
+            if x != 0 && x.wrapping_add(fine_x) % 8 == 0 {
+                tile = state.fetch_background(chr, x, y);
+            }
+            let background = tile.pixel(x.wrapping_add(fine_x));
+            let (color, is_hit) = state.color_at(chr, &self.sprites, background, x, y);
+            row[x as usize] = color;
+            if is_hit && hit_x.is_none() {
+                hit_x = Some(x);
+            }
+        }
+        // The flag is noted at the dot where the hit would happen, even though the
+        // whole scanline is drawn at once.
+        if let Some(x) = hit_x {
+            self.set_status_flag(PpuStatusFlag::Sprite0Hit, true, x as u64 + 1);
+        }
+    }
+
+    /// The render strategy is a user setting, and the frame is drawn again, so only
+    /// the emulated state is saved.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.state.save_state(writer);
+        self.registers.save_state(writer);
+        writer.u64(self.dot);
+        writer.u64(self.frame_count);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), String> {
+        self.state.load_state(reader)?;
+        self.registers.load_state(reader)?;
+        let dot = reader.u64()?;
+        if dot >= DOTS_PER_FRAME {
+            return Err(format!("The PPU dot {} is past the end of the frame.", dot));
+        }
+        self.dot = dot;
+        self.frame_count = reader.u64()?;
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "profile", tracing::instrument(skip_all))]
+    fn update_framebuffer(&mut self) {
+        for (rgba, &color) in self.framebuffer.chunks_exact_mut(4).zip(&self.frame) {
+            let [r, g, b] = NTSC_PALETTE[(color & 0x3f) as usize];
+            rgba.copy_from_slice(&[r, g, b, 0xff]);
+        }
+        if !self.palette_write_artifacts {
+            return;
+        }
+        // The visible scanlines are done, so all of the frame's writes are in.
+        for write in &self.pending_palette_writes {
+            if (1..=SCREEN_WIDTH as u64).contains(&write.dot) {
+                let index =
+                    write.scanline as usize * SCREEN_WIDTH + write.dot as usize - 1;
+                let [r, g, b] = NTSC_PALETTE[(write.value & 0x3f) as usize];
+                self.framebuffer[index * 4..][..4].copy_from_slice(&[r, g, b, 0xff]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ppu::registers::PPUADDR;
+    use crate::ppu::PpuMaskFlag;
+
+    /// Tile 1 is a solid block of pixel value 1, and tile 2 has value 3 on its left
+    /// half only.
+    fn test_chr() -> Vec<u8> {
+        let mut chr = vec![0; 0x2000];
+        for row in 0..8 {
+            chr[16 + row] = 0xff;
+            chr[32 + row] = 0xf0;
+            chr[32 + 8 + row] = 0xf0;
+        }
+        chr
+    }
+
+    fn test_state() -> PpuState {
+        let mut state = PpuState::new(Mirroring::Vertical);
+        state.mask = PpuMask(
+            PpuMaskFlag::ShowBackground as u8
+                | PpuMaskFlag::ShowSprites as u8
+                | PpuMaskFlag::ShowBackgroundLeft as u8
+                | PpuMaskFlag::ShowSpritesLeft as u8,
+        );
+        for i in 0..0x20 {
+            state.palette_ram.write(0x3f00 + i, i as u8 + 0x10);
+        }
+        state.palette_ram.write(0x3f00, 0x0f);
+
+        // A checkerboard of tiles in the first nametable, with the second palette in
+        // the top left attribute quadrant.
+        for i in 0..0x3c0 {
+            state.vram[i] = ((i + i / 32) % 2) as u8;
+        }
+        state.vram[0x3c0] = 0b01;
+        // A sprite at (20, 10) using tile 2, with the second sprite palette.
+        state.oam[0..4].copy_from_slice(&[9, 2, 0b01, 20]);
+        state
+    }
+
+    #[test]
+    fn test_pattern_table_pixels() {
+        let pixels = pattern_table_pixels(&test_chr(), 0);
+        // Tile 1 is to the right of tile 0, and tile 2 is to the right of that.
+        assert_eq!(pixels[7], 0);
+        assert_eq!(pixels[8], 1);
+        assert_eq!(pixels[PATTERN_TABLE_SIZE * 7 + 19], 3);
+        assert_eq!(pixels[PATTERN_TABLE_SIZE * 7 + 20], 0);
+        assert!(pattern_table_pixels(&test_chr(), 0x1000)
+            .iter()
+            .all(|pixel| *pixel == 0));
+    }
+
+    #[test]
+    fn test_nametable_colors() {
+        let mut state = test_state();
+        let colors = state.nametable_colors(&test_chr());
+        let color = |x: usize, y: usize| colors[y * NAMETABLES_WIDTH + x];
+        // Tile 0 shows the backdrop, and tile 1 uses the second palette.
+        assert_eq!(color(0, 0), 0x0f);
+        assert_eq!(color(8, 0), 0x15);
+        // With vertical mirroring $2800 is the same as $2000, and $2400 is empty.
+        assert_eq!(color(8, 240), 0x15);
+        assert_eq!(color(264, 0), 0x0f);
+
+        assert_eq!(state.scroll_position(), (0, 0));
+        state.scroll_x = 12;
+        state.scroll_y = 34;
+        state.ctrl = PpuCtrlFlag::NametableX as u8 | PpuCtrlFlag::NametableY as u8;
+        assert_eq!(state.scroll_position(), (268, 274));
+    }
+
+    fn render_frame(strategy: RenderStrategy) -> Ppu {
+        let mut ppu = Ppu::new(test_state(), strategy);
+        ppu.tick(&test_chr(), DOTS_PER_FRAME);
+        ppu
+    }
+
+    fn color(ppu: &Ppu, x: usize, y: usize) -> u8 {
+        ppu.frame()[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn test_background_and_sprites() {
+        let ppu = render_frame(RenderStrategy::Dot);
+        // Tile 0 is transparent, so the backdrop shows.
+        assert_eq!(color(&ppu, 0, 0), 0x0f);
+        // Tile 1 uses palette 1 in the top left quadrant, and palette 0 outside it.
+        assert_eq!(color(&ppu, 8, 0), 0x15);
+        assert_eq!(color(&ppu, 40, 0), 0x11);
+        // The left half of the sprite is opaque.
+        assert_eq!(color(&ppu, 20, 10), 0x27);
+        assert_eq!(color(&ppu, 24, 10), color(&ppu, 24, 11));
+        assert_eq!(color(&ppu, 20, 9), color(&ppu, 20, 8));
+    }
+
+    #[test]
+    fn test_framebuffer() {
+        let mut ppu = Ppu::new(test_state(), RenderStrategy::Dot);
+        // The framebuffer is only filled in once vblank starts.
+        ppu.tick(&test_chr(), VBLANK_SCANLINE * DOTS_PER_SCANLINE);
+        assert_eq!(&ppu.framebuffer()[0..4], [0, 0, 0, 0]);
+        ppu.tick(&test_chr(), 2);
+        // The backdrop $0F is black, and $15 is at (8, 0).
+        assert_eq!(&ppu.framebuffer()[0..4], [0, 0, 0, 0xff]);
+        assert_eq!(&ppu.framebuffer()[8 * 4..9 * 4], [160, 20, 100, 0xff]);
+    }
+
+    #[test]
+    fn test_strategies_match() {
+        for scroll_x in [0, 3, 200] {
+            let mut a = Ppu::new(test_state(), RenderStrategy::Dot);
+            let mut b = Ppu::new(test_state(), RenderStrategy::Scanline);
+            for ppu in [&mut a, &mut b] {
+                // Tile 2 is only half filled, so the fine scroll shows.
+                ppu.state.vram[3] = 2;
+                ppu.state.scroll_x = scroll_x;
+                ppu.state.scroll_y = 13;
+                ppu.tick(&test_chr(), DOTS_PER_FRAME);
+            }
+            assert!(a.frame() == b.frame(), "scroll_x {}", scroll_x);
+        }
+    }
+
+    #[test]
+    fn test_mid_scanline_write() {
+        // Turn off the background halfway through the first scanline.
+        let run = |strategy| {
+            let mut ppu = Ppu::new(test_state(), strategy);
+            ppu.tick(&test_chr(), 129);
+            ppu.state.mask = PpuMask(0);
+            ppu.tick(&test_chr(), DOTS_PER_FRAME - 129);
+            ppu
+        };
+        let dot = run(RenderStrategy::Dot);
+        assert_eq!(color(&dot, 8, 0), 0x15);
+        assert_eq!(color(&dot, 136, 0), 0x0f);
+        // The scanline renderer doesn't see the write until the next scanline.
+        let scanline = run(RenderStrategy::Scanline);
+        assert_eq!(color(&scanline, 136, 0), 0x11);
+        assert_eq!(color(&scanline, 8, 1), 0x0f);
+    }
+
+    #[test]
+    fn test_strategy_changes_at_frame_boundary() {
+        let mut ppu = Ppu::new(test_state(), RenderStrategy::Dot);
+        ppu.tick(&test_chr(), 1000);
+        ppu.set_strategy(RenderStrategy::Scanline);
+        assert_eq!(ppu.strategy(), RenderStrategy::Dot);
+        ppu.tick(&test_chr(), DOTS_PER_FRAME - 1000);
+        assert_eq!(ppu.strategy(), RenderStrategy::Scanline);
+        assert_eq!(ppu.frame_count(), 1);
+    }
+
+    #[test]
+    fn test_inspect_pixel() {
+        let mut state = test_state();
+        // Scrolling by 3 puts the second tile under x = 5.
+        state.scroll_x = 3;
+        let inspection = state.inspect_pixel(&test_chr(), 5, 2);
+        assert_eq!(inspection.nametable_address, 0x2001);
+        assert_eq!(inspection.tile_index, 1);
+        assert_eq!(inspection.attribute_address, 0x23c0);
+        assert_eq!(inspection.palette, 1);
+        assert_eq!(inspection.pixel_value, 1);
+        assert_eq!(inspection.color, 0x15);
+        assert_eq!(inspection.pattern_address, 0x0010);
+        assert_eq!(inspection.pattern_bytes[..8], [0xff; 8]);
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        let run = |sprite_limit| {
+            let mut state = test_state();
+            // Put 9 sprites in a row on the same scanline.
+            for sprite in 0..9 {
+                state.oam[sprite * 4..][..4].copy_from_slice(&[
+                    9,
+                    2,
+                    0,
+                    sprite as u8 * 8,
+                ]);
+            }
+            let mut ppu = Ppu::new(state, RenderStrategy::Dot);
+            ppu.set_sprite_limit(sprite_limit);
+            ppu.tick(&test_chr(), 11 * DOTS_PER_SCANLINE);
+            ppu
+        };
+
+        let limited = run(true);
+        assert_eq!(color(&limited, 56, 10), 0x23);
+        assert_ne!(color(&limited, 64, 10), 0x23);
+        assert_ne!(
+            limited.registers.status() & PpuStatusFlag::SpriteOverflow as u8,
+            0
+        );
+
+        // The 9th sprite is drawn, and the overflow flag is still set.
+        let unlimited = run(false);
+        assert_eq!(color(&unlimited, 64, 10), 0x23);
+        assert_ne!(
+            unlimited.registers.status() & PpuStatusFlag::SpriteOverflow as u8,
+            0
+        );
+
+        // Every sprite is counted either way. The other 55 are at y = 0.
+        for ppu in [limited, unlimited].iter_mut() {
+            assert!(ppu.sprite_counts().is_empty());
+            ppu.tick(&test_chr(), DOTS_PER_FRAME - 11 * DOTS_PER_SCANLINE);
+            let counts = ppu.sprite_counts();
+            assert_eq!(counts.len(), SCREEN_HEIGHT);
+            assert_eq!(counts[..2], [0, 55]);
+            assert_eq!(counts[10..=18], [9, 9, 9, 9, 9, 9, 9, 9, 0]);
+        }
+    }
+
+    #[test]
+    fn test_sprite_pixels() {
+        let mut state = test_state();
+        // Tile 2's left half is pixel value 3, and the sprite uses the second palette.
+        let pixels = state.sprite_pixels(&test_chr(), 0);
+        assert_eq!(pixels.len(), 64);
+        assert_eq!(pixels[0..8], [7, 7, 7, 7, 4, 4, 4, 4]);
+
+        // Flipped horizontally, and as a tall sprite.
+        state.oam[2] |= 0b0100_0000;
+        state.ctrl = PpuCtrlFlag::TallSprites as u8;
+        let pixels = state.sprite_pixels(&test_chr(), 0);
+        assert_eq!(pixels.len(), 128);
+        assert_eq!(pixels[0..8], [4, 4, 4, 4, 7, 7, 7, 7]);
+    }
+
+    #[test]
+    fn test_scroll_lines() {
+        let mut ppu = Ppu::new(test_state(), RenderStrategy::Scanline);
+        ppu.state.scroll_y = 8;
+        // A status bar split at scanline 32, into the nametable on the right.
+        ppu.tick(&test_chr(), 32 * DOTS_PER_SCANLINE);
+        ppu.state.scroll_x = 12;
+        ppu.state.ctrl = PpuCtrlFlag::NametableX as u8;
+        assert!(ppu.scroll_lines().is_empty());
+        ppu.tick(&test_chr(), DOTS_PER_FRAME - 32 * DOTS_PER_SCANLINE);
+
+        let lines = ppu.scroll_lines();
+        assert_eq!(lines.len(), SCREEN_HEIGHT);
+        assert_eq!(lines[31], (0, 8));
+        assert_eq!(lines[32], (256 + 12, 8));
+        assert_eq!(lines[239], (256 + 12, 8));
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        for strategy in [RenderStrategy::Dot, RenderStrategy::Scanline] {
+            // Sprite 0 at (20, 10) overlaps the solid tile 1 in the background.
+            let mut ppu = Ppu::new(test_state(), strategy);
+            ppu.tick(&test_chr(), 11 * DOTS_PER_SCANLINE);
+            assert_ne!(ppu.registers.status() & PpuStatusFlag::Sprite0Hit as u8, 0);
+            assert_eq!(ppu.sprite_zero_hit(), None);
+
+            ppu.tick(&test_chr(), DOTS_PER_FRAME - 11 * DOTS_PER_SCANLINE);
+            assert_eq!(ppu.registers.status() & PpuStatusFlag::Sprite0Hit as u8, 0);
+            assert_eq!(ppu.sprite_zero_hit(), Some((10, 21)), "{:?}", strategy);
+            let change = |is_set, scanline, dot| StatusFlagChange {
+                flag: PpuStatusFlag::Sprite0Hit,
+                is_set,
+                scanline,
+                dot,
+            };
+            // The rest of the sprites are at y = 0, so the overflow flag changes too.
+            let changes: Vec<_> = ppu
+                .status_changes()
+                .iter()
+                .filter(|change| change.flag == PpuStatusFlag::Sprite0Hit)
+                .copied()
+                .collect();
+            assert_eq!(
+                changes,
+                [change(true, 10, 21), change(false, PRE_RENDER_SCANLINE, 1)]
+            );
+        }
+
+        // Hiding the background in the left column doesn't matter at x = 20, but
+        // turning the background off stops the hit.
+        let mut state = test_state();
+        state.mask = PpuMask(PpuMaskFlag::ShowSprites as u8);
+        let mut ppu = Ppu::new(state, RenderStrategy::Dot);
+        ppu.tick(&test_chr(), DOTS_PER_FRAME);
+        assert_eq!(ppu.sprite_zero_hit(), None);
+    }
+
+    #[test]
+    fn test_palette_writes() {
+        let mut chr = test_chr();
+        let mut ppu = Ppu::new(test_state(), RenderStrategy::Dot);
+        ppu.set_palette_write_artifacts(true);
+        // Point the VRAM address at $3F01 in vblank, which isn't noted.
+        ppu.tick(&chr, VBLANK_SCANLINE * DOTS_PER_SCANLINE);
+        ppu.write_register(&mut chr, PPUADDR, 0x3f);
+        ppu.write_register(&mut chr, PPUADDR, 0x01);
+        ppu.write_register(&mut chr, PPUDATA, 0x20);
+        ppu.tick(&chr, DOTS_PER_FRAME - VBLANK_SCANLINE * DOTS_PER_SCANLINE);
+        assert_eq!(ppu.palette_writes(), []);
+
+        // Then write the next entry on the fifth scanline, with rendering on.
+        ppu.tick(&chr, 4 * DOTS_PER_SCANLINE + 10);
+        ppu.write_register(&mut chr, PPUDATA, 0x30);
+        assert_eq!(ppu.palette_writes(), []);
+        ppu.tick(&chr, DOTS_PER_FRAME - 4 * DOTS_PER_SCANLINE - 10);
+        assert_eq!(
+            ppu.palette_writes(),
+            [PaletteWrite {
+                address: 0x3f02,
+                value: 0x30,
+                scanline: 4,
+                dot: 10,
+                is_rendering_enabled: true,
+            }]
+        );
+        // The pixel at x = 9 shows the written white.
+        let [r, g, b] = NTSC_PALETTE[0x30];
+        let index = (4 * SCREEN_WIDTH + 9) * 4;
+        assert_eq!(ppu.framebuffer()[index..][..4], [r, g, b, 0xff]);
+        assert_ne!(ppu.framebuffer()[index + 4..][..4], [r, g, b, 0xff]);
+    }
+
+    #[test]
+    fn test_video_settings() {
+        let settings = VideoSettings {
+            render_strategy: RenderStrategy::Scanline,
+            sprite_limit: false,
+        };
+        let text = settings.to_config_string();
+        assert_eq!(text, "render_strategy = scanline\nsprite_limit = false\n");
+        assert_eq!(VideoSettings::from_config_str(&text), Ok(settings));
+        assert!(VideoSettings::from_config_str("render_strategy = fast").is_err());
+    }
+}
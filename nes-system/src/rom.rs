@@ -0,0 +1,353 @@
+//! Loading ROM files dumped from real cartridges.
+//!
+//! The iNES format is a 16 byte header, an optional 512 byte trainer, then the PRG ROM
+//! and CHR ROM banks back to back.
+//!
+//! https://www.nesdev.org/wiki/INES
+
+pub mod database;
+
+use crate::mappers::{create_mapper, FallbackMapper, Mapper, SUPPORTED_MAPPERS};
+use crate::ppu::Mirroring;
+use database::{crc32, RomDatabase, RomInfo};
+
+pub const INES_MAGIC: &[u8; 4] = b"NES\x1a";
+pub const INES_HEADER_SIZE: usize = 16;
+pub const TRAINER_SIZE: usize = 512;
+pub const PRG_BANK_SIZE: usize = 0x4000;
+pub const CHR_BANK_SIZE: usize = 0x2000;
+/// PRG RAM is switched in 8KB banks at $6000-$7FFF.
+pub const PRG_RAM_BANK_SIZE: usize = 0x2000;
+/// NROM-256, 32KB of PRG ROM mapped to $8000-$FFFF.
+pub const NROM_PRG_SIZE: usize = PRG_BANK_SIZE * 2;
+
+#[rustfmt::skip]
+enum Flags6 {
+  VerticalMirroring = 0b00000001,
+  Battery           = 0b00000010,
+  Trainer           = 0b00000100,
+  FourScreen        = 0b00001000,
+}
+
+/// A parsed iNES file.
+pub struct InesRom {
+    /// The iNES mapper number, which says what hardware is on the cartridge.
+    pub mapper: u8,
+    pub mirroring: Mirroring,
+    /// The cartridge has battery backed PRG RAM, for saving games.
+    pub has_battery: bool,
+    /// Some ROMs have a trainer, that is loaded into $7000-$71FF.
+    pub trainer: Option<Vec<u8>>,
+    /// The PRG RAM at $6000, in bytes. This is at least one 8KB bank, as mappers have
+    /// traditionally provided it even when the header doesn't say so.
+    pub prg_ram_size: usize,
+    pub prg_rom: Vec<u8>,
+    /// Empty when the cartridge uses CHR RAM instead.
+    pub chr_rom: Vec<u8>,
+}
+
+impl InesRom {
+    pub fn from_ines_bytes(bytes: &[u8]) -> Result<InesRom, String> {
+        if bytes.len() < INES_HEADER_SIZE || &bytes[0..4] != INES_MAGIC {
+            return Err("This file is not an iNES ROM.".into());
+        }
+        let flags_6 = bytes[6];
+        let flags_7 = bytes[7];
+
+        // Old dumping tools wrote a signature like "DiskDude!" into bytes 7-15, which
+        // would garble the upper nibble of the mapper number. NES 2.0 headers use
+        // those bytes, so they are only trusted when the header says it's NES 2.0.
+        let is_nes_2 = flags_7 & 0b1100 == 0b1000;
+        let is_padded = bytes[12..INES_HEADER_SIZE].iter().all(|byte| *byte == 0);
+
+        // NES 2.0 adds the upper bits of the bank counts, for ROMs over 4MB.
+        let (prg_banks_high, chr_banks_high) = if is_nes_2 {
+            (bytes[9] & 0x0f, bytes[9] >> 4)
+        } else {
+            (0, 0)
+        };
+        if prg_banks_high == 0xf || chr_banks_high == 0xf {
+            return Err("ROM sizes in the exponent form aren't supported.".into());
+        }
+        let prg_banks = (prg_banks_high as usize) << 8 | bytes[4] as usize;
+        let chr_banks = (chr_banks_high as usize) << 8 | bytes[5] as usize;
+
+        if prg_banks == 0 {
+            return Err("The ROM header doesn't have any PRG ROM banks.".into());
+        }
+
+        let prg_ram_size = if is_nes_2 {
+            // The volatile and battery backed sizes are shift counts, 64 << n bytes.
+            let size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+            size(bytes[10] & 0x0f) + size(bytes[10] >> 4)
+        } else if is_padded {
+            // In 8KB units, where 0 means 8KB for compatibility.
+            bytes[8] as usize * PRG_RAM_BANK_SIZE
+        } else {
+            0
+        };
+        let mapper_high = if is_nes_2 || is_padded {
+            flags_7 & 0xf0
+        } else {
+            0
+        };
+        let mapper = mapper_high | (flags_6 >> 4);
+
+        let mirroring = if flags_6 & Flags6::FourScreen as u8 != 0 {
+            Mirroring::FourScreen
+        } else if flags_6 & Flags6::VerticalMirroring as u8 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        let has_trainer = flags_6 & Flags6::Trainer as u8 != 0;
+        let prg_start = INES_HEADER_SIZE + if has_trainer { TRAINER_SIZE } else { 0 };
+        let chr_start = prg_start + prg_banks * PRG_BANK_SIZE;
+        let chr_end = chr_start + chr_banks * CHR_BANK_SIZE;
+        // Extra bytes at the end are ignored, some dumps have a title there.
+        if bytes.len() < chr_end {
+            return Err(format!(
+                "The ROM is {} bytes, but the header describes {} bytes.",
+                bytes.len(),
+                chr_end
+            ));
+        }
+
+        Ok(InesRom {
+            mapper,
+            mirroring,
+            has_battery: flags_6 & Flags6::Battery as u8 != 0,
+            prg_ram_size: prg_ram_size
+                .max(PRG_RAM_BANK_SIZE)
+                .next_multiple_of(PRG_RAM_BANK_SIZE),
+            trainer: if has_trainer {
+                Some(bytes[INES_HEADER_SIZE..prg_start].to_vec())
+            } else {
+                None
+            },
+            prg_rom: bytes[prg_start..chr_start].to_vec(),
+            chr_rom: bytes[chr_start..chr_end].to_vec(),
+        })
+    }
+
+    /// Look the ROM up in the built in database of known dumps, see `rom::database`.
+    pub fn identify(&self) -> Option<RomInfo> {
+        self.identify_in(&RomDatabase::embedded())
+    }
+
+    /// The dumps are hashed without the header or trainer, so a ROM is still found
+    /// when its header was written by a different tool.
+    pub fn identify_in(&self, database: &RomDatabase) -> Option<RomInfo> {
+        let mut contents = self.prg_rom.clone();
+        contents.extend_from_slice(&self.chr_rom);
+        database.find(contents.len(), crc32(&contents)).cloned()
+    }
+
+    /// Build the cartridge hardware described by the header.
+    pub fn into_mapper(self) -> Result<Box<dyn Mapper>, String> {
+        create_mapper(self.mapper, self)
+    }
+
+    /// Like `into_mapper`, but a mapper that isn't supported boots as NROM, which can
+    /// be enough to see the title screen. See FallbackMapper.
+    pub fn into_mapper_or_fallback(self) -> Result<Box<dyn Mapper>, String> {
+        if SUPPORTED_MAPPERS.contains(&self.mapper) {
+            self.into_mapper()
+        } else {
+            Ok(Box::new(FallbackMapper::new(self)?))
+        }
+    }
+}
+
+/// The parts of an iNES header that are picked when a program is assembled into one.
+/// The default is NROM-256 with horizontal mirroring.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InesHeader {
+    pub mapper: u8,
+    pub mirroring: Mirroring,
+    pub has_battery: bool,
+    /// 2 for 32KB of PRG ROM at $8000, or 1 for 16KB at $c000, like NROM-128.
+    pub prg_banks: u8,
+}
+
+impl Default for InesHeader {
+    fn default() -> InesHeader {
+        InesHeader {
+            mapper: 0,
+            mirroring: Mirroring::Horizontal,
+            has_battery: false,
+            prg_banks: 2,
+        }
+    }
+}
+
+impl InesHeader {
+    /// "horizontal", "vertical", or "four_screen", which are the only mirrorings a
+    /// header can describe. The rest are switched by the mapper.
+    pub fn mirroring_from_name(name: &str) -> Result<Mirroring, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "horizontal" => Ok(Mirroring::Horizontal),
+            "vertical" => Ok(Mirroring::Vertical),
+            "four_screen" => Ok(Mirroring::FourScreen),
+            _ => Err(format!(
+                "Unknown mirroring \"{}\", expected horizontal, vertical, or four_screen",
+                name
+            )),
+        }
+    }
+
+    /// Bytes 6 and 7 of the header.
+    pub fn flags(&self) -> Result<[u8; 2], String> {
+        let mirroring = match self.mirroring {
+            Mirroring::Horizontal => 0,
+            Mirroring::Vertical => Flags6::VerticalMirroring as u8,
+            Mirroring::FourScreen => Flags6::FourScreen as u8,
+            mirroring => {
+                return Err(format!(
+                    "An iNES header can't describe {:?} mirroring.",
+                    mirroring
+                ))
+            }
+        };
+        let battery = if self.has_battery {
+            Flags6::Battery as u8
+        } else {
+            0
+        };
+        Ok([self.mapper << 4 | battery | mirroring, self.mapper & 0xf0])
+    }
+}
+
+/// Build an iNES file, with each PRG bank filled with its index, and each CHR bank
+/// filled with its index plus $80. It's for tests, where the banks that are mapped in
+/// can be told apart by their bytes.
+pub fn ines_bytes(flags_6: u8, prg_banks: u8, chr_banks: u8) -> Vec<u8> {
+    let mut bytes = INES_MAGIC.to_vec();
+    bytes.extend_from_slice(&[prg_banks, chr_banks, flags_6]);
+    bytes.resize(INES_HEADER_SIZE, 0);
+    for bank in 0..prg_banks {
+        bytes.extend_from_slice(&[bank; PRG_BANK_SIZE]);
+    }
+    for bank in 0..chr_banks {
+        bytes.extend_from_slice(&[bank | 0x80; CHR_BANK_SIZE]);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::{NesCpu, Step};
+    use crate::test_helpers::nes_from_ines_bytes;
+
+    #[test]
+    fn test_header() {
+        let rom = InesRom::from_ines_bytes(&ines_bytes(0b0001_0011, 2, 1)).unwrap();
+        assert_eq!(rom.mapper, 1);
+        assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert!(rom.has_battery);
+        assert!(rom.trainer.is_none());
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_BANK_SIZE);
+        assert_eq!(rom.prg_rom[PRG_BANK_SIZE], 1);
+        assert_eq!(rom.chr_rom, vec![0x80; CHR_BANK_SIZE]);
+        assert_eq!(rom.prg_ram_size, PRG_RAM_BANK_SIZE);
+    }
+
+    #[test]
+    fn test_nes_2_sizes() {
+        // 512 PRG banks is 8MB, past what the iNES header can describe.
+        let mut bytes = ines_bytes(0, 0, 0);
+        bytes[7] = 0b1000;
+        bytes[9] = 0x02;
+        // 32KB of battery backed PRG RAM, and 8KB of volatile.
+        bytes[10] = 0x97;
+        bytes.resize(INES_HEADER_SIZE + 512 * PRG_BANK_SIZE, 0);
+        let rom = InesRom::from_ines_bytes(&bytes).unwrap();
+        assert_eq!(rom.prg_rom.len(), 512 * PRG_BANK_SIZE);
+        assert_eq!(rom.prg_ram_size, 0xa000);
+
+        // The iNES PRG RAM size is in 8KB units.
+        let mut bytes = ines_bytes(0, 1, 0);
+        bytes[8] = 4;
+        assert_eq!(
+            InesRom::from_ines_bytes(&bytes).unwrap().prg_ram_size,
+            0x8000
+        );
+    }
+
+    #[test]
+    fn test_trainer_and_mapper_high_nibble() {
+        let mut bytes = ines_bytes(0b0010_1100, 1, 0);
+        bytes[7] = 0x40;
+        bytes.splice(INES_HEADER_SIZE..INES_HEADER_SIZE, [0xee; TRAINER_SIZE]);
+        let rom = InesRom::from_ines_bytes(&bytes).unwrap();
+        assert_eq!(rom.mapper, 0x42);
+        assert_eq!(rom.mirroring, Mirroring::FourScreen);
+        assert_eq!(rom.trainer, Some(vec![0xee; TRAINER_SIZE]));
+        assert_eq!(rom.prg_rom, vec![0; PRG_BANK_SIZE]);
+        assert!(rom.chr_rom.is_empty());
+
+        // A signature in the padding means the upper nibble can't be trusted.
+        bytes[12..INES_HEADER_SIZE].copy_from_slice(b"ude!");
+        assert_eq!(InesRom::from_ines_bytes(&bytes).unwrap().mapper, 0x02);
+    }
+
+    #[test]
+    fn test_invalid_roms() {
+        let error = |bytes: &[u8]| InesRom::from_ines_bytes(bytes).err();
+        assert_eq!(error(b"NES"), Some("This file is not an iNES ROM.".into()));
+        assert_eq!(
+            error(&ines_bytes(0, 0, 1)),
+            Some("The ROM header doesn't have any PRG ROM banks.".into())
+        );
+        let bytes = ines_bytes(0, 1, 1);
+        assert_eq!(
+            error(&bytes[..bytes.len() - 1]),
+            Some("The ROM is 24591 bytes, but the header describes 24592 bytes.".into())
+        );
+        assert_eq!(
+            InesRom::from_ines_bytes(&ines_bytes(0xf0, 1, 1))
+                .unwrap()
+                .into_mapper()
+                .err(),
+            Some("Mapper 15 isn't supported yet.".into())
+        );
+    }
+
+    /// A short run of what the fuzz targets in fuzz/ do, so that cargo test catches
+    /// the obvious regressions. Valid headers are corrupted, and the programs are
+    /// random bytes, which poke at the mapper and PPU registers.
+    #[test]
+    fn test_corrupt_roms() {
+        // xorshift, so the ROMs are the same on every run.
+        let mut seed: u32 = 0x6502;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        for _ in 0..64 {
+            let mapper = [0, 1, 2, 3, 5][random() as usize % 5];
+            let prg_banks = random() as u8 % 4;
+            let chr_banks = random() as u8 % 3;
+            let mut bytes = ines_bytes(mapper << 4, prg_banks, chr_banks);
+            for (index, byte) in bytes.iter_mut().enumerate().skip(4) {
+                // Keep most of the header, so that the mappers get built.
+                if index >= INES_HEADER_SIZE || random() % 8 == 0 {
+                    *byte = random() as u8;
+                }
+            }
+            let length = random() as usize % (bytes.len() + 1);
+            bytes.truncate(if random() % 4 == 0 {
+                length
+            } else {
+                bytes.len()
+            });
+            if let Ok(mut cpu) = nes_from_ines_bytes(&bytes) {
+                cpu.step(Step::Frames(2));
+            }
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Known dumps of cartridges, so a ROM can be shown by its game's name rather than
+//! whatever its file happens to be called. The entries are in the Logiqx XML format of
+//! the No-Intro DAT files, which hash the ROM without its iNES header.
+//!
+//! https://datomatic.no-intro.org
+//!
+//! A few well known games are built in. The full No-Intro DAT for the NES (Headerless)
+//! can be loaded with `RomDatabase::from_dat`.
+
+/// The built in entries.
+const EMBEDDED_DAT: &str = include_str!("database.dat");
+
+/// The CRC-32 used by zip files and the DATs, with the reversed 0xEDB88320 polynomial.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A known dump.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomInfo {
+    /// The No-Intro name, e.g. "Super Mario Bros. (World)".
+    pub name: String,
+    /// The size and CRC-32 of the PRG ROM followed by the CHR ROM.
+    pub size: usize,
+    pub crc32: u32,
+}
+
+impl RomInfo {
+    /// The name without its tags, e.g. "Super Mario Bros.".
+    pub fn title(&self) -> &str {
+        match self.name.find(" (") {
+            Some(index) => &self.name[..index],
+            None => &self.name,
+        }
+    }
+
+    /// The first tag in a No-Intro name is the region, e.g. "USA", "Japan, USA", or
+    /// "World".
+    pub fn region(&self) -> Option<&str> {
+        let start = self.name.find(" (")? + 2;
+        let end = start + self.name[start..].find(')')?;
+        Some(&self.name[start..end])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RomDatabase {
+    pub entries: Vec<RomInfo>,
+}
+
+/// The value of an attribute in a tag, e.g. `name` in `game name="Tetris (USA)"`.
+fn attribute(tag: &str, key: &str) -> Option<String> {
+    let start = tag.find(&format!(" {}=\"", key))? + key.len() + 3;
+    let end = start + tag[start..].find('"')?;
+    Some(
+        tag[start..end]
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&amp;", "&"),
+    )
+}
+
+impl RomDatabase {
+    pub fn embedded() -> RomDatabase {
+        RomDatabase::from_dat(EMBEDDED_DAT).expect("The embedded DAT is valid.")
+    }
+
+    /// Read the games out of a Logiqx XML DAT. Only the game names and the size and
+    /// crc of their roms are used, the rest of the file is skipped.
+    pub fn from_dat(text: &str) -> Result<RomDatabase, String> {
+        let mut database = RomDatabase::default();
+        let mut game = None;
+        for tag in text.split('<') {
+            if tag.starts_with("game ") {
+                game = Some(
+                    attribute(tag, "name")
+                        .ok_or_else(|| format!("Expected a name in <{}", tag.trim()))?,
+                );
+            } else if tag.starts_with("/game") {
+                game = None;
+            } else if tag.starts_with("rom ") {
+                let name = game
+                    .clone()
+                    .ok_or_else(|| format!("Expected <{} to be in a game", tag.trim()))?;
+                let size = attribute(tag, "size").and_then(|size| size.parse().ok());
+                let crc32 = attribute(tag, "crc")
+                    .and_then(|crc| u32::from_str_radix(&crc, 16).ok());
+                match (size, crc32) {
+                    (Some(size), Some(crc32)) => {
+                        database.entries.push(RomInfo { name, size, crc32 })
+                    }
+                    _ => {
+                        return Err(format!("Expected a size and crc in <{}", tag.trim()))
+                    }
+                }
+            }
+        }
+        if database.entries.is_empty() {
+            return Err("The DAT doesn't have any roms in it.".into());
+        }
+        Ok(database)
+    }
+
+    /// Find the dump with the size and CRC-32 of the headerless ROM.
+    pub fn find(&self, size: usize, crc32: u32) -> Option<&RomInfo> {
+        self.entries
+            .iter()
+            .find(|entry| entry.size == size && entry.crc32 == crc32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::{ines_bytes, InesRom};
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn test_rom_database() {
+        let rom = InesRom::from_ines_bytes(&ines_bytes(0, 2, 1)).unwrap();
+        let mut contents = rom.prg_rom.clone();
+        contents.extend_from_slice(&rom.chr_rom);
+        let dat = format!(
+            "<?xml version=\"1.0\"?>
+            <datafile>
+                <header><name>Test</name></header>
+                <game name=\"Other (USA)\">
+                    <rom name=\"Other (USA).nes\" size=\"16\" crc=\"00000000\"/>
+                </game>
+                <game name=\"Tom &amp; Jerry (Europe) (Rev 1)\">
+                    <description>Tom &amp; Jerry (Europe) (Rev 1)</description>
+                    <rom name=\"Tom.nes\" size=\"{}\" crc=\"{:08X}\" sha1=\"0\"/>
+                </game>
+            </datafile>",
+            contents.len(),
+            crc32(&contents)
+        );
+        let database = RomDatabase::from_dat(&dat).unwrap();
+        let info = rom.identify_in(&database).unwrap();
+        assert_eq!(info.name, "Tom & Jerry (Europe) (Rev 1)");
+        assert_eq!(info.title(), "Tom & Jerry");
+        assert_eq!(info.region(), Some("Europe"));
+        assert_eq!(rom.identify(), None);
+
+        assert!(RomDatabase::from_dat("<datafile></datafile>").is_err());
+        assert!(RomDatabase::from_dat("<rom size=\"16\" crc=\"0\"/>").is_err());
+        assert!(RomDatabase::from_dat("<game name=\"A\"><rom crc=\"0\"/>").is_err());
+
+        let embedded = RomDatabase::embedded();
+        assert!(embedded
+            .entries
+            .iter()
+            .all(|entry| entry.region().is_some()));
+    }
+}
@@ -0,0 +1,549 @@
+//! Save states are a small header followed by the state of each component, written in
+//! a fixed order as little endian values.
+//!
+//!   "6502" magic, u16 version, CPU, RAM, IRQ line, controller 1, mapper, PPU, APU,
+//!   controller 2, PPU alignment, open bus, PPU I/O latch
+//!
+//! The versioning policy: the layout never changes without bumping
+//! SAVE_STATE_VERSION. When the version is bumped, add a migration that upgrades the
+//! body of the previous version, and freeze a state from the previous version in the
+//! save_state directory, with a test that it still loads. Old states are always
+//! migrated forward, only states from a newer version of the emulator are rejected.
+
+use crate::bus::Bus;
+use mos6502_core::cpu_6502::Cpu6502;
+
+pub const SAVE_STATE_MAGIC: &[u8; 4] = b"6502";
+pub const SAVE_STATE_VERSION: u16 = 6;
+
+/// Upgrades a save state body by one version.
+type Migration = fn(Vec<u8>) -> Result<Vec<u8>, String>;
+
+/// MIGRATIONS[n] upgrades a body from version n + 1 to version n + 2.
+const MIGRATIONS: &[Migration] = &[
+    add_ppu,
+    add_apu,
+    add_controller_2,
+    add_ppu_alignment,
+    add_open_bus,
+];
+
+// Every version except the current one needs a way forward.
+const _: () = assert!(MIGRATIONS.len() == SAVE_STATE_VERSION as usize - 1);
+
+/// Version 2 added the PPU to the end. The PPU wasn't running before, so it starts
+/// out zeroed. Migrations write out the old layout by hand rather than calling into the
+/// current code, so that they keep producing the same bytes as the emulator changes.
+fn add_ppu(mut body: Vec<u8>) -> Result<Vec<u8>, String> {
+    let mut writer = StateWriter::default();
+    // PPUCTRL, PPUMASK, the scroll, and v.
+    writer.bytes.extend_from_slice(&[0; 6]);
+    // VRAM, palette RAM, and OAM.
+    writer.bytes(&[0; 0x1000]);
+    writer.bytes(&[0; 0x20]);
+    writer.bytes(&[0; 0x100]);
+    // The register latches.
+    writer.bytes.extend_from_slice(&[0; 6]);
+    // The dot and the frame count.
+    writer.u64(0);
+    writer.u64(0);
+    body.extend_from_slice(&writer.bytes);
+    Ok(body)
+}
+
+/// Version 3 added the APU to the end, in its power up state.
+fn add_apu(mut body: Vec<u8>) -> Result<Vec<u8>, String> {
+    let mut writer = StateWriter::default();
+    // The two pulse channels: the length counter, envelope, timer, and sweep.
+    writer.bytes.extend_from_slice(&[0; 21 * 2]);
+    // The triangle.
+    writer.bytes.extend_from_slice(&[0; 11]);
+    // The noise, with its period and the shift register's initial 1.
+    writer.bytes.extend_from_slice(&[0; 12]);
+    writer.u16(4);
+    writer.u16(1);
+    // The DMC's flags, timer, and period.
+    writer.bytes.extend_from_slice(&[0; 5]);
+    writer.u16(428);
+    // The level, the sample address and length, and the reader.
+    writer.u8(0);
+    writer.u16(0xc000);
+    writer.u16(1);
+    writer.u16(0xc000);
+    writer.u16(0);
+    // The sample buffer, the shift register, the bits remaining, and the silence.
+    writer.bytes.extend_from_slice(&[0; 3]);
+    writer.u8(8);
+    writer.bool(true);
+    // The frame counter.
+    writer.u64(0);
+    writer.bytes.extend_from_slice(&[0; 5]);
+    body.extend_from_slice(&writer.bytes);
+    Ok(body)
+}
+
+/// Version 4 added the second controller to the end, with the strobe off.
+fn add_controller_2(mut body: Vec<u8>) -> Result<Vec<u8>, String> {
+    body.extend_from_slice(&[0; 2]);
+    Ok(body)
+}
+
+/// Version 5 added the PPU alignment to the end. It was always 0 before.
+fn add_ppu_alignment(mut body: Vec<u8>) -> Result<Vec<u8>, String> {
+    body.push(0);
+    Ok(body)
+}
+
+/// Version 6 added the open bus and the PPU's I/O latch to the end, which were always
+/// 0 before.
+fn add_open_bus(mut body: Vec<u8>) -> Result<Vec<u8>, String> {
+    let mut writer = StateWriter::default();
+    writer.u8(0);
+    // The latch, and the frame that each of its bits was refreshed on.
+    writer.u8(0);
+    for _ in 0..8 {
+        writer.u64(0);
+    }
+    body.extend_from_slice(&writer.bytes);
+    Ok(body)
+}
+
+/// Bring the body of a save state from an older version up to the current layout.
+fn migrate(version: u16, mut body: Vec<u8>) -> Result<Vec<u8>, String> {
+    if version == 0 || version > SAVE_STATE_VERSION {
+        return Err(format!(
+            "The save state is version {}, but only versions 1 to {} are supported.",
+            version, SAVE_STATE_VERSION
+        ));
+    }
+    for migration in &MIGRATIONS[(version as usize - 1)..] {
+        body = migration(body)?;
+    }
+    Ok(body)
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Variable length data is prefixed with its length.
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.bytes
+            .extend_from_slice(&(value.len() as u32).to_le_bytes());
+        self.bytes.extend_from_slice(value);
+    }
+
+    /// Components with their own layout, like mappers, write into a separate buffer.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> StateReader<'a> {
+        StateReader { bytes, offset: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.offset + len;
+        let slice = self.bytes.get(self.offset..end).ok_or_else(|| {
+            format!("The save state ended early, at byte {}.", self.bytes.len())
+        })?;
+        self.offset = end;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let mut len = [0; 4];
+        len.copy_from_slice(self.take(4)?);
+        self.take(u32::from_le_bytes(len) as usize)
+    }
+
+    pub fn finish(&self) -> Result<(), String> {
+        if self.offset != self.bytes.len() {
+            return Err(format!(
+                "The save state has {} unexpected bytes at the end.",
+                self.bytes.len() - self.offset
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Saving and loading the whole machine, the CPU along with the NES around it.
+pub trait SaveState {
+    /// Serialize the emulated machine. The cartridge ROM isn't included, so the state
+    /// can only be loaded with the same program.
+    fn save_state(&self) -> Vec<u8>;
+
+    /// Restore a state from save_state, migrating it if it came from an older version.
+    /// On failure the machine is left untouched.
+    fn load_state(&mut self, bytes: &[u8]) -> Result<(), String>;
+}
+
+impl SaveState for Cpu6502<Bus> {
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.bytes.extend_from_slice(SAVE_STATE_MAGIC);
+        writer.u16(SAVE_STATE_VERSION);
+        writer.u8(self.a);
+        writer.u8(self.x);
+        writer.u8(self.y);
+        writer.u8(self.s);
+        writer.u8(self.p);
+        writer.u16(self.pc);
+        writer.u64(self.tick_count);
+        writer.u64(self.cycle_count);
+        self.bus.save_state(&mut writer);
+        writer.bytes
+    }
+
+    fn load_state(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if bytes.len() < 6 || &bytes[0..4] != SAVE_STATE_MAGIC {
+            return Err("This file is not a save state.".into());
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        let body = migrate(version, bytes[6..].to_vec())?;
+
+        let backup = self.save_state();
+        let result = load_body(self, &mut StateReader::new(&body));
+        if result.is_err() {
+            // A bad state shouldn't leave the machine half loaded.
+            load_body(self, &mut StateReader::new(&backup[6..]))
+                .expect("The backup state can always be loaded.");
+        }
+        result
+    }
+}
+
+fn load_body(cpu: &mut Cpu6502<Bus>, reader: &mut StateReader) -> Result<(), String> {
+    cpu.a = reader.u8()?;
+    cpu.x = reader.u8()?;
+    cpu.y = reader.u8()?;
+    cpu.s = reader.u8()?;
+    cpu.p = reader.u8()?;
+    cpu.pc = reader.u16()?;
+    cpu.tick_count = reader.u64()?;
+    cpu.cycle_count = reader.u64()?;
+    cpu.bus.load_state(reader)?;
+    cpu.history.clear();
+    cpu.bus.write_log.forget_from(cpu.cycle_count);
+    reader.finish()
+}
+
+/// A byte that differs between two save states, for tracking down desyncs. The CPU
+/// and RAM are at fixed places at the start of the state, so they're named, while the
+/// rest of the components are only located by their offset into the state.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateDifference {
+    Register {
+        name: &'static str,
+        expected: u64,
+        actual: u64,
+    },
+    Ram {
+        address: u16,
+        expected: u8,
+        actual: u8,
+    },
+    /// The byte is missing when one state is longer than the other.
+    Other {
+        offset: usize,
+        expected: Option<u8>,
+        actual: Option<u8>,
+    },
+}
+
+impl StateDifference {
+    pub fn to_text(&self) -> String {
+        let byte = |value: &Option<u8>| match value {
+            Some(value) => format!("${:02x}", value),
+            None => "nothing".into(),
+        };
+        match self {
+            StateDifference::Register {
+                name,
+                expected,
+                actual,
+            } => format!("{}: expected ${:x}, found ${:x}", name, expected, actual),
+            StateDifference::Ram {
+                address,
+                expected,
+                actual,
+            } => format!(
+                "RAM ${:04x}: expected ${:02x}, found ${:02x}",
+                address, expected, actual
+            ),
+            StateDifference::Other {
+                offset,
+                expected,
+                actual,
+            } => format!(
+                "Byte {} of the state: expected {}, found {}",
+                offset,
+                byte(expected),
+                byte(actual)
+            ),
+        }
+    }
+}
+
+/// Compare two states that were saved by this version of the emulator.
+pub fn diff_states(
+    expected: &[u8],
+    actual: &[u8],
+) -> Result<Vec<StateDifference>, String> {
+    const HEADER_LEN: usize = 6;
+    for state in [expected, actual] {
+        if state.get(..HEADER_LEN) != Some(&header()) {
+            return Err(
+                "Only states from this version of the emulator can be compared.".into(),
+            );
+        }
+    }
+    let mut expected_reader = StateReader::new(&expected[HEADER_LEN..]);
+    let mut actual_reader = StateReader::new(&actual[HEADER_LEN..]);
+    let mut differences = vec![];
+
+    const REGISTERS: [(&str, usize); 8] = [
+        ("a", 1),
+        ("x", 1),
+        ("y", 1),
+        ("s", 1),
+        ("p", 1),
+        ("pc", 2),
+        ("tick_count", 8),
+        ("cycle_count", 8),
+    ];
+    for (name, len) in REGISTERS {
+        let read = |reader: &mut StateReader| -> Result<u64, String> {
+            let mut bytes = [0; 8];
+            bytes[..len].copy_from_slice(reader.take(len)?);
+            Ok(u64::from_le_bytes(bytes))
+        };
+        let (expected, actual) = (read(&mut expected_reader)?, read(&mut actual_reader)?);
+        if expected != actual {
+            differences.push(StateDifference::Register {
+                name,
+                expected,
+                actual,
+            });
+        }
+    }
+
+    let (expected_ram, actual_ram) = (expected_reader.bytes()?, actual_reader.bytes()?);
+    for (address, (expected, actual)) in expected_ram.iter().zip(actual_ram).enumerate() {
+        if expected != actual {
+            differences.push(StateDifference::Ram {
+                address: address as u16,
+                expected: *expected,
+                actual: *actual,
+            });
+        }
+    }
+
+    let start = HEADER_LEN + expected_reader.offset;
+    let (expected_rest, actual_rest) = (&expected[start..], &actual[start..]);
+    for index in 0..expected_rest.len().max(actual_rest.len()) {
+        let (expected, actual) = (expected_rest.get(index), actual_rest.get(index));
+        if expected != actual {
+            differences.push(StateDifference::Other {
+                offset: start + index,
+                expected: expected.copied(),
+                actual: actual.copied(),
+            });
+        }
+    }
+    Ok(differences)
+}
+
+fn header() -> [u8; 6] {
+    let [low, high] = SAVE_STATE_VERSION.to_le_bytes();
+    let [a, b, c, d] = *SAVE_STATE_MAGIC;
+    [a, b, c, d, low, high]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff_states, SaveState, StateDifference, StateWriter};
+    use crate::apu::Apu;
+    use crate::irq::IrqSource;
+    use crate::test_helpers::{load_program, run_program};
+
+    const PROGRAM: &str = "
+        lda #$12
+        ldx #$34
+        ldy #$56
+        sta $0200
+        stx $07ff
+        lda #$01
+        sta $4016
+    ";
+
+    /// Sets the backdrop color through the PPU.
+    const PPU_PROGRAM: &str = "
+        lda #$3f
+        sta $2006
+        lda #$00
+        sta $2006
+        lda #$21
+        sta $2007
+    ";
+
+    #[test]
+    fn test_round_trip() {
+        let program = format!("{}{}", PROGRAM, PPU_PROGRAM);
+        let mut cpu = run_program(&program);
+        cpu.bus.irq.assert(IrqSource::Dmc);
+        let state = cpu.save_state();
+
+        let mut loaded = load_program(&program);
+        loaded.load_state(&state).unwrap();
+        assert_eq!(
+            (loaded.a, loaded.x, loaded.y, loaded.pc),
+            (cpu.a, cpu.x, cpu.y, cpu.pc)
+        );
+        assert_eq!(loaded.cycle_count, cpu.cycle_count);
+        assert_eq!(loaded.save_state(), state);
+        let bus = &loaded.bus;
+        assert_eq!(bus.read_u8(0x07ff), 0x34);
+        assert!(bus.irq.is_asserted_by(IrqSource::Dmc));
+        assert_eq!(bus.ppu.state.palette_ram.backdrop(), 0x21);
+    }
+
+    #[test]
+    fn test_rejected_states() {
+        let mut cpu = load_program(PROGRAM);
+        let mut state = run_program(PROGRAM).save_state();
+
+        assert!(cpu.load_state(&state[..state.len() - 1]).is_err());
+        state[4] = 7;
+        assert_eq!(
+            cpu.load_state(&state),
+            Err(
+                "The save state is version 7, but only versions 1 to 6 are supported."
+                    .into()
+            )
+        );
+        // Nothing was applied.
+        assert_eq!(cpu.a, 0);
+    }
+
+    #[test]
+    fn test_diff_states() {
+        let cpu = run_program(PROGRAM);
+        let expected = cpu.save_state();
+        let mut cpu = run_program(PROGRAM);
+        cpu.x = 0x35;
+        cpu.bus.set_u8(0x0200, 0x13);
+        cpu.bus.ppu.state.palette_ram.write(0x3f00, 0x22);
+        let differences = diff_states(&expected, &cpu.save_state()).unwrap();
+        assert_eq!(
+            differences[..2],
+            [
+                StateDifference::Register {
+                    name: "x",
+                    expected: 0x34,
+                    actual: 0x35
+                },
+                StateDifference::Ram {
+                    address: 0x0200,
+                    expected: 0x12,
+                    actual: 0x13
+                },
+            ]
+        );
+        assert!(matches!(differences[2], StateDifference::Other { .. }));
+        assert_eq!(
+            differences[1].to_text(),
+            "RAM $0200: expected $12, found $13"
+        );
+        assert!(diff_states(&expected, &expected).unwrap().is_empty());
+    }
+
+    /// States from every released version must keep loading. These are frozen files,
+    /// never regenerate them.
+    #[test]
+    fn test_frozen_states() {
+        let mut cpu = load_program(PROGRAM);
+        cpu.load_state(include_bytes!("save_state/v1.state"))
+            .unwrap();
+        assert_eq!((cpu.a, cpu.x, cpu.y), (0x01, 0x34, 0x56));
+        assert_eq!(cpu.bus.read_u8(0x0200), 0x12);
+
+        let program = format!("{}{}", PROGRAM, PPU_PROGRAM);
+        let mut cpu = load_program(&program);
+        cpu.load_state(include_bytes!("save_state/v2.state"))
+            .unwrap();
+        let bus = &cpu.bus;
+        assert_eq!(bus.ppu.state.palette_ram.backdrop(), 0x21);
+        // The APU was added at its power up state, which is the same as a new one.
+        let mut writer = StateWriter::default();
+        bus.apu.save_state(&mut writer);
+        let mut expected = StateWriter::default();
+        Apu::new().save_state(&mut expected);
+        assert_eq!(writer.into_bytes(), expected.into_bytes());
+
+        let mut cpu = load_program(&program);
+        cpu.load_state(include_bytes!("save_state/v3.state"))
+            .unwrap();
+        assert_eq!(cpu.bus.read_u8(0x4017), 0);
+        assert_eq!(cpu.bus.read_u8(0x07ff), 0x34);
+        assert_eq!(cpu.bus.ppu.state.palette_ram.backdrop(), 0x21);
+
+        let mut cpu = load_program(&program);
+        cpu.load_state(include_bytes!("save_state/v4.state"))
+            .unwrap();
+        assert_eq!(cpu.bus.read_u8(0x07ff), 0x34);
+        assert_eq!(cpu.bus.ppu_alignment(), 0);
+
+        let mut cpu = load_program(&program);
+        cpu.load_state(include_bytes!("save_state/v5.state"))
+            .unwrap();
+        assert_eq!(cpu.bus.read_u8(0x07ff), 0x34);
+        assert_eq!(cpu.bus.read_u8(0x5000), 0x34);
+        assert_eq!(cpu.bus.read_u8(0x2000), 0x00);
+    }
+}
@@ -0,0 +1,41 @@
+//! Running programs and cartridges in the tests. The frontends use cpu-6502's
+//! `Emulator`, these are only the CPU on the bus.
+
+use crate::bus::Bus;
+use crate::mappers::{Mapper, SimpleProgram};
+use crate::rom::InesRom;
+use mos6502_asm::AsmLexer;
+use mos6502_core::cpu_6502::{Cpu6502, CpuVariant};
+use mos6502_core::opcodes::OpCode;
+
+/// Assemble the program, and load it at $8000. A KIL is added at the end so that the
+/// CPU stops once the program is done.
+pub fn load_program(text: &str) -> Cpu6502<Bus> {
+    let mut lexer = AsmLexer::new(text);
+    if let Err(parse_error) = lexer.parse() {
+        parse_error.panic_nicely();
+    }
+    let mut bytes = lexer.into_bytes().unwrap().bytes;
+    bytes.push(OpCode::KIL as u8);
+    Cpu6502::new(
+        Bus::new(Box::new(SimpleProgram::load(&bytes))),
+        CpuVariant::Ricoh2A03,
+    )
+}
+
+/// Run the program until the KIL at the end.
+pub fn run_program(text: &str) -> Cpu6502<Bus> {
+    let mut cpu = load_program(text);
+    cpu.run();
+    cpu
+}
+
+/// The CPU with a cartridge plugged in, without a power up preset applied.
+pub fn nes(cartridge: Box<dyn Mapper>) -> Cpu6502<Bus> {
+    Cpu6502::new(Bus::new(cartridge), CpuVariant::Ricoh2A03)
+}
+
+/// Boot the contents of a .nes file.
+pub fn nes_from_ines_bytes(bytes: &[u8]) -> Result<Cpu6502<Bus>, String> {
+    Ok(nes(InesRom::from_ines_bytes(bytes)?.into_mapper()?))
+}
@@ -0,0 +1,164 @@
+//! A rolling log of the writes to the CPU address space, so a debugger can answer
+//! "when was $xxxx last written, and by what?" without setting a watchpoint and running
+//! the game again. Together with the rewind states, the emulator can then go back to
+//! that write, see `Emulator::rewind_to_write`.
+
+use std::collections::VecDeque;
+
+use crate::constants::memory_range;
+
+/// About 10 frames of a busy game, which writes a few thousand times a frame.
+pub const DEFAULT_WRITE_LOG_CAPACITY: usize = 0x8000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoggedWrite {
+    /// The address as it was written, mirrors included.
+    pub address: u16,
+    pub value: u8,
+    /// The instruction that wrote it, or the PC that was interrupted when the CPU
+    /// pushed to the stack for an interrupt.
+    pub pc: u16,
+    /// The PPU frame count at the time of the write.
+    pub frame: u64,
+    /// The CPU's cycle_count when the instruction started.
+    pub cycle: u64,
+}
+
+/// The RAM and PPU registers are mirrored, so e.g. $0810 is the same memory as $0010.
+fn unmirror(address: u16) -> u16 {
+    if address < memory_range::RAM.end {
+        address & memory_range::RAM_ACTUAL.mask()
+    } else if address < memory_range::PPU.end {
+        memory_range::PPU_ACTUAL.start | (address & 0b111)
+    } else {
+        address
+    }
+}
+
+/// A ring buffer of the most recent writes. It's off by default, as it costs a little
+/// for every write.
+pub struct WriteLog {
+    entries: VecDeque<LoggedWrite>,
+    capacity: usize,
+    // The instruction that's running, which the CPU sets before each one.
+    pc: u16,
+    cycle: u64,
+}
+
+impl WriteLog {
+    pub fn new(capacity: usize) -> WriteLog {
+        WriteLog {
+            entries: VecDeque::new(),
+            capacity,
+            pc: 0,
+            cycle: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// A capacity of 0 turns off the log.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Always false without the "debugger" feature, so the logging is optimized out.
+    pub fn is_enabled(&self) -> bool {
+        cfg!(feature = "debugger") && self.capacity > 0
+    }
+
+    /// Called by the CPU before each instruction or interrupt, for the writes it makes.
+    pub fn start_instruction(&mut self, pc: u16, cycle: u64) {
+        self.pc = pc;
+        self.cycle = cycle;
+    }
+
+    pub fn push(&mut self, address: u16, value: u8, frame: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(LoggedWrite {
+            address,
+            value,
+            pc: self.pc,
+            frame,
+            cycle: self.cycle,
+        });
+    }
+
+    /// Forget the writes from instructions that started on or after `cycle`, e.g. when
+    /// an earlier state is loaded, and they haven't happened yet.
+    pub fn forget_from(&mut self, cycle: u64) {
+        while self
+            .entries
+            .back()
+            .is_some_and(|write| write.cycle >= cycle)
+        {
+            self.entries.pop_back();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Iterate from the oldest to the most recent write.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &LoggedWrite> {
+        self.entries.iter()
+    }
+
+    /// The writes to an address or any of its mirrors, from the most recent back.
+    pub fn writes_to(&self, address: u16) -> impl Iterator<Item = &LoggedWrite> {
+        let address = unmirror(address);
+        self.entries
+            .iter()
+            .rev()
+            .filter(move |write| unmirror(write.address) == address)
+    }
+
+    /// The most recent write to an address, if it's still in the log.
+    pub fn last_write(&self, address: u16) -> Option<LoggedWrite> {
+        self.writes_to(address).next().copied()
+    }
+}
+
+#[cfg(all(test, feature = "debugger"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_log() {
+        let mut log = WriteLog::new(2);
+        for cycle in 0..3 {
+            log.start_instruction(0x8000 + cycle as u16, cycle);
+            log.push(0x0810, cycle as u8, 0);
+        }
+        let cycles: Vec<u64> = log.iter().map(|write| write.cycle).collect();
+        assert_eq!(cycles, [1, 2]);
+        // Found through the mirror.
+        assert_eq!(log.last_write(0x0010).map(|write| write.pc), Some(0x8002));
+        assert_eq!(log.last_write(0x0011), None);
+
+        log.forget_from(2);
+        assert_eq!(log.last_write(0x0010).map(|write| write.value), Some(1));
+        log.set_capacity(0);
+        log.push(0x0010, 0, 0);
+        assert!(log.is_empty());
+    }
+}
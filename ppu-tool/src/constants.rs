@@ -12,3 +12,10 @@ pub const SIDE_PANEL_WIDTH: f32 =
 pub const PALETTE_SWATCH_SIZE: f32 = 22.0;
 // This is the distance the attributes are offset in the nametable data.
 pub const ATTRIBUTES_OFFSET: usize = 0x3c0;
+// The palettes used until a palette file is loaded.
+pub const DEFAULT_PALETTES: [[u8; 4]; 4] = [
+    [0x22, 0x29, 0x1a, 0x0f],
+    [0x22, 0x36, 0x17, 0x0f],
+    [0x22, 0x30, 0x21, 0x0f],
+    [0x22, 0x27, 0x17, 0x0f],
+];
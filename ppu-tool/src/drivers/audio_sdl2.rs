@@ -0,0 +1,76 @@
+use cpu_6502::apu::{Apu, DEFAULT_SAMPLE_RATE};
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+
+const FRAMES_PER_SECOND: u32 = 60;
+
+/// Never let more than this many frames of audio pile up in the queue, or the sound
+/// lags behind the picture.
+const MAX_QUEUED_FRAMES: u32 = 4;
+
+/// Plays the APU samples through an SDL2 audio queue. The queue is pushed to from the
+/// frame loop, so there's no audio callback thread to share the emulator with.
+pub struct AudioSdl2 {
+    // The queue stops playing when the subsystem is dropped.
+    _audio: AudioSubsystem,
+    queue: AudioQueue<f32>,
+    buffer: Vec<f32>,
+}
+
+impl AudioSdl2 {
+    pub fn open() -> Result<AudioSdl2, String> {
+        let audio = super::sdl()?.audio()?;
+        let desired = AudioSpecDesired {
+            freq: Some(DEFAULT_SAMPLE_RATE as i32),
+            channels: Some(1),
+            samples: Some(1024),
+        };
+        let queue = audio.open_queue::<f32, _>(None, &desired)?;
+        queue.resume();
+        Ok(AudioSdl2 {
+            _audio: audio,
+            queue,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// The device may not give us 44.1kHz, so the APU is told to sample at whatever
+    /// rate was actually opened.
+    pub fn sample_rate(&self) -> u32 {
+        self.queue.spec().freq as u32
+    }
+
+    fn samples_per_frame(&self) -> u32 {
+        self.sample_rate() / FRAMES_PER_SECOND
+    }
+
+    /// How many frames to emulate before the next draw. The window's refresh rate
+    /// isn't always 60Hz, so the queued audio decides: run an extra frame when the
+    /// queue is about to run dry, and skip one when it's getting too far ahead.
+    pub fn frames_to_run(&self) -> u32 {
+        let queued = self.queue.size() / std::mem::size_of::<f32>() as u32;
+        if queued < self.samples_per_frame() {
+            2
+        } else if queued > self.samples_per_frame() * MAX_QUEUED_FRAMES {
+            0
+        } else {
+            1
+        }
+    }
+
+    /// Move the buffered samples from the APU to the audio device. The APU's mixer has
+    /// already applied the volume and mute.
+    pub fn queue_samples(&mut self, apu: &mut Apu) -> Result<(), String> {
+        if apu.sample_rate() != self.sample_rate() {
+            apu.set_sample_rate(self.sample_rate());
+        }
+        self.buffer.resize(apu.samples.len(), 0.0);
+        let count = apu.samples.drain_into(&mut self.buffer);
+        self.queue.queue_audio(&self.buffer[..count])
+    }
+
+    /// Drop what's queued, e.g. when pausing, so it doesn't play out late.
+    pub fn clear(&self) {
+        self.queue.clear();
+    }
+}
@@ -0,0 +1,65 @@
+use sdl2::controller::GameController;
+use sdl2::event::Event;
+use sdl2::{EventPump, GameControllerSubsystem};
+
+/// Reads gamepads through SDL2's game controller API. It names the buttons by where
+/// they are on an Xbox style pad, e.g. "A" is the bottom face button, whatever the
+/// pad prints on it.
+pub struct GamepadSdl2 {
+    subsystem: GameControllerSubsystem,
+    event_pump: EventPump,
+    // The pads stop sending events when they're dropped.
+    controllers: Vec<GameController>,
+}
+
+impl GamepadSdl2 {
+    pub fn open() -> Result<GamepadSdl2, String> {
+        let sdl = super::sdl()?;
+        Ok(GamepadSdl2 {
+            subsystem: sdl.game_controller()?,
+            event_pump: sdl.event_pump()?,
+            controllers: Vec::new(),
+        })
+    }
+
+    /// The buttons that went down or up since the last poll, along with the index of
+    /// the pad they came from. SDL reports the pads that are already plugged in as
+    /// added, so they're opened here along with the ones that are plugged in later.
+    pub fn poll(&mut self) -> Vec<(usize, String, bool)> {
+        let mut changes = vec![];
+        for event in self.event_pump.poll_iter() {
+            match event {
+                Event::ControllerDeviceAdded { which, .. } => {
+                    match self.subsystem.open(which) {
+                        Ok(controller) => self.controllers.push(controller),
+                        Err(err) => eprintln!("Failed to open the gamepad: {}", err),
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers
+                        .retain(|controller| controller.instance_id() != which);
+                }
+                Event::ControllerButtonDown { which, button, .. } => {
+                    if let Some(pad) = pad_index(&self.controllers, which) {
+                        changes.push((pad, format!("{:?}", button), true));
+                    }
+                }
+                Event::ControllerButtonUp { which, button, .. } => {
+                    if let Some(pad) = pad_index(&self.controllers, which) {
+                        changes.push((pad, format!("{:?}", button), false));
+                    }
+                }
+                _ => {}
+            }
+        }
+        changes
+    }
+}
+
+/// The pads are numbered in the order they were plugged in, so unplugging the first pad
+/// makes the second one player 1.
+fn pad_index(controllers: &[GameController], instance_id: u32) -> Option<usize> {
+    controllers
+        .iter()
+        .position(|controller| controller.instance_id() == instance_id)
+}
@@ -0,0 +1,22 @@
+pub mod audio_sdl2;
+pub mod gamepad_sdl2;
+
+use std::cell::RefCell;
+
+thread_local! {
+    static SDL: RefCell<Option<sdl2::Sdl>> = const { RefCell::new(None) };
+}
+
+/// SDL can only be initialized once at a time, so the drivers share one context. It
+/// stays alive for as long as any of their subsystems do.
+pub fn sdl() -> Result<sdl2::Sdl, String> {
+    SDL.with(|sdl| {
+        let mut sdl = sdl.borrow_mut();
+        if let Some(ref sdl) = *sdl {
+            return Ok(sdl.clone());
+        }
+        let context = sdl2::init()?;
+        *sdl = Some(context.clone());
+        Ok(context)
+    })
+}
@@ -0,0 +1,890 @@
+use crate::drivers::audio_sdl2::AudioSdl2;
+use crate::state::HostInput;
+use crate::window::Progress;
+use cpu_6502::apu::{wav::encode_wav, CHANNELS};
+use cpu_6502::asm::AddressToLabel;
+use cpu_6502::controller::{ControllerMappings, InputEvent};
+use cpu_6502::cpu_6502::{NesCpu, Step, StepResult, STEP_LIMIT_FRAMES};
+use cpu_6502::emulator::{Emulator, StemsRender, DEFAULT_REWIND_FRAMES};
+use cpu_6502::gallery::{Example, ExampleKind};
+use cpu_6502::movie::{Movie, MovieState};
+use cpu_6502::power_up::{PowerUpPreset, PpuAlignment};
+use cpu_6502::ppu::render::{
+    NAMETABLES_HEIGHT, NAMETABLES_WIDTH, PATTERN_TABLE_SIZE, SCREEN_HEIGHT, SCREEN_WIDTH,
+};
+use cpu_6502::ppu::NTSC_PALETTE;
+use cpu_6502::rom::database::{RomDatabase, RomInfo};
+use cpu_6502::rom::{InesRom, PRG_BANK_SIZE};
+use cpu_6502::save_state::SaveState;
+use cpu_6502::session::Session;
+use cpu_6502::storage::StorageBackend;
+use cpu_6502::symbols;
+use cpu_6502::watch::{Watch, WatchFormat};
+use cpu_6502::write_log::{LoggedWrite, DEFAULT_WRITE_LOG_CAPACITY};
+use image::ImageEncoder;
+use std::path::{Path, PathBuf};
+
+/// The size of each sprite in the sprites image, which fits the 8x16 sprites.
+pub const SPRITE_CELL_WIDTH: usize = 8;
+pub const SPRITE_CELL_HEIGHT: usize = 16;
+
+/// How many frames of the stem export run on each host frame. It's a couple of seconds
+/// of audio, which keeps the tool responsive while it renders.
+const STEM_FRAMES_PER_UPDATE: u64 = 120;
+
+/// How many save states each game can keep.
+pub const STATE_SLOTS: usize = 4;
+
+/// The address spaces that the memory windows can show.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MemorySpace {
+    Cpu,
+    Ppu,
+}
+
+impl MemorySpace {
+    pub fn name(self) -> &'static str {
+        match self {
+            MemorySpace::Cpu => "CPU",
+            MemorySpace::Ppu => "PPU",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MemorySpace> {
+        [MemorySpace::Cpu, MemorySpace::Ppu]
+            .into_iter()
+            .find(|space| space.name() == name)
+    }
+
+    pub fn last_address(self) -> u16 {
+        match self {
+            MemorySpace::Cpu => 0xffff,
+            MemorySpace::Ppu => 0x3fff,
+        }
+    }
+}
+
+/// A hex view of an address range. Any number of them can be open, e.g. one pinned to
+/// the OAM buffer at $0200 and another to the PRG RAM at $6000.
+pub struct MemoryWindow {
+    /// Keeps the egui state of each window apart.
+    pub id: u64,
+    pub space: MemorySpace,
+    /// The first and last address shown.
+    pub start: u16,
+    pub end: u16,
+    /// The highlighted address.
+    pub address: u16,
+    /// Scroll to the highlighted address on the next frame.
+    pub is_scroll_pending: bool,
+    pub jump_text: String,
+    /// The byte being edited, and the hex typed in so far.
+    pub edit: Option<(u16, String)>,
+}
+
+impl MemoryWindow {
+    /// Show the whole address space, scrolled to `address`.
+    pub fn new(id: u64, space: MemorySpace, address: u16) -> MemoryWindow {
+        MemoryWindow {
+            id,
+            space,
+            start: 0,
+            end: space.last_address(),
+            address,
+            is_scroll_pending: true,
+            jump_text: String::new(),
+            edit: None,
+        }
+    }
+
+    pub fn contains(&self, address: u16) -> bool {
+        (self.start..=self.end).contains(&address)
+    }
+
+    /// Highlight an address, and show the whole space again if it's outside the range.
+    pub fn jump(&mut self, address: u16) {
+        if !self.contains(address) {
+            self.start = 0;
+            self.end = self.space.last_address();
+        }
+        self.address = address;
+        self.is_scroll_pending = true;
+    }
+}
+
+/// The "key = value" lines of a text section of the session.
+fn session_config<'a>(
+    session: &'a Session,
+    name: &str,
+) -> Result<Vec<(&'a str, &'a str)>, String> {
+    let text = session.get_string(name)?.unwrap_or_default();
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| match line.split_once('=') {
+            Some((key, value)) => Ok((key.trim(), value.trim())),
+            None => Err(format!(
+                "Expected \"key = value\" in the session's {}, but found \"{}\"",
+                name, line
+            )),
+        })
+        .collect()
+}
+
+/// A ROM running in the emulator, for looking at the PPU of a real game.
+pub struct Game {
+    pub filename: String,
+    /// The ROM file, for picking the game back up in the next session. The examples
+    /// don't have one.
+    pub path: Option<PathBuf>,
+    /// The game's name when the ROM is a known dump, otherwise the filename.
+    pub title: String,
+    pub rom_info: Option<RomInfo>,
+    pub emulator: Emulator,
+    /// The labels from the assembler for the examples, or for a ROM, from the FCEUX or
+    /// Mesen label files next to it.
+    pub labels: AddressToLabel,
+    /// How many 16KB banks of PRG ROM the cartridge has, for placing the labels in the
+    /// label files.
+    prg_banks: usize,
+    pub is_paused: bool,
+    /// Runs the game backwards, a frame at a time, while the rewind key is held.
+    pub is_rewinding: bool,
+    pub texture: Option<egui::TextureHandle>,
+    /// Clicking the game view picks a pixel to inspect while this is on.
+    pub is_inspecting: bool,
+    pub inspected_pixel: Option<(u8, u8)>,
+    pub memory_windows: Vec<MemoryWindow>,
+    next_memory_window: u64,
+    pub is_chr_banks_open: bool,
+    pub is_memory_map_open: bool,
+    pub is_pattern_tables_open: bool,
+    /// The palette that the pattern tables are drawn with, 0-3 for the background
+    /// palettes and 4-7 for the sprite palettes.
+    pub pattern_table_palette: u8,
+    /// The pattern tables are drawn through the CHR banks that this scanline of the
+    /// last frame was drawn with, for games that switch banks mid-frame.
+    pub pattern_table_scanline: u64,
+    pub pattern_table_texture: Option<egui::TextureHandle>,
+    pub is_nametables_open: bool,
+    /// Outlines sprite 0 on the game view, and marks where it hit.
+    pub is_sprite_zero_open: bool,
+    pub is_oam_open: bool,
+    /// The sprites on this scanline are highlighted in the OAM window.
+    pub oam_scanline: u8,
+    pub oam_texture: Option<egui::TextureHandle>,
+    pub nametable_texture: Option<egui::TextureHandle>,
+    pub is_palette_ram_open: bool,
+    /// Lists the palette writes that landed mid-frame, and marks them on the game view.
+    pub is_palette_writes_open: bool,
+    /// Graphs the number of sprites on each scanline beside the game view.
+    pub is_sprite_counts_open: bool,
+    pub is_scroll_open: bool,
+    /// The scanline picked in the scroll graph, which is also shown in the sprites
+    /// window.
+    pub scroll_scanline: Option<u8>,
+    /// The palette RAM entry that's being changed, from $3F00-$3F1F.
+    pub palette_ram_address: Option<u16>,
+    pub is_watch_open: bool,
+    pub watches: Vec<Watch>,
+    /// The expression being typed into the watch window, and why it didn't parse.
+    pub watch_text: String,
+    pub watch_error: Option<String>,
+    /// The hex address typed in for the "Run to" button.
+    pub run_to_text: String,
+    /// Why the last debugger step didn't finish, e.g. it timed out.
+    pub step_error: Option<String>,
+    /// Stretch the screenshots to the 8:7 pixels of an NTSC TV.
+    pub is_screenshot_aspect_corrected: bool,
+    /// How much audio the stem export renders.
+    pub stem_seconds: u32,
+    /// The stem export that's being rendered, and the directory it's going to. The
+    /// game doesn't run until it's done.
+    stems_export: Option<(PathBuf, StemsRender)>,
+    /// The game runs silently when there's no audio device.
+    pub audio: Option<AudioSdl2>,
+    /// Save states that the player can go back to. They belong to this game, so they
+    /// stay around while another ROM in the session is running.
+    pub state_slots: [Option<Vec<u8>>; STATE_SLOTS],
+    /// The storage key that the PRG RAM is saved under, for cartridges with a
+    /// battery.
+    battery_key: Option<String>,
+    /// Movies without a start state are played from here.
+    power_on_state: Vec<u8>,
+    /// The last movie that was recorded, for saving.
+    pub movie: Option<Movie>,
+}
+
+/// How the ROMs that are opened are set up.
+pub struct LoadOptions {
+    /// What the PPU and APU hold when a ROM is switched on.
+    pub power_up: PowerUpPreset,
+    /// Where the PPU starts relative to the CPU, which timing test ROMs can see.
+    pub ppu_alignment: PpuAlignment,
+    /// The known dumps, for showing the game's name.
+    pub rom_database: RomDatabase,
+}
+
+impl Game {
+    /// Cartridges with a battery pick up their PRG RAM from the last session, which is
+    /// saved next to the ROM, e.g. "zelda.sav" for "zelda.nes".
+    pub fn load(
+        path: &Path,
+        options: &LoadOptions,
+        storage: &dyn StorageBackend,
+    ) -> Result<Game, String> {
+        let bytes = std::fs::read(path)
+            .map_err(|err| format!("Failed to read the ROM {:?}: {}", path, err))?;
+        let filename = match path.file_name() {
+            Some(filename) => filename.to_string_lossy().to_string(),
+            None => return Err(format!("Could not get the filename from {:?}", path)),
+        };
+        let rom = InesRom::from_ines_bytes(&bytes)?;
+        let rom_info = rom.identify_in(&options.rom_database);
+        let prg_banks = rom.prg_rom.len() / PRG_BANK_SIZE;
+        let labels = symbols::load_labels(storage, &path.to_string_lossy(), prg_banks)
+            .unwrap_or_else(|err| {
+                eprintln!("Failed to load the labels: {}", err);
+                AddressToLabel::new()
+            });
+        let battery_key = match rom.has_battery {
+            true => Some(path.with_extension("sav").to_string_lossy().to_string()),
+            false => None,
+        };
+        // A mapper that isn't supported still boots, with a warning in the game window.
+        let mut emulator = Emulator::new(rom.into_mapper_or_fallback()?);
+        emulator.cpu.bus.power_up(options.power_up);
+        emulator.cpu.bus.align_ppu(options.ppu_alignment);
+        if let Some(ref key) = battery_key {
+            emulator.load_prg_ram(storage, key)?;
+        }
+        let mut game = Game::new(filename, emulator);
+        game.path = Some(path.to_path_buf());
+        game.labels = labels;
+        game.prg_banks = prg_banks;
+        game.battery_key = battery_key;
+        if let Some(ref info) = rom_info {
+            game.title = info.name.clone();
+        }
+        game.rom_info = rom_info;
+        Ok(game)
+    }
+
+    /// Assemble one of the built in examples and run it. The CPU examples don't draw
+    /// anything, so they open the memory window on the memory they work on.
+    pub fn load_example(
+        example: &Example,
+        options: &LoadOptions,
+    ) -> Result<Game, String> {
+        let (mut emulator, labels) = example.boot_with_labels()?;
+        emulator.cpu.bus.power_up(options.power_up);
+        emulator.cpu.bus.align_ppu(options.ppu_alignment);
+        let mut game = Game::new(example.name.to_string(), emulator);
+        game.labels = labels;
+        if let ExampleKind::Cpu { watch } = example.kind {
+            game.open_memory_window(MemorySpace::Cpu, watch);
+        }
+        Ok(game)
+    }
+
+    fn new(filename: String, mut emulator: Emulator) -> Game {
+        emulator.set_rewind_capacity(DEFAULT_REWIND_FRAMES);
+        // The watch window looks up when its addresses were last written.
+        emulator
+            .cpu
+            .bus
+            .write_log
+            .set_capacity(DEFAULT_WRITE_LOG_CAPACITY);
+        Game {
+            title: filename.clone(),
+            filename,
+            path: None,
+            rom_info: None,
+            labels: AddressToLabel::new(),
+            prg_banks: 2,
+            is_paused: false,
+            is_rewinding: false,
+            texture: None,
+            is_inspecting: false,
+            inspected_pixel: None,
+            memory_windows: Vec::new(),
+            next_memory_window: 0,
+            is_chr_banks_open: false,
+            is_memory_map_open: false,
+            is_pattern_tables_open: false,
+            pattern_table_palette: 0,
+            pattern_table_scanline: 0,
+            pattern_table_texture: None,
+            is_nametables_open: false,
+            is_sprite_zero_open: false,
+            is_oam_open: false,
+            oam_scanline: 0,
+            oam_texture: None,
+            nametable_texture: None,
+            is_palette_ram_open: false,
+            is_palette_writes_open: false,
+            is_sprite_counts_open: false,
+            is_scroll_open: false,
+            scroll_scanline: None,
+            palette_ram_address: None,
+            is_watch_open: false,
+            is_screenshot_aspect_corrected: false,
+            watches: Vec::new(),
+            watch_text: String::new(),
+            watch_error: None,
+            run_to_text: String::new(),
+            step_error: None,
+            stem_seconds: 30,
+            stems_export: None,
+            audio: match AudioSdl2::open() {
+                Ok(audio) => Some(audio),
+                Err(err) => {
+                    eprintln!(
+                        "Failed to open the audio device, the game is muted: {}",
+                        err
+                    );
+                    None
+                }
+            },
+            state_slots: Default::default(),
+            battery_key: None,
+            power_on_state: emulator.cpu.save_state(),
+            movie: None,
+            emulator,
+        }
+    }
+
+    /// Queue the key and gamepad presses from this host frame. The controller applies
+    /// them when the emulated frame ends, so the game sees the same input however the
+    /// host's frames line up with the emulator's.
+    pub fn update_input(&mut self, mappings: &ControllerMappings, inputs: &[HostInput]) {
+        if self.is_playing_movie() {
+            return;
+        }
+        let bus = &mut self.emulator.cpu.bus;
+        let controllers = [&mut bus.controller_1, &mut bus.controller_2];
+        for (player, (controller, mapping)) in
+            controllers.into_iter().zip(&mappings.players).enumerate()
+        {
+            let frame = controller.frame();
+            for input in inputs {
+                if input.player.is_some_and(|pad| pad != player) {
+                    continue;
+                }
+                let buttons = mapping.buttons(input.source, &input.name);
+                if buttons != 0 {
+                    controller.queue_event(InputEvent {
+                        frame,
+                        buttons,
+                        is_pressed: input.is_pressed,
+                    });
+                }
+            }
+        }
+    }
+
+    pub fn update(&mut self) {
+        if self.stems_export.is_some() {
+            self.continue_export_stems();
+            return;
+        }
+        if self.is_rewinding {
+            self.emulator.rewind_frames(1);
+            // The frames are run again to draw them, but their audio shouldn't play.
+            self.emulator.cpu.bus.apu.samples.clear();
+            if let Some(ref audio) = self.audio {
+                audio.clear();
+            }
+            return;
+        }
+        if self.is_paused {
+            return;
+        }
+        let audio = match self.audio {
+            Some(ref mut audio) => audio,
+            None => {
+                self.emulator.run_frame();
+                return;
+            }
+        };
+        for _ in 0..audio.frames_to_run() {
+            self.emulator.run_frame();
+        }
+        let bus = &mut self.emulator.cpu.bus;
+        if let Err(err) = audio.queue_samples(&mut bus.apu) {
+            eprintln!("Failed to queue the audio: {}", err);
+        }
+    }
+
+    /// Read the watched memory after the frame has run.
+    pub fn update_watches(&mut self) {
+        let bus = &self.emulator.cpu.bus;
+        let frame = bus.ppu.frame_count();
+        for watch in &mut self.watches {
+            watch.update(bus, frame);
+        }
+    }
+
+    /// Watch the expression that's been typed into the watch window.
+    pub fn add_watch(&mut self) {
+        match Watch::parse(&self.watch_text, Some(&self.labels)) {
+            Ok(mut watch) => {
+                watch.update(
+                    &self.emulator.cpu.bus,
+                    self.emulator.cpu.bus.ppu.frame_count(),
+                );
+                self.watches.push(watch);
+                self.watch_text.clear();
+                self.watch_error = None;
+            }
+            Err(err) => self.watch_error = Some(err),
+        }
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.is_paused = !self.is_paused;
+        self.step_error = None;
+        self.clear_audio();
+    }
+
+    /// Step the paused game with the debugger controls. The audio from the step isn't
+    /// played.
+    pub fn step(&mut self, step: Step) {
+        let cpu = &mut self.emulator.cpu;
+        self.step_error = match cpu.step(step) {
+            StepResult::Done => None,
+            StepResult::Kil => {
+                Some(format!("The CPU halted on a KIL before ${:04x}.", cpu.pc))
+            }
+            StepResult::TimedOut => Some(format!(
+                "Gave up after {} frames, stopped at ${:04x}.",
+                STEP_LIMIT_FRAMES, cpu.pc
+            )),
+        };
+        cpu.bus.apu.samples.clear();
+    }
+
+    /// Pause on the write, going back through the rewind states to get there.
+    pub fn rewind_to_write(&mut self, write: &LoggedWrite) {
+        if !self.emulator.rewind_to_write(write) {
+            eprintln!(
+                "The write from frame {} is too far back to rewind to.",
+                write.frame
+            );
+            return;
+        }
+        self.is_paused = true;
+        self.emulator.cpu.bus.apu.samples.clear();
+        self.clear_audio();
+        self.update_watches();
+    }
+
+    /// Drop the audio that's queued to play, for when the game stops running, or jumps
+    /// somewhere else.
+    pub fn clear_audio(&self) {
+        if let Some(ref audio) = self.audio {
+            audio.clear();
+        }
+    }
+
+    pub fn open_memory_window(&mut self, space: MemorySpace, address: u16) {
+        let id = self.next_memory_window;
+        self.next_memory_window += 1;
+        self.memory_windows
+            .push(MemoryWindow::new(id, space, address));
+    }
+
+    /// Jump the newest memory window that covers the address, or open a new one.
+    pub fn show_memory(&mut self, space: MemorySpace, address: u16) {
+        let window = self
+            .memory_windows
+            .iter_mut()
+            .rev()
+            .find(|window| window.space == space && window.contains(address));
+        match window {
+            Some(window) => window.jump(address),
+            None => self.open_memory_window(space, address),
+        }
+    }
+
+    pub fn is_playing_movie(&self) -> bool {
+        matches!(self.emulator.movie_state(), Some(MovieState::Playing(..)))
+    }
+
+    pub fn record_movie(&mut self) {
+        self.emulator.record_movie(&self.filename);
+    }
+
+    /// Stop recording or playing. A recording is kept for saving.
+    pub fn stop_movie(&mut self) {
+        let is_recording =
+            matches!(self.emulator.movie_state(), Some(MovieState::Recording(_)));
+        let movie = self.emulator.stop_movie();
+        if is_recording {
+            self.movie = movie;
+        }
+        // Let go of the buttons that the movie was holding.
+        let bus = &mut self.emulator.cpu.bus;
+        bus.controller_1.set_buttons(0);
+        bus.controller_2.set_buttons(0);
+    }
+
+    pub fn screenshot_png(&self, is_aspect_corrected: bool) -> Result<Vec<u8>, String> {
+        let screenshot = self.emulator.screenshot(is_aspect_corrected);
+        let mut png = Vec::new();
+        image::codecs::png::PngEncoder::new(&mut png)
+            .write_image(
+                &screenshot.rgba,
+                screenshot.width as u32,
+                screenshot.height as u32,
+                image::ColorType::Rgba8,
+            )
+            .map_err(|err| format!("Failed to encode the screenshot: {}", err))?;
+        Ok(png)
+    }
+
+    /// Save the picture to the working directory, named after the ROM and the frame,
+    /// e.g. "smb-1234.png".
+    pub fn save_screenshot(&self) -> Result<PathBuf, String> {
+        let stem = Path::new(&self.filename)
+            .file_stem()
+            .map_or("screenshot".into(), |stem| stem.to_string_lossy());
+        let path = PathBuf::from(format!(
+            "{}-{}.png",
+            stem,
+            self.emulator.cpu.bus.ppu.frame_count()
+        ));
+        let png = self.screenshot_png(self.is_screenshot_aspect_corrected)?;
+        std::fs::write(&path, png).map_err(|err| {
+            format!("Failed to write the screenshot {:?}: {}", path, err)
+        })?;
+        Ok(path)
+    }
+
+    pub fn save_movie(&self, path: &Path) -> Result<(), String> {
+        let movie = self.movie.as_ref().ok_or("There's no movie to save.")?;
+        std::fs::write(path, movie.to_fm2())
+            .map_err(|err| format!("Failed to write the movie {:?}: {}", path, err))
+    }
+
+    /// Play an FM2 movie. Movies that start from power on are played from the state
+    /// the game was loaded in.
+    pub fn play_movie(&mut self, path: &Path) -> Result<(), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read the movie {:?}: {}", path, err))?;
+        let movie = Movie::from_fm2(&text)?;
+        if movie.start_state.is_none() {
+            self.emulator.cpu.load_state(&self.power_on_state)?;
+        }
+        self.emulator.play_movie(movie)?;
+        self.emulator.cpu.bus.apu.samples.clear();
+        self.clear_audio();
+        self.is_paused = false;
+        Ok(())
+    }
+
+    pub fn save_battery_ram(
+        &self,
+        storage: &mut dyn StorageBackend,
+    ) -> Result<(), String> {
+        match self.battery_key {
+            Some(ref key) => self.emulator.save_prg_ram(storage, key),
+            None => Ok(()),
+        }
+    }
+
+    pub fn save_slot(&mut self, slot: usize) {
+        self.state_slots[slot] = Some(self.emulator.cpu.save_state());
+    }
+
+    pub fn load_slot(&mut self, slot: usize) -> Result<(), String> {
+        let state = match self.state_slots[slot] {
+            Some(ref state) => state,
+            None => return Err(format!("Slot {} is empty.", slot + 1)),
+        };
+        self.emulator.cpu.load_state(state)?;
+        // Don't play the rest of the audio from before the load.
+        self.emulator.cpu.bus.apu.samples.clear();
+        self.clear_audio();
+        Ok(())
+    }
+
+    /// Write the labels for FCEUX and Mesen next to the ROM, or to the working directory
+    /// for the examples, and return the files that were written.
+    pub fn export_labels(
+        &self,
+        storage: &mut dyn StorageBackend,
+    ) -> Result<Vec<String>, String> {
+        let rom_key = match self.path {
+            Some(ref path) => path.to_string_lossy().to_string(),
+            None => format!("{}.nes", self.filename),
+        };
+        symbols::save_labels(&self.labels, storage, &rom_key, self.prg_banks)
+    }
+
+    /// The windows that the session keeps open, by name.
+    fn windows_mut(&mut self) -> [(&'static str, &mut bool); 11] {
+        [
+            ("chr_banks", &mut self.is_chr_banks_open),
+            ("memory_map", &mut self.is_memory_map_open),
+            ("pattern_tables", &mut self.is_pattern_tables_open),
+            ("nametables", &mut self.is_nametables_open),
+            ("sprite_zero", &mut self.is_sprite_zero_open),
+            ("oam", &mut self.is_oam_open),
+            ("palette_ram", &mut self.is_palette_ram_open),
+            ("palette_writes", &mut self.is_palette_writes_open),
+            ("sprite_counts", &mut self.is_sprite_counts_open),
+            ("scroll", &mut self.is_scroll_open),
+            ("watch", &mut self.is_watch_open),
+        ]
+    }
+
+    /// Add the game to a suspended session: the ROM it came from, the emulator, the
+    /// debugger's watches and save states, and the windows that are open.
+    pub fn suspend(&mut self, session: &mut Session) -> Result<(), String> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or("Only games loaded from a ROM file can be suspended.")?;
+        session.insert("rom", path.to_string_lossy().as_bytes().to_vec());
+        self.emulator.suspend(session);
+
+        let mut debugger = format!("paused = {}\n", self.is_paused);
+        for watch in &self.watches {
+            debugger.push_str(&format!(
+                "watch = {} {}\n",
+                watch.format.name(),
+                watch.expression()
+            ));
+        }
+        session.insert("debugger", debugger.into_bytes());
+        for (slot, state) in self.state_slots.iter().enumerate() {
+            if let Some(state) = state {
+                session.insert(&format!("slot{}", slot + 1), state.clone());
+            }
+        }
+
+        let mut layout = String::new();
+        for window in &self.memory_windows {
+            layout.push_str(&format!(
+                "memory = {} ${:04x}\n",
+                window.space.name(),
+                window.address
+            ));
+        }
+        for (name, is_open) in self.windows_mut() {
+            layout.push_str(&format!("{} = {}\n", name, is_open));
+        }
+        session.insert("layout", layout.into_bytes());
+        Ok(())
+    }
+
+    /// Load the game that a session was suspended with, and pick up where it was.
+    pub fn resume(
+        session: &Session,
+        options: &LoadOptions,
+        storage: &dyn StorageBackend,
+    ) -> Result<Game, String> {
+        let path = session
+            .get_string("rom")?
+            .ok_or("The session doesn't have a game.")?;
+        let mut game = Game::load(Path::new(path), options, storage)?;
+        game.emulator.resume(session)?;
+        for (slot, state) in game.state_slots.iter_mut().enumerate() {
+            *state = session
+                .get(&format!("slot{}", slot + 1))
+                .map(<[u8]>::to_vec);
+        }
+        for (key, value) in session_config(session, "debugger")? {
+            match key {
+                "paused" => game.is_paused = value == "true",
+                "watch" => {
+                    let (format, expression) = value.split_once(' ').unwrap_or_default();
+                    let mut watch = Watch::parse(expression, None)?;
+                    watch.format = WatchFormat::from_name(format)
+                        .ok_or_else(|| format!("Unknown watch format \"{}\"", format))?;
+                    game.watches.push(watch);
+                }
+                _ => {}
+            }
+        }
+        for (key, value) in session_config(session, "layout")? {
+            if key == "memory" {
+                let address = value.split_once(" $").and_then(|(space, address)| {
+                    Some((
+                        MemorySpace::from_name(space)?,
+                        u16::from_str_radix(address, 16).ok()?,
+                    ))
+                });
+                match address {
+                    Some((space, address)) => game.open_memory_window(space, address),
+                    None => {
+                        return Err(format!(
+                            "Expected \"CPU $0200\" for the memory window, found \"{}\"",
+                            value
+                        ))
+                    }
+                }
+            }
+            for (name, is_open) in game.windows_mut() {
+                if name == key {
+                    *is_open = value == "true";
+                }
+            }
+        }
+        game.update_watches();
+        Ok(game)
+    }
+
+    /// Start rendering the next stem_seconds of each APU channel into its own WAV file
+    /// in the directory. It's rendered over the next few updates, and then the game
+    /// carries on from where it was.
+    pub fn start_export_stems(&mut self, directory: PathBuf) {
+        if self.stems_export.is_some() {
+            eprintln!("The stems are already being exported.");
+            return;
+        }
+        self.clear_audio();
+        let render = self.emulator.start_stems(self.stem_seconds as u64 * 60);
+        self.stems_export = Some((directory, render));
+    }
+
+    pub fn export_progress(&self) -> Option<Progress> {
+        self.stems_export
+            .as_ref()
+            .map(|(directory, render)| Progress {
+                label: format!("Exporting the stems to {:?}", directory),
+                fraction: render.progress(),
+            })
+    }
+
+    fn continue_export_stems(&mut self) {
+        let (directory, mut render) = match self.stems_export.take() {
+            Some(export) => export,
+            None => return,
+        };
+        if !self
+            .emulator
+            .continue_stems(&mut render, STEM_FRAMES_PER_UPDATE)
+        {
+            self.stems_export = Some((directory, render));
+            return;
+        }
+        let stems = self.emulator.finish_stems(render);
+        match self.write_stems(&directory, &stems) {
+            Ok(paths) => {
+                for path in paths {
+                    println!("Exported {:?}", path);
+                }
+            }
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+
+    /// Write each stem to a WAV file in the directory, and return the paths.
+    fn write_stems(
+        &self,
+        directory: &Path,
+        stems: &[Vec<f32>; 5],
+    ) -> Result<Vec<PathBuf>, String> {
+        let sample_rate = self.emulator.cpu.bus.apu.sample_rate();
+        let name = Path::new(&self.filename)
+            .file_stem()
+            .map_or(self.filename.clone(), |stem| {
+                stem.to_string_lossy().to_string()
+            });
+        let mut paths = vec![];
+        for (channel, stem) in CHANNELS.iter().zip(stems.iter()) {
+            let path = directory.join(format!("{}-{}.wav", name, channel.name()));
+            std::fs::write(&path, encode_wav(stem, sample_rate))
+                .map_err(|err| format!("Failed to write {:?}: {}", path, err))?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    pub fn image(&self) -> egui::ColorImage {
+        egui::ColorImage::from_rgba_unmultiplied(
+            [SCREEN_WIDTH, SCREEN_HEIGHT],
+            self.emulator.framebuffer(),
+        )
+    }
+
+    /// The colors of a palette from the palette RAM, as indexes into the NTSC palette.
+    /// Pixel value 0 is always the backdrop color.
+    pub fn palette_colors(&self, palette: u8) -> [u8; 4] {
+        let bus = &self.emulator.cpu.bus;
+        let mut colors = [bus.peek_ppu(0x3f00); 4];
+        for (value, color) in colors.iter_mut().enumerate().skip(1) {
+            *color = bus.peek_ppu(0x3f00 + palette as u16 * 4 + value as u16);
+        }
+        colors
+    }
+
+    /// Both pattern tables side by side, drawn from the CHR banks of the selected
+    /// scanline with the selected palette.
+    pub fn pattern_tables_image(&self) -> egui::ColorImage {
+        let colors = self.palette_colors(self.pattern_table_palette);
+        let bus = &self.emulator.cpu.bus;
+        let scanline = self.pattern_table_scanline;
+        let tables = [
+            bus.frame_pattern_table_pixels(0x0000, scanline),
+            bus.frame_pattern_table_pixels(0x1000, scanline),
+        ];
+        let mut image = egui::ColorImage::new(
+            [PATTERN_TABLE_SIZE * 2, PATTERN_TABLE_SIZE],
+            egui::Color32::BLACK,
+        );
+        for (index, pixel) in image.pixels.iter_mut().enumerate() {
+            let (x, y) = (
+                index % (PATTERN_TABLE_SIZE * 2),
+                index / (PATTERN_TABLE_SIZE * 2),
+            );
+            let table = &tables[x / PATTERN_TABLE_SIZE];
+            let value = table[y * PATTERN_TABLE_SIZE + x % PATTERN_TABLE_SIZE];
+            let [r, g, b] = NTSC_PALETTE[colors[value as usize] as usize & 0x3f];
+            *pixel = egui::Color32::from_rgb(r, g, b);
+        }
+        image
+    }
+
+    /// The 4 logical nametables from the live VRAM, with the mirroring applied.
+    pub fn nametables_image(&self) -> egui::ColorImage {
+        let colors = self.emulator.cpu.bus.nametable_colors();
+        let mut image = egui::ColorImage::new(
+            [NAMETABLES_WIDTH, NAMETABLES_HEIGHT],
+            egui::Color32::BLACK,
+        );
+        for (pixel, color) in image.pixels.iter_mut().zip(colors) {
+            let [r, g, b] = NTSC_PALETTE[color as usize & 0x3f];
+            *pixel = egui::Color32::from_rgb(r, g, b);
+        }
+        image
+    }
+
+    /// All 64 sprites from OAM in an 8x8 grid of 8x16 cells, with the flips applied.
+    /// The transparent pixels are left clear.
+    pub fn sprites_image(&self) -> egui::ColorImage {
+        let bus = &self.emulator.cpu.bus;
+        let mut image = egui::ColorImage::new(
+            [SPRITE_CELL_WIDTH * 8, SPRITE_CELL_HEIGHT * 8],
+            egui::Color32::TRANSPARENT,
+        );
+        for sprite in 0..64 {
+            let (left, top) = (
+                sprite % 8 * SPRITE_CELL_WIDTH,
+                sprite / 8 * SPRITE_CELL_HEIGHT,
+            );
+            for (index, pixel) in bus.sprite_pixels(sprite).into_iter().enumerate() {
+                if pixel & 0b11 == 0 {
+                    continue;
+                }
+                let color = bus.peek_ppu(0x3f10 + pixel as u16);
+                let [r, g, b] = NTSC_PALETTE[color as usize & 0x3f];
+                let (x, y) = (left + index % 8, top + index / 8);
+                image.pixels[y * SPRITE_CELL_WIDTH * 8 + x] =
+                    egui::Color32::from_rgb(r, g, b);
+            }
+        }
+        image
+    }
+}
@@ -1,14 +1,31 @@
 // Remove once stabler.
 // #![allow(unused)]
 mod constants;
+mod drivers;
 mod egui_mq;
+mod game;
+mod panels;
+mod remote;
+mod render;
 mod state;
+#[cfg(feature = "profile")]
+mod trace_profile;
 mod view;
+mod window;
 
 use crate::constants::*;
+use cpu_6502::power_up::{PowerUpPreset, PpuAlignment};
+use cpu_6502::ppu::palette_file::PaletteFile;
+use cpu_6502::rom::database::RomDatabase;
+use cpu_6502::rom::InesRom;
+use game::LoadOptions;
 use macroquad::{self as mq, prelude::*};
-use state::State;
-use std::{cell::RefCell, path::PathBuf};
+use state::{Action, State};
+use std::{
+    cell::RefCell,
+    path::{Path, PathBuf},
+    process::exit,
+};
 
 use structopt::StructOpt;
 
@@ -21,50 +38,256 @@ struct CliOptions {
     /// The path to a character table (.chr)
     #[structopt(short, long)]
     chartable: Option<PathBuf>,
-    /// The path to a palette file (.pal)
+    /// The path to a palette file (.pal), either palette indexes or RGB colors
     #[structopt(short, long)]
     palette: Option<PathBuf>,
+    /// The path to an iNES ROM (.nes) to run in the game window
+    #[structopt(short, long)]
+    rom: Option<PathBuf>,
+    /// Write the nametable to a PNG without opening a window, e.g. for generating
+    /// asset previews in a build script. Needs a nametable and a chartable.
+    #[structopt(long)]
+    export: Option<PathBuf>,
+    /// Serve the HTTP API on this localhost port, so scripts can drive the tool. It's
+    /// off by default. See src/remote.rs for the endpoints.
+    #[structopt(long)]
+    remote_port: Option<u16>,
+    /// The keys and gamepad buttons for the controller, as TOML. The controls window
+    /// saves the rebound buttons here.
+    #[structopt(long, default_value = "controls.toml")]
+    controls: String,
+    /// The volume, mute, and channel gains of the audio mixer, saved here on quit.
+    #[structopt(long, default_value = "mixer.cfg")]
+    mixer: String,
+    /// What the PPU and APU hold when a ROM is switched on: nes, famicom, or zeroed.
+    #[structopt(long, default_value = "nes", parse(try_from_str = PowerUpPreset::from_name))]
+    power_up: PowerUpPreset,
+    /// How many dots the PPU starts ahead of the CPU, from 0 to 2, or random to pick
+    /// one each time a ROM is switched on, like a real console. Some timing test ROMs
+    /// pass or fail depending on it.
+    #[structopt(long, default_value = "0", parse(try_from_str = PpuAlignment::from_name))]
+    ppu_alignment: PpuAlignment,
+    /// A No-Intro DAT of the known NES dumps (headerless), in the XML format, for the
+    /// names of the games. A few well known games are built in.
+    #[structopt(long)]
+    rom_database: Option<PathBuf>,
+    /// Write a Chrome trace of where the time goes in each frame, e.g. out.json, and
+    /// open it in chrome://tracing or ui.perfetto.dev. Needs `--features profile`.
+    #[structopt(long)]
+    trace_profile: Option<PathBuf>,
+    /// Suspend the game to this file when quitting, e.g. game.session, with its movie
+    /// recording, watches, save states, and open windows. Passing the same file again
+    /// picks up where it left off.
+    #[structopt(long)]
+    session: Option<PathBuf>,
+    /// A rhai script that adds debug panels for the game, e.g. an entity table. It can
+    /// be passed more than once. See src/panels.rs for what the scripts can do.
+    #[structopt(long)]
+    script: Vec<PathBuf>,
 }
 
 fn main() {
+    let options = CliOptions::from_args();
+    if let Some(ref path) = options.export {
+        if let Err(err) = export_png(&options, path) {
+            eprintln!("{}", err);
+            exit(1);
+        }
+        return;
+    }
+    if let Some(ref path) = options.trace_profile {
+        if let Err(err) = start_trace_profile(path) {
+            eprintln!("{}", err);
+            exit(1);
+        }
+    }
+
+    let rom_database = match options.rom_database {
+        Some(ref path) => load_rom_database(path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            RomDatabase::embedded()
+        }),
+        None => RomDatabase::embedded(),
+    };
+    // The game's name when it's a known dump, as the title can't change later.
+    let filename = options.rom.as_ref().and_then(|rom| {
+        let rom_info = std::fs::read(rom)
+            .ok()
+            .and_then(|bytes| InesRom::from_ines_bytes(&bytes).ok())
+            .and_then(|ines| ines.identify_in(&rom_database));
+        match rom_info {
+            Some(info) => Some(info.name),
+            None => rom
+                .file_name()
+                .map(|filename| filename.to_string_lossy().to_string()),
+        }
+    });
     mq::Window::from_config(
         Conf {
             sample_count: 4, // msaa
-            window_title: "egui with macroquad".to_string(),
+            window_title: window::title(filename.as_deref(), false),
+            icon: Some(window::icon()),
             high_dpi: true,
             window_width: (TEXTURE_DISPLAY_W + SIDE_PANEL_WIDTH) as i32,
             window_height: TEXTURE_DISPLAY_H as i32,
             ..Default::default()
         },
-        run(),
+        run(options, rom_database),
     );
 }
 
-async fn run() {
+fn export_png(options: &CliOptions, path: &PathBuf) -> Result<(), String> {
+    let read = |path: &Option<PathBuf>, name: &str| match path {
+        Some(path) => std::fs::read(path).map_err(|err| {
+            format!("Failed to read the {} file {:?}: {}", name, path, err)
+        }),
+        None => Err(format!(
+            "Exporting needs a {} file, pass it with --{}",
+            name, name
+        )),
+    };
+    let nametable = read(&options.nametable, "nametable")?;
+    let chartable = read(&options.chartable, "chartable")?;
+    // Like the nametable view, this uses the background palettes or the colors from
+    // the palette file, whichever it has.
+    let file = match options.palette {
+        Some(_) => Some(PaletteFile::parse(&read(&options.palette, "palette")?)?),
+        None => None,
+    };
+    let palettes = file
+        .as_ref()
+        .and_then(|file| file.background_palettes())
+        .unwrap_or(DEFAULT_PALETTES);
+    let master_palette = file
+        .as_ref()
+        .and_then(|file| file.master_palette())
+        .unwrap_or(NTSC_PALETTE);
+
+    let pixels =
+        render::render_nametable(&nametable, &chartable, &palettes, &master_palette)?;
+    image::save_buffer(
+        path,
+        &pixels.data,
+        pixels.width as u32,
+        pixels.height as u32,
+        image::ColorType::Rgba8,
+    )
+    .map_err(|err| format!("Failed to write the PNG {:?}: {}", path, err))
+}
+
+#[cfg(feature = "profile")]
+fn start_trace_profile(path: &Path) -> Result<(), String> {
+    trace_profile::start(path)
+}
+
+#[cfg(not(feature = "profile"))]
+fn start_trace_profile(_path: &Path) -> Result<(), String> {
+    Err(
+        "The trace profile needs the profile feature, run the ppu-tool with \
+         `--features profile`."
+            .into(),
+    )
+}
+
+async fn run(options: CliOptions, rom_database: RomDatabase) {
     let state = {
         let CliOptions {
             nametable,
             chartable,
             palette,
-        } = CliOptions::from_args();
+            rom,
+            remote_port,
+            controls,
+            mixer,
+            power_up,
+            ppu_alignment,
+            session,
+            script,
+            ..
+        } = options;
 
-        RefCell::new(State::new(nametable, chartable, palette))
+        let remote = remote_port.and_then(|port| match remote::start(port) {
+            Ok(receiver) => Some(receiver),
+            Err(err) => {
+                eprintln!("{}", err);
+                None
+            }
+        });
+        let mut state = State::new(
+            nametable,
+            chartable,
+            palette,
+            rom,
+            remote,
+            controls,
+            LoadOptions {
+                power_up,
+                ppu_alignment,
+                rom_database,
+            },
+        );
+        if let Err(err) = state.load_mixer(mixer) {
+            eprintln!("Failed to load the mixer settings: {}", err);
+        }
+        for path in script {
+            if let Err(err) = state.scripts.load_file(&path) {
+                eprintln!("{}", err);
+            }
+        }
+        if let Some(path) = session {
+            if let Err(err) = state.resume_session(path.to_string_lossy().to_string()) {
+                eprintln!("Failed to resume the session: {}", err);
+            }
+        }
+        RefCell::new(state)
     };
 
     loop {
         state.borrow_mut().update();
-        if state.borrow().shortcuts.quit {
+        if state.borrow().shortcuts.triggered(Action::Quit) {
+            state.borrow_mut().save_battery_ram();
+            state.borrow_mut().save_mixer();
+            state.borrow_mut().suspend_session();
             return;
         }
 
-        clear_background(Color::from(state.borrow().background));
-        view::main_art_view(&state);
-        egui_mq::ui(|ctx| {
-            view::palette_change_color_window(&ctx, &state);
-            view::side_panel(&ctx, &state);
-        });
-
-        egui_mq::draw();
+        draw(&state);
         next_frame().await;
     }
 }
+
+fn load_rom_database(path: &Path) -> Result<RomDatabase, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read the ROM database {:?}: {}", path, err))?;
+    RomDatabase::from_dat(&text)
+}
+
+/// Draw the nametable view and the windows.
+#[cfg_attr(feature = "profile", tracing::instrument(skip_all))]
+fn draw(state: &RefCell<State>) {
+    clear_background(Color::from(state.borrow().background));
+    view::main_art_view(state);
+    egui_mq::ui(|ctx| {
+        view::palette_change_color_window(ctx, state);
+        view::side_panel(ctx, state);
+        view::help_window(ctx, state);
+        view::game_window(ctx, state);
+        view::controls_window(ctx, state);
+        view::pixel_inspector_window(ctx, state);
+        view::memory_windows(ctx, state);
+        view::memory_map_window(ctx, state);
+        view::pattern_tables_window(ctx, state);
+        view::nametables_window(ctx, state);
+        view::sprite_zero_window(ctx, state);
+        view::oam_window(ctx, state);
+        view::palette_ram_window(ctx, state);
+        view::palette_writes_window(ctx, state);
+        view::scroll_window(ctx, state);
+        view::watch_window(ctx, state);
+        view::script_panel_windows(ctx, state);
+        view::chr_banks_window(ctx, state);
+        view::progress_window(ctx, state);
+    });
+
+    egui_mq::draw();
+}
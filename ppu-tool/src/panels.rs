@@ -0,0 +1,374 @@
+//! Debug panels that rhai scripts define, for game specific views like an entity table
+//! or the level state, without changing the tool. The scripts are passed with
+//! `--script`, and each `panel` call registers a name and a function that's run after
+//! every frame. The function returns the rows of labels and values, and optionally a
+//! small image, which are drawn in the panel's own window.
+//!
+//!   panel("Player", || #{
+//!       rows: [
+//!           #{ label: "X", value: peek(0x0086) },
+//!           #{ label: "Lives", value: peek(address("lives")) + 1 },
+//!       ],
+//!       image: #{ width: 2, height: 1, rgba: [255, 0, 0, 255, 0, 0, 255, 255] },
+//!   });
+//!
+//! `peek` reads the CPU's memory without side effects, and `address` looks up one of
+//! the game's labels.
+
+use cpu_6502::asm::AddressToLabel;
+use cpu_6502::bus::Bus;
+use rhai::{
+    Array, Blob, Dynamic, Engine, EvalAltResult, FnPtr, ImmutableString, Map, AST,
+};
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+/// The largest image a panel can show, which is as big as the screen.
+const MAX_IMAGE_SIZE: usize = 256;
+
+/// A panel function that runs away is stopped, rather than hanging the frame.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+#[derive(Debug, PartialEq)]
+pub struct PanelRow {
+    pub label: String,
+    pub value: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PanelImage {
+    pub width: usize,
+    pub height: usize,
+    pub rgba: Vec<u8>,
+}
+
+pub struct ScriptPanel {
+    pub name: String,
+    pub rows: Vec<PanelRow>,
+    pub image: Option<PanelImage>,
+    /// The panel function failed, and the panel shows why instead of its rows.
+    pub error: Option<String>,
+    pub texture: Option<egui::TextureHandle>,
+    pub is_open: bool,
+}
+
+fn parse_row(row: &Dynamic) -> Result<PanelRow, String> {
+    let row = row
+        .read_lock::<Map>()
+        .ok_or("Each row should be a map, like #{ label: \"X\", value: 1 }.")?;
+    let label = match row.get("label") {
+        Some(label) if label.is_string() => label.to_string(),
+        _ => return Err("Each row needs a \"label\".".into()),
+    };
+    let value = match row.get("value") {
+        Some(value) => value.to_string(),
+        None => return Err(format!("The row \"{}\" needs a \"value\".", label)),
+    };
+    Ok(PanelRow { label, value })
+}
+
+fn parse_image(image: &Dynamic) -> Result<PanelImage, String> {
+    let image = image
+        .read_lock::<Map>()
+        .ok_or("The image should be a map with a width, height, and rgba.")?;
+    let size = |name: &str| match image.get(name).and_then(|size| size.as_int().ok()) {
+        Some(size) if (1..=MAX_IMAGE_SIZE as i64).contains(&size) => Ok(size as usize),
+        _ => Err(format!(
+            "The image needs a \"{}\" from 1 to {}.",
+            name, MAX_IMAGE_SIZE
+        )),
+    };
+    let (width, height) = (size("width")?, size("height")?);
+    let rgba: Vec<u8> = match image.get("rgba") {
+        Some(rgba) if rgba.is_blob() => rgba.clone().cast::<Blob>(),
+        Some(rgba) if rgba.is_array() => rgba
+            .read_lock::<Array>()
+            .expect("The rgba is an array.")
+            .iter()
+            .map(|byte| match byte.as_int() {
+                Ok(byte) if (0..=0xff).contains(&byte) => Ok(byte as u8),
+                _ => Err("The image's \"rgba\" should be bytes from 0 to 255."),
+            })
+            .collect::<Result<_, _>>()?,
+        _ => return Err("The image needs an \"rgba\" array or blob.".into()),
+    };
+    if rgba.len() != width * height * 4 {
+        return Err(format!(
+            "The image is {}x{}, so \"rgba\" should have {} bytes, not {}.",
+            width,
+            height,
+            width * height * 4,
+            rgba.len()
+        ));
+    }
+    Ok(PanelImage {
+        width,
+        height,
+        rgba,
+    })
+}
+
+impl ScriptPanel {
+    /// Build a panel from what its function returned, a map with the "rows" and
+    /// optionally an "image".
+    pub fn parse(name: &str, value: &Dynamic) -> Result<ScriptPanel, String> {
+        let map = value
+            .read_lock::<Map>()
+            .ok_or("A panel function should return a map, like #{ rows: [] }.")?;
+        let rows = match map.get("rows") {
+            Some(rows) => rows
+                .read_lock::<Array>()
+                .ok_or("The \"rows\" should be an array.")?
+                .iter()
+                .map(parse_row)
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+        let image = match map.get("image") {
+            Some(image) => Some(parse_image(image)?),
+            None => None,
+        };
+        Ok(ScriptPanel {
+            name: name.to_string(),
+            rows,
+            image,
+            error: None,
+            texture: None,
+            is_open: true,
+        })
+    }
+
+    /// Take the rows and image that the panel function returned this frame. The
+    /// texture is only rebuilt when the image changes.
+    fn set_contents(&mut self, panel: ScriptPanel) {
+        if panel.image != self.image {
+            self.texture = None;
+        }
+        self.rows = panel.rows;
+        self.image = panel.image;
+        self.error = None;
+    }
+}
+
+/// What the scripts can see of the game, which is set before the panel functions run.
+#[derive(Default)]
+struct ScriptContext {
+    /// The whole CPU address space, peeked after the frame.
+    memory: Vec<u8>,
+    labels: AddressToLabel,
+    /// The script whose top level is running, for the panels that it registers.
+    script: usize,
+}
+
+/// A panel that a script registered, with the function that fills it in.
+struct Registration {
+    name: String,
+    callback: FnPtr,
+    /// The index of the script that registered it, for calling the function.
+    script: usize,
+}
+
+/// The rhai engine, the scripts that were loaded, and the panels that they registered.
+pub struct PanelScripts {
+    engine: Engine,
+    scripts: Vec<AST>,
+    registrations: Rc<RefCell<Vec<Registration>>>,
+    context: Rc<RefCell<ScriptContext>>,
+    pub panels: Vec<ScriptPanel>,
+}
+
+impl PanelScripts {
+    pub fn new() -> PanelScripts {
+        let registrations: Rc<RefCell<Vec<Registration>>> = Default::default();
+        let context: Rc<RefCell<ScriptContext>> = Default::default();
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|text| eprintln!("{}", text));
+
+        let peek_context = Rc::clone(&context);
+        engine.register_fn("peek", move |address: i64| -> i64 {
+            let context = peek_context.borrow();
+            context
+                .memory
+                .get(address as u16 as usize)
+                .map_or(0, |value| *value as i64)
+        });
+        let address_context = Rc::clone(&context);
+        engine.register_fn(
+            "address",
+            move |name: &str| -> Result<i64, Box<EvalAltResult>> {
+                address_context
+                    .borrow()
+                    .labels
+                    .iter()
+                    .find(|(_, label)| label.as_str() == name)
+                    .map(|(address, _)| *address as i64)
+                    .ok_or_else(|| format!("There's no label named \"{}\".", name).into())
+            },
+        );
+        let panel_registrations = Rc::clone(&registrations);
+        let panel_context = Rc::clone(&context);
+        engine.register_fn("panel", move |name: ImmutableString, callback: FnPtr| {
+            let mut registrations = panel_registrations.borrow_mut();
+            let script = panel_context.borrow().script;
+            registrations.retain(|other| other.name != name.as_str());
+            registrations.push(Registration {
+                name: name.to_string(),
+                callback,
+                script,
+            });
+        });
+
+        PanelScripts {
+            engine,
+            scripts: Vec::new(),
+            registrations,
+            context,
+            panels: Vec::new(),
+        }
+    }
+
+    /// Run a script's top level, which registers its panels.
+    pub fn load(&mut self, name: &str, text: &str) -> Result<(), String> {
+        let ast = self
+            .engine
+            .compile(text)
+            .map_err(|err| format!("Failed to compile the script {}: {}", name, err))?;
+        self.context.borrow_mut().script = self.scripts.len();
+        let result = self.engine.run_ast(&ast);
+        self.scripts.push(ast);
+        result.map_err(|err| format!("The script {} failed: {}", name, err))
+    }
+
+    /// Load a script from a file.
+    pub fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read the script {:?}: {}", path, err))?;
+        self.load(&path.to_string_lossy(), &text)
+    }
+
+    /// Run the panel functions after the frame, with the memory that it left behind.
+    pub fn update(&mut self, bus: &Bus, labels: &AddressToLabel) {
+        if self.registrations.borrow().is_empty() {
+            return;
+        }
+        {
+            let mut context = self.context.borrow_mut();
+            context.memory = (0..=u16::MAX).map(|address| bus.peek_u8(address)).collect();
+            context.labels.clone_from(labels);
+        }
+        for registration in self.registrations.borrow().iter() {
+            let result: Result<Dynamic, String> = registration
+                .callback
+                .call(&self.engine, &self.scripts[registration.script], ())
+                .map_err(|err| err.to_string());
+            let contents =
+                result.and_then(|value| ScriptPanel::parse(&registration.name, &value));
+            let index = match self
+                .panels
+                .iter()
+                .position(|panel| panel.name == registration.name)
+            {
+                Some(index) => index,
+                None => {
+                    self.panels.push(ScriptPanel {
+                        name: registration.name.clone(),
+                        rows: Vec::new(),
+                        image: None,
+                        error: None,
+                        texture: None,
+                        is_open: true,
+                    });
+                    self.panels.len() - 1
+                }
+            };
+            let panel = &mut self.panels[index];
+            match contents {
+                Ok(contents) => panel.set_contents(contents),
+                Err(err) => panel.error = Some(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval(text: &str) -> Dynamic {
+        Engine::new().eval(text).unwrap()
+    }
+
+    #[test]
+    fn test_parse_row() {
+        let row = parse_row(&eval("#{ label: \"Lives\", value: 3 }")).unwrap();
+        assert_eq!(
+            row,
+            PanelRow {
+                label: "Lives".into(),
+                value: "3".into(),
+            }
+        );
+        let row = parse_row(&eval("#{ label: \"Mode\", value: \"Boss\" }")).unwrap();
+        assert_eq!(row.value, "Boss");
+        assert!(parse_row(&eval("#{ value: 3 }")).is_err());
+        assert!(parse_row(&eval("#{ label: \"Lives\" }")).is_err());
+        assert!(parse_row(&eval("[\"Lives\", 3]")).is_err());
+    }
+
+    #[test]
+    fn test_parse_image() {
+        let image = parse_image(&eval(
+            "#{ width: 2, height: 1, rgba: [255, 0, 0, 255, 0, 0, 255, 255] }",
+        ))
+        .unwrap();
+        assert_eq!(
+            image,
+            PanelImage {
+                width: 2,
+                height: 1,
+                rgba: vec![255, 0, 0, 255, 0, 0, 255, 255],
+            }
+        );
+        // A blob works too.
+        let image = parse_image(&eval(
+            "let rgba = blob(4, 0x80); #{ width: 1, height: 1, rgba: rgba }",
+        ))
+        .unwrap();
+        assert_eq!(image.rgba, [0x80; 4]);
+
+        assert!(parse_image(&eval("#{ width: 1, height: 1, rgba: [0, 0, 0] }")).is_err());
+        assert!(
+            parse_image(&eval("#{ width: 1, height: 1, rgba: [0, 0, 0, 256] }")).is_err()
+        );
+        assert!(parse_image(&eval("#{ width: 0, height: 1, rgba: [] }")).is_err());
+        assert!(parse_image(&eval("#{ width: 300, height: 1, rgba: [] }")).is_err());
+        assert!(parse_image(&eval("#{ width: 1, height: 1 }")).is_err());
+    }
+
+    #[test]
+    fn test_parse_panel() {
+        let panel = ScriptPanel::parse(
+            "Player",
+            &eval(
+                "#{
+                    rows: [#{ label: \"X\", value: 16 }, #{ label: \"Y\", value: 32 }],
+                    image: #{ width: 1, height: 1, rgba: [0, 0, 0, 255] },
+                }",
+            ),
+        )
+        .unwrap();
+        assert_eq!(panel.name, "Player");
+        assert_eq!(panel.rows.len(), 2);
+        assert_eq!(panel.rows[1].value, "32");
+        assert!(panel.image.is_some());
+
+        let panel = ScriptPanel::parse("Empty", &eval("#{}")).unwrap();
+        assert!(panel.rows.is_empty());
+        assert!(panel.image.is_none());
+
+        assert!(ScriptPanel::parse("Rows", &eval("#{ rows: 1 }")).is_err());
+        assert!(ScriptPanel::parse("Number", &eval("1")).is_err());
+    }
+}
@@ -0,0 +1,338 @@
+//! A small HTTP API for driving the tool from test scripts and stream overlays. It's
+//! off unless the tool is started with --remote-port, and then only listens on
+//! localhost.
+//!
+//!   GET  /actions                The actions from the shortcut registry.
+//!   POST /actions/<name>         Trigger an action, like pressing its shortcut.
+//!   POST /actions/<name>/down    Hold an action, e.g. "rewind", until it's let go
+//!   POST /actions/<name>/up      with "up".
+//!   POST /rom                    Load the ROM at the path in the body.
+//!   POST /buttons/<name>/down    Press or release a controller 1 button, e.g. "start".
+//!   POST /buttons/<name>/up
+//!   POST /players/<n>/buttons/<name>/down
+//!   POST /players/<n>/buttons/<name>/up  The same for player 1 or 2.
+//!   GET  /screenshot             The current frame as a PNG.
+//!   GET  /memory/<address>/<len> Read CPU memory, the address is in hex.
+//!   GET  /states                 Which save state slots are filled.
+//!   POST /states/<slot>/save     Save or load a slot, from 1 to 4.
+//!   POST /states/<slot>/load
+//!
+//! The server runs on its own thread, and hands each request to the main thread,
+//! which answers it in between frames.
+
+use crate::game::{Game, STATE_SLOTS};
+use crate::state::{Action, State, SHORTCUTS};
+use cpu_6502::controller::{InputEvent, BUTTON_NAMES, PLAYERS};
+use serde_json::{json, Value};
+use std::{
+    path::PathBuf,
+    sync::mpsc::{channel, Receiver, Sender},
+    time::Duration,
+};
+use tiny_http::{Header, Method, Response, Server};
+
+/// How long a request waits for the main thread, which may be stuck behind a file
+/// dialog.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most memory that can be read in one request.
+const MAX_MEMORY_READ: usize = 0x1000;
+
+pub enum RemoteCommand {
+    ListActions,
+    Action(Action, Press),
+    LoadRom(PathBuf),
+    /// The player, counting from 0, the Button bits, and whether they're pressed.
+    Button(usize, u8, bool),
+    Screenshot,
+    ReadMemory {
+        address: u16,
+        len: usize,
+    },
+    ListStates,
+    SaveState(usize),
+    LoadState(usize),
+}
+
+#[derive(Clone, Copy)]
+pub enum Press {
+    /// Trigger the action once.
+    Tap,
+    Down,
+    Up,
+}
+
+pub enum RemoteResponse {
+    Json(Value),
+    Png(Vec<u8>),
+    Error(u16, String),
+}
+
+/// A command from the server thread, with the channel to send its response back on.
+pub struct RemoteRequest {
+    pub command: RemoteCommand,
+    reply: Sender<RemoteResponse>,
+}
+
+impl RemoteRequest {
+    pub fn respond(self, response: RemoteResponse) {
+        // The server stops waiting after a timeout, so there may be no one to reply to.
+        let _ = self.reply.send(response);
+    }
+}
+
+/// Start the server, the main thread handles the requests that come out of the
+/// receiver.
+pub fn start(port: u16) -> Result<Receiver<RemoteRequest>, String> {
+    let server = Server::http(("127.0.0.1", port))
+        .map_err(|err| format!("Failed to start the remote server: {}", err))?;
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let mut body = String::new();
+            let response = match request.as_reader().read_to_string(&mut body) {
+                Ok(_) => match parse_command(request.method(), request.url(), &body) {
+                    Ok(command) => {
+                        let (reply, reply_receiver) = channel();
+                        if sender.send(RemoteRequest { command, reply }).is_err() {
+                            // The tool is shutting down.
+                            return;
+                        }
+                        reply_receiver
+                            .recv_timeout(REPLY_TIMEOUT)
+                            .unwrap_or_else(|_| {
+                                RemoteResponse::Error(
+                                    503,
+                                    "The tool didn't respond.".into(),
+                                )
+                            })
+                    }
+                    Err(response) => response,
+                },
+                Err(err) => RemoteResponse::Error(
+                    400,
+                    format!("Failed to read the body: {}", err),
+                ),
+            };
+            if let Err(err) = request.respond(into_http(response)) {
+                eprintln!("Failed to send the remote response: {}", err);
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+fn into_http(response: RemoteResponse) -> Response<std::io::Cursor<Vec<u8>>> {
+    let (status, mime, data) = match response {
+        RemoteResponse::Json(value) => (200, "application/json", value.to_string()),
+        RemoteResponse::Png(bytes) => {
+            return Response::from_data(bytes).with_header(content_type("image/png"))
+        }
+        RemoteResponse::Error(status, message) => (
+            status,
+            "application/json",
+            json!({ "error": message }).to_string(),
+        ),
+    };
+    Response::from_string(data)
+        .with_status_code(status)
+        .with_header(content_type(mime))
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes("Content-Type", value).expect("The header is valid.")
+}
+
+fn not_found(url: &str) -> RemoteResponse {
+    RemoteResponse::Error(404, format!("Unknown endpoint {}", url))
+}
+
+fn parse_command(
+    method: &Method,
+    url: &str,
+    body: &str,
+) -> Result<RemoteCommand, RemoteResponse> {
+    let path: Vec<&str> = url
+        .split('?')
+        .next()
+        .unwrap_or("")
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .collect();
+    let bad_request = |message: String| RemoteResponse::Error(400, message);
+    let press = |part: Option<&&str>| match part {
+        None => Ok(Press::Tap),
+        Some(&"down") => Ok(Press::Down),
+        Some(&"up") => Ok(Press::Up),
+        Some(_) => Err(not_found(url)),
+    };
+    let button = |player: usize, name: &str, state: &str| {
+        let (button, _) = BUTTON_NAMES
+            .iter()
+            .find(|(_, button_name)| button_name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| bad_request(format!("Unknown button {}", name)))?;
+        match press(Some(&state))? {
+            Press::Down => Ok(RemoteCommand::Button(player, *button, true)),
+            Press::Up => Ok(RemoteCommand::Button(player, *button, false)),
+            Press::Tap => Err(not_found(url)),
+        }
+    };
+    let slot = |part: &str| match part.parse::<usize>() {
+        Ok(slot) if (1..=STATE_SLOTS).contains(&slot) => Ok(slot - 1),
+        _ => Err(bad_request(format!(
+            "Expected a slot from 1 to {}, not {}",
+            STATE_SLOTS, part
+        ))),
+    };
+
+    match (method, path.as_slice()) {
+        (Method::Get, ["actions"]) => Ok(RemoteCommand::ListActions),
+        (Method::Post, ["actions", name, rest @ ..]) if rest.len() <= 1 => {
+            let action = Action::from_name(name)
+                .ok_or_else(|| bad_request(format!("Unknown action {}", name)))?;
+            Ok(RemoteCommand::Action(action, press(rest.first())?))
+        }
+        (Method::Post, ["rom"]) => {
+            let path = body.trim();
+            if path.is_empty() {
+                return Err(bad_request(
+                    "Expected the path to a ROM in the body.".into(),
+                ));
+            }
+            Ok(RemoteCommand::LoadRom(PathBuf::from(path)))
+        }
+        (Method::Post, ["buttons", name, state]) => button(0, name, state),
+        (Method::Post, ["players", n, "buttons", name, state]) => {
+            let player = match n.parse::<usize>() {
+                Ok(player) if (1..=PLAYERS).contains(&player) => player - 1,
+                _ => {
+                    return Err(bad_request(format!(
+                        "Expected a player from 1 to {}, not {}",
+                        PLAYERS, n
+                    )))
+                }
+            };
+            button(player, name, state)
+        }
+        (Method::Get, ["screenshot"]) => Ok(RemoteCommand::Screenshot),
+        (Method::Get, ["memory", address, len]) => {
+            let address = u16::from_str_radix(address.trim_start_matches('$'), 16)
+                .map_err(|_| {
+                    bad_request(format!("Expected a hex address, not {}", address))
+                })?;
+            let len = match len.parse::<usize>() {
+                Ok(len) if len <= MAX_MEMORY_READ => len,
+                _ => {
+                    return Err(bad_request(format!(
+                        "Expected a length up to {}, not {}",
+                        MAX_MEMORY_READ, len
+                    )))
+                }
+            };
+            Ok(RemoteCommand::ReadMemory { address, len })
+        }
+        (Method::Get, ["states"]) => Ok(RemoteCommand::ListStates),
+        (Method::Post, ["states", n, "save"]) => Ok(RemoteCommand::SaveState(slot(n)?)),
+        (Method::Post, ["states", n, "load"]) => Ok(RemoteCommand::LoadState(slot(n)?)),
+        _ => Err(not_found(url)),
+    }
+}
+
+fn no_game() -> RemoteResponse {
+    RemoteResponse::Error(409, "No ROM is loaded.".into())
+}
+
+/// Run a command on the main thread.
+pub fn handle(state: &mut State, command: &RemoteCommand) -> RemoteResponse {
+    match command {
+        RemoteCommand::ListActions => RemoteResponse::Json(
+            SHORTCUTS
+                .iter()
+                .map(|shortcut| {
+                    json!({
+                        "name": shortcut.action.name(),
+                        "shortcut": shortcut.label(),
+                        "description": shortcut.description,
+                    })
+                })
+                .collect(),
+        ),
+        RemoteCommand::Action(action, press) => {
+            let action = *action;
+            match press {
+                Press::Tap => state.shortcuts.trigger(action),
+                Press::Down => state.shortcuts.set_held(action, true),
+                Press::Up => state.shortcuts.set_held(action, false),
+            }
+            RemoteResponse::Json(json!({ "action": action.name() }))
+        }
+        RemoteCommand::LoadRom(path) => {
+            match Game::load(path, &state.load_options, &*state.storage) {
+                Ok(game) => {
+                    let filename = game.filename.clone();
+                    state.add_game(game);
+                    RemoteResponse::Json(json!({ "filename": filename }))
+                }
+                Err(err) => RemoteResponse::Error(400, err),
+            }
+        }
+        RemoteCommand::Button(player, buttons, is_pressed) => match state.game {
+            Some(ref mut game) => {
+                let bus = &mut game.emulator.cpu.bus;
+                let controller = match player {
+                    0 => &mut bus.controller_1,
+                    _ => &mut bus.controller_2,
+                };
+                let frame = controller.frame();
+                controller.queue_event(InputEvent {
+                    frame,
+                    buttons: *buttons,
+                    is_pressed: *is_pressed,
+                });
+                RemoteResponse::Json(json!({ "frame": frame }))
+            }
+            None => no_game(),
+        },
+        RemoteCommand::Screenshot => match state.game {
+            Some(ref game) => match game.screenshot_png(false) {
+                Ok(png) => RemoteResponse::Png(png),
+                Err(err) => RemoteResponse::Error(500, err),
+            },
+            None => no_game(),
+        },
+        RemoteCommand::ReadMemory { address, len } => match state.game {
+            Some(ref game) => {
+                let bus = &game.emulator.cpu.bus;
+                let bytes: Vec<u8> = (0..*len)
+                    .map(|offset| bus.peek_u8(address.wrapping_add(offset as u16)))
+                    .collect();
+                RemoteResponse::Json(json!({ "address": address, "bytes": bytes }))
+            }
+            None => no_game(),
+        },
+        RemoteCommand::ListStates => match state.game {
+            Some(ref game) => RemoteResponse::Json(json!({
+                "slots": game
+                    .state_slots
+                    .iter()
+                    .map(|slot| slot.is_some())
+                    .collect::<Vec<bool>>(),
+            })),
+            None => no_game(),
+        },
+        RemoteCommand::SaveState(slot) => match state.game {
+            Some(ref mut game) => {
+                game.save_slot(*slot);
+                RemoteResponse::Json(json!({ "slot": slot + 1 }))
+            }
+            None => no_game(),
+        },
+        RemoteCommand::LoadState(slot) => match state.game {
+            Some(ref mut game) => match game.load_slot(*slot) {
+                Ok(()) => RemoteResponse::Json(json!({ "slot": slot + 1 })),
+                Err(err) => RemoteResponse::Error(409, err),
+            },
+            None => no_game(),
+        },
+    }
+}
@@ -0,0 +1,135 @@
+//! Turns the nametable, chartable, and palette files into RGBA pixels. This doesn't
+//! touch macroquad, so the same pixels can be uploaded to a texture or written out
+//! to a PNG by the headless export.
+
+use crate::constants::*;
+use cpu_6502::ppu::palette_file::MasterPalette;
+
+const TILE_PIXEL_WIDTH: usize = 8;
+const RGBA_COMPONENTS: usize = 4;
+const BYTES_PER_BIT_PLANE: usize = 8;
+// Two bit planes.
+const BYTES_PER_CH_TILE: usize = BYTES_PER_BIT_PLANE + BYTES_PER_BIT_PLANE;
+const CH_TILES_PER_SIDE: usize = 16;
+/// A chartable is a single 4KB pattern table of 256 tiles.
+pub const CHARTABLE_BYTES: usize =
+    CH_TILES_PER_SIDE * CH_TILES_PER_SIDE * BYTES_PER_CH_TILE;
+/// 960 bytes of tiles, and 64 bytes of attributes.
+pub const NAMETABLE_BYTES: usize = 1024;
+
+/// An RGBA image, 4 bytes per pixel.
+pub struct Pixels {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Pixels {
+    fn new(width: usize, height: usize) -> Pixels {
+        Pixels {
+            width,
+            height,
+            data: vec![0; width * height * RGBA_COMPONENTS],
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, [r, g, b]: [u8; 3]) {
+        let offset = (y * self.width + x) * RGBA_COMPONENTS;
+        self.data[offset..offset + RGBA_COMPONENTS].copy_from_slice(&[r, g, b, 0xff]);
+    }
+}
+
+fn check_chartable(chartable: &[u8]) -> Result<(), String> {
+    if chartable.len() != CHARTABLE_BYTES {
+        return Err(format!(
+            "Char data has size {} bytes, expected {} bytes",
+            chartable.len(),
+            CHARTABLE_BYTES
+        ));
+    }
+    Ok(())
+}
+
+/// The 2 bit value of a pixel in a tile.
+/// https://www.nesdev.org/wiki/PPU_pattern_tables
+fn tile_pixel(chartable: &[u8], tile: u8, ch_x: usize, ch_y: usize) -> u8 {
+    let offset = tile as usize * BYTES_PER_CH_TILE;
+    let ch_plane_1 = chartable[offset + ch_y];
+    let ch_plane_2 = chartable[offset + BYTES_PER_BIT_PLANE + ch_y];
+    let low_bit = (ch_plane_1 >> (7 - ch_x)) & 0b0000_0001;
+    let high_bit = ((ch_plane_2 >> (7 - ch_x)) & 0b0000_0001) << 1;
+    low_bit | high_bit
+}
+
+/// The chartable as a 16x16 grid of tiles, in greyscale.
+pub fn render_chartable(chartable: &[u8]) -> Result<Pixels, String> {
+    check_chartable(chartable)?;
+    let side = CH_TILES_PER_SIDE * TILE_PIXEL_WIDTH;
+    let mut pixels = Pixels::new(side, side);
+    for tile in 0..=255u8 {
+        let tile_x = tile as usize % CH_TILES_PER_SIDE * TILE_PIXEL_WIDTH;
+        let tile_y = tile as usize / CH_TILES_PER_SIDE * TILE_PIXEL_WIDTH;
+        for ch_y in 0..TILE_PIXEL_WIDTH {
+            for ch_x in 0..TILE_PIXEL_WIDTH {
+                let grey = tile_pixel(chartable, tile, ch_x, ch_y) * 85;
+                pixels.set(tile_x + ch_x, tile_y + ch_y, [grey, grey, grey]);
+            }
+        }
+    }
+    Ok(pixels)
+}
+
+/// The full nametable, with the tiles drawn from the chartable and colored by the
+/// attribute palettes, which index into the master palette.
+pub fn render_nametable(
+    nametable: &[u8],
+    chartable: &[u8],
+    palettes: &[[u8; 4]; 4],
+    master_palette: &MasterPalette,
+) -> Result<Pixels, String> {
+    if nametable.len() != NAMETABLE_BYTES {
+        return Err(format!(
+            "Expected the nametable file to contain {} bytes. Instead {} were found.",
+            NAMETABLE_BYTES,
+            nametable.len()
+        ));
+    }
+    check_chartable(chartable)?;
+
+    let mut pixels = Pixels::new(
+        NAMETABLE_W * TILE_PIXEL_WIDTH,
+        NAMETABLE_H * TILE_PIXEL_WIDTH,
+    );
+    for tile_y in 0..NAMETABLE_H {
+        for tile_x in 0..NAMETABLE_W {
+            let tile = nametable[tile_y * NAMETABLE_W + tile_x];
+            let palette = palettes[attribute(nametable, tile_x, tile_y) as usize];
+            for ch_y in 0..TILE_PIXEL_WIDTH {
+                for ch_x in 0..TILE_PIXEL_WIDTH {
+                    let value = tile_pixel(chartable, tile, ch_x, ch_y);
+                    pixels.set(
+                        tile_x * TILE_PIXEL_WIDTH + ch_x,
+                        tile_y * TILE_PIXEL_WIDTH + ch_y,
+                        master_palette[palette[value as usize] as usize & 0x3f],
+                    );
+                }
+            }
+        }
+    }
+    Ok(pixels)
+}
+
+/// Look up which of the 4 palettes a tile uses.
+fn attribute(nametable: &[u8], tile_x: usize, tile_y: usize) -> u8 {
+    // Each byte has 2 tiles, and each attribute covers 2 tiles, so 2 * 2 = 4.
+    let index = ATTRIBUTES_OFFSET + (tile_x >> 2) + (tile_y >> 2) * 8;
+    let byte = nametable[index];
+
+    // 7654 3210
+    // |||| ||++- Color bits 3-2 for top left quadrant of this byte
+    // |||| ++--- Color bits 3-2 for top right quadrant of this byte
+    // ||++------ Color bits 3-2 for bottom left quadrant of this byte
+    // ++-------- Color bits 3-2 for bottom right quadrant of this byte
+    let quadrant = (tile_x >> 1) & 0b1 | (((tile_y >> 1) & 0b1) << 1);
+    (byte >> (quadrant * 2)) & 0b11
+}
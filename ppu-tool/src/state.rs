@@ -1,4 +1,16 @@
 use crate::constants::*;
+use crate::drivers::gamepad_sdl2::GamepadSdl2;
+use crate::game::{Game, LoadOptions};
+use crate::panels::PanelScripts;
+use crate::remote::{self, RemoteRequest};
+use crate::render;
+use cpu_6502::apu::MixerSettings;
+use cpu_6502::controller::{
+    mappings_from_toml, mappings_to_toml, Button, ControllerMappings, InputSource,
+};
+use cpu_6502::ppu::palette_file::{MasterPalette, PaletteFile};
+use cpu_6502::session::Session;
+use cpu_6502::storage::{FileStorage, StorageBackend};
 use macroquad::prelude::*;
 use native_dialog::FileDialog;
 use std::{
@@ -23,6 +35,142 @@ pub struct State {
     pub palette_change: PaletteChange,
     pub palettes_file: UserBinaryFile,
     pub palettes: [[u8; 4]; 4],
+    /// The colors for the palette indexes, which a palette file with RGB colors can
+    /// replace.
+    pub master_palette: MasterPalette,
+
+    pub mirroring: MirroringOverlay,
+    pub is_help_open: bool,
+
+    pub game: Option<Game>,
+    /// The other ROMs that were loaded in this session. They're paused, and keep their
+    /// save RAM and save states until they're switched back to.
+    pub other_games: Vec<Game>,
+    /// The requests from the HTTP API, when it's turned on.
+    pub remote: Option<Receiver<RemoteRequest>>,
+    /// The rhai scripts, and the debug panels that they define.
+    pub scripts: PanelScripts,
+
+    pub controls: Controls,
+    pub mixer: Mixer,
+    pub load_options: LoadOptions,
+    /// Where the controls, the mixer, and the battery saves are kept.
+    pub storage: Box<dyn StorageBackend>,
+    /// The storage key that the session is suspended to on quit, and resumed from on
+    /// launch, when it's turned on.
+    pub session_key: Option<String>,
+}
+
+/// The keys and gamepad buttons for both controllers, which can be rebound in the
+/// controls window.
+pub struct Controls {
+    pub mappings: ControllerMappings,
+    /// The storage key that the mapping is loaded from and saved to.
+    pub key: String,
+    pub gamepad: Option<GamepadSdl2>,
+    /// The player and button that the next key or gamepad press is bound to.
+    pub rebinding: Option<(usize, InputSource, Button)>,
+    pub is_open: bool,
+}
+
+/// The volume, mute, and channel gains. They're the user's rather than the game's, so
+/// each game is handed them when it starts running, and they're saved on quit.
+pub struct Mixer {
+    /// Used when there's no game running, otherwise the game's APU has the settings.
+    pub settings: MixerSettings,
+    /// The storage key that the settings are loaded from and saved to, when it's set.
+    pub key: Option<String>,
+}
+
+/// A key or gamepad button that went down or up during this frame.
+pub struct HostInput {
+    pub source: InputSource,
+    pub name: String,
+    pub is_pressed: bool,
+    /// The gamepads each belong to a player, while keys can be bound by either.
+    pub player: Option<usize>,
+}
+
+impl Controls {
+    fn new(key: String, storage: &dyn StorageBackend) -> Controls {
+        let mappings = match storage.read_string(&key).and_then(|text| match text {
+            Some(text) => mappings_from_toml(&text),
+            None => Ok(ControllerMappings::default()),
+        }) {
+            Ok(mappings) => mappings,
+            Err(err) => {
+                eprintln!("{}", err);
+                ControllerMappings::default()
+            }
+        };
+        Controls {
+            mappings,
+            key,
+            gamepad: match GamepadSdl2::open() {
+                Ok(gamepad) => Some(gamepad),
+                Err(err) => {
+                    eprintln!(
+                        "Failed to open the gamepads, only the keyboard works: {}",
+                        err
+                    );
+                    None
+                }
+            },
+            rebinding: None,
+            is_open: false,
+        }
+    }
+
+    pub fn save(&self, storage: &mut dyn StorageBackend) -> Result<(), String> {
+        storage.write(&self.key, mappings_to_toml(&self.mappings).as_bytes())
+    }
+
+    /// Gather this frame's key and gamepad presses. While a button is being rebound,
+    /// the next press goes to it rather than to the game.
+    fn update(&mut self, shortcuts: &Shortcuts) -> Vec<HostInput> {
+        let mut inputs: Vec<HostInput> = shortcuts
+            .key_changes
+            .iter()
+            .map(|(key, is_pressed)| HostInput {
+                source: InputSource::Keyboard,
+                name: format!("{:?}", key),
+                is_pressed: *is_pressed,
+                player: None,
+            })
+            .collect();
+        if let Some(ref mut gamepad) = self.gamepad {
+            inputs.extend(gamepad.poll().into_iter().map(|(pad, name, is_pressed)| {
+                HostInput {
+                    source: InputSource::Gamepad,
+                    name,
+                    is_pressed,
+                    player: Some(pad),
+                }
+            }));
+        }
+
+        if let Some((player, source, button)) = self.rebinding {
+            let pressed = inputs
+                .iter()
+                .find(|input| input.source == source && input.is_pressed);
+            if let Some(input) = pressed {
+                // Escape cancels, rather than binding the key.
+                if input.name != "Escape" {
+                    self.mappings.bind(player, source, button, &input.name);
+                }
+                self.rebinding = None;
+            }
+            inputs.retain(|input| !input.is_pressed);
+        }
+        inputs
+    }
+}
+
+/// Shows the game's 4 logical nametables, and which physical VRAM backs each of them.
+pub struct MirroringOverlay {
+    pub is_visible: bool,
+    /// The nametables from the live VRAM, updated each frame that it's visible.
+    pub texture: Option<Texture2D>,
 }
 
 impl State {
@@ -30,7 +178,13 @@ impl State {
         nametable: Option<PathBuf>,
         chartable: Option<PathBuf>,
         palette: Option<PathBuf>,
+        rom: Option<PathBuf>,
+        remote: Option<Receiver<RemoteRequest>>,
+        controls_key: String,
+        load_options: LoadOptions,
     ) -> State {
+        // Relative to the working directory, like the paths from the command line.
+        let storage: Box<dyn StorageBackend> = Box::new(FileStorage::new(""));
         let (channel_sender, channel_receiver) = channel();
         let nametable = UserBinaryFile::new(
             BinaryFileId::NameTable,
@@ -72,12 +226,33 @@ impl State {
                 is_open: false,
             },
             palettes_file,
-            palettes: [
-                [0x22, 0x29, 0x1a, 0x0f],
-                [0x22, 0x36, 0x17, 0x0f],
-                [0x22, 0x30, 0x21, 0x0f],
-                [0x22, 0x27, 0x17, 0x0f],
-            ],
+            palettes: DEFAULT_PALETTES,
+            master_palette: NTSC_PALETTE,
+            mirroring: MirroringOverlay {
+                is_visible: false,
+                texture: None,
+            },
+            is_help_open: false,
+            game: rom.and_then(|path| {
+                match Game::load(&path, &load_options, &*storage) {
+                    Ok(game) => Some(game),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        None
+                    }
+                }
+            }),
+            other_games: Vec::new(),
+            remote,
+            scripts: PanelScripts::new(),
+            controls: Controls::new(controls_key, &*storage),
+            mixer: Mixer {
+                settings: MixerSettings::default(),
+                key: None,
+            },
+            load_options,
+            storage,
+            session_key: None,
         };
 
         // Builds the texture if it's available.
@@ -88,8 +263,30 @@ impl State {
         state
     }
 
+    #[cfg_attr(feature = "profile", tracing::instrument(skip_all))]
     pub fn update(&mut self) {
         self.shortcuts.update();
+        self.handle_remote_requests();
+        if self.shortcuts.triggered(Action::ToggleHelp) {
+            self.is_help_open = !self.is_help_open;
+        }
+        let inputs = self.controls.update(&self.shortcuts);
+        if let Some(ref mut game) = self.game {
+            game.update_input(&self.controls.mappings, &inputs);
+            game.is_rewinding = self.shortcuts.is_held(Action::Rewind);
+            if self.shortcuts.triggered(Action::ToggleMute) {
+                game.emulator.cpu.bus.apu.mixer.toggle_mute();
+            }
+            if self.shortcuts.triggered(Action::Screenshot) {
+                match game.save_screenshot() {
+                    Ok(path) => eprintln!("Saved the screenshot {:?}", path),
+                    Err(err) => eprintln!("{}", err),
+                }
+            }
+            game.update();
+            game.update_watches();
+            self.scripts.update(&game.emulator.cpu.bus, &game.labels);
+        }
 
         if let Ok(message) = self.channel_receiver.try_recv() {
             match message {
@@ -103,25 +300,164 @@ impl State {
                     self.palettes_file.load(path);
                     self.build_palettes();
                 }
+                ThreadMessage::NewRom(path) => {
+                    match Game::load(&path, &self.load_options, &*self.storage) {
+                        Ok(game) => self.add_game(game),
+                        Err(err) => eprintln!("{}", err),
+                    }
+                }
+                ThreadMessage::StemsDirectory(path) => {
+                    if let Some(ref mut game) = self.game {
+                        game.start_export_stems(path);
+                    }
+                }
+                ThreadMessage::SaveMovie(path) => {
+                    if let Some(ref game) = self.game {
+                        if let Err(err) = game.save_movie(&path) {
+                            eprintln!("{}", err);
+                        }
+                    }
+                }
+                ThreadMessage::PlayMovie(path) => {
+                    if let Some(ref mut game) = self.game {
+                        if let Err(err) = game.play_movie(&path) {
+                            eprintln!("{}", err);
+                        }
+                    }
+                }
             }
             self.build_view_texture();
             self.build_chartable_texture();
         }
     }
 
+    /// Answer the HTTP API's requests. They're handled after the shortcuts, so the
+    /// actions they trigger are seen this frame.
+    fn handle_remote_requests(&mut self) {
+        let requests: Vec<RemoteRequest> = match self.remote {
+            Some(ref receiver) => receiver.try_iter().collect(),
+            None => return,
+        };
+        for request in requests {
+            let response = remote::handle(self, &request.command);
+            request.respond(response);
+        }
+    }
+
+    /// Keep the save RAM of every game with a battery, for when the tool is closed.
+    pub fn save_battery_ram(&mut self) {
+        for game in self.game.iter().chain(&self.other_games) {
+            if let Err(err) = game.save_battery_ram(&mut *self.storage) {
+                eprintln!("{}", err);
+            }
+        }
+    }
+
+    /// The mixer settings of the running game, or the saved ones when there's none.
+    pub fn mixer_settings(&self) -> &MixerSettings {
+        match self.game {
+            Some(ref game) => &game.emulator.cpu.bus.apu.mixer,
+            None => &self.mixer.settings,
+        }
+    }
+
+    /// Load the volume, mute, and channel gains from the key, and save them back to it
+    /// on quit. A missing key keeps the defaults.
+    pub fn load_mixer(&mut self, key: String) -> Result<(), String> {
+        let settings = match self.storage.read_string(&key)? {
+            Some(text) => MixerSettings::from_config_str(&text)?,
+            None => MixerSettings::default(),
+        };
+        self.mixer.key = Some(key);
+        if let Some(ref mut game) = self.game {
+            game.emulator.cpu.bus.apu.mixer = settings.clone();
+        }
+        self.mixer.settings = settings;
+        Ok(())
+    }
+
+    /// Keep the volume, mute, and channel gains for the next launch.
+    pub fn save_mixer(&mut self) {
+        let key = match self.mixer.key {
+            Some(ref key) => key,
+            None => return,
+        };
+        let text = self.mixer_settings().to_config_string();
+        if let Err(err) = self.storage.write(key, text.as_bytes()) {
+            eprintln!("Failed to save the mixer settings: {}", err);
+        }
+    }
+
+    /// Write the current game to the session, to carry on with it on the next launch.
+    /// The other games aren't kept, though their battery saves are.
+    pub fn suspend_session(&mut self) {
+        let key = match self.session_key {
+            Some(ref key) => key,
+            None => return,
+        };
+        let mut session = Session::default();
+        let result = match self.game {
+            Some(ref mut game) => game.suspend(&mut session),
+            None => Ok(()),
+        };
+        if let Err(err) = result.and_then(|_| session.save(&mut *self.storage, key)) {
+            eprintln!("Failed to suspend the session: {}", err);
+        }
+    }
+
+    /// Suspend the session to the key on quit, and pick up the game that was suspended
+    /// there last time. It runs alongside a ROM from the command line.
+    pub fn resume_session(&mut self, key: String) -> Result<(), String> {
+        let session = Session::load(&*self.storage, &key)?;
+        self.session_key = Some(key);
+        if let Some(session) = session.filter(|session| session.get("rom").is_some()) {
+            let game = Game::resume(&session, &self.load_options, &*self.storage)?;
+            self.add_game(game);
+        }
+        Ok(())
+    }
+
+    /// Run a newly loaded ROM, and keep the current one around to switch back to.
+    pub fn add_game(&mut self, mut game: Game) {
+        game.emulator.cpu.bus.apu.mixer = self.mixer_settings().clone();
+        if let Some(previous) = self.game.replace(game) {
+            previous.clear_audio();
+            self.other_games.push(previous);
+        }
+    }
+
+    /// Switch to one of the other games in the session. The current one takes its
+    /// place in the list.
+    pub fn switch_game(&mut self, index: usize) {
+        if index >= self.other_games.len() {
+            return;
+        }
+        let mut next = self.other_games.remove(index);
+        next.emulator.cpu.bus.apu.mixer = self.mixer_settings().clone();
+        if let Some(previous) = self.game.replace(next) {
+            previous.clear_audio();
+            self.other_games.insert(index, previous);
+        }
+    }
+
     fn build_palettes(&mut self) {
-        if self.palettes_file.data.len() == 0 {
+        if self.palettes_file.data.is_empty() {
             // No palette data yet.
             return;
         }
-        if self.palettes_file.data.len() != 16 {
-            eprintln!(
-                "Invalid palette file. Expected a 16 byte file but a {} byte file was received.",
-                self.palettes_file.data.len()
-            );
-        }
-        for (i, v) in self.palettes_file.data.iter().enumerate() {
-            self.palettes[i / 4][i % 4] = *v;
+        // The nametable view only has a background, so it takes the background
+        // palettes, and the colors without emphasis. The sprite palettes of a palette
+        // RAM dump, and the other emphasis sets, aren't used.
+        match PaletteFile::parse(&self.palettes_file.data) {
+            Ok(file) => {
+                if let Some(palettes) = file.background_palettes() {
+                    self.palettes = palettes;
+                }
+                if let Some(master_palette) = file.master_palette() {
+                    self.master_palette = master_palette;
+                }
+            }
+            Err(err) => eprintln!("{}", err),
         }
     }
 
@@ -154,84 +490,25 @@ impl State {
         if self.chartable.data.is_empty() {
             return;
         }
-
-        const TILES_PER_SIDE: usize = 16;
-        const TILES_COUNT: usize = TILES_PER_SIDE * TILES_PER_SIDE;
-        const TILE_PIXEL_WIDTH: usize = 8;
-        const RGBA_COMPONENTS: usize = 4;
-        const SOURCE_BYTE_LENGTH: usize = TILES_COUNT * 16;
-        const TEXTURE_BYTES: usize = TILES_PER_SIDE
-            * TILES_PER_SIDE
-            * TILE_PIXEL_WIDTH
-            * TILE_PIXEL_WIDTH
-            * RGBA_COMPONENTS;
-
-        // 4096
-        if self.chartable.data.len() != SOURCE_BYTE_LENGTH {
-            eprintln!(
-                "Char data has size {} bytes, expected {} bytes",
-                self.chartable.data.len(),
-                SOURCE_BYTE_LENGTH
-            );
-            return;
-        }
-        let mut texture_data: [u8; TEXTURE_BYTES] = [0; TEXTURE_BYTES];
-
-        for (tile_index, tile_planes) in self.chartable.data.chunks(16).enumerate() {
-            let tile_x = tile_index % TILES_PER_SIDE;
-            let tile_y = tile_index / TILES_PER_SIDE;
-            let x_offset = tile_x * TILE_PIXEL_WIDTH * RGBA_COMPONENTS;
-            let y_offset = tile_y
-                * TILES_PER_SIDE
-                * TILE_PIXEL_WIDTH
-                * TILE_PIXEL_WIDTH
-                * RGBA_COMPONENTS;
-
-            let tile_plane_1 = &tile_planes[0..8];
-            let tile_plane_2 = &tile_planes[8..];
-            for ch_y in 0..8 {
-                for ch_x in 0..8 {
-                    let low_bit = (tile_plane_1[ch_y] >> (7 - ch_x)) & 0b0000_0001;
-                    let high_bit = if ch_x == 7 {
-                        (tile_plane_2[ch_y] << 1) & 0b0000_0010
-                    } else {
-                        (tile_plane_2[ch_y] >> (6 - ch_x)) & 0b0000_0010
-                    };
-                    let value = low_bit + high_bit;
-
-                    let offset = y_offset
-                        + x_offset
-                        + ch_x * RGBA_COMPONENTS
-                        + ch_y * TILES_PER_SIDE * TILE_PIXEL_WIDTH * RGBA_COMPONENTS;
-
-                    let color = match value {
-                        0 => 0,
-                        1 => 85,
-                        2 => 170,
-                        _ => 255,
-                    };
-                    texture_data[offset] = color;
-                    texture_data[offset + 1] = color;
-                    texture_data[offset + 2] = color;
-                    texture_data[offset + 3] = 0xff;
-                }
+        let pixels = match render::render_chartable(&self.chartable.data) {
+            Ok(pixels) => pixels,
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
             }
-        }
+        };
 
         let texture = Texture2D::from_rgba8(
-            (TILES_PER_SIDE * TILE_PIXEL_WIDTH) as u16,
-            (TILES_PER_SIDE * TILE_PIXEL_WIDTH) as u16,
-            &texture_data,
+            pixels.width as u16,
+            pixels.height as u16,
+            &pixels.data,
         );
         texture.set_filter(FilterMode::Nearest);
         self.char_texture = Some(texture);
 
         self.char_egui_image = Some(egui::ColorImage::from_rgba_unmultiplied(
-            [
-                TILES_PER_SIDE * TILE_PIXEL_WIDTH,
-                TILES_PER_SIDE * TILE_PIXEL_WIDTH,
-            ],
-            &texture_data,
+            [pixels.width, pixels.height],
+            &pixels.data,
         ));
         self.char_egui_texture = None;
     }
@@ -240,99 +517,27 @@ impl State {
         if self.nametable.data.is_empty() || self.chartable.data.is_empty() {
             return;
         }
-
-        const TILE_PIXEL_WIDTH: usize = 8;
-        const TILE_PIXEL_AREA: usize = TILE_PIXEL_WIDTH * TILE_PIXEL_WIDTH;
-        const RGBA_COMPONENTS: usize = 4;
-        const BYTES_PER_BIT_PLANE: usize = 8;
-        const BYTES_PER_CH_TILE: usize = BYTES_PER_BIT_PLANE + BYTES_PER_BIT_PLANE; // Two bit planes
-        const TEXTURE_BYTES: usize =
-            NAMETABLE_W * NAMETABLE_H * RGBA_COMPONENTS * TILE_PIXEL_AREA;
-        const TEXTURE_ROW_BYTES: usize = NAMETABLE_W * RGBA_COMPONENTS * TILE_PIXEL_WIDTH;
-
-        let mut texture_data: [u8; TEXTURE_BYTES] = [0; TEXTURE_BYTES];
-        for tile_y in 0..NAMETABLE_H {
-            for tile_x in 0..NAMETABLE_W {
-                let tile_index = tile_y as usize * NAMETABLE_W + tile_x as usize;
-
-                // https://www.nesdev.org/wiki/PPU_pattern_tables
-                let ch_byte_offset =
-                    self.nametable.data[tile_index] as usize * BYTES_PER_CH_TILE;
-                let offsets = (
-                    ch_byte_offset,
-                    ch_byte_offset + BYTES_PER_BIT_PLANE,
-                    ch_byte_offset + BYTES_PER_BIT_PLANE + BYTES_PER_BIT_PLANE,
-                );
-                let ch_plane_1 = &self.chartable.data[offsets.0..offsets.1];
-                let ch_plane_2 = &self.chartable.data[offsets.1..offsets.2];
-
-                let x_offset = RGBA_COMPONENTS * tile_x * TILE_PIXEL_WIDTH;
-                let y_offset = RGBA_COMPONENTS * tile_y * TILE_PIXEL_AREA * NAMETABLE_W;
-
-                let palette = self.lookup_attribute_palette(tile_x, tile_y);
-
-                for ch_y in 0..8 {
-                    for ch_x in 0..8 {
-                        let low_bit = (ch_plane_1[ch_y] >> (7 - ch_x)) & 0b0000_0001;
-                        let high_bit = if ch_x == 7 {
-                            (ch_plane_2[ch_y] << 1) & 0b0000_0010
-                        } else {
-                            (ch_plane_2[ch_y] >> (6 - ch_x)) & 0b0000_0010
-                        };
-
-                        let value = low_bit + high_bit;
-
-                        if value > 3 {
-                            panic!("Logic error in bit shifting.");
-                        }
-
-                        let offset = y_offset
-                            + x_offset
-                            + ch_x * RGBA_COMPONENTS
-                            + ch_y * TEXTURE_ROW_BYTES;
-
-                        let color = NTSC_PALETTE[palette[value as usize] as usize];
-                        texture_data[offset] = color[0];
-                        texture_data[offset + 1] = color[1];
-                        texture_data[offset + 2] = color[2];
-                        texture_data[offset + 3] = 0xff;
-                    }
-                }
+        let pixels = match render::render_nametable(
+            &self.nametable.data,
+            &self.chartable.data,
+            &self.palettes,
+            &self.master_palette,
+        ) {
+            Ok(pixels) => pixels,
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
             }
-        }
+        };
+
         let texture = Texture2D::from_rgba8(
-            (NAMETABLE_W * TILE_PIXEL_WIDTH) as u16,
-            (NAMETABLE_H * TILE_PIXEL_WIDTH) as u16,
-            &texture_data,
+            pixels.width as u16,
+            pixels.height as u16,
+            &pixels.data,
         );
         texture.set_filter(FilterMode::Nearest);
         self.texture = Some(texture);
     }
-
-    fn lookup_attribute_palette(&self, tile_x: usize, tile_y: usize) -> [u8; 4] {
-        // Each byte has 2 tiles, and each attribute covers 2 tiles, so 2 * 2 = 4.
-        let index = ATTRIBUTES_OFFSET + (tile_x >> 2) + (tile_y >> 2) * 8;
-        let byte = self.nametable.data[index];
-
-        // 7654 3210
-        // |||| ||++- Color bits 3-2 for top left quadrant of this byte
-        // |||| ++--- Color bits 3-2 for top right quadrant of this byte
-        // ||++------ Color bits 3-2 for bottom left quadrant of this byte
-        // ++-------- Color bits 3-2 for bottom right quadrant of this byte
-        let br_mask = 0b1100_0000;
-        let bl_mask = 0b0011_0000;
-        let tr_mask = 0b0000_1100;
-        let tl_mask = 0b0000_0011;
-
-        let attribute = match (tile_x >> 1) & 0b1 | (((tile_y >> 1) & 0b1) << 1) {
-            0b00 => byte & tl_mask,        // tl
-            0b01 => (byte & tr_mask) >> 2, // tr
-            0b10 => (byte & bl_mask) >> 4, // bl
-            0b11 => (byte & br_mask) >> 6, // br
-            _ => unreachable!("Logic error"),
-        };
-        self.palettes[attribute as usize]
-    }
 }
 
 #[derive(Clone, Copy)]
@@ -342,26 +547,133 @@ pub struct PaletteChange {
     pub is_open: bool,
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    ToggleHelp,
+    Quit,
+    Rewind,
+    Screenshot,
+    ToggleMute,
+}
+
+impl Action {
+    /// The name of the action in the HTTP API.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::ToggleHelp => "toggle-help",
+            Action::Quit => "quit",
+            Action::Rewind => "rewind",
+            Action::Screenshot => "screenshot",
+            Action::ToggleMute => "toggle-mute",
+        }
+    }
+
+    /// Look up one of the actions in the shortcut registry by its name.
+    pub fn from_name(name: &str) -> Option<Action> {
+        SHORTCUTS
+            .iter()
+            .map(|shortcut| shortcut.action)
+            .find(|action| action.name() == name)
+    }
+}
+
+pub struct Shortcut {
+    pub action: Action,
+    pub key: miniquad::KeyCode,
+    /// Ctrl, or Cmd on macOS.
+    pub command: bool,
+    pub description: &'static str,
+}
+
+impl Shortcut {
+    pub fn label(&self) -> String {
+        let modifier = if self.command { "Ctrl/Cmd + " } else { "" };
+        format!("{}{:?}", modifier, self.key)
+    }
+}
+
+/// Every keyboard shortcut, the help window lists these so it never goes stale.
+pub const SHORTCUTS: &[Shortcut] = &[
+    Shortcut {
+        action: Action::ToggleHelp,
+        key: miniquad::KeyCode::F1,
+        command: false,
+        description: "Show or hide this help",
+    },
+    Shortcut {
+        action: Action::Quit,
+        key: miniquad::KeyCode::Q,
+        command: true,
+        description: "Quit",
+    },
+    Shortcut {
+        action: Action::Rewind,
+        key: miniquad::KeyCode::Backspace,
+        command: false,
+        description: "Hold to rewind the game",
+    },
+    Shortcut {
+        action: Action::Screenshot,
+        key: miniquad::KeyCode::F12,
+        command: false,
+        description: "Save a screenshot of the game",
+    },
+    Shortcut {
+        action: Action::ToggleMute,
+        key: miniquad::KeyCode::M,
+        command: true,
+        description: "Mute or unmute the game",
+    },
+];
+
 // This works around the limitation that the logo key event is not registered on macOS.
 pub struct Shortcuts {
     handler_id: usize,
-    pub quit: bool,
+    actions: Vec<Action>,
+    /// The actions whose keys are still down.
+    held: Vec<Action>,
+    /// Every key that went down or up this frame, for the controller.
+    pub key_changes: Vec<(miniquad::KeyCode, bool)>,
 }
 
 impl Shortcuts {
     pub fn new() -> Self {
         Self {
             handler_id: macroquad::input::utils::register_input_subscriber(),
-            quit: false,
+            actions: Vec::new(),
+            held: Vec::new(),
+            key_changes: Vec::new(),
         }
     }
 
     pub fn update(&mut self) {
-        // Reset the state to the default.
-        self.quit = false;
+        // Only the actions from this frame are kept.
+        self.actions.clear();
+        self.key_changes.clear();
 
         macroquad::input::utils::repeat_all_miniquad_input(self, self.handler_id);
     }
+
+    pub fn triggered(&self, action: Action) -> bool {
+        self.actions.contains(&action)
+    }
+
+    pub fn is_held(&self, action: Action) -> bool {
+        self.held.contains(&action)
+    }
+
+    /// Trigger an action for this frame, as if its shortcut was pressed.
+    pub fn trigger(&mut self, action: Action) {
+        self.actions.push(action);
+    }
+
+    /// Hold or let go of an action, as if its shortcut's key went down or up.
+    pub fn set_held(&mut self, action: Action, is_held: bool) {
+        self.held.retain(|&held| held != action);
+        if is_held {
+            self.held.push(action);
+        }
+    }
 }
 
 impl miniquad::EventHandler for Shortcuts {
@@ -374,12 +686,35 @@ impl miniquad::EventHandler for Shortcuts {
         _ctx: &mut miniquad::Context,
         keycode: miniquad::KeyCode,
         keymods: miniquad::KeyMods,
-        _repeat: bool,
+        repeat: bool,
     ) {
-        if keycode == miniquad::KeyCode::Q && (keymods.ctrl || keymods.logo) {
-            self.quit = true;
+        if repeat {
+            return;
+        }
+        self.key_changes.push((keycode, true));
+        let is_command = keymods.ctrl || keymods.logo;
+        for shortcut in SHORTCUTS {
+            if shortcut.key == keycode && shortcut.command == is_command {
+                self.actions.push(shortcut.action);
+                self.held.push(shortcut.action);
+            }
         }
     }
+
+    fn key_up_event(
+        &mut self,
+        _ctx: &mut miniquad::Context,
+        keycode: miniquad::KeyCode,
+        _keymods: miniquad::KeyMods,
+    ) {
+        self.key_changes.push((keycode, false));
+        // The modifier may have been let go first, so only the key is checked.
+        self.held.retain(|&action| {
+            !SHORTCUTS
+                .iter()
+                .any(|shortcut| shortcut.action == action && shortcut.key == keycode)
+        });
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -391,6 +726,96 @@ pub enum BinaryFileId {
 
 pub enum ThreadMessage {
     NewBinaryFile(BinaryFileId, PathBuf),
+    NewRom(PathBuf),
+    /// The directory to export the audio stems of the current game to.
+    StemsDirectory(PathBuf),
+    /// Where to save the current game's movie, and a movie to play.
+    SaveMovie(PathBuf),
+    PlayMovie(PathBuf),
+}
+
+/// Ask for a directory to export the audio stems to.
+pub fn request_stems_directory(channel_sender: Sender<ThreadMessage>) {
+    when_dialog_ready(move || {
+        match FileDialog::new()
+            .set_location("~/Desktop")
+            .show_open_single_dir()
+        {
+            Ok(Some(path)) => {
+                if let Err(err) = channel_sender.send(ThreadMessage::StemsDirectory(path))
+                {
+                    eprintln!("Problem sending message {:?}", err);
+                };
+            }
+            Err(err) => {
+                eprintln!("Unable to pick the directory. {:?}", err);
+            }
+            _ => {}
+        }
+    });
+}
+
+/// Ask where to save the movie that was recorded.
+pub fn request_movie_save(channel_sender: Sender<ThreadMessage>) {
+    when_dialog_ready(move || {
+        match FileDialog::new()
+            .set_location("~/Desktop")
+            .add_filter("FM2 movie", &["fm2"])
+            .show_save_single_file()
+        {
+            Ok(Some(path)) => {
+                if let Err(err) = channel_sender.send(ThreadMessage::SaveMovie(path)) {
+                    eprintln!("Problem sending message {:?}", err);
+                };
+            }
+            Err(err) => {
+                eprintln!("Unable to pick where to save the movie. {:?}", err);
+            }
+            _ => {}
+        }
+    });
+}
+
+/// Ask for a movie to play on the current game.
+pub fn request_movie(channel_sender: Sender<ThreadMessage>) {
+    when_dialog_ready(move || {
+        match FileDialog::new()
+            .set_location("~/Desktop")
+            .add_filter("FM2 movie", &["fm2"])
+            .show_open_single_file()
+        {
+            Ok(Some(path)) => {
+                if let Err(err) = channel_sender.send(ThreadMessage::PlayMovie(path)) {
+                    eprintln!("Problem sending message {:?}", err);
+                };
+            }
+            Err(err) => {
+                eprintln!("Unable to open the movie. {:?}", err);
+            }
+            _ => {}
+        }
+    });
+}
+
+/// Ask for an iNES ROM to add to the session.
+pub fn request_rom(channel_sender: Sender<ThreadMessage>) {
+    when_dialog_ready(move || {
+        match FileDialog::new()
+            .set_location("~/Desktop")
+            .add_filter("NES ROM", &["nes"])
+            .show_open_single_file()
+        {
+            Ok(Some(path)) => {
+                if let Err(err) = channel_sender.send(ThreadMessage::NewRom(path)) {
+                    eprintln!("Problem sending message {:?}", err);
+                };
+            }
+            Err(err) => {
+                eprintln!("Unable to open the ROM. {:?}", err);
+            }
+            _ => {}
+        }
+    });
 }
 
 pub struct UserBinaryFile {
@@ -0,0 +1,90 @@
+//! Writes the tracing spans to a Chrome trace file, which chrome://tracing or
+//! https://ui.perfetto.dev show as a flamegraph of each frame. The spans come from the
+//! draw loop here, and from the emulator when it's built with the profile feature.
+//!
+//!   cargo run -p ppu-tool --release --features profile -- --rom game.nes \
+//!     --trace-profile out.json
+
+use serde_json::json;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+use tracing::{span, Subscriber};
+use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static THREAD_ID: u64 = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records every span as a begin and end event. The file is a JSON array that's left
+/// open, which the trace viewers accept, so it's still valid when the window is closed
+/// without the tool getting a chance to finish it.
+struct ChromeTraceLayer {
+    start: Instant,
+    file: Mutex<BufWriter<File>>,
+}
+
+impl ChromeTraceLayer {
+    fn write_event(&self, phase: &str, name: &str) {
+        let event = json!({
+            "name": name,
+            "ph": phase,
+            "ts": self.start.elapsed().as_micros() as u64,
+            "pid": 1,
+            "tid": THREAD_ID.with(|id| *id),
+        });
+        let mut file = self.file.lock().expect("The trace file isn't poisoned.");
+        if let Err(err) = writeln!(file, "{},", event) {
+            eprintln!("Failed to write the trace profile: {}", err);
+        }
+    }
+}
+
+impl<S> Layer<S> for ChromeTraceLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            self.write_event("B", span.name());
+        }
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            self.write_event("E", span.name());
+        }
+        // Flush once the outermost span is done, rather than for every event.
+        if ctx.current_span().id().is_none() {
+            let mut file = self.file.lock().expect("The trace file isn't poisoned.");
+            if let Err(err) = file.flush() {
+                eprintln!("Failed to write the trace profile: {}", err);
+            }
+        }
+    }
+}
+
+/// Start recording the spans into the file at `path`.
+pub fn start(path: &Path) -> Result<(), String> {
+    let mut file = BufWriter::new(
+        File::create(path)
+            .map_err(|err| format!("Failed to create the trace {:?}: {}", path, err))?,
+    );
+    writeln!(file, "[")
+        .map_err(|err| format!("Failed to write the trace {:?}: {}", path, err))?;
+    let layer = ChromeTraceLayer {
+        start: Instant::now(),
+        file: Mutex::new(file),
+    };
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))
+        .map_err(|err| format!("Failed to start the trace profile: {}", err))
+}
@@ -1,7 +1,29 @@
-use crate::state::State;
+use crate::game::{
+    Game, MemorySpace, SPRITE_CELL_HEIGHT, SPRITE_CELL_WIDTH, STATE_SLOTS,
+};
+use crate::state::{
+    request_movie, request_movie_save, request_rom, request_stems_directory, State,
+    ThreadMessage, SHORTCUTS,
+};
+use crate::window;
 use crate::{constants::*, state::PaletteChange};
+use cpu_6502::apu::CHANNELS;
+use cpu_6502::controller::{Button, InputSource, PLAYERS};
+use cpu_6502::cpu_6502::Step;
+use cpu_6502::gallery::EXAMPLES;
+use cpu_6502::mappers::Bank;
+use cpu_6502::memory_map;
+use cpu_6502::movie::MovieState;
+use cpu_6502::ppu::registers::PpuStatusFlag;
+use cpu_6502::ppu::render::{
+    NAMETABLES_HEIGHT, NAMETABLES_WIDTH, PATTERN_TABLE_SIZE, SCREEN_HEIGHT, SCREEN_WIDTH,
+    SPRITES_PER_SCANLINE, VBLANK_SCANLINE,
+};
+use cpu_6502::ppu::{DOTS_PER_FRAME, DOTS_PER_SCANLINE};
+use cpu_6502::watch::WatchFormat;
 use egui::epaint::Hsva;
 use std::cell::RefCell;
+use std::sync::mpsc::Sender;
 
 pub fn side_panel(ctx: &egui::Context, state: &RefCell<State>) {
     egui::SidePanel::right("side-panel")
@@ -9,6 +31,13 @@ pub fn side_panel(ctx: &egui::Context, state: &RefCell<State>) {
         .resizable(false)
         .show(ctx, |ui| {
             egui::ScrollArea::vertical().show(ui, |ui| {
+                // The OS title bar only has the name from startup.
+                ui.heading(match state.borrow().game {
+                    Some(ref game) => window::title(Some(&game.title), game.is_paused),
+                    None => window::title(None, false),
+                });
+                ui.separator();
+
                 ui.label("Nametable:");
 
                 let open_button = ui.add(egui::widgets::Button::new(
@@ -90,10 +119,57 @@ pub fn side_panel(ctx: &egui::Context, state: &RefCell<State>) {
                     add_swatch_button(&state, ui, 3, 2);
                     add_swatch_button(&state, ui, 3, 3);
                 });
+
+                ui.separator();
+
+                mirroring_controls(ui, state);
+
+                ui.separator();
+
+                ui.label("ROM");
+                let open_button =
+                    ui.add(egui::widgets::Button::new(match state.borrow().game {
+                        Some(ref game) => game.filename.as_str(),
+                        None => "Choose…",
+                    }));
+                if open_button.clicked() {
+                    request_rom(state.borrow().channel_sender.clone());
+                }
+                examples_menu(ui, state);
+
+                ui.separator();
+
+                if ui.button("Help (F1)").clicked() {
+                    state.borrow_mut().is_help_open = true;
+                }
             });
         });
 }
 
+/// Assembles and runs the examples that are built into the emulator.
+fn examples_menu(ui: &mut egui::Ui, state: &RefCell<State>) {
+    let mut launch = None;
+    ui.menu_button("Examples", |ui| {
+        for example in EXAMPLES.iter() {
+            if ui
+                .button(example.name)
+                .on_hover_text(example.description)
+                .clicked()
+            {
+                launch = Some(example);
+                ui.close_menu();
+            }
+        }
+    });
+    if let Some(example) = launch {
+        let game = Game::load_example(example, &state.borrow().load_options);
+        match game {
+            Ok(game) => state.borrow_mut().add_game(game),
+            Err(err) => eprintln!("Failed to run the example {}: {}", example.name, err),
+        }
+    }
+}
+
 pub fn palette_change_color_window(ctx: &egui::Context, state: &RefCell<State>) {
     let mut is_change_palette_open = state.borrow().palette_change.is_open;
 
@@ -119,8 +195,1870 @@ pub fn palette_change_color_window(ctx: &egui::Context, state: &RefCell<State>)
     }
 }
 
+/// Lists the keyboard shortcuts and build info, with links to the other windows.
+pub fn help_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut is_open = state.borrow().is_help_open;
+
+    egui::Window::new("Help")
+        .open(&mut is_open)
+        .collapsible(false)
+        .auto_sized()
+        .show(ctx, |ui| {
+            ui.heading("PPU Tool");
+            ui.label(format!(
+                "Version {} ({} build)",
+                env!("CARGO_PKG_VERSION"),
+                if cfg!(debug_assertions) {
+                    "debug"
+                } else {
+                    "release"
+                }
+            ));
+
+            ui.separator();
+            ui.label("Keyboard shortcuts");
+            egui::Grid::new("help-shortcuts").show(ui, |ui| {
+                for shortcut in SHORTCUTS {
+                    ui.monospace(shortcut.label());
+                    ui.label(shortcut.description);
+                    ui.end_row();
+                }
+            });
+
+            ui.separator();
+            ui.label("Windows");
+            if ui.button("Change Color").clicked() {
+                state.borrow_mut().palette_change.is_open = true;
+            }
+            if ui.button("Controls").clicked() {
+                state.borrow_mut().controls.is_open = true;
+            }
+            ui.checkbox(
+                &mut state.borrow_mut().mirroring.is_visible,
+                "Mirroring overlay",
+            );
+        });
+
+    if !is_open {
+        state.borrow_mut().is_help_open = false;
+    }
+}
+
+const NEAREST: egui::TextureOptions = egui::TextureOptions {
+    magnification: egui::TextureFilter::Nearest,
+    minification: egui::TextureFilter::Nearest,
+};
+
+/// The game view scales the screen up, so that single pixels are easy to click.
+const GAME_SCALE: f32 = 2.0;
+
+/// Runs the ROM from --rom. With the inspector on, clicking a pixel shows what's
+/// behind it in the pixel inspector.
+pub fn game_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let State {
+        ref mut game,
+        ref other_games,
+        ref channel_sender,
+        ref mut controls,
+        ref mut storage,
+        ..
+    } = *state;
+    let game = match game {
+        Some(ref mut game) => game,
+        None => return,
+    };
+    let mut switch_to = None;
+    let image = game.image();
+    let texture = match game.texture {
+        Some(ref mut texture) => {
+            texture.set(image, NEAREST);
+            texture.clone()
+        }
+        None => game
+            .texture
+            .insert(ctx.load_texture("game", image, NEAREST))
+            .clone(),
+    };
+
+    egui::Window::new(format!("Game - {}", game.title))
+        .id(egui::Id::new("game"))
+        .resizable(false)
+        .show(ctx, |ui| {
+            fallback_banner(ui, game);
+            ui.horizontal(|ui| {
+                let label = if game.is_paused { "Resume" } else { "Pause" };
+                if ui.button(label).clicked() {
+                    game.toggle_pause();
+                }
+                ui.checkbox(&mut game.is_inspecting, "Inspect pixels");
+                ui.checkbox(&mut game.is_chr_banks_open, "CHR banks");
+                ui.checkbox(&mut game.is_memory_map_open, "Memory map");
+                ui.checkbox(&mut game.is_pattern_tables_open, "Pattern tables");
+                ui.checkbox(&mut game.is_nametables_open, "Nametables");
+                ui.checkbox(&mut game.is_sprite_zero_open, "Sprite 0 hit");
+                ui.checkbox(&mut game.is_oam_open, "Sprites");
+                ui.checkbox(&mut game.is_palette_ram_open, "Palette RAM");
+                ui.checkbox(&mut game.is_palette_writes_open, "Palette writes");
+                ui.checkbox(&mut game.is_sprite_counts_open, "Sprites per line");
+                ui.checkbox(&mut game.is_scroll_open, "Scroll");
+                ui.checkbox(&mut game.is_watch_open, "Watch");
+            });
+            if game.is_paused {
+                step_controls(ui, game);
+            }
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_id_source("session-roms")
+                    .selected_text(&game.title)
+                    .show_ui(ui, |ui| {
+                        for (index, other_game) in other_games.iter().enumerate() {
+                            if ui.selectable_label(false, &other_game.title).clicked() {
+                                switch_to = Some(index);
+                            }
+                        }
+                    });
+                if ui.button("Load another ROM…").clicked() {
+                    request_rom(channel_sender.clone());
+                }
+            });
+            if let Some(ref info) = game.rom_info {
+                ui.label(format!(
+                    "{}, {}, CRC-32 {:08x}",
+                    info.region().unwrap_or("unknown region"),
+                    game.filename,
+                    info.crc32
+                ))
+                .on_hover_text("The ROM is a known dump");
+            }
+            ui.label(format!(
+                "PPU alignment: {} dots",
+                game.emulator.cpu.bus.ppu_alignment()
+            ))
+            .on_hover_text(
+                "How far the PPU started ahead of the CPU, set with --ppu-alignment",
+            );
+            ui.horizontal(|ui| {
+                ui.label("Save state:");
+                for slot in 0..STATE_SLOTS {
+                    if ui.button(format!("{}", slot + 1)).clicked() {
+                        game.save_slot(slot);
+                    }
+                }
+                ui.label("Load state:");
+                for slot in 0..STATE_SLOTS {
+                    let button = egui::Button::new(format!("{}", slot + 1));
+                    let is_saved = game.state_slots[slot].is_some();
+                    if ui.add_enabled(is_saved, button).clicked() {
+                        if let Err(err) = game.load_slot(slot) {
+                            eprintln!("Failed to load the state: {}", err);
+                        }
+                    }
+                }
+            });
+            movie_controls(ui, game, channel_sender);
+            ui.horizontal(|ui| {
+                if ui
+                    .button("Screenshot")
+                    .on_hover_text("Saved to the working directory, or press F12")
+                    .clicked()
+                {
+                    match game.save_screenshot() {
+                        Ok(path) => eprintln!("Saved the screenshot {:?}", path),
+                        Err(err) => eprintln!("{}", err),
+                    }
+                }
+                ui.checkbox(&mut game.is_screenshot_aspect_corrected, "8:7 pixels");
+                if ui
+                    .add_enabled(
+                        !game.labels.is_empty(),
+                        egui::Button::new("Export labels"),
+                    )
+                    .on_hover_text("As FCEUX .nl and Mesen .mlb files, next to the ROM")
+                    .clicked()
+                {
+                    match game.export_labels(&mut **storage) {
+                        Ok(keys) => eprintln!("Saved the labels to {}", keys.join(", ")),
+                        Err(err) => eprintln!("{}", err),
+                    }
+                }
+            });
+            if ui.button("Controls…").clicked() {
+                controls.is_open = true;
+            }
+            if game.audio.is_some() {
+                let mixer = &mut game.emulator.cpu.bus.apu.mixer;
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut mixer.is_muted, "Mute");
+                    ui.add_enabled(
+                        !mixer.is_muted,
+                        egui::Slider::new(&mut mixer.master_volume, 0.0..=1.0)
+                            .text("Volume"),
+                    );
+                });
+            }
+            ui.horizontal(|ui| {
+                ui.label("Solo:");
+                let mixer = &mut game.emulator.cpu.bus.apu.mixer;
+                for channel in CHANNELS {
+                    if ui
+                        .selectable_label(mixer.is_soloed(channel), channel.name())
+                        .clicked()
+                    {
+                        mixer.toggle_solo(channel);
+                    }
+                }
+            });
+            ui.collapsing("Channel gain", |ui| {
+                let mixer = &mut game.emulator.cpu.bus.apu.mixer;
+                for channel in CHANNELS {
+                    let mut gain = mixer.gain(channel);
+                    let slider =
+                        egui::Slider::new(&mut gain, 0.0..=1.0).text(channel.name());
+                    if ui.add(slider).changed() {
+                        mixer.set_gain(channel, gain);
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                if ui.button("Export stems…").clicked() {
+                    request_stems_directory(channel_sender.clone());
+                }
+                ui.add(
+                    egui::DragValue::new(&mut game.stem_seconds)
+                        .clamp_range(1..=600)
+                        .suffix(" seconds"),
+                );
+            });
+
+            let size = egui::vec2(
+                SCREEN_WIDTH as f32 * GAME_SCALE,
+                SCREEN_HEIGHT as f32 * GAME_SCALE,
+            );
+            let sense = if game.is_inspecting {
+                egui::Sense::click()
+            } else {
+                egui::Sense::hover()
+            };
+            let response = ui
+                .horizontal(|ui| {
+                    let response = ui.add(egui::Image::new(&texture, size).sense(sense));
+                    if game.is_sprite_counts_open {
+                        sprite_counts_graph(ui, game);
+                    }
+                    response
+                })
+                .inner;
+            if response.clicked() {
+                if let Some(position) = response.interact_pointer_pos() {
+                    let pixel = (position - response.rect.min) / GAME_SCALE;
+                    game.inspected_pixel = Some((
+                        pixel.x.clamp(0.0, SCREEN_WIDTH as f32 - 1.0) as u8,
+                        pixel.y.clamp(0.0, SCREEN_HEIGHT as f32 - 1.0) as u8,
+                    ));
+                }
+            }
+            if let (true, Some((x, y))) = (game.is_inspecting, game.inspected_pixel) {
+                let min = response.rect.min + egui::vec2(x as f32, y as f32) * GAME_SCALE;
+                ui.painter().rect_stroke(
+                    egui::Rect::from_min_size(min, egui::vec2(GAME_SCALE, GAME_SCALE))
+                        .expand(2.0),
+                    0.0,
+                    egui::Stroke::new(1.0, egui::Color32::RED),
+                );
+            }
+            if game.is_sprite_zero_open {
+                sprite_zero_overlay(ui, response.rect, game);
+            }
+            if game.is_palette_writes_open {
+                palette_writes_overlay(ui, response.rect, game);
+            }
+        });
+
+    if let Some(index) = switch_to {
+        state.switch_game(index);
+    }
+}
+
+/// Record the input to an FM2 movie, or play one back.
+fn movie_controls(
+    ui: &mut egui::Ui,
+    game: &mut Game,
+    channel_sender: &Sender<ThreadMessage>,
+) {
+    ui.horizontal(|ui| {
+        ui.label("Movie:");
+        match game.emulator.movie_state() {
+            None => {
+                if ui.button("Record").clicked() {
+                    game.record_movie();
+                }
+                if ui.button("Play…").clicked() {
+                    request_movie(channel_sender.clone());
+                }
+            }
+            Some(movie_state) => {
+                ui.monospace(match movie_state {
+                    MovieState::Recording(movie) => {
+                        format!("Recording frame {}", movie.frames.len())
+                    }
+                    MovieState::Playing(movie, frame) => {
+                        format!("Playing {}/{}", frame, movie.frames.len())
+                    }
+                });
+                if ui.button("Stop").clicked() {
+                    game.stop_movie();
+                }
+            }
+        }
+        if ui
+            .add_enabled(game.movie.is_some(), egui::Button::new("Save…"))
+            .on_hover_text("Save the last recording")
+            .clicked()
+        {
+            request_movie_save(channel_sender.clone());
+        }
+    });
+}
+
+/// The debugger's steps, for when the game is paused.
+/// Warn that the game's mapper isn't supported, and list the registers that it wrote,
+/// which show what the mapper would need to do.
+fn fallback_banner(ui: &mut egui::Ui, game: &Game) {
+    let report = match game.emulator.cpu.bus.fallback_report() {
+        Some(report) => report,
+        None => return,
+    };
+    ui.label(
+        egui::RichText::new(format!(
+            "Mapper {} isn't supported, so the game is running as NROM without any \
+             bank switching. It may only get as far as the title screen.",
+            report.mapper_id
+        ))
+        .color(egui::Color32::YELLOW)
+        .strong(),
+    );
+    ui.collapsing(
+        format!("Mapper register writes ({})", report.writes.len()),
+        |ui| {
+            egui::Grid::new("fallback-writes").show(ui, |ui| {
+                for write in &report.writes {
+                    ui.monospace(format!("${:04x}", write.address));
+                    ui.monospace(format!("{} writes", write.count));
+                    ui.monospace(format!("last ${:02x}", write.last_value));
+                    ui.end_row();
+                }
+            });
+        },
+    );
+}
+
+fn step_controls(ui: &mut egui::Ui, game: &mut Game) {
+    ui.horizontal(|ui| {
+        let ppu = &game.emulator.cpu.bus.ppu;
+        let pc = game.emulator.cpu.pc;
+        let label = match game.labels.get(&pc) {
+            Some(label) => format!(" {}", label),
+            None => String::new(),
+        };
+        ui.monospace(format!(
+            "PC ${:04x}{} at {}:{}",
+            pc,
+            label,
+            ppu.scanline(),
+            ppu.scanline_dot()
+        ))
+        .on_hover_text("The PPU's scanline and dot");
+        for (label, step) in [
+            ("Step", Step::Instructions(1)),
+            ("Step over", Step::Over),
+            ("Step out", Step::Out),
+            ("8 dots", Step::Dots(8)),
+            ("Scanline", Step::Scanlines(1)),
+            ("Frame", Step::Frames(1)),
+        ] {
+            if ui.button(label).clicked() {
+                game.step(step);
+            }
+        }
+        let address = u16::from_str_radix(game.run_to_text.trim_start_matches('$'), 16);
+        if ui
+            .add_enabled(address.is_ok(), egui::Button::new("Run to"))
+            .clicked()
+        {
+            if let Ok(address) = address {
+                game.step(Step::ToAddress(address));
+            }
+        }
+        ui.add(
+            egui::TextEdit::singleline(&mut game.run_to_text)
+                .hint_text("$8000")
+                .desired_width(48.0),
+        );
+    });
+    if let Some(ref err) = game.step_error {
+        ui.colored_label(egui::Color32::LIGHT_RED, err);
+    }
+}
+
+/// Outline sprite 0 where it is now, and mark where it hit in the last frame. The
+/// sprite is drawn a scanline below its OAM y value.
+fn sprite_zero_overlay(ui: &egui::Ui, rect: egui::Rect, game: &Game) {
+    let ppu = &game.emulator.cpu.bus.ppu;
+    let painter = ui.painter().with_clip_rect(rect);
+    let [y, _, _, x] = [0, 1, 2, 3].map(|i| ppu.state.oam[i]);
+    let min = rect.min + egui::vec2(x as f32, y as f32 + 1.0) * GAME_SCALE;
+    let size = egui::vec2(8.0, ppu.state.sprite_height() as f32) * GAME_SCALE;
+    painter.rect_stroke(
+        egui::Rect::from_min_size(min, size),
+        0.0,
+        egui::Stroke::new(1.0, egui::Color32::YELLOW),
+    );
+    if let Some((scanline, dot)) = ppu.sprite_zero_hit() {
+        // Dot 1 draws the pixel at x = 0.
+        let hit = rect.min
+            + egui::vec2(dot as f32 - 1.0 + 0.5, scanline as f32 + 0.5) * GAME_SCALE;
+        let stroke = egui::Stroke::new(1.0, egui::Color32::RED);
+        painter.hline(rect.x_range(), hit.y, stroke);
+        painter.vline(hit.x, rect.y_range(), stroke);
+    }
+}
+
+/// When the sprite 0 hit and sprite overflow flags were set and cleared in the last
+/// frame, on a timeline of the whole frame. Status bar splits poll for the hit, so
+/// this shows how far down the frame the split really happened.
+/// A progress bar for the long operations, which stays up until they finish.
+pub fn progress_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let progress = match state.borrow().game {
+        Some(ref game) => game.export_progress(),
+        None => None,
+    };
+    let progress = match progress {
+        Some(progress) => progress,
+        None => return,
+    };
+    egui::Window::new("Progress")
+        .auto_sized()
+        .collapsible(false)
+        .show(ctx, |ui| {
+            ui.label(&progress.label);
+            ui.add(egui::ProgressBar::new(progress.fraction).show_percentage());
+        });
+}
+
+pub fn sprite_zero_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let game = match state.game {
+        Some(ref mut game) if game.is_sprite_zero_open => game,
+        _ => return,
+    };
+    let mut is_open = true;
+    let ppu = &game.emulator.cpu.bus.ppu;
+    let changes = ppu.status_changes();
+
+    egui::Window::new("Sprite 0 Hit")
+        .open(&mut is_open)
+        .auto_sized()
+        .show(ctx, |ui| {
+            ui.label(match ppu.sprite_zero_hit() {
+                Some((scanline, dot)) => {
+                    format!("Hit on scanline {}, dot {}", scanline, dot)
+                }
+                None => "No hit in the last frame".to_string(),
+            });
+            ui.separator();
+            let width = DOTS_PER_FRAME as f32 / DOTS_PER_SCANLINE as f32 * 2.0;
+            for (flag, name) in [
+                (PpuStatusFlag::Sprite0Hit, "Sprite 0 hit"),
+                (PpuStatusFlag::SpriteOverflow, "Overflow"),
+            ] {
+                ui.horizontal(|ui| {
+                    ui.add_sized([80.0, 16.0], egui::Label::new(name));
+                    let (rect, _) = ui.allocate_exact_size(
+                        egui::vec2(width, 16.0),
+                        egui::Sense::hover(),
+                    );
+                    let painter = ui.painter();
+                    painter.rect_filled(rect, 0.0, egui::Color32::from_gray(40));
+                    let x = |scanline: u64, dot: u64| {
+                        let position = scanline * DOTS_PER_SCANLINE + dot;
+                        rect.left() + position as f32 / DOTS_PER_FRAME as f32 * width
+                    };
+                    // Both flags are clear at the start of the frame.
+                    let mut set_at = None;
+                    for change in changes.iter().filter(|change| change.flag == flag) {
+                        let at = x(change.scanline, change.dot);
+                        match (change.is_set, set_at) {
+                            (true, _) => set_at = Some(at),
+                            (false, Some(start)) => {
+                                let span = egui::Rect::from_x_y_ranges(
+                                    start..=at.max(start + 1.0),
+                                    rect.y_range(),
+                                );
+                                painter.rect_filled(span, 0.0, egui::Color32::GREEN);
+                                set_at = None;
+                            }
+                            (false, None) => {}
+                        }
+                    }
+                    if let Some(start) = set_at {
+                        let span = egui::Rect::from_x_y_ranges(
+                            start..=rect.right(),
+                            rect.y_range(),
+                        );
+                        painter.rect_filled(span, 0.0, egui::Color32::GREEN);
+                    }
+                    // Mark where vblank starts.
+                    let vblank = x(VBLANK_SCANLINE, 1);
+                    painter.vline(
+                        vblank,
+                        rect.y_range(),
+                        egui::Stroke::new(1.0, egui::Color32::WHITE),
+                    );
+                });
+            }
+            ui.label("The white line is the start of vblank.");
+            ui.separator();
+            egui::Grid::new("sprite-zero-changes")
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.strong("Scanline");
+                    ui.strong("Dot");
+                    ui.strong("Change");
+                    ui.end_row();
+                    for change in changes {
+                        ui.monospace(change.scanline.to_string());
+                        ui.monospace(change.dot.to_string());
+                        ui.label(format!(
+                            "{:?} {}",
+                            change.flag,
+                            if change.is_set { "set" } else { "cleared" }
+                        ));
+                        ui.end_row();
+                    }
+                });
+        });
+
+    if !is_open {
+        game.is_sprite_zero_open = false;
+    }
+}
+
+/// Circle where each mid-frame palette write landed in the last frame. The writes in
+/// hblank are past the right edge of the screen, so they're marked at the edge.
+fn palette_writes_overlay(ui: &egui::Ui, rect: egui::Rect, game: &Game) {
+    let painter = ui.painter().with_clip_rect(rect);
+    for write in game.emulator.cpu.bus.ppu.palette_writes() {
+        let x = (write.dot as f32 - 1.0).clamp(0.0, SCREEN_WIDTH as f32 - 1.0);
+        let center =
+            rect.min + egui::vec2(x + 0.5, write.scanline as f32 + 0.5) * GAME_SCALE;
+        painter.circle_stroke(
+            center,
+            GAME_SCALE * 2.0,
+            egui::Stroke::new(1.0, egui::Color32::from_rgb(255, 0, 255)),
+        );
+    }
+}
+
+/// The width of each sprite's part of a bar in the sprites per line graph.
+const SPRITE_COUNT_WIDTH: f32 = 4.0;
+/// The graph is this many sprites wide, and longer bars are cut off.
+const SPRITE_COUNT_MAX: u8 = 16;
+
+/// The number of sprites on each scanline of the last frame, as bars lined up with the
+/// scanlines of the game view. The part of a bar past the 8 sprite limit is red, as
+/// those are the scanlines where sprites drop out or flicker. Clicking a scanline
+/// shows its sprites in the sprites window.
+fn sprite_counts_graph(ui: &mut egui::Ui, game: &mut Game) {
+    let counts = game.emulator.cpu.bus.ppu.sprite_counts();
+    let (rect, response) = ui.allocate_exact_size(
+        egui::vec2(
+            SPRITE_COUNT_MAX as f32 * SPRITE_COUNT_WIDTH,
+            SCREEN_HEIGHT as f32 * GAME_SCALE,
+        ),
+        egui::Sense::click(),
+    );
+    let painter = ui.painter().with_clip_rect(rect);
+    painter.rect_filled(rect, 0.0, egui::Color32::from_gray(40));
+    let limit = SPRITES_PER_SCANLINE as f32 * SPRITE_COUNT_WIDTH;
+    for (scanline, &count) in counts.iter().enumerate() {
+        let top = rect.top() + scanline as f32 * GAME_SCALE;
+        let bar = |from: f32, to: f32| {
+            egui::Rect::from_min_max(
+                egui::pos2(rect.left() + from, top),
+                egui::pos2(rect.left() + to, top + GAME_SCALE),
+            )
+        };
+        let width = count as f32 * SPRITE_COUNT_WIDTH;
+        painter.rect_filled(bar(0.0, width.min(limit)), 0.0, egui::Color32::LIGHT_BLUE);
+        if width > limit {
+            painter.rect_filled(bar(limit, width), 0.0, egui::Color32::RED);
+        }
+    }
+    painter.vline(
+        rect.left() + limit,
+        rect.y_range(),
+        egui::Stroke::new(1.0, egui::Color32::GRAY),
+    );
+
+    let hovered = response
+        .hover_pos()
+        .map(|position| ((position.y - rect.top()) / GAME_SCALE) as usize)
+        .filter(|scanline| *scanline < counts.len());
+    let text = match hovered {
+        Some(scanline) => format!("Scanline {}: {} sprites", scanline, counts[scanline]),
+        None => "Waiting for a complete frame".to_string(),
+    };
+    if response.on_hover_text_at_pointer(text).clicked() {
+        if let Some(scanline) = hovered {
+            game.oam_scanline = scanline as u8;
+            game.is_oam_open = true;
+        }
+    }
+}
+
+/// The writes to the palette RAM while the picture was being drawn in the last frame.
+/// A palette swap that runs late out of vblank changes the colors partway down the
+/// screen, which shows up as a flash line, and this points at the code that did it.
+pub fn palette_writes_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let game = match state.game {
+        Some(ref mut game) if game.is_palette_writes_open => game,
+        _ => return,
+    };
+    let mut is_open = true;
+    let ppu = &mut game.emulator.cpu.bus.ppu;
+
+    egui::Window::new("Palette Writes")
+        .open(&mut is_open)
+        .auto_sized()
+        .show(ctx, |ui| {
+            let mut artifacts = ppu.palette_write_artifacts();
+            if ui
+                .checkbox(&mut artifacts, "Draw the writes on the screen")
+                .on_hover_text(
+                    "Show each write as a pixel of the written color, like the streak \
+                     the backdrop makes while a palette is uploaded mid-frame.",
+                )
+                .changed()
+            {
+                ppu.set_palette_write_artifacts(artifacts);
+            }
+            let writes = ppu.palette_writes();
+            ui.label(match writes.len() {
+                0 => "No palette writes during rendering in the last frame".to_string(),
+                count => format!("{} palette writes during rendering", count),
+            });
+            ui.separator();
+
+            // A timeline of the visible scanlines, with a tick for each write.
+            let width = SCREEN_HEIGHT as f32 * 2.0;
+            let (rect, _) =
+                ui.allocate_exact_size(egui::vec2(width, 16.0), egui::Sense::hover());
+            let painter = ui.painter();
+            painter.rect_filled(rect, 0.0, egui::Color32::from_gray(40));
+            for write in writes {
+                let position =
+                    write.scanline as f32 + write.dot as f32 / DOTS_PER_SCANLINE as f32;
+                let x = rect.left() + position / SCREEN_HEIGHT as f32 * width;
+                let color = if write.is_rendering_enabled {
+                    egui::Color32::RED
+                } else {
+                    egui::Color32::from_rgb(255, 0, 255)
+                };
+                painter.vline(x, rect.y_range(), egui::Stroke::new(1.0, color));
+            }
+            ui.label("Red writes were made with rendering on.");
+            ui.separator();
+
+            egui::ScrollArea::vertical()
+                .max_height(300.0)
+                .show(ui, |ui| {
+                    egui::Grid::new("palette-writes")
+                        .striped(true)
+                        .show(ui, |ui| {
+                            ui.strong("Scanline");
+                            ui.strong("Dot");
+                            ui.strong("Address");
+                            ui.strong("Value");
+                            ui.strong("Rendering");
+                            ui.end_row();
+                            for write in writes {
+                                ui.monospace(write.scanline.to_string());
+                                ui.monospace(write.dot.to_string());
+                                ui.monospace(format!("${:04x}", write.address));
+                                ui.horizontal(|ui| {
+                                    let [r, g, b] =
+                                        NTSC_PALETTE[(write.value & 0x3f) as usize];
+                                    let (swatch, _) = ui.allocate_exact_size(
+                                        egui::vec2(12.0, 12.0),
+                                        egui::Sense::hover(),
+                                    );
+                                    ui.painter().rect_filled(
+                                        swatch,
+                                        0.0,
+                                        egui::Color32::from_rgb(r, g, b),
+                                    );
+                                    ui.monospace(format!("${:02x}", write.value));
+                                });
+                                ui.label(if write.is_rendering_enabled {
+                                    "on"
+                                } else {
+                                    "off"
+                                });
+                                ui.end_row();
+                            }
+                        });
+                });
+        });
+
+    if !is_open {
+        game.is_palette_writes_open = false;
+    }
+}
+
+/// The scroll position of every scanline in the last frame, graphed down the height of
+/// the screen. The X and Y are plotted separately, so a split shows up as a jump at
+/// the scanline where the game changed the scroll. Clicking a scanline picks it, and
+/// opens the sprites window on it.
+pub fn scroll_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let game = match state.game {
+        Some(ref mut game) if game.is_scroll_open => game,
+        _ => return,
+    };
+    let mut is_open = true;
+    let lines = game.emulator.cpu.bus.ppu.scroll_lines().to_vec();
+    let mut picked = None;
+
+    egui::Window::new("Scroll")
+        .open(&mut is_open)
+        .auto_sized()
+        .show(ctx, |ui| {
+            if lines.is_empty() {
+                ui.label("Waiting for a complete frame.");
+                return;
+            }
+            ui.horizontal(|ui| {
+                for (name, size, value) in
+                    [("X", NAMETABLES_WIDTH, 0), ("Y", NAMETABLES_HEIGHT, 1)]
+                {
+                    ui.vertical(|ui| {
+                        ui.label(format!("{} (0-{})", name, size - 1));
+                        let scale = GAME_SCALE / 2.0;
+                        let (rect, response) = ui.allocate_exact_size(
+                            egui::vec2(
+                                size as f32 * scale,
+                                SCREEN_HEIGHT as f32 * GAME_SCALE,
+                            ),
+                            egui::Sense::click(),
+                        );
+                        let painter = ui.painter().with_clip_rect(rect);
+                        painter.rect_filled(rect, 0.0, egui::Color32::from_gray(40));
+                        let point = |scanline: usize| {
+                            let position = [lines[scanline].0, lines[scanline].1][value];
+                            rect.min
+                                + egui::vec2(
+                                    position as f32 * scale,
+                                    (scanline as f32 + 0.5) * GAME_SCALE,
+                                )
+                        };
+                        let stroke = egui::Stroke::new(1.0, egui::Color32::LIGHT_BLUE);
+                        for scanline in 1..lines.len() {
+                            painter.line_segment(
+                                [point(scanline - 1), point(scanline)],
+                                stroke,
+                            );
+                        }
+                        if let Some(scanline) = game.scroll_scanline {
+                            let y = rect.top() + (scanline as f32 + 0.5) * GAME_SCALE;
+                            painter.hline(
+                                rect.x_range(),
+                                y,
+                                egui::Stroke::new(1.0, egui::Color32::YELLOW),
+                            );
+                        }
+                        if let Some(position) = response.interact_pointer_pos() {
+                            if response.clicked() {
+                                let scanline = (position.y - rect.top()) / GAME_SCALE;
+                                picked =
+                                    Some(scanline.clamp(0.0, lines.len() as f32 - 1.0)
+                                        as u8);
+                            }
+                        }
+                    });
+                }
+            });
+            ui.label("Click a scanline to show its sprites.");
+            if let Some(scanline) = game.scroll_scanline {
+                let (x, y) = lines[scanline as usize];
+                ui.monospace(format!("Scanline {}: X {}, Y {}", scanline, x, y));
+            }
+
+            ui.separator();
+            egui::Grid::new("scroll-splits")
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.strong("Scanline");
+                    ui.strong("X");
+                    ui.strong("Y");
+                    ui.end_row();
+                    for (scanline, &(x, y)) in lines.iter().enumerate() {
+                        if scanline > 0 && lines[scanline - 1] == (x, y) {
+                            continue;
+                        }
+                        if ui.link(scanline.to_string()).clicked() {
+                            picked = Some(scanline as u8);
+                        }
+                        ui.monospace(x.to_string());
+                        ui.monospace(y.to_string());
+                        ui.end_row();
+                    }
+                });
+        });
+
+    if let Some(scanline) = picked {
+        game.scroll_scanline = Some(scanline);
+        game.oam_scanline = scanline;
+        game.is_oam_open = true;
+    }
+    if !is_open {
+        game.is_scroll_open = false;
+    }
+}
+
+const BUTTONS: [Button; 8] = [
+    Button::A,
+    Button::B,
+    Button::Select,
+    Button::Start,
+    Button::Up,
+    Button::Down,
+    Button::Left,
+    Button::Right,
+];
+
+/// Rebinds both controllers. Clicking an input waits for the next key or gamepad
+/// press, and Escape cancels.
+pub fn controls_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let State {
+        controls, storage, ..
+    } = &mut *state;
+    let mut is_open = controls.is_open;
+
+    egui::Window::new("Controls")
+        .open(&mut is_open)
+        .collapsible(false)
+        .auto_sized()
+        .show(ctx, |ui| {
+            egui::Grid::new("controls-mapping").show(ui, |ui| {
+                ui.strong("Button");
+                for player in 1..=PLAYERS {
+                    ui.strong(format!("P{} Keyboard", player));
+                    ui.strong(format!("P{} Gamepad", player));
+                }
+                ui.end_row();
+                for button in BUTTONS {
+                    ui.label(format!("{:?}", button));
+                    for player in 0..PLAYERS {
+                        for source in [InputSource::Keyboard, InputSource::Gamepad] {
+                            let binding = (player, source, button);
+                            let bound =
+                                controls.mappings.players[player].bound(source, button);
+                            let text = if controls.rebinding == Some(binding) {
+                                "Press…".to_string()
+                            } else if bound.is_empty() {
+                                "None".to_string()
+                            } else {
+                                bound.join(", ")
+                            };
+                            if ui.button(text).clicked() {
+                                controls.rebinding = Some(binding);
+                            }
+                        }
+                    }
+                    ui.end_row();
+                }
+            });
+            if controls.gamepad.is_none() {
+                ui.label("Gamepads aren't available.");
+            } else {
+                ui.label("Player 2 uses the second gamepad that was plugged in.");
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Save").clicked() {
+                    if let Err(err) = controls.save(&mut **storage) {
+                        eprintln!("{}", err);
+                    }
+                }
+                if ui.button("Reset to defaults").clicked() {
+                    controls.mappings = Default::default();
+                }
+                ui.label(&controls.key);
+            });
+        });
+
+    controls.is_open = is_open;
+    if !is_open {
+        controls.rebinding = None;
+    }
+}
+
+/// Shows the nametable entry, attribute, and pattern behind the inspected pixel. It's
+/// looked up again every frame, so it follows the game as it runs.
+pub fn pixel_inspector_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let game = match state.game {
+        Some(ref mut game) if game.is_inspecting => game,
+        _ => return,
+    };
+    let (x, y) = match game.inspected_pixel {
+        Some(pixel) => pixel,
+        None => return,
+    };
+    let inspection = game.emulator.cpu.bus.inspect_pixel(x, y);
+    let mut memory_address = None;
+
+    egui::Window::new("Pixel Inspector")
+        .auto_sized()
+        .show(ctx, |ui| {
+            ui.label(format!("Pixel ({}, {})", inspection.x, inspection.y));
+            ui.label("Uses the current scroll, and only the background.");
+            ui.separator();
+            egui::Grid::new("pixel-inspector").show(ui, |ui| {
+                let mut address_row = |ui: &mut egui::Ui, label: &str, address: u16| {
+                    ui.label(label);
+                    if ui.link(format!("${:04X}", address)).clicked() {
+                        memory_address = Some((MemorySpace::Ppu, address));
+                    }
+                };
+                address_row(ui, "Nametable", inspection.nametable_address);
+                ui.monospace(format!("tile ${:02X}", inspection.tile_index));
+                ui.end_row();
+                address_row(ui, "Attribute", inspection.attribute_address);
+                ui.monospace(format!(
+                    "${:02X}, palette {}",
+                    inspection.attribute, inspection.palette
+                ));
+                ui.end_row();
+                address_row(ui, "Pattern", inspection.pattern_address);
+                ui.monospace(match inspection.chr_offset {
+                    Some(offset) => format!("CHR offset ${:05X}", offset),
+                    None => "CHR offset unknown".to_string(),
+                });
+                ui.end_row();
+                ui.label("Color");
+                ui.horizontal(|ui| {
+                    // The game is drawn with the NTSC palette.
+                    color_button(ui, NTSC_PALETTE[inspection.color as usize & 0x3f]);
+                    ui.monospace(format!(
+                        "${:02X}, pixel value {}",
+                        inspection.color, inspection.pixel_value
+                    ));
+                });
+                ui.end_row();
+            });
+            ui.separator();
+            ui.label("Pattern bytes");
+            for plane in inspection.pattern_bytes.chunks(8) {
+                ui.monospace(
+                    plane
+                        .iter()
+                        .map(|byte| format!("{:02X}", byte))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                );
+            }
+        });
+
+    if let Some((space, address)) = memory_address {
+        game.show_memory(space, address);
+    }
+}
+
+const MEMORY_ROWS: u16 = 16;
+
+/// The hex views of the CPU and PPU address spaces, which are opened from the
+/// inspector, the memory map, and each other. They read through `peek_u8` and `peek_ppu` so that the
+/// registers aren't disturbed. Clicking a byte edits it, and Enter writes it through
+/// the bus like the game would.
+pub fn memory_windows(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let game = match state.game {
+        Some(ref mut game) => game,
+        None => return,
+    };
+    let mut new_window = None;
+    let Game {
+        ref mut memory_windows,
+        ref mut emulator,
+        ref labels,
+        ..
+    } = *game;
+
+    memory_windows.retain_mut(|window| {
+        let mut is_open = true;
+        let mut write = None;
+        let space = window.space;
+        let last_address = space.last_address();
+        let bus = &emulator.cpu.bus;
+        let read = |address| match space {
+            MemorySpace::Cpu => bus.peek_u8(address),
+            MemorySpace::Ppu => bus.peek_ppu(address),
+        };
+
+        egui::Window::new(format!(
+            "{} Memory ${:04X}-${:04X}",
+            space.name(),
+            window.start,
+            window.end
+        ))
+        .id(egui::Id::new(("memory", window.id)))
+        .open(&mut is_open)
+        .auto_sized()
+        .show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                let end = window.end;
+                ui.add(
+                    egui::DragValue::new(&mut window.start)
+                        .hexadecimal(4, false, true)
+                        .prefix("$")
+                        .clamp_range(0..=end),
+                );
+                ui.label("to");
+                let start = window.start;
+                ui.add(
+                    egui::DragValue::new(&mut window.end)
+                        .hexadecimal(4, false, true)
+                        .prefix("$")
+                        .clamp_range(start..=last_address),
+                );
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut window.jump_text)
+                        .desired_width(48.0)
+                        .hint_text("Jump"),
+                );
+                if response.lost_focus() && ui.input().key_pressed(egui::Key::Enter) {
+                    let text = window.jump_text.trim().trim_start_matches('$');
+                    match u16::from_str_radix(text, 16) {
+                        Ok(address) if address <= last_address => window.jump(address),
+                        _ => {}
+                    }
+                }
+                if ui.button("New window").clicked() {
+                    new_window = Some((space, window.address));
+                }
+            });
+
+            let first_row = window.start / 16;
+            let rows = (window.end / 16 - first_row) as usize + 1;
+            let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+            let mut scroll_area = egui::ScrollArea::vertical().max_height(
+                MEMORY_ROWS as f32 * (row_height + ui.spacing().item_spacing.y),
+            );
+            if window.is_scroll_pending {
+                window.is_scroll_pending = false;
+                // Show a couple of rows before the address.
+                let row = (window.address / 16).saturating_sub(first_row + 2);
+                scroll_area = scroll_area.vertical_scroll_offset(
+                    row as f32 * (row_height + ui.spacing().item_spacing.y),
+                );
+            }
+            scroll_area.show_rows(ui, row_height, rows, |ui, rows| {
+                for row in rows {
+                    let row_address = (first_row + row as u16) * 16;
+                    ui.horizontal(|ui| {
+                        ui.monospace(format!("${:04X}", row_address));
+                        let mut ascii = String::new();
+                        for column in 0..16 {
+                            let address = row_address + column;
+                            if !window.contains(address) {
+                                ui.monospace("  ");
+                                ascii.push(' ');
+                                continue;
+                            }
+                            let value = read(address);
+                            ascii.push(match value {
+                                0x20..=0x7e => value as char,
+                                _ => '.',
+                            });
+                            if let Some((edit_address, ref mut text)) = window.edit {
+                                if edit_address == address {
+                                    let response = ui.add(
+                                        egui::TextEdit::singleline(text)
+                                            .font(egui::TextStyle::Monospace)
+                                            .desired_width(row_height),
+                                    );
+                                    if response.lost_focus() {
+                                        if ui.input().key_pressed(egui::Key::Enter) {
+                                            if let Ok(value) =
+                                                u8::from_str_radix(text, 16)
+                                            {
+                                                write = Some((address, value));
+                                            }
+                                        }
+                                        window.edit = None;
+                                    } else if !response.has_focus() {
+                                        response.request_focus();
+                                    }
+                                    continue;
+                                }
+                            }
+                            let mut text =
+                                egui::RichText::new(format!("{:02X}", value)).monospace();
+                            if address == window.address {
+                                text = text.color(egui::Color32::RED);
+                            }
+                            let label =
+                                egui::Label::new(text).sense(egui::Sense::click());
+                            let mut response = ui.add(label);
+                            if let (MemorySpace::Cpu, Some(name)) =
+                                (space, labels.get(&address))
+                            {
+                                response = response.on_hover_text(name);
+                            }
+                            if response.clicked() {
+                                window.address = address;
+                                window.edit = Some((address, format!("{:02X}", value)));
+                            }
+                        }
+                        ui.monospace(ascii);
+                    });
+                }
+            });
+        });
+
+        if let Some((address, value)) = write {
+            match space {
+                MemorySpace::Cpu => emulator.cpu.bus.set_u8(address, value),
+                MemorySpace::Ppu => emulator.cpu.bus.poke_ppu(address, value),
+            }
+        }
+        is_open
+    });
+
+    if let Some((space, address)) = new_window {
+        game.open_memory_window(space, address);
+    }
+}
+
+fn chr_bank_rows(ui: &mut egui::Ui, banks: &[Bank], color: egui::Color32) {
+    for bank in banks {
+        ui.monospace(format!(
+            "${:04X}-${:04X}",
+            bank.address,
+            bank.address as usize + bank.size - 1
+        ));
+        ui.label(
+            egui::RichText::new(format!("{}KB bank {}", bank.size / 1024, bank.bank))
+                .monospace()
+                .color(color),
+        );
+        ui.end_row();
+    }
+}
+
+/// The CHR banks that the last frame was drawn with. The banks that were switched in
+/// while the frame was drawn are listed after it, with the scanline they took effect.
+pub fn chr_banks_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let game = match state.game {
+        Some(ref mut game) if game.is_chr_banks_open => game,
+        _ => return,
+    };
+    let mut is_open = true;
+    let frame = game.emulator.cpu.bus.chr_bank_frame();
+
+    egui::Window::new("CHR Banks")
+        .open(&mut is_open)
+        .auto_sized()
+        .show(ctx, |ui| {
+            if frame.banks.is_empty() {
+                ui.label("This cartridge doesn't switch CHR banks.");
+                return;
+            }
+            egui::Grid::new("chr-banks").show(ui, |ui| {
+                chr_bank_rows(ui, &frame.banks, ui.visuals().text_color());
+            });
+            if !frame.is_switched_mid_frame() {
+                return;
+            }
+            ui.separator();
+            ui.colored_label(egui::Color32::YELLOW, "Switched mid-frame");
+            for (scanline, banks) in &frame.switches {
+                ui.label(format!("From scanline {}", scanline));
+                egui::Grid::new(("chr-banks", *scanline)).show(ui, |ui| {
+                    chr_bank_rows(ui, banks, egui::Color32::YELLOW);
+                });
+            }
+        });
+
+    if !is_open {
+        game.is_chr_banks_open = false;
+    }
+}
+
+/// The pattern tables at $0000 and $1000, through the CHR banks that the last frame was
+/// drawn with. They're drawn again every frame, so CHR RAM writes and bank switches
+/// show up as the game runs. When the banks were switched mid-frame, each set of banks
+/// can be picked by the scanline it started on.
+pub fn pattern_tables_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let game = match state.game {
+        Some(ref mut game) if game.is_pattern_tables_open => game,
+        _ => return,
+    };
+    let mut is_open = true;
+    let image = game.pattern_tables_image();
+    let texture = match game.pattern_table_texture {
+        Some(ref mut texture) => {
+            texture.set(image, NEAREST);
+            texture.clone()
+        }
+        None => game
+            .pattern_table_texture
+            .insert(ctx.load_texture("pattern-tables", image, NEAREST))
+            .clone(),
+    };
+
+    egui::Window::new("Pattern Tables")
+        .open(&mut is_open)
+        .auto_sized()
+        .show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                for palette in 0..8 {
+                    let label = match palette {
+                        0..=3 => format!("BG {}", palette),
+                        _ => format!("Sprite {}", palette - 4),
+                    };
+                    ui.selectable_value(&mut game.pattern_table_palette, palette, label);
+                }
+            });
+            let frame = game.emulator.cpu.bus.chr_bank_frame();
+            if frame.is_switched_mid_frame() {
+                ui.horizontal(|ui| {
+                    ui.colored_label(egui::Color32::YELLOW, "Switched mid-frame");
+                    let scanlines = std::iter::once(0)
+                        .chain(frame.switches.iter().map(|(scanline, _)| *scanline));
+                    for scanline in scanlines {
+                        ui.selectable_value(
+                            &mut game.pattern_table_scanline,
+                            scanline,
+                            format!("Scanline {}", scanline),
+                        );
+                    }
+                });
+            }
+            ui.horizontal(|ui| {
+                for color in game.palette_colors(game.pattern_table_palette) {
+                    color_button(ui, NTSC_PALETTE[color as usize & 0x3f]);
+                }
+            });
+            ui.image(
+                &texture,
+                [
+                    PATTERN_TABLE_SIZE as f32 * 2.0 * GAME_SCALE,
+                    PATTERN_TABLE_SIZE as f32 * GAME_SCALE,
+                ],
+            );
+            ui.label("$0000 is on the left, and $1000 is on the right.");
+        });
+
+    if !is_open {
+        game.is_pattern_tables_open = false;
+    }
+}
+
+/// The 32 bytes of palette RAM, a row for each palette. While the game is paused, a
+/// swatch can be clicked and given any of the 64 colors, which is written through the
+/// bus like the game would.
+pub fn palette_ram_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let game = match state.game {
+        Some(ref mut game) if game.is_palette_ram_open => game,
+        _ => return,
+    };
+    let mut is_open = true;
+    if !game.is_paused {
+        game.palette_ram_address = None;
+    }
+
+    egui::Window::new("Palette RAM")
+        .open(&mut is_open)
+        .auto_sized()
+        .show(ctx, |ui| {
+            egui::Grid::new("palette-ram").show(ui, |ui| {
+                for palette in 0..8 {
+                    let base = 0x3f00 + palette * 4;
+                    ui.monospace(format!("${:04X}", base));
+                    ui.label(match palette {
+                        0..=3 => format!("BG {}", palette),
+                        _ => format!("Sprite {}", palette - 4),
+                    });
+                    for address in base..base + 4 {
+                        let color = game.emulator.cpu.bus.peek_ppu(address);
+                        let response = ui
+                            .add_enabled_ui(game.is_paused, |ui| {
+                                color_button(ui, NTSC_PALETTE[color as usize])
+                            })
+                            .inner
+                            .on_hover_text(format!("${:04X}: ${:02X}", address, color));
+                        if response.clicked() {
+                            game.palette_ram_address = Some(address);
+                        }
+                        if game.palette_ram_address == Some(address) {
+                            ui.painter().rect_stroke(
+                                response.rect.expand(2.0),
+                                0.0,
+                                egui::Stroke::new(1.0, egui::Color32::RED),
+                            );
+                        }
+                    }
+                    ui.end_row();
+                }
+            });
+            ui.label(
+                "$3F10, $3F14, $3F18, and $3F1C mirror the first color of the BG \
+                 palettes.",
+            );
+
+            let address = match game.palette_ram_address {
+                Some(address) => address,
+                None => {
+                    if game.is_paused {
+                        ui.label("Click a swatch to change it.");
+                    } else {
+                        ui.label("Pause the game to change the colors.");
+                    }
+                    return;
+                }
+            };
+            ui.separator();
+            ui.label(format!(
+                "${:04X}, the game sees the new color when it resumes.",
+                address
+            ));
+            for row in 0..4 {
+                ui.horizontal(|ui| {
+                    for column in 0..16 {
+                        let color = row * 16 + column;
+                        let response = color_button(ui, NTSC_PALETTE[color as usize])
+                            .on_hover_text(format!("${:02X}", color));
+                        if response.clicked() {
+                            game.emulator.cpu.bus.poke_ppu(address, color);
+                            game.palette_ram_address = None;
+                        }
+                    }
+                });
+            }
+        });
+
+    if !is_open {
+        game.is_palette_ram_open = false;
+        game.palette_ram_address = None;
+    }
+}
+
+/// The 4 logical nametables, read from the PPU every frame, with the part that's on
+/// the screen outlined. The outline wraps around the edges like the scroll does.
+pub fn nametables_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let game = match state.game {
+        Some(ref mut game) if game.is_nametables_open => game,
+        _ => return,
+    };
+    let mut is_open = true;
+    let image = game.nametables_image();
+    let texture = match game.nametable_texture {
+        Some(ref mut texture) => {
+            texture.set(image, NEAREST);
+            texture.clone()
+        }
+        None => game
+            .nametable_texture
+            .insert(ctx.load_texture("nametables", image, NEAREST))
+            .clone(),
+    };
+    let bus = &game.emulator.cpu.bus;
+    let (scroll_x, scroll_y) = bus.scroll_position();
+    let mirroring = bus.mirroring();
+
+    egui::Window::new("Nametables")
+        .open(&mut is_open)
+        .auto_sized()
+        .show(ctx, |ui| {
+            ui.label(format!(
+                "Scroll ({}, {}), {:?} mirroring",
+                scroll_x, scroll_y, mirroring
+            ));
+            let response = ui.image(
+                &texture,
+                [NAMETABLES_WIDTH as f32, NAMETABLES_HEIGHT as f32],
+            );
+            let painter = ui.painter().with_clip_rect(response.rect);
+            let stroke = egui::Stroke::new(2.0, egui::Color32::RED);
+            for logical in 0..4u16 {
+                let min = response.rect.min
+                    + egui::vec2(
+                        (logical % 2 * SCREEN_WIDTH as u16) as f32,
+                        (logical / 2 * SCREEN_HEIGHT as u16) as f32,
+                    );
+                painter.text(
+                    min + egui::vec2(4.0, 4.0),
+                    egui::Align2::LEFT_TOP,
+                    format!("${:04X}", 0x2000 + logical * 0x400),
+                    egui::FontId::monospace(12.0),
+                    egui::Color32::WHITE,
+                );
+            }
+            let screen = egui::vec2(SCREEN_WIDTH as f32, SCREEN_HEIGHT as f32);
+            // Draw the rectangle up to 4 times so that it wraps around the edges.
+            for dx in [0.0, -(NAMETABLES_WIDTH as f32)] {
+                for dy in [0.0, -(NAMETABLES_HEIGHT as f32)] {
+                    let min = response.rect.min
+                        + egui::vec2(scroll_x as f32 + dx, scroll_y as f32 + dy);
+                    painter.rect_stroke(
+                        egui::Rect::from_min_size(min, screen),
+                        0.0,
+                        stroke,
+                    );
+                }
+            }
+        });
+
+    if !is_open {
+        game.is_nametables_open = false;
+    }
+}
+
+/// How long a watched value stays highlighted after it changes, half a second.
+const WATCH_HIGHLIGHT_FRAMES: u64 = 30;
+
+/// Memory locations that are read after every frame. A value that just changed is
+/// highlighted.
+pub fn watch_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let game = match state.game {
+        Some(ref mut game) if game.is_watch_open => game,
+        _ => return,
+    };
+    let mut is_open = true;
+    let frame = game.emulator.cpu.bus.ppu.frame_count();
+
+    egui::Window::new("Watch")
+        .open(&mut is_open)
+        .auto_sized()
+        .show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut game.watch_text)
+                        .hint_text("$00ff, a label, or name (=$0203)"),
+                );
+                let is_entered =
+                    response.lost_focus() && ui.input().key_pressed(egui::Key::Enter);
+                if ui.button("Add").clicked() || is_entered {
+                    game.add_watch();
+                }
+            });
+            if let Some(ref err) = game.watch_error {
+                ui.colored_label(egui::Color32::LIGHT_RED, err);
+            }
+            ui.separator();
+
+            let mut removed = None;
+            let mut rewind_to = None;
+            egui::Grid::new("watches").striped(true).show(ui, |ui| {
+                for (index, watch) in game.watches.iter_mut().enumerate() {
+                    ui.monospace(watch.label());
+                    let is_changed = watch.changed_frame.is_some_and(|changed| {
+                        frame.saturating_sub(changed) < WATCH_HIGHLIGHT_FRAMES
+                    });
+                    let value = egui::RichText::new(watch.formatted_value()).monospace();
+                    ui.label(match is_changed {
+                        true => value.color(egui::Color32::YELLOW),
+                        false => value,
+                    });
+                    egui::ComboBox::from_id_source(("watch format", index))
+                        .selected_text(watch.format.name())
+                        .show_ui(ui, |ui| {
+                            for format in WatchFormat::ALL {
+                                ui.selectable_value(
+                                    &mut watch.format,
+                                    format,
+                                    format.name(),
+                                );
+                            }
+                        });
+                    let write_log = &game.emulator.cpu.bus.write_log;
+                    match write_log.last_write(watch.address) {
+                        Some(write) => {
+                            let response = ui
+                                .small_button(format!(
+                                    "Written on frame {} by ${:04x}",
+                                    write.frame, write.pc
+                                ))
+                                .on_hover_text("Rewind to the write");
+                            if response.clicked() {
+                                rewind_to = Some(write);
+                            }
+                        }
+                        None => {
+                            ui.weak("No recent writes");
+                        }
+                    }
+                    if ui.small_button("Remove").clicked() {
+                        removed = Some(index);
+                    }
+                    ui.end_row();
+                }
+            });
+            if let Some(index) = removed {
+                game.watches.remove(index);
+            }
+            if let Some(write) = rewind_to {
+                game.rewind_to_write(&write);
+            }
+        });
+
+    if !is_open {
+        game.is_watch_open = false;
+    }
+}
+
+/// The panels that the rhai scripts define. Closing one hides it until the tool is
+/// started again.
+pub fn script_panel_windows(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    for panel in state
+        .scripts
+        .panels
+        .iter_mut()
+        .filter(|panel| panel.is_open)
+    {
+        egui::Window::new(&panel.name)
+            .id(egui::Id::new(("script panel", &panel.name)))
+            .open(&mut panel.is_open)
+            .auto_sized()
+            .show(ctx, |ui| {
+                if let Some(ref err) = panel.error {
+                    ui.colored_label(egui::Color32::LIGHT_RED, err);
+                    return;
+                }
+                egui::Grid::new("rows").striped(true).show(ui, |ui| {
+                    for row in &panel.rows {
+                        ui.label(&row.label);
+                        ui.monospace(&row.value);
+                        ui.end_row();
+                    }
+                });
+                let image = match panel.image {
+                    Some(ref image) => image,
+                    None => return,
+                };
+                let texture = panel.texture.get_or_insert_with(|| {
+                    ui.ctx().load_texture(
+                        format!("script panel {}", panel.name),
+                        egui::ColorImage::from_rgba_unmultiplied(
+                            [image.width, image.height],
+                            &image.rgba,
+                        ),
+                        egui::TextureOptions {
+                            magnification: egui::TextureFilter::Nearest,
+                            minification: egui::TextureFilter::Nearest,
+                        },
+                    )
+                });
+                ui.image(
+                    texture,
+                    [
+                        image.width as f32 * GAME_SCALE,
+                        image.height as f32 * GAME_SCALE,
+                    ],
+                );
+            });
+    }
+}
+
+/// Every sprite in OAM, with its tile and attributes. The sprites on the chosen
+/// scanline are highlighted, and the ones past the 8 sprite limit are marked as
+/// dropped, as the PPU won't draw them.
+pub fn oam_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let game = match state.game {
+        Some(ref mut game) if game.is_oam_open => game,
+        _ => return,
+    };
+    let mut is_open = true;
+    let image = game.sprites_image();
+    let texture = match game.oam_texture {
+        Some(ref mut texture) => {
+            texture.set(image, NEAREST);
+            texture.clone()
+        }
+        None => game
+            .oam_texture
+            .insert(ctx.load_texture("sprites", image, NEAREST))
+            .clone(),
+    };
+
+    egui::Window::new("Sprites")
+        .open(&mut is_open)
+        .default_height(400.0)
+        .show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Highlight scanline");
+                ui.add(
+                    egui::DragValue::new(&mut game.oam_scanline)
+                        .clamp_range(0..=SCREEN_HEIGHT as u8 - 1),
+                );
+            });
+            let ppu = &game.emulator.cpu.bus.ppu;
+            let (on_scanline, _) = ppu.state.evaluate_sprites(game.oam_scanline, false);
+            let height = ppu.state.sprite_height();
+            ui.label(format!(
+                "{} sprites on the scanline, 8x{} sprites",
+                on_scanline.len(),
+                height
+            ));
+            ui.separator();
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                egui::Grid::new("oam").striped(true).show(ui, |ui| {
+                    for heading in
+                        ["#", "", "X", "Y", "Tile", "Palette", "Priority", "Flip"]
+                    {
+                        ui.strong(heading);
+                    }
+                    ui.end_row();
+                    for sprite in 0..64 {
+                        let [y, tile, attributes, x] =
+                            [0, 1, 2, 3].map(|i| ppu.state.oam[sprite * 4 + i]);
+                        let order = on_scanline.iter().position(|&s| s == sprite);
+                        let color = match order {
+                            Some(order) if order >= SPRITES_PER_SCANLINE => {
+                                egui::Color32::RED
+                            }
+                            Some(_) => egui::Color32::YELLOW,
+                            None => ui.visuals().text_color(),
+                        };
+                        let text = |text: String| egui::RichText::new(text).color(color);
+                        let label = match order {
+                            Some(order) if order >= SPRITES_PER_SCANLINE => {
+                                format!("{} dropped", sprite)
+                            }
+                            _ => sprite.to_string(),
+                        };
+                        ui.label(text(label));
+                        let cell = egui::vec2(
+                            1.0 / 8.0,
+                            height as f32 / (SPRITE_CELL_HEIGHT * 8) as f32,
+                        );
+                        let min = egui::pos2(
+                            (sprite % 8) as f32 / 8.0,
+                            (sprite / 8) as f32 / 8.0,
+                        );
+                        ui.add(
+                            egui::Image::new(
+                                &texture,
+                                [
+                                    SPRITE_CELL_WIDTH as f32 * GAME_SCALE,
+                                    height as f32 * GAME_SCALE,
+                                ],
+                            )
+                            .uv(egui::Rect::from_min_size(min, cell))
+                            .bg_fill(egui::Color32::from_gray(40)),
+                        );
+                        ui.monospace(text(x.to_string()));
+                        ui.monospace(text(y.to_string()));
+                        ui.monospace(text(format!("${:02X}", tile)));
+                        ui.monospace(text((attributes & 0b11).to_string()));
+                        ui.label(text(
+                            if attributes & 0b0010_0000 != 0 {
+                                "Behind"
+                            } else {
+                                "Front"
+                            }
+                            .to_string(),
+                        ));
+                        let flip = match attributes >> 6 {
+                            0b01 => "H",
+                            0b10 => "V",
+                            0b11 => "H V",
+                            _ => "",
+                        };
+                        ui.label(text(flip.to_string()));
+                        ui.end_row();
+                    }
+                });
+            });
+        });
+
+    if !is_open {
+        game.is_oam_open = false;
+    }
+}
+
+/// The CPU memory map, from the bus's memory ranges and the PRG banks that the
+/// cartridge has switched in. Clicking a region opens the memory window at its start.
+pub fn memory_map_window(ctx: &egui::Context, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    let game = match state.game {
+        Some(ref mut game) if game.is_memory_map_open => game,
+        _ => return,
+    };
+    let mut is_open = true;
+    let regions = game.emulator.cpu.bus.memory_map();
+    let mut memory_address = None;
+
+    egui::Window::new("Memory Map")
+        .open(&mut is_open)
+        .auto_sized()
+        .show(ctx, |ui| {
+            if ui.button("Copy as Markdown").clicked() {
+                ui.output().copied_text = memory_map::to_markdown(&regions);
+            }
+            ui.separator();
+            egui::Grid::new("memory-map").striped(true).show(ui, |ui| {
+                for region in &regions {
+                    let range = format!("${:04X}-${:04X}", region.start, region.end);
+                    if ui.link(egui::RichText::new(range).monospace()).clicked() {
+                        memory_address = Some((MemorySpace::Cpu, region.start));
+                    }
+                    ui.label(&region.name);
+                    ui.label(&region.description);
+                    ui.end_row();
+                }
+            });
+        });
+
+    if let Some((space, address)) = memory_address {
+        game.show_memory(space, address);
+    }
+    if !is_open {
+        game.is_memory_map_open = false;
+    }
+}
+
+/// The overlay follows the running game, so its mirroring and scroll are only shown.
+fn mirroring_controls(ui: &mut egui::Ui, state: &RefCell<State>) {
+    let mut state = state.borrow_mut();
+    ui.checkbox(&mut state.mirroring.is_visible, "Mirroring overlay");
+    if !state.mirroring.is_visible {
+        return;
+    }
+    match state.game {
+        Some(ref game) => {
+            let bus = &game.emulator.cpu.bus;
+            let (scroll_x, scroll_y) = bus.scroll_position();
+            ui.label(format!("{:?} mirroring", bus.mirroring()));
+            ui.label(format!("Scroll ({}, {})", scroll_x, scroll_y));
+        }
+        None => {
+            ui.label("Load a ROM to see its nametables.");
+        }
+    }
+}
+
+// Each physical page of VRAM gets its own tint.
+const VRAM_PAGE_TINTS: [[u8; 3]; 4] = [
+    [120, 170, 255],
+    [255, 170, 90],
+    [130, 230, 130],
+    [230, 130, 230],
+];
+
+/// Draw the game's 4 logical nametables from the live VRAM in a 2x2 grid, shaded by
+/// which physical VRAM page backs each one with the cartridge's mirroring right now, so
+/// mappers like the MMC1 show their switches as they happen. The scroll rectangle shows
+/// the visible screen, and wraps around the edges just like the PPU does.
+fn mirroring_view(state: &mut State) {
+    use macroquad::prelude::*;
+    let game = match state.game {
+        Some(ref game) => game,
+        None => return,
+    };
+    let bus = &game.emulator.cpu.bus;
+    let mirroring = bus.mirroring();
+    let (scroll_x, scroll_y) = bus.scroll_position();
+    let image = Image {
+        bytes: game
+            .nametables_image()
+            .pixels
+            .iter()
+            .flat_map(|pixel| pixel.to_array())
+            .collect(),
+        width: NAMETABLES_WIDTH as u16,
+        height: NAMETABLES_HEIGHT as u16,
+    };
+    let texture = *state.mirroring.texture.get_or_insert_with(|| {
+        let texture = Texture2D::from_image(&image);
+        texture.set_filter(FilterMode::Nearest);
+        texture
+    });
+    texture.update(&image);
+
+    let view_w = screen_width() - SIDE_PANEL_WIDTH;
+    let view_h = screen_height();
+    let nametable_w = view_w / 2.0;
+    let nametable_h = view_h / 2.0;
+
+    for logical in 0..4u8 {
+        let physical = mirroring.physical_nametable(logical);
+        let (column, row) = ((logical & 0b1) as f32, (logical >> 1) as f32);
+        let x = column * nametable_w;
+        let y = row * nametable_h;
+        let [r, g, b] = VRAM_PAGE_TINTS[physical as usize];
+        let (screen_w, screen_h) = (SCREEN_WIDTH as f32, SCREEN_HEIGHT as f32);
+        draw_texture_ex(
+            texture,
+            x,
+            y,
+            Color::from_rgba(r, g, b, 255),
+            DrawTextureParams {
+                dest_size: Some(vec2(nametable_w, nametable_h)),
+                source: Some(Rect::new(
+                    column * screen_w,
+                    row * screen_h,
+                    screen_w,
+                    screen_h,
+                )),
+                ..Default::default()
+            },
+        );
+        draw_rectangle_lines(x, y, nametable_w, nametable_h, 2.0, BLACK);
+        draw_text(
+            &format!(
+                "${:04X} -> VRAM {}",
+                0x2000 + logical as u16 * 0x400,
+                (b'A' + physical) as char
+            ),
+            x + 6.0,
+            y + 20.0,
+            20.0,
+            WHITE,
+        );
+    }
+
+    // The scroll rectangle is in the 512x480 pixel space of the 4 nametables.
+    let scale_x = view_w / 512.0;
+    let scale_y = view_h / 480.0;
+    let left = scroll_x as f32 * scale_x;
+    let top = scroll_y as f32 * scale_y;
+    let width = 256.0 * scale_x;
+    let height = 240.0 * scale_y;
+    // Draw the rectangle up to 4 times so that it wraps around the edges.
+    for dx in [0.0, -view_w] {
+        for dy in [0.0, -view_h] {
+            draw_rectangle_lines(left + dx, top + dy, width, height, 3.0, RED);
+        }
+    }
+}
+
 pub fn main_art_view(state: &RefCell<State>) {
     use macroquad::prelude::*;
+    if state.borrow().mirroring.is_visible && state.borrow().game.is_some() {
+        mirroring_view(&mut state.borrow_mut());
+        return;
+    }
     if let Some(texture) = state.borrow().texture {
         draw_texture_ex(
             texture,
@@ -158,8 +2096,7 @@ impl ColorConvert for macroquad::color::Color {
     }
 }
 
-fn color_button(ui: &mut egui::Ui, ntsc_index: u8) -> egui::Response {
-    let color = &NTSC_PALETTE[ntsc_index as usize];
+fn color_button(ui: &mut egui::Ui, color: [u8; 3]) -> egui::Response {
     ui.add(
         egui::Button::new("")
             .fill(egui::Color32::from_rgb(color[0], color[1], color[2]))
@@ -177,10 +2114,11 @@ fn add_swatch_button(
     palette_index: u8,
     color_index: u8,
 ) {
-    let response = color_button(
-        ui,
-        state.borrow().palettes[palette_index as usize][color_index as usize],
-    );
+    let response = {
+        let state = state.borrow();
+        let ntsc_index = state.palettes[palette_index as usize][color_index as usize];
+        color_button(ui, state.master_palette[ntsc_index as usize & 0x3f])
+    };
 
     if response.clicked() {
         state.borrow_mut().palette_change = PaletteChange {
@@ -192,7 +2130,8 @@ fn add_swatch_button(
 }
 
 fn add_color_button(state: &RefCell<State>, ui: &mut egui::Ui, ntsc_index: u8) {
-    let response = color_button(ui, ntsc_index);
+    let color = state.borrow().master_palette[ntsc_index as usize];
+    let response = color_button(ui, color);
 
     if response.clicked() {
         let palette_change = state.borrow().palette_change;
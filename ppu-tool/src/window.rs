@@ -0,0 +1,47 @@
+//! The OS window around the tool: its icon, its title, and how long operations show
+//! their progress. Miniquad 0.3 only takes the title and icon when the window opens,
+//! and has no taskbar progress, so the side panel repeats the live title and the
+//! progress is drawn in a window of its own.
+
+use miniquad::conf::Icon;
+
+/// The icon is drawn at 64x64, and scaled down for the smaller sizes.
+const ICON_PNG: &[u8] = include_bytes!("../assets/icon.png");
+
+pub const TOOL_NAME: &str = "PPU Tool";
+
+pub fn icon() -> Icon {
+    let image = image::load_from_memory(ICON_PNG)
+        .expect("The icon is a valid PNG.")
+        .into_rgba8();
+    let resize = |size: u32| {
+        image::imageops::resize(&image, size, size, image::imageops::FilterType::Nearest)
+            .into_raw()
+    };
+    let mut icon = Icon {
+        small: [0; 16 * 16 * 4],
+        medium: [0; 32 * 32 * 4],
+        big: [0; 64 * 64 * 4],
+    };
+    icon.small.copy_from_slice(&resize(16));
+    icon.medium.copy_from_slice(&resize(32));
+    icon.big.copy_from_slice(&resize(64));
+    icon
+}
+
+/// e.g. "PPU Tool - Super Mario Bros. (World) (paused)", or just the tool's name with
+/// nothing loaded. Games that aren't known dumps go by their filename.
+pub fn title(game: Option<&str>, is_paused: bool) -> String {
+    match game {
+        Some(game) if is_paused => format!("{} - {} (paused)", TOOL_NAME, game),
+        Some(game) => format!("{} - {}", TOOL_NAME, game),
+        None => TOOL_NAME.to_string(),
+    }
+}
+
+/// Something that takes longer than a frame, and is run a piece at a time.
+pub struct Progress {
+    pub label: String,
+    /// From 0 to 1.
+    pub fraction: f32,
+}
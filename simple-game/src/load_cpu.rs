@@ -3,12 +3,10 @@ use std::path::Path;
 use cpu_6502::{
     asm::{AddressToLabel, AsmLexer, BytesLabels},
     bus::Bus,
-    cpu_6502::Cpu6502,
-    mappers::SimpleProgram,
-    opcodes::OpCode,
+    cpu_6502::{Cpu6502, NesCpu},
 };
 
-pub fn load_cpu<P: AsRef<Path>>(filename: P) -> (Cpu6502, AddressToLabel) {
+pub fn load_cpu<P: AsRef<Path>>(filename: P) -> (Cpu6502<Bus>, AddressToLabel) {
     let contents = std::fs::read_to_string(filename).unwrap();
     let mut lexer = AsmLexer::new(&contents);
 
@@ -18,12 +16,9 @@ pub fn load_cpu<P: AsRef<Path>>(filename: P) -> (Cpu6502, AddressToLabel) {
     }
 
     let BytesLabels {
-        mut bytes,
+        bytes,
         address_to_label,
+        ..
     } = lexer.into_bytes().unwrap();
-    bytes.push(OpCode::KIL as u8);
-    (
-        Cpu6502::new(Bus::new_shared_bus(Box::new(SimpleProgram::load(&bytes)))),
-        address_to_label,
-    )
+    (Cpu6502::from_program(&bytes), address_to_label)
 }
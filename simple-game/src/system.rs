@@ -1,5 +1,6 @@
 use std::cell::RefCell;
 
+use cpu_6502::bus::Bus;
 use cpu_6502::cpu_6502::Cpu6502;
 use sdl2::{
     event::Event,
@@ -47,10 +48,10 @@ impl<'a> ScreenBuffer<'a> {
         }
     }
 
-    pub fn update(&mut self, cpu: &Cpu6502) -> bool {
+    pub fn update(&mut self, cpu: &Cpu6502<Bus>) -> bool {
         let mut frame_index = 0;
         let mut texture_dirty = false;
-        let bus = cpu.bus.borrow_mut();
+        let bus = &cpu.bus;
         for index in self.mem_offset.0..self.mem_offset.1 {
             let (b1, b2, b3) = color(bus.read_u8(index as u16)).rgb();
             if self.texture_data[frame_index] != b1
@@ -126,13 +127,13 @@ impl System {
 }
 
 pub struct SimpleGame<'a> {
-    pub cpu: Cpu6502,
+    pub cpu: Cpu6502<Bus>,
     pub system: &'a System,
     pub screen: ScreenBuffer<'a>,
 }
 
 impl<'a> SimpleGame<'a> {
-    pub fn new(cpu: Cpu6502, system: &'a System) -> SimpleGame<'a> {
+    pub fn new(cpu: Cpu6502<Bus>, system: &'a System) -> SimpleGame<'a> {
         SimpleGame {
             cpu,
             system,
@@ -167,16 +168,16 @@ impl<'a> SimpleGame<'a> {
                         keycode: Some(key), ..
                     } => match key {
                         Keycode::W | Keycode::Up => {
-                            self.cpu.bus.borrow_mut().set_u8(0xff, 0x77);
+                            self.cpu.bus.set_u8(0xff, 0x77);
                         }
                         Keycode::S | Keycode::Down => {
-                            self.cpu.bus.borrow_mut().set_u8(0xff, 0x73);
+                            self.cpu.bus.set_u8(0xff, 0x73);
                         }
                         Keycode::A | Keycode::Left => {
-                            self.cpu.bus.borrow_mut().set_u8(0xff, 0x61);
+                            self.cpu.bus.set_u8(0xff, 0x61);
                         }
                         Keycode::D | Keycode::Right => {
-                            self.cpu.bus.borrow_mut().set_u8(0xff, 0x64);
+                            self.cpu.bus.set_u8(0xff, 0x64);
                         }
                         _ => {}
                     },
@@ -184,10 +185,7 @@ impl<'a> SimpleGame<'a> {
                 }
             }
 
-            self.cpu
-                .bus
-                .borrow_mut()
-                .set_u8(0xfe, rand::random::<u8>() % 15 + 1);
+            self.cpu.bus.set_u8(0xfe, rand::random::<u8>() % 15 + 1);
 
             self.cpu.tick();
             self.draw()?;
@@ -0,0 +1,142 @@
+//! Tasks for working on the project, run through the cargo alias in .cargo/config.toml.
+//!
+//!   cargo task new-game <name>
+//!   cargo task build-asm [asm.toml]
+//!   cargo task export-rom <game.nes> <directory> [game.cdl]
+
+use cpu_6502::asm_project::AsmProject;
+use cpu_6502::rom::InesRom;
+use cpu_6502::rom_export::{CodeDataLog, RomExport};
+use std::path::{Path, PathBuf};
+use std::{env, fs, process::exit};
+
+const MAIN_ASM: &str = include_str!("../templates/new-game/main.asm");
+const MAKEFILE: &str = include_str!("../templates/new-game/Makefile");
+const ASM_TOML: &str = include_str!("../templates/new-game/asm.toml");
+
+/// The CHR for one 8KB bank of tiles, all blank.
+const CHR_SIZE: usize = 0x2000;
+
+/// The background palettes, in the 16 byte format that the ppu-tool reads. These match
+/// the palettes in main.asm.
+const PALETTES: [u8; 16] = [
+    0x0f, 0x00, 0x10, 0x30, //
+    0x0f, 0x06, 0x16, 0x26, //
+    0x0f, 0x09, 0x19, 0x29, //
+    0x0f, 0x02, 0x12, 0x22, //
+];
+
+fn print_usage() {
+    eprintln!("Usage:");
+    eprintln!("  cargo task new-game <name>  Create a starter NES project in ./<name>");
+    eprintln!("  cargo task build-asm [path]  Assemble the project in an asm.toml");
+    eprintln!(
+        "  cargo task export-rom <game.nes> <directory> [game.cdl]  \
+         Disassemble an NROM game into a project"
+    );
+}
+
+/// The folder with the workspace Cargo.toml, which the generated Makefile builds with.
+fn workspace_dir() -> PathBuf {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("The xtask crate is in the workspace.");
+    dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf())
+}
+
+fn new_game(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "The game name \"{}\" should only use letters, numbers, - and _.",
+            name
+        ));
+    }
+    let dir = Path::new(name);
+    if dir.exists() {
+        return Err(format!("{:?} already exists.", dir));
+    }
+    fs::create_dir_all(dir)
+        .map_err(|err| format!("Failed to create {:?}: {}", dir, err))?;
+
+    let fill = |template: &str| {
+        template
+            .replace("{{name}}", name)
+            .replace("{{workspace}}", &workspace_dir().to_string_lossy())
+    };
+    let files: [(&str, Vec<u8>); 5] = [
+        ("main.asm", fill(MAIN_ASM).into_bytes()),
+        ("Makefile", fill(MAKEFILE).into_bytes()),
+        ("asm.toml", fill(ASM_TOML).into_bytes()),
+        ("game.chr", vec![0; CHR_SIZE]),
+        ("palette.pal", PALETTES.to_vec()),
+    ];
+    for (filename, contents) in files.iter() {
+        let path = dir.join(filename);
+        fs::write(&path, contents)
+            .map_err(|err| format!("Failed to write {:?}: {}", path, err))?;
+    }
+
+    println!("Created {:?}. To build and run it:", dir);
+    println!("  cd {}", name);
+    println!("  make run");
+    Ok(())
+}
+
+/// Build a project from its manifest. A directory builds the asm.toml in it.
+fn build_asm(path: &str) -> Result<(), String> {
+    let mut path = PathBuf::from(path);
+    if path.is_dir() {
+        path.push("asm.toml");
+    }
+    AsmProject::load(&path)?.build_and_write()
+}
+
+/// Disassemble a ROM into a project that builds back into it.
+fn export_rom(
+    rom_path: &str,
+    directory: &str,
+    cdl_path: Option<&str>,
+) -> Result<(), String> {
+    let read = |path: &str| {
+        fs::read(path).map_err(|err| format!("Failed to read {:?}: {}", path, err))
+    };
+    let rom = InesRom::from_ines_bytes(&read(rom_path)?)?;
+    let code_data_log = match cdl_path {
+        Some(path) => Some(CodeDataLog::from_cdl_bytes(
+            &read(path)?,
+            rom.prg_rom.len(),
+        )?),
+        None => None,
+    };
+    let name = Path::new(rom_path)
+        .file_name()
+        .map_or(rom_path.into(), |name| name.to_string_lossy());
+    let export = RomExport::from_rom(&rom, &name, code_data_log.as_ref())?;
+    export.write(Path::new(directory))?;
+    println!("Exported {} to {:?}. To build it again:", name, directory);
+    println!("  cargo task build-asm {}", directory);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["new-game", name] => new_game(name),
+        ["build-asm"] => build_asm("asm.toml"),
+        ["build-asm", path] => build_asm(path),
+        ["export-rom", rom, directory] => export_rom(rom, directory, None),
+        ["export-rom", rom, directory, cdl] => export_rom(rom, directory, Some(cdl)),
+        _ => {
+            print_usage();
+            exit(1);
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
@@ -0,0 +1,66 @@
+; {{name}}
+;
+; Build the ROM with `make`, and run it with `make run`. The code is placed at $8000,
+; and the asm tool points the interrupt vectors at the reset, nmi, and irq labels.
+
+; The 4 background palettes, the same colors as palette.pal. The assembler can't index
+; from a label yet, so they're first, at the known address $8000. They're never run,
+; as the CPU starts at reset.
+palettes:
+    .byte $0f, $00, $10, $30
+    .byte $0f, $06, $16, $26
+    .byte $0f, $09, $19, $29
+    .byte $0f, $02, $12, $22
+
+reset:
+    sei         ; Ignore IRQs.
+    cld         ; The NES has no decimal mode, but clear it anyway.
+    ldx #$ff
+    txs         ; Set up the stack.
+
+    ; Turn off the NMI and rendering while setting up.
+    lda #$00
+    sta $2000
+    sta $2001
+
+    ; The PPU takes 2 frames to warm up, wait for the vblank flag twice.
+vblank_wait_1:
+    bit $2002
+    bpl vblank_wait_1
+vblank_wait_2:
+    bit $2002
+    bpl vblank_wait_2
+
+    ; Copy the palettes to $3F00 in the PPU.
+    lda #$3f
+    sta $2006
+    lda #$00
+    sta $2006
+    ldx #$00
+load_palettes:
+    lda $8000,x
+    sta $2007
+    inx
+    cpx #$10
+    bne load_palettes
+
+    ; Reset the scroll, then turn on the NMI and the background.
+    lda #$00
+    sta $2005
+    sta $2005
+    lda #%10000000
+    sta $2000
+    lda #%00001010
+    sta $2001
+
+main_loop:
+    ; The game logic goes here.
+    jmp main_loop
+
+nmi:
+    ; This runs at the start of every vblank, 60 times a second. It's the safe time
+    ; to update the PPU.
+    rti
+
+irq:
+    rti